@@ -0,0 +1,361 @@
+//! The commands that read and store single blocks, list the blocks, and find blocks by height or
+//! by a short hash.
+use std::path::PathBuf;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable, FullBlockStream, ToHex};
+use hex::FromHex;
+use serde_json::json;
+use bsv_blockarchive::{merkle, BlockArchive, ChainIndex, ListOptions, Network, Result, Error};
+use bsv_blockarchive::coinbase::read_coinbase;
+use bsv_blockarchive::thin::ThinBlockStore;
+use bsv_blockarchive::verify::{self, CheckFailure, FailureKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use crate::{emit, emit_failure, OutputFormat};
+use crate::exit::Outcome;
+
+// the shortest prefix of a block hash that is accepted for a stored block
+const MIN_SHORT_HASH: usize = 4;
+
+// the blocks listed when a short hash is ambiguous
+const AMBIGUOUS_SHOWN: usize = 5;
+
+// count the blocks in the archive, for the total of a progress report
+pub async fn count_blocks(archive: &mut dyn BlockArchive, enabled: bool) -> Result<Option<u64>> {
+    if !enabled {
+        return Ok(None);
+    }
+    let mut results = archive.block_list().await?;
+    let mut n = 0;
+    while results.next().await.is_some() {
+        n += 1;
+    }
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    Ok(Some(n))
+}
+
+pub async fn list_blocks(mut archive: Box<dyn BlockArchive>, options: ListOptions, output: OutputFormat) -> Result<()>{
+    let mut results = archive.block_list_opts(options).await?;
+    while let Some(block_hash) = results.next().await {
+        emit(output, block_hash.to_string(), json!({"block_hash": block_hash.to_string()}));
+    }
+    match results.as_mut().take_error() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// list the blocks with a timestamp in the range, in order of time
+pub async fn list_blocks_in_time_range(mut archive: Box<dyn BlockArchive>, range: (u64, u64), median_time_past: bool, options: ListOptions, output: OutputFormat) -> Result<()> {
+    let prefix = options.prefix.map(|p| p.to_lowercase());
+    let blocks = archive.blocks_in_time_range(range.0, range.1, median_time_past).await?;
+    let blocks = blocks.into_iter()
+        .filter(|h| match prefix.as_ref() {
+            Some(p) => h.to_string().starts_with(p.as_str()),
+            None => true,
+        })
+        .skip(options.offset)
+        .take(options.limit.unwrap_or(usize::MAX));
+    for block_hash in blocks {
+        emit(output, block_hash.to_string(), json!({"block_hash": block_hash.to_string()}));
+    }
+    Ok(())
+}
+
+// parse a block hash given on the command line, which can be a short hash
+pub fn parse_short_hash(s: &str) -> std::result::Result<ShortHash, String> {
+    if s.len() < MIN_SHORT_HASH || s.len() > 64 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid block hash {}, expected {} to 64 hex digits", s, MIN_SHORT_HASH));
+    }
+    Ok(ShortHash(s.to_lowercase()))
+}
+
+pub async fn header(archive: &dyn BlockArchive, block_hash: BlockHash, hex: bool, output: OutputFormat) -> Result<()> {
+    let h = archive.block_header(&block_hash).await?;
+    let x: String = h.encode_hex();
+    if output == OutputFormat::Json {
+        println!("{}", json!({
+            "block_hash": h.hash().to_string(),
+            "version": h.version,
+            "prev_hash": h.prev_hash.to_string(),
+            "merkle_root": h.merkle_root.to_string(),
+            "timestamp": h.timestamp,
+            "bits": h.bits,
+            "nonce": h.nonce,
+            "hex": x,
+        }));
+    } else if hex {
+        println!("{}", x);
+    } else {
+        println!("{:?}", h);
+    }
+    Ok(())
+}
+
+// print the header, number of transactions and size of a block
+pub async fn block_summary(archive: &dyn BlockArchive, block_hash: BlockHash, output: OutputFormat) -> Result<()> {
+    let summary = archive.block_summary(&block_hash).await?;
+    let h = &summary.header;
+    emit(output, format!("Block: {}\nPrevious: {}\nMerkle root: {}\nTimestamp: {}\nBits: {:08x}\nTransactions: {}\nSize: {}",
+                         block_hash, h.prev_hash, h.merkle_root, h.timestamp, h.bits, summary.tx_count, summary.size),
+         json!({
+             "block_hash": block_hash.to_string(),
+             "version": h.version,
+             "prev_hash": h.prev_hash.to_string(),
+             "merkle_root": h.merkle_root.to_string(),
+             "timestamp": h.timestamp,
+             "bits": h.bits,
+             "nonce": h.nonce,
+             "tx_count": summary.tx_count,
+             "size": summary.size,
+         }));
+    Ok(())
+}
+
+// write the raw bytes of a block to a file or stdout
+pub async fn get_block(archive: &dyn BlockArchive, block_hash: BlockHash, out: Option<PathBuf>, hex: bool) -> Result<()> {
+    let mut reader = archive.get_block(&block_hash).await?;
+    let mut writer: Box<dyn AsyncWrite + Unpin + Send> = match out {
+        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+    let result = async {
+        if hex {
+            // the block is encoded a chunk at a time so that large blocks are not held in memory
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                writer.write_all(hex::encode(&buf[..n]).as_bytes()).await?;
+            }
+            writer.write_all(b"\n").await?;
+        } else {
+            tokio::io::copy(&mut reader, &mut writer).await?;
+        }
+        writer.flush().await
+    }.await;
+    match result {
+        // the reader of a pipe may stop early, such as head
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        r => Ok(r?),
+    }
+}
+
+// check that a raw block has the expected hash, and optionally that its transactions match the
+// merkle root, returning the hash of the block or the check that failed
+pub async fn check_raw_block(block: &[u8], expected: Option<BlockHash>, merkle: bool) -> Result<std::result::Result<BlockHash, CheckFailure>> {
+    if block.len() < 80 {
+        return Err(Error::InvalidBlockFile(format!("{} bytes is too short for a block", block.len())));
+    }
+    let header = BlockHeader::from_binary(&mut &block[..80]).await?;
+    let block_hash = header.hash();
+    if let Some(expected) = expected {
+        if expected != block_hash {
+            return Ok(Err(CheckFailure::new(&expected, FailureKind::HashMismatch,
+                format!("the header has the hash {}, not {}", block_hash, expected))));
+        }
+    }
+    if merkle {
+        let reader: Box<dyn AsyncRead + Unpin + Send> = Box::new(std::io::Cursor::new(block.to_vec()));
+        let full_block = FullBlockStream::new(reader).await?;
+        if let Some(failure) = verify::check_transactions(&block_hash, full_block).await? {
+            return Ok(Err(failure));
+        }
+    }
+    Ok(Ok(block_hash))
+}
+
+// read a block from a file or stdin, check it, and store it, returns false if the block was not
+// stored
+pub async fn store_block(archive: Box<dyn BlockArchive>, file: Option<PathBuf>, expected: Option<BlockHash>, merkle: bool,
+                     output: OutputFormat) -> Result<bool> {
+    // the whole block is read so that it can be checked before anything is written
+    let mut block = Vec::new();
+    match file {
+        Some(path) => tokio::fs::File::open(path).await?.read_to_end(&mut block).await?,
+        None => tokio::io::stdin().read_to_end(&mut block).await?,
+    };
+    let block_hash = match check_raw_block(&block, expected, merkle).await? {
+        Ok(h) => h,
+        Err(failure) => {
+            emit_failure(output, &failure);
+            return Ok(false);
+        }
+    };
+    match archive.store_block(&block_hash, &mut &block[..]).await {
+        Ok(()) => {
+            emit(output, block_hash.to_string(), json!({"block_hash": block_hash.to_string(), "status": "stored"}));
+            Ok(true)
+        }
+        Err(Error::BlockExists) => {
+            emit(output, format!("Block {} already exists", block_hash),
+                 json!({"block_hash": block_hash.to_string(), "status": "exists"}));
+            Ok(true)
+        }
+        // rejected by the write policy of the archive
+        Err(Error::InvalidBlock(msg)) => {
+            emit(output, format!("ERROR: {}", msg),
+                 json!({"block_hash": block_hash.to_string(), "status": "error", "error": "invalid_block", "message": msg}));
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// print a merkle proof for a transaction in a block
+pub async fn proof(archive: Box<dyn BlockArchive>, thin_dir: PathBuf, block_hash: BlockHash, txid: BlockHash, output: OutputFormat) -> Result<Outcome> {
+    // a block that has been thinned is not in the archive
+    let thin = if !archive.block_exists(&block_hash).await? && tokio::fs::try_exists(&thin_dir).await? {
+        ThinBlockStore::open(&thin_dir)?.get(&block_hash)?
+    } else {
+        None
+    };
+    let proof = match &thin {
+        Some(t) => t.merkle_proof(&txid),
+        None => merkle::merkle_proof(archive.as_ref(), &block_hash, &txid).await,
+    };
+    let proof = proof?;
+    let header = match &thin {
+        Some(t) => t.block_header().await?,
+        None => archive.block_header(&block_hash).await?,
+    };
+    if !proof.verify(&header.merkle_root) {
+        emit_failure(output, &CheckFailure::new(&block_hash, FailureKind::MerkleRootMismatch, format!("merkle root mismatch for block {}", block_hash)));
+        return Ok(Outcome::Problems(1));
+    }
+    emit(output, hex::encode(proof.to_binary()), proof.to_json());
+    Ok(Outcome::Ok)
+}
+
+// print the coinbase of a block, and the fees if the height of the block is known
+pub async fn coinbase(mut archive: Box<dyn BlockArchive>, block_hash: BlockHash, network: Network, output: OutputFormat) -> Result<()> {
+    let coinbase = read_coinbase(archive.as_ref(), &block_hash).await?;
+    let chain = ChainIndex::build(&mut archive).await?;
+    let height = chain.height_of(&block_hash);
+    let subsidy = height.map(|h| network.block_subsidy(h));
+    let fees = subsidy.map(|s| coinbase.fees(s));
+    if output == OutputFormat::Json {
+        let mut record = coinbase.to_json();
+        record["height"] = json!(height);
+        record["subsidy"] = json!(subsidy);
+        record["fees"] = json!(fees);
+        println!("{}", record);
+        return Ok(());
+    }
+    let unknown = || "unknown".to_string();
+    println!("Block: {}", block_hash);
+    println!("Height: {}", height.map_or_else(unknown, |h| h.to_string()));
+    println!("Txid: {}", coinbase.txid);
+    println!("Script: {}", hex::encode(&coinbase.script));
+    println!("Miner tag: {}", coinbase.miner_tag().unwrap_or_default());
+    println!("Reward: {}", coinbase.reward);
+    println!("Subsidy: {}", subsidy.map_or_else(unknown, |s| s.to_string()));
+    println!("Fees: {}", fees.map_or_else(unknown, |f| f.to_string()));
+    Ok(())
+}
+
+// get the blocks given as hashes, in a file of hashes, and as a range of heights in the best chain
+pub async fn select_blocks(archive: &mut dyn BlockArchive, mut block_hashes: Vec<BlockHash>, hashes: Option<PathBuf>,
+                       heights: Option<(u64, u64)>) -> Result<Vec<BlockHash>> {
+    if let Some(path) = hashes {
+        block_hashes.extend(read_hashes(path).await?);
+    }
+    if let Some((from, to)) = heights {
+        let chain = ChainIndex::build(archive).await?;
+        for height in from..=to {
+            match chain.block_by_height(height) {
+                Some(h) => block_hashes.push(h),
+                None => {
+                    println!("No block at height {}", height);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(block_hashes)
+}
+
+// read a file of block hashes, one per line
+pub async fn read_hashes(path: PathBuf) -> Result<Vec<BlockHash>> {
+    let mut block_hashes = Vec::new();
+    for line in tokio::fs::read_to_string(path).await?.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        block_hashes.push(BlockHash::from_hex(line).map_err(|_| Error::InvalidHash(line.to_string()))?);
+    }
+    Ok(block_hashes)
+}
+
+// a block hash given on the command line, the full hash or the start of the hash of a stored
+// block, see resolve_hash
+#[derive(Clone, Debug)]
+pub struct ShortHash(String);
+
+// get the stored block that a short hash is the start of, a full hash is not looked up
+pub async fn resolve_hash(archive: &mut dyn BlockArchive, hash: ShortHash) -> Result<BlockHash> {
+    if hash.0.len() == 64 {
+        return BlockHash::from_hex(&hash.0).map_err(|_| Error::InvalidHash(hash.0));
+    }
+    let mut found = archive.resolve_prefix(&hash.0).await?;
+    match found.len() {
+        0 => Err(Error::BlockNotFound),
+        1 => Ok(found.remove(0)),
+        n => {
+            let shown: Vec<String> = found.iter().take(AMBIGUOUS_SHOWN).map(|h| h.to_string()).collect();
+            let more = if n > AMBIGUOUS_SHOWN { ", ..." } else { "" };
+            Err(Error::InvalidHash(format!("{} is ambiguous, {} blocks start with it: {}{}", hash.0, n, shown.join(", "), more)))
+        }
+    }
+}
+
+// get the stored blocks of several short hashes
+pub async fn resolve_hashes(archive: &mut dyn BlockArchive, hashes: Vec<ShortHash>) -> Result<Vec<BlockHash>> {
+    let mut block_hashes = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        block_hashes.push(resolve_hash(archive, hash).await?);
+    }
+    Ok(block_hashes)
+}
+
+// print the hash of the block at a height
+pub async fn block_at(mut archive: Box<dyn BlockArchive>, height: u64) -> Result<()> {
+    let chain = ChainIndex::build(&mut archive).await?;
+    match chain.block_by_height(height) {
+        Some(h) => println!("{}", h),
+        None => println!("No block at height {}", height),
+    }
+    Ok(())
+}
+
+// print the height of a block
+pub async fn height(mut archive: Box<dyn BlockArchive>, block_hash: BlockHash) -> Result<()> {
+    let chain = ChainIndex::build(&mut archive).await?;
+    print_height(&chain, block_hash);
+    Ok(())
+}
+
+pub fn print_height(chain: &ChainIndex, block_hash: BlockHash) {
+    match chain.height_of(&block_hash) {
+        Some(height) => println!("{}", height),
+        None => println!("Block not found or not linked to the genesis block"),
+    }
+}
+
+// print the best tip
+pub async fn tip(mut archive: Box<dyn BlockArchive>) -> Result<()> {
+    let chain = ChainIndex::build(&mut archive).await?;
+    print_tip(&chain);
+    Ok(())
+}
+
+pub fn print_tip(chain: &ChainIndex) {
+    match chain.tip() {
+        Some((h, height)) => println!("{} {}", h, height),
+        None => println!("No chain, the archive does not contain the genesis block"),
+    }
+}
@@ -0,0 +1,163 @@
+//! The configuration file of the blockarchive command.
+//!
+//! The file is TOML with the same settings as the global command line options, for example:
+//!
+//!     root_dir = "/data/blocks"
+//!     archive_type = "simple"
+//!     compress = true
+//!     cache_size = 100000
+//!     write_policy = "header"
+//!     network = "mainnet"
+//!
+//! Checkpoints for "check checkpoints", in addition to the built-in ones of the network, are
+//! given as a list of tables:
+//!
+//!     [[checkpoints]]
+//!     height = 600000
+//!     hash = "<the hash of the block at height 600000>"
+//!
+//! Command line options and environment variables override the values in the file.
+use std::path::{Path, PathBuf};
+use bitcoinsv::bitcoin::BlockHash;
+use hex::FromHex;
+use serde::Deserialize;
+use bsv_blockarchive::Network;
+use crate::{ArchiveType, CipherName, PublishSchema, WriteCheck};
+
+/// The name of the configuration file in the default locations.
+pub const CONFIG_FILE: &str = "blockarchive.toml";
+
+/// The settings in a configuration file, all of them are optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The root of the block archive.
+    pub root_dir: Option<String>,
+    /// The type of the block archive.
+    pub archive_type: Option<ArchiveType>,
+    /// The endpoint of the S3-compatible store.
+    pub s3_endpoint: Option<String>,
+    /// Store new blocks zstd compressed, for a simple archive.
+    pub compress: Option<bool>,
+    /// Store new blocks larger than this many MiB in chunks, for a simple or S3 archive.
+    pub chunk_size: Option<u64>,
+    /// Keep failed multipart uploads to an S3 archive to be continued.
+    pub resumable_uploads: Option<bool>,
+    /// Read the list of blocks of an S3 archive from an S3 Inventory report, "bucket/prefix".
+    pub s3_inventory: Option<String>,
+    /// Read the list of blocks of an S3 archive from a listing file, "bucket/key".
+    pub s3_listing: Option<String>,
+    /// The directory of the transaction index.
+    pub index_dir: Option<PathBuf>,
+    /// The directory of the block filters.
+    pub filters_dir: Option<PathBuf>,
+    /// The directory of the thin blocks.
+    pub thin_dir: Option<PathBuf>,
+    /// The directory of the quarantine archive.
+    pub quarantine_dir: Option<PathBuf>,
+    /// The directory of the journals of the import, sync and replicate commands.
+    pub journal_dir: Option<PathBuf>,
+    /// The address to expose Prometheus metrics on.
+    pub metrics_listen: Option<String>,
+    /// The http URL to post the events of the replicate, serve, and watch commands to.
+    pub webhook: Option<String>,
+    /// The URL of the broker that the blocks stored by those commands are published to.
+    pub publish: Option<String>,
+    /// The topic that blocks are published to.
+    pub publish_topic: Option<String>,
+    /// The content of the published messages.
+    pub publish_schema: Option<PublishSchema>,
+    /// The number of headers and block sizes to cache.
+    pub cache_size: Option<usize>,
+    /// The number of blocks to cache.
+    pub block_cache_size: Option<usize>,
+    /// The number of blocks to read ahead of check blocks, export parquet, and export-metadata.
+    pub prefetch: Option<usize>,
+    /// The checks made on blocks before they are stored.
+    pub write_policy: Option<WriteCheck>,
+    /// The file with the key that the blocks are encrypted with.
+    pub encryption_key_file: Option<PathBuf>,
+    /// The cipher that new blocks are encrypted with.
+    pub cipher: Option<CipherName>,
+    /// The limit on the bytes of block data per second.
+    pub max_bandwidth: Option<u64>,
+    /// The limit on the archive operations per second.
+    pub max_ops: Option<u64>,
+    /// The number of seconds to wait for another process to release the archive lock.
+    pub lock_wait: Option<u64>,
+    /// The network of the blocks, such as "mainnet" or "testnet".
+    pub network: Option<String>,
+    /// Checkpoints of the network, in addition to the built-in ones.
+    pub checkpoints: Option<Vec<Checkpoint>>,
+}
+
+/// A known block of the network, see "check checkpoints".
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Checkpoint {
+    /// The height of the block.
+    pub height: u64,
+    /// The hash of the block.
+    pub hash: String,
+}
+
+impl Config {
+    /// Read the configuration file at the path, or if there is no path the first file found in
+    /// the default locations. Returns an empty configuration if there is no path and no file in
+    /// the default locations.
+    pub async fn load(path: Option<&Path>) -> Result<Config, String> {
+        let path = match path {
+            Some(p) => p.to_path_buf(),
+            None => {
+                let mut found = None;
+                for p in Self::default_paths() {
+                    if tokio::fs::try_exists(&p).await.unwrap_or(false) {
+                        found = Some(p);
+                        break;
+                    }
+                }
+                match found {
+                    Some(p) => p,
+                    None => return Ok(Config::default()),
+                }
+            }
+        };
+        let text = tokio::fs::read_to_string(&path).await
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let config: Config = toml::from_str(&text).map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+        // check the network now rather than when it is first used
+        config.network()?;
+        config.checkpoints()?;
+        Ok(config)
+    }
+
+    /// Get the network, if one is set.
+    pub fn network(&self) -> Result<Option<Network>, String> {
+        match &self.network {
+            Some(n) => n.parse::<Network>().map(Some).map_err(|e| e.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the checkpoints as heights and hashes.
+    pub fn checkpoints(&self) -> Result<Vec<(u64, BlockHash)>, String> {
+        let mut checkpoints = Vec::new();
+        for c in self.checkpoints.iter().flatten() {
+            let hash = BlockHash::from_hex(&c.hash).map_err(|_| format!("invalid checkpoint hash at height {}: {}", c.height, c.hash))?;
+            checkpoints.push((c.height, hash));
+        }
+        Ok(checkpoints)
+    }
+
+    // The default locations of the file, in the order they are checked: the current directory,
+    // then the user configuration directory.
+    fn default_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(CONFIG_FILE)];
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+        if let Some(dir) = config_dir {
+            paths.push(dir.join("blockarchive").join(CONFIG_FILE));
+        }
+        paths
+    }
+}
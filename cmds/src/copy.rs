@@ -0,0 +1,54 @@
+//! The sync and replicate commands, which copy blocks between archives.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use bsv_blockarchive::{replicate, sync, BlockArchive, SledHeaderArchive, Result};
+use crate::Progress;
+use crate::blocks::count_blocks;
+use crate::import::begin_journaled_batch;
+
+// copy the blocks missing from the destination archive
+pub async fn sync_archive(mut src: Box<dyn BlockArchive>, dst: Box<dyn BlockArchive>, journal: (&Path, &str), progress: bool) -> Result<()> {
+    let dst = begin_journaled_batch(dst, journal).await?;
+    let total = count_blocks(src.as_mut(), progress).await?;
+    let progress = Progress::start(progress, "sync", total, None);
+    let summary = sync::sync_archives(&mut src, &dst, |_, summary| {
+        progress.set((summary.copied + summary.skipped) as u64, summary.bytes);
+    }).await?;
+    dst.commit().await?;
+    progress.finish();
+    println!("copied {} blocks ({} bytes), skipped {} existing blocks", summary.copied, summary.bytes, summary.skipped);
+    Ok(())
+}
+
+// copy the headers of the blocks to a header archive
+pub async fn sync_header_archive(mut src: Box<dyn BlockArchive>, dir: PathBuf, linked: bool, progress: bool) -> Result<()> {
+    let dst = SledHeaderArchive::open(&dir)?.with_chain_linkage(linked);
+    let total = count_blocks(src.as_mut(), progress).await?;
+    let progress = Progress::start(progress, "sync", total, None);
+    let summary = sync::sync_headers(&mut src, &dst, |_, summary| {
+        progress.set((summary.copied + summary.skipped) as u64, 0);
+    }).await?;
+    progress.finish();
+    dst.flush()?;
+    for h in summary.unlinked.iter() {
+        println!("not linked to the chain: {}", h);
+    }
+    println!("copied {} headers, skipped {} existing headers, {} not linked", summary.copied, summary.skipped, summary.unlinked.len());
+    Ok(())
+}
+
+// copy new blocks to the replicas until stopped
+pub async fn replicate_archive(primary: Box<dyn BlockArchive>, replicas: Vec<(String, Box<dyn BlockArchive>)>, journal_dir: &Path,
+                           interval: u64, retries: u32, status_listen: Option<String>) -> Result<()> {
+    let mut replicator = replicate::Replicator::new(primary).with_retries(retries, Duration::from_secs(1))
+        .with_journal_dir(journal_dir);
+    for (name, replica) in replicas {
+        replicator = replicator.with_replica(&name, replica);
+    }
+    if let Some(listen) = status_listen {
+        let listener = tokio::net::TcpListener::bind(&listen).await?;
+        tokio::spawn(replicate::serve_status(replicator.status(), listener));
+    }
+    replicator.run(Duration::from_secs(interval)).await;
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! The exit code of a command and the summary line written when it ends, for scripts and cron
+//! jobs.
+//!
+//! The exit code tells what kind of failure stopped the command, or whether a check found
+//! problems, see [EXIT_CODES_HELP]. When the command ends a summary line is written to stderr,
+//! after anything the command has written to stdout, for example:
+//!
+//!     summary: status=error code=4 command="get" error=block_not_found message="Block not found" elapsed=0.012
+//!
+//! With --output json the summary is a JSON object with the same fields. The status is ok,
+//! problems or error, problems gives the number of problems a check found, and error is the
+//! name of the error, see [bsv_blockarchive::Error::kind], or usage.
+use std::io::Write;
+use std::time::Duration;
+use clap::ArgMatches;
+use serde_json::json;
+use bsv_blockarchive::Error;
+use crate::OutputFormat;
+
+/// The command succeeded.
+pub const OK: i32 = 0;
+/// A check found problems, or the archives compared are different.
+pub const PROBLEMS: i32 = 1;
+/// The command line or the configuration is not valid, the code clap uses for usage errors.
+pub const USAGE: i32 = 2;
+/// The storage, an index or an output file could not be read or written.
+pub const IO: i32 = 3;
+/// The block or transaction was not found.
+pub const NOT_FOUND: i32 = 4;
+/// The archive refused the command, for example because it is locked or read-only.
+pub const REFUSED: i32 = 5;
+/// A node, peer, remote archive or message broker failed.
+pub const REMOTE: i32 = 6;
+/// Data that was read is damaged or not valid.
+pub const INVALID_DATA: i32 = 7;
+/// Any other failure, such as a background task that panicked.
+pub const INTERNAL: i32 = 8;
+
+/// The exit codes, shown after the help.
+pub const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  ok
+  1  a check found problems, or the archives compared are different
+  2  the command line or the configuration is not valid
+  3  the storage, an index or an output file could not be read or written
+  4  the block or transaction was not found
+  5  the archive refused the command, such as a locked or read-only archive
+  6  a node, peer, remote archive or message broker failed
+  7  data that was read is damaged or not valid
+  8  any other failure";
+
+/// What a command found, when it did not fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Nothing wrong was found.
+    Ok,
+    /// A check found this many problems.
+    Problems(usize),
+}
+
+impl Outcome {
+    /// The outcome of a check that found this many problems.
+    pub fn found(problems: usize) -> Outcome {
+        if problems == 0 { Outcome::Ok } else { Outcome::Problems(problems) }
+    }
+}
+
+/// Why a command failed.
+#[derive(Debug)]
+pub enum Failure {
+    /// The command line or the configuration is not valid.
+    Usage(String),
+    /// An error from the archive or the library.
+    Error(Error),
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Failure {
+        Failure::Error(e)
+    }
+}
+
+/// Get the exit code for an error.
+pub fn error_code(e: &Error) -> i32 {
+    match e {
+        Error::InvalidHash(_) | Error::InvalidAttribute(_) | Error::UnknownNetwork(_) | Error::InvalidPolicy(_)
+        | Error::InvalidKey(_) => USAGE,
+        Error::StorageUnavailable(_) | Error::IndexError(_) | Error::FilterError(_) | Error::CatalogError(_)
+        | Error::ExportError(_) | Error::IoError(_) => IO,
+        Error::BlockNotFound | Error::TxNotFound => NOT_FOUND,
+        Error::BlockExists | Error::ArchiveLocked(_) | Error::ReadOnly | Error::ArchiveImmutable
+        | Error::WrongNetwork(_) => REFUSED,
+        Error::PeerError(_) | Error::RemoteError(_) | Error::PublishError(_) => REMOTE,
+        Error::CorruptBlock(_) | Error::ChecksumMismatch(_) | Error::InvalidManifest(_) | Error::InvalidSignature
+        | Error::InvalidArchiveMeta(_) | Error::UnsupportedFormatVersion(_) | Error::InvalidBackup(_)
+        | Error::InvalidBlockFile(_) | Error::InvalidBlock(_) | Error::InvalidPack(_) | Error::InvalidHeaderArchive(_)
+        | Error::InvalidDedupStore(_) | Error::InvalidChunkManifest(_) | Error::MissingParent(_)
+        | Error::InvalidThinBlock(_) | Error::InvalidShardMap(_) | Error::InvalidReport(_)
+        | Error::BitcoinSVError(_) => INVALID_DATA,
+        Error::TaskFailed(_) | Error::TaskPanicked(_) => INTERNAL,
+    }
+}
+
+/// Get the name of the command that was run, with its subcommands, such as "check blocks".
+pub fn command_name(matches: &ArgMatches) -> String {
+    let mut names = Vec::new();
+    let mut m = matches;
+    while let Some((name, sub)) = m.subcommand() {
+        names.push(name);
+        m = sub;
+    }
+    names.join(" ")
+}
+
+/// Write the summary line of a command that has ended, and exit with its code.
+pub fn finish(command: &str, output: OutputFormat, elapsed: Duration, result: std::result::Result<Outcome, Failure>) -> ! {
+    let (code, status, problems, error, message) = match result {
+        Ok(Outcome::Ok) => (OK, "ok", None, None, None),
+        Ok(Outcome::Problems(n)) => (PROBLEMS, "problems", Some(n), None, None),
+        Err(Failure::Usage(msg)) => (USAGE, "error", None, Some("usage"), Some(msg)),
+        Err(Failure::Error(e)) => (error_code(&e), "error", None, Some(e.kind()), Some(e.to_string())),
+    };
+    let elapsed = elapsed.as_secs_f64();
+    // anything the command wrote to stdout comes first
+    let _ = std::io::stdout().flush();
+    match output {
+        OutputFormat::Text => {
+            let mut line = format!("summary: status={} code={} command={:?}", status, code, command);
+            if let Some(n) = problems {
+                line.push_str(&format!(" problems={}", n));
+            }
+            if let Some((error, message)) = error.zip(message) {
+                line.push_str(&format!(" error={} message={:?}", error, message));
+            }
+            eprintln!("{} elapsed={:.3}", line, elapsed);
+        }
+        OutputFormat::Json => {
+            let mut summary = json!({"status": status, "code": code, "command": command, "elapsed": elapsed});
+            if let Some(n) = problems {
+                summary["problems"] = json!(n);
+            }
+            if let Some((error, message)) = error.zip(message) {
+                summary["error"] = json!(error);
+                summary["message"] = json!(message);
+            }
+            eprintln!("{}", summary);
+        }
+    }
+    std::process::exit(code)
+}
@@ -0,0 +1,282 @@
+//! The commands that write the blocks of an archive or facts about them to files: exports,
+//! checksums, manifests and backups, and the restore of a backup.
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use bitcoinsv::bitcoin::BlockHash;
+use clap::Subcommand;
+use serde_json::json;
+use bsv_blockarchive::{backup, blkdat, checksums, diff, stats, BlockArchive, ChainIndex, Manifest, Network, SimpleFileBasedBlockArchive, Result, Error};
+use bsv_blockarchive::parquet_export;
+use bsv_blockarchive::prefetch::PrefetchingBlockArchive;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+use crate::{emit, parse_hex, OutputFormat};
+use crate::exit::Outcome;
+use crate::blocks::{parse_short_hash, select_blocks, ShortHash};
+
+#[derive(Subcommand, Debug)]
+pub enum ExportCommands {
+    /// Write the blocks to "blocks.parquet" and the transactions in them to
+    /// "transactions.parquet", in the output directory.
+    ///
+    /// Every block in the archive is exported unless hashes or a range of heights are given.
+    Parquet {
+        /// A file of block hashes to export, one per line.
+        #[clap(long)]
+        hashes: Option<PathBuf>,
+        /// Export the blocks in the best chain from this height.
+        #[clap(long, requires = "to")]
+        from: Option<u64>,
+        /// Export the blocks in the best chain up to and including this height.
+        #[clap(long, requires = "from")]
+        to: Option<u64>,
+        /// The output directory.
+        #[clap(short = 'o', long)]
+        out: PathBuf,
+        /// Block hashes to export.
+        #[clap(value_parser = parse_short_hash)]
+        block_hashes: Vec<ShortHash>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ManifestCommands {
+    /// Write a manifest of all the blocks in the archive.
+    ///
+    /// The shard directories of a simple archive are read in parallel, other archives are read
+    /// one block at a time.
+    Build {
+        /// The number of shard directories to read at the same time.
+        #[clap(short, long, default_value = "8")]
+        jobs: usize,
+        /// Sign the manifest with the Ed25519 key in this PKCS#8 DER file, such as one made by
+        /// "openssl genpkey -algorithm ed25519 -outform DER". The hex encoded signature is
+        /// written next to the manifest, with a ".sig" extension.
+        #[clap(long)]
+        sign_key: Option<PathBuf>,
+        /// The manifest file.
+        out: PathBuf,
+    },
+    /// Check that every block in a manifest is in the archive with the same size and checksum.
+    ///
+    /// Blocks in the archive that are not in the manifest are ignored.
+    Verify {
+        /// The number of blocks to check at the same time.
+        #[clap(short, long, default_value = "8")]
+        jobs: usize,
+        /// Check the signature of the manifest, in the file next to it with a ".sig" extension,
+        /// against this hex encoded Ed25519 public key before checking the blocks.
+        #[clap(long, value_parser = parse_hex)]
+        public_key: Option<Vec<u8>>,
+        /// The manifest file.
+        file: PathBuf,
+    },
+}
+
+// write blocks to .bin files, or to a blk*.dat style file for the given network
+pub async fn export_blocks(mut archive: Box<dyn BlockArchive>, block_hashes: Vec<BlockHash>, hashes: Option<PathBuf>,
+                       heights: Option<(u64, u64)>, out: PathBuf, blkdat: Option<Network>, verbose: bool) -> Result<()> {
+    let block_hashes = select_blocks(archive.as_mut(), block_hashes, hashes, heights).await?;
+    let mut bytes = 0;
+    if let Some(network) = blkdat {
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&out).await?);
+        for h in block_hashes.iter() {
+            bytes += blkdat::write_block(&archive, h, network, &mut file).await?;
+            if verbose { println!("exported block {}", h); }
+        }
+        file.flush().await?;
+    } else {
+        tokio::fs::create_dir_all(&out).await?;
+        for h in block_hashes.iter() {
+            let mut reader = archive.get_block(h).await?;
+            let mut file = tokio::fs::File::create(out.join(format!("{}.bin", h))).await?;
+            bytes += tokio::io::copy(&mut reader, &mut file).await?;
+            if verbose { println!("exported block {}", h); }
+        }
+    }
+    println!("exported {} blocks ({} bytes)", block_hashes.len(), bytes);
+    Ok(())
+}
+
+// compare the blocks in two archives
+pub async fn compare_archives(mut archive: Box<dyn BlockArchive>, mut other: Box<dyn BlockArchive>, checksums: bool, output: OutputFormat) -> Result<Outcome> {
+    let diff = diff::diff_archives(archive.as_mut(), other.as_mut(), checksums).await?;
+    let lines = [("<", "only_in_a", &diff.only_in_a), (">", "only_in_b", &diff.only_in_b), ("!", "different", &diff.different)];
+    for (marker, status, block_hashes) in lines {
+        for h in block_hashes.iter() {
+            emit(output, format!("{} {}", marker, h), json!({"block_hash": h.to_string(), "status": status}));
+        }
+    }
+    emit(output, format!("{} blocks the same, {} only in this archive, {} only in the other archive, {} different",
+                         diff.same, diff.only_in_a.len(), diff.only_in_b.len(), diff.different.len()),
+         json!({"same": diff.same, "only_in_a": diff.only_in_a.len(), "only_in_b": diff.only_in_b.len(), "different": diff.different.len()}));
+    Ok(Outcome::found(diff.only_in_a.len() + diff.only_in_b.len() + diff.different.len()))
+}
+
+// export the data of blocks to parquet files, every block in the archive if none are selected
+pub async fn export_parquet(mut archive: Box<dyn BlockArchive>, block_hashes: Vec<BlockHash>, hashes: Option<PathBuf>,
+                        heights: Option<(u64, u64)>, out: PathBuf, options: parquet_export::ParquetOptions, prefetch: usize) -> Result<()> {
+    let block_hashes = select_exported_blocks(archive.as_mut(), block_hashes, hashes, heights).await?;
+    let chain = ChainIndex::build(&mut archive).await?;
+    let archive = PrefetchingBlockArchive::new(archive, prefetch);
+    archive.prefetch(block_hashes.clone());
+    let blocks = block_hashes.into_iter().map(|h| (h, chain.height_of(&h)));
+    let summary = parquet_export::export_parquet(&archive, blocks, &out, &options).await?;
+    if options.transactions {
+        println!("exported {} blocks and {} transactions to {}", summary.blocks, summary.transactions, out.display());
+    } else {
+        println!("exported {} blocks to {}", summary.blocks, out.display());
+    }
+    Ok(())
+}
+
+// export the statistics of blocks to a CSV or TSV file, every block in the archive if none are selected
+pub async fn export_stats_csv(mut archive: Box<dyn BlockArchive>, block_hashes: Vec<BlockHash>, hashes: Option<PathBuf>,
+                          heights: Option<(u64, u64)>, out: PathBuf, options: stats::CsvOptions) -> Result<()> {
+    let block_hashes = select_exported_blocks(archive.as_mut(), block_hashes, hashes, heights).await?;
+    let chain = ChainIndex::build(&mut archive).await?;
+    let blocks = block_hashes.into_iter().map(|h| (h, chain.height_of(&h)));
+    if out.as_os_str() == "-" {
+        stats::export_csv(&archive, blocks, &mut tokio::io::stdout(), &options).await?;
+    } else {
+        let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&out).await?);
+        let written = stats::export_csv(&archive, blocks, &mut file, &options).await?;
+        println!("exported {} blocks to {}", written, out.display());
+    }
+    Ok(())
+}
+
+// get the blocks to export, every block in the archive if none are selected
+async fn select_exported_blocks(archive: &mut dyn BlockArchive, block_hashes: Vec<BlockHash>, hashes: Option<PathBuf>,
+                                heights: Option<(u64, u64)>) -> Result<Vec<BlockHash>> {
+    let all = block_hashes.is_empty() && hashes.is_none() && heights.is_none();
+    let mut block_hashes = select_blocks(archive, block_hashes, hashes, heights).await?;
+    if all {
+        let mut results = archive.block_list().await?;
+        while let Some(h) = results.next().await {
+            block_hashes.push(h);
+        }
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+    }
+    Ok(block_hashes)
+}
+
+// write a checksum manifest to a file or stdout
+pub async fn export_checksums(root_dir: PathBuf, out: Option<PathBuf>) -> Result<()> {
+    let mut archive= SimpleFileBasedBlockArchive::new(root_dir).await?;
+    match out {
+        Some(path) => {
+            let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(path).await?);
+            checksums::export_checksums(&mut archive, &mut file).await?;
+        }
+        None => {
+            checksums::export_checksums(&mut archive, &mut tokio::io::stdout()).await?;
+        }
+    }
+    Ok(())
+}
+
+// verify the block files against a checksum manifest
+pub async fn verify_checksums(root_dir: PathBuf, manifest: PathBuf) -> Result<Outcome> {
+    let manifest = tokio::fs::read_to_string(manifest).await?;
+    let report = checksums::verify_checksums(&root_dir, &manifest).await?;
+    for path in report.failed.iter() {
+        println!("{}: FAILED", path.display());
+    }
+    for path in report.missing.iter() {
+        println!("{}: MISSING", path.display());
+    }
+    println!("{} files ok, {} failed, {} missing", report.ok, report.failed.len(), report.missing.len());
+    Ok(Outcome::found(report.failed.len() + report.missing.len()))
+}
+
+// the file next to a manifest that has its signature
+fn signature_path(manifest_path: &Path) -> PathBuf {
+    let mut p = manifest_path.as_os_str().to_owned();
+    p.push(".sig");
+    PathBuf::from(p)
+}
+
+// write a manifest, and its signature next to it if there is a key
+pub async fn write_manifest(manifest: &Manifest, out: PathBuf, sign_key: Option<PathBuf>, output: OutputFormat) -> Result<()> {
+    tokio::fs::write(&out, manifest.to_string()).await?;
+    if let Some(key) = sign_key {
+        let signature = manifest.sign(&tokio::fs::read(key).await?)?;
+        tokio::fs::write(signature_path(&out), format!("{}\n", hex::encode(signature))).await?;
+    }
+    emit(output, format!("{} blocks in the manifest", manifest.entries.len()),
+         json!({"blocks": manifest.entries.len(), "file": out.display().to_string()}));
+    Ok(())
+}
+
+// check the archive against a manifest, after its signature if there is a public key
+pub async fn verify_manifest(archive: Box<dyn BlockArchive>, file: PathBuf, public_key: Option<Vec<u8>>, jobs: usize,
+                         output: OutputFormat) -> Result<Outcome> {
+    let manifest = Manifest::from_str(&tokio::fs::read_to_string(&file).await?)?;
+    if let Some(public_key) = public_key {
+        let signature = tokio::fs::read_to_string(signature_path(&file)).await?;
+        let signature = hex::decode(signature.trim())
+            .map_err(|_| Error::InvalidManifest("invalid signature file".to_string()))?;
+        if manifest.verify_signature(&public_key, &signature).is_err() {
+            emit(output, "ERROR: the signature is not valid".to_string(), json!({"valid": false}));
+            return Ok(Outcome::Problems(1));
+        }
+    }
+    let report = manifest.verify_parallel(archive.as_ref(), jobs).await?;
+    for block_hash in report.missing.iter() {
+        emit(output, format!("{}: MISSING", block_hash), json!({"block_hash": block_hash.to_string(), "status": "missing"}));
+    }
+    for block_hash in report.mismatched.iter() {
+        emit(output, format!("{}: FAILED", block_hash), json!({"block_hash": block_hash.to_string(), "status": "failed"}));
+    }
+    emit(output, format!("{} blocks ok, {} failed, {} missing", report.ok, report.mismatched.len(), report.missing.len()),
+         json!({"ok": report.ok, "failed": report.mismatched.len(), "missing": report.missing.len()}));
+    Ok(Outcome::found(report.mismatched.len() + report.missing.len()))
+}
+
+// write a backup set, and the manifest of the archive next to it
+pub async fn backup_archive(mut archive: Box<dyn BlockArchive>, file: PathBuf, base: Option<PathBuf>, manifest_path: Option<PathBuf>) -> Result<()> {
+    let base = match base {
+        Some(p) => Some(Manifest::from_str(&tokio::fs::read_to_string(p).await?)?),
+        None => None,
+    };
+    let to_stdout = file.as_os_str() == "-";
+    // the manifest file is required when the backup set is written to stdout
+    let manifest_path = manifest_path.unwrap_or_else(|| {
+        let mut p = file.clone().into_os_string();
+        p.push(".manifest");
+        PathBuf::from(p)
+    });
+    let (manifest, summary) = if to_stdout {
+        backup::backup(&mut archive, tokio::io::stdout(), base.as_ref()).await?
+    } else {
+        let writer = tokio::fs::File::create(&file).await?;
+        backup::backup(&mut archive, writer, base.as_ref()).await?
+    };
+    tokio::fs::write(manifest_path, manifest.to_string()).await?;
+    let message = format!("backed up {} blocks ({} bytes), skipped {} blocks", summary.blocks, summary.bytes, summary.skipped);
+    if to_stdout {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+    Ok(())
+}
+
+// restore the blocks in a backup set
+pub async fn restore_archive(archive: Box<dyn BlockArchive>, file: PathBuf) -> Result<()> {
+    let summary = if file.as_os_str() == "-" {
+        backup::restore(&archive, tokio::io::BufReader::new(tokio::io::stdin())).await?
+    } else {
+        let reader = tokio::io::BufReader::new(tokio::fs::File::open(file).await?);
+        backup::restore(&archive, reader).await?
+    };
+    println!("restored {} blocks ({} bytes), skipped {} existing blocks", summary.blocks, summary.bytes, summary.skipped);
+    if !summary.missing.is_empty() {
+        println!("{} blocks in the manifest of the backup set are not in the archive, restore the earlier backup sets first",
+                 summary.missing.len());
+    }
+    Ok(())
+}
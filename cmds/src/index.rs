@@ -0,0 +1,141 @@
+//! The index, filters and tx commands, which build and query the transaction index and the
+//! block filters.
+use std::path::PathBuf;
+use bitcoinsv::bitcoin::BlockHash;
+use clap::Subcommand;
+use serde_json::{json, Value};
+use bsv_blockarchive::{BlockArchive, IndexedBlockArchive, Transaction, TxIndex, Result, Error};
+use bsv_blockarchive::filters::FilterIndex;
+use crate::{emit, parse_hex, OutputFormat};
+use crate::blocks::{parse_short_hash, ShortHash};
+
+#[derive(Subcommand, Debug)]
+pub enum FiltersCommands {
+    /// Compute the filter of every block in the archive that does not have one.
+    Build,
+    /// Print the filter of a block, hex encoded.
+    Get {
+        /// Block hash.
+        #[clap(value_parser = parse_short_hash)]
+        block_hash: ShortHash,
+    },
+    /// List the blocks whose filters match any of the items, which are hex encoded output scripts
+    /// or spent outpoints. A few of the blocks listed may not contain any of the items.
+    Match {
+        /// Hex encoded items.
+        #[clap(required = true, value_parser = parse_hex)]
+        items: Vec<Vec<u8>>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IndexCommands {
+    /// Add every block in the archive that is not yet indexed to the transaction index.
+    Build,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TxCommands {
+    /// Check whether a transaction is in the index, exiting with status 4 if it is not.
+    Exists {
+        /// Transaction id.
+        txid: BlockHash,
+    },
+    /// Get a transaction, hex encoded or decoded.
+    Get {
+        /// Print the transaction hex encoded, this is the default.
+        #[clap(long, conflicts_with = "json")]
+        hex: bool,
+        /// Print the decoded transaction as JSON.
+        #[clap(long)]
+        json: bool,
+        /// Transaction id.
+        txid: BlockHash,
+    },
+}
+
+// index every block that has not been indexed yet
+pub async fn build_index(mut archive: Box<dyn BlockArchive>, index_dir: PathBuf) -> Result<()> {
+    let index = TxIndex::open(&index_dir)?;
+    let count = index.build(&mut archive).await?;
+    println!("indexed {} blocks, {} blocks in index", count, index.num_blocks());
+    Ok(())
+}
+
+// compute the filters of the blocks that do not have one
+pub async fn build_filters(mut archive: Box<dyn BlockArchive>, filters_dir: PathBuf) -> Result<()> {
+    let filters = FilterIndex::open(&filters_dir)?;
+    let count = filters.build(&mut archive).await?;
+    println!("computed {} filters, {} blocks have filters", count, filters.num_blocks());
+    Ok(())
+}
+
+// print the filter of a block
+pub async fn get_filter(filters_dir: PathBuf, block_hash: BlockHash) -> Result<()> {
+    match FilterIndex::open(&filters_dir)?.block_filter(&block_hash)? {
+        Some(filter) => println!("{}", hex::encode(filter.to_bytes())),
+        None => println!("No filter for block {}", block_hash),
+    }
+    Ok(())
+}
+
+// list the blocks whose filters match any of the items
+pub async fn match_filters(filters_dir: PathBuf, items: Vec<Vec<u8>>) -> Result<()> {
+    for block_hash in FilterIndex::open(&filters_dir)?.scan(&items)? {
+        println!("{}", block_hash);
+    }
+    Ok(())
+}
+
+// get a transaction using the index
+pub async fn get_transaction(archive: Box<dyn BlockArchive>, index_dir: PathBuf, txid: BlockHash, decode: bool, output: OutputFormat) -> Result<()> {
+    let archive = IndexedBlockArchive::new(archive, TxIndex::open(&index_dir)?);
+    print_transaction(&archive, txid, decode, output).await
+}
+
+pub async fn print_transaction<A: BlockArchive>(archive: &IndexedBlockArchive<A>, txid: BlockHash, decode: bool, output: OutputFormat) -> Result<()> {
+    if decode {
+        let tx = archive.get_decoded_transaction(&txid).await?;
+        println!("{}", transaction_json(&tx));
+        return Ok(());
+    }
+    let tx = hex::encode(archive.get_transaction(&txid).await?);
+    emit(output, tx.clone(), json!({"txid": txid.to_string(), "hex": tx}));
+    Ok(())
+}
+
+// the JSON record of a decoded transaction, scripts are hex encoded
+fn transaction_json(tx: &Transaction) -> Value {
+    let inputs: Vec<Value> = tx.inputs.iter().map(|i| json!({
+        "prev_txid": i.prev_txid.to_string(),
+        "prev_index": i.prev_index,
+        "script": hex::encode(&i.script),
+        "sequence": i.sequence,
+    })).collect();
+    let outputs: Vec<Value> = tx.outputs.iter().map(|o| json!({
+        "value": o.value,
+        "script": hex::encode(&o.script),
+    })).collect();
+    json!({
+        "txid": tx.txid.to_string(),
+        "version": tx.version,
+        "inputs": inputs,
+        "outputs": outputs,
+        "lock_time": tx.lock_time,
+    })
+}
+
+// check whether a transaction is in the index, failing with TxNotFound if it is not
+pub fn transaction_exists(index_dir: PathBuf, txid: BlockHash, output: OutputFormat) -> Result<()> {
+    match TxIndex::open(&index_dir)?.get(&txid)? {
+        Some(l) => {
+            emit(output, format!("transaction {} is in block {}", txid, l.block_hash),
+                 json!({"txid": txid.to_string(), "exists": true, "block_hash": l.block_hash.to_string()}));
+            Ok(())
+        }
+        None => {
+            emit(output, format!("transaction {} not found", txid), json!({"txid": txid.to_string(), "exists": false}));
+            Err(Error::TxNotFound)
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! The commands that keep an archive in shape: rebuilding the headers file and the catalog,
+//! compressing, pruning and collecting garbage, moving blocks between tiers, packs and shards,
+//! and thinning blocks.
+use std::path::PathBuf;
+use bitcoinsv::bitcoin::BlockHash;
+use clap::Subcommand;
+use serde_json::json;
+use bsv_blockarchive::{fetch, gc, sync, thin, tier, BlockArchive, ChainIndex, LayeredBlockArchive, Network, PackedBlockArchive, ShardMap, ShardedFileBlockArchive, SimpleFileBasedBlockArchive, WritePolicy, Result, Error};
+use bsv_blockarchive::catalog::Catalog;
+use bsv_blockarchive::thin::ThinBlockStore;
+use bsv_blockarchive::tier::TierPolicy;
+use tokio_stream::StreamExt;
+use crate::{emit, ArchiveType, OutputFormat, Progress};
+use crate::blocks::{count_blocks, parse_short_hash, read_hashes, ShortHash};
+
+#[derive(Subcommand, Debug)]
+pub enum CatalogCommands {
+    /// Write the catalog from the blocks in the archive, creating it if necessary.
+    ///
+    /// This takes the write lock, so no other process that locks the archive can be writing to
+    /// it.
+    Rebuild {
+        /// Read every block in full to record its checksum.
+        #[clap(long)]
+        checksums: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HeadersCommands {
+    /// Write the headers file from the headers of all the blocks, creating it if necessary.
+    ///
+    /// Once the file exists it is kept up to date as blocks are stored and removed. This takes
+    /// the write lock, so no other process that locks the archive can be writing to it.
+    Rebuild,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ThinCommands {
+    /// Replace the given blocks in the archive with their thin blocks.
+    Blocks {
+        /// A file of block hashes to thin, one per line.
+        #[clap(long)]
+        hashes: Option<PathBuf>,
+        /// Block hashes.
+        #[clap(value_parser = parse_short_hash)]
+        block_hashes: Vec<ShortHash>,
+    },
+    /// List the thinned blocks.
+    List,
+    /// Fetch thinned blocks from an SV Node and store them in the archive again, all of them if
+    /// no block hashes are given.
+    Rehydrate {
+        /// The address of the node, "host:port".
+        #[clap(short = 'p', long)]
+        peer: String,
+        /// The network of the node. Defaults to the network in the configuration file, or mainnet.
+        #[clap(short = 'n', long)]
+        network: Option<Network>,
+        /// Block hashes.
+        block_hashes: Vec<BlockHash>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TierCommands {
+    /// Move the blocks selected by a policy from this archive to a cold archive, checking each
+    /// copy before removing the original.
+    Run {
+        /// Which blocks to move: "height:N" for blocks in the best chain below height N, or
+        /// "age:N" for blocks with a timestamp more than N days ago.
+        #[clap(long)]
+        policy: TierPolicy,
+        /// Only report which blocks would be moved.
+        #[clap(long, default_value = "false")]
+        dry_run: bool,
+        /// The type of the cold archive.
+        #[clap(long, value_enum, default_value = "simple")]
+        cold_type: ArchiveType,
+        /// The root of the cold archive, "bucket/prefix" for an archive in an object store.
+        cold_root: String,
+    },
+}
+
+// compress or decompress every block in the archive
+// write the headers file of a simple archive
+pub async fn rebuild_headers(root_dir: PathBuf) -> Result<()> {
+    let mut archive = SimpleFileBasedBlockArchive::new(root_dir).await?;
+    let count = archive.rebuild_headers().await?;
+    println!("wrote {} headers", count);
+    Ok(())
+}
+
+// rebuild the catalog of a local archive from the blocks in it
+pub async fn rebuild_catalog(mut archive: Box<dyn BlockArchive>, catalog: Catalog, checksums: bool) -> Result<()> {
+    let count = catalog.rebuild(archive.as_mut(), checksums).await?;
+    println!("wrote {} blocks to the catalog", count);
+    Ok(())
+}
+
+pub async fn compress_archive(root_dir: PathBuf, compress: bool, verbose: bool) -> Result<()> {
+    let mut archive= SimpleFileBasedBlockArchive::new(root_dir).await?;
+    // new blocks are stored the same way from now on
+    archive.set_compression(compress).await?;
+    let mut block_it = archive.block_list().await?;
+    let mut changed = 0;
+    let mut before = 0;
+    let mut after = 0;
+    while let Some(block_hash) = block_it.next().await {
+        before += archive.block_disk_size(&block_hash).await?;
+        let r = if compress {
+            archive.compress_block(&block_hash).await?
+        } else {
+            archive.decompress_block(&block_hash).await?
+        };
+        after += archive.block_disk_size(&block_hash).await?;
+        if r {
+            changed += 1;
+            if verbose { println!("{} block {}", if compress { "compressed" } else { "decompressed" }, block_hash); }
+        }
+    }
+    if let Some(e) = block_it.as_mut().take_error() {
+        return Err(e);
+    }
+    println!("{} blocks changed, disk usage {} bytes before, {} bytes after", changed, before, after);
+    Ok(())
+}
+
+// remove or quarantine the blocks of forks that are deep below the best tip
+pub async fn collect_garbage(mut archive: Box<dyn BlockArchive>, depth: u64, quarantine: Option<PathBuf>, dry_run: bool,
+                         output: OutputFormat) -> Result<()> {
+    let quarantine = match quarantine {
+        Some(dir) => {
+            tokio::fs::create_dir_all(&dir).await?;
+            Some(SimpleFileBasedBlockArchive::new(dir).await?)
+        }
+        None => None,
+    };
+    let chain = ChainIndex::build(&mut archive).await?;
+    let summary = gc::collect_garbage(archive.as_mut(), &chain, depth, quarantine.as_ref().map(|q| q as &dyn BlockArchive), dry_run).await?;
+    let verb = match (dry_run, quarantine.is_some()) {
+        (true, true) => "would quarantine",
+        (true, false) => "would remove",
+        (false, true) => "quarantined",
+        (false, false) => "removed",
+    };
+    for h in summary.collected.iter() {
+        emit(output, format!("{} block {}", verb, h), json!({"block_hash": h.to_string(), "status": verb}));
+    }
+    emit(output, format!("{} {} blocks ({} bytes), kept {} blocks of recent forks", verb, summary.collected.len(), summary.bytes, summary.kept),
+         json!({"collected": summary.collected.len(), "bytes": summary.bytes, "kept": summary.kept}));
+    Ok(())
+}
+
+// remove blocks given by hash, by a range of heights, or that are not in the best chain
+pub async fn prune(mut archive: Box<dyn BlockArchive>, mut block_hashes: Vec<BlockHash>, hashes: Option<PathBuf>,
+               heights: Option<(u64, u64)>, stale: bool, dry_run: bool) -> Result<()> {
+    if let Some(path) = hashes {
+        block_hashes.extend(read_hashes(path).await?);
+    }
+    if heights.is_some() || stale {
+        let chain = ChainIndex::build(&mut archive).await?;
+        if let Some((from, to)) = heights {
+            for height in from..=to {
+                match chain.block_by_height(height) {
+                    Some(h) => block_hashes.push(h),
+                    None => break,
+                }
+            }
+        }
+        if stale {
+            let mut block_it = archive.block_list().await?;
+            while let Some(h) = block_it.next().await {
+                if chain.height_of(&h).is_some() && !chain.is_in_best_chain(&h) {
+                    block_hashes.push(h);
+                }
+            }
+            if let Some(e) = block_it.as_mut().take_error() {
+                return Err(e);
+            }
+        }
+    }
+    let mut removed = 0;
+    for h in block_hashes.iter() {
+        if dry_run {
+            if archive.block_exists(h).await? {
+                println!("would remove block {}", h);
+                removed += 1;
+            }
+            continue;
+        }
+        match archive.remove_block(h).await {
+            Ok(_) => {
+                println!("removed block {}", h);
+                removed += 1;
+            }
+            Err(Error::BlockNotFound) => println!("Block not found {}", h),
+            Err(e) => return Err(e),
+        }
+    }
+    println!("{} {} blocks", if dry_run { "would remove" } else { "removed" }, removed);
+    Ok(())
+}
+
+// replace blocks with their thin blocks
+pub async fn thin_blocks(archive: Box<dyn BlockArchive>, thin_dir: PathBuf, block_hashes: Vec<BlockHash>) -> Result<()> {
+    let store = ThinBlockStore::open(&thin_dir)?;
+    for h in block_hashes {
+        match thin::thin_block(archive.as_ref(), &store, &h).await {
+            Ok(t) => println!("thinned block {}, {} transactions", h, t.txids.len()),
+            Err(Error::BlockNotFound) => println!("block not found: {}", h),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// list the thinned blocks
+pub async fn list_thin_blocks(thin_dir: PathBuf) -> Result<()> {
+    for h in ThinBlockStore::open(&thin_dir)?.block_hashes()? {
+        println!("{}", h);
+    }
+    Ok(())
+}
+
+// fetch thinned blocks from a node
+pub async fn rehydrate_blocks(archive: Box<dyn BlockArchive>, thin_dir: PathBuf, peer: String, network: Network, mut block_hashes: Vec<BlockHash>) -> Result<()> {
+    let store = ThinBlockStore::open(&thin_dir)?;
+    if block_hashes.is_empty() {
+        block_hashes = store.block_hashes()?;
+    }
+    let mut fetcher = fetch::P2PFetcher::connect(peer, network).await?;
+    for h in block_hashes {
+        match thin::rehydrate_block(archive.as_ref(), &store, &mut fetcher, &h).await {
+            Ok(true) => println!("rehydrated block {}", h),
+            Ok(false) => println!("peer does not have block {}", h),
+            Err(Error::BlockNotFound) => println!("block is not thinned: {}", h),
+            Err(e) => return Err(e),
+        }
+    }
+    store.flush().await?;
+    Ok(())
+}
+
+// move blocks selected by the policy from the hot archive to the cold archive
+pub async fn tier_run(hot: Box<dyn BlockArchive>, cold: Box<dyn BlockArchive>, policy: TierPolicy, dry_run: bool, verbose: bool) -> Result<()> {
+    // heights are taken from both archives, as the lower blocks may already be in the cold archive
+    let mut archive = LayeredBlockArchive::new(vec![hot, cold]);
+    let chain = ChainIndex::build(&mut archive).await?;
+    let (hot, cold) = archive.layers_mut().split_at_mut(1);
+    let verb = if dry_run { "would move" } else { "moved" };
+    let summary = tier::tier_blocks(&mut hot[0], &cold[0], &chain, &policy, dry_run, |block_hash, summary| {
+        if verbose {
+            println!("{} {}", verb, block_hash);
+        } else if summary.moved % 1000 == 0 {
+            println!("{} {} blocks ({} bytes)", verb, summary.moved, summary.bytes);
+        }
+    }).await?;
+    println!("checked {} blocks, {} {} blocks ({} bytes)", summary.checked, verb, summary.moved, summary.bytes);
+    Ok(())
+}
+
+// move the blocks of a sharded archive to the shards they belong in
+pub async fn rebalance(map_file: PathBuf, write_policy: WritePolicy, dry_run: bool, verbose: bool) -> Result<()> {
+    let map = ShardMap::read(&map_file).await?;
+    let mut archive = ShardedFileBlockArchive::new(map).await?.with_write_policy(write_policy);
+    let verb = if dry_run { "would move" } else { "moved" };
+    let summary = archive.rebalance(dry_run, |block_hash, summary| {
+        if verbose {
+            println!("{} {}", verb, block_hash);
+        } else if summary.moved % 1000 == 0 {
+            println!("{} {} blocks ({} bytes)", verb, summary.moved, summary.bytes);
+        }
+    }).await?;
+    println!("checked {} blocks, {} {} blocks ({} bytes)", summary.checked, verb, summary.moved, summary.bytes);
+    Ok(())
+}
+
+// copy all the blocks in the archive into a packed archive
+pub async fn repack(mut src: Box<dyn BlockArchive>, dest_dir: PathBuf, max_pack_size: u64, progress: bool) -> Result<()> {
+    let dst = PackedBlockArchive::new(dest_dir).await?.with_max_pack_size(max_pack_size);
+    let total = count_blocks(src.as_mut(), progress).await?;
+    let progress = Progress::start(progress, "repack", total, None);
+    let summary = sync::sync_archives(&mut src, &dst, |_, summary| {
+        progress.set((summary.copied + summary.skipped) as u64, summary.bytes);
+    }).await?;
+    progress.finish();
+    println!("packed {} blocks ({} bytes), skipped {} existing blocks", summary.copied, summary.bytes, summary.skipped);
+    Ok(())
+}
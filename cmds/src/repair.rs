@@ -0,0 +1,150 @@
+//! The quarantine commands, which move damaged blocks out of the archive and repair them from
+//! another archive or a node.
+use std::path::PathBuf;
+use bitcoinsv::bitcoin::BlockHash;
+use clap::Subcommand;
+use serde_json::json;
+use bsv_blockarchive::{fetch, quarantine, BlockArchive, Network, SimpleFileBasedBlockArchive, Result, Error};
+use bsv_blockarchive::verify::CheckFailure;
+use crate::{emit, emit_failure, emit_ok, open_archive, ArchiveOptions, ArchiveType, OutputFormat};
+use crate::exit::Outcome;
+use crate::blocks::{parse_short_hash, ShortHash};
+
+#[derive(Subcommand, Debug)]
+pub enum QuarantineCommands {
+    /// Move blocks to the quarantine archive.
+    Add {
+        /// The reason the blocks are quarantined.
+        #[clap(long, default_value = "corrupt")]
+        reason: String,
+        /// Block hashes.
+        #[clap(required = true, value_parser = parse_short_hash)]
+        block_hashes: Vec<ShortHash>,
+    },
+    /// List the quarantined blocks, with the reason and time each was quarantined.
+    List,
+}
+
+// open the quarantine archive, creating it if necessary
+async fn open_quarantine(dir: PathBuf) -> Result<SimpleFileBasedBlockArchive> {
+    tokio::fs::create_dir_all(&dir).await?;
+    SimpleFileBasedBlockArchive::new(dir).await
+}
+
+// move the blocks that failed a check to the quarantine archive, with the failure as the reason
+pub async fn quarantine_failures(archive: &dyn BlockArchive, dir: PathBuf, failures: &[CheckFailure], output: OutputFormat) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    let quarantine_archive = open_quarantine(dir).await?;
+    for failure in failures {
+        let h = failure.block_hash;
+        match quarantine::quarantine_block(archive, &quarantine_archive, &h, &failure.message).await {
+            Ok(_) => emit(output, format!("quarantined block {}", h), json!({"block_hash": h.to_string(), "status": "quarantined"})),
+            Err(e) => emit(output, format!("ERROR: could not quarantine block {}: {}", h, e),
+                           json!({"block_hash": h.to_string(), "status": "error", "message": e.to_string()})),
+        }
+    }
+    Ok(())
+}
+
+// move blocks to the quarantine archive
+pub async fn quarantine_blocks(archive: Box<dyn BlockArchive>, dir: PathBuf, block_hashes: Vec<BlockHash>, reason: String, output: OutputFormat) -> Result<()> {
+    let quarantine_archive = open_quarantine(dir).await?;
+    for h in block_hashes {
+        quarantine::quarantine_block(archive.as_ref(), &quarantine_archive, &h, &reason).await?;
+        emit(output, format!("quarantined block {}", h), json!({"block_hash": h.to_string(), "status": "quarantined"}));
+    }
+    Ok(())
+}
+
+// list the quarantined blocks with the reason each was quarantined
+pub async fn list_quarantined(dir: PathBuf, output: OutputFormat) -> Result<()> {
+    if !tokio::fs::try_exists(&dir).await? {
+        return Ok(());
+    }
+    let mut quarantine_archive = SimpleFileBasedBlockArchive::new(dir).await?;
+    for q in quarantine::quarantined_blocks(&mut quarantine_archive).await? {
+        let reason = q.reason.unwrap_or_default();
+        emit(output, format!("{} {} {}", q.block_hash, q.time.map_or("-".to_string(), |t| t.to_string()), reason),
+             json!({"block_hash": q.block_hash.to_string(), "time": q.time, "reason": reason}));
+    }
+    Ok(())
+}
+
+// where the repair command copies good blocks from
+pub enum RepairFrom {
+    Peer(fetch::P2PFetcher),
+    Archive(Box<dyn BlockArchive>),
+}
+
+impl RepairFrom {
+    // connect to the node given as "p2p://host:port", or open the archive
+    pub async fn open(source: &str, source_type: &ArchiveType, network: Network, options: &ArchiveOptions) -> Result<RepairFrom> {
+        match source.strip_prefix("p2p://") {
+            Some(peer) => Ok(RepairFrom::Peer(fetch::P2PFetcher::connect(peer, network).await?)),
+            None => Ok(RepairFrom::Archive(open_archive(source_type, source, options).await?)),
+        }
+    }
+
+    fn as_source(&mut self) -> quarantine::RepairSource<'_> {
+        match self {
+            RepairFrom::Peer(f) => quarantine::RepairSource::Peer(f),
+            RepairFrom::Archive(a) => quarantine::RepairSource::Archive(&**a),
+        }
+    }
+}
+
+// replace quarantined blocks with copies from a peer or another archive
+pub async fn repair_blocks(archive: Box<dyn BlockArchive>, dir: PathBuf, mut source: RepairFrom, mut block_hashes: Vec<BlockHash>,
+                       output: OutputFormat) -> Result<()> {
+    let mut quarantine_archive = open_quarantine(dir).await?;
+    if block_hashes.is_empty() {
+        block_hashes = quarantine::quarantined_blocks(&mut quarantine_archive).await?.into_iter().map(|q| q.block_hash).collect();
+    }
+    for h in block_hashes {
+        if !quarantine_archive.block_exists(&h).await? {
+            emit(output, format!("block is not quarantined: {}", h), json!({"block_hash": h.to_string(), "status": "not_quarantined"}));
+            continue;
+        }
+        let r = match &mut source {
+            RepairFrom::Peer(f) => quarantine::repair_block_from_peer(archive.as_ref(), &quarantine_archive, f, &h).await,
+            RepairFrom::Archive(a) => quarantine::repair_block(archive.as_ref(), &quarantine_archive, a.as_ref(), &h).await,
+        };
+        match r {
+            Ok(true) => emit(output, format!("repaired block {}", h), json!({"block_hash": h.to_string(), "status": "repaired"})),
+            Ok(false) => emit(output, format!("no copy of block {}", h), json!({"block_hash": h.to_string(), "status": "unavailable"})),
+            Err(Error::InvalidBlock(msg)) => emit(output, format!("ERROR: the copy of block {} is bad: {}", h, msg),
+                                                  json!({"block_hash": h.to_string(), "status": "error", "message": msg})),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// check every block and replace the damaged ones with copies from a peer or another archive
+pub async fn repair_archive(mut archive: Box<dyn BlockArchive>, dir: PathBuf, mut source: RepairFrom, verbose: bool, output: OutputFormat) -> Result<Outcome> {
+    let quarantine_archive = open_quarantine(dir).await?;
+    let report = quarantine::repair_archive(archive.as_mut(), &quarantine_archive, &mut source.as_source(), |h, failure| {
+        match failure {
+            Some(f) => emit_failure(output, f),
+            None if verbose => emit_ok(output, h, format!("OK: block {}", h)),
+            None => {}
+        }
+    }).await?;
+    for h in report.repaired.iter() {
+        emit(output, format!("repaired block {}", h), json!({"block_hash": h.to_string(), "status": "repaired"}));
+    }
+    for h in report.unavailable.iter() {
+        emit(output, format!("no copy of block {}", h), json!({"block_hash": h.to_string(), "status": "unavailable"}));
+    }
+    for (h, msg) in report.bad_copies.iter() {
+        emit(output, format!("ERROR: the copy of block {} is bad: {}", h, msg),
+             json!({"block_hash": h.to_string(), "status": "error", "message": msg}));
+    }
+    emit(output, format!("{} blocks checked, {} damaged, {} repaired, {} without a copy, {} with a bad copy",
+                         report.checked, report.failures.len(), report.repaired.len(), report.unavailable.len(), report.bad_copies.len()),
+         report.to_json());
+    // the damaged blocks that could not be repaired
+    Ok(Outcome::found(report.unavailable.len() + report.bad_copies.len()))
+}
@@ -0,0 +1,142 @@
+//! The serve commands, which serve an archive over HTTP, JSON-RPC or P2P, and the notifications
+//! sent when blocks are stored.
+use clap::Subcommand;
+use serde_json::Value;
+use bsv_blockarchive::{events, http, p2p_server, rpc, BlockArchive, Network, Result, Error};
+use bsv_blockarchive::publish::{BlockPublisher, PayloadSchema};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+#[derive(Subcommand, Debug)]
+pub enum ServeCommands {
+    /// Serve the blocks in the archive to nodes over the peer-to-peer protocol.
+    ///
+    /// A node that is syncing can be pointed at the archive to download the blocks with getdata
+    /// and the headers of the best chain with getheaders. The headers are read when the server
+    /// starts, from the headers file if the archive has one.
+    P2p {
+        /// The address to listen on.
+        #[clap(short, long, default_value = "127.0.0.1:8333")]
+        listen: String,
+        /// The network of the nodes. Defaults to the network in the configuration file, or
+        /// mainnet.
+        #[clap(short = 'n', long)]
+        network: Option<Network>,
+    },
+}
+
+// serve the archive over JSON-RPC until an error occurs
+pub async fn serve_rpc(archive: Box<dyn BlockArchive>, listen: String) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(&listen).await?;
+    println!("listening on {}", listener.local_addr()?);
+    rpc::serve(archive, listener).await
+}
+
+// serve the archive to nodes over the peer-to-peer protocol until an error occurs
+pub async fn serve_p2p(archive: Box<dyn BlockArchive>, listen: String, network: Network) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(&listen).await?;
+    println!("listening on {}", listener.local_addr()?);
+    p2p_server::serve(archive, listener, network).await
+}
+
+// serve the archive over HTTP until an error occurs, streaming the events sent to the feed if
+// there is one
+pub async fn serve(archive: Box<dyn BlockArchive>, listen: String, writable: bool, feed: Option<EventFeed>) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(&listen).await?;
+    println!("listening on {}", listener.local_addr()?);
+    match feed {
+        Some(feed) => http::serve_with_events(archive, listener, writable, feed).await,
+        None if writable => http::serve_writable(archive, listener).await,
+        None => http::serve(archive, listener).await,
+    }
+}
+
+// the channel of the events streamed by the serve command
+type EventFeed = tokio::sync::broadcast::Sender<events::BlockEvent>;
+
+// where the events of the replicate, serve, and watch commands are sent
+pub struct Notifications {
+    pub webhook: Option<String>,
+    // the URL of the broker that stored blocks are published to
+    pub publish: Option<String>,
+    pub publish_topic: String,
+    pub publish_schema: PayloadSchema,
+    // set by connect()
+    pub publisher: Option<BlockPublisher>,
+    pub feed: Option<EventFeed>,
+}
+
+impl Notifications {
+    // connect to the broker, if there is one, and add the feed of the serve command, which is
+    // only done by the commands that send events
+    pub async fn connect(mut self, feed: Option<EventFeed>) -> Result<Notifications> {
+        if let Some(url) = &self.publish {
+            self.publisher = Some(BlockPublisher::connect(url, &self.publish_topic, self.publish_schema).await?);
+        }
+        self.feed = feed;
+        Ok(self)
+    }
+
+    // wrap an archive so that its events, with the details of stored blocks, are posted to the
+    // webhook, published to the broker, and sent to the feed, if there are any
+    pub fn wrap(&self, archive: Box<dyn BlockArchive>) -> Result<Box<dyn BlockArchive>> {
+        if self.webhook.is_none() && self.publisher.is_none() && self.feed.is_none() {
+            return Ok(archive);
+        }
+        let mut archive = events::NotifyingBlockArchive::new(archive).with_details();
+        if let Some(url) = &self.webhook {
+            archive = archive.with_listener(webhook_listener(url)?);
+        }
+        if let Some(publisher) = &self.publisher {
+            archive = archive.with_listener(publisher.listener());
+        }
+        if let Some(feed) = self.feed.clone() {
+            // there may be no clients of the stream
+            archive = archive.with_listener(move |event: &events::BlockEvent| { let _ = feed.send(event.clone()); });
+        }
+        Ok(Box::new(archive))
+    }
+}
+
+// a listener that posts each event to a webhook, the events are posted in order by a separate
+// task so that the archive operations do not wait for the webhook
+fn webhook_listener(url: &str) -> Result<impl Fn(&events::BlockEvent) + Send + Sync + 'static> {
+    let url = Url::parse(url).map_err(|e| Error::IoError(std::io::Error::other(format!("invalid webhook URL {}: {}", url, e))))?;
+    if url.scheme() != "http" {
+        return Err(Error::IoError(std::io::Error::other(format!("only http webhooks are supported, not {}", url))));
+    }
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<events::BlockEvent>();
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let Err(e) = post_json(&url, &event.to_json()).await {
+                eprintln!("could not post {} event for block {} to {}: {}", event.name(), event.block_hash(), url, e);
+            }
+        }
+    });
+    Ok(move |event: &events::BlockEvent| {
+        let _ = tx.send(event.clone());
+    })
+}
+
+// post a JSON record to an http URL, failing if the response is not a success
+async fn post_json(url: &Url, record: &Value) -> Result<()> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let target = match url.query() {
+        Some(q) => format!("{}?{}", url.path(), q),
+        None => url.path().to_string(),
+    };
+    let body = record.to_string();
+    let mut socket = tokio::net::TcpStream::connect((host, port)).await?;
+    let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                          target, host, body.len(), body);
+    socket.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    socket.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Error::IoError(std::io::Error::other(format!("webhook response: {}", status)))),
+    }
+}
@@ -0,0 +1,60 @@
+//! The commands that read and change the settings recorded in an archive: its network, its format
+//! version and whether it is immutable.
+use std::path::PathBuf;
+use serde_json::json;
+use bsv_blockarchive::{Network, SimpleFileBasedBlockArchive, Result, Error};
+use bsv_blockarchive::meta;
+use crate::{emit, OutputFormat};
+
+// upgrade a simple archive to the current format
+pub async fn migrate(root_dir: PathBuf) -> Result<()> {
+    let archive = SimpleFileBasedBlockArchive::new(root_dir).await?;
+    let before = archive.format_version().await?;
+    let steps = meta::migrate_to_latest(&archive).await?;
+    for step in steps.iter() {
+        println!("done: {}", step);
+    }
+    println!("format version {} before, {} after", before, archive.format_version().await?);
+    Ok(())
+}
+
+// choose the network for a command from the option, the configuration file, and the network
+// recorded in the archive, which fails if it is not the recorded network
+pub fn choose_network(given: Option<Network>, configured: Option<Network>, recorded: Option<Network>) -> Result<Network> {
+    let network = given.or(configured).or(recorded).unwrap_or_default();
+    match recorded {
+        Some(r) if r != network => Err(Error::WrongNetwork(format!("the archive holds {} blocks, not {} blocks", r, network))),
+        _ => Ok(network),
+    }
+}
+
+// record the network of a simple archive, if it has not been recorded
+pub async fn record_network(root_dir: PathBuf, network: Network) -> Result<()> {
+    SimpleFileBasedBlockArchive::new(root_dir).await?.set_network(network).await
+}
+
+// show or record the network of a simple archive
+pub async fn archive_network(root_dir: PathBuf, set: Option<Network>, output: OutputFormat) -> Result<()> {
+    let mut archive = SimpleFileBasedBlockArchive::new(root_dir).await?;
+    if let Some(network) = set {
+        if let Err(e) = archive.set_network(network).await {
+            eprintln!("ERROR: {}", e);
+            return Ok(());
+        }
+    }
+    let recorded = archive.recorded_network().await?;
+    emit(output, recorded.map_or("no network recorded".to_string(), |n| n.to_string()),
+         json!({"network": recorded.map(|n| n.to_string())}));
+    Ok(())
+}
+
+// show whether a simple archive is immutable, or make it immutable
+pub async fn archive_immutable(root_dir: PathBuf, set: bool, output: OutputFormat) -> Result<()> {
+    let mut archive = SimpleFileBasedBlockArchive::new(root_dir).await?;
+    if set {
+        archive.set_immutable().await?;
+    }
+    let immutable = archive.is_immutable();
+    emit(output, if immutable { "immutable" } else { "mutable" }.to_string(), json!({"immutable": immutable}));
+    Ok(())
+}
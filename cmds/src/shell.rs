@@ -0,0 +1,289 @@
+//! The shell command, which opens the archive once and then reads commands from the terminal.
+//!
+//! The blocks are listed when the shell starts, and the chain of blocks is built the first time
+//! a command needs it, so that exploring an archive does not pay for opening and listing it for
+//! every command. Block hashes can be abbreviated to a prefix that only one block has, and tab
+//! completes the commands and the hashes of the blocks.
+use std::path::PathBuf;
+use bitcoinsv::bitcoin::BlockHash;
+use clap::{Parser, Subcommand};
+use hex::FromHex;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde_json::json;
+use tokio_stream::StreamExt;
+use bsv_blockarchive::{BlockArchive, ChainIndex, Error, IndexedBlockArchive, Result, TxIndex};
+use crate::{emit, OutputFormat};
+use crate::blocks::{get_block, header, print_height, print_tip};
+use crate::index::{print_transaction, TxCommands};
+
+// The names of the commands, for completion.
+const COMMANDS: [&str; 10] = ["exists", "exit", "get", "header", "height", "help", "quit", "reload", "tip", "tx"];
+
+// The names of the tx commands, for completion.
+const TX_COMMANDS: [&str; 2] = ["exists", "get"];
+
+// A line read by the shell.
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+struct ShellLine {
+    #[command(subcommand)]
+    cmd: ShellCommands,
+}
+
+#[derive(Subcommand, Debug)]
+enum ShellCommands {
+    /// Check whether a block is in the archive.
+    Exists {
+        /// Block hash, or a prefix of it.
+        block_hash: String,
+    },
+    /// Leave the shell.
+    #[command(alias = "quit")]
+    Exit,
+    /// Get the raw bytes of a block, written to stdout unless a file is given.
+    Get {
+        /// Write the block to this file.
+        #[clap(short = 'o', long)]
+        out: Option<PathBuf>,
+        /// Write the block hex encoded.
+        #[clap(short = 'x', long, default_value = "false")]
+        hex: bool,
+        /// Block hash, or a prefix of it.
+        block_hash: String,
+    },
+    /// Get the header of a block.
+    Header {
+        /// Return hex encoded.
+        #[clap(short = 'x', long, default_value = "false")]
+        hex: bool,
+        /// Block hash, or a prefix of it.
+        block_hash: String,
+    },
+    /// Get the height of a block.
+    Height {
+        /// Block hash, or a prefix of it.
+        block_hash: String,
+    },
+    /// List the blocks again, after blocks have been stored or removed by another process.
+    Reload,
+    /// Get the hash and height of the best tip.
+    Tip,
+    /// Get transactions using the transaction index.
+    Tx {
+        #[command(subcommand)]
+        tx_cmd: TxCommands,
+    },
+}
+
+// Completes the commands and the hashes of the blocks.
+struct ShellHelper {
+    // the hashes of the blocks in the archive, sorted
+    hashes: Vec<String>,
+}
+
+impl ShellHelper {
+    // Get the hashes that start with a prefix.
+    fn matching(&self, prefix: &str) -> &[String] {
+        let start = self.hashes.partition_point(|h| h.as_str() < prefix);
+        let end = start + self.hashes[start..].iter().take_while(|h| h.starts_with(prefix)).count();
+        &self.hashes[start..end]
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let before: Vec<&str> = line[..start].split_whitespace().collect();
+        let candidates = match before.as_slice() {
+            [] => COMMANDS.iter().filter(|c| c.starts_with(word)).map(|c| c.to_string()).collect(),
+            ["tx"] => TX_COMMANDS.iter().filter(|c| c.starts_with(word)).map(|c| c.to_string()).collect(),
+            // transactions are not listed
+            ["tx", ..] => Vec::new(),
+            _ if word.starts_with('-') => Vec::new(),
+            _ => self.matching(&word.to_lowercase()).to_vec(),
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+// The state of the shell.
+struct Shell {
+    // the archive, until a transaction is requested and the index is opened with it
+    archive: Option<Box<dyn BlockArchive>>,
+    indexed: Option<IndexedBlockArchive<Box<dyn BlockArchive>>>,
+    index_dir: PathBuf,
+    // the chain of blocks, built when it is first needed
+    chain: Option<ChainIndex>,
+    output: OutputFormat,
+}
+
+impl Shell {
+    fn archive(&self) -> &dyn BlockArchive {
+        match self.indexed.as_ref() {
+            Some(indexed) => indexed.archive().as_ref(),
+            None => self.archive.as_deref().expect("the archive is held by the shell or the index"),
+        }
+    }
+
+    fn archive_mut(&mut self) -> &mut dyn BlockArchive {
+        match self.indexed.as_mut() {
+            Some(indexed) => indexed,
+            None => self.archive.as_deref_mut().expect("the archive is held by the shell or the index"),
+        }
+    }
+
+    // List the hashes of the blocks, sorted for completion.
+    async fn list(&mut self) -> Result<Vec<String>> {
+        let mut results = self.archive_mut().block_list().await?;
+        let mut hashes = Vec::new();
+        while let Some(h) = results.next().await {
+            hashes.push(h.to_string());
+        }
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    async fn chain(&mut self) -> Result<&ChainIndex> {
+        if self.chain.is_none() {
+            self.chain = Some(ChainIndex::build(self.archive_mut()).await?);
+        }
+        Ok(self.chain.as_ref().unwrap())
+    }
+
+    fn indexed(&mut self) -> Result<&IndexedBlockArchive<Box<dyn BlockArchive>>> {
+        if self.indexed.is_none() {
+            let index = TxIndex::open(&self.index_dir)?;
+            let archive = self.archive.take().expect("the archive is held by the shell or the index");
+            self.indexed = Some(IndexedBlockArchive::new(archive, index));
+        }
+        Ok(self.indexed.as_ref().unwrap())
+    }
+
+    // Run a command, returning false when the shell should stop.
+    async fn run(&mut self, helper: &mut ShellHelper, cmd: ShellCommands) -> Result<bool> {
+        let output = self.output;
+        match cmd {
+            ShellCommands::Exists{block_hash} => {
+                let block_hash = resolve(helper, &block_hash)?;
+                let exists = self.archive().block_exists(&block_hash).await?;
+                let text = if exists { "is in the archive" } else { "not found" };
+                emit(output, format!("block {} {}", block_hash, text), json!({"block_hash": block_hash.to_string(), "exists": exists}));
+            }
+            ShellCommands::Exit => return Ok(false),
+            ShellCommands::Get{out, hex, block_hash} => {
+                let block_hash = resolve(helper, &block_hash)?;
+                get_block(self.archive(), block_hash, out, hex).await?;
+            }
+            ShellCommands::Header{hex, block_hash} => {
+                let block_hash = resolve(helper, &block_hash)?;
+                header(self.archive(), block_hash, hex, output).await?;
+            }
+            ShellCommands::Height{block_hash} => {
+                let block_hash = resolve(helper, &block_hash)?;
+                print_height(self.chain().await?, block_hash);
+            }
+            ShellCommands::Reload => {
+                helper.hashes = self.list().await?;
+                self.chain = None;
+                println!("{} blocks", helper.hashes.len());
+            }
+            ShellCommands::Tip => {
+                print_tip(self.chain().await?);
+            }
+            ShellCommands::Tx{tx_cmd} => {
+                match tx_cmd {
+                    TxCommands::Exists{txid} => {
+                        // unlike the tx exists command, a missing transaction does not stop the shell
+                        match self.indexed()?.index().get(&txid)? {
+                            Some(l) => emit(output, format!("transaction {} is in block {}", txid, l.block_hash),
+                                            json!({"txid": txid.to_string(), "exists": true, "block_hash": l.block_hash.to_string()})),
+                            None => emit(output, format!("transaction {} not found", txid), json!({"txid": txid.to_string(), "exists": false})),
+                        }
+                    }
+                    TxCommands::Get{hex: _, json, txid} => {
+                        print_transaction(self.indexed()?, txid, json, output).await?;
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+// Get the block hash that an argument gives, which is a full hash or the prefix of the hash of
+// only one block.
+fn resolve(helper: &ShellHelper, arg: &str) -> Result<BlockHash> {
+    let block_hash = match helper.matching(&arg.to_lowercase()) {
+        _ if arg.len() == 64 => arg,
+        [] => return Err(Error::BlockNotFound),
+        [h] => h.as_str(),
+        found => return Err(Error::InvalidHash(format!("{} is the start of {} block hashes", arg, found.len()))),
+    };
+    BlockHash::from_hex(block_hash).map_err(|_| Error::InvalidHash(arg.to_string()))
+}
+
+/// Read commands from the terminal and run them against the archive until the input ends or
+/// exit is given.
+pub async fn run(archive: Box<dyn BlockArchive>, index_dir: PathBuf, output: OutputFormat) -> Result<()> {
+    let mut shell = Shell { archive: Some(archive), indexed: None, index_dir, chain: None, output };
+    let hashes = shell.list().await?;
+    println!("{} blocks, type help for the commands", hashes.len());
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new().map_err(readline_error)?;
+    editor.set_helper(Some(ShellHelper { hashes }));
+    loop {
+        // reading the line blocks the thread until it is entered
+        let line = match tokio::task::block_in_place(|| editor.readline("blockarchive> ")) {
+            Ok(line) => line,
+            // ctrl-c abandons the line
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        let cmd = match ShellLine::try_parse_from(words) {
+            Ok(parsed) => parsed.cmd,
+            Err(e) => {
+                // this is also how help is printed
+                let _ = e.print();
+                continue;
+            }
+        };
+        let helper = editor.helper_mut().expect("the helper is set");
+        match shell.run(helper, cmd).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    Ok(())
+}
+
+fn readline_error(e: ReadlineError) -> Error {
+    Error::IoError(std::io::Error::other(e.to_string()))
+}
@@ -0,0 +1,125 @@
+//! The stats, sizes and bench commands, which measure an archive.
+use std::path::{Path, PathBuf};
+use clap::Subcommand;
+use serde_json::{json, Value};
+use bsv_blockarchive::{bench, stats, BlockArchive, Network, SimpleFileBasedBlockArchive, Result};
+use bsv_blockarchive::catalog::Catalog;
+use crate::{emit, open_archive, ArchiveOptions, ArchiveType, OutputFormat};
+
+#[derive(Subcommand, Debug)]
+pub enum SizesCommands {
+    /// List the largest blocks with their sizes, largest first.
+    Largest {
+        /// The number of blocks to list.
+        #[clap(short = 'n', long, default_value = "10")]
+        count: usize,
+    },
+    /// Write the sizes file from the sizes of all the blocks, creating it if necessary.
+    ///
+    /// Once the file exists it is kept up to date as blocks are stored and removed. This takes
+    /// the write lock, so no other process that locks the archive can be writing to it.
+    Rebuild,
+    /// Print the total size of the blocks in bytes.
+    Total,
+}
+
+// print statistics about the blocks in the archive, read from the catalog if there is one
+pub async fn archive_stats(mut archive: Box<dyn BlockArchive>, catalog: Option<Catalog>, top: usize, network: Network, output: OutputFormat) -> Result<()> {
+    let stats = match catalog {
+        Some(catalog) => catalog.stats(top)?,
+        None => stats::collect_stats(archive.as_mut(), top).await?,
+    };
+    if output == OutputFormat::Json {
+        let mut record = stats.to_json();
+        record["network"] = json!(network.to_string());
+        println!("{}", record);
+        return Ok(());
+    }
+    println!("Network: {}", network);
+    println!("Blocks: {}", stats.blocks);
+    println!("Total bytes: {}", stats.total_bytes);
+    println!("Block size: min {} max {} average {}", stats.min_size, stats.max_size, stats.average_size());
+    if let Some(transactions) = stats.transactions {
+        println!("Transactions: {}", transactions);
+    }
+    println!("Blocks by size:");
+    for (bucket, count) in stats.size_histogram.iter() {
+        println!("  >= {:>12} bytes: {}", bucket, count);
+    }
+    println!("Blocks by year:");
+    for (year, count) in stats.by_year.iter() {
+        println!("  {}: {}", year, count);
+    }
+    println!("Largest blocks:");
+    for (block_hash, size) in stats.largest.iter() {
+        println!("  {} {}", block_hash, size);
+    }
+    Ok(())
+}
+
+// measure each backend with a synthetic archive in a directory of its own under dir, which is
+// removed afterwards
+pub async fn bench_backends(backends: &[ArchiveType], config: &bench::BenchConfig, dir: &Path, options: &ArchiveOptions, output: OutputFormat) -> Result<()> {
+    let mut reports = Vec::new();
+    let mut result = Ok(());
+    for archive_type in backends {
+        let name = format!("{:?}", archive_type).to_lowercase();
+        let root = dir.join(&name);
+        match bench_backend(archive_type, &root, config, options).await {
+            Ok(report) => reports.push((name, report)),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    // the archives are removed even if a measurement failed
+    if tokio::fs::try_exists(dir).await? {
+        tokio::fs::remove_dir_all(dir).await?;
+    }
+    result?;
+    if output == OutputFormat::Json {
+        let results: Vec<Value> = reports.iter().map(|(name, r)| json!({"backend": name, "ops": r.to_json()})).collect();
+        println!("{}", json!({"blocks": config.blocks, "block_sizes": config.block_sizes, "results": results}));
+        return Ok(());
+    }
+    println!("{:<8} {:<7} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10}", "backend", "op", "count", "ops/s", "MB/s", "p50 us", "p99 us", "max us");
+    for (name, report) in reports.iter() {
+        for op in report.ops.iter() {
+            println!("{:<8} {:<7} {:>8} {:>10.1} {:>10.1} {:>10} {:>10} {:>10}", name, op.op, op.count,
+                     op.ops_per_sec(), op.bytes_per_sec() / 1_000_000.0,
+                     op.p50.as_micros(), op.p99.as_micros(), op.max.as_micros());
+        }
+    }
+    Ok(())
+}
+
+// measure one backend with a synthetic archive in a new directory
+async fn bench_backend(archive_type: &ArchiveType, root: &Path, config: &bench::BenchConfig, options: &ArchiveOptions) -> Result<bench::BenchReport> {
+    tokio::fs::create_dir_all(root).await?;
+    let mut archive = open_archive(archive_type, &root.to_string_lossy(), options).await?;
+    bench::run(archive.as_mut(), config).await
+}
+
+// write the sizes file of a simple archive
+pub async fn rebuild_sizes(root_dir: PathBuf) -> Result<()> {
+    let mut archive = SimpleFileBasedBlockArchive::new(root_dir).await?;
+    let count = archive.rebuild_sizes().await?;
+    println!("wrote {} sizes", count);
+    Ok(())
+}
+
+// list the largest blocks, reading only the sizes of the blocks
+pub async fn largest_blocks(mut archive: Box<dyn BlockArchive>, count: usize, output: OutputFormat) -> Result<()> {
+    for (block_hash, size) in stats::largest_blocks(archive.as_mut(), count).await? {
+        emit(output, format!("{} {}", block_hash, size), json!({"block_hash": block_hash.to_string(), "size": size}));
+    }
+    Ok(())
+}
+
+// print the total size of the blocks
+pub async fn total_size(mut archive: Box<dyn BlockArchive>, output: OutputFormat) -> Result<()> {
+    let total = archive.total_size().await?;
+    emit(output, format!("{} bytes", total), json!({"total_bytes": total}));
+    Ok(())
+}
@@ -20,4 +20,8 @@ async fn main() {
     while let Some(block_hash) = results.next().await {
         println!("{}", block_hash);
     }
+    if let Some(e) = results.as_mut().take_error() {
+        eprintln!("error listing blocks: {}", e);
+        std::process::exit(1);
+    }
 }
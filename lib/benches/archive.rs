@@ -0,0 +1,135 @@
+//! Benchmarks of the store, get, header, and list operations of the local backends, against a
+//! synthetic archive of generated blocks, see the bench module.
+//!
+//! Run them with "cargo bench -p bsv-blockarchive". The number of blocks in the archive and their
+//! sizes can be set with the BENCH_BLOCKS and BENCH_BLOCK_SIZES environment variables, the sizes
+//! separated by commas, and each size is measured separately.
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use bitcoinsv::bitcoin::BlockHash;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mktemp::Temp;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+use bsv_blockarchive::bench::synthetic_blocks;
+use bsv_blockarchive::{BlockArchive, DedupBlockArchive, PackedBlockArchive, SimpleFileBasedBlockArchive};
+
+// the backends that are measured
+const BACKENDS: [&str; 3] = ["simple", "packed", "dedup"];
+
+// the defaults for the size of the synthetic archive
+const DEFAULT_BLOCKS: usize = 100;
+const DEFAULT_BLOCK_SIZES: [usize; 2] = [1_000, 1_000_000];
+
+// Open an empty archive of a backend in a directory.
+async fn open(backend: &str, root: PathBuf) -> Box<dyn BlockArchive> {
+    match backend {
+        "simple" => Box::new(SimpleFileBasedBlockArchive::new(root).await.unwrap()),
+        "packed" => Box::new(PackedBlockArchive::new(root).await.unwrap()),
+        "dedup" => Box::new(DedupBlockArchive::new(root).await.unwrap()),
+        _ => panic!("unknown backend {}", backend),
+    }
+}
+
+// Get the number of blocks and the block sizes from the environment.
+fn config() -> (usize, Vec<usize>) {
+    let blocks = std::env::var("BENCH_BLOCKS").ok()
+        .map(|s| s.parse().expect("BENCH_BLOCKS must be a number"))
+        .unwrap_or(DEFAULT_BLOCKS);
+    let sizes = std::env::var("BENCH_BLOCK_SIZES").ok()
+        .map(|s| s.split(',').map(|n| n.trim().parse().expect("BENCH_BLOCK_SIZES must be numbers")).collect())
+        .unwrap_or(DEFAULT_BLOCK_SIZES.to_vec());
+    (blocks, sizes)
+}
+
+// Storing blocks, each sample stores new blocks in a new archive.
+fn bench_store(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (_, sizes) = config();
+    let mut group = c.benchmark_group("store");
+    for size in sizes {
+        group.throughput(Throughput::Bytes(size as u64));
+        for backend in BACKENDS {
+            group.bench_with_input(BenchmarkId::new(backend, size), &size, |b, size| {
+                b.to_async(&rt).iter_custom(|iters| async move {
+                    let root = Temp::new_dir().unwrap();
+                    let archive = open(backend, root.to_path_buf()).await;
+                    let mut elapsed = Duration::ZERO;
+                    for (block_hash, block) in synthetic_blocks(iters as usize, &[*size]) {
+                        let start = Instant::now();
+                        archive.store_block(&block_hash, &mut &block[..]).await.unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+// Reading blocks, their headers, and the list of blocks from a synthetic archive.
+fn bench_read(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (blocks, sizes) = config();
+    for size in sizes {
+        for backend in BACKENDS {
+            let root = Temp::new_dir().unwrap();
+            let (mut archive, hashes) = rt.block_on(async {
+                let archive = open(backend, root.to_path_buf()).await;
+                let mut hashes: Vec<BlockHash> = Vec::new();
+                for (block_hash, block) in synthetic_blocks(blocks, &[size]) {
+                    archive.store_block(&block_hash, &mut &block[..]).await.unwrap();
+                    hashes.push(block_hash);
+                }
+                (archive, hashes)
+            });
+            let hashes = &hashes;
+            let id = BenchmarkId::new(backend, size);
+
+            let mut group = c.benchmark_group("get");
+            group.throughput(Throughput::Bytes(size as u64));
+            let reader = &archive;
+            group.bench_function(id.clone(), |b| {
+                b.to_async(&rt).iter_custom(|iters| async move {
+                    let mut buf = Vec::new();
+                    let start = Instant::now();
+                    for i in 0..iters as usize {
+                        buf.clear();
+                        reader.get_block(&hashes[i % hashes.len()]).await.unwrap().read_to_end(&mut buf).await.unwrap();
+                    }
+                    start.elapsed()
+                });
+            });
+            group.finish();
+
+            let mut group = c.benchmark_group("header");
+            group.throughput(Throughput::Elements(1));
+            group.bench_function(id.clone(), |b| {
+                b.to_async(&rt).iter_custom(|iters| async move {
+                    let start = Instant::now();
+                    for i in 0..iters as usize {
+                        reader.block_header(&hashes[i % hashes.len()]).await.unwrap();
+                    }
+                    start.elapsed()
+                });
+            });
+            group.finish();
+
+            let mut group = c.benchmark_group("list");
+            group.throughput(Throughput::Elements(blocks as u64));
+            // listing needs the archive mutably, so each iteration runs to completion on its own
+            group.bench_function(id, |b| {
+                b.iter(|| rt.block_on(async {
+                    let mut results = archive.block_list().await.unwrap();
+                    while results.next().await.is_some() {}
+                }));
+            });
+            group.finish();
+        }
+    }
+}
+
+criterion_group!(benches, bench_store, bench_read);
+criterion_main!(benches);
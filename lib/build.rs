@@ -0,0 +1,19 @@
+fn main() {
+    // the generated gRPC code is only needed by the grpc feature
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/blockarchive.proto").expect("compiling proto/blockarchive.proto");
+
+    // the C header is regenerated from the C API whenever it changes, see cbindgen.toml
+    #[cfg(feature = "cabi")]
+    {
+        println!("cargo:rerun-if-changed=src/cabi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let config = cbindgen::Config::from_file("cbindgen.toml").expect("reading cbindgen.toml");
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src("src/cabi.rs")
+            .generate()
+            .expect("generating the C header")
+            .write_to_file("include/bsv_blockarchive.h");
+    }
+}
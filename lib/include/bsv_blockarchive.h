@@ -0,0 +1,137 @@
+/*
+ * C API for reading a bsv-blockarchive block archive.
+ *
+ * Block hashes are passed as NUL terminated hex strings in display order.
+ */
+
+#ifndef BSV_BLOCKARCHIVE_H
+#define BSV_BLOCKARCHIVE_H
+
+/* Generated by cbindgen from src/cabi.rs, do not edit. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/*
+ * The call succeeded.
+ */
+#define BSVBA_OK 0
+
+/*
+ * The block was not found in the archive.
+ */
+#define BSVBA_NOT_FOUND 1
+
+/*
+ * An argument was null or could not be parsed.
+ */
+#define BSVBA_INVALID_ARGUMENT 2
+
+/*
+ * An IO error occurred.
+ */
+#define BSVBA_IO_ERROR 3
+
+/*
+ * The callback asked for the operation to be aborted.
+ */
+#define BSVBA_ABORTED 4
+
+/*
+ * Any other error.
+ */
+#define BSVBA_ERROR 5
+
+/*
+ * An open archive, opaque to C callers.
+ *
+ * Each handle owns its own runtime, so callers do not need to know anything about tokio.
+ */
+typedef struct BsvBlockArchive BsvBlockArchive;
+
+/*
+ * Callback used by [bsvba_get_block] to deliver block bytes. A non-zero return value aborts
+ * the transfer. A null callback is refused with [BSVBA_INVALID_ARGUMENT].
+ */
+typedef int (*BsvbaWriteCallback)(const uint8_t *data, size_t len, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ * Open the archive at `root_path`.
+ *
+ * Returns null if the path is invalid or the archive could not be opened. The returned handle
+ * must be released with [bsvba_close].
+ *
+ * # Safety
+ *
+ * `root_path` must be null or point to a valid NUL terminated string.
+ */
+BsvBlockArchive *bsvba_open(const char *root_path);
+
+/*
+ * Close an archive opened with [bsvba_open].
+ *
+ * # Safety
+ *
+ * `archive` must be null or a handle returned by [bsvba_open] that has not already been closed.
+ */
+void bsvba_close(BsvBlockArchive *archive);
+
+/*
+ * Check whether a block exists in the archive, storing the result in `exists`.
+ *
+ * # Safety
+ *
+ * `archive` must be a valid handle, `hash_hex` a valid NUL terminated string, and `exists` a
+ * valid pointer to a bool.
+ */
+int bsvba_block_exists(const BsvBlockArchive *archive, const char *hash_hex, bool *exists);
+
+/*
+ * Get the size of a block in bytes, storing the result in `size`.
+ *
+ * # Safety
+ *
+ * `archive` must be a valid handle, `hash_hex` a valid NUL terminated string, and `size` a
+ * valid pointer.
+ */
+int bsvba_block_size(const BsvBlockArchive *archive, const char *hash_hex, uint64_t *size);
+
+/*
+ * Copy the 80 byte encoded header of a block into `header_out`.
+ *
+ * The header is read with [BlockArchive::block_header], so an archive with a headers file does
+ * not open the block.
+ *
+ * # Safety
+ *
+ * `archive` must be a valid handle, `hash_hex` a valid NUL terminated string, and `header_out`
+ * must point to at least 80 writable bytes.
+ */
+int bsvba_block_header(const BsvBlockArchive *archive, const char *hash_hex, uint8_t *header_out);
+
+/*
+ * Stream the bytes of a block to `callback`, in chunks of at most 1MB.
+ *
+ * The callback is called on the calling thread. If it returns a non-zero value then the
+ * transfer is stopped and [BSVBA_ABORTED] is returned.
+ *
+ * # Safety
+ *
+ * `archive` must be a valid handle, `hash_hex` a valid NUL terminated string, and `callback`
+ * null or a valid function. `user_data` is passed through to the callback untouched.
+ */
+int bsvba_get_block(const BsvBlockArchive *archive,
+                    const char *hash_hex,
+                    BsvbaWriteCallback callback,
+                    void *user_data);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* BSV_BLOCKARCHIVE_H */
@@ -0,0 +1,288 @@
+use std::path::Path;
+use std::str::FromStr;
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
+use bitcoinsv::bitcoin::BlockHash;
+use hex::FromHex;
+use ring::digest::{Context, SHA256};
+use tokio::fs::File;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use crate::block_archive::{decode_block_attrs, encode_block_attrs};
+use crate::{BlockArchive, Error, Manifest, ManifestEntry, Result};
+
+// the first bytes of every backup set, includes the format version
+const BACKUP_MAGIC: &[u8; 8] = b"BSVBAK02";
+// the first bytes of a backup set written before the manifest was included
+const BACKUP_MAGIC_V1: &[u8; 8] = b"BSVBAK01";
+// record type for a block
+const RECORD_BLOCK: u8 = b'B';
+// record type for the attributes of the preceding block
+const RECORD_ATTRS: u8 = b'A';
+// record type for the manifest, which comes after the last block
+const RECORD_MANIFEST: u8 = b'M';
+// record type for the end of the backup set
+const RECORD_END: u8 = b'E';
+// the size of the buffer used when copying blocks
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A summary of a backup or restore.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BackupSummary {
+    /// The number of blocks written to the backup, or restored to the archive.
+    pub blocks: usize,
+    /// The number of bytes of block data written or restored.
+    pub bytes: u64,
+    /// The number of blocks skipped, either because they were in the base manifest (backup) or
+    /// were already in the archive (restore).
+    pub skipped: usize,
+    /// The blocks in the manifest of the backup set that are not in the archive after a restore.
+    /// For an incremental backup set these blocks are in the earlier backup sets, which should be
+    /// restored too. Always empty after a backup.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub missing: Vec<BlockHash>,
+}
+
+/// Write a zstd compressed backup set of the blocks in an archive.
+///
+/// If a base manifest is given then only blocks which are not in the base manifest are written,
+/// making this an incremental backup. The returned manifest describes every block in the archive
+/// at the time of the backup and should be kept as the base for the next incremental backup.
+///
+/// The backup set is a sequence of records, one per block, each with the block hash, size, data,
+/// and SHA-256 checksum. The checksum is verified when the block is restored. A block with
+/// attributes is followed by a record holding the attributes. The manifest is written at the end
+/// of the backup set, so that the set can be checked without the manifest file.
+pub async fn backup<A, W>(archive: &mut A, writer: W, base: Option<&Manifest>) -> Result<(Manifest, BackupSummary)>
+    where A: BlockArchive, W: AsyncWrite + Unpin + Send
+{
+    let mut encoder = ZstdEncoder::new(writer);
+    encoder.write_all(BACKUP_MAGIC).await?;
+    let mut manifest = Manifest::new();
+    let mut summary = BackupSummary::default();
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut results = archive.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        if let Some(entry) = base.and_then(|m| m.entries.get(&block_hash)) {
+            manifest.entries.insert(block_hash, *entry);
+            summary.skipped += 1;
+            continue;
+        }
+        let size = archive.block_size(&block_hash).await? as u64;
+        let mut reader = archive.get_block(&block_hash).await?;
+        encoder.write_u8(RECORD_BLOCK).await?;
+        encoder.write_all(block_hash.to_string().as_bytes()).await?;
+        encoder.write_u64_le(size).await?;
+        let mut context = Context::new(&SHA256);
+        let mut remaining = size;
+        while remaining > 0 {
+            let n = reader.read(&mut buf[..COPY_BUFFER_SIZE.min(remaining as usize)]).await?;
+            if n == 0 {
+                return Err(Error::CorruptBlock(block_hash));
+            }
+            context.update(&buf[..n]);
+            encoder.write_all(&buf[..n]).await?;
+            remaining -= n as u64;
+        }
+        let mut checksum = [0u8; 32];
+        checksum.copy_from_slice(context.finish().as_ref());
+        encoder.write_all(&checksum).await?;
+        let attrs = archive.get_block_attrs(&block_hash).await?;
+        if !attrs.is_empty() {
+            let attrs = encode_block_attrs(&attrs);
+            encoder.write_u8(RECORD_ATTRS).await?;
+            encoder.write_all(block_hash.to_string().as_bytes()).await?;
+            encoder.write_u32_le(attrs.len() as u32).await?;
+            encoder.write_all(attrs.as_bytes()).await?;
+        }
+        manifest.entries.insert(block_hash, ManifestEntry { size, checksum });
+        summary.blocks += 1;
+        summary.bytes += size;
+    }
+    // an incomplete backup must not look complete
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    let text = manifest.to_string();
+    encoder.write_u8(RECORD_MANIFEST).await?;
+    encoder.write_u64_le(text.len() as u64).await?;
+    encoder.write_all(text.as_bytes()).await?;
+    encoder.write_u8(RECORD_END).await?;
+    encoder.shutdown().await?;
+    Ok((manifest, summary))
+}
+
+/// Restore the blocks in a backup set written by [backup] into an archive.
+///
+/// Blocks that are already in the archive are skipped. Each block is written to a temporary
+/// file and its checksum verified before it is stored, so a damaged backup never results in a
+/// damaged block in the archive.
+///
+/// The blocks of an incremental backup are restored by restoring the full backup set and then
+/// each incremental backup set in order. The blocks listed in the manifest of the backup set that
+/// are still not in the archive are returned in [BackupSummary::missing].
+pub async fn restore<A, R>(archive: &A, reader: R) -> Result<BackupSummary>
+    where A: BlockArchive, R: AsyncBufRead + Unpin + Send
+{
+    let mut decoder = ZstdDecoder::new(reader);
+    let mut magic = [0u8; 8];
+    decoder.read_exact(&mut magic).await?;
+    if &magic != BACKUP_MAGIC && &magic != BACKUP_MAGIC_V1 {
+        return Err(Error::InvalidBackup("unknown backup format".to_string()));
+    }
+    let mut summary = BackupSummary::default();
+    let mut manifest = None;
+    loop {
+        let record_type = decoder.read_u8().await?;
+        if record_type == RECORD_END {
+            break;
+        }
+        if record_type == RECORD_MANIFEST {
+            let len = decoder.read_u64_le().await?;
+            let mut text = String::new();
+            // read through take() so that a corrupt length does not cause a huge allocation
+            (&mut decoder).take(len).read_to_string(&mut text).await
+                .map_err(|_| Error::InvalidBackup("invalid manifest".to_string()))?;
+            if text.len() as u64 != len {
+                return Err(Error::InvalidBackup("unexpected end of backup".to_string()));
+            }
+            manifest = Some(Manifest::from_str(&text)?);
+            continue;
+        }
+        let mut hash_hex = [0u8; 64];
+        decoder.read_exact(&mut hash_hex).await?;
+        let block_hash = BlockHash::from_hex(hash_hex)
+            .map_err(|_| Error::InvalidBackup("invalid block hash".to_string()))?;
+        match record_type {
+            RECORD_BLOCK => {}
+            RECORD_ATTRS => {
+                let len = decoder.read_u32_le().await? as usize;
+                let mut buf = vec![0u8; len];
+                decoder.read_exact(&mut buf).await?;
+                let attrs = String::from_utf8(buf)
+                    .map_err(|_| Error::InvalidBackup("invalid block attributes".to_string()))?;
+                for (key, value) in decode_block_attrs(&attrs)?.iter() {
+                    archive.set_block_attr(&block_hash, key, value).await?;
+                }
+                continue;
+            }
+            r => return Err(Error::InvalidBackup(format!("unknown record type {}", r))),
+        }
+        let size = decoder.read_u64_le().await?;
+        let tmp_path = std::env::temp_dir()
+            .join(format!("bsv-blockarchive-restore-{}-{}.tmp", std::process::id(), block_hash));
+        let r = restore_block(archive, &mut decoder, &block_hash, size, &tmp_path).await;
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        if r? {
+            summary.blocks += 1;
+            summary.bytes += size;
+        } else {
+            summary.skipped += 1;
+        }
+    }
+    if let Some(manifest) = manifest {
+        for block_hash in manifest.entries.keys() {
+            if !archive.block_exists(block_hash).await? {
+                summary.missing.push(*block_hash);
+            }
+        }
+    }
+    Ok(summary)
+}
+
+// Copy one block from the backup set to a temporary file, check it, and store it in the archive.
+// Returns false if the block was already in the archive.
+async fn restore_block<A, R>(archive: &A, decoder: &mut R, block_hash: &BlockHash, size: u64, tmp_path: &Path) -> Result<bool>
+    where A: BlockArchive, R: AsyncRead + Unpin + Send
+{
+    let mut tmp = File::create(tmp_path).await?;
+    let mut context = Context::new(&SHA256);
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut remaining = size;
+    while remaining > 0 {
+        let n = decoder.read(&mut buf[..COPY_BUFFER_SIZE.min(remaining as usize)]).await?;
+        if n == 0 {
+            return Err(Error::InvalidBackup("unexpected end of backup".to_string()));
+        }
+        context.update(&buf[..n]);
+        tmp.write_all(&buf[..n]).await?;
+        remaining -= n as u64;
+    }
+    tmp.flush().await?;
+    let mut checksum = [0u8; 32];
+    decoder.read_exact(&mut checksum).await?;
+    if context.finish().as_ref() != &checksum[..] {
+        return Err(Error::ChecksumMismatch(*block_hash));
+    }
+    if archive.block_exists(block_hash).await? {
+        return Ok(false);
+    }
+    let mut block = File::open(tmp_path).await?;
+    archive.store_block(block_hash, &mut block).await?;
+    Ok(true)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Back up the test archive, restore it into an empty archive, and compare.
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let mut backup_set = Vec::new();
+        let (manifest, summary) = backup(&mut src, &mut backup_set, None).await.unwrap();
+        assert_eq!(summary.blocks, 3);
+        let root = Temp::new_dir().unwrap();
+        let dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let summary = restore(&dst, Cursor::new(backup_set)).await.unwrap();
+        assert_eq!(summary.blocks, 3);
+        assert!(summary.missing.is_empty());
+        assert!(manifest.verify(&dst).await.unwrap().is_ok());
+    }
+
+    // An incremental backup against a full manifest contains no blocks.
+    #[tokio::test]
+    async fn test_incremental_backup() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let (manifest, _) = backup(&mut src, &mut Vec::new(), None).await.unwrap();
+        let mut backup_set = Vec::new();
+        let (manifest2, summary) = backup(&mut src, &mut backup_set, Some(&manifest)).await.unwrap();
+        assert_eq!(summary.blocks, 0);
+        assert_eq!(summary.skipped, 3);
+        assert_eq!(manifest2.entries, manifest.entries);
+        let root = Temp::new_dir().unwrap();
+        let dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert_eq!(restore(&dst, Cursor::new(backup_set)).await.unwrap().blocks, 0);
+    }
+
+    // Restoring an incremental backup set on its own reports the blocks of the earlier sets, which
+    // are no longer missing once the sets are restored in order.
+    #[tokio::test]
+    async fn test_restore_incremental() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let mut full_set = Vec::new();
+        let (manifest, _) = backup(&mut src, &mut full_set, None).await.unwrap();
+        let mut incremental_set = Vec::new();
+        backup(&mut src, &mut incremental_set, Some(&manifest)).await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let summary = restore(&dst, Cursor::new(incremental_set.clone())).await.unwrap();
+        assert_eq!(summary.missing, manifest.entries.keys().copied().collect::<Vec<_>>());
+        restore(&dst, Cursor::new(full_set)).await.unwrap();
+        assert!(restore(&dst, Cursor::new(incremental_set)).await.unwrap().missing.is_empty());
+    }
+
+    // A corrupted backup must not be restored.
+    #[tokio::test]
+    async fn test_restore_corrupt_backup() {
+        assert!(restore(&SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap(),
+                        Cursor::new(b"not a backup".to_vec())).await.is_err());
+    }
+}
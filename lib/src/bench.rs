@@ -0,0 +1,331 @@
+//! Measuring the speed of the operations of a block archive.
+//!
+//! The measurements are made against a synthetic archive, blocks that are generated rather than
+//! read from the chain, so that archives of any number of blocks of any size can be compared.
+//! Each generated block is a chain-linked header followed by a single transaction, padded to the
+//! requested size, so it is stored and read like a real block. The padding is pseudo-random so
+//! that backends which compress blocks do not get an unrealistic advantage.
+//!
+//! The benchmarks in the benches directory use the same blocks, this module is also used by the
+//! bench command of the command line tool.
+//!
+//! Example code:
+//!     let config = BenchConfig { blocks: 100, block_sizes: vec![1_000_000], list_rounds: 10 };
+//!     let report = bench::run(&mut archive, &config).await?;
+//!     println!("{}", report.to_json());
+use std::time::{Duration, Instant};
+use bitcoinsv::bitcoin::BlockHash;
+use serde_json::{json, Value};
+use tokio::io::AsyncReadExt;
+use tokio_stream::StreamExt;
+use crate::merkle::write_varint;
+use crate::{BlockArchive, Result};
+
+/// The smallest block that can be generated, a header and a transaction with no padding.
+pub const MIN_BLOCK_SIZE: usize = 149;
+
+// the timestamp of the first generated block, the timestamp of the genesis block
+const FIRST_TIMESTAMP: u32 = 1231006505;
+
+// the difficulty of the generated blocks, the lowest, as on regtest
+const BITS: u32 = 0x207fffff;
+
+// the size of a generated transaction without the output script and its length
+const TX_OVERHEAD: usize = 67;
+
+/// The synthetic archive and the measurements made against it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BenchConfig {
+    /// The number of blocks that are stored, read, and listed.
+    pub blocks: usize,
+    /// The sizes of the blocks in bytes, the blocks take each size in turn. Sizes below
+    /// [MIN_BLOCK_SIZE] are rounded up to it.
+    pub block_sizes: Vec<usize>,
+    /// The number of times all the blocks are listed.
+    pub list_rounds: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig { blocks: 100, block_sizes: vec![1_000_000], list_rounds: 10 }
+    }
+}
+
+/// The measurements of one operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct OpStats {
+    /// The name of the operation: store, get, header, or list.
+    pub op: &'static str,
+    /// The number of times the operation was made.
+    pub count: u64,
+    /// The number of bytes stored or read.
+    pub bytes: u64,
+    /// The total time taken.
+    pub elapsed: Duration,
+    /// The median time taken by an operation.
+    pub p50: Duration,
+    /// The time within which 99% of the operations completed.
+    pub p99: Duration,
+    /// The longest time taken by an operation.
+    pub max: Duration,
+}
+
+impl OpStats {
+    /// The number of operations per second.
+    pub fn ops_per_sec(&self) -> f64 {
+        per_sec(self.count, self.elapsed)
+    }
+
+    /// The number of bytes stored or read per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        per_sec(self.bytes, self.elapsed)
+    }
+
+    /// Get the measurements as a JSON object, the times in microseconds.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "op": self.op,
+            "count": self.count,
+            "bytes": self.bytes,
+            "elapsed_us": self.elapsed.as_micros() as u64,
+            "p50_us": self.p50.as_micros() as u64,
+            "p99_us": self.p99.as_micros() as u64,
+            "max_us": self.max.as_micros() as u64,
+            "ops_per_sec": self.ops_per_sec(),
+            "bytes_per_sec": self.bytes_per_sec(),
+        })
+    }
+}
+
+/// The measurements of all the operations, in the order they were made.
+///
+/// With the `serde` feature a report can be serialized but not deserialized, as the names of the
+/// operations are static strings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BenchReport {
+    pub ops: Vec<OpStats>,
+}
+
+impl BenchReport {
+    /// Get the report as a JSON array, one object for each operation.
+    pub fn to_json(&self) -> Value {
+        Value::Array(self.ops.iter().map(|o| o.to_json()).collect())
+    }
+}
+
+/// Generate the blocks of a synthetic archive, each with its hash.
+///
+/// The blocks form a chain, the first has a parent of all zeros like a genesis block. The same
+/// configuration always gives the same blocks.
+pub fn synthetic_blocks(count: usize, sizes: &[usize]) -> impl Iterator<Item=(BlockHash, Vec<u8>)> + '_ {
+    let mut prev_hash = [0u8; 32];
+    (0..count).map(move |i| {
+        let size = sizes.get(i % sizes.len().max(1)).copied().unwrap_or(MIN_BLOCK_SIZE);
+        let block = synthetic_block(&prev_hash, i as u32, size);
+        let block_hash = BlockHash::sha256d(&block[..80]);
+        prev_hash = block_hash.hash;
+        (block_hash, block)
+    })
+}
+
+/// Generate a block with the given parent, height, and size.
+pub fn synthetic_block(prev_hash: &[u8; 32], height: u32, size: usize) -> Vec<u8> {
+    let tx = synthetic_tx(height, size.max(MIN_BLOCK_SIZE) - 81);
+    let mut block = Vec::with_capacity(81 + tx.len());
+    block.extend_from_slice(&1u32.to_le_bytes());
+    block.extend_from_slice(prev_hash);
+    // the merkle root of a block with one transaction is its txid
+    block.extend_from_slice(&BlockHash::sha256d(&tx).hash);
+    block.extend_from_slice(&(FIRST_TIMESTAMP + height * 600).to_le_bytes());
+    block.extend_from_slice(&BITS.to_le_bytes());
+    block.extend_from_slice(&0u32.to_le_bytes());
+    write_varint(&mut block, 1);
+    block.extend_from_slice(&tx);
+    block
+}
+
+// Generate a coinbase transaction of the given size, at least 68 bytes, with an output script that
+// pads it out.
+fn synthetic_tx(height: u32, size: usize) -> Vec<u8> {
+    // the longest output script that fits, the length of its own length depends on it
+    let mut script_len = size.saturating_sub(TX_OVERHEAD + 1);
+    while script_len > 0 && TX_OVERHEAD + varint_size(script_len as u64) + script_len > size {
+        script_len -= 1;
+    }
+    // the coinbase script makes up the byte or two that are left when the length grows
+    let extra = size.saturating_sub(TX_OVERHEAD + varint_size(script_len as u64) + script_len);
+    let mut tx = Vec::with_capacity(size);
+    tx.extend_from_slice(&1u32.to_le_bytes());
+    tx.push(1);
+    tx.extend_from_slice(&[0u8; 32]);
+    tx.extend_from_slice(&u32::MAX.to_le_bytes());
+    // the height in the coinbase script makes each transaction different
+    tx.push(8 + extra as u8);
+    tx.extend_from_slice(&(height as u64).to_le_bytes());
+    tx.resize(tx.len() + extra, 0);
+    tx.extend_from_slice(&u32::MAX.to_le_bytes());
+    tx.push(1);
+    tx.extend_from_slice(&0u64.to_le_bytes());
+    write_varint(&mut tx, script_len as u64);
+    // OP_RETURN followed by padding
+    let mut state = 0x9e3779b97f4a7c15u64 ^ height as u64;
+    let mut script = Vec::with_capacity(script_len + 8);
+    script.push(0x6a);
+    while script.len() < script_len {
+        // xorshift, cheap enough not to slow down the generation of large blocks
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        script.extend_from_slice(&state.to_le_bytes());
+    }
+    script.truncate(script_len);
+    tx.extend_from_slice(&script);
+    tx.extend_from_slice(&0u32.to_le_bytes());
+    tx
+}
+
+/// Store the blocks of a synthetic archive in the archive and measure the store, get, header,
+/// and list operations.
+///
+/// The archive should be empty, a block that is already in it fails to store. The blocks are
+/// left in the archive. Each block is generated just before it is stored, which is not included
+/// in the measurements, so the blocks are not all held in memory.
+pub async fn run<A: BlockArchive + ?Sized>(archive: &mut A, config: &BenchConfig) -> Result<BenchReport> {
+    let mut hashes = Vec::with_capacity(config.blocks);
+    let mut store = Timings::new("store");
+    for (block_hash, block) in synthetic_blocks(config.blocks, &config.block_sizes) {
+        let start = Instant::now();
+        archive.store_block(&block_hash, &mut &block[..]).await?;
+        store.add(start.elapsed(), block.len() as u64);
+        hashes.push(block_hash);
+    }
+    let mut get = Timings::new("get");
+    let mut buf = Vec::new();
+    for block_hash in hashes.iter() {
+        buf.clear();
+        let start = Instant::now();
+        archive.get_block(block_hash).await?.read_to_end(&mut buf).await?;
+        get.add(start.elapsed(), buf.len() as u64);
+    }
+    let mut header = Timings::new("header");
+    for block_hash in hashes.iter() {
+        let start = Instant::now();
+        archive.block_header(block_hash).await?;
+        header.add(start.elapsed(), 80);
+    }
+    let mut list = Timings::new("list");
+    for _ in 0..config.list_rounds {
+        let start = Instant::now();
+        let mut results = archive.block_list().await?;
+        while results.next().await.is_some() {}
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        list.add(start.elapsed(), 0);
+    }
+    Ok(BenchReport { ops: vec![store.finish(), get.finish(), header.finish(), list.finish()] })
+}
+
+// Collects the times taken by an operation.
+struct Timings {
+    op: &'static str,
+    times: Vec<Duration>,
+    bytes: u64,
+}
+
+impl Timings {
+    fn new(op: &'static str) -> Timings {
+        Timings { op, times: Vec::new(), bytes: 0 }
+    }
+
+    fn add(&mut self, elapsed: Duration, bytes: u64) {
+        self.times.push(elapsed);
+        self.bytes += bytes;
+    }
+
+    fn finish(mut self) -> OpStats {
+        self.times.sort();
+        OpStats {
+            op: self.op,
+            count: self.times.len() as u64,
+            bytes: self.bytes,
+            elapsed: self.times.iter().sum(),
+            p50: percentile(&self.times, 50),
+            p99: percentile(&self.times, 99),
+            max: self.times.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+// Get a percentile of sorted times, zero if there are none.
+fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() * pct).div_ceil(100).max(1) - 1]
+}
+
+// Get a rate per second, zero if no time was taken.
+fn per_sec(n: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() { 0.0 } else { n as f64 / elapsed.as_secs_f64() }
+}
+
+// Get the number of bytes in the encoding of a varint.
+fn varint_size(n: u64) -> usize {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x10000..=0xffffffff => 5,
+        _ => 9,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use mktemp::Temp;
+    use crate::{SimpleFileBasedBlockArchive, WritePolicy};
+    use super::*;
+
+    // Generated blocks have the requested sizes, form a chain, and pass the merkle check.
+    #[tokio::test]
+    async fn test_synthetic_blocks() {
+        let sizes = [0, 149, 400, 100_000];
+        let blocks: Vec<(BlockHash, Vec<u8>)> = synthetic_blocks(5, &sizes).collect();
+        let lengths: Vec<usize> = blocks.iter().map(|(_, b)| b.len()).collect();
+        assert_eq!(lengths, vec![149, 149, 400, 100_000, 149]);
+        assert_eq!(blocks[0].1[4..36], [0u8; 32]);
+        for i in 1..blocks.len() {
+            assert_eq!(blocks[i].1[4..36], blocks[i - 1].0.hash);
+        }
+        // the length of the output script takes three bytes from a block of 404 bytes
+        for size in 400..406 {
+            assert_eq!(synthetic_block(&[0u8; 32], 0, size).len(), size);
+        }
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap()
+            .with_write_policy(WritePolicy::MerkleRoot);
+        for (block_hash, block) in blocks.iter() {
+            archive.store_block(block_hash, &mut &block[..]).await.unwrap();
+        }
+    }
+
+    // Every operation is measured for each block.
+    #[tokio::test]
+    async fn test_run() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let config = BenchConfig { blocks: 4, block_sizes: vec![1000, 2000], list_rounds: 2 };
+        let report = run(&mut archive, &config).await.unwrap();
+        let counts: Vec<(&str, u64)> = report.ops.iter().map(|o| (o.op, o.count)).collect();
+        assert_eq!(counts, vec![("store", 4), ("get", 4), ("header", 4), ("list", 2)]);
+        assert_eq!(report.ops[0].bytes, 6000);
+        assert_eq!(report.ops[1].bytes, 6000);
+        assert!(report.ops.iter().all(|o| o.p50 <= o.p99 && o.p99 <= o.max && o.max <= o.elapsed));
+        assert_eq!(report.to_json()[0]["op"], json!("store"));
+        assert_eq!(percentile(&[Duration::from_millis(1)], 99), Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,200 @@
+//! Import and export of the blk*.dat block files written by an SV Node.
+//!
+//! Each block in a blk*.dat file is preceded by the disk magic of the network and the length of
+//! the block as a little-endian u32. The node pre-allocates space in the files, so the end of a
+//! file may be filled with zeros.
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use bitcoinsv::bitcoin::BlockHash;
+use tracing::warn;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
+use crate::{BlockArchive, Error, Network, Result};
+
+// the size of an encoded block header
+const HEADER_SIZE: u32 = 80;
+
+/// A summary of an import.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ImportSummary {
+    /// The number of blocks stored in the archive.
+    pub imported: usize,
+    /// The number of blocks skipped because they were already in the archive.
+    pub skipped: usize,
+    /// The number of blocks that could not be read or stored.
+    pub failed: usize,
+}
+
+/// List the blk*.dat files in a directory, in order.
+pub async fn blkdat_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("blk") && name.ends_with(".dat") {
+            files.push(entry.path());
+        }
+    }
+    // the file names are zero padded, so they sort in the order they were written
+    files.sort();
+    Ok(files)
+}
+
+/// Import every block in the blk*.dat files in a directory into an archive.
+///
+/// Blocks that are already in the archive are skipped. A block that can not be stored is counted
+/// as failed and the import continues. If the framing of a file is damaged then the rest of that
+/// file is skipped, counting as one failure. The progress function is called after each file.
+pub async fn import_blkdat<A, F>(archive: &A, dir: &Path, network: Network, mut progress: F) -> Result<ImportSummary>
+    where A: BlockArchive + ?Sized, F: FnMut(&Path, &ImportSummary)
+{
+    let mut summary = ImportSummary::default();
+    for path in blkdat_files(dir).await? {
+        if let Err(e) = import_file(archive, &path, network, &mut summary).await {
+            warn!(path = %path.display(), error = %e, "error importing block file");
+            summary.failed += 1;
+        }
+        progress(&path, &summary);
+    }
+    Ok(summary)
+}
+
+/// Import every block in a single blk*.dat file into an archive, adding the results to summary.
+///
+/// Fails if the framing of the file is damaged, blocks that were imported before the damage
+/// remain in the archive.
+pub async fn import_file<A: BlockArchive + ?Sized>(archive: &A, path: &Path, network: Network, summary: &mut ImportSummary) -> Result<()> {
+    let file = File::open(path).await?;
+    let file_len = file.metadata().await?.len();
+    let mut reader = BufReader::new(file);
+    // the position of the start of the next block in the file
+    let mut pos = 0;
+    let mut magic = [0u8; 4];
+    loop {
+        match reader.read_exact(&mut magic).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        // the rest of the file is pre-allocated space
+        if magic == [0u8; 4] {
+            return Ok(());
+        }
+        if magic != network.disk_magic() {
+            return Err(Error::InvalidBlockFile(format!("bad magic in {}", path.display())));
+        }
+        let size = reader.read_u32_le().await?;
+        pos += 8 + size as u64;
+        // a block that was being written when the node stopped can be cut short
+        if size < HEADER_SIZE || pos > file_len {
+            return Err(Error::InvalidBlockFile(format!("bad block size in {}", path.display())));
+        }
+        let mut header = [0u8; HEADER_SIZE as usize];
+        reader.read_exact(&mut header).await?;
+        let block_hash = BlockHash::sha256d(&header);
+        let remaining = (size - HEADER_SIZE) as u64;
+        if archive.block_exists(&block_hash).await? {
+            reader.seek(SeekFrom::Current(remaining as i64)).await?;
+            summary.skipped += 1;
+            continue;
+        }
+        // stream the block into the archive, without holding it in memory
+        let mut body = (&mut reader).take(remaining);
+        let r = {
+            let mut block = (&header[..]).chain(&mut body);
+            archive.store_block(&block_hash, &mut block).await
+        };
+        // make sure that the reader is at the start of the next block even if the store failed
+        let unread = body.limit();
+        if unread > 0 {
+            reader.seek(SeekFrom::Current(unread as i64)).await?;
+        }
+        match r {
+            Ok(_) => summary.imported += 1,
+            Err(e) => {
+                warn!(block_hash = %block_hash, error = %e, "failed to import block");
+                summary.failed += 1;
+            }
+        }
+    }
+}
+
+/// Write a block from the archive with blk*.dat framing, so that a sequence of blocks written
+/// with this function can be read by an SV Node or by [import_file].
+///
+/// Returns the size of the block.
+pub async fn write_block<A, W>(archive: &A, block_hash: &BlockHash, network: Network, writer: &mut W) -> Result<u64>
+    where A: BlockArchive + ?Sized, W: AsyncWrite + Unpin + ?Sized
+{
+    let size = archive.block_size(block_hash).await?;
+    // the framing can not describe blocks of 4GB or more
+    let size = u32::try_from(size).map_err(|_| Error::InvalidBlockFile(format!("block {} is too large", block_hash)))?;
+    let mut reader = archive.get_block(block_hash).await?;
+    writer.write_all(&network.disk_magic()).await?;
+    writer.write_u32_le(size).await?;
+    let copied = tokio::io::copy(&mut (&mut reader).take(size as u64), writer).await?;
+    if copied != size as u64 {
+        return Err(Error::CorruptBlock(*block_hash));
+    }
+    Ok(size as u64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Write the test blocks to a blk file, then import them twice.
+    #[tokio::test]
+    async fn test_import_blkdat() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let mut data = Vec::new();
+        let mut results = src.block_list().await.unwrap();
+        while let Some(h) = tokio_stream::StreamExt::next(&mut results).await {
+            let mut block = Vec::new();
+            src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+            data.extend_from_slice(&Network::Mainnet.disk_magic());
+            data.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            data.extend_from_slice(&block);
+        }
+        // pre-allocated space
+        data.extend_from_slice(&[0u8; 100]);
+        let dir = Temp::new_dir().unwrap();
+        tokio::fs::write(dir.to_path_buf().join("blk00000.dat"), &data).await.unwrap();
+        tokio::fs::write(dir.to_path_buf().join("rev00000.dat"), b"not blocks").await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let summary = import_blkdat(&dst, &dir.to_path_buf(), Network::Mainnet, |_, _| {}).await.unwrap();
+        assert_eq!(summary, ImportSummary { imported: 3, skipped: 0, failed: 0 });
+        let summary = import_blkdat(&dst, &dir.to_path_buf(), Network::Mainnet, |_, _| {}).await.unwrap();
+        assert_eq!(summary, ImportSummary { imported: 0, skipped: 3, failed: 0 });
+        // the wrong network is a damaged file
+        let root = Temp::new_dir().unwrap();
+        let dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let summary = import_blkdat(&dst, &dir.to_path_buf(), Network::Testnet, |_, _| {}).await.unwrap();
+        assert_eq!(summary, ImportSummary { imported: 0, skipped: 0, failed: 1 });
+    }
+
+    // Export the test blocks to a blk file and import them again.
+    #[tokio::test]
+    async fn test_export_blkdat() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let mut data = Vec::new();
+        let mut results = src.block_list().await.unwrap();
+        while let Some(h) = tokio_stream::StreamExt::next(&mut results).await {
+            let size = write_block(&src, &h, Network::Regtest, &mut data).await.unwrap();
+            assert_eq!(size, src.block_size(&h).await.unwrap() as u64);
+        }
+        let dir = Temp::new_dir().unwrap();
+        tokio::fs::write(dir.to_path_buf().join("blk00000.dat"), &data).await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let summary = import_blkdat(&dst, &dir.to_path_buf(), Network::Regtest, |_, _| {}).await.unwrap();
+        assert_eq!(summary, ImportSummary { imported: 3, skipped: 0, failed: 0 });
+    }
+}
@@ -1,21 +1,58 @@
+use std::collections::BTreeMap;
+use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use async_trait::async_trait;
-use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
-use tokio::io::AsyncRead;
+use futures::{StreamExt, TryStreamExt};
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use hex::FromHex;
+use ring::digest;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinHandle;
 use tokio_stream::Stream;
-use crate::Result;
+use crate::headers::HEADER_SIZE;
+use crate::journal::Batch;
+use crate::manifest::sha256_reader;
+use crate::merkle::{compute_merkle_root, validate_no_duplicate_vulnerability};
+use crate::txindex::{read_varint, scan_transactions};
+use crate::{ChainIndex, Error, Result};
 
+// the number of blocks that block_stream() opens ahead of the consumer
+const BLOCK_PREFETCH: usize = 4;
+
+// the number of headers read at a time by block_list_opts() for height order
+const HEADER_BATCH_SIZE: usize = 500;
+
+// the number of block sizes that block_sizes() reads at the same time
+const SIZE_CONCURRENCY: usize = 16;
+
+// the most bytes in an encoded varint
+const MAX_VARINT_SIZE: usize = 9;
+
+/// The header, number of transactions and size of a block, see [BlockArchive::block_summary].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockSummary {
+    /// The header of the block.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::header"))]
+    pub header: BlockHeader,
+    /// The number of transactions in the block.
+    pub tx_count: u64,
+    /// The size of the block in bytes.
+    pub size: u64,
+}
 
 /// The BlockArchive stores blocks, where a block is a BlockHeader and the transactions
 /// that are required to validate the block.
 ///
 /// The BlockArchive has very little knowledge of the structure of block, it only knows how to
 /// store and retrieve blocks.
+///
+/// The trait is object safe, so the archive implementation can be chosen at runtime and used as
+/// a `Box<dyn BlockArchive>`. A boxed archive is itself a BlockArchive.
 #[async_trait]
-pub trait BlockArchive {
+pub trait BlockArchive: Send + Sync {
     /// Get a block from the archive.
     ///
     /// Returns a reader for the encoded block.
@@ -24,15 +61,69 @@ pub trait BlockArchive {
     /// bytes in the block.
     async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>>;
 
+    /// Get part of a block from the archive, for example a single transaction.
+    ///
+    /// Returns a reader for length bytes of the encoded block, starting at offset. If the range
+    /// extends past the end of the block the reader stops at the end of the block. The default
+    /// reads and discards the start of the block, backends that can seek or make range requests
+    /// only read the range.
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let mut reader = self.get_block(block_hash).await?;
+        tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink()).await?;
+        Ok(Box::new(reader.take(length)))
+    }
+
     /// Check if a block exists in the archive.
     async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool>;
 
+    /// Check whether each of several blocks is in the archive, in the same order as the hashes.
+    ///
+    /// The default checks the blocks one at a time, backends where each check has a high latency
+    /// check them concurrently.
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        let mut result = Vec::with_capacity(block_hashes.len());
+        for block_hash in block_hashes {
+            result.push((*block_hash, self.block_exists(block_hash).await?));
+        }
+        Ok(result)
+    }
+
     /// Store a block in the archive.
     ///
     /// Expects a reader for the encoded block.
     ///
-    /// This function does not do any checking of the block, it stores the bytes of the block as is.
-    async fn store_block(&self, block_hash: &BlockHash, block: &mut Box<dyn AsyncRead + Unpin + Send>) -> Result<()>;
+    /// This function does not do any checking of the block unless the archive has a
+    /// [WritePolicy], it stores the bytes of the block as is. Fails with [Error::BlockExists] if
+    /// the block is already in the archive, unless the archive has been set to overwrite blocks
+    /// (for example [crate::SimpleFileBasedBlockArchive::with_overwrite]), in which case the stored
+    /// block is replaced and its attributes are kept.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()>;
+
+    /// Store a block in the archive if it is not already there, returns false if it was already
+    /// there. An existing block is never replaced, even if the archive has been set to overwrite
+    /// blocks, so a failed copy can be retried safely.
+    async fn store_block_if_absent(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<bool> {
+        if self.block_exists(block_hash).await? {
+            return Ok(false);
+        }
+        match self.store_block(block_hash, block).await {
+            Ok(()) => Ok(true),
+            // stored by someone else since the check
+            Err(Error::BlockExists) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Start a batch of writes to the archive, which can be committed or aborted as a whole, and
+    /// recovered with a journal if the process stops. See [crate::journal].
+    fn begin_batch(self) -> Batch<Self> where Self: Sized {
+        Batch::new(self)
+    }
+
+    /// Remove a block from the archive, along with its attributes and checksum.
+    ///
+    /// Fails with [Error::BlockNotFound] if the block is not in the archive.
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()>;
 
     /// Get the size of a block in the archive.
     async fn block_size(&self, block_hash: &BlockHash) -> Result<usize>;
@@ -40,6 +131,34 @@ pub trait BlockArchive {
     /// Get the header of a block in the archive.
     async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader>;
 
+    /// Get the header, number of transactions and size of a block in the archive.
+    ///
+    /// Only the header and the transaction count at the start of the block are read, the
+    /// transactions are not parsed. The default reads them with [BlockArchive::get_block_range].
+    /// Fails with [Error::CorruptBlock] if the start of the block can not be decoded.
+    async fn block_summary(&self, block_hash: &BlockHash) -> Result<BlockSummary> {
+        let size = self.block_size(block_hash).await? as u64;
+        let mut reader = self.get_block_range(block_hash, 0, (HEADER_SIZE + MAX_VARINT_SIZE) as u64).await?;
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header).await.map_err(|_| Error::CorruptBlock(*block_hash))?;
+        let header = BlockHeader::from_binary(&mut &header[..]).await.map_err(|_| Error::CorruptBlock(*block_hash))?;
+        let tx_count = read_varint(&mut reader, &mut Vec::new()).await.map_err(|_| Error::CorruptBlock(*block_hash))?;
+        Ok(BlockSummary { header, tx_count, size })
+    }
+
+    /// Get the headers of several blocks in the archive, in the same order as the hashes.
+    ///
+    /// Fails with [Error::BlockNotFound] if any of the blocks is not in the archive. The default
+    /// reads the headers one at a time, backends where each read has a high latency read them
+    /// concurrently.
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        let mut headers = Vec::with_capacity(block_hashes.len());
+        for block_hash in block_hashes {
+            headers.push((*block_hash, self.block_header(block_hash).await?));
+        }
+        Ok(headers)
+    }
+
     /// Get a list of all the blocks in the archive.
     ///
     /// It returns a stream of block hashes.
@@ -50,23 +169,518 @@ pub trait BlockArchive {
     ///       println!("{}", block_hash);
     ///     }
     async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>>;
+
+    /// Find the blocks in the archive whose hex encoded hash starts with a prefix, sorted by hash,
+    /// for example to accept short hashes the way git accepts short commit ids.
+    ///
+    /// Fails with [Error::InvalidHash] if the prefix is not hex or is longer than a hash. A full
+    /// hash is looked up with [BlockArchive::block_exists], the default lists the blocks with
+    /// [BlockArchive::block_list_opts] for a shorter prefix.
+    async fn resolve_prefix(&mut self, prefix: &str) -> Result<Vec<BlockHash>> {
+        let prefix = check_hash_prefix(prefix)?;
+        if let Some(block_hash) = full_hash(&prefix) {
+            return Ok(if self.block_exists(&block_hash).await? { vec![block_hash] } else { Vec::new() });
+        }
+        list_prefix(self, prefix).await
+    }
+
+    /// Get a list of the blocks in the archive, sorted, filtered, or a page at a time.
+    ///
+    /// The unsorted list is streamed, a sorted list is collected and sorted before the first hash
+    /// is returned. Sorting by height reads the header of every block, see [ListOrder::Height].
+    ///
+    /// Example code:
+    ///     let options = ListOptions { order: ListOrder::Hash, prefix: Some("00".to_string()), limit: Some(100), ..Default::default() };
+    ///     let mut results = archive.block_list_opts(options).await?;
+    async fn block_list_opts(&mut self, options: ListOptions) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let mut results = self.block_list().await?;
+        if options.order == ListOrder::Unsorted {
+            return Ok(Box::pin(FilteredBlockHashList::new(results, &options)));
+        }
+        let mut hashes = Vec::new();
+        while let Some(block_hash) = results.next().await {
+            hashes.push(block_hash);
+        }
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        match options.order {
+            ListOrder::Height => {
+                // the chain is built from every block, not just the ones that will be listed
+                let mut headers = Vec::with_capacity(hashes.len());
+                for batch in hashes.chunks(HEADER_BATCH_SIZE) {
+                    headers.extend(self.block_headers(batch).await?.into_iter().map(|(_, h)| h));
+                }
+                let chain = ChainIndex::from_headers(headers);
+                hashes.sort_by_cached_key(|h| (chain.height_of(h).is_none(), chain.height_of(h), h.to_string()));
+            }
+            _ => hashes.sort_by_cached_key(|h| h.to_string()),
+        }
+        let results = Box::pin(BlockHashListStreamFromVec { hashes: hashes.into_iter() });
+        Ok(Box::pin(FilteredBlockHashList::new(results, &options)))
+    }
+
+    /// Get a stream of all the blocks in the archive, with a reader for each block.
+    ///
+    /// This saves consumers that read every block from listing the blocks and then opening each
+    /// one. The default opens a few blocks ahead of the consumer, backends may prefetch more.
+    ///
+    /// Example code:
+    ///     let mut blocks = archive.block_stream().await?;
+    ///     while let Some(r) = blocks.next().await {
+    ///       let (block_hash, mut reader) = r?;
+    ///     }
+    async fn block_stream<'a>(&'a mut self) -> Result<BlockStream<'a>> {
+        let hashes = self.block_list().await?;
+        let archive = &*self;
+        let stream = listed_hashes(hashes)
+            .map(move |r| async move {
+                let block_hash = r?;
+                archive.get_block(&block_hash).await.map(|reader| (block_hash, reader))
+            })
+            .buffered(BLOCK_PREFETCH);
+        Ok(Box::pin(stream))
+    }
+
+    /// Get a stream of all the blocks in the archive with their sizes in bytes.
+    ///
+    /// The default lists the blocks and gets the size of each one, backends that keep an index of
+    /// the sizes read it instead, see [crate::SimpleFileBasedBlockArchive::rebuild_sizes].
+    ///
+    /// Example code:
+    ///     let mut sizes = archive.block_sizes().await?;
+    ///     while let Some(r) = sizes.next().await {
+    ///       let (block_hash, size) = r?;
+    ///     }
+    async fn block_sizes<'a>(&'a mut self) -> Result<BlockSizeStream<'a>> {
+        list_block_sizes(self).await
+    }
+
+    /// Get the total size of all the blocks in the archive in bytes, see [BlockArchive::block_sizes].
+    async fn total_size(&mut self) -> Result<u64> {
+        let mut sizes = self.block_sizes().await?;
+        let mut total = 0;
+        while let Some(r) = sizes.next().await {
+            total += r?.1;
+        }
+        Ok(total)
+    }
+
+    /// Get the blocks whose header timestamp is from start up to but not including end, in seconds
+    /// since the epoch, in order of time.
+    ///
+    /// If median_time_past is set the median time past of each block is used instead of its
+    /// timestamp, see [ChainIndex::median_time_past]. The headers are read with
+    /// [BlockArchive::block_headers], which is fast for an archive with a headers file.
+    async fn blocks_in_time_range(&mut self, start: u64, end: u64, median_time_past: bool) -> Result<Vec<BlockHash>> {
+        let mut hashes = Vec::new();
+        let mut results = self.block_list().await?;
+        while let Some(block_hash) = results.next().await {
+            hashes.push(block_hash);
+        }
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        let mut headers = Vec::with_capacity(hashes.len());
+        for batch in hashes.chunks(HEADER_BATCH_SIZE) {
+            headers.extend(self.block_headers(batch).await?);
+        }
+        let mut blocks: Vec<(u64, BlockHash)> = if median_time_past {
+            let chain = ChainIndex::from_headers(headers.into_iter().map(|(_, h)| h));
+            hashes.iter().filter_map(|h| chain.median_time_past(h).map(|t| (t, *h))).collect()
+        } else {
+            headers.into_iter().map(|(h, header)| (header.timestamp as u64, h)).collect()
+        };
+        blocks.retain(|(t, _)| *t >= start && *t < end);
+        blocks.sort();
+        Ok(blocks.into_iter().map(|(_, h)| h).collect())
+    }
+
+    /// Set a user-defined attribute on a block, replacing any previous value for the key.
+    ///
+    /// Attributes are small key/value strings such as "source=peer-x". Keys must not be empty or
+    /// contain '=' or newlines, values must not contain newlines.
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()>;
+
+    /// Remove a user-defined attribute from a block. It is not an error if the attribute is not set.
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()>;
+
+    /// Get all the user-defined attributes of a block.
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs>;
+
+    /// Check a block against the SHA-256 checksum that was recorded when it was stored.
+    ///
+    /// This is much faster than checking the merkle root and detects damage to the stored block.
+    /// Returns false if no checksum was recorded, for example because the block was stored by an
+    /// older version of the library. Fails with [Error::ChecksumMismatch] if the block does not
+    /// match the checksum.
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool>;
+}
+
+#[async_trait]
+impl<A: BlockArchive + ?Sized> BlockArchive for Box<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        (**self).get_block(block_hash).await
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        (**self).get_block_range(block_hash, offset, length).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        (**self).block_exists(block_hash).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        (**self).blocks_exist(block_hashes).await
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        (**self).store_block(block_hash, block).await
+    }
+
+    async fn store_block_if_absent(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<bool> {
+        (**self).store_block_if_absent(block_hash, block).await
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        (**self).remove_block(block_hash).await
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        (**self).block_size(block_hash).await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        (**self).block_header(block_hash).await
+    }
+
+    async fn block_summary(&self, block_hash: &BlockHash) -> Result<BlockSummary> {
+        (**self).block_summary(block_hash).await
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        (**self).block_headers(block_hashes).await
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        (**self).block_list().await
+    }
+
+    async fn block_list_opts(&mut self, options: ListOptions) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        (**self).block_list_opts(options).await
+    }
+
+    async fn resolve_prefix(&mut self, prefix: &str) -> Result<Vec<BlockHash>> {
+        (**self).resolve_prefix(prefix).await
+    }
+
+    async fn block_stream<'a>(&'a mut self) -> Result<BlockStream<'a>> {
+        (**self).block_stream().await
+    }
+
+    async fn block_sizes<'a>(&'a mut self) -> Result<BlockSizeStream<'a>> {
+        (**self).block_sizes().await
+    }
+
+    async fn total_size(&mut self) -> Result<u64> {
+        (**self).total_size().await
+    }
+
+    async fn blocks_in_time_range(&mut self, start: u64, end: u64, median_time_past: bool) -> Result<Vec<BlockHash>> {
+        (**self).blocks_in_time_range(start, end, median_time_past).await
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        (**self).set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        (**self).remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        (**self).get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        (**self).verify_checksum(block_hash).await
+    }
+}
+
+/// The user-defined attributes of a block, see [BlockArchive::set_block_attr].
+pub type BlockAttrs = BTreeMap<String, String>;
+
+/// The order of the blocks listed by [BlockArchive::block_list_opts].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ListOrder {
+    /// The order of [BlockArchive::block_list], which depends on the backend.
+    #[default]
+    Unsorted,
+    /// In order of the hex encoded hash.
+    Hash,
+    /// In order of height, see [ChainIndex]. Blocks at the same height are in order of hash, and
+    /// blocks that are not linked to a genesis block come last.
+    Height,
+}
+
+/// The options of [BlockArchive::block_list_opts].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ListOptions {
+    /// The order of the blocks.
+    pub order: ListOrder,
+    /// Only list blocks whose hex encoded hash starts with this prefix.
+    pub prefix: Option<String>,
+    /// The number of matching blocks to skip.
+    pub offset: usize,
+    /// The largest number of blocks to list.
+    pub limit: Option<usize>,
+}
+
+/// The checks that an archive makes on a block given to [BlockArchive::store_block], before the
+/// block is stored. A block that fails a check is rejected with [Error::InvalidBlock] and nothing
+/// is stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WritePolicy {
+    /// The block is stored as it is given.
+    #[default]
+    None,
+    /// The header of the block must have the hash that the block is stored under. The rest of
+    /// the block is not checked, it is streamed to storage as usual.
+    HeaderOnly,
+    /// As HeaderOnly, and the transactions must parse and match the merkle root of the header.
+    /// The whole block is read into memory before it is stored.
+    MerkleRoot,
+}
+
+/// A stream of blocks with a reader for each, returned by [BlockArchive::block_stream].
+pub type BlockStream<'a> = Pin<Box<dyn Stream<Item = Result<(BlockHash, Box<dyn AsyncRead + Unpin + Send>)>> + Send + 'a>>;
+
+/// A stream of blocks with the size of each, returned by [BlockArchive::block_sizes].
+pub type BlockSizeStream<'a> = Pin<Box<dyn Stream<Item = Result<(BlockHash, u64)>> + Send + 'a>>;
+
+// The hashes of a listing followed by the error that ended it, if it failed part way, so that a
+// stream built on the listing does not end as though it were complete.
+fn listed_hashes(hashes: Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>) -> impl Stream<Item = Result<BlockHash>> + Send {
+    futures::stream::unfold(Some(hashes), |hashes| async move {
+        let mut hashes = hashes?;
+        match hashes.next().await {
+            Some(block_hash) => Some((Ok(block_hash), Some(hashes))),
+            None => hashes.as_mut().take_error().map(|e| (Err(e), None)),
+        }
+    })
+}
+
+// List the blocks of an archive and get the size of each one, the default of
+// BlockArchive::block_sizes().
+pub(crate) async fn list_block_sizes<A: BlockArchive + ?Sized>(archive: &mut A) -> Result<BlockSizeStream<'_>> {
+    let hashes = archive.block_list().await?;
+    let archive = &*archive;
+    let stream = listed_hashes(hashes)
+        .map(move |r| async move {
+            let block_hash = r?;
+            archive.block_size(&block_hash).await.map(|size| (block_hash, size as u64))
+        })
+        .buffered(SIZE_CONCURRENCY);
+    Ok(Box::pin(stream))
+}
+
+// Check whether each of several blocks exists, with up to concurrency checks at the same time.
+pub(crate) async fn check_blocks_exist<A: BlockArchive + ?Sized>(archive: &A, block_hashes: &[BlockHash], concurrency: usize) -> Result<Vec<(BlockHash, bool)>> {
+    futures::stream::iter(block_hashes.to_vec())
+        .map(move |h| async move { archive.block_exists(&h).await.map(|exists| (h, exists)) })
+        .buffered(concurrency)
+        .try_collect()
+        .await
+}
+
+// Check that an attribute key and value can be stored.
+pub(crate) fn validate_block_attr(key: &str, value: &str) -> Result<()> {
+    if key.is_empty() || key.contains(['=', '\n', '\r']) {
+        return Err(Error::InvalidAttribute(key.to_string()));
+    }
+    if value.contains(['\n', '\r']) {
+        return Err(Error::InvalidAttribute(key.to_string()));
+    }
+    Ok(())
+}
+
+// Encode attributes as "key=value" lines.
+pub(crate) fn encode_block_attrs(attrs: &BlockAttrs) -> String {
+    let mut s = String::new();
+    for (key, value) in attrs.iter() {
+        s.push_str(key);
+        s.push('=');
+        s.push_str(value);
+        s.push('\n');
+    }
+    s
+}
+
+// Decode attributes encoded by encode_block_attrs().
+pub(crate) fn decode_block_attrs(s: &str) -> Result<BlockAttrs> {
+    let mut attrs = BlockAttrs::new();
+    for line in s.lines() {
+        match line.split_once('=') {
+            Some((key, value)) => { attrs.insert(key.to_string(), value.to_string()); }
+            None => return Err(Error::InvalidAttribute(line.to_string())),
+        }
+    }
+    Ok(attrs)
+}
+
+// A reader that calculates the SHA-256 checksum of the data read through it, used to record the
+// checksum of a block as it is stored.
+pub(crate) struct ChecksumReader<R> {
+    inner: R,
+    context: digest::Context,
+}
+
+impl<R> ChecksumReader<R> {
+    pub(crate) fn new(inner: R) -> ChecksumReader<R> {
+        ChecksumReader { inner, context: digest::Context::new(&digest::SHA256) }
+    }
+
+    // Get the hex encoded checksum of the data read so far.
+    pub(crate) fn checksum(self) -> String {
+        hex::encode(self.context.finish())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ChecksumReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.context.update(&buf.filled()[before..]);
+        r
+    }
+}
+
+// Apply the write policy of an archive to a block given to store_block(), returning a reader for
+// the block to store. The checks are made before anything is returned.
+pub(crate) async fn checked_block<'a>(policy: WritePolicy, block_hash: &BlockHash, block: &'a mut (dyn AsyncRead + Unpin + Send))
+    -> Result<Box<dyn AsyncRead + Unpin + Send + 'a>>
+{
+    match policy {
+        WritePolicy::None => Ok(Box::new(block)),
+        WritePolicy::HeaderOnly => {
+            let mut header = [0u8; HEADER_SIZE];
+            match block.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err(Error::InvalidBlock(format!("block {} is too short", block_hash)));
+                }
+                Err(e) => return Err(e.into()),
+            }
+            check_header_hash(block_hash, &header).await?;
+            Ok(Box::new(std::io::Cursor::new(header).chain(block)))
+        }
+        WritePolicy::MerkleRoot => {
+            let mut data = Vec::new();
+            block.read_to_end(&mut data).await?;
+            if data.len() < HEADER_SIZE {
+                return Err(Error::InvalidBlock(format!("block {} is too short", block_hash)));
+            }
+            let header = check_header_hash(block_hash, &data[..HEADER_SIZE]).await?;
+            let txids: Vec<BlockHash> = scan_transactions(&mut &data[..]).await
+                .map_err(|_| Error::InvalidBlock(format!("the transactions of block {} can not be read", block_hash)))?
+                .into_iter().map(|(h, _, _)| h).collect();
+            if compute_merkle_root(txids.iter().copied()) != Some(header.merkle_root) {
+                return Err(Error::InvalidBlock(format!("merkle root mismatch for block {}", block_hash)));
+            }
+            if !validate_no_duplicate_vulnerability(txids.into_iter()) {
+                return Err(Error::InvalidBlock(format!("block {} has duplicate txids in its merkle tree", block_hash)));
+            }
+            Ok(Box::new(std::io::Cursor::new(data)))
+        }
+    }
+}
+
+// Check a prefix of a hex encoded block hash, returning it in lower case.
+pub(crate) fn check_hash_prefix(prefix: &str) -> Result<String> {
+    if prefix.len() > 64 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::InvalidHash(prefix.to_string()));
+    }
+    Ok(prefix.to_lowercase())
+}
+
+// List the blocks whose hash starts with a checked prefix, sorted by hash.
+pub(crate) async fn list_prefix<A: BlockArchive + ?Sized>(archive: &mut A, prefix: String) -> Result<Vec<BlockHash>> {
+    let options = ListOptions { order: ListOrder::Hash, prefix: Some(prefix), ..Default::default() };
+    let mut results = archive.block_list_opts(options).await?;
+    let mut hashes = Vec::new();
+    while let Some(block_hash) = results.next().await {
+        hashes.push(block_hash);
+    }
+    match results.as_mut().take_error() {
+        Some(e) => Err(e),
+        None => Ok(hashes),
+    }
+}
+
+// Get the block hash of a prefix that is a full hash.
+pub(crate) fn full_hash(prefix: &str) -> Option<BlockHash> {
+    if prefix.len() == 64 { BlockHash::from_hex(prefix).ok() } else { None }
+}
+
+// Check that an encoded header has the hash that the block is being stored under.
+async fn check_header_hash(block_hash: &BlockHash, header: &[u8]) -> Result<BlockHeader> {
+    let header = BlockHeader::from_binary(&mut &header[..]).await
+        .map_err(|_| Error::InvalidBlock(format!("the header of block {} can not be read", block_hash)))?;
+    if header.hash() != *block_hash {
+        return Err(Error::InvalidBlock(format!("the header has the hash {}, not {}", header.hash(), block_hash)));
+    }
+    Ok(header)
+}
+
+// Check a block in the archive against a hex encoded checksum.
+pub(crate) async fn check_block_checksum<A>(archive: &A, block_hash: &BlockHash, checksum: &str) -> Result<()>
+    where A: BlockArchive + ?Sized
+{
+    let mut reader = archive.get_block(block_hash).await?;
+    let (actual, _) = sha256_reader(&mut reader).await?;
+    if hex::encode(actual) != checksum.trim() {
+        return Err(Error::ChecksumMismatch(*block_hash));
+    }
+    Ok(())
 }
 
 /// A stream of block hashes, returned by [BlockArchive::block_list].
 ///
 /// Implemented as a trait for future extensibility.
-pub trait BlockHashListStream: Stream<Item = BlockHash> {}
+///
+/// A listing that fails part way through ends the stream early. Check
+/// [BlockHashListStream::take_error] when the stream has ended to tell a failed listing from a
+/// complete one.
+pub trait BlockHashListStream: Stream<Item = BlockHash> + Send {
+    /// Take the error that ended the listing, if there was one. Only meaningful once the stream
+    /// has ended.
+    fn take_error(self: Pin<&mut Self>) -> Option<Error> {
+        None
+    }
+}
 
 /// An implementation of the [BlockHashListStream] trait.
 ///
 /// Built for the SimpleFileBasedBlockArchive but expected to be useful elsewhere.
-/// It expects a background task to be created which sends block hashes to a channel. This stream
-/// reads the block hashes from the channel.
+/// It expects a background task to be created which sends block hashes to a bounded channel. This
+/// stream reads the block hashes from the channel, and the task waits when the channel is full,
+/// so the memory used does not depend on the number of blocks.
+///
+/// The stream ends once the task has finished and the hashes it sent have been read. If the task
+/// fails, its error is returned by [BlockHashListStream::take_error], and if it panics or is
+/// cancelled the error is [Error::TaskFailed]. The stream does not wait for senders that the task
+/// left behind.
 pub struct BlockHashListStreamFromChannel {
     // The receiver to which the background task sends block hashes.
     receiver: Receiver<BlockHash>,
-    // Handle to the background task that reads the block hashes.
-    handle: JoinHandle<Result<()>>,
+    // Handle to the background task that reads the block hashes, None once it has finished.
+    handle: Option<JoinHandle<Result<()>>>,
+    // The error returned by the background task.
+    error: Option<Error>,
 }
 
 impl BlockHashListStreamFromChannel {
@@ -74,7 +688,7 @@ impl BlockHashListStreamFromChannel {
     /// to the background process. The handle is used to close the background task when the stream
     /// is dropped.
     pub fn new(receiver: Receiver<BlockHash>, handle: JoinHandle<Result<()>>) -> BlockHashListStreamFromChannel {
-        BlockHashListStreamFromChannel { receiver, handle }
+        BlockHashListStreamFromChannel { receiver, handle: Some(handle), error: None }
     }
 }
 
@@ -82,18 +696,191 @@ impl Stream for BlockHashListStreamFromChannel {
     type Item = BlockHash;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.receiver).poll_recv(cx)
+        // the task is polled as well as the channel, so that a task that ends without dropping
+        // every sender, such as one that panics in a task of its own, still ends the stream
+        if let Some(handle) = self.handle.as_mut() {
+            if let Poll::Ready(result) = Pin::new(handle).poll(cx) {
+                self.handle = None;
+                self.error = match result {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(e),
+                    Err(e) => Some(Error::TaskFailed(e)),
+                };
+                // nothing more will be sent, the hashes already sent can still be read
+                self.receiver.close();
+            }
+        }
+        match Pin::new(&mut self.receiver).poll_recv(cx) {
+            // the senders have gone, wait for the result of the task
+            Poll::Ready(None) if self.handle.is_some() => Poll::Pending,
+            r => r,
+        }
+    }
+}
+
+impl BlockHashListStream for BlockHashListStreamFromChannel {
+    fn take_error(mut self: Pin<&mut Self>) -> Option<Error> {
+        self.error.take()
+    }
+}
+
+// A list of block hashes that has already been collected.
+pub(crate) struct BlockHashListStreamFromVec {
+    pub(crate) hashes: std::vec::IntoIter<BlockHash>,
+}
+
+impl Stream for BlockHashListStreamFromVec {
+    type Item = BlockHash;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.hashes.next())
+    }
+}
+
+impl BlockHashListStream for BlockHashListStreamFromVec {}
+
+// Applies the prefix, offset, and limit of the list options to another list.
+struct FilteredBlockHashList {
+    inner: Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>,
+    prefix: Option<String>,
+    // the number of matching hashes still to skip
+    skip: usize,
+    // the number of hashes still to return
+    remaining: Option<usize>,
+}
+
+impl FilteredBlockHashList {
+    fn new(inner: Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>, options: &ListOptions) -> FilteredBlockHashList {
+        FilteredBlockHashList {
+            inner,
+            prefix: options.prefix.as_ref().map(|p| p.to_lowercase()),
+            skip: options.offset,
+            remaining: options.limit,
+        }
+    }
+}
+
+impl Stream for FilteredBlockHashList {
+    type Item = BlockHash;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.remaining == Some(0) {
+                return Poll::Ready(None);
+            }
+            let block_hash = match self.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(h)) => h,
+                r => return r,
+            };
+            if let Some(prefix) = self.prefix.as_ref() {
+                if !block_hash.to_string().starts_with(prefix.as_str()) {
+                    continue;
+                }
+            }
+            if self.skip > 0 {
+                self.skip -= 1;
+                continue;
+            }
+            if let Some(remaining) = self.remaining.as_mut() {
+                *remaining -= 1;
+            }
+            return Poll::Ready(Some(block_hash));
+        }
     }
 }
 
-impl BlockHashListStream for BlockHashListStreamFromChannel {}
+impl BlockHashListStream for FilteredBlockHashList {
+    fn take_error(mut self: Pin<&mut Self>) -> Option<Error> {
+        self.inner.as_mut().take_error()
+    }
+}
 
 impl Drop for BlockHashListStreamFromChannel {
     // close the handle to the background task when the stream is dropped
     fn drop(&mut self) {
-        if self.handle.is_finished() {
-            return;
+        if let Some(handle) = self.handle.as_ref() {
+            if !handle.is_finished() {
+                handle.abort();
+            }
         }
-        self.handle.abort();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hex::FromHex;
+    use super::*;
+
+    fn hash(n: u64) -> BlockHash {
+        BlockHash::from_hex(format!("{:064x}", n)).unwrap()
+    }
+
+    // A listing larger than the channel is read in full, the task waits for the consumer.
+    #[tokio::test]
+    async fn test_list_stream_backpressure() {
+        let (tx, rx) = tokio::sync::mpsc::channel(2);
+        let handle: JoinHandle<Result<()>> = tokio::spawn(async move {
+            for n in 0..100 {
+                if tx.send(hash(n)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            Ok(())
+        });
+        let mut results = BlockHashListStreamFromChannel::new(rx, handle);
+        let listed: Vec<BlockHash> = (&mut results).collect().await;
+        assert_eq!(listed.len(), 100);
+        assert!(Pin::new(&mut results).take_error().is_none());
+    }
+
+    // The summary of a block has its header, transaction count and size, and a block that ends
+    // within its header can not be summarised.
+    #[tokio::test]
+    async fn test_block_summary() {
+        let archive = crate::SimpleFileBasedBlockArchive::new(std::path::PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let summary = archive.block_summary(&h).await.unwrap();
+        assert_eq!(summary.header, archive.block_header(&h).await.unwrap());
+        assert_eq!(summary.size, archive.block_size(&h).await.unwrap() as u64);
+        assert!(summary.tx_count > 0 && summary.tx_count < summary.size);
+        let mut block = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+
+        let truncated = crate::MemoryBlockArchive::new();
+        truncated.store_block(&h, &mut &block[..40]).await.unwrap();
+        assert!(matches!(truncated.block_summary(&h).await, Err(Error::CorruptBlock(_))));
+    }
+
+    // A prefix finds the blocks that start with it, a full hash finds only its block, and a
+    // prefix that is not hex is refused.
+    #[tokio::test]
+    async fn test_resolve_prefix() {
+        let mut archive = crate::MemoryBlockArchive::new();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        for block_hash in [h, hash(1), hash(2)] {
+            archive.store_block(&block_hash, &mut &b"block"[..]).await.unwrap();
+        }
+        assert_eq!(archive.resolve_prefix("00000000000000A86C").await.unwrap(), vec![h]);
+        assert_eq!(archive.resolve_prefix("0000000000000000000").await.unwrap(), vec![hash(1), hash(2)]);
+        assert_eq!(archive.resolve_prefix(&h.to_string()).await.unwrap(), vec![h]);
+        assert!(archive.resolve_prefix("00000000000000b").await.unwrap().is_empty());
+        assert!(matches!(archive.resolve_prefix("00zz").await, Err(Error::InvalidHash(_))));
+    }
+
+    // A task that panics ends the stream with an error, even if a sender outlives it.
+    #[tokio::test]
+    async fn test_list_stream_panic() {
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        let leftover = tx.clone();
+        let handle: JoinHandle<Result<()>> = tokio::spawn(async move {
+            tx.send(hash(1)).await.unwrap();
+            panic!("listing failed");
+        });
+        let mut results = BlockHashListStreamFromChannel::new(rx, handle);
+        let listed: Vec<BlockHash> = (&mut results).collect().await;
+        assert_eq!(listed, vec![hash(1)]);
+        assert!(matches!(Pin::new(&mut results).take_error(), Some(Error::TaskFailed(_))));
+        drop(leftover);
     }
 }
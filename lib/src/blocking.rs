@@ -0,0 +1,224 @@
+//! A synchronous wrapper around a [BlockArchive], for applications that do not use async.
+//!
+//! Example code:
+//!     let archive = BlockingBlockArchive::open(PathBuf::from("/mnt/blockstore/mainnet")).unwrap();
+//!     for block_hash in archive.block_list().unwrap() {
+//!         println!("{}", archive.block_header(&block_hash).unwrap().timestamp);
+//!     }
+//!     archive.store_block(&block_hash, &mut std::fs::File::open("block.bin").unwrap()).unwrap();
+//!
+//! These functions must not be called from within an async context, they will panic.
+use std::io::Read;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::runtime::{Handle, Runtime};
+use tokio_stream::StreamExt;
+use crate::block_archive::BlockHashListStream;
+use crate::{BlockArchive, BlockAttrs, Error, ListOptions, Result, SimpleFileBasedBlockArchive};
+
+/// Wraps a [BlockArchive] together with a tokio runtime and exposes blocking versions of the
+/// archive functions.
+pub struct BlockingBlockArchive<A: BlockArchive> {
+    runtime: Runtime,
+    archive: A,
+}
+
+impl BlockingBlockArchive<SimpleFileBasedBlockArchive> {
+    /// Open a [SimpleFileBasedBlockArchive] with the given root path.
+    pub fn open(root_path: PathBuf) -> Result<BlockingBlockArchive<SimpleFileBasedBlockArchive>> {
+        let runtime = Runtime::new()?;
+        let archive = runtime.block_on(SimpleFileBasedBlockArchive::new(root_path))?;
+        Ok(BlockingBlockArchive { runtime, archive })
+    }
+}
+
+impl<A: BlockArchive> BlockingBlockArchive<A> {
+    /// Wrap an existing archive, creating a new runtime to drive it.
+    pub fn new(archive: A) -> Result<BlockingBlockArchive<A>> {
+        let runtime = Runtime::new()?;
+        Ok(BlockingBlockArchive { runtime, archive })
+    }
+
+    /// Get a reference to the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+
+    /// Get a block from the archive, returns a reader for the encoded block.
+    pub fn get_block(&self, block_hash: &BlockHash) -> Result<BlockingBlockReader> {
+        let reader = self.runtime.block_on(self.archive.get_block(block_hash))?;
+        Ok(BlockingBlockReader { handle: self.runtime.handle().clone(), reader })
+    }
+
+    /// Get part of a block from the archive, see [BlockArchive::get_block_range].
+    pub fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<BlockingBlockReader> {
+        let reader = self.runtime.block_on(self.archive.get_block_range(block_hash, offset, length))?;
+        Ok(BlockingBlockReader { handle: self.runtime.handle().clone(), reader })
+    }
+
+    /// Check if a block exists in the archive.
+    pub fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.runtime.block_on(self.archive.block_exists(block_hash))
+    }
+
+    /// Store a block in the archive, reading the encoded block from the reader.
+    pub fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn Read + Send)) -> Result<()> {
+        let mut reader = BlockingReaderAdapter { reader: block };
+        self.runtime.block_on(self.archive.store_block(block_hash, &mut reader))
+    }
+
+    /// Remove a block from the archive, along with its attributes and checksum.
+    pub fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.runtime.block_on(self.archive.remove_block(block_hash))
+    }
+
+    /// Get the size of a block in the archive.
+    pub fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.runtime.block_on(self.archive.block_size(block_hash))
+    }
+
+    /// Get the header of a block in the archive.
+    pub fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.runtime.block_on(self.archive.block_header(block_hash))
+    }
+
+    /// Get the headers of several blocks in the archive, in the same order as the hashes.
+    pub fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        self.runtime.block_on(self.archive.block_headers(block_hashes))
+    }
+
+    /// Get an iterator over the hashes of all blocks in the archive.
+    pub fn block_list(&mut self) -> Result<BlockHashIter> {
+        let stream = self.runtime.block_on(self.archive.block_list())?;
+        Ok(BlockHashIter { handle: self.runtime.handle().clone(), stream })
+    }
+
+    /// Get an iterator over the hashes of the blocks in the archive, sorted, filtered, or a page
+    /// at a time, see [BlockArchive::block_list_opts].
+    pub fn block_list_opts(&mut self, options: ListOptions) -> Result<BlockHashIter> {
+        let stream = self.runtime.block_on(self.archive.block_list_opts(options))?;
+        Ok(BlockHashIter { handle: self.runtime.handle().clone(), stream })
+    }
+
+    /// Set a user-defined attribute on a block, see [BlockArchive::set_block_attr].
+    pub fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.runtime.block_on(self.archive.set_block_attr(block_hash, key, value))
+    }
+
+    /// Remove a user-defined attribute from a block.
+    pub fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.runtime.block_on(self.archive.remove_block_attr(block_hash, key))
+    }
+
+    /// Get all the user-defined attributes of a block.
+    pub fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.runtime.block_on(self.archive.get_block_attrs(block_hash))
+    }
+
+    /// Check a block against the checksum recorded when it was stored, see
+    /// [BlockArchive::verify_checksum].
+    pub fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.runtime.block_on(self.archive.verify_checksum(block_hash))
+    }
+}
+
+// Adapts a blocking reader to the async reader that store_block() expects. The reads block the
+// thread, which is the thread that is waiting for store_block() anyway.
+struct BlockingReaderAdapter<'a> {
+    reader: &'a mut (dyn Read + Send),
+}
+
+impl AsyncRead for BlockingReaderAdapter<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let n = self.reader.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A blocking reader for an encoded block, returned by [BlockingBlockArchive::get_block].
+pub struct BlockingBlockReader {
+    handle: Handle,
+    reader: Box<dyn AsyncRead + Unpin + Send>,
+}
+
+impl Read for BlockingBlockReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.handle.block_on(self.reader.read(buf))
+    }
+}
+
+/// An iterator over block hashes, returned by [BlockingBlockArchive::block_list].
+///
+/// A listing that fails part way through ends the iterator early, check
+/// [BlockHashIter::take_error] when it has ended.
+pub struct BlockHashIter {
+    handle: Handle,
+    stream: Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>,
+}
+
+impl BlockHashIter {
+    /// Take the error that ended the listing, if there was one, see
+    /// [BlockHashListStream::take_error]. Only meaningful once the iterator has ended.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.stream.as_mut().take_error()
+    }
+}
+
+impl Iterator for BlockHashIter {
+    type Item = BlockHash;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.handle.block_on(self.stream.next())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hex::FromHex;
+    use super::*;
+
+    // Read a block and its header without an async runtime.
+    #[test]
+    fn test_blocking_get_block() {
+        let archive = BlockingBlockArchive::open(PathBuf::from("../testdata/blockarchive")).unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut buf = Vec::new();
+        archive.get_block(&h).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf.len(), 227);
+        assert_eq!(archive.block_header(&h).unwrap().version, 2);
+        assert!(archive.block_exists(&h).unwrap());
+    }
+
+    // Store a block, read it back, and remove it without an async runtime.
+    #[test]
+    fn test_blocking_store_block() {
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let block = std::fs::read("../testdata/blockarchive/6f/e2/00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f.bin").unwrap();
+        let root = mktemp::Temp::new_dir().unwrap();
+        let archive = BlockingBlockArchive::open(root.to_path_buf()).unwrap();
+        archive.store_block(&h, &mut &block[..]).unwrap();
+        let mut buf = Vec::new();
+        archive.get_block(&h).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, block);
+        let mut buf = Vec::new();
+        archive.get_block_range(&h, 4, 32).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, block[4..36]);
+        assert!(archive.verify_checksum(&h).unwrap());
+        archive.set_block_attr(&h, "source", "test").unwrap();
+        assert_eq!(archive.get_block_attrs(&h).unwrap().get("source").unwrap(), "test");
+        archive.remove_block(&h).unwrap();
+        assert!(!archive.block_exists(&h).unwrap());
+    }
+
+    // List the blocks without an async runtime.
+    #[test]
+    fn test_blocking_block_list() {
+        let mut archive = BlockingBlockArchive::open(PathBuf::from("../testdata/blockarchive")).unwrap();
+        assert_eq!(archive.block_list().unwrap().count(), 3);
+    }
+}
@@ -0,0 +1,307 @@
+//! C-compatible API for reading a [SimpleFileBasedBlockArchive].
+//!
+//! This module is only compiled with the `cabi` feature. The C/C++ shared library is built with:
+//!
+//! ```text
+//! cargo build -p bsv-blockarchive --features cabi --release
+//! ```
+//!
+//! The matching declarations are in `include/bsv_blockarchive.h`, which is generated from this
+//! module by cbindgen when the crate is built with the `cabi` feature, see `cbindgen.toml`.
+//!
+//! Block hashes are passed as NUL terminated hex strings in the usual display order, which is
+//! the same as the file names in the archive. All functions return one of the `BSVBA_*` status
+//! codes.
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::path::PathBuf;
+use bitcoinsv::bitcoin::BlockHash;
+use hex::FromHex;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+use crate::headers::encode_header;
+use crate::{BlockArchive, Error, SimpleFileBasedBlockArchive};
+
+/// The call succeeded.
+pub const BSVBA_OK: c_int = 0;
+/// The block was not found in the archive.
+pub const BSVBA_NOT_FOUND: c_int = 1;
+/// An argument was null or could not be parsed.
+pub const BSVBA_INVALID_ARGUMENT: c_int = 2;
+/// An IO error occurred.
+pub const BSVBA_IO_ERROR: c_int = 3;
+/// The callback asked for the operation to be aborted.
+pub const BSVBA_ABORTED: c_int = 4;
+/// Any other error.
+pub const BSVBA_ERROR: c_int = 5;
+
+// the size of the buffer used when streaming a block to the callback
+const STREAM_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Callback used by [bsvba_get_block] to deliver block bytes. A non-zero return value aborts
+/// the transfer. A null callback is refused with [BSVBA_INVALID_ARGUMENT].
+pub type BsvbaWriteCallback = Option<extern "C" fn(data: *const u8, len: usize, user_data: *mut c_void) -> c_int>;
+
+/// An open archive, opaque to C callers.
+///
+/// Each handle owns its own runtime, so callers do not need to know anything about tokio.
+pub struct BsvBlockArchive {
+    runtime: Runtime,
+    archive: SimpleFileBasedBlockArchive,
+}
+
+// map a library error to a status code
+fn status_from_error(err: &Error) -> c_int {
+    match err {
+        Error::BlockNotFound => BSVBA_NOT_FOUND,
+        Error::IoError(_) => BSVBA_IO_ERROR,
+        _ => BSVBA_ERROR,
+    }
+}
+
+// parse a hex encoded block hash from a C string
+unsafe fn hash_from_c_str(hash_hex: *const c_char) -> Option<BlockHash> {
+    if hash_hex.is_null() {
+        return None;
+    }
+    let s = CStr::from_ptr(hash_hex).to_str().ok()?;
+    BlockHash::from_hex(s).ok()
+}
+
+/// Open the archive at `root_path`.
+///
+/// Returns null if the path is invalid or the archive could not be opened. The returned handle
+/// must be released with [bsvba_close].
+///
+/// # Safety
+///
+/// `root_path` must be null or point to a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bsvba_open(root_path: *const c_char) -> *mut BsvBlockArchive {
+    if root_path.is_null() {
+        return std::ptr::null_mut();
+    }
+    let root_path = match CStr::from_ptr(root_path).to_str() {
+        Ok(p) => PathBuf::from(p),
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let runtime = match Runtime::new() {
+        Ok(r) => r,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    match runtime.block_on(SimpleFileBasedBlockArchive::new(root_path)) {
+        Ok(archive) => Box::into_raw(Box::new(BsvBlockArchive { runtime, archive })),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Close an archive opened with [bsvba_open].
+///
+/// # Safety
+///
+/// `archive` must be null or a handle returned by [bsvba_open] that has not already been closed.
+#[no_mangle]
+pub unsafe extern "C" fn bsvba_close(archive: *mut BsvBlockArchive) {
+    if !archive.is_null() {
+        drop(Box::from_raw(archive));
+    }
+}
+
+/// Check whether a block exists in the archive, storing the result in `exists`.
+///
+/// # Safety
+///
+/// `archive` must be a valid handle, `hash_hex` a valid NUL terminated string, and `exists` a
+/// valid pointer to a bool.
+#[no_mangle]
+pub unsafe extern "C" fn bsvba_block_exists(archive: *const BsvBlockArchive, hash_hex: *const c_char, exists: *mut bool) -> c_int {
+    if archive.is_null() || exists.is_null() {
+        return BSVBA_INVALID_ARGUMENT;
+    }
+    let handle = &*archive;
+    let hash = match hash_from_c_str(hash_hex) {
+        Some(h) => h,
+        None => return BSVBA_INVALID_ARGUMENT,
+    };
+    match handle.runtime.block_on(handle.archive.block_exists(&hash)) {
+        Ok(r) => {
+            *exists = r;
+            BSVBA_OK
+        }
+        Err(e) => status_from_error(&e),
+    }
+}
+
+/// Get the size of a block in bytes, storing the result in `size`.
+///
+/// # Safety
+///
+/// `archive` must be a valid handle, `hash_hex` a valid NUL terminated string, and `size` a
+/// valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bsvba_block_size(archive: *const BsvBlockArchive, hash_hex: *const c_char, size: *mut u64) -> c_int {
+    if archive.is_null() || size.is_null() {
+        return BSVBA_INVALID_ARGUMENT;
+    }
+    let handle = &*archive;
+    let hash = match hash_from_c_str(hash_hex) {
+        Some(h) => h,
+        None => return BSVBA_INVALID_ARGUMENT,
+    };
+    match handle.runtime.block_on(handle.archive.block_size(&hash)) {
+        Ok(s) => {
+            *size = s as u64;
+            BSVBA_OK
+        }
+        Err(e) => status_from_error(&e),
+    }
+}
+
+/// Copy the 80 byte encoded header of a block into `header_out`.
+///
+/// The header is read with [BlockArchive::block_header], so an archive with a headers file does
+/// not open the block.
+///
+/// # Safety
+///
+/// `archive` must be a valid handle, `hash_hex` a valid NUL terminated string, and `header_out`
+/// must point to at least 80 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bsvba_block_header(archive: *const BsvBlockArchive, hash_hex: *const c_char, header_out: *mut u8) -> c_int {
+    if archive.is_null() || header_out.is_null() {
+        return BSVBA_INVALID_ARGUMENT;
+    }
+    let handle = &*archive;
+    let hash = match hash_from_c_str(hash_hex) {
+        Some(h) => h,
+        None => return BSVBA_INVALID_ARGUMENT,
+    };
+    match handle.runtime.block_on(handle.archive.block_header(&hash)) {
+        Ok(header) => {
+            let encoded = encode_header(&header);
+            std::ptr::copy_nonoverlapping(encoded.as_ptr(), header_out, encoded.len());
+            BSVBA_OK
+        }
+        Err(e) => status_from_error(&e),
+    }
+}
+
+/// Stream the bytes of a block to `callback`, in chunks of at most 1MB.
+///
+/// The callback is called on the calling thread. If it returns a non-zero value then the
+/// transfer is stopped and [BSVBA_ABORTED] is returned.
+///
+/// # Safety
+///
+/// `archive` must be a valid handle, `hash_hex` a valid NUL terminated string, and `callback`
+/// null or a valid function. `user_data` is passed through to the callback untouched.
+#[no_mangle]
+pub unsafe extern "C" fn bsvba_get_block(archive: *const BsvBlockArchive, hash_hex: *const c_char,
+                                         callback: BsvbaWriteCallback, user_data: *mut c_void) -> c_int {
+    if archive.is_null() {
+        return BSVBA_INVALID_ARGUMENT;
+    }
+    let callback = match callback {
+        Some(c) => c,
+        None => return BSVBA_INVALID_ARGUMENT,
+    };
+    let handle = &*archive;
+    let hash = match hash_from_c_str(hash_hex) {
+        Some(h) => h,
+        None => return BSVBA_INVALID_ARGUMENT,
+    };
+    let r = handle.runtime.block_on(async {
+        let mut reader = handle.archive.get_block(&hash).await?;
+        let mut buf = vec![0u8; STREAM_BUFFER_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok::<c_int, Error>(BSVBA_OK);
+            }
+            if callback(buf.as_ptr(), n, user_data) != 0 {
+                return Ok::<c_int, Error>(BSVBA_ABORTED);
+            }
+        }
+    });
+    match r {
+        Ok(code) => code,
+        Err(e) => status_from_error(&e),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use super::*;
+
+    const BLOCK: &str = "00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f";
+
+    // Append the bytes of a block to the Vec passed as the user data.
+    extern "C" fn collect(data: *const u8, len: usize, user_data: *mut c_void) -> c_int {
+        let out = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        out.extend_from_slice(unsafe { std::slice::from_raw_parts(data, len) });
+        0
+    }
+
+    // Abort the transfer at the first chunk.
+    extern "C" fn abort(_data: *const u8, _len: usize, _user_data: *mut c_void) -> c_int {
+        1
+    }
+
+    // Open the test archive.
+    fn open() -> *mut BsvBlockArchive {
+        let root = CString::new("../testdata/blockarchive").unwrap();
+        unsafe { bsvba_open(root.as_ptr()) }
+    }
+
+    // A block, its size, and its header are read from the test archive.
+    #[test]
+    fn test_read_block() {
+        let archive = open();
+        assert!(!archive.is_null());
+        let hash = CString::new(BLOCK).unwrap();
+        unsafe {
+            let mut exists = false;
+            assert_eq!(bsvba_block_exists(archive, hash.as_ptr(), &mut exists), BSVBA_OK);
+            assert!(exists);
+            let mut size = 0u64;
+            assert_eq!(bsvba_block_size(archive, hash.as_ptr(), &mut size), BSVBA_OK);
+            assert_eq!(size, 227);
+            let mut block: Vec<u8> = Vec::new();
+            let user_data = &mut block as *mut Vec<u8> as *mut c_void;
+            assert_eq!(bsvba_get_block(archive, hash.as_ptr(), Some(collect), user_data), BSVBA_OK);
+            assert_eq!(block.len() as u64, size);
+            let mut header = [0u8; 80];
+            assert_eq!(bsvba_block_header(archive, hash.as_ptr(), header.as_mut_ptr()), BSVBA_OK);
+            assert_eq!(header[..], block[..80]);
+            bsvba_close(archive);
+        }
+    }
+
+    // Missing blocks, bad arguments, a null callback, and an aborted transfer each give their
+    // status code.
+    #[test]
+    fn test_status_codes() {
+        assert!(unsafe { bsvba_open(std::ptr::null()) }.is_null());
+        let archive = open();
+        let hash = CString::new(BLOCK).unwrap();
+        let missing = CString::new(format!("{:064x}", 1)).unwrap();
+        let bad = CString::new("not a hash").unwrap();
+        unsafe {
+            let mut exists = true;
+            assert_eq!(bsvba_block_exists(archive, missing.as_ptr(), &mut exists), BSVBA_OK);
+            assert!(!exists);
+            let mut size = 0u64;
+            assert_eq!(bsvba_block_size(archive, missing.as_ptr(), &mut size), BSVBA_NOT_FOUND);
+            assert_eq!(bsvba_block_size(archive, bad.as_ptr(), &mut size), BSVBA_INVALID_ARGUMENT);
+            assert_eq!(bsvba_block_size(std::ptr::null(), hash.as_ptr(), &mut size), BSVBA_INVALID_ARGUMENT);
+            let mut header = [0u8; 80];
+            assert_eq!(bsvba_block_header(archive, missing.as_ptr(), header.as_mut_ptr()), BSVBA_NOT_FOUND);
+            assert_eq!(bsvba_get_block(archive, hash.as_ptr(), None, std::ptr::null_mut()), BSVBA_INVALID_ARGUMENT);
+            assert_eq!(bsvba_get_block(archive, hash.as_ptr(), Some(abort), std::ptr::null_mut()), BSVBA_ABORTED);
+            assert_eq!(bsvba_get_block(archive, missing.as_ptr(), Some(abort), std::ptr::null_mut()), BSVBA_NOT_FOUND);
+            bsvba_close(archive);
+            bsvba_close(std::ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::block_archive::{BlockHashListStream, BlockSizeStream};
+use crate::{BlockArchive, BlockAttrs, Error, Result};
+
+/// A block archive that keeps recently used headers, block sizes, and optionally small blocks in
+/// memory, so that repeated lookups do not go to the wrapped archive.
+///
+/// Each cache holds up to its capacity of entries and evicts the least recently used entry when
+/// it is full. Blocks are only cached if [CachedBlockArchive::with_block_cache] is used.
+///
+/// The cache assumes that it is the only writer to the wrapped archive, blocks removed from the
+/// wrapped archive directly may still be returned from the cache.
+///
+/// Example code:
+///     let mut archive = CachedBlockArchive::new(archive, 100_000);
+///     let chain = ChainIndex::build(&mut archive).await?;
+pub struct CachedBlockArchive<A: BlockArchive> {
+    archive: A,
+    headers: Mutex<LruCache<BlockHeader>>,
+    sizes: Mutex<LruCache<usize>>,
+    blocks: Mutex<LruCache<Vec<u8>>>,
+    // the largest block that will be cached
+    max_block_size: usize,
+}
+
+impl<A: BlockArchive> CachedBlockArchive<A> {
+    /// Wrap an archive, caching up to capacity headers and capacity block sizes.
+    pub fn new(archive: A, capacity: usize) -> CachedBlockArchive<A> {
+        CachedBlockArchive {
+            archive,
+            headers: Mutex::new(LruCache::new(capacity)),
+            sizes: Mutex::new(LruCache::new(capacity)),
+            blocks: Mutex::new(LruCache::new(0)),
+            max_block_size: 0,
+        }
+    }
+
+    /// Also cache up to capacity whole blocks, of at most max_block_size bytes each.
+    pub fn with_block_cache(mut self, capacity: usize, max_block_size: usize) -> CachedBlockArchive<A> {
+        self.blocks = Mutex::new(LruCache::new(capacity));
+        self.max_block_size = max_block_size;
+        self
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+
+    /// Remove everything from the caches.
+    pub fn clear(&self) {
+        self.headers.lock().unwrap().clear();
+        self.sizes.lock().unwrap().clear();
+        self.blocks.lock().unwrap().clear();
+    }
+
+    // Remove a block from the caches.
+    fn invalidate(&self, block_hash: &BlockHash) {
+        self.headers.lock().unwrap().remove(block_hash);
+        self.sizes.lock().unwrap().remove(block_hash);
+        self.blocks.lock().unwrap().remove(block_hash);
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for CachedBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        if let Some(block) = self.blocks.lock().unwrap().get(block_hash) {
+            return Ok(Box::new(Cursor::new(block)));
+        }
+        if self.max_block_size == 0 || self.block_size(block_hash).await? > self.max_block_size {
+            return self.archive.get_block(block_hash).await;
+        }
+        let mut block = Vec::new();
+        self.archive.get_block(block_hash).await?.read_to_end(&mut block).await?;
+        self.blocks.lock().unwrap().insert(*block_hash, block.clone());
+        Ok(Box::new(Cursor::new(block)))
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        if let Some(block) = self.blocks.lock().unwrap().get(block_hash) {
+            let start = offset.min(block.len() as u64) as usize;
+            let end = offset.saturating_add(length).min(block.len() as u64) as usize;
+            return Ok(Box::new(Cursor::new(block[start..end].to_vec())));
+        }
+        self.archive.get_block_range(block_hash, offset, length).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        if self.sizes.lock().unwrap().contains(block_hash) || self.headers.lock().unwrap().contains(block_hash) {
+            return Ok(true);
+        }
+        self.archive.block_exists(block_hash).await
+    }
+
+    /// Blocks that are not in the cache are checked in the wrapped archive in one batch.
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        let missing: Vec<BlockHash> = {
+            let sizes = self.sizes.lock().unwrap();
+            let headers = self.headers.lock().unwrap();
+            block_hashes.iter().filter(|h| !sizes.contains(h) && !headers.contains(h)).copied().collect()
+        };
+        let mut checked = self.archive.blocks_exist(&missing).await?.into_iter().peekable();
+        let mut result = Vec::with_capacity(block_hashes.len());
+        for block_hash in block_hashes {
+            match checked.peek() {
+                Some((h, exists)) if h == block_hash => {
+                    result.push((*block_hash, *exists));
+                    checked.next();
+                }
+                _ => result.push((*block_hash, true)),
+            }
+        }
+        Ok(result)
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        // the archive may replace a block that is cached
+        self.invalidate(block_hash);
+        self.archive.store_block(block_hash, block).await
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.invalidate(block_hash);
+        self.archive.remove_block(block_hash).await
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        if let Some(size) = self.sizes.lock().unwrap().get(block_hash) {
+            return Ok(size);
+        }
+        let size = self.archive.block_size(block_hash).await?;
+        self.sizes.lock().unwrap().insert(*block_hash, size);
+        Ok(size)
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        if let Some(header) = self.headers.lock().unwrap().get(block_hash) {
+            return Ok(header);
+        }
+        let header = self.archive.block_header(block_hash).await?;
+        self.headers.lock().unwrap().insert(*block_hash, header.clone());
+        Ok(header)
+    }
+
+    /// Headers that are not in the cache are read from the wrapped archive in one batch.
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        let mut headers = Vec::with_capacity(block_hashes.len());
+        let mut missing = Vec::new();
+        {
+            let mut cache = self.headers.lock().unwrap();
+            for block_hash in block_hashes {
+                let header = cache.get(block_hash);
+                if header.is_none() {
+                    missing.push(*block_hash);
+                }
+                headers.push((*block_hash, header));
+            }
+        }
+        let mut fetched = self.archive.block_headers(&missing).await?.into_iter();
+        let mut cache = self.headers.lock().unwrap();
+        let mut result = Vec::with_capacity(headers.len());
+        for (block_hash, header) in headers {
+            let header = match header {
+                Some(h) => h,
+                None => {
+                    let (_, h) = fetched.next().ok_or(Error::BlockNotFound)?;
+                    cache.insert(block_hash, h.clone());
+                    h
+                }
+            };
+            result.push((block_hash, header));
+        }
+        Ok(result)
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.archive.block_list().await
+    }
+
+    async fn block_sizes<'a>(&'a mut self) -> Result<BlockSizeStream<'a>> {
+        self.archive.block_sizes().await
+    }
+
+    async fn total_size(&mut self) -> Result<u64> {
+        self.archive.total_size().await
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.archive.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.archive.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.archive.get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.verify_checksum(block_hash).await
+    }
+}
+
+// A least recently used cache keyed by block hash.
+struct LruCache<V> {
+    capacity: usize,
+    // the value of each entry and when it was last used
+    entries: BTreeMap<BlockHash, (V, u64)>,
+    // the entries in the order they were last used
+    order: BTreeMap<u64, BlockHash>,
+    tick: u64,
+}
+
+impl<V: Clone> LruCache<V> {
+    fn new(capacity: usize) -> LruCache<V> {
+        LruCache { capacity, entries: BTreeMap::new(), order: BTreeMap::new(), tick: 0 }
+    }
+
+    fn get(&mut self, key: &BlockHash) -> Option<V> {
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.tick += 1;
+        *last_used = self.tick;
+        self.order.insert(self.tick, *key);
+        Some(value.clone())
+    }
+
+    fn contains(&self, key: &BlockHash) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn insert(&mut self, key: BlockHash, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        self.tick += 1;
+        self.entries.insert(key, (value, self.tick));
+        self.order.insert(self.tick, key);
+        while self.entries.len() > self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &BlockHash) {
+        if let Some((_, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    fn hash(n: u8) -> BlockHash {
+        BlockHash::from_hex(format!("{:064x}", n)).unwrap()
+    }
+
+    #[test]
+    fn test_lru_cache() {
+        let mut cache = LruCache::new(2);
+        cache.insert(hash(1), 1);
+        cache.insert(hash(2), 2);
+        // using 1 makes 2 the least recently used
+        assert_eq!(cache.get(&hash(1)), Some(1));
+        cache.insert(hash(3), 3);
+        assert_eq!(cache.get(&hash(2)), None);
+        assert_eq!(cache.get(&hash(1)), Some(1));
+        assert_eq!(cache.get(&hash(3)), Some(3));
+        cache.remove(&hash(1));
+        assert!(!cache.contains(&hash(1)));
+        let mut cache = LruCache::new(0);
+        cache.insert(hash(1), 1);
+        assert_eq!(cache.get(&hash(1)), None);
+    }
+
+    // Cached results match the wrapped archive, and removed blocks are no longer cached.
+    #[tokio::test]
+    async fn test_cached_archive() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let inner = MemoryBlockArchive::new();
+        inner.store_block(&h, &mut &block[..]).await.unwrap();
+        let archive = CachedBlockArchive::new(inner, 10).with_block_cache(10, block.len());
+        for _ in 0..2 {
+            assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+            assert_eq!(archive.block_size(&h).await.unwrap(), block.len());
+            let mut buf = Vec::new();
+            archive.get_block(&h).await.unwrap().read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, block);
+        }
+        assert!(archive.blocks.lock().unwrap().contains(&h));
+        archive.clear();
+        let headers = archive.block_headers(&[h, h]).await.unwrap();
+        assert_eq!(headers.len(), 2);
+        assert!(headers.iter().all(|(hash, header)| *hash == h && header.hash() == h));
+        assert!(archive.headers.lock().unwrap().contains(&h));
+        archive.remove_block(&h).await.unwrap();
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert!(archive.get_block(&h).await.is_err());
+        assert!(archive.block_header(&h).await.is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use std::ffi::OsStr;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use bitcoinsv::bitcoin::BlockHash;
+use hex::{FromHex, ToHex};
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use crate::runtime::{Runtime, TokioRuntime};
+use crate::{BlockArchive, Error, Result};
+
+/// Details of a block in the [CandidateStore].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CandidateInfo {
+    /// The hash of the block.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))]
+    pub block_hash: BlockHash,
+    /// The size of the encoded block.
+    pub size: u64,
+    /// When the block was stored.
+    pub stored: SystemTime,
+}
+
+/// A storage area for blocks that are not (yet) part of the chain, such as mining candidates or
+/// provisional blocks near the tip.
+///
+/// Candidate blocks are kept separate from the archive. A candidate can be promoted into an
+/// archive once it has been accepted, or expired after it has been superseded.
+///
+/// Blocks are stored flat in a single directory as "<hash>.bin", there should never be many of
+/// them. The files are accessed with the [Runtime] R, tokio by default.
+///
+/// Example code:
+///     let candidates = archive.candidates().await?;
+///     candidates.store(&hash, &mut reader).await?;
+///     candidates.promote(&hash, &archive).await?;
+#[derive(Debug)]
+pub struct CandidateStore<R: Runtime = TokioRuntime> {
+    /// The directory in which the candidates are stored.
+    pub path: PathBuf,
+    runtime: PhantomData<R>,
+}
+
+impl CandidateStore {
+    /// Create a candidate store in the given directory, creating the directory if necessary.
+    pub async fn new(path: PathBuf) -> Result<CandidateStore> {
+        CandidateStore::with_runtime(path).await
+    }
+}
+
+impl<R: Runtime> CandidateStore<R> {
+    /// Create a candidate store in the given directory that accesses its files with the runtime
+    /// R, creating the directory if necessary.
+    pub async fn with_runtime(path: PathBuf) -> Result<CandidateStore<R>> {
+        R::create_dir_all(&path).await?;
+        Ok(CandidateStore { path, runtime: PhantomData })
+    }
+
+    // Get the path for a candidate.
+    fn get_path_from_hash(&self, hash: &BlockHash) -> PathBuf {
+        let s: String = hash.encode_hex();
+        self.path.join(s).with_extension("bin")
+    }
+
+    /// Store a candidate block.
+    pub async fn store(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let path = self.get_path_from_hash(block_hash);
+        if R::try_exists(&path).await? {
+            return Err(Error::BlockExists);
+        }
+        let mut file = R::create(&path).await?;
+        tokio::io::copy(block, &mut file).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Get a candidate block, returns a reader for the encoded block.
+    pub async fn get(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        match R::open(&self.get_path_from_hash(block_hash)).await {
+            Ok(f) => Ok(f),
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
+                _ => Err(e.into())
+            }
+        }
+    }
+
+    /// Check if a candidate block exists.
+    pub async fn exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        Ok(R::try_exists(&self.get_path_from_hash(block_hash)).await?)
+    }
+
+    /// Remove a candidate block.
+    pub async fn remove(&self, block_hash: &BlockHash) -> Result<()> {
+        match R::remove_file(&self.get_path_from_hash(block_hash)).await {
+            Ok(_) => Ok(()),
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
+                _ => Err(e.into())
+            }
+        }
+    }
+
+    /// List all candidate blocks.
+    pub async fn list(&self) -> Result<Vec<CandidateInfo>> {
+        let mut result = Vec::new();
+        for path in R::read_dir(&self.path).await? {
+            // ignore anything that is not a .bin file named after a block hash
+            if path.extension() != Some(OsStr::new("bin")) {
+                continue;
+            }
+            let block_hash = match path.file_stem().and_then(|s| s.to_str()).map(BlockHash::from_hex) {
+                Some(Ok(h)) => h,
+                _ => continue,
+            };
+            let metadata = R::metadata(&path).await?;
+            result.push(CandidateInfo {
+                block_hash,
+                size: metadata.len(),
+                stored: metadata.modified()?,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Move a candidate block into the archive.
+    ///
+    /// The candidate is removed once it has been stored in the archive.
+    pub async fn promote<A: BlockArchive>(&self, block_hash: &BlockHash, archive: &A) -> Result<()> {
+        let mut reader = self.get(block_hash).await?;
+        archive.store_block(block_hash, &mut reader).await?;
+        self.remove(block_hash).await
+    }
+
+    /// Remove all candidates that were stored more than max_age ago.
+    ///
+    /// Returns the hashes of the blocks that were removed.
+    pub async fn expire(&self, max_age: Duration) -> Result<Vec<BlockHash>> {
+        let now = SystemTime::now();
+        let mut removed = Vec::new();
+        for c in self.list().await? {
+            // a timestamp in the future counts as zero age
+            let age = now.duration_since(c.stored).unwrap_or(Duration::ZERO);
+            if age >= max_age {
+                self.remove(&c.block_hash).await?;
+                removed.push(c.block_hash);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    fn test_hash() -> BlockHash {
+        BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap()
+    }
+
+    // Store and list a candidate, it should not appear in the archive.
+    #[tokio::test]
+    async fn test_store_candidate() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let candidates = archive.candidates().await.unwrap();
+        let mut block: Box<dyn AsyncRead + Unpin + Send> = Box::new(Cursor::new(b"candidate".to_vec()));
+        candidates.store(&test_hash(), &mut block).await.unwrap();
+        let list = candidates.list().await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].block_hash, test_hash());
+        assert_eq!(list[0].size, 9);
+        assert!(!archive.block_exists(&test_hash()).await.unwrap());
+        let mut results = archive.block_list().await.unwrap();
+        assert!(tokio_stream::StreamExt::next(&mut results).await.is_none());
+    }
+
+    // Promoting a candidate moves it into the archive.
+    #[tokio::test]
+    async fn test_promote_candidate() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let candidates = archive.candidates().await.unwrap();
+        let mut block: Box<dyn AsyncRead + Unpin + Send> = Box::new(Cursor::new(b"candidate".to_vec()));
+        candidates.store(&test_hash(), &mut block).await.unwrap();
+        candidates.promote(&test_hash(), &archive).await.unwrap();
+        assert!(!candidates.exists(&test_hash()).await.unwrap());
+        let mut buf = Vec::new();
+        archive.get_block(&test_hash()).await.unwrap().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"candidate");
+    }
+
+    // Expiring with a zero age removes everything.
+    #[tokio::test]
+    async fn test_expire_candidates() {
+        let root = Temp::new_dir().unwrap();
+        let candidates = CandidateStore::new(root.to_path_buf()).await.unwrap();
+        let mut block: Box<dyn AsyncRead + Unpin + Send> = Box::new(Cursor::new(b"candidate".to_vec()));
+        candidates.store(&test_hash(), &mut block).await.unwrap();
+        assert!(candidates.expire(Duration::from_secs(3600)).await.unwrap().is_empty());
+        assert_eq!(candidates.expire(Duration::ZERO).await.unwrap(), vec![test_hash()]);
+        assert!(candidates.list().await.unwrap().is_empty());
+    }
+
+    // A candidate store can run on the smol executor.
+    #[cfg(feature = "smol")]
+    #[test]
+    fn test_smol_candidates() {
+        smol::block_on(async {
+            let root = Temp::new_dir().unwrap();
+            let candidates = CandidateStore::<crate::runtime::SmolRuntime>::with_runtime(root.to_path_buf()).await.unwrap();
+            let mut block: Box<dyn AsyncRead + Unpin + Send> = Box::new(Cursor::new(b"candidate".to_vec()));
+            candidates.store(&test_hash(), &mut block).await.unwrap();
+            assert_eq!(candidates.list().await.unwrap()[0].size, 9);
+            let mut buf = Vec::new();
+            candidates.get(&test_hash()).await.unwrap().read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"candidate");
+            assert_eq!(candidates.expire(Duration::ZERO).await.unwrap(), vec![test_hash()]);
+        });
+    }
+}
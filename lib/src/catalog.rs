@@ -0,0 +1,536 @@
+//! A SQLite catalog of the blocks in an archive.
+//!
+//! The catalog records the hash, size, height, header timestamp, checksum, and storage location
+//! of every block in a single file, [CATALOG_FILE] at the root of the archive. Wrap an archive in
+//! a [CatalogedBlockArchive] to keep the catalog in sync as blocks are stored and removed, and to
+//! answer listing and size queries from the catalog rather than the storage, which is orders of
+//! magnitude faster for a large archive. A catalog that has fallen behind, or that is missing,
+//! is rebuilt from the archive with [Catalog::rebuild].
+//!
+//! This module is only available with the "catalog" feature.
+//!
+//! Example code:
+//!     let catalog = Catalog::open(&root.join(CATALOG_FILE))?;
+//!     catalog.rebuild(&mut archive, false).await?;
+//!     let mut archive = CatalogedBlockArchive::new(archive, catalog);
+//!     let sizes = archive.block_sizes().await?;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Mutex;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use hex::FromHex;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+use crate::block_archive::{BlockHashListStream, BlockHashListStreamFromVec, BlockSizeStream, ChecksumReader};
+use crate::manifest::sha256_reader;
+use crate::stats::{ArchiveStats, StatsCollector};
+use crate::{BlockArchive, BlockAttrs, ChainIndex, Error, ListOptions, ListOrder, Result};
+
+/// The name of the catalog file at the root of an archive.
+pub const CATALOG_FILE: &str = "catalog.sqlite";
+
+// the number of headers requested at a time when the catalog is rebuilt
+const HEADER_BATCH_SIZE: usize = 500;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS blocks (
+        hash TEXT PRIMARY KEY NOT NULL,
+        prev_hash TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        height INTEGER,
+        timestamp INTEGER NOT NULL,
+        checksum TEXT,
+        location TEXT
+    );
+    CREATE INDEX IF NOT EXISTS blocks_height ON blocks (height, hash);
+    CREATE INDEX IF NOT EXISTS blocks_prev_hash ON blocks (prev_hash);
+";
+
+const ENTRY_COLUMNS: &str = "hash, prev_hash, size, height, timestamp, checksum, location";
+
+/// The details of a block recorded in a [Catalog].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogEntry {
+    pub block_hash: BlockHash,
+    /// The hash of the parent block, from the header.
+    pub prev_hash: BlockHash,
+    /// The size of the block in bytes.
+    pub size: u64,
+    /// The height of the block, None if it is not linked to a genesis block in the catalog.
+    pub height: Option<u64>,
+    /// The timestamp of the header, in seconds since the epoch.
+    pub timestamp: u64,
+    /// The hex encoded SHA-256 checksum of the block, None if it was not recorded.
+    pub checksum: Option<String>,
+    /// Where the block is stored, such as the path of the block file relative to the root of the
+    /// archive, None if it was not recorded.
+    pub location: Option<String>,
+}
+
+/// Gets the storage location of a block, see [Catalog::with_locator].
+pub type Locator = Box<dyn Fn(&BlockHash) -> String + Send + Sync>;
+
+/// A SQLite catalog of the blocks in an archive, see the [module](self) documentation.
+pub struct Catalog {
+    conn: Mutex<Connection>,
+    locator: Option<Locator>,
+}
+
+impl Catalog {
+    /// Open the catalog in the given file, creating it if necessary.
+    pub fn open(path: &Path) -> Result<Catalog> {
+        let conn = Connection::open(path).map_err(catalog_error)?;
+        conn.execute_batch(SCHEMA).map_err(catalog_error)?;
+        Ok(Catalog { conn: Mutex::new(conn), locator: None })
+    }
+
+    /// Record the storage location of each block given by the locator.
+    pub fn with_locator<F>(mut self, locator: F) -> Catalog
+        where F: Fn(&BlockHash) -> String + Send + Sync + 'static
+    {
+        self.locator = Some(Box::new(locator));
+        self
+    }
+
+    /// Add a block to the catalog, replacing any entry it already has.
+    ///
+    /// If the height is not given it is found from the parent, and the heights of the blocks in
+    /// the catalog that descend from the block are filled in.
+    pub fn insert(&self, entry: &CatalogEntry) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let height = match entry.height {
+            Some(height) => Some(height),
+            None if entry.prev_hash == (BlockHash { hash: [0u8; 32] }) => Some(0),
+            None => conn.query_row("SELECT height FROM blocks WHERE hash = ?1", params![entry.prev_hash.to_string()], |row| row.get::<_, Option<i64>>(0))
+                .optional().map_err(catalog_error)?
+                .flatten()
+                .map(|h| h as u64 + 1),
+        };
+        conn.execute(&format!("INSERT OR REPLACE INTO blocks ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)", ENTRY_COLUMNS), params![
+            entry.block_hash.to_string(),
+            entry.prev_hash.to_string(),
+            entry.size as i64,
+            height.map(|h| h as i64),
+            entry.timestamp as i64,
+            entry.checksum,
+            entry.location,
+        ]).map_err(catalog_error)?;
+        if let Some(height) = height {
+            set_descendant_heights(&conn, &entry.block_hash, Some(height))?;
+        }
+        Ok(())
+    }
+
+    /// Remove a block from the catalog, returning false if it was not in the catalog.
+    ///
+    /// The blocks in the catalog that descend from the block are no longer linked to a genesis
+    /// block, so their heights are cleared.
+    pub fn remove(&self, block_hash: &BlockHash) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM blocks WHERE hash = ?1", params![block_hash.to_string()]).map_err(catalog_error)?;
+        if removed > 0 {
+            set_descendant_heights(&conn, block_hash, None)?;
+        }
+        Ok(removed > 0)
+    }
+
+    /// Get the entry of a block, None if it is not in the catalog.
+    pub fn get(&self, block_hash: &BlockHash) -> Result<Option<CatalogEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn.query_row(&format!("SELECT {} FROM blocks WHERE hash = ?1", ENTRY_COLUMNS), params![block_hash.to_string()], read_row)
+            .optional().map_err(catalog_error)?;
+        row.map(CatalogRow::into_entry).transpose()
+    }
+
+    /// Get the number of blocks in the catalog.
+    pub fn block_count(&self) -> Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM blocks", [], |row| row.get(0)).map_err(catalog_error)?;
+        Ok(count as u64)
+    }
+
+    /// Get the total size of the blocks in the catalog in bytes.
+    pub fn total_size(&self) -> Result<u64> {
+        let conn = self.conn.lock().unwrap();
+        let total: i64 = conn.query_row("SELECT COALESCE(SUM(size), 0) FROM blocks", [], |row| row.get(0)).map_err(catalog_error)?;
+        Ok(total as u64)
+    }
+
+    /// Get the size of a block, None if it is not in the catalog.
+    pub fn block_size(&self, block_hash: &BlockHash) -> Result<Option<u64>> {
+        let conn = self.conn.lock().unwrap();
+        let size = conn.query_row("SELECT size FROM blocks WHERE hash = ?1", params![block_hash.to_string()], |row| row.get::<_, i64>(0))
+            .optional().map_err(catalog_error)?;
+        Ok(size.map(|s| s as u64))
+    }
+
+    /// Get every block in the catalog with its size, in order of hash.
+    pub fn sizes(&self) -> Result<Vec<(BlockHash, u64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT hash, size FROM blocks ORDER BY hash").map_err(catalog_error)?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))).map_err(catalog_error)?;
+        let mut sizes = Vec::new();
+        for row in rows {
+            let (hash, size) = row.map_err(catalog_error)?;
+            sizes.push((parse_hash(&hash)?, size as u64));
+        }
+        Ok(sizes)
+    }
+
+    /// List the blocks in the catalog in the order, and with the prefix, offset, and limit, of
+    /// the options. The unsorted order is the order of hash.
+    pub fn list(&self, options: &ListOptions) -> Result<Vec<BlockHash>> {
+        let order = match options.order {
+            ListOrder::Height => "height IS NULL, height, hash",
+            ListOrder::Hash | ListOrder::Unsorted => "hash",
+        };
+        let prefix = options.prefix.as_deref().unwrap_or("").to_lowercase();
+        let limit = options.limit.map_or(-1, |l| l as i64);
+        let conn = self.conn.lock().unwrap();
+        let sql = format!("SELECT hash FROM blocks WHERE substr(hash, 1, length(?1)) = ?1 ORDER BY {} LIMIT ?2 OFFSET ?3", order);
+        let mut stmt = conn.prepare(&sql).map_err(catalog_error)?;
+        let rows = stmt.query_map(params![prefix, limit, options.offset as i64], |row| row.get::<_, String>(0)).map_err(catalog_error)?;
+        let mut hashes = Vec::new();
+        for row in rows {
+            hashes.push(parse_hash(&row.map_err(catalog_error)?)?);
+        }
+        Ok(hashes)
+    }
+
+    /// Collect the statistics of [crate::stats::collect_stats] from the catalog, without reading
+    /// the archive.
+    pub fn stats(&self, largest: usize) -> Result<ArchiveStats> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT hash, size, timestamp FROM blocks").map_err(catalog_error)?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)))
+            .map_err(catalog_error)?;
+        let mut collector = StatsCollector::new(largest);
+        for row in rows {
+            let (hash, size, timestamp) = row.map_err(catalog_error)?;
+            collector.add(parse_hash(&hash)?, size as u64, timestamp as u64);
+        }
+        Ok(collector.finish())
+    }
+
+    /// Replace the contents of the catalog with the blocks in an archive, returning the number
+    /// of blocks recorded.
+    ///
+    /// The sizes are read with [BlockArchive::block_sizes] and the headers with
+    /// [BlockArchive::block_headers]. If checksums is true every block is read in full to record
+    /// its checksum. The archive should not be wrapped in a [CatalogedBlockArchive] that uses
+    /// this catalog, or the sizes would be read from the catalog being rebuilt.
+    pub async fn rebuild<A: BlockArchive + ?Sized>(&self, archive: &mut A, checksums: bool) -> Result<usize> {
+        let mut sizes = Vec::new();
+        let mut results = archive.block_sizes().await?;
+        while let Some(r) = results.next().await {
+            sizes.push(r?);
+        }
+        drop(results);
+        let mut headers: Vec<(BlockHash, BlockHeader)> = Vec::with_capacity(sizes.len());
+        for batch in sizes.chunks(HEADER_BATCH_SIZE) {
+            let hashes: Vec<BlockHash> = batch.iter().map(|(h, _)| *h).collect();
+            headers.extend(archive.block_headers(&hashes).await?);
+        }
+        let mut sums = Vec::with_capacity(sizes.len());
+        for (block_hash, _) in sizes.iter() {
+            sums.push(match checksums {
+                true => Some(hex::encode(sha256_reader(&mut archive.get_block(block_hash).await?).await?.0)),
+                false => None,
+            });
+        }
+        let chain = ChainIndex::from_headers(headers.iter().map(|(_, h)| h.clone()));
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(catalog_error)?;
+        tx.execute("DELETE FROM blocks", []).map_err(catalog_error)?;
+        {
+            let mut stmt = tx.prepare(&format!("INSERT INTO blocks ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)", ENTRY_COLUMNS))
+                .map_err(catalog_error)?;
+            for (((block_hash, header), (_, size)), checksum) in headers.iter().zip(sizes.iter()).zip(sums) {
+                stmt.execute(params![
+                    block_hash.to_string(),
+                    header.prev_hash.to_string(),
+                    *size as i64,
+                    chain.height_of(block_hash).map(|h| h as i64),
+                    header.timestamp as i64,
+                    checksum,
+                    self.location(block_hash),
+                ]).map_err(catalog_error)?;
+            }
+        }
+        tx.commit().map_err(catalog_error)?;
+        Ok(sizes.len())
+    }
+
+    // Get the storage location of a block from the locator.
+    fn location(&self, block_hash: &BlockHash) -> Option<String> {
+        self.locator.as_ref().map(|locator| locator(block_hash))
+    }
+}
+
+/// A block archive that keeps a [Catalog] in sync with the blocks stored in and removed from the
+/// wrapped archive, and answers listing and size queries from the catalog.
+///
+/// Blocks are read from the wrapped archive. A block stored in the wrapped archive by other means
+/// is not in the catalog until the catalog is rebuilt.
+pub struct CatalogedBlockArchive<A: BlockArchive> {
+    archive: A,
+    catalog: Catalog,
+}
+
+impl<A: BlockArchive> CatalogedBlockArchive<A> {
+    /// Wrap an archive, keeping the given catalog in sync with it.
+    pub fn new(archive: A, catalog: Catalog) -> CatalogedBlockArchive<A> {
+        CatalogedBlockArchive { archive, catalog }
+    }
+
+    /// Get the catalog.
+    pub fn catalog(&self) -> &Catalog {
+        &self.catalog
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+
+    /// Rebuild the catalog from the wrapped archive, see [Catalog::rebuild].
+    pub async fn rebuild_catalog(&mut self, checksums: bool) -> Result<usize> {
+        self.catalog.rebuild(&mut self.archive, checksums).await
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for CatalogedBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.archive.get_block(block_hash).await
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.archive.get_block_range(block_hash, offset, length).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.block_exists(block_hash).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        self.archive.blocks_exist(block_hashes).await
+    }
+
+    /// The checksum of the block is taken as it is stored, then the size and header are read
+    /// back from the wrapped archive for the catalog.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let mut reader = ChecksumReader::new(block);
+        self.archive.store_block(block_hash, &mut reader).await?;
+        let checksum = reader.checksum();
+        let size = self.archive.block_size(block_hash).await? as u64;
+        let header = self.archive.block_header(block_hash).await?;
+        self.catalog.insert(&CatalogEntry {
+            block_hash: *block_hash,
+            prev_hash: header.prev_hash,
+            size,
+            height: None,
+            timestamp: header.timestamp as u64,
+            checksum: Some(checksum),
+            location: self.catalog.location(block_hash),
+        })
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.archive.remove_block(block_hash).await?;
+        self.catalog.remove(block_hash)?;
+        Ok(())
+    }
+
+    /// The size is read from the catalog, or from the wrapped archive for a block that is not in
+    /// the catalog.
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        match self.catalog.block_size(block_hash)? {
+            Some(size) => Ok(size as usize),
+            None => self.archive.block_size(block_hash).await,
+        }
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.archive.block_header(block_hash).await
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        self.archive.block_headers(block_hashes).await
+    }
+
+    /// The blocks in the catalog, in order of hash.
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.block_list_opts(ListOptions::default()).await
+    }
+
+    async fn block_list_opts(&mut self, options: ListOptions) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let hashes = self.catalog.list(&options)?;
+        Ok(Box::pin(BlockHashListStreamFromVec { hashes: hashes.into_iter() }))
+    }
+
+    async fn block_sizes<'a>(&'a mut self) -> Result<BlockSizeStream<'a>> {
+        let sizes: Vec<Result<(BlockHash, u64)>> = self.catalog.sizes()?.into_iter().map(Ok).collect();
+        Ok(Box::pin(futures::stream::iter(sizes)))
+    }
+
+    async fn total_size(&mut self) -> Result<u64> {
+        self.catalog.total_size()
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.archive.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.archive.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.archive.get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.verify_checksum(block_hash).await
+    }
+}
+
+// A row of the blocks table, before the hashes are parsed.
+struct CatalogRow {
+    hash: String,
+    prev_hash: String,
+    size: i64,
+    height: Option<i64>,
+    timestamp: i64,
+    checksum: Option<String>,
+    location: Option<String>,
+}
+
+impl CatalogRow {
+    fn into_entry(self) -> Result<CatalogEntry> {
+        Ok(CatalogEntry {
+            block_hash: parse_hash(&self.hash)?,
+            prev_hash: parse_hash(&self.prev_hash)?,
+            size: self.size as u64,
+            height: self.height.map(|h| h as u64),
+            timestamp: self.timestamp as u64,
+            checksum: self.checksum,
+            location: self.location,
+        })
+    }
+}
+
+// Read a row selected with ENTRY_COLUMNS.
+fn read_row(row: &rusqlite::Row) -> rusqlite::Result<CatalogRow> {
+    Ok(CatalogRow {
+        hash: row.get(0)?,
+        prev_hash: row.get(1)?,
+        size: row.get(2)?,
+        height: row.get(3)?,
+        timestamp: row.get(4)?,
+        checksum: row.get(5)?,
+        location: row.get(6)?,
+    })
+}
+
+// Set the heights of the blocks that descend from a block of the given height, or clear them if
+// the height is None, stopping at blocks whose height is already right.
+fn set_descendant_heights(conn: &Connection, block_hash: &BlockHash, height: Option<u64>) -> Result<()> {
+    let mut pending = vec![(block_hash.to_string(), height)];
+    while let Some((hash, height)) = pending.pop() {
+        let child_height = height.map(|h| h as i64 + 1);
+        let mut stmt = conn.prepare("SELECT hash FROM blocks WHERE prev_hash = ?1 AND height IS NOT ?2").map_err(catalog_error)?;
+        let children = stmt.query_map(params![hash, child_height], |row| row.get::<_, String>(0)).map_err(catalog_error)?
+            .collect::<rusqlite::Result<Vec<String>>>().map_err(catalog_error)?;
+        for child in children {
+            conn.execute("UPDATE blocks SET height = ?1 WHERE hash = ?2", params![child_height, child]).map_err(catalog_error)?;
+            pending.push((child, child_height.map(|h| h as u64)));
+        }
+    }
+    Ok(())
+}
+
+fn parse_hash(s: &str) -> Result<BlockHash> {
+    BlockHash::from_hex(s).map_err(|_| Error::CatalogError(format!("invalid block hash: {}", s)))
+}
+
+fn catalog_error(e: rusqlite::Error) -> Error {
+    Error::CatalogError(e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // A rebuilt catalog holds every block of the archive with its height, and answers the same
+    // lists and sizes as the archive.
+    #[tokio::test]
+    async fn test_rebuild() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let catalog = Catalog::open(&dir.join(CATALOG_FILE)).unwrap()
+            .with_locator(|h| SimpleFileBasedBlockArchive::relative_block_path(h).to_string_lossy().into_owned());
+        let count = catalog.rebuild(&mut archive, true).await.unwrap();
+        assert_eq!(catalog.block_count().unwrap(), count as u64);
+        assert_eq!(catalog.total_size().unwrap(), archive.total_size().await.unwrap());
+
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let entry = catalog.get(&genesis).unwrap().unwrap();
+        assert_eq!(entry.height, Some(0));
+        assert_eq!(entry.timestamp, 1231006505);
+        assert_eq!(entry.size, archive.block_size(&genesis).await.unwrap() as u64);
+        assert!(entry.checksum.is_some());
+        assert_eq!(entry.location.as_deref(), Some(SimpleFileBasedBlockArchive::relative_block_path(&genesis).to_str().unwrap()));
+
+        let mut cataloged = CatalogedBlockArchive::new(archive, catalog);
+        for order in [ListOrder::Hash, ListOrder::Height] {
+            let options = ListOptions { order, ..Default::default() };
+            let expected: Vec<BlockHash> = cataloged.archive.block_list_opts(options.clone()).await.unwrap().collect().await;
+            let listed: Vec<BlockHash> = cataloged.block_list_opts(options).await.unwrap().collect().await;
+            assert_eq!(listed, expected);
+        }
+        let options = ListOptions { order: ListOrder::Height, offset: 1, limit: Some(1), ..Default::default() };
+        let listed: Vec<BlockHash> = cataloged.block_list_opts(options).await.unwrap().collect().await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(cataloged.catalog().get(&listed[0]).unwrap().unwrap().height, Some(1));
+    }
+
+    // Storing and removing blocks keeps the catalog in sync, and heights are filled in when a
+    // missing parent arrives.
+    #[tokio::test]
+    async fn test_store_and_remove() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let block1 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let catalog = Catalog::open(&dir.join(CATALOG_FILE)).unwrap();
+        let mut archive = CatalogedBlockArchive::new(MemoryBlockArchive::new(), catalog);
+
+        let mut total = 0;
+        for h in [block1, genesis] {
+            let mut block = Vec::new();
+            src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+            archive.store_block(&h, &mut &block[..]).await.unwrap();
+            total += block.len() as u64;
+            if h == block1 {
+                // the parent is not in the catalog yet
+                assert_eq!(archive.catalog().get(&block1).unwrap().unwrap().height, None);
+            }
+        }
+        assert_eq!(archive.catalog().get(&block1).unwrap().unwrap().height, Some(1));
+        assert_eq!(archive.total_size().await.unwrap(), total);
+
+        archive.remove_block(&genesis).await.unwrap();
+        assert_eq!(archive.catalog().get(&genesis).unwrap(), None);
+        assert_eq!(archive.catalog().get(&block1).unwrap().unwrap().height, None);
+        let listed: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        assert_eq!(listed, vec![block1]);
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, BTreeSet};
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use hex::FromHex;
+use tokio_stream::StreamExt;
+use crate::{BlockArchive, Network, Result};
+
+// the number of blocks whose timestamps make up the median time past
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Details of a block in the [ChainIndex].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChainEntry {
+    /// The header of the block.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::header"))]
+    pub header: BlockHeader,
+    /// The height of the block, if it is linked to a genesis block.
+    pub height: Option<u64>,
+    /// The total work of the chain up to and including this block, if it is linked to a genesis
+    /// block. This is approximate, see block_work().
+    pub chain_work: Option<u128>,
+}
+
+/// A branch of blocks that are not in the best chain, see [ChainIndex::forks].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fork {
+    /// The block in the best chain that the fork branches from, None if the fork is not linked to
+    /// the best chain, such as a run of orphans whose parent is not in the archive.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::optional_hash"))]
+    pub branch_point: Option<BlockHash>,
+    /// The blocks of the fork, from the first block after the branch point to the tip.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub blocks: Vec<BlockHash>,
+}
+
+impl Fork {
+    /// Get the last block of the fork.
+    pub fn tip(&self) -> BlockHash {
+        *self.blocks.last().unwrap()
+    }
+
+    /// Get the number of blocks in the fork.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Check whether the fork has no blocks, which never happens for a fork from [ChainIndex::forks].
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// The result of checking a checkpoint, see [ChainIndex::check_checkpoints].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckpointResult {
+    /// The height of the checkpoint.
+    pub height: u64,
+    /// The hash of the known block at the height.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))]
+    pub expected: BlockHash,
+    /// The block at the height in the best chain, None if the best chain is not that long.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::optional_hash"))]
+    pub found: Option<BlockHash>,
+}
+
+impl CheckpointResult {
+    /// Check whether the best chain has the known block at the height.
+    pub fn passed(&self) -> bool {
+        self.found == Some(self.expected)
+    }
+
+    /// Check whether the best chain has a different block at the height, which means the archive
+    /// holds a fork of the chain or a fake one.
+    pub fn failed(&self) -> bool {
+        self.found.is_some() && !self.passed()
+    }
+}
+
+/// An index of the chain formed by the blocks in an archive.
+///
+/// The archive only knows block hashes, the ChainIndex reads all the headers, links them into a
+/// chain, and finds the best tip, which is the tip with the most work. Heights refer to the
+/// chain ending at the best tip.
+///
+/// Only blocks that are linked back to a genesis block (a block with an all-zero previous hash)
+/// have a height, so an archive that does not contain the genesis block has no chain.
+///
+/// Example code:
+///     let chain = ChainIndex::build(&mut archive).await?;
+///     let block_hash = chain.block_by_height(1000);
+pub struct ChainIndex {
+    // every block in the archive
+    entries: BTreeMap<BlockHash, ChainEntry>,
+    // the hashes of the blocks in the best chain, indexed by height
+    best_chain: Vec<BlockHash>,
+}
+
+impl ChainIndex {
+    /// Build the index from the headers of all the blocks in an archive.
+    pub async fn build<A: BlockArchive + ?Sized>(archive: &mut A) -> Result<ChainIndex> {
+        let mut headers = Vec::new();
+        let mut results = archive.block_list().await?;
+        while let Some(block_hash) = results.next().await {
+            headers.push(archive.block_header(&block_hash).await?);
+        }
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        Ok(Self::from_headers(headers))
+    }
+
+    /// Build the index from a set of headers.
+    pub fn from_headers<I: IntoIterator<Item=BlockHeader>>(headers: I) -> ChainIndex {
+        let zero = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+        let mut entries = BTreeMap::new();
+        let mut children: BTreeMap<BlockHash, Vec<BlockHash>> = BTreeMap::new();
+        let mut stack = Vec::new();
+        for header in headers {
+            let hash = header.hash();
+            if header.prev_hash == zero {
+                stack.push((hash, 0, block_work(header.bits)));
+            } else {
+                children.entry(header.prev_hash).or_default().push(hash);
+            }
+            entries.insert(hash, ChainEntry { header, height: None, chain_work: None });
+        }
+        // walk the tree from each genesis block, keeping track of the tip with the most work
+        let mut tip: Option<(BlockHash, u128)> = None;
+        while let Some((hash, height, chain_work)) = stack.pop() {
+            let entry = entries.get_mut(&hash).unwrap();
+            entry.height = Some(height);
+            entry.chain_work = Some(chain_work);
+            if !matches!(tip, Some((_, w)) if w >= chain_work) {
+                tip = Some((hash, chain_work));
+            }
+            for child in children.get(&hash).into_iter().flatten() {
+                let work = block_work(entries[child].header.bits);
+                stack.push((*child, height + 1, chain_work.saturating_add(work)));
+            }
+        }
+        // follow the best tip back to genesis
+        let mut best_chain = Vec::new();
+        let mut next = tip.map(|(h, _)| h);
+        while let Some(hash) = next {
+            best_chain.push(hash);
+            next = entries.get(&hash).map(|e| e.header.prev_hash).filter(|h| entries.contains_key(h));
+        }
+        best_chain.reverse();
+        ChainIndex { entries, best_chain }
+    }
+
+    /// Get the hash and height of the best tip, or None if there is no chain.
+    pub fn tip(&self) -> Option<(BlockHash, u64)> {
+        self.best_chain.last().map(|h| (*h, self.best_chain.len() as u64 - 1))
+    }
+
+    /// Get the hash of the block at the given height in the best chain.
+    pub fn block_by_height(&self, height: u64) -> Option<BlockHash> {
+        self.best_chain.get(usize::try_from(height).ok()?).copied()
+    }
+
+    /// Get the height of a block, or None if the block is unknown or not linked to a genesis block.
+    ///
+    /// Blocks that are not in the best chain also have a height.
+    pub fn height_of(&self, block_hash: &BlockHash) -> Option<u64> {
+        self.entries.get(block_hash).and_then(|e| e.height)
+    }
+
+    /// Check whether a block is in the best chain.
+    pub fn is_in_best_chain(&self, block_hash: &BlockHash) -> bool {
+        match self.height_of(block_hash) {
+            Some(height) => self.block_by_height(height).as_ref() == Some(block_hash),
+            None => false,
+        }
+    }
+
+    /// Get the genesis blocks in the index, the blocks with an all-zero previous hash. An archive
+    /// of a single network has at most one.
+    pub fn genesis_blocks(&self) -> Vec<BlockHash> {
+        self.entries.iter().filter(|(_, e)| e.height == Some(0)).map(|(h, _)| *h).collect()
+    }
+
+    /// Get the genesis blocks in the index that are not the genesis block of the network, every
+    /// block linked to one of these belongs to another network.
+    pub fn foreign_genesis_blocks(&self, network: Network) -> Vec<BlockHash> {
+        let genesis = network.genesis_hash();
+        self.genesis_blocks().into_iter().filter(|h| *h != genesis).collect()
+    }
+
+    /// Get the branches of blocks that are not in the best chain, stale forks and orphans.
+    ///
+    /// There is a fork for each tip other than the best tip, forks that branch from another fork
+    /// include the blocks they share with it. Forks that branch from the best chain come first,
+    /// in order of the height of the branch point.
+    pub fn forks(&self) -> Vec<Fork> {
+        let parents: BTreeSet<BlockHash> = self.entries.values().map(|e| e.header.prev_hash).collect();
+        let mut forks = Vec::new();
+        for (hash, entry) in self.entries.iter() {
+            if parents.contains(hash) || self.is_in_best_chain(hash) {
+                continue;
+            }
+            // walk back to the best chain or the first block whose parent is not in the index
+            let mut blocks = vec![*hash];
+            let mut branch_point = None;
+            let mut prev = entry.header.prev_hash;
+            while let Some(e) = self.entries.get(&prev) {
+                if self.is_in_best_chain(&prev) {
+                    branch_point = Some(prev);
+                    break;
+                }
+                blocks.push(prev);
+                prev = e.header.prev_hash;
+            }
+            blocks.reverse();
+            forks.push(Fork { branch_point, blocks });
+        }
+        forks.sort_by_key(|f| (f.branch_point.is_none(), f.branch_point.and_then(|h| self.height_of(&h)), f.tip()));
+        forks
+    }
+
+    /// Get the median time past of a block, the median of the timestamps of the block and the ten
+    /// blocks before it, or of as many of them as are in the index. None if the block is unknown.
+    ///
+    /// This is the time used by consensus rules, as a single block can not move it far from the
+    /// actual time.
+    pub fn median_time_past(&self, block_hash: &BlockHash) -> Option<u64> {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut next = self.entries.get(block_hash);
+        while let Some(e) = next {
+            times.push(e.header.timestamp as u64);
+            if times.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            next = self.entries.get(&e.header.prev_hash);
+        }
+        if times.is_empty() {
+            return None;
+        }
+        times.sort();
+        Some(times[times.len() / 2])
+    }
+
+    /// Check that the best chain passes through the checkpoints, given as heights and hashes,
+    /// such as those of [Network::checkpoints]. The results are in order of height.
+    ///
+    /// A checkpoint above the best tip is neither passed nor failed, as the archive may be
+    /// partial.
+    pub fn check_checkpoints(&self, checkpoints: &[(u64, BlockHash)]) -> Vec<CheckpointResult> {
+        let mut results: Vec<CheckpointResult> = checkpoints.iter()
+            .map(|(height, expected)| CheckpointResult { height: *height, expected: *expected, found: self.block_by_height(*height) })
+            .collect();
+        results.sort_by_key(|r| r.height);
+        results
+    }
+
+    /// Get the details of a block.
+    pub fn get(&self, block_hash: &BlockHash) -> Option<&ChainEntry> {
+        self.entries.get(block_hash)
+    }
+
+    /// Get the number of blocks in the index.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+// Get the approximate work of a block from the compact encoding of its target, which is
+// 2^256 / (target + 1). The result saturates at u128::MAX, which is far more than the work of
+// any real block.
+pub(crate) fn block_work(bits: u32) -> u128 {
+    let exponent = (bits >> 24) as i32;
+    let mantissa = (bits & 0x007f_ffff) as u128;
+    if mantissa == 0 {
+        return 0;
+    }
+    // target = mantissa * 2^(8 * (exponent - 3)), so work = 2^(256 - 8 * (exponent - 3)) / mantissa
+    let shift = 256 - 8 * (exponent - 3);
+    if shift >= 128 {
+        return u128::MAX;
+    }
+    if shift < 0 {
+        return 0;
+    }
+    (1u128 << shift) / mantissa
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoinsv::bitcoin::Encodable;
+
+    // the easiest target, as used on regtest
+    const EASY_BITS: u32 = 0x207fffff;
+    // the target at difficulty 1
+    const HARD_BITS: u32 = 0x1d00ffff;
+
+    // Create a header with the given parent.
+    async fn make_header(prev_hash: &BlockHash, bits: u32, nonce: u32) -> BlockHeader {
+        let mut b = Vec::new();
+        b.extend_from_slice(&1u32.to_le_bytes());
+        b.extend_from_slice(&prev_hash.hash);
+        b.extend_from_slice(&[0u8; 32]);
+        b.extend_from_slice(&0u32.to_le_bytes());
+        b.extend_from_slice(&bits.to_le_bytes());
+        b.extend_from_slice(&nonce.to_le_bytes());
+        BlockHeader::from_binary(&mut &b[..]).await.unwrap()
+    }
+
+    // Create a header with the given parent and timestamp.
+    async fn make_timed_header(prev_hash: &BlockHash, timestamp: u32) -> BlockHeader {
+        let mut b = Vec::new();
+        b.extend_from_slice(&1u32.to_le_bytes());
+        b.extend_from_slice(&prev_hash.hash);
+        b.extend_from_slice(&[0u8; 32]);
+        b.extend_from_slice(&timestamp.to_le_bytes());
+        b.extend_from_slice(&EASY_BITS.to_le_bytes());
+        b.extend_from_slice(&0u32.to_le_bytes());
+        BlockHeader::from_binary(&mut &b[..]).await.unwrap()
+    }
+
+    #[test]
+    fn test_block_work() {
+        assert_eq!(block_work(HARD_BITS), (1u128 << 48) / 0xffff);
+        assert_eq!(block_work(EASY_BITS), 2);
+        assert_eq!(block_work(0), 0);
+    }
+
+    // The best tip is the one with the most work, not the longest chain.
+    #[tokio::test]
+    async fn test_best_tip() {
+        let zero = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+        let genesis = make_header(&zero, EASY_BITS, 0).await;
+        let a1 = make_header(&genesis.hash(), EASY_BITS, 1).await;
+        let a2 = make_header(&a1.hash(), EASY_BITS, 2).await;
+        let a3 = make_header(&a2.hash(), EASY_BITS, 3).await;
+        let b2 = make_header(&a1.hash(), HARD_BITS, 4).await;
+        // a block whose parent is not in the index
+        let missing = make_header(&a3.hash(), EASY_BITS, 5).await;
+        let orphan = make_header(&missing.hash(), EASY_BITS, 6).await;
+        let chain = ChainIndex::from_headers(vec![a3.clone(), b2.clone(), genesis.clone(), a1.clone(), a2.clone(), orphan.clone()]);
+        assert_eq!(chain.len(), 6);
+        assert_eq!(chain.tip(), Some((b2.hash(), 2)));
+        assert_eq!(chain.block_by_height(0), Some(genesis.hash()));
+        assert_eq!(chain.block_by_height(1), Some(a1.hash()));
+        assert_eq!(chain.block_by_height(2), Some(b2.hash()));
+        assert_eq!(chain.block_by_height(3), None);
+        assert_eq!(chain.height_of(&a3.hash()), Some(3));
+        assert!(!chain.is_in_best_chain(&a3.hash()));
+        assert!(chain.is_in_best_chain(&a1.hash()));
+        assert_eq!(chain.height_of(&orphan.hash()), None);
+        assert_eq!(chain.genesis_blocks(), vec![genesis.hash()]);
+        assert_eq!(chain.foreign_genesis_blocks(Network::Mainnet), vec![genesis.hash()]);
+    }
+
+    // The stale branch and the orphan are forks, the best chain is not.
+    #[tokio::test]
+    async fn test_forks() {
+        let zero = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+        let genesis = make_header(&zero, EASY_BITS, 0).await;
+        let a1 = make_header(&genesis.hash(), EASY_BITS, 1).await;
+        let a2 = make_header(&a1.hash(), EASY_BITS, 2).await;
+        let a3 = make_header(&a2.hash(), EASY_BITS, 3).await;
+        let b2 = make_header(&a1.hash(), HARD_BITS, 4).await;
+        let missing = make_header(&a3.hash(), EASY_BITS, 5).await;
+        let orphan = make_header(&missing.hash(), EASY_BITS, 6).await;
+        let chain = ChainIndex::from_headers(vec![a3.clone(), b2.clone(), genesis.clone(), a1.clone(), a2.clone(), orphan.clone()]);
+        let forks = chain.forks();
+        assert_eq!(forks.len(), 2);
+        assert_eq!(forks[0].branch_point, Some(a1.hash()));
+        assert_eq!(forks[0].blocks, vec![a2.hash(), a3.hash()]);
+        assert_eq!(forks[0].tip(), a3.hash());
+        assert_eq!(forks[1].branch_point, None);
+        assert_eq!(forks[1].blocks, vec![orphan.hash()]);
+        let chain = ChainIndex::from_headers(vec![genesis.clone(), a1.clone(), b2.clone()]);
+        assert!(chain.forks().is_empty());
+    }
+
+    // A block with a timestamp far in the future does not move the median time past.
+    #[tokio::test]
+    async fn test_median_time_past() {
+        let zero = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+        let mut headers = vec![make_timed_header(&zero, 1000).await];
+        for i in 1..12u32 {
+            let timestamp = if i == 11 { 1_000_000 } else { 1000 + i * 600 };
+            let header = make_timed_header(&headers[i as usize - 1].hash(), timestamp).await;
+            headers.push(header);
+        }
+        let chain = ChainIndex::from_headers(headers.clone());
+        assert_eq!(chain.median_time_past(&headers[0].hash()), Some(1000));
+        // the median of 1000, 1600 and 2200
+        assert_eq!(chain.median_time_past(&headers[2].hash()), Some(1600));
+        // the median of the timestamps of blocks 1 to 11
+        assert_eq!(chain.median_time_past(&headers[11].hash()), Some(1000 + 6 * 600));
+        assert_eq!(chain.median_time_past(&zero), None);
+    }
+
+    // The best chain passes the checkpoint at height 1, fails the one at height 2, and does not
+    // reach the one at height 3.
+    #[tokio::test]
+    async fn test_check_checkpoints() {
+        let zero = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+        let genesis = make_header(&zero, EASY_BITS, 0).await;
+        let a1 = make_header(&genesis.hash(), EASY_BITS, 1).await;
+        let a2 = make_header(&a1.hash(), EASY_BITS, 2).await;
+        let b2 = make_header(&a1.hash(), EASY_BITS, 3).await;
+        let chain = ChainIndex::from_headers(vec![genesis.clone(), a1.clone(), a2.clone()]);
+        let results = chain.check_checkpoints(&[(3, a2.hash()), (2, b2.hash()), (1, a1.hash())]);
+        assert_eq!(results.iter().map(|r| r.height).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(results[0].passed());
+        assert!(results[1].failed());
+        assert_eq!(results[1].found, Some(a2.hash()));
+        assert!(!results[2].passed() && !results[2].failed());
+        assert_eq!(Network::Mainnet.checkpoints()[0].0, 11111);
+        assert!(Network::Regtest.checkpoints().is_empty());
+    }
+
+    // The test archive contains the genesis block and block 1, the other block does not link to
+    // them.
+    #[tokio::test]
+    async fn test_archive_chain() {
+        let mut archive = crate::SimpleFileBasedBlockArchive::new(std::path::PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let chain = ChainIndex::build(&mut archive).await.unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let block1 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain.tip(), Some((block1, 1)));
+        assert_eq!(chain.block_by_height(0), Some(genesis));
+        assert_eq!(chain.genesis_blocks(), vec![genesis]);
+    }
+}
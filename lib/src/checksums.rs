@@ -0,0 +1,131 @@
+//! Checksum manifests compatible with the `sha256sum` tool.
+//!
+//! A checksum manifest has one line per block file, with the hex encoded SHA-256 checksum of the
+//! file, two spaces, and the path of the file relative to the root of the archive:
+//!
+//! ```text
+//! 4e9f...c2a1  6f/e2/00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f.bin
+//! ```
+//!
+//! which means it can be checked with `sha256sum -c SHA256SUMS` from the root of the archive, as
+//! well as with [verify_checksums].
+use std::path::{Path, PathBuf};
+use hex::{FromHex, ToHex};
+use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use crate::manifest::sha256_reader;
+use crate::{BlockArchive, Error, Result, SimpleFileBasedBlockArchive};
+
+/// The result of checking a checksum manifest with [verify_checksums].
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChecksumReport {
+    /// The number of files with the correct checksum.
+    pub ok: usize,
+    /// Files whose checksum did not match.
+    pub failed: Vec<PathBuf>,
+    /// Files that could not be found.
+    pub missing: Vec<PathBuf>,
+}
+
+impl ChecksumReport {
+    /// Returns true if every file was found and had the correct checksum.
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Write a checksum manifest of every block file in the archive, including the chunks of blocks
+/// that are stored in chunks.
+///
+/// Returns the number of files in the manifest.
+pub async fn export_checksums<W>(archive: &mut SimpleFileBasedBlockArchive, writer: &mut W) -> Result<usize>
+    where W: AsyncWrite + Unpin
+{
+    let mut count = 0;
+    let mut results = archive.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        // the checksum is of the file as stored, which may be compressed, and a block stored in
+        // chunks has a line for each chunk
+        for path in archive.block_files(&block_hash).await? {
+            let mut file = File::open(&path).await?;
+            let (checksum, _) = sha256_reader(&mut file).await?;
+            let checksum: String = checksum.encode_hex();
+            let path = path.strip_prefix(&archive.root_path).unwrap_or(&path);
+            writer.write_all(format!("{}  {}\n", checksum, path.display()).as_bytes()).await?;
+            count += 1;
+        }
+    }
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    writer.flush().await?;
+    Ok(count)
+}
+
+/// Check the files listed in a checksum manifest, with paths relative to root_path.
+///
+/// Both the text ("  ") and binary (" *") separators written by `sha256sum` are accepted.
+pub async fn verify_checksums(root_path: &Path, manifest: &str) -> Result<ChecksumReport> {
+    let mut report = ChecksumReport::default();
+    for line in manifest.lines() {
+        if line.is_empty() {
+            continue;
+        }
+        let bad_line = || Error::InvalidManifest(format!("invalid checksum line: {}", line));
+        if line.len() < 67 || !line.is_char_boundary(64) {
+            return Err(bad_line());
+        }
+        let (checksum, rest) = line.split_at(64);
+        let checksum = <[u8; 32]>::from_hex(checksum).map_err(|_| bad_line())?;
+        let path = rest.strip_prefix("  ").or_else(|| rest.strip_prefix(" *")).ok_or_else(bad_line)?;
+        let path = PathBuf::from(path);
+        let mut file = match File::open(root_path.join(&path)).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                report.missing.push(path);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if sha256_reader(&mut file).await?.0 == checksum {
+            report.ok += 1;
+        } else {
+            report.failed.push(path);
+        }
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Export checksums for the test archive and check them.
+    #[tokio::test]
+    async fn test_export_and_verify_checksums() {
+        let root = PathBuf::from("../testdata/blockarchive");
+        let mut archive = SimpleFileBasedBlockArchive::new(root.clone()).await.unwrap();
+        let mut out = Vec::new();
+        assert_eq!(export_checksums(&mut archive, &mut out).await.unwrap(), 3);
+        let manifest = String::from_utf8(out).unwrap();
+        assert!(manifest.contains("  6f/e2/00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f.bin\n"));
+        let report = verify_checksums(&root, &manifest).await.unwrap();
+        assert_eq!(report.ok, 3);
+        assert!(report.is_ok());
+    }
+
+    // A wrong checksum and a missing file should both be reported.
+    #[tokio::test]
+    async fn test_verify_bad_checksums() {
+        let root = PathBuf::from("../testdata/blockarchive");
+        let zeros = "0".repeat(64);
+        let manifest = format!("{}  6f/e2/00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f.bin\n{} *no/such/file.bin\n", zeros, zeros);
+        let report = verify_checksums(&root, &manifest).await.unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.missing, vec![PathBuf::from("no/such/file.bin")]);
+        assert!(verify_checksums(&root, "not a checksum line").await.is_err());
+    }
+}
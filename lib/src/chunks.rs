@@ -0,0 +1,197 @@
+//! Storage of blocks in chunks, for filesystems and object stores that limit the size of a file.
+//!
+//! A Bitcoin SV block can be larger than the largest file that some filesystems allow, or the
+//! largest object that an object store accepts in one upload. The simple file based archive and
+//! the S3 archive can be given a chunk size, see
+//! [crate::SimpleFileBasedBlockArchive::with_chunk_size], and they then store a block that is
+//! larger than the chunk size in parts. The first chunk is stored where the block would be, the
+//! other chunks are stored next to it with their number and a "part" extension, and a manifest
+//! with a "chunks" extension lists the size of every chunk:
+//!
+//! ```text
+//! 31/c5/00000000000000000d9c2d2ed2f66a1f3f5e1e5e0d2f2e1c5b8c77f20b9e5e709dc531.bin
+//! 31/c5/00000000000000000d9c2d2ed2f66a1f3f5e1e5e0d2f2e1c5b8c77f20b9e5e709dc531.00001.part
+//! 31/c5/00000000000000000d9c2d2ed2f66a1f3f5e1e5e0d2f2e1c5b8c77f20b9e5e709dc531.00002.part
+//! 31/c5/00000000000000000d9c2d2ed2f66a1f3f5e1e5e0d2f2e1c5b8c77f20b9e5e709dc531.chunks
+//! ```
+//!
+//! The first chunk is written last, so a block whose write was interrupted is not in the archive.
+//! The chunk size is always a multiple of [CHUNK_ALIGN], so a block file whose size is not a
+//! multiple of it can not be the first chunk of a block and the manifest is only looked for when
+//! it is. Reading the block joins the chunks back together, so the chunks are invisible to the
+//! users of the archive, and an archive can be read whatever chunk size it is opened with.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use crate::{Error, Result};
+
+/// The chunk size is a multiple of this many bytes, 8 MiB.
+pub const CHUNK_ALIGN: u64 = 8 * 1024 * 1024;
+
+/// The chunk size used by the command line tool when none is given, 2 GiB.
+pub const DEFAULT_CHUNK_SIZE: u64 = 256 * CHUNK_ALIGN;
+
+/// The extension of the manifest of a block that is stored in chunks.
+pub const MANIFEST_EXTENSION: &str = "chunks";
+
+/// The extension of the chunks of a block after the first.
+pub const PART_EXTENSION: &str = "part";
+
+/// The sizes of the chunks in which a block is stored, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkManifest {
+    /// The size of each chunk, the first chunk is the one stored in place of the block.
+    pub sizes: Vec<u64>,
+}
+
+impl ChunkManifest {
+    /// The size of the block.
+    pub fn block_size(&self) -> u64 {
+        self.sizes.iter().sum()
+    }
+
+    /// Encode the manifest as text, with the size of one chunk per line.
+    pub fn encode(&self) -> String {
+        self.sizes.iter().map(|s| format!("{}\n", s)).collect()
+    }
+
+    /// Decode a manifest written by [ChunkManifest::encode].
+    pub fn decode(s: &str) -> Result<ChunkManifest> {
+        let sizes = s.lines()
+            .map(|l| l.trim().parse::<u64>().map_err(|_| Error::InvalidChunkManifest(format!("invalid chunk size: {}", l))))
+            .collect::<Result<Vec<u64>>>()?;
+        if sizes.len() < 2 {
+            return Err(Error::InvalidChunkManifest("a block in chunks has at least two chunks".to_string()));
+        }
+        Ok(ChunkManifest { sizes })
+    }
+
+    /// Find the chunk that holds an offset in the block, returning the number of the chunk and
+    /// the offset in the chunk, or None if the offset is past the end of the block.
+    pub fn locate(&self, offset: u64) -> Option<(usize, u64)> {
+        let mut start = 0;
+        for (i, size) in self.sizes.iter().enumerate() {
+            if offset < start + size {
+                return Some((i, offset - start));
+            }
+            start += size;
+        }
+        None
+    }
+}
+
+/// Check that a chunk size is a positive multiple of [CHUNK_ALIGN].
+pub fn check_chunk_size(chunk_size: u64) -> Result<()> {
+    if chunk_size == 0 || !chunk_size.is_multiple_of(CHUNK_ALIGN) {
+        return Err(Error::InvalidChunkManifest(format!("the chunk size must be a multiple of {} bytes", CHUNK_ALIGN)));
+    }
+    Ok(())
+}
+
+// Whether a block file of the given size may be the first chunk of a block.
+pub(crate) fn may_be_first_chunk(size: u64) -> bool {
+    size > 0 && size.is_multiple_of(CHUNK_ALIGN)
+}
+
+// The suffix of the name of a chunk after the first, which replaces the "bin" extension.
+pub(crate) fn part_suffix(index: usize) -> String {
+    format!("{:05}.{}", index, PART_EXTENSION)
+}
+
+// Opens the reader of a chunk.
+pub(crate) type OpenChunk = Pin<Box<dyn Future<Output = Result<Box<dyn AsyncRead + Unpin + Send>>> + Send>>;
+
+// Reads the chunks of a block one after the other, opening each chunk when the one before it has
+// been read.
+pub(crate) struct ChainedReader {
+    current: Option<Box<dyn AsyncRead + Unpin + Send>>,
+    opening: Option<OpenChunk>,
+    pending: VecDeque<OpenChunk>,
+}
+
+impl ChainedReader {
+    pub(crate) fn new(first: Box<dyn AsyncRead + Unpin + Send>, rest: Vec<OpenChunk>) -> ChainedReader {
+        ChainedReader { current: Some(first), opening: None, pending: rest.into() }
+    }
+}
+
+impl AsyncRead for ChainedReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            if let Some(reader) = self.current.as_mut() {
+                let before = buf.filled().len();
+                match Pin::new(reader).poll_read(cx, buf) {
+                    Poll::Ready(Ok(())) if buf.filled().len() == before && buf.remaining() > 0 => {
+                        // the end of this chunk
+                        self.current = None;
+                    }
+                    other => return other,
+                }
+            }
+            if let Some(opening) = self.opening.as_mut() {
+                match opening.as_mut().poll(cx) {
+                    Poll::Ready(Ok(reader)) => {
+                        self.opening = None;
+                        self.current = Some(reader);
+                        continue;
+                    }
+                    Poll::Ready(Err(e)) => {
+                        self.opening = None;
+                        self.pending.clear();
+                        return Poll::Ready(Err(match e {
+                            Error::IoError(e) => e,
+                            e => std::io::Error::other(e.to_string()),
+                        }));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            match self.pending.pop_front() {
+                Some(open) => self.opening = Some(open),
+                // the end of the block
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use tokio::io::AsyncReadExt;
+    use super::*;
+
+    // Test encoding, decoding and locating offsets in a manifest.
+    #[test]
+    fn test_manifest() {
+        let manifest = ChunkManifest { sizes: vec![CHUNK_ALIGN, CHUNK_ALIGN, 100] };
+        assert_eq!(ChunkManifest::decode(&manifest.encode()).unwrap(), manifest);
+        assert_eq!(manifest.block_size(), 2 * CHUNK_ALIGN + 100);
+        assert_eq!(manifest.locate(0), Some((0, 0)));
+        assert_eq!(manifest.locate(CHUNK_ALIGN + 5), Some((1, 5)));
+        assert_eq!(manifest.locate(2 * CHUNK_ALIGN + 99), Some((2, 99)));
+        assert_eq!(manifest.locate(2 * CHUNK_ALIGN + 100), None);
+        assert!(ChunkManifest::decode("100\n").is_err());
+        assert!(ChunkManifest::decode("100\nabc\n").is_err());
+        assert!(check_chunk_size(CHUNK_ALIGN).is_ok());
+        assert!(check_chunk_size(0).is_err());
+        assert!(check_chunk_size(CHUNK_ALIGN + 1).is_err());
+    }
+
+    // Test that the chained reader reads the chunks in order.
+    #[tokio::test]
+    async fn test_chained_reader() {
+        let rest: Vec<OpenChunk> = vec![
+            Box::pin(async { Ok::<_, Error>(Box::new(Cursor::new(vec![3u8, 4])) as Box<dyn AsyncRead + Unpin + Send>) }),
+            Box::pin(async { Ok::<_, Error>(Box::new(Cursor::new(Vec::new())) as Box<dyn AsyncRead + Unpin + Send>) }),
+            Box::pin(async { Ok::<_, Error>(Box::new(Cursor::new(vec![5u8])) as Box<dyn AsyncRead + Unpin + Send>) }),
+        ];
+        let mut reader = ChainedReader::new(Box::new(Cursor::new(vec![1u8, 2])), rest);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, vec![1, 2, 3, 4, 5]);
+    }
+}
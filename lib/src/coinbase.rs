@@ -0,0 +1,181 @@
+//! The coinbase transaction of a block, which names the miner and claims the block reward.
+use bitcoinsv::bitcoin::BlockHash;
+use serde_json::{json, Value};
+use tokio::io::AsyncRead;
+use crate::headers::HEADER_SIZE;
+use crate::txindex::{read_bytes, read_tx, read_varint, take, take_varint};
+use crate::{BlockArchive, Error, Result};
+
+// the shortest run of printable characters that is taken as part of the miner tag
+const MIN_TAG_RUN: usize = 4;
+
+// the push opcodes with a length that follows the opcode
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+
+/// The coinbase transaction of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coinbase {
+    /// The hash of the block.
+    pub block_hash: BlockHash,
+    /// The txid of the coinbase transaction.
+    pub txid: BlockHash,
+    /// The script of the coinbase input, which is chosen by the miner.
+    pub script: Vec<u8>,
+    /// The total value of the outputs in satoshis, the block subsidy plus the fees.
+    pub reward: u64,
+}
+
+impl Coinbase {
+    /// Get the height of the block from the start of the coinbase script, as required by BIP34.
+    ///
+    /// Blocks before BIP34 activated, at height 227931 on mainnet, do not start the script with
+    /// their height and the value returned for them is meaningless.
+    pub fn script_height(&self) -> Option<u64> {
+        match *self.script.first()? {
+            0 => Some(0),
+            n @ 1..=8 => {
+                let bytes = self.script.get(1..1 + n as usize)?;
+                let mut value = [0u8; 8];
+                value[..bytes.len()].copy_from_slice(bytes);
+                Some(u64::from_le_bytes(value))
+            }
+            // OP_1 to OP_16
+            n @ 0x51..=0x60 => Some((n - 0x50) as u64),
+            _ => None,
+        }
+    }
+
+    /// Get the text that the miner put in the coinbase script, such as "/P2SH/" or the name of a
+    /// pool.
+    ///
+    /// The text is the runs of at least 4 printable ASCII characters in the data pushed by the
+    /// script, joined by spaces, or None if there are none.
+    pub fn miner_tag(&self) -> Option<String> {
+        let mut runs = Vec::new();
+        for data in script_pushes(&self.script) {
+            for run in data.split(|b| !(0x20..0x7f).contains(b)) {
+                if run.len() >= MIN_TAG_RUN {
+                    runs.push(String::from_utf8_lossy(run).trim().to_string());
+                }
+            }
+        }
+        runs.retain(|r| !r.is_empty());
+        if runs.is_empty() { None } else { Some(runs.join(" ")) }
+    }
+
+    /// Get the fees collected by the block, the reward less the subsidy.
+    ///
+    /// A miner can claim less than the subsidy, in which case the fees are zero.
+    pub fn fees(&self, subsidy: u64) -> u64 {
+        self.reward.saturating_sub(subsidy)
+    }
+
+    /// Get the coinbase as a JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "block_hash": self.block_hash.to_string(),
+            "txid": self.txid.to_string(),
+            "script": hex::encode(&self.script),
+            "miner_tag": self.miner_tag(),
+            "reward": self.reward,
+        })
+    }
+}
+
+/// Read the coinbase transaction of a block, without reading the rest of the block.
+///
+/// Fails with [Error::CorruptBlock] if the block does not start with a coinbase transaction.
+pub async fn read_coinbase<A: BlockArchive + ?Sized>(archive: &A, block_hash: &BlockHash) -> Result<Coinbase> {
+    let mut reader = archive.get_block(block_hash).await?;
+    let tx = match read_first_tx(&mut reader).await {
+        Ok(Some(tx)) => tx,
+        Ok(None) => return Err(Error::CorruptBlock(*block_hash)),
+        Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(Error::CorruptBlock(*block_hash)),
+        Err(e) => return Err(e),
+    };
+    let (script, reward) = parse_coinbase(&tx).ok_or(Error::CorruptBlock(*block_hash))?;
+    Ok(Coinbase { block_hash: *block_hash, txid: BlockHash::sha256d(&tx), script, reward })
+}
+
+// Read the first transaction of an encoded block, or None if the block has no transactions.
+async fn read_first_tx<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut buf = Vec::new();
+    read_bytes(reader, &mut buf, HEADER_SIZE as u64).await?;
+    if read_varint(reader, &mut buf).await? == 0 {
+        return Ok(None);
+    }
+    buf.clear();
+    read_tx(reader, &mut buf).await?;
+    Ok(Some(buf))
+}
+
+// Get the input script and the total output value of an encoded coinbase transaction, or None if
+// it does not have exactly one input.
+pub(crate) fn parse_coinbase(tx: &[u8]) -> Option<(Vec<u8>, u64)> {
+    let mut cursor = tx.get(4..)?;
+    if take_varint(&mut cursor)? != 1 {
+        return None;
+    }
+    take(&mut cursor, 36)?;
+    let script_len = take_varint(&mut cursor)?;
+    let script = take(&mut cursor, script_len)?.to_vec();
+    take(&mut cursor, 4)?;
+    let mut reward = 0u64;
+    for _ in 0..take_varint(&mut cursor)? {
+        let value = u64::from_le_bytes(take(&mut cursor, 8)?.try_into().unwrap());
+        reward = reward.checked_add(value)?;
+        let script_len = take_varint(&mut cursor)?;
+        take(&mut cursor, script_len)?;
+    }
+    Some((script, reward))
+}
+
+// Get the data pushed by a script, stopping at the first push that runs past the end.
+fn script_pushes(script: &[u8]) -> Vec<&[u8]> {
+    let mut pushes = Vec::new();
+    let mut cursor = script;
+    while let Some(opcode) = take(&mut cursor, 1) {
+        let len = match opcode[0] {
+            n @ 1..=0x4b => Some(n as u64),
+            OP_PUSHDATA1 => take(&mut cursor, 1).map(|b| b[0] as u64),
+            OP_PUSHDATA2 => take(&mut cursor, 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u64),
+            OP_PUSHDATA4 => take(&mut cursor, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64),
+            // other opcodes push no data
+            _ => continue,
+        };
+        match len.and_then(|n| take(&mut cursor, n)) {
+            Some(data) => pushes.push(data),
+            None => break,
+        }
+    }
+    pushes
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use crate::{Network, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // Read the coinbase of a block after BIP34, and the genesis block.
+    #[tokio::test]
+    async fn test_read_coinbase() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let coinbase = read_coinbase(&archive, &h).await.unwrap();
+        // the coinbase is the only transaction in the block
+        assert_eq!(coinbase.txid, archive.block_header(&h).await.unwrap().merkle_root);
+        assert_eq!(coinbase.script_height(), Some(227495));
+        assert_eq!(coinbase.miner_tag(), Some("/P2SH/".to_string()));
+        assert_eq!(coinbase.reward, 2_500_000_000);
+        assert_eq!(coinbase.fees(Network::Mainnet.block_subsidy(227495)), 0);
+
+        let genesis = read_coinbase(&archive, &Network::Mainnet.genesis_hash()).await.unwrap();
+        assert_eq!(genesis.miner_tag(), Some("The Times 03/Jan/2009 Chancellor on brink of second bailout for banks".to_string()));
+        assert_eq!(genesis.reward, 5_000_000_000);
+    }
+}
@@ -0,0 +1,359 @@
+//! Compact blocks, as described in BIP152, for serving blocks to peers that already have most of
+//! the transactions in their mempool.
+//!
+//! A compact block has the header of a block, a 6 byte short id for each transaction, and the
+//! transactions that the peer is unlikely to have in full, which is only the coinbase. The short
+//! ids are SipHash-2-4 of the txid keyed by the header and a nonce, so they differ between
+//! compact blocks of the same block. A peer rebuilds the block from its own transactions with
+//! [CompactBlock::reconstruct], and asks for the transactions it does not have.
+//!
+//! Example code:
+//!     let compact = compact_block(&archive, &block_hash, nonce).await?;
+//!     match compact.reconstruct(&mempool)? {
+//!         Reconstruction::Block(block) => ...,
+//!         Reconstruction::Missing(indexes) => ...,
+//!     }
+use std::collections::{BTreeMap, BTreeSet};
+use bitcoinsv::bitcoin::BlockHash;
+use ring::digest::{digest, SHA256};
+use tokio::io::AsyncRead;
+use crate::headers::HEADER_SIZE;
+use crate::merkle::{compute_merkle_root, validate_no_duplicate_vulnerability, write_varint, TxHash};
+use crate::txindex::{read_bytes, read_tx, read_varint, take, take_varint};
+use crate::{BlockArchive, Error, Result};
+
+// the number of bytes of a short id
+const SHORT_ID_LEN: usize = 6;
+
+/// A compact block, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactBlock {
+    /// The encoded header of the block.
+    pub header: [u8; 80],
+    /// The nonce that the short ids are keyed with.
+    pub nonce: u64,
+    /// The short ids of the transactions that are not prefilled, in block order.
+    pub short_ids: Vec<u64>,
+    /// The transactions that are sent in full, with their index in the block, in block order.
+    pub prefilled: Vec<(u64, Vec<u8>)>,
+}
+
+/// The result of [CompactBlock::reconstruct].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reconstruction {
+    /// The encoded block.
+    Block(Vec<u8>),
+    /// The indexes of the transactions that the provider does not have, or whose short ids
+    /// match more than one of its transactions.
+    Missing(Vec<u64>),
+}
+
+/// A source of transactions that may be in a block, such as a mempool.
+pub trait TxProvider {
+    /// Call the function with the txid and the encoded transaction of each transaction.
+    fn for_each_tx(&self, f: &mut dyn FnMut(&TxHash, &[u8]));
+}
+
+impl TxProvider for BTreeMap<TxHash, Vec<u8>> {
+    fn for_each_tx(&self, f: &mut dyn FnMut(&TxHash, &[u8])) {
+        for (txid, tx) in self.iter() {
+            f(txid, tx);
+        }
+    }
+}
+
+impl CompactBlock {
+    /// Read an encoded block, keeping the coinbase and the short ids of the other transactions.
+    pub async fn read<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, nonce: u64) -> Result<CompactBlock> {
+        let mut buf = Vec::new();
+        read_bytes(reader, &mut buf, HEADER_SIZE as u64).await?;
+        let header: [u8; 80] = buf[..].try_into().unwrap();
+        let num_tx = read_varint(reader, &mut buf).await?;
+        let keys = short_id_keys(&header, nonce);
+        let mut short_ids = Vec::new();
+        let mut prefilled = Vec::new();
+        for i in 0..num_tx {
+            buf.clear();
+            read_tx(reader, &mut buf).await?;
+            if i == 0 {
+                prefilled.push((0, buf.clone()));
+            } else {
+                short_ids.push(short_id(keys, &BlockHash::sha256d(&buf)));
+            }
+        }
+        Ok(CompactBlock { header, nonce, short_ids, prefilled })
+    }
+
+    /// Decode a compact block from the BIP152 encoding written by [CompactBlock::to_bytes].
+    pub fn from_bytes(bytes: &[u8]) -> Option<CompactBlock> {
+        let mut cursor = bytes;
+        let header = take(&mut cursor, HEADER_SIZE as u64)?.try_into().ok()?;
+        let nonce = u64::from_le_bytes(take(&mut cursor, 8)?.try_into().unwrap());
+        let num_ids = take_varint(&mut cursor)?;
+        let short_ids = take(&mut cursor, num_ids.checked_mul(SHORT_ID_LEN as u64)?)?.chunks_exact(SHORT_ID_LEN)
+            .map(|id| {
+                let mut v = [0u8; 8];
+                v[..SHORT_ID_LEN].copy_from_slice(id);
+                u64::from_le_bytes(v)
+            })
+            .collect();
+        let num_prefilled = take_varint(&mut cursor)?;
+        let mut prefilled = Vec::new();
+        // the indexes are encoded as the difference from the previous index plus one
+        let mut next = 0u64;
+        for _ in 0..num_prefilled {
+            let index = next.checked_add(take_varint(&mut cursor)?)?;
+            let start = cursor;
+            tx_len(&mut cursor)?;
+            let tx = start[..start.len() - cursor.len()].to_vec();
+            prefilled.push((index, tx));
+            next = index.checked_add(1)?;
+        }
+        if !cursor.is_empty() {
+            return None;
+        }
+        Some(CompactBlock { header, nonce, short_ids, prefilled })
+    }
+
+    /// Encode the compact block as the header, the nonce, the short ids, and the prefilled
+    /// transactions, as in the cmpctblock message of BIP152.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.to_vec();
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        write_varint(&mut bytes, self.short_ids.len() as u64);
+        for id in self.short_ids.iter() {
+            bytes.extend_from_slice(&id.to_le_bytes()[..SHORT_ID_LEN]);
+        }
+        write_varint(&mut bytes, self.prefilled.len() as u64);
+        let mut next = 0;
+        for (index, tx) in self.prefilled.iter() {
+            write_varint(&mut bytes, index - next);
+            bytes.extend_from_slice(tx);
+            next = index + 1;
+        }
+        bytes
+    }
+
+    /// Get the hash of the block.
+    pub fn block_hash(&self) -> BlockHash {
+        BlockHash::sha256d(&self.header)
+    }
+
+    /// Get the short id of a transaction in this compact block.
+    pub fn short_id(&self, txid: &TxHash) -> u64 {
+        short_id(short_id_keys(&self.header, self.nonce), txid)
+    }
+
+    /// Rebuild the block from the prefilled transactions and the transactions of the provider.
+    ///
+    /// Fails with [Error::InvalidBlock] if the compact block is malformed, or if the rebuilt
+    /// block does not have the merkle root in the header, which happens when a short id matches
+    /// the wrong transaction.
+    pub fn reconstruct<P: TxProvider + ?Sized>(&self, provider: &P) -> Result<Reconstruction> {
+        let total = self.short_ids.len() + self.prefilled.len();
+        let mut txs: Vec<Option<Vec<u8>>> = vec![None; total];
+        for (index, tx) in self.prefilled.iter() {
+            match txs.get_mut(*index as usize) {
+                Some(slot) if slot.is_none() => *slot = Some(tx.clone()),
+                _ => return Err(Error::InvalidBlock(format!("bad prefilled index {} in compact block {}", index, self.block_hash()))),
+            }
+        }
+        // the transactions of the provider by short id, and the short ids that can not be
+        // resolved because they are in the compact block twice or match two transactions
+        let keys = short_id_keys(&self.header, self.nonce);
+        let mut found: BTreeMap<u64, Option<Vec<u8>>> = BTreeMap::new();
+        let mut ambiguous = BTreeSet::new();
+        for id in self.short_ids.iter() {
+            if found.insert(*id, None).is_some() {
+                ambiguous.insert(*id);
+            }
+        }
+        provider.for_each_tx(&mut |txid, tx| {
+            let id = short_id(keys, txid);
+            if let Some(slot) = found.get_mut(&id) {
+                if slot.is_some() {
+                    ambiguous.insert(id);
+                }
+                *slot = Some(tx.to_vec());
+            }
+        });
+        let mut ids = self.short_ids.iter();
+        let mut missing = Vec::new();
+        for (index, slot) in txs.iter_mut().enumerate() {
+            if slot.is_some() {
+                continue;
+            }
+            let id = ids.next().unwrap();
+            match found.get(id) {
+                Some(Some(tx)) if !ambiguous.contains(id) => *slot = Some(tx.clone()),
+                _ => missing.push(index as u64),
+            }
+        }
+        if !missing.is_empty() {
+            return Ok(Reconstruction::Missing(missing));
+        }
+        let txs: Vec<Vec<u8>> = txs.into_iter().map(|t| t.unwrap()).collect();
+        let merkle_root = BlockHash { hash: self.header[36..68].try_into().unwrap() };
+        let txids: Vec<TxHash> = txs.iter().map(|t| BlockHash::sha256d(t)).collect();
+        if compute_merkle_root(txids.iter().copied()) != Some(merkle_root)
+            || !validate_no_duplicate_vulnerability(txids.iter().copied()) {
+            return Err(Error::InvalidBlock(format!("merkle root mismatch for reconstructed block {}", self.block_hash())));
+        }
+        let mut block = self.header.to_vec();
+        write_varint(&mut block, total as u64);
+        for tx in txs {
+            block.extend_from_slice(&tx);
+        }
+        Ok(Reconstruction::Block(block))
+    }
+}
+
+/// Make a compact block of a block in the archive.
+pub async fn compact_block<A>(archive: &A, block_hash: &BlockHash, nonce: u64) -> Result<CompactBlock>
+    where A: BlockArchive + ?Sized
+{
+    let mut reader = archive.get_block(block_hash).await?;
+    CompactBlock::read(&mut reader, nonce).await
+}
+
+// The SipHash keys of the short ids, the first 16 bytes of the SHA256 of the header and nonce.
+fn short_id_keys(header: &[u8; 80], nonce: u64) -> (u64, u64) {
+    let mut data = header.to_vec();
+    data.extend_from_slice(&nonce.to_le_bytes());
+    let hash = digest(&SHA256, &data);
+    let hash = hash.as_ref();
+    (u64::from_le_bytes(hash[..8].try_into().unwrap()), u64::from_le_bytes(hash[8..16].try_into().unwrap()))
+}
+
+// The short id of a txid, the low 6 bytes of its SipHash-2-4.
+fn short_id(keys: (u64, u64), txid: &TxHash) -> u64 {
+    siphash24(keys.0, keys.1, &txid.hash) & 0xffff_ffff_ffff
+}
+
+// Take an encoded transaction from the front of the cursor.
+fn tx_len(cursor: &mut &[u8]) -> Option<()> {
+    take(cursor, 4)?;
+    for _ in 0..take_varint(cursor)? {
+        take(cursor, 36)?;
+        let script_len = take_varint(cursor)?;
+        take(cursor, script_len.checked_add(4)?)?;
+    }
+    for _ in 0..take_varint(cursor)? {
+        take(cursor, 8)?;
+        let script_len = take_varint(cursor)?;
+        take(cursor, script_len)?;
+    }
+    take(cursor, 4)?;
+    Some(())
+}
+
+// SipHash-2-4 of the data.
+fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    let mut chunks = data.chunks_exact(8);
+    for chunk in chunks.by_ref() {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+    // the last word holds the remaining bytes and the length
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    let m = u64::from_le_bytes(last) | ((data.len() as u64) << 56);
+    v[3] ^= m;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= m;
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13);
+    v[1] ^= v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16);
+    v[3] ^= v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21);
+    v[3] ^= v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17);
+    v[1] ^= v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use tokio::io::AsyncReadExt;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // The test vectors of the SipHash paper, with the key 00..0f.
+    #[test]
+    fn test_siphash24() {
+        let (k0, k1) = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        assert_eq!(siphash24(k0, k1, &[]), 0x726fdb47dd0e0e31);
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(k0, k1, &data), 0xa129ca6149be45e5);
+    }
+
+    // A block is rebuilt from its compact block and a mempool with its transactions. The test
+    // archive only has blocks with a coinbase, so the block is made from the genesis block with
+    // two more transactions that differ from the coinbase in their lock time.
+    #[tokio::test]
+    async fn test_compact_block() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let mut genesis_block = Vec::new();
+        src.get_block(&genesis).await.unwrap().read_to_end(&mut genesis_block).await.unwrap();
+        let coinbase = genesis_block[HEADER_SIZE + 1..].to_vec();
+        let mut txs = vec![coinbase.clone()];
+        for i in 1..3u8 {
+            let mut tx = coinbase.clone();
+            *tx.last_mut().unwrap() = i;
+            txs.push(tx);
+        }
+        let txids: Vec<TxHash> = txs.iter().map(|t| BlockHash::sha256d(t)).collect();
+        let mut block = genesis_block[..HEADER_SIZE].to_vec();
+        block[36..68].copy_from_slice(&compute_merkle_root(txids.iter().copied()).unwrap().hash);
+        block.push(txs.len() as u8);
+        for tx in txs.iter() {
+            block.extend_from_slice(tx);
+        }
+        let h = BlockHash::sha256d(&block[..HEADER_SIZE]);
+        let archive = MemoryBlockArchive::new();
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+
+        let compact = compact_block(&archive, &h, 42).await.unwrap();
+        assert_eq!(compact.block_hash(), h);
+        assert_eq!(compact.prefilled, vec![(0, coinbase)]);
+        assert_eq!(compact.short_ids, vec![compact.short_id(&txids[1]), compact.short_id(&txids[2])]);
+        assert_eq!(CompactBlock::from_bytes(&compact.to_bytes()), Some(compact.clone()));
+        // the short ids change with the nonce
+        assert_ne!(compact_block(&archive, &h, 43).await.unwrap().short_ids, compact.short_ids);
+
+        let mut mempool = BTreeMap::new();
+        for (txid, tx) in txids.iter().zip(txs.iter()).skip(1) {
+            mempool.insert(*txid, tx.clone());
+        }
+        assert_eq!(compact.reconstruct(&mempool).unwrap(), Reconstruction::Block(block.clone()));
+        mempool.remove(&txids[1]);
+        assert_eq!(compact.reconstruct(&mempool).unwrap(), Reconstruction::Missing(vec![1]));
+    }
+}
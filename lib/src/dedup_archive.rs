@@ -0,0 +1,475 @@
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use hex::{FromHex, ToHex};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::Mutex;
+use crate::block_archive::{check_block_checksum, checked_block, decode_block_attrs, encode_block_attrs, validate_block_attr, BlockHashListStream, BlockHashListStreamFromChannel, ChecksumReader};
+use crate::headers::HEADER_SIZE;
+use crate::merkle::write_varint;
+use crate::thin::ThinBlock;
+use crate::txindex::{read_bytes, read_tx, read_varint};
+use crate::{BlockArchive, BlockAttrs, Error, Result, WritePolicy};
+
+// the directory, relative to the root, of the database
+const DB_DIR: &str = "db";
+
+// the name of the tree which maps each txid to the encoded transaction
+const TXS_TREE: &str = "txs";
+
+// the name of the tree which maps each txid to the number of blocks that contain it
+const REFS_TREE: &str = "refs";
+
+// the name of the tree which holds the attributes of the blocks
+const ATTRS_TREE: &str = "attrs";
+
+// the length of the size and checksum at the start of an encoded manifest
+const MANIFEST_PREFIX_LEN: usize = 8 + 32;
+
+// the size of the channel used to send block hashes
+const LIST_BUFFER: usize = 1000;
+
+/// An experimental block archive that stores each transaction once, however many blocks contain
+/// it.
+///
+/// A fork block shares nearly all of its transactions with the competing block, so an archive
+/// that keeps the blocks of stale forks holds most transactions twice. This archive stores each
+/// transaction once, keyed by its txid, and each block as its header and the txids of its
+/// transactions. The block is put back together from its transactions by
+/// [BlockArchive::get_block], byte for byte as it was stored.
+///
+/// Everything is kept in a sled database in the "db" directory under the root. Each transaction
+/// has a count of the blocks that contain it, and is removed when the last of them is removed.
+/// A block must be exactly the header, the transactions, and nothing more, otherwise it is
+/// rejected with [Error::InvalidBlock].
+///
+/// Example code:
+///     let archive = DedupBlockArchive::new(PathBuf::from("/mnt/blockstore/forks")).await?;
+pub struct DedupBlockArchive {
+    root_path: PathBuf,
+    // maps the hex block hash to the encoded manifest of the block
+    db: sled::Db,
+    // maps the txid to the encoded transaction
+    txs: sled::Tree,
+    // maps the txid to the number of blocks that contain the transaction
+    refs: sled::Tree,
+    // maps the hex block hash to the encoded attributes
+    attrs: sled::Tree,
+    // held while blocks are stored and removed, so that the reference counts stay right
+    writer: Mutex<()>,
+    // the checks made on blocks before they are stored
+    write_policy: WritePolicy,
+    // whether store_block() replaces an existing block
+    overwrite: bool,
+}
+
+/// How much space a [DedupBlockArchive] saves, see [DedupBlockArchive::dedup_stats].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DedupStats {
+    /// The number of blocks.
+    pub blocks: u64,
+    /// The number of distinct transactions.
+    pub transactions: u64,
+    /// The total size of the blocks in bytes, as they would be stored in full.
+    pub block_bytes: u64,
+    /// The number of bytes stored for the transactions and the manifests of the blocks.
+    pub stored_bytes: u64,
+}
+
+// The size, checksum, header, and txids of a stored block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BlockManifest {
+    size: u64,
+    checksum: [u8; 32],
+    thin: ThinBlock,
+}
+
+impl DedupBlockArchive {
+    /// Open the archive in the given directory, creating it if necessary.
+    pub async fn new(root_path: PathBuf) -> Result<DedupBlockArchive> {
+        tokio::fs::create_dir_all(&root_path).await?;
+        let db = sled::open(root_path.join(DB_DIR)).map_err(dedup_error)?;
+        let txs = db.open_tree(TXS_TREE).map_err(dedup_error)?;
+        let refs = db.open_tree(REFS_TREE).map_err(dedup_error)?;
+        let attrs = db.open_tree(ATTRS_TREE).map_err(dedup_error)?;
+        Ok(DedupBlockArchive {
+            root_path,
+            db,
+            txs,
+            refs,
+            attrs,
+            writer: Mutex::new(()),
+            write_policy: WritePolicy::default(),
+            overwrite: false,
+        })
+    }
+
+    /// Set the checks that are made on blocks before they are stored, the default is to make no
+    /// checks beyond reading the transactions.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> DedupBlockArchive {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Set whether storing a block that is already in the archive replaces it, instead of
+    /// failing with [Error::BlockExists].
+    pub fn with_overwrite(mut self, overwrite: bool) -> DedupBlockArchive {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Get the root directory of the archive.
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Get the number of blocks in the archive.
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    /// Check whether the archive is empty.
+    pub fn is_empty(&self) -> bool {
+        self.db.is_empty()
+    }
+
+    /// Count the blocks and transactions and the space they take, which reads the whole database.
+    pub fn dedup_stats(&self) -> Result<DedupStats> {
+        let mut stats = DedupStats::default();
+        for r in self.db.iter() {
+            let (_, v) = r.map_err(dedup_error)?;
+            stats.blocks += 1;
+            stats.block_bytes += decode_manifest(&v)?.size;
+            stats.stored_bytes += v.len() as u64;
+        }
+        for r in self.txs.iter() {
+            let (_, v) = r.map_err(dedup_error)?;
+            stats.transactions += 1;
+            stats.stored_bytes += v.len() as u64;
+        }
+        Ok(stats)
+    }
+
+    /// Remove the transactions that are not in any block, returning the number removed.
+    ///
+    /// A block that fails part way through being stored can leave some of its transactions
+    /// behind.
+    pub async fn remove_unreferenced(&self) -> Result<usize> {
+        let _writer = self.writer.lock().await;
+        let mut removed = 0;
+        for key in self.txs.iter().keys() {
+            let key = key.map_err(dedup_error)?;
+            if !self.refs.contains_key(&key).map_err(dedup_error)? {
+                self.txs.remove(&key).map_err(dedup_error)?;
+                removed += 1;
+            }
+        }
+        self.db.flush_async().await.map_err(dedup_error)?;
+        Ok(removed)
+    }
+
+    // Get the manifest of a block.
+    fn manifest(&self, block_hash: &BlockHash) -> Result<BlockManifest> {
+        let key: String = block_hash.encode_hex();
+        match self.db.get(key).map_err(dedup_error)? {
+            Some(v) => decode_manifest(&v),
+            None => Err(Error::BlockNotFound),
+        }
+    }
+
+    // Read a block, storing each transaction that is not already stored, and get its manifest.
+    // The transactions are not referenced until the manifest is stored.
+    async fn store_transactions(&self, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<BlockManifest> {
+        let mut reader = ChecksumReader::new(block);
+        let mut buf = Vec::new();
+        read_bytes(&mut reader, &mut buf, HEADER_SIZE as u64).await?;
+        let header: [u8; 80] = buf[..].try_into().unwrap();
+        buf.clear();
+        let num_tx = read_varint(&mut reader, &mut buf).await?;
+        let mut size = (HEADER_SIZE + buf.len()) as u64;
+        let mut txids = Vec::new();
+        for _ in 0..num_tx {
+            buf.clear();
+            read_tx(&mut reader, &mut buf).await?;
+            size += buf.len() as u64;
+            let txid = BlockHash::sha256d(&buf);
+            if !self.txs.contains_key(txid.hash).map_err(dedup_error)? {
+                self.txs.insert(txid.hash, &buf[..]).map_err(dedup_error)?;
+            }
+            txids.push(txid);
+        }
+        // the block could not be put back together with anything after the transactions
+        if reader.read_u8().await.is_ok() {
+            return Err(Error::InvalidBlock("data after the last transaction".to_string()));
+        }
+        let checksum = <[u8; 32]>::from_hex(reader.checksum()).unwrap();
+        Ok(BlockManifest { size, checksum, thin: ThinBlock { header, txids } })
+    }
+
+    // Add a block to the reference count of each of its transactions.
+    fn add_refs(&self, manifest: &BlockManifest) -> Result<()> {
+        for txid in manifest.thin.txids.iter() {
+            self.refs.update_and_fetch(txid.hash, |v| Some((decode_count(v) + 1).to_le_bytes().to_vec()))
+                .map_err(dedup_error)?;
+        }
+        Ok(())
+    }
+
+    // Take a block from the reference count of each of its transactions, removing the
+    // transactions that are no longer in any block.
+    fn release_refs(&self, manifest: &BlockManifest) -> Result<()> {
+        for txid in manifest.thin.txids.iter() {
+            let count = self.refs.update_and_fetch(txid.hash, |v| match decode_count(v) {
+                0 | 1 => None,
+                n => Some((n - 1).to_le_bytes().to_vec()),
+            }).map_err(dedup_error)?;
+            if count.is_none() {
+                self.txs.remove(txid.hash).map_err(dedup_error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockArchive for DedupBlockArchive {
+    /// The block is put back together in memory from its transactions.
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let manifest = self.manifest(block_hash)?;
+        let mut block = Vec::with_capacity(manifest.size as usize);
+        block.extend_from_slice(&manifest.thin.header);
+        write_varint(&mut block, manifest.thin.txids.len() as u64);
+        for txid in manifest.thin.txids.iter() {
+            match self.txs.get(txid.hash).map_err(dedup_error)? {
+                Some(tx) => block.extend_from_slice(&tx),
+                None => return Err(Error::CorruptBlock(*block_hash)),
+            }
+        }
+        Ok(Box::new(std::io::Cursor::new(block)))
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        let key: String = block_hash.encode_hex();
+        self.db.contains_key(key).map_err(dedup_error)
+    }
+
+    /// The transactions are stored before the manifest of the block, and the transactions of a
+    /// replaced block are released after it.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let _writer = self.writer.lock().await;
+        let old = match self.manifest(block_hash) {
+            Ok(_) if !self.overwrite => return Err(Error::BlockExists),
+            Ok(m) => Some(m),
+            Err(Error::BlockNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        let mut block = checked_block(self.write_policy, block_hash, block).await?;
+        let manifest = self.store_transactions(&mut block).await?;
+        self.add_refs(&manifest)?;
+        let key: String = block_hash.encode_hex();
+        self.db.insert(key, encode_manifest(&manifest)).map_err(dedup_error)?;
+        if let Some(old) = old {
+            self.release_refs(&old)?;
+        }
+        self.db.flush_async().await.map_err(dedup_error)?;
+        Ok(())
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        let _writer = self.writer.lock().await;
+        let manifest = self.manifest(block_hash)?;
+        let key: String = block_hash.encode_hex();
+        self.attrs.remove(&key).map_err(dedup_error)?;
+        self.db.remove(&key).map_err(dedup_error)?;
+        self.release_refs(&manifest)?;
+        self.db.flush_async().await.map_err(dedup_error)?;
+        Ok(())
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        Ok(self.manifest(block_hash)?.size as usize)
+    }
+
+    /// The header is kept in the manifest, the transactions are not read.
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.manifest(block_hash)?.thin.block_header().await
+    }
+
+    /// The blocks are listed in order of their hex encoded hash.
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER);
+        let db = self.db.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            for key in db.iter().keys() {
+                let key = key.map_err(dedup_error)?;
+                let hash = BlockHash::from_hex(&key[..]).map_err(|_| Error::InvalidDedupStore("invalid block hash".to_string()))?;
+                if tx.blocking_send(hash).is_err() {
+                    return Ok(());      // this is not an error, the receiver has merely dropped
+                }
+            }
+            Ok(())
+        });
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        validate_block_attr(key, value)?;
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        attrs.insert(key.to_string(), value.to_string());
+        let hash: String = block_hash.encode_hex();
+        self.attrs.insert(hash, encode_block_attrs(&attrs).as_bytes()).map_err(dedup_error)?;
+        Ok(())
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        if attrs.remove(key).is_some() {
+            let hash: String = block_hash.encode_hex();
+            self.attrs.insert(hash, encode_block_attrs(&attrs).as_bytes()).map_err(dedup_error)?;
+        }
+        Ok(())
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        if !self.block_exists(block_hash).await? {
+            return Err(Error::BlockNotFound);
+        }
+        let hash: String = block_hash.encode_hex();
+        match self.attrs.get(hash).map_err(dedup_error)? {
+            Some(v) => decode_block_attrs(&String::from_utf8_lossy(&v)),
+            None => Ok(BlockAttrs::new()),
+        }
+    }
+
+    /// The checksum of every block is recorded in its manifest, so this is never false.
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        let manifest = self.manifest(block_hash)?;
+        check_block_checksum(self, block_hash, &hex::encode(manifest.checksum)).await?;
+        Ok(true)
+    }
+}
+
+// Encode a manifest as the size, the checksum, and the thin block.
+fn encode_manifest(manifest: &BlockManifest) -> Vec<u8> {
+    let mut v = Vec::with_capacity(MANIFEST_PREFIX_LEN + HEADER_SIZE + 9 + 32 * manifest.thin.txids.len());
+    v.extend_from_slice(&manifest.size.to_le_bytes());
+    v.extend_from_slice(&manifest.checksum);
+    v.extend_from_slice(&manifest.thin.to_bytes());
+    v
+}
+
+fn decode_manifest(v: &[u8]) -> Result<BlockManifest> {
+    let damaged = || Error::InvalidDedupStore("damaged block manifest".to_string());
+    if v.len() < MANIFEST_PREFIX_LEN {
+        return Err(damaged());
+    }
+    Ok(BlockManifest {
+        size: u64::from_le_bytes(v[0..8].try_into().unwrap()),
+        checksum: v[8..40].try_into().unwrap(),
+        thin: ThinBlock::from_bytes(&v[MANIFEST_PREFIX_LEN..]).ok_or_else(damaged)?,
+    })
+}
+
+// Decode a reference count, a missing or damaged count is zero.
+fn decode_count(v: Option<&[u8]>) -> u64 {
+    v.and_then(|v| v.try_into().ok()).map_or(0, u64::from_le_bytes)
+}
+
+fn dedup_error(e: sled::Error) -> Error {
+    Error::InvalidDedupStore(e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use tokio_stream::StreamExt;
+    use crate::sync::sync_archives;
+    use crate::{Manifest, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    #[test]
+    fn test_encode_manifest() {
+        let thin = ThinBlock { header: [1u8; 80], txids: vec![BlockHash { hash: [2u8; 32] }, BlockHash { hash: [3u8; 32] }] };
+        let manifest = BlockManifest { size: 285, checksum: [7u8; 32], thin };
+        assert_eq!(decode_manifest(&encode_manifest(&manifest)).unwrap(), manifest);
+        assert!(decode_manifest(&[0u8; 10]).is_err());
+        assert_eq!(decode_count(None), 0);
+        assert_eq!(decode_count(Some(&5u64.to_le_bytes())), 5);
+    }
+
+    // The blocks of the test archive come back unchanged.
+    #[tokio::test]
+    async fn test_dedup_archive() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let mut archive = DedupBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert!(archive.is_empty());
+        let summary = sync_archives(&mut src, &archive, |_, _| {}).await.unwrap();
+        assert_eq!(archive.len(), summary.copied);
+        let manifest = Manifest::generate(&mut src).await.unwrap();
+        assert!(manifest.verify(&archive).await.unwrap().is_ok());
+        let hashes: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        assert_eq!(hashes.len(), summary.copied);
+        let h = hashes[0];
+        assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+        assert_eq!(archive.block_size(&h).await.unwrap(), src.block_size(&h).await.unwrap());
+        assert!(archive.verify_checksum(&h).await.unwrap());
+        assert!(matches!(archive.store_block(&h, &mut &[0u8; 80][..]).await, Err(Error::BlockExists)));
+        archive.set_block_attr(&h, "source", "test").await.unwrap();
+        archive.remove_block(&h).await.unwrap();
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert!(matches!(archive.get_block_attrs(&h).await, Err(Error::BlockNotFound)));
+    }
+
+    // Two blocks that share a transaction store it once, and it stays until both are removed.
+    #[tokio::test]
+    async fn test_shared_transactions() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&genesis).await.unwrap().read_to_end(&mut block).await.unwrap();
+        // the genesis block has a single transaction after the header and a one byte count
+        let coinbase = block[HEADER_SIZE + 1..].to_vec();
+        let make_block = |nonce: u8, lock_time: u8| {
+            let mut header = block[..HEADER_SIZE].to_vec();
+            header[HEADER_SIZE - 1] = nonce;
+            let mut tx = coinbase.clone();
+            let n = tx.len();
+            tx[n - 4] = lock_time;
+            let mut b = header.clone();
+            write_varint(&mut b, 2);
+            b.extend_from_slice(&coinbase);
+            b.extend_from_slice(&tx);
+            (BlockHash::sha256d(&header), b)
+        };
+        let (a, block_a) = make_block(1, 1);
+        let (b, block_b) = make_block(2, 2);
+        let root = Temp::new_dir().unwrap();
+        let archive = DedupBlockArchive::new(root.to_path_buf()).await.unwrap();
+        archive.store_block(&a, &mut &block_a[..]).await.unwrap();
+        archive.store_block(&b, &mut &block_b[..]).await.unwrap();
+        let stats = archive.dedup_stats().unwrap();
+        assert_eq!((stats.blocks, stats.transactions), (2, 3));
+        assert_eq!(stats.block_bytes, (block_a.len() + block_b.len()) as u64);
+
+        archive.remove_block(&a).await.unwrap();
+        assert_eq!(archive.dedup_stats().unwrap().transactions, 2);
+        let mut read = Vec::new();
+        archive.get_block(&b).await.unwrap().read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, block_b);
+        archive.remove_block(&b).await.unwrap();
+        assert_eq!(archive.dedup_stats().unwrap(), DedupStats::default());
+
+        // anything after the transactions is rejected, and leaves nothing referenced
+        let mut trailing = block_a.clone();
+        trailing.push(0);
+        assert!(matches!(archive.store_block(&a, &mut &trailing[..]).await, Err(Error::InvalidBlock(_))));
+        assert!(!archive.block_exists(&a).await.unwrap());
+        assert_eq!(archive.remove_unreferenced().await.unwrap(), 2);
+        assert_eq!(archive.dedup_stats().unwrap(), DedupStats::default());
+    }
+}
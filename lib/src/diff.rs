@@ -0,0 +1,133 @@
+//! Comparing the blocks in two archives, for example to check a migration or a replica.
+use std::collections::BTreeSet;
+use bitcoinsv::bitcoin::BlockHash;
+use serde_json::{json, Value};
+use tokio_stream::StreamExt;
+use crate::manifest::sha256_reader;
+use crate::{BlockArchive, Result};
+
+/// The differences between two archives, see [diff_archives].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ArchiveDiff {
+    /// The number of blocks found in both archives with the same contents.
+    pub same: usize,
+    /// Blocks that are only in the first archive, in hash order.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub only_in_a: Vec<BlockHash>,
+    /// Blocks that are only in the second archive, in hash order.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub only_in_b: Vec<BlockHash>,
+    /// Blocks that are in both archives but whose size or checksum differs, in hash order.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub different: Vec<BlockHash>,
+}
+
+impl ArchiveDiff {
+    /// Returns true if the archives hold the same blocks.
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.different.is_empty()
+    }
+
+    /// Get the differences as a JSON object.
+    pub fn to_json(&self) -> Value {
+        let hashes = |v: &Vec<BlockHash>| v.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+        json!({
+            "same": self.same,
+            "only_in_a": hashes(&self.only_in_a),
+            "only_in_b": hashes(&self.only_in_b),
+            "different": hashes(&self.different),
+        })
+    }
+}
+
+/// Compare the blocks in two archives.
+///
+/// Blocks in both archives are compared by size, and if checksums is true by the SHA-256
+/// checksum of their contents, which reads every block in both archives in full. The archives
+/// can be of different types, and a compressed block matches the same block stored uncompressed.
+pub async fn diff_archives<A, B>(a: &mut A, b: &mut B, checksums: bool) -> Result<ArchiveDiff>
+    where A: BlockArchive + ?Sized, B: BlockArchive + ?Sized
+{
+    let blocks_a = list_blocks(a).await?;
+    let blocks_b = list_blocks(b).await?;
+    let mut diff = ArchiveDiff {
+        only_in_a: blocks_a.difference(&blocks_b).copied().collect(),
+        only_in_b: blocks_b.difference(&blocks_a).copied().collect(),
+        ..Default::default()
+    };
+    for block_hash in blocks_a.intersection(&blocks_b) {
+        if a.block_size(block_hash).await? != b.block_size(block_hash).await? {
+            diff.different.push(*block_hash);
+            continue;
+        }
+        if checksums {
+            let (checksum_a, _) = sha256_reader(&mut a.get_block(block_hash).await?).await?;
+            let (checksum_b, _) = sha256_reader(&mut b.get_block(block_hash).await?).await?;
+            if checksum_a != checksum_b {
+                diff.different.push(*block_hash);
+                continue;
+            }
+        }
+        diff.same += 1;
+    }
+    Ok(diff)
+}
+
+// Get the hashes of all the blocks in an archive.
+async fn list_blocks<A: BlockArchive + ?Sized>(archive: &mut A) -> Result<BTreeSet<BlockHash>> {
+    let mut blocks = BTreeSet::new();
+    let mut results = archive.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        blocks.insert(block_hash);
+    }
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    Ok(blocks)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use crate::sync::sync_archives;
+    use super::*;
+
+    // Compare a copy of the test archive with a block removed, a block added, and a block changed.
+    #[tokio::test]
+    async fn test_diff_archives() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let mut dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap().with_compression(true);
+        sync_archives(&mut src, &dst, |_, _| {}).await.unwrap();
+        let diff = diff_archives(&mut src, &mut dst, true).await.unwrap();
+        assert!(diff.is_empty());
+        assert_eq!(diff.same, 3);
+
+        let removed = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        dst.remove_block(&removed).await.unwrap();
+        let added = BlockHash::from_hex(format!("{:064x}", 1)).unwrap();
+        dst.store_block(&added, &mut &[0u8; 81][..]).await.unwrap();
+        // the same size but different contents
+        let changed = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        let mut block = Vec::new();
+        dst.get_block(&changed).await.unwrap().read_to_end(&mut block).await.unwrap();
+        *block.last_mut().unwrap() ^= 1;
+        dst.remove_block(&changed).await.unwrap();
+        dst.store_block(&changed, &mut &block[..]).await.unwrap();
+
+        let diff = diff_archives(&mut src, &mut dst, false).await.unwrap();
+        assert_eq!(diff.only_in_a, vec![removed]);
+        assert_eq!(diff.only_in_b, vec![added]);
+        assert!(diff.different.is_empty());
+        let diff = diff_archives(&mut src, &mut dst, true).await.unwrap();
+        assert_eq!(diff.different, vec![changed]);
+        assert_eq!(diff.same, 1);
+        assert_eq!(diff.to_json()["only_in_b"][0], added.to_string());
+    }
+}
@@ -0,0 +1,446 @@
+//! Encryption of the blocks in an archive.
+//!
+//! An [EncryptedBlockArchive] encrypts blocks before they are stored in the wrapped archive and
+//! decrypts them when they are read, so that the storage only ever holds ciphertext, for example
+//! in an object store that is shared with others. The hashes of the blocks are not encrypted,
+//! because they are the keys that blocks are stored under, and neither are block attributes.
+//!
+//! Blocks are encrypted in segments of 64KB with AES-256-GCM or ChaCha20-Poly1305, so that they
+//! are streamed rather than held in memory. Each segment is authenticated together with the hash
+//! of the block and its position in the block, so a block that has been damaged, truncated, or
+//! stored under another hash fails to decrypt, and reading it fails with an error of kind
+//! [std::io::ErrorKind::InvalidData].
+//!
+//! The key is 32 bytes, hex encoded in a file or an environment variable. A key held by a key
+//! management service can be fetched by the application and given to [EncryptionKey::new]. Each
+//! block gets a random nonce, so a key should not be used for more than about 2^28 blocks.
+//!
+//! Example code:
+//!     let key = EncryptionKey::from_file(Cipher::Aes256Gcm, Path::new("archive.key")).await?;
+//!     let archive = EncryptedBlockArchive::new(archive, key);
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use hex::FromHex;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use crate::block_archive::{checked_block, BlockHashListStream};
+use crate::headers::HEADER_SIZE;
+use crate::{BlockArchive, BlockAttrs, Error, ListOptions, Result, WritePolicy};
+
+// the length of a key
+const KEY_LEN: usize = 32;
+
+// the start of every encrypted block
+const MAGIC: &[u8; 4] = b"BAEC";
+
+// the random part of the nonces of a block, the rest is the segment number and the last flag
+const PREFIX_LEN: usize = 7;
+
+// the magic, the cipher, and the nonce prefix
+const ENCRYPTED_HEADER_LEN: usize = MAGIC.len() + 1 + PREFIX_LEN;
+
+// the size of the plaintext of every segment but the last
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+// the length of the authentication tag added to each segment
+const TAG_LEN: usize = 16;
+
+/// The cipher used to encrypt blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES-256 in GCM mode, which is fast on processors with AES instructions.
+    #[default]
+    Aes256Gcm,
+    /// ChaCha20 with Poly1305, which is fast on processors without AES instructions.
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    // The id of the cipher in the header of an encrypted block.
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Cipher> {
+        match id {
+            1 => Some(Cipher::Aes256Gcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// A key for an [EncryptedBlockArchive], with the cipher that new blocks are encrypted with.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: Cipher,
+    key: [u8; KEY_LEN],
+}
+
+impl EncryptionKey {
+    /// Create a key from its bytes.
+    pub fn new(cipher: Cipher, key: [u8; KEY_LEN]) -> EncryptionKey {
+        EncryptionKey { cipher, key }
+    }
+
+    /// Create a key from 64 hex characters, surrounding whitespace is ignored.
+    pub fn from_hex(cipher: Cipher, s: &str) -> Result<EncryptionKey> {
+        let key = <[u8; KEY_LEN]>::from_hex(s.trim())
+            .map_err(|_| Error::InvalidKey(format!("a key must be {} hex characters", KEY_LEN * 2)))?;
+        Ok(EncryptionKey::new(cipher, key))
+    }
+
+    /// Read a hex encoded key from a file.
+    pub async fn from_file(cipher: Cipher, path: &Path) -> Result<EncryptionKey> {
+        let s = tokio::fs::read_to_string(path).await
+            .map_err(|e| Error::InvalidKey(format!("could not read {}: {}", path.display(), e)))?;
+        EncryptionKey::from_hex(cipher, &s)
+    }
+
+    /// Read a hex encoded key from an environment variable.
+    pub fn from_env(cipher: Cipher, name: &str) -> Result<EncryptionKey> {
+        let s = std::env::var(name).map_err(|_| Error::InvalidKey(format!("environment variable {} is not set", name)))?;
+        EncryptionKey::from_hex(cipher, &s)
+    }
+
+    /// Get the cipher that new blocks are encrypted with.
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+}
+
+// the key is not shown
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey").field("cipher", &self.cipher).finish_non_exhaustive()
+    }
+}
+
+// The key for each cipher, so that a block can be read whichever cipher it was encrypted with.
+struct Keys {
+    aes: LessSafeKey,
+    chacha: LessSafeKey,
+}
+
+impl Keys {
+    fn new(key: &[u8; KEY_LEN]) -> Keys {
+        Keys {
+            aes: LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("AES-256 key is 32 bytes")),
+            chacha: LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("ChaCha20 key is 32 bytes")),
+        }
+    }
+
+    fn get(&self, cipher: Cipher) -> &LessSafeKey {
+        match cipher {
+            Cipher::Aes256Gcm => &self.aes,
+            Cipher::ChaCha20Poly1305 => &self.chacha,
+        }
+    }
+}
+
+/// A block archive that encrypts the blocks stored in the wrapped archive.
+///
+/// The wrapped archive only sees ciphertext, so its write policy must be [WritePolicy::None].
+/// Set the write policy of this archive instead, which checks blocks before they are encrypted.
+pub struct EncryptedBlockArchive<A: BlockArchive> {
+    archive: A,
+    cipher: Cipher,
+    keys: Arc<Keys>,
+    rng: SystemRandom,
+    write_policy: WritePolicy,
+}
+
+impl<A: BlockArchive> EncryptedBlockArchive<A> {
+    /// Wrap an archive, encrypting new blocks with the key.
+    pub fn new(archive: A, key: EncryptionKey) -> EncryptedBlockArchive<A> {
+        EncryptedBlockArchive {
+            archive,
+            cipher: key.cipher,
+            keys: Arc::new(Keys::new(&key.key)),
+            rng: SystemRandom::new(),
+            write_policy: WritePolicy::None,
+        }
+    }
+
+    /// Set the checks made on blocks before they are stored. The default is [WritePolicy::None].
+    pub fn with_write_policy(mut self, write_policy: WritePolicy) -> EncryptedBlockArchive<A> {
+        self.write_policy = write_policy;
+        self
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for EncryptedBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let reader = self.archive.get_block(block_hash).await?;
+        Ok(Box::new(CipherReader::decrypt(reader, self.keys.clone(), block_hash)))
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.block_exists(block_hash).await
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let block = checked_block(self.write_policy, block_hash, block).await?;
+        let mut prefix = [0u8; PREFIX_LEN];
+        self.rng.fill(&mut prefix).map_err(|_| std::io::Error::other("could not generate a nonce"))?;
+        let mut reader = CipherReader::encrypt(block, self.keys.clone(), self.cipher, prefix, block_hash);
+        self.archive.store_block(block_hash, &mut reader).await
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.archive.remove_block(block_hash).await
+    }
+
+    /// The size of the block before it was encrypted, which is calculated from the size of the
+    /// encrypted block.
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        let size = self.archive.block_size(block_hash).await?;
+        let segments_len = size.checked_sub(ENCRYPTED_HEADER_LEN).ok_or(Error::CorruptBlock(*block_hash))?;
+        // every segment but the last is full, and the last may be just a tag
+        let segments = segments_len / (SEGMENT_SIZE + TAG_LEN) + 1;
+        segments_len.checked_sub(segments * TAG_LEN).ok_or(Error::CorruptBlock(*block_hash))
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.get_block(block_hash).await?.read_exact(&mut header).await?;
+        Ok(BlockHeader::from_binary(&mut &header[..]).await?)
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.archive.block_list().await
+    }
+
+    async fn block_list_opts(&mut self, options: ListOptions) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.archive.block_list_opts(options).await
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.archive.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.archive.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.archive.get_block_attrs(block_hash).await
+    }
+
+    /// Checks the encrypted block against the checksum recorded by the wrapped archive. The
+    /// authentication of each segment is checked whenever the block is read.
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.verify_checksum(block_hash).await
+    }
+}
+
+// Encrypts or decrypts a block as it is read, a segment at a time.
+//
+// An encrypted block is the magic, the id of the cipher, and the nonce prefix, followed by the
+// encrypted segments. Every segment but the last holds SEGMENT_SIZE bytes of the block, the last
+// holds the rest, which may be nothing. The nonce of a segment is the prefix, the number of the
+// segment, and a flag that is set for the last segment.
+struct CipherReader<R> {
+    inner: R,
+    keys: Arc<Keys>,
+    encrypt: bool,
+    block_hash: BlockHash,
+    // the cipher and nonce prefix of the block, None until the header of an encrypted block has
+    // been read
+    cipher: Option<(Cipher, [u8; PREFIX_LEN])>,
+    segment: u32,
+    // the input for the next segment, and how much of it has been read
+    input: Vec<u8>,
+    filled: usize,
+    // the output of the last segment, and how much of it has been returned
+    output: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> CipherReader<R> {
+    fn encrypt(inner: R, keys: Arc<Keys>, cipher: Cipher, prefix: [u8; PREFIX_LEN], block_hash: &BlockHash) -> CipherReader<R> {
+        let mut header = MAGIC.to_vec();
+        header.push(cipher.id());
+        header.extend_from_slice(&prefix);
+        CipherReader {
+            inner, keys, encrypt: true, block_hash: *block_hash, cipher: Some((cipher, prefix)), segment: 0,
+            input: Vec::new(), filled: 0, output: header, pos: 0, done: false,
+        }
+    }
+
+    fn decrypt(inner: R, keys: Arc<Keys>, block_hash: &BlockHash) -> CipherReader<R> {
+        CipherReader {
+            inner, keys, encrypt: false, block_hash: *block_hash, cipher: None, segment: 0,
+            input: Vec::new(), filled: 0, output: Vec::new(), pos: 0, done: false,
+        }
+    }
+
+    // The size of the next input.
+    fn input_size(&self) -> usize {
+        match (self.encrypt, self.cipher) {
+            (true, _) => SEGMENT_SIZE,
+            (false, None) => ENCRYPTED_HEADER_LEN,
+            (false, Some(_)) => SEGMENT_SIZE + TAG_LEN,
+        }
+    }
+
+    // Encrypt or decrypt the input, which is the last if the end of the inner reader was reached.
+    fn process(&mut self, last: bool) -> std::io::Result<()> {
+        let mut data = std::mem::replace(&mut self.input, std::mem::take(&mut self.output));
+        data.truncate(self.filled);
+        self.filled = 0;
+        self.pos = 0;
+        let (cipher, prefix) = match self.cipher {
+            Some(c) => c,
+            None => {
+                // the header of an encrypted block
+                if last || &data[..MAGIC.len()] != MAGIC {
+                    return Err(self.invalid("not an encrypted block"));
+                }
+                let cipher = Cipher::from_id(data[MAGIC.len()]).ok_or_else(|| self.invalid("unknown cipher"))?;
+                self.cipher = Some((cipher, data[MAGIC.len() + 1..].try_into().unwrap()));
+                return Ok(());
+            }
+        };
+        let mut nonce = [0u8; 12];
+        nonce[..PREFIX_LEN].copy_from_slice(&prefix);
+        nonce[PREFIX_LEN..PREFIX_LEN + 4].copy_from_slice(&self.segment.to_be_bytes());
+        nonce[PREFIX_LEN + 4] = last as u8;
+        let nonce = Nonce::assume_unique_for_key(nonce);
+        let aad = Aad::from(self.block_hash.hash);
+        let key = self.keys.get(cipher);
+        if self.encrypt {
+            key.seal_in_place_append_tag(nonce, aad, &mut data).map_err(|_| self.invalid("encryption failed"))?;
+        } else {
+            if data.len() < TAG_LEN {
+                return Err(self.invalid("the encrypted block is truncated"));
+            }
+            let len = key.open_in_place(nonce, aad, &mut data)
+                .map_err(|_| self.invalid("the block could not be decrypted, it is damaged or the key is wrong"))?.len();
+            data.truncate(len);
+        }
+        self.output = data;
+        self.segment = self.segment.checked_add(1).ok_or_else(|| self.invalid("too many segments"))?;
+        self.done = last;
+        Ok(())
+    }
+
+    fn invalid(&self, msg: &str) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("block {}: {}", self.block_hash, msg))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CipherReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos < this.output.len() {
+                let n = buf.remaining().min(this.output.len() - this.pos);
+                buf.put_slice(&this.output[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            let size = this.input_size();
+            this.input.resize(size, 0);
+            while this.filled < size {
+                let mut input = ReadBuf::new(&mut this.input[this.filled..]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut input))?;
+                let n = input.filled().len();
+                if n == 0 {
+                    break;
+                }
+                this.filled += n;
+            }
+            // a short segment is the last
+            let last = this.filled < size;
+            this.process(last)?;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    async fn read_block<A: BlockArchive>(archive: &A, block_hash: &BlockHash) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        archive.get_block(block_hash).await.unwrap().read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    // Blocks are stored encrypted and read back, including blocks that end on a segment boundary.
+    #[tokio::test]
+    async fn test_encrypted_archive() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let block = read_block(&src, &h).await.unwrap();
+        let key = EncryptionKey::from_hex(Cipher::Aes256Gcm, KEY).unwrap();
+        let archive = EncryptedBlockArchive::new(MemoryBlockArchive::new(), key).with_write_policy(WritePolicy::HeaderOnly);
+
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        let encrypted = read_block(archive.archive(), &h).await.unwrap();
+        assert!(encrypted.starts_with(MAGIC));
+        assert!(!encrypted.windows(HEADER_SIZE).any(|w| w == &block[..HEADER_SIZE]));
+        assert_eq!(read_block(&archive, &h).await.unwrap(), block);
+        assert_eq!(archive.block_size(&h).await.unwrap(), block.len());
+        assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+
+        for len in [0, 1, SEGMENT_SIZE, 2 * SEGMENT_SIZE + 1] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let h = BlockHash::from_hex(format!("{:064x}", len + 1)).unwrap();
+            let archive = EncryptedBlockArchive::new(MemoryBlockArchive::new(), EncryptionKey::from_hex(Cipher::ChaCha20Poly1305, KEY).unwrap());
+            archive.store_block(&h, &mut &data[..]).await.unwrap();
+            assert_eq!(read_block(&archive, &h).await.unwrap(), data);
+            assert_eq!(archive.block_size(&h).await.unwrap(), len);
+        }
+    }
+
+    // A block fails to decrypt with the wrong key, under another hash, or when truncated.
+    #[tokio::test]
+    async fn test_decryption_fails() {
+        let h = BlockHash::from_hex(format!("{:064x}", 1)).unwrap();
+        let h2 = BlockHash::from_hex(format!("{:064x}", 2)).unwrap();
+        let data = vec![7u8; SEGMENT_SIZE + 100];
+        let archive = EncryptedBlockArchive::new(MemoryBlockArchive::new(), EncryptionKey::from_hex(Cipher::Aes256Gcm, KEY).unwrap());
+        archive.store_block(&h, &mut &data[..]).await.unwrap();
+        let encrypted = read_block(archive.archive(), &h).await.unwrap();
+
+        let other = EncryptedBlockArchive::new(MemoryBlockArchive::new(), EncryptionKey::new(Cipher::Aes256Gcm, [9u8; KEY_LEN]));
+        other.archive().store_block(&h, &mut &encrypted[..]).await.unwrap();
+        assert!(read_block(&other, &h).await.is_err());
+
+        archive.archive().store_block(&h2, &mut &encrypted[..]).await.unwrap();
+        assert!(read_block(&archive, &h2).await.is_err());
+
+        let h3 = BlockHash::from_hex(format!("{:064x}", 3)).unwrap();
+        let truncated = &encrypted[..ENCRYPTED_HEADER_LEN + SEGMENT_SIZE + TAG_LEN];
+        archive.archive().store_block(&h3, &mut &truncated[..]).await.unwrap();
+        assert!(read_block(&archive, &h3).await.is_err());
+
+        assert!(EncryptionKey::from_hex(Cipher::Aes256Gcm, "00ff").is_err());
+        assert!(!format!("{:?}", EncryptionKey::new(Cipher::Aes256Gcm, [9u8; KEY_LEN])).contains('9'));
+    }
+}
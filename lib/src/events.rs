@@ -0,0 +1,273 @@
+//! Notifications of changes to an archive.
+//!
+//! Wrap an archive in a [NotifyingBlockArchive] to be told when blocks are stored or removed, and
+//! when an operation finds a damaged block, for example to index new blocks or to raise an alert.
+//! Any type of archive can be wrapped. Listeners are called synchronously after the operation
+//! completes, so a listener that has slow work to do should hand it to another task, for example
+//! through a channel.
+//!
+//! An archive made [NotifyingBlockArchive::with_details] also reads the height, size and number
+//! of transactions of each block it stores, and sends them with the event, for listeners that
+//! pass the event on to other services.
+//!
+//! Example code:
+//!     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+//!     let archive = NotifyingBlockArchive::new(archive).with_listener(move |e: &BlockEvent| { let _ = tx.send(e.clone()); });
+use std::pin::Pin;
+use std::sync::Arc;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use serde_json::{json, Value};
+use tokio::io::AsyncRead;
+use crate::block_archive::BlockHashListStream;
+use crate::coinbase::read_coinbase;
+use crate::{BlockArchive, BlockAttrs, Error, ListOptions, Result};
+
+/// A change to an archive, or a problem found in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum BlockEvent {
+    /// A block was stored, with its details if the archive reads them.
+    BlockStored(#[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))] BlockHash, Option<BlockDetails>),
+    /// A block was removed.
+    BlockDeleted(#[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))] BlockHash),
+    /// A stored block was found to be damaged, such as a block that does not match its checksum.
+    CorruptionDetected(#[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))] BlockHash),
+}
+
+impl BlockEvent {
+    /// Get the block that the event is about.
+    pub fn block_hash(&self) -> &BlockHash {
+        match self {
+            BlockEvent::BlockStored(h, _) | BlockEvent::BlockDeleted(h) | BlockEvent::CorruptionDetected(h) => h,
+        }
+    }
+
+    /// Get a short name of the event, for scripts.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockEvent::BlockStored(..) => "block_stored",
+            BlockEvent::BlockDeleted(_) => "block_deleted",
+            BlockEvent::CorruptionDetected(_) => "corruption_detected",
+        }
+    }
+
+    /// Encode the event as JSON.
+    pub fn to_json(&self) -> Value {
+        let mut v = json!({"event": self.name(), "block_hash": self.block_hash().to_string()});
+        if let BlockEvent::BlockStored(_, Some(details)) = self {
+            v["height"] = json!(details.height);
+            v["size"] = json!(details.size);
+            v["tx_count"] = json!(details.tx_count);
+        }
+        v
+    }
+}
+
+/// The details of a stored block that are sent with its [BlockEvent::BlockStored] event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockDetails {
+    /// The height given in the coinbase of the block, None for blocks from before BIP34.
+    pub height: Option<u64>,
+    /// The size of the block in bytes.
+    pub size: u64,
+    /// The number of transactions in the block.
+    pub tx_count: u64,
+}
+
+impl BlockDetails {
+    /// Read the details of a block in an archive, which reads the start of the block, see
+    /// [BlockArchive::block_summary].
+    pub async fn read<A: BlockArchive + ?Sized>(archive: &A, block_hash: &BlockHash) -> Result<BlockDetails> {
+        let summary = archive.block_summary(block_hash).await?;
+        let height = read_coinbase(archive, block_hash).await?.script_height();
+        Ok(BlockDetails { height, size: summary.size, tx_count: summary.tx_count })
+    }
+}
+
+/// Receives the events of a [NotifyingBlockArchive].
+///
+/// Any `Fn(&BlockEvent)` closure can be used as a listener.
+pub trait BlockArchiveEvents: Send + Sync {
+    /// Called after each event.
+    fn on_event(&self, event: &BlockEvent);
+}
+
+impl<F: Fn(&BlockEvent) + Send + Sync> BlockArchiveEvents for F {
+    fn on_event(&self, event: &BlockEvent) {
+        self(event)
+    }
+}
+
+/// A block archive that tells its listeners about the blocks stored in and removed from the
+/// wrapped archive, and about the damaged blocks that its operations find.
+pub struct NotifyingBlockArchive<A: BlockArchive> {
+    archive: A,
+    listeners: Vec<Arc<dyn BlockArchiveEvents>>,
+    // true if the details of stored blocks are read for their events
+    details: bool,
+}
+
+impl<A: BlockArchive> NotifyingBlockArchive<A> {
+    /// Wrap an archive, without any listeners.
+    pub fn new(archive: A) -> NotifyingBlockArchive<A> {
+        NotifyingBlockArchive { archive, listeners: Vec::new(), details: false }
+    }
+
+    /// Add a listener.
+    pub fn with_listener<L: BlockArchiveEvents + 'static>(mut self, listener: L) -> NotifyingBlockArchive<A> {
+        self.listeners.push(Arc::new(listener));
+        self
+    }
+
+    /// Read the [BlockDetails] of each block that is stored and send them with its event. The
+    /// block is read again after it has been stored, and if that fails the event is sent
+    /// without the details.
+    pub fn with_details(mut self) -> NotifyingBlockArchive<A> {
+        self.details = true;
+        self
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+
+    fn notify(&self, event: BlockEvent) {
+        for listener in self.listeners.iter() {
+            listener.on_event(&event);
+        }
+    }
+
+    // Notify the listeners if the result of an operation shows a damaged block.
+    fn check<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(Error::CorruptBlock(h) | Error::ChecksumMismatch(h)) = &result {
+            self.notify(BlockEvent::CorruptionDetected(*h));
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for NotifyingBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.check(self.archive.get_block(block_hash).await)
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.check(self.archive.get_block_range(block_hash, offset, length).await)
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.block_exists(block_hash).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        self.archive.blocks_exist(block_hashes).await
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        self.archive.store_block(block_hash, block).await?;
+        let details = if self.details {
+            BlockDetails::read(&self.archive, block_hash).await.ok()
+        } else {
+            None
+        };
+        self.notify(BlockEvent::BlockStored(*block_hash, details));
+        Ok(())
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.archive.remove_block(block_hash).await?;
+        self.notify(BlockEvent::BlockDeleted(*block_hash));
+        Ok(())
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.check(self.archive.block_size(block_hash).await)
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.check(self.archive.block_header(block_hash).await)
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        self.check(self.archive.block_headers(block_hashes).await)
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.archive.block_list().await
+    }
+
+    async fn block_list_opts(&mut self, options: ListOptions) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.archive.block_list_opts(options).await
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.archive.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.archive.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.archive.get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.check(self.archive.verify_checksum(block_hash).await)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use hex::FromHex;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Storing and removing a block are reported, as is a block that no longer matches its
+    // checksum. Failed operations are not reported. A stored block is reported with its details.
+    #[tokio::test]
+    async fn test_events() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let received = events.clone();
+        let archive = NotifyingBlockArchive::new(SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap())
+            .with_details()
+            .with_listener(move |e: &BlockEvent| received.lock().unwrap().push(e.clone()));
+
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        assert!(archive.store_block(&h, &mut &block[..]).await.is_err());
+        assert!(archive.verify_checksum(&h).await.unwrap());
+        let path = archive.archive().get_path_from_hash(&h);
+        let mut damaged = block.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        tokio::fs::write(&path, &damaged).await.unwrap();
+        assert!(archive.verify_checksum(&h).await.is_err());
+        archive.remove_block(&h).await.unwrap();
+        assert!(archive.remove_block(&h).await.is_err());
+
+        let details = BlockDetails::read(&src, &h).await.unwrap();
+        assert_eq!(details.size, block.len() as u64);
+        assert!(details.tx_count > 0);
+        assert_eq!(*events.lock().unwrap(), vec![
+            BlockEvent::BlockStored(h, Some(details)), BlockEvent::CorruptionDetected(h), BlockEvent::BlockDeleted(h),
+        ]);
+        let json = BlockEvent::BlockStored(h, Some(details)).to_json();
+        assert_eq!(json["event"], "block_stored");
+        assert_eq!(json["tx_count"], details.tx_count);
+        assert!(BlockEvent::BlockStored(h, None).to_json().get("size").is_none());
+    }
+}
@@ -0,0 +1,520 @@
+//! Fetching blocks from an SV Node over the peer-to-peer protocol.
+//!
+//! This is a minimal implementation of the protocol, enough to request blocks by hash and to sync
+//! headers. It is used to fill gaps in an archive, for example the missing parents reported by
+//! [missing_parents], and to fill a [HeaderArchive] with [fetch_headers].
+//!
+//! Example code:
+//!     let mut fetcher = P2PFetcher::connect("127.0.0.1:8333", Network::Mainnet).await?;
+//!     let summary = fetch_missing(&mut archive, &mut fetcher).await?;
+use std::collections::BTreeSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bitcoinsv::bitcoin::BlockHash;
+use hex::FromHex;
+use tracing::{debug, info};
+use ring::digest::{digest, SHA256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_stream::StreamExt;
+use crate::merkle::write_varint;
+use crate::txindex::{take, take_varint};
+use crate::{BlockArchive, Error, HeaderArchive, Network, Result};
+
+// the protocol version that we claim to speak
+pub(crate) const PROTOCOL_VERSION: i32 = 70015;
+
+// the user agent sent to the peer
+const USER_AGENT: &str = concat!("/bsv-blockarchive:", env!("CARGO_PKG_VERSION"), "/");
+
+// the inventory type for a block
+pub(crate) const MSG_BLOCK: u32 = 2;
+
+// the size of a message header
+pub(crate) const MESSAGE_HEADER_SIZE: usize = 24;
+
+// the largest message, other than a block, that will be read into memory
+pub(crate) const MAX_MESSAGE_SIZE: u32 = 32 * 1024 * 1024;
+
+// the size of an encoded block header
+const BLOCK_HEADER_SIZE: u32 = 80;
+
+// the most headers that a peer sends in reply to a getheaders message
+pub(crate) const MAX_HEADERS: usize = 2000;
+
+/// A summary of [fetch_missing].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FetchSummary {
+    /// The number of blocks fetched and stored.
+    pub fetched: usize,
+    /// Blocks that the peer did not have.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub not_found: Vec<BlockHash>,
+}
+
+/// A connection to an SV Node, used to fetch blocks.
+pub struct P2PFetcher {
+    stream: BufReader<TcpStream>,
+    network: Network,
+}
+
+impl P2PFetcher {
+    /// Connect to a node and perform the version handshake.
+    pub async fn connect<A: ToSocketAddrs>(addr: A, network: Network) -> Result<P2PFetcher> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut fetcher = P2PFetcher { stream: BufReader::new(stream), network };
+        fetcher.handshake().await?;
+        Ok(fetcher)
+    }
+
+    // Exchange version and verack messages with the peer.
+    async fn handshake(&mut self) -> Result<()> {
+        // services, we provide none, and start height, we have no chain to offer
+        self.send("version", &version_payload(0, 0)).await?;
+        let mut got_version = false;
+        let mut got_verack = false;
+        while !(got_version && got_verack) {
+            let (command, length) = self.read_header().await?;
+            let payload = self.read_payload(length).await?;
+            match command.as_str() {
+                "version" => {
+                    got_version = true;
+                    self.send("verack", &[]).await?;
+                }
+                "verack" => got_verack = true,
+                "ping" => self.send("pong", &payload).await?,
+                _ => debug!(%command, "ignoring message during handshake"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch a block from the peer and store it in the archive.
+    ///
+    /// The block is streamed from the peer into the archive. The hash of the block header is
+    /// checked, but the transactions are not, use a consistency check for that.
+    ///
+    /// Returns false if the peer does not have the block.
+    pub async fn fetch_block<A: BlockArchive + ?Sized>(&mut self, block_hash: &BlockHash, archive: &A) -> Result<bool> {
+        let (header, remaining) = match self.request_block(block_hash).await? {
+            Some(r) => r,
+            None => return Ok(false),
+        };
+        let mut body = (&mut self.stream).take(remaining);
+        let r = {
+            let mut block = (&header[..]).chain(&mut body);
+            archive.store_block(block_hash, &mut block).await
+        };
+        // keep the connection usable even if the block could not be stored
+        let unread = body.limit();
+        self.skip(unread).await?;
+        r?;
+        Ok(true)
+    }
+
+    /// Fetch a block from the peer into memory.
+    ///
+    /// The hash of the block header is checked, but the transactions are not.
+    ///
+    /// Returns None if the peer does not have the block.
+    pub async fn fetch_block_data(&mut self, block_hash: &BlockHash) -> Result<Option<Vec<u8>>> {
+        let (header, remaining) = match self.request_block(block_hash).await? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let mut data = header.to_vec();
+        (&mut self.stream).take(remaining).read_to_end(&mut data).await?;
+        if (data.len() as u64) < BLOCK_HEADER_SIZE as u64 + remaining {
+            return Err(Error::PeerError("block message truncated".to_string()));
+        }
+        Ok(Some(data))
+    }
+
+    /// Fetch the header of a block, by fetching the block and discarding the rest of it.
+    ///
+    /// Returns None if the peer does not have the block.
+    pub async fn fetch_header(&mut self, block_hash: &BlockHash) -> Result<Option<[u8; 80]>> {
+        match self.request_block(block_hash).await? {
+            Some((header, remaining)) => {
+                self.skip(remaining).await?;
+                Ok(Some(header))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get the headers that follow the first block in the locator that the peer has in its best
+    /// chain, at most 2000 of them, with a getheaders message.
+    ///
+    /// The locator lists block hashes from the highest, the peer starts after its genesis block if
+    /// it has none of them.
+    pub async fn get_headers(&mut self, locator: &[BlockHash]) -> Result<Vec<[u8; 80]>> {
+        let mut getheaders = PROTOCOL_VERSION.to_le_bytes().to_vec();
+        write_varint(&mut getheaders, locator.len() as u64);
+        for block_hash in locator {
+            getheaders.extend_from_slice(&block_hash.hash);
+        }
+        // no stop hash, get as many headers as the peer will send
+        getheaders.extend_from_slice(&[0u8; 32]);
+        self.send("getheaders", &getheaders).await?;
+        loop {
+            let (command, length) = self.read_header().await?;
+            match command.as_str() {
+                "headers" => {
+                    let payload = self.read_payload(length).await?;
+                    return decode_headers(&payload).ok_or_else(|| Error::PeerError("invalid headers message".to_string()));
+                }
+                "ping" => {
+                    let payload = self.read_payload(length).await?;
+                    self.send("pong", &payload).await?;
+                }
+                _ => {
+                    debug!(%command, "ignoring message");
+                    self.skip(length as u64).await?;
+                }
+            }
+        }
+    }
+
+    // Request a block and read its header, returning the header and the length of the rest of the
+    // block, which is the next thing to be read from the stream. Returns None if the peer does not
+    // have the block.
+    async fn request_block(&mut self, block_hash: &BlockHash) -> Result<Option<([u8; 80], u64)>> {
+        let mut getdata = vec![1u8];
+        getdata.extend_from_slice(&MSG_BLOCK.to_le_bytes());
+        getdata.extend_from_slice(&block_hash.hash);
+        self.send("getdata", &getdata).await?;
+        loop {
+            let (command, length) = self.read_header().await?;
+            match command.as_str() {
+                "block" => {
+                    if length < BLOCK_HEADER_SIZE {
+                        return Err(Error::PeerError("block message too short".to_string()));
+                    }
+                    let mut header = [0u8; BLOCK_HEADER_SIZE as usize];
+                    self.stream.read_exact(&mut header).await?;
+                    let remaining = (length - BLOCK_HEADER_SIZE) as u64;
+                    if BlockHash::sha256d(&header) != *block_hash {
+                        // not the block we asked for
+                        self.skip(remaining).await?;
+                        continue;
+                    }
+                    return Ok(Some((header, remaining)));
+                }
+                "notfound" => {
+                    let payload = self.read_payload(length).await?;
+                    if payload.windows(32).any(|w| w == block_hash.hash) {
+                        return Ok(None);
+                    }
+                }
+                "ping" => {
+                    let payload = self.read_payload(length).await?;
+                    self.send("pong", &payload).await?;
+                }
+                _ => {
+                    debug!(%command, "ignoring message");
+                    self.skip(length as u64).await?;
+                }
+            }
+        }
+    }
+
+    // Send a message.
+    async fn send(&mut self, command: &str, payload: &[u8]) -> Result<()> {
+        let message = encode_message(self.network, command, payload);
+        self.stream.get_mut().write_all(&message).await?;
+        Ok(())
+    }
+
+    // Read a message header, returning the command and the length of the payload.
+    async fn read_header(&mut self) -> Result<(String, u32)> {
+        let mut header = [0u8; MESSAGE_HEADER_SIZE];
+        self.stream.read_exact(&mut header).await?;
+        decode_header(self.network, &header)
+    }
+
+    // Read the payload of a message.
+    async fn read_payload(&mut self, length: u32) -> Result<Vec<u8>> {
+        if length > MAX_MESSAGE_SIZE {
+            return Err(Error::PeerError(format!("message too large: {} bytes", length)));
+        }
+        let mut payload = vec![0u8; length as usize];
+        self.stream.read_exact(&mut payload).await?;
+        Ok(payload)
+    }
+
+    // Discard part of the stream.
+    async fn skip(&mut self, length: u64) -> Result<()> {
+        skip(&mut self.stream, length).await
+    }
+}
+
+/// Find the blocks which are the parent of a block in the archive but which are not themselves
+/// in the archive, not counting the parent of a genesis block.
+pub async fn missing_parents<A: BlockArchive + ?Sized>(archive: &mut A) -> Result<Vec<BlockHash>> {
+    let zero = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+    let mut block_hashes = BTreeSet::new();
+    let mut parents = BTreeSet::new();
+    let mut results = archive.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        block_hashes.insert(block_hash);
+        let header = archive.block_header(&block_hash).await?;
+        if header.prev_hash != zero {
+            parents.insert(header.prev_hash);
+        }
+    }
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    Ok(parents.difference(&block_hashes).copied().collect())
+}
+
+/// Fetch the missing parents of the blocks in the archive, and then their parents in turn, until
+/// the archive is linked back to the genesis block or the peer does not have a block.
+pub async fn fetch_missing<A: BlockArchive + ?Sized>(archive: &mut A, fetcher: &mut P2PFetcher) -> Result<FetchSummary> {
+    let mut summary = FetchSummary::default();
+    let mut queue = missing_parents(archive).await?;
+    let zero = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+    while let Some(block_hash) = queue.pop() {
+        if archive.block_exists(&block_hash).await? {
+            continue;
+        }
+        if !fetcher.fetch_block(&block_hash, archive).await? {
+            summary.not_found.push(block_hash);
+            continue;
+        }
+        info!(block_hash = %block_hash, "fetched block");
+        summary.fetched += 1;
+        let parent = archive.block_header(&block_hash).await?.prev_hash;
+        if parent != zero && !archive.block_exists(&parent).await? {
+            queue.push(parent);
+        }
+    }
+    Ok(summary)
+}
+
+/// Fetch the chain of headers from the peer into the header archive, returning the number of
+/// headers stored.
+///
+/// If the header archive is chain-linked and not empty, the headers are fetched from its tip,
+/// otherwise from the genesis block, skipping the headers that the archive already has. The
+/// headers are taken from the best chain of the peer and their proof of work is not checked.
+pub async fn fetch_headers<H: HeaderArchive + ?Sized>(archive: &H, fetcher: &mut P2PFetcher) -> Result<usize> {
+    let mut stored = 0;
+    let mut tip = match archive.tip().await? {
+        Some((block_hash, _)) => block_hash,
+        None => {
+            let genesis = fetcher.network.genesis_hash();
+            if !archive.header_exists(&genesis).await? {
+                let header = fetcher.fetch_header(&genesis).await?
+                    .ok_or_else(|| Error::PeerError("the peer does not have the genesis block".to_string()))?;
+                archive.store_header(&header).await?;
+                stored += 1;
+            }
+            genesis
+        }
+    };
+    loop {
+        let headers = fetcher.get_headers(&[tip]).await?;
+        for header in headers.iter() {
+            match archive.store_header(header).await {
+                Ok(_) => stored += 1,
+                Err(Error::BlockExists) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        info!(headers = stored, "stored headers");
+        match headers.last() {
+            Some(header) if headers.len() == MAX_HEADERS => tip = BlockHash::sha256d(header),
+            _ => break,
+        }
+    }
+    Ok(stored)
+}
+
+// Decode the payload of a headers message, each header is followed by a transaction count of zero.
+fn decode_headers(payload: &[u8]) -> Option<Vec<[u8; 80]>> {
+    let mut cursor = payload;
+    let count = take_varint(&mut cursor)?;
+    let mut headers = Vec::new();
+    for _ in 0..count {
+        headers.push(take(&mut cursor, BLOCK_HEADER_SIZE as u64)?.try_into().ok()?);
+        take_varint(&mut cursor)?;
+    }
+    Some(headers)
+}
+
+// Encode a message, with the header.
+pub(crate) fn encode_message(network: Network, command: &str, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MESSAGE_HEADER_SIZE + payload.len());
+    message.extend_from_slice(&message_header(network, command, payload.len() as u32, checksum(payload)));
+    message.extend_from_slice(payload);
+    message
+}
+
+// Encode a message header, for a payload with the given length and checksum.
+pub(crate) fn message_header(network: Network, command: &str, length: u32, checksum: [u8; 4]) -> [u8; MESSAGE_HEADER_SIZE] {
+    let mut header = [0u8; MESSAGE_HEADER_SIZE];
+    header[..4].copy_from_slice(&network.net_magic());
+    header[4..4 + command.len()].copy_from_slice(command.as_bytes());
+    header[16..20].copy_from_slice(&length.to_le_bytes());
+    header[20..].copy_from_slice(&checksum);
+    header
+}
+
+// Decode a message header, returning the command and the length of the payload.
+pub(crate) fn decode_header(network: Network, header: &[u8; MESSAGE_HEADER_SIZE]) -> Result<(String, u32)> {
+    if header[..4] != network.net_magic() {
+        return Err(Error::PeerError("wrong network magic".to_string()));
+    }
+    let name = &header[4..16];
+    let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    let command = String::from_utf8_lossy(&name[..end]).to_string();
+    let length = u32::from_le_bytes(header[16..20].try_into().unwrap());
+    Ok((command, length))
+}
+
+// The message checksum, the first four bytes of the double SHA-256 of the payload.
+pub(crate) fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = digest(&SHA256, digest(&SHA256, payload).as_ref());
+    hash.as_ref()[..4].try_into().unwrap()
+}
+
+// The payload of our version message, with the services we provide and the height of our best
+// chain.
+pub(crate) fn version_payload(services: u64, start_height: i32) -> Vec<u8> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    payload.extend_from_slice(&services.to_le_bytes());
+    payload.extend_from_slice(&(now.as_secs() as i64).to_le_bytes());
+    // the receiving and sending addresses: services, IPv6 address, and port, all unused
+    payload.extend_from_slice(&[0u8; 26]);
+    payload.extend_from_slice(&[0u8; 26]);
+    // the nonce only has to differ from the nonces the node uses itself
+    let nonce = now.as_nanos() as u64 ^ ((std::process::id() as u64) << 32);
+    payload.extend_from_slice(&nonce.to_le_bytes());
+    payload.push(USER_AGENT.len() as u8);
+    payload.extend_from_slice(USER_AGENT.as_bytes());
+    payload.extend_from_slice(&start_height.to_le_bytes());
+    // dont relay transactions to us
+    payload.push(0);
+    payload
+}
+
+// Discard bytes from a reader.
+pub(crate) async fn skip<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, length: u64) -> Result<()> {
+    let skipped = tokio::io::copy(&mut reader.take(length), &mut tokio::io::sink()).await?;
+    if skipped != length {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use tokio::net::TcpListener;
+    use crate::{SimpleFileBasedBlockArchive, SledHeaderArchive};
+    use super::*;
+
+    #[test]
+    fn test_encode_message() {
+        let message = encode_message(Network::Mainnet, "verack", &[]);
+        // the well known verack message on mainnet
+        assert_eq!(hex::encode(&message), "e3e1f3e876657261636b000000000000000000005df6e0e2");
+        let header: [u8; MESSAGE_HEADER_SIZE] = message[..].try_into().unwrap();
+        assert_eq!(decode_header(Network::Mainnet, &header).unwrap(), ("verack".to_string(), 0));
+        assert!(decode_header(Network::Testnet, &header).is_err());
+    }
+
+    // A fake node that answers the handshake and serves one block, with no headers after it.
+    async fn fake_node(listener: TcpListener, block: Vec<u8>) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut header = [0u8; MESSAGE_HEADER_SIZE];
+        loop {
+            socket.read_exact(&mut header).await.unwrap();
+            let (command, length) = decode_header(Network::Mainnet, &header).unwrap();
+            let mut payload = vec![0u8; length as usize];
+            socket.read_exact(&mut payload).await.unwrap();
+            match command.as_str() {
+                "version" => {
+                    socket.write_all(&encode_message(Network::Mainnet, "version", &version_payload(0, 0))).await.unwrap();
+                    socket.write_all(&encode_message(Network::Mainnet, "verack", &[])).await.unwrap();
+                    socket.write_all(&encode_message(Network::Mainnet, "ping", &[1u8; 8])).await.unwrap();
+                }
+                "getdata" => {
+                    let requested = &payload[5..37];
+                    if requested == &BlockHash::sha256d(&block[..80]).hash[..] {
+                        socket.write_all(&encode_message(Network::Mainnet, "block", &block)).await.unwrap();
+                    } else {
+                        socket.write_all(&encode_message(Network::Mainnet, "notfound", &payload)).await.unwrap();
+                    }
+                }
+                // the node has no headers after the block it serves
+                "getheaders" => {
+                    socket.write_all(&encode_message(Network::Mainnet, "headers", &[0u8])).await.unwrap();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Fetch a block from the fake node.
+    #[tokio::test]
+    async fn test_fetch_block() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let node = tokio::spawn(fake_node(listener, block.clone()));
+        let root = Temp::new_dir().unwrap();
+        let dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let mut fetcher = P2PFetcher::connect(addr, Network::Mainnet).await.unwrap();
+        assert!(fetcher.fetch_block(&h, &dst).await.unwrap());
+        let mut stored = Vec::new();
+        dst.get_block(&h).await.unwrap().read_to_end(&mut stored).await.unwrap();
+        assert_eq!(stored, block);
+        let other = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(!fetcher.fetch_block(&other, &dst).await.unwrap());
+        node.abort();
+    }
+
+    // Fetch the headers from a fake node which only has the genesis block.
+    #[tokio::test]
+    async fn test_fetch_headers() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = Network::Mainnet.genesis_hash();
+        let mut block = Vec::new();
+        src.get_block(&genesis).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let node = tokio::spawn(fake_node(listener, block.clone()));
+        let dir = Temp::new_dir().unwrap();
+        let dst = SledHeaderArchive::open(&dir.to_path_buf()).unwrap().with_chain_linkage(true);
+        let mut fetcher = P2PFetcher::connect(addr, Network::Mainnet).await.unwrap();
+        assert_eq!(fetch_headers(&dst, &mut fetcher).await.unwrap(), 1);
+        assert_eq!(dst.get_header(&genesis).await.unwrap()[..], block[..80]);
+        assert_eq!(fetch_headers(&dst, &mut fetcher).await.unwrap(), 0);
+        node.abort();
+
+        let mut payload = vec![2u8];
+        for _ in 0..2 {
+            payload.extend_from_slice(&block[..80]);
+            payload.push(0);
+        }
+        assert_eq!(decode_headers(&payload).unwrap().len(), 2);
+        assert!(decode_headers(&payload[..100]).is_none());
+    }
+
+    // The test archive does not contain the genesis block, so some parents are missing.
+    #[tokio::test]
+    async fn test_missing_parents() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        assert!(!missing_parents(&mut archive).await.unwrap().is_empty());
+    }
+}
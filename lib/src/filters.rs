@@ -0,0 +1,468 @@
+//! Compact block filters, in the style of BIP157 and BIP158.
+//!
+//! A filter is a small summary of a block, a Golomb-coded set of the output scripts created by the
+//! block and the outpoints spent by it. A client can test the filters for the scripts and
+//! outpoints it is interested in, and then read only the blocks whose filters match. A filter
+//! always matches the items in its block, and matches an item that is not in the block about once
+//! in 784931 tests.
+//!
+//! The filters use the parameters and encoding of the BIP158 basic filter, with one difference.
+//! BIP158 filters contain the scripts of the outputs that are spent, which can only be found by
+//! reading the earlier blocks, whereas these filters contain the outpoints that are spent, so that
+//! each filter can be computed from its own block. As in BIP158, outputs that only carry data are
+//! left out, which here includes the scripts that start with OP_FALSE OP_RETURN.
+//!
+//! Example code:
+//!     let filters = FilterIndex::open(&root_dir.join("filters"))?;
+//!     filters.build(&mut archive).await?;
+//!     let filter = filters.block_filter(&block_hash)?;
+use std::collections::HashSet;
+use std::path::Path;
+use bitcoinsv::bitcoin::BlockHash;
+use hex::{FromHex, ToHex};
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+use crate::headers::HEADER_SIZE;
+use crate::merkle::write_varint;
+use crate::txindex::{read_bytes, read_tx, read_varint, take, take_varint};
+use crate::{BlockArchive, Error, Result};
+
+// the number of bits in the remainder of each Golomb-Rice coded value
+const FILTER_P: u8 = 19;
+// the inverse of the false positive rate
+const FILTER_M: u64 = 784931;
+
+// the first byte of a script that only carries data
+const OP_RETURN: u8 = 0x6a;
+
+/// The compact filter of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter {
+    block_hash: BlockHash,
+    // the number of items in the filter
+    n: u64,
+    // the Golomb-Rice coded differences between the sorted hashes of the items
+    data: Vec<u8>,
+}
+
+impl BlockFilter {
+    /// Create the filter of a block from its items. Duplicate items are only added once.
+    pub fn new<I, T>(block_hash: &BlockHash, items: I) -> BlockFilter
+        where I: IntoIterator<Item=T>, T: AsRef<[u8]>
+    {
+        let items: HashSet<Vec<u8>> = items.into_iter().map(|i| i.as_ref().to_vec()).collect();
+        let n = items.len() as u64;
+        let key = siphash_key(block_hash);
+        let mut values: Vec<u64> = items.iter().map(|i| hash_to_range(key, i, n * FILTER_M)).collect();
+        values.sort_unstable();
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in values {
+            golomb_encode(&mut writer, value - last);
+            last = value;
+        }
+        BlockFilter { block_hash: *block_hash, n, data: writer.bytes }
+    }
+
+    /// Decode a filter encoded by [BlockFilter::to_bytes].
+    pub fn from_bytes(block_hash: &BlockHash, bytes: &[u8]) -> Result<BlockFilter> {
+        let mut cursor = bytes;
+        let n = take_varint(&mut cursor).ok_or_else(|| Error::FilterError("truncated filter".to_string()))?;
+        Ok(BlockFilter { block_hash: *block_hash, n, data: cursor.to_vec() })
+    }
+
+    /// Encode the filter in the BIP158 format, the number of items followed by the coded set.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(self.data.len() + 9);
+        write_varint(&mut v, self.n);
+        v.extend_from_slice(&self.data);
+        v
+    }
+
+    /// Get the hash of the block that the filter is for.
+    pub fn block_hash(&self) -> &BlockHash {
+        &self.block_hash
+    }
+
+    /// Get the number of items in the filter.
+    pub fn len(&self) -> u64 {
+        self.n
+    }
+
+    /// Check whether the filter has no items.
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    /// Check whether an item may be in the block.
+    pub fn matches(&self, item: &[u8]) -> Result<bool> {
+        self.match_any(&[item])
+    }
+
+    /// Check whether any of the items may be in the block.
+    ///
+    /// Fails with [Error::FilterError] if the filter is damaged.
+    pub fn match_any<T: AsRef<[u8]>>(&self, items: &[T]) -> Result<bool> {
+        if self.n == 0 || items.is_empty() {
+            return Ok(false);
+        }
+        let damaged = || Error::FilterError(format!("damaged filter for block {}", self.block_hash));
+        let f = self.n.checked_mul(FILTER_M).ok_or_else(damaged)?;
+        let key = siphash_key(&self.block_hash);
+        let mut targets: Vec<u64> = items.iter().map(|i| hash_to_range(key, i.as_ref(), f)).collect();
+        targets.sort_unstable();
+        let mut targets = targets.into_iter().peekable();
+        let mut reader = BitReader { bytes: &self.data, pos: 0 };
+        let mut value = 0u64;
+        for _ in 0..self.n {
+            value = golomb_decode(&mut reader)
+                .and_then(|delta| value.checked_add(delta))
+                .ok_or_else(damaged)?;
+            while targets.next_if(|t| *t < value).is_some() {}
+            match targets.peek() {
+                Some(t) if *t == value => return Ok(true),
+                Some(_) => {}
+                None => return Ok(false),
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Compute the filter of a block in the archive.
+///
+/// Fails with [Error::CorruptBlock] if the block cannot be parsed.
+pub async fn compute_filter<A: BlockArchive + ?Sized>(archive: &A, block_hash: &BlockHash) -> Result<BlockFilter> {
+    let mut reader = archive.get_block(block_hash).await?;
+    match block_items(&mut reader).await {
+        Ok(items) => Ok(BlockFilter::new(block_hash, items)),
+        Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(Error::CorruptBlock(*block_hash)),
+        Err(e) => Err(e),
+    }
+}
+
+/// The filters of the blocks in an archive.
+///
+/// The filters are kept in a sled database, normally in a "filters" directory next to the
+/// archive, and are added by [FilterIndex::build] as new blocks are stored.
+pub struct FilterIndex {
+    // maps the hex block hash to the encoded filter
+    db: sled::Db,
+}
+
+impl FilterIndex {
+    /// Open the filters in the given directory, creating it if necessary.
+    pub fn open(path: &Path) -> Result<FilterIndex> {
+        let db = sled::open(path).map_err(filter_error)?;
+        Ok(FilterIndex { db })
+    }
+
+    /// Get the filter of a block, or None if it has not been computed.
+    pub fn block_filter(&self, block_hash: &BlockHash) -> Result<Option<BlockFilter>> {
+        let key: String = block_hash.encode_hex();
+        match self.db.get(key).map_err(filter_error)? {
+            Some(v) => Ok(Some(BlockFilter::from_bytes(block_hash, &v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the number of blocks with filters.
+    pub fn num_blocks(&self) -> usize {
+        self.db.len()
+    }
+
+    /// Compute and store the filter of a block.
+    ///
+    /// Returns false if the block already had a filter.
+    pub async fn add_block<A: BlockArchive + ?Sized>(&self, archive: &A, block_hash: &BlockHash) -> Result<bool> {
+        let key: String = block_hash.encode_hex();
+        if self.db.contains_key(&key).map_err(filter_error)? {
+            return Ok(false);
+        }
+        let filter = compute_filter(archive, block_hash).await?;
+        self.db.insert(key, filter.to_bytes()).map_err(filter_error)?;
+        Ok(true)
+    }
+
+    /// Remove the filter of a block, returning false if it had no filter.
+    pub fn remove_block(&self, block_hash: &BlockHash) -> Result<bool> {
+        let key: String = block_hash.encode_hex();
+        Ok(self.db.remove(key).map_err(filter_error)?.is_some())
+    }
+
+    /// Compute the filter of every block in the archive that does not have one.
+    ///
+    /// Returns the number of filters computed.
+    pub async fn build<A: BlockArchive + ?Sized>(&self, archive: &mut A) -> Result<usize> {
+        let mut count = 0;
+        let mut results = archive.block_list().await?;
+        while let Some(block_hash) = results.next().await {
+            if self.add_block(archive, &block_hash).await? {
+                count += 1;
+            }
+        }
+        self.flush().await?;
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        Ok(count)
+    }
+
+    /// Find the blocks whose filters match any of the items, without reading the blocks.
+    ///
+    /// The blocks are returned in the order of their hashes. A few of them may not contain any
+    /// of the items.
+    pub fn scan<T: AsRef<[u8]>>(&self, items: &[T]) -> Result<Vec<BlockHash>> {
+        let mut result = Vec::new();
+        for entry in self.db.iter() {
+            let (key, value) = entry.map_err(filter_error)?;
+            let block_hash = BlockHash::from_hex(&key[..])
+                .map_err(|_| Error::FilterError("invalid block hash in filter index".to_string()))?;
+            if BlockFilter::from_bytes(&block_hash, &value)?.match_any(items)? {
+                result.push(block_hash);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Write any buffered changes to disk.
+    pub async fn flush(&self) -> Result<()> {
+        self.db.flush_async().await.map_err(filter_error)?;
+        Ok(())
+    }
+}
+
+// Read the items that go in the filter of an encoded block.
+async fn block_items<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<HashSet<Vec<u8>>> {
+    let mut buf = Vec::new();
+    read_bytes(reader, &mut buf, HEADER_SIZE as u64).await?;
+    let num_tx = read_varint(reader, &mut buf).await?;
+    let mut items = HashSet::new();
+    for i in 0..num_tx {
+        buf.clear();
+        read_tx(reader, &mut buf).await?;
+        // the coinbase does not spend an output
+        tx_items(&buf, i == 0, &mut items)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+    }
+    Ok(items)
+}
+
+// Add the outpoints spent by an encoded transaction and the scripts of its outputs to the items.
+fn tx_items(tx: &[u8], coinbase: bool, items: &mut HashSet<Vec<u8>>) -> Option<()> {
+    let mut cursor = tx.get(4..)?;
+    let num_inputs = take_varint(&mut cursor)?;
+    for _ in 0..num_inputs {
+        let outpoint = take(&mut cursor, 36)?;
+        if !coinbase {
+            items.insert(outpoint.to_vec());
+        }
+        let script_len = take_varint(&mut cursor)?;
+        take(&mut cursor, script_len.checked_add(4)?)?;
+    }
+    let num_outputs = take_varint(&mut cursor)?;
+    for _ in 0..num_outputs {
+        take(&mut cursor, 8)?;
+        let script_len = take_varint(&mut cursor)?;
+        let script = take(&mut cursor, script_len)?;
+        let data_carrier = script.first() == Some(&OP_RETURN) || script.starts_with(&[0x00, OP_RETURN]);
+        if !script.is_empty() && !data_carrier {
+            items.insert(script.to_vec());
+        }
+    }
+    Some(())
+}
+
+// The SipHash key of a filter is the first 16 bytes of the block hash.
+fn siphash_key(block_hash: &BlockHash) -> (u64, u64) {
+    (u64::from_le_bytes(block_hash.hash[..8].try_into().unwrap()), u64::from_le_bytes(block_hash.hash[8..16].try_into().unwrap()))
+}
+
+// Map an item uniformly to the range [0, f).
+fn hash_to_range(key: (u64, u64), item: &[u8], f: u64) -> u64 {
+    ((siphash(key, item) as u128 * f as u128) >> 64) as u64
+}
+
+// SipHash-2-4.
+fn siphash(key: (u64, u64), data: &[u8]) -> u64 {
+    let (k0, k1) = key;
+    let mut v = [k0 ^ 0x736f6d6570736575, k1 ^ 0x646f72616e646f6d, k0 ^ 0x6c7967656e657261, k1 ^ 0x7465646279746573];
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        sip_compress(&mut v, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    // the last word holds the remaining bytes and the length
+    let mut last = [0u8; 8];
+    let remainder = chunks.remainder();
+    last[..remainder.len()].copy_from_slice(remainder);
+    last[7] = data.len() as u8;
+    sip_compress(&mut v, u64::from_le_bytes(last));
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_compress(v: &mut [u64; 4], m: u64) {
+    v[3] ^= m;
+    sip_round(v);
+    sip_round(v);
+    v[0] ^= m;
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+// Write a value as a unary quotient followed by a FILTER_P bit remainder.
+fn golomb_encode(writer: &mut BitWriter, value: u64) {
+    for _ in 0..(value >> FILTER_P) {
+        writer.write_bit(true);
+    }
+    writer.write_bit(false);
+    for i in (0..FILTER_P).rev() {
+        writer.write_bit((value >> i) & 1 == 1);
+    }
+}
+
+// Read a value written by golomb_encode, or None if the data ends first.
+fn golomb_decode(reader: &mut BitReader) -> Option<u64> {
+    let mut quotient = 0u64;
+    while reader.read_bit()? {
+        quotient += 1;
+        if quotient >> (64 - FILTER_P) != 0 {
+            return None;
+        }
+    }
+    let mut remainder = 0u64;
+    for _ in 0..FILTER_P {
+        remainder = (remainder << 1) | reader.read_bit()? as u64;
+    }
+    Some((quotient << FILTER_P) | remainder)
+}
+
+// Writes bits from the most significant bit of each byte.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    // the number of bits used in the last byte, zero when a new byte is needed
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    // the position of the next bit
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = *self.bytes.get(self.pos / 8)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+}
+
+fn filter_error(e: sled::Error) -> Error {
+    Error::FilterError(e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // The first test vectors of the SipHash reference implementation.
+    #[test]
+    fn test_siphash() {
+        let key = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        assert_eq!(siphash(key, &[]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash(key, &[0]), 0x74f839c593dc67fd);
+    }
+
+    // A filter matches its items, survives encoding, and a damaged filter is reported.
+    #[test]
+    fn test_filter() {
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let items: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        let filter = BlockFilter::new(&h, items.iter().chain(items.iter()));
+        assert_eq!(filter.len(), 1000);
+        for item in items.iter() {
+            assert!(filter.matches(item).unwrap());
+        }
+        let missing: Vec<Vec<u8>> = (1000..1100u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        // a false positive is possible but unlikely among 100 items
+        assert!(missing.iter().filter(|i| filter.matches(i).unwrap()).count() < 2);
+        let decoded = BlockFilter::from_bytes(&h, &filter.to_bytes()).unwrap();
+        assert_eq!(decoded, filter);
+        let empty = BlockFilter::new(&h, Vec::<Vec<u8>>::new());
+        assert!(empty.is_empty());
+        assert!(!empty.matches(&items[0]).unwrap());
+        let truncated = BlockFilter::from_bytes(&h, &filter.to_bytes()[..3]).unwrap();
+        assert!(matches!(truncated.matches(&items[999]), Err(Error::FilterError(_))));
+    }
+
+    // Build the filters of the test archive and find a block by one of its outputs.
+    #[tokio::test]
+    async fn test_filter_index() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let filters = FilterIndex::open(&dir.to_path_buf()).unwrap();
+        assert_eq!(filters.build(&mut archive).await.unwrap(), 3);
+        assert_eq!(filters.build(&mut archive).await.unwrap(), 0);
+        assert_eq!(filters.num_blocks(), 3);
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let items = block_items(&mut &block[..]).await.unwrap();
+        assert!(!items.is_empty());
+        let filter = filters.block_filter(&h).unwrap().unwrap();
+        assert_eq!(filter.len(), items.len() as u64);
+        for item in items.iter() {
+            assert!(filter.matches(item).unwrap());
+        }
+        let item = items.iter().next().unwrap();
+        assert!(filters.scan(&[item]).unwrap().contains(&h));
+        assert!(filters.remove_block(&h).unwrap());
+        assert_eq!(filters.block_filter(&h).unwrap(), None);
+        assert!(!filters.remove_block(&h).unwrap());
+    }
+
+    // A listing that fails part way fails the build, rather than counting as complete.
+    #[tokio::test]
+    async fn test_filter_build_list_error() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        tokio::fs::remove_dir_all(root.as_path()).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let filters = FilterIndex::open(&dir.to_path_buf()).unwrap();
+        assert!(matches!(filters.build(&mut archive).await, Err(Error::IoError(_))));
+    }
+}
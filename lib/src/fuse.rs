@@ -0,0 +1,292 @@
+//! A read-only FUSE filesystem that presents an archive as files, so that existing file based
+//! tools can read the blocks without changes.
+//!
+//! The root directory has a file "<hash>.bin" for each block, with the encoded block as its
+//! contents. If the archive has a chain linked to the genesis block there is also a "by-height"
+//! directory, with a symlink "by-height/N" to the file of the block at height N in the best chain.
+//!
+//! The blocks and the chain are listed when the filesystem is mounted. A block stored later can
+//! be opened by name but is not listed until the archive is mounted again.
+//!
+//! Example code:
+//!     mount(archive, Path::new("/mnt/blocks")).await?;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::raw::c_int;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use bitcoinsv::bitcoin::BlockHash;
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request};
+use hex::FromHex;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Handle;
+use tokio_stream::StreamExt;
+use crate::{BlockArchive, ChainIndex, Error, Result};
+
+/// The name of the directory of symlinks by height.
+pub const BY_HEIGHT_DIR: &str = "by-height";
+
+const ROOT_INO: u64 = 1;
+const BY_HEIGHT_INO: u64 = 2;
+const FIRST_BLOCK_INO: u64 = 3;
+// the inode of a by-height symlink is the height with this bit set
+const HEIGHT_INO_FLAG: u64 = 1 << 62;
+// how long the kernel can cache names and attributes, the files never change
+const TTL: Duration = Duration::from_secs(60);
+const BLOCK_FILE_EXTENSION: &str = ".bin";
+
+/// A read-only filesystem that presents an archive as files, see [mount].
+pub struct BlockArchiveFs<A: BlockArchive> {
+    archive: A,
+    // the filesystem callbacks are synchronous and run the archive operations on this runtime
+    runtime: Handle,
+    // the block with inode n is at index n - FIRST_BLOCK_INO
+    blocks: Vec<BlockHash>,
+    inodes: BTreeMap<BlockHash, u64>,
+    sizes: BTreeMap<u64, u64>,
+    // None if the archive has no chain linked to the genesis block
+    chain: Option<ChainIndex>,
+    mounted: SystemTime,
+}
+
+impl<A: BlockArchive> BlockArchiveFs<A> {
+    /// List the blocks and build the chain index of the archive.
+    ///
+    /// Must be called within a tokio runtime, which runs the archive operations.
+    pub async fn new(mut archive: A) -> Result<BlockArchiveFs<A>> {
+        let mut blocks = Vec::new();
+        let mut results = archive.block_list().await?;
+        while let Some(block_hash) = results.next().await {
+            blocks.push(block_hash);
+        }
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        blocks.sort();
+        let inodes = blocks.iter().enumerate().map(|(i, h)| (*h, FIRST_BLOCK_INO + i as u64)).collect();
+        let chain = ChainIndex::build(&mut archive).await?;
+        let chain = if chain.tip().is_some() { Some(chain) } else { None };
+        Ok(BlockArchiveFs { archive, runtime: Handle::current(), blocks, inodes, sizes: BTreeMap::new(), chain,
+            mounted: SystemTime::now() })
+    }
+
+    // Get the inode of a block file from its name, or None if there is no such block.
+    fn lookup_block(&mut self, name: &str) -> std::result::Result<Option<u64>, c_int> {
+        let block_hash = match name.strip_suffix(BLOCK_FILE_EXTENSION).and_then(|h| BlockHash::from_hex(h).ok()) {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        if let Some(ino) = self.inodes.get(&block_hash) {
+            return Ok(Some(*ino));
+        }
+        // a block stored since the filesystem was mounted
+        if !self.runtime.block_on(self.archive.block_exists(&block_hash)).map_err(errno)? {
+            return Ok(None);
+        }
+        let ino = FIRST_BLOCK_INO + self.blocks.len() as u64;
+        self.blocks.push(block_hash);
+        self.inodes.insert(block_hash, ino);
+        Ok(Some(ino))
+    }
+
+    // Get the inode of a by-height symlink from its name, or None if there is no such height.
+    fn lookup_height(&self, name: &str) -> Option<u64> {
+        let height = name.parse::<u64>().ok().filter(|h| h & HEIGHT_INO_FLAG == 0)?;
+        self.chain.as_ref()?.block_by_height(height)?;
+        Some(height | HEIGHT_INO_FLAG)
+    }
+
+    fn block_hash(&self, ino: u64) -> Option<BlockHash> {
+        let index = usize::try_from(ino.checked_sub(FIRST_BLOCK_INO)?).ok()?;
+        self.blocks.get(index).copied()
+    }
+
+    // Get the target of a by-height symlink.
+    fn link_target(&self, ino: u64) -> Option<String> {
+        if ino & HEIGHT_INO_FLAG == 0 {
+            return None;
+        }
+        let block_hash = self.chain.as_ref()?.block_by_height(ino & !HEIGHT_INO_FLAG)?;
+        Some(format!("../{}{}", block_hash, BLOCK_FILE_EXTENSION))
+    }
+
+    fn block_size(&mut self, ino: u64, block_hash: &BlockHash) -> std::result::Result<u64, c_int> {
+        if let Some(size) = self.sizes.get(&ino) {
+            return Ok(*size);
+        }
+        let size = self.runtime.block_on(self.archive.block_size(block_hash)).map_err(errno)? as u64;
+        self.sizes.insert(ino, size);
+        Ok(size)
+    }
+
+    fn attr(&mut self, req: &Request<'_>, ino: u64) -> std::result::Result<FileAttr, c_int> {
+        let (kind, size, perm, nlink) = if ino == ROOT_INO || (ino == BY_HEIGHT_INO && self.chain.is_some()) {
+            (FileType::Directory, 0, 0o555, 2)
+        } else if let Some(target) = self.link_target(ino) {
+            (FileType::Symlink, target.len() as u64, 0o777, 1)
+        } else if let Some(block_hash) = self.block_hash(ino) {
+            (FileType::RegularFile, self.block_size(ino, &block_hash)?, 0o444, 1)
+        } else {
+            return Err(libc::ENOENT);
+        };
+        Ok(FileAttr {
+            ino, size, blocks: size.div_ceil(512),
+            atime: self.mounted, mtime: self.mounted, ctime: self.mounted, crtime: self.mounted,
+            kind, perm, nlink, uid: req.uid(), gid: req.gid(), rdev: 0, blksize: 4096, flags: 0,
+        })
+    }
+
+    // Read up to size bytes of a block file, starting at offset.
+    fn read_block(&mut self, ino: u64, offset: u64, size: u64) -> std::result::Result<Vec<u8>, c_int> {
+        let block_hash = self.block_hash(ino).ok_or(libc::ENOENT)?;
+        let block_size = self.block_size(ino, &block_hash)?;
+        let length = size.min(block_size.saturating_sub(offset));
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        let archive = &self.archive;
+        self.runtime.block_on(async {
+            let mut reader = archive.get_block_range(&block_hash, offset, length).await?;
+            let mut buf = Vec::with_capacity(length as usize);
+            reader.read_to_end(&mut buf).await?;
+            Ok::<_, Error>(buf)
+        }).map_err(errno)
+    }
+}
+
+impl<A: BlockArchive> Filesystem for BlockArchiveFs<A> {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => return reply.error(libc::ENOENT),
+        };
+        let ino = match parent {
+            ROOT_INO if name == BY_HEIGHT_DIR && self.chain.is_some() => Ok(Some(BY_HEIGHT_INO)),
+            ROOT_INO => self.lookup_block(name),
+            BY_HEIGHT_INO => Ok(self.lookup_height(name)),
+            _ => Ok(None),
+        };
+        match ino.and_then(|ino| ino.ok_or(libc::ENOENT)).and_then(|ino| self.attr(req, ino)) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(req, ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.link_target(ino) {
+            Some(target) => reply.data(target.as_bytes()),
+            None => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn read(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32,
+            _lock_owner: Option<u64>, reply: ReplyData) {
+        let offset = match u64::try_from(offset) {
+            Ok(o) => o,
+            Err(_) => return reply.error(libc::EINVAL),
+        };
+        match self.read_block(ino, offset, size as u64) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // The offset of an entry is its index in the directory plus one.
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let offset = usize::try_from(offset).unwrap_or(0);
+        let mut entries = vec![(ino, FileType::Directory, ".".to_string()), (ROOT_INO, FileType::Directory, "..".to_string())];
+        match ino {
+            ROOT_INO if self.chain.is_some() => entries.push((BY_HEIGHT_INO, FileType::Directory, BY_HEIGHT_DIR.to_string())),
+            ROOT_INO => {}
+            BY_HEIGHT_INO if self.chain.is_some() => {}
+            _ => return reply.error(libc::ENOTDIR),
+        }
+        let fixed = entries.len();
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                return reply.ok();
+            }
+        }
+        // the blocks or heights are generated from the offset, so that listing a large directory
+        // in several calls does not format the names of the entries already listed
+        let start = offset.saturating_sub(fixed);
+        if ino == ROOT_INO {
+            for (i, block_hash) in self.blocks.iter().enumerate().skip(start) {
+                let name = format!("{}{}", block_hash, BLOCK_FILE_EXTENSION);
+                if reply.add(FIRST_BLOCK_INO + i as u64, (fixed + i + 1) as i64, FileType::RegularFile, name) {
+                    break;
+                }
+            }
+        } else if let Some((_, tip)) = self.chain.as_ref().and_then(|c| c.tip()) {
+            for height in (start as u64)..=tip {
+                let i = height as usize;
+                if reply.add(height | HEIGHT_INO_FLAG, (fixed + i + 1) as i64, FileType::Symlink, height.to_string()) {
+                    break;
+                }
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mount the archive as a read-only filesystem, returning when it is unmounted, for example with
+/// "fusermount -u <mountpoint>".
+pub async fn mount<A: BlockArchive + 'static>(archive: A, mountpoint: &Path) -> Result<()> {
+    let fs = BlockArchiveFs::new(archive).await?;
+    let mountpoint = mountpoint.to_path_buf();
+    let options = [MountOption::RO, MountOption::FSName("blockarchive".to_string()), MountOption::DefaultPermissions];
+    tokio::task::spawn_blocking(move || fuser::mount2(fs, &mountpoint, &options)).await
+        .map_err(|e| Error::IoError(std::io::Error::other(e)))??;
+    Ok(())
+}
+
+fn errno(e: Error) -> c_int {
+    match e {
+        Error::BlockNotFound => libc::ENOENT,
+        _ => libc::EIO,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::{Network, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // Look up and read block files and by-height symlinks without mounting the filesystem.
+    #[test]
+    fn test_block_archive_fs() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut fs = runtime.block_on(async {
+            let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+            BlockArchiveFs::new(archive).await.unwrap()
+        });
+        assert_eq!(fs.blocks.len(), 3);
+        let genesis = Network::Mainnet.genesis_hash();
+        let name = format!("{}.bin", genesis);
+        let ino = fs.lookup_block(&name).unwrap().unwrap();
+        assert_eq!(fs.block_hash(ino), Some(genesis));
+        assert_eq!(fs.lookup_block("nothing.bin").unwrap(), None);
+        assert_eq!(fs.lookup_block(&format!("{:064x}.bin", 1)).unwrap(), None);
+
+        let mut block = Vec::new();
+        runtime.block_on(async {
+            fs.archive.get_block(&genesis).await.unwrap().read_to_end(&mut block).await.unwrap();
+        });
+        assert_eq!(fs.read_block(ino, 0, 80).unwrap(), block[..80]);
+        assert_eq!(fs.read_block(ino, 100, 1000).unwrap(), block[100..]);
+        assert!(fs.read_block(ino, block.len() as u64, 10).unwrap().is_empty());
+
+        let link = fs.lookup_height("0").unwrap();
+        assert_eq!(fs.link_target(link), Some(format!("../{}", name)));
+        assert_eq!(fs.lookup_height("1"), None);
+    }
+}
@@ -0,0 +1,100 @@
+//! Finding the blocks of a chain that are missing from an archive.
+//!
+//! The archive can not know about blocks it does not have, so the headers of missing blocks come
+//! from somewhere else, such as a node.
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use hex::FromHex;
+use crate::{BlockArchive, Result};
+
+/// A run of consecutive blocks of a chain that are missing from the archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Gap {
+    /// The height of the first missing block.
+    pub start_height: u64,
+    /// The missing blocks, in order of height.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub blocks: Vec<BlockHash>,
+}
+
+impl Gap {
+    /// Get the height of the last missing block.
+    pub fn end_height(&self) -> u64 {
+        self.start_height + self.blocks.len() as u64 - 1
+    }
+}
+
+/// Walk the chain from the tip back to genesis and find the blocks that are missing from the
+/// archive. The headers of blocks that are in the archive are read from the archive, the header
+/// function is called to get the header of each missing block.
+///
+/// The gaps are returned in order of height.
+pub async fn find_gaps<A, F>(archive: &A, tip: &BlockHash, tip_height: u64, mut header: F) -> Result<Vec<Gap>>
+    where A: BlockArchive + ?Sized, F: FnMut(&BlockHash) -> Result<BlockHeader>
+{
+    let zero = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+    let mut gaps = Vec::new();
+    // the gap being collected, with its blocks from the top down
+    let mut current: Option<(u64, Vec<BlockHash>)> = None;
+    let mut block_hash = *tip;
+    let mut height = tip_height;
+    loop {
+        let prev_hash = if archive.block_exists(&block_hash).await? {
+            if let Some(gap) = current.take() {
+                gaps.push(gap);
+            }
+            archive.block_header(&block_hash).await?.prev_hash
+        } else {
+            current.get_or_insert_with(|| (height, Vec::new())).1.push(block_hash);
+            header(&block_hash)?.prev_hash
+        };
+        if height == 0 || prev_hash == zero {
+            break;
+        }
+        block_hash = prev_hash;
+        height -= 1;
+    }
+    if let Some(gap) = current.take() {
+        gaps.push(gap);
+    }
+    // the gaps were found from the top down
+    Ok(gaps.into_iter().rev().map(|(end_height, mut blocks)| {
+        blocks.reverse();
+        Gap { start_height: end_height + 1 - blocks.len() as u64, blocks }
+    }).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tokio::io::AsyncReadExt;
+    use crate::{Error, MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // The missing genesis block is found below block 1, and nothing is missing once it is stored.
+    #[tokio::test]
+    async fn test_find_gaps() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let h1 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        let genesis_header = src.block_header(&genesis).await.unwrap();
+        let archive = MemoryBlockArchive::new();
+        let mut block = Vec::new();
+        src.get_block(&h1).await.unwrap().read_to_end(&mut block).await.unwrap();
+        archive.store_block(&h1, &mut &block[..]).await.unwrap();
+
+        let gaps = find_gaps(&archive, &h1, 1, |h| {
+            assert_eq!(*h, genesis);
+            Ok(genesis_header.clone())
+        }).await.unwrap();
+        assert_eq!(gaps, vec![Gap { start_height: 0, blocks: vec![genesis] }]);
+        assert_eq!(gaps[0].end_height(), 0);
+
+        let mut block = Vec::new();
+        src.get_block(&genesis).await.unwrap().read_to_end(&mut block).await.unwrap();
+        archive.store_block(&genesis, &mut &block[..]).await.unwrap();
+        let gaps = find_gaps(&archive, &h1, 1, |_| Err(Error::BlockNotFound)).await.unwrap();
+        assert!(gaps.is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+//! Garbage collection of the blocks of stale forks.
+//!
+//! A fork that branches from the best chain is stale once the best chain has moved on, but it
+//! may still become the best chain while it is close to the tip. [collect_garbage] only takes
+//! the blocks of forks whose tip is at least a confirmation depth below the best tip, and either
+//! removes them or moves them to a quarantine archive, from which they can be restored.
+//!
+//! Example code:
+//!     let chain = ChainIndex::build(&mut archive).await?;
+//!     let summary = collect_garbage(&mut archive, &chain, 100, None, true).await?;
+//!     println!("would reclaim {} bytes", summary.bytes);
+use std::collections::BTreeSet;
+use bitcoinsv::bitcoin::BlockHash;
+use serde_json::{json, Value};
+use crate::tier::move_block;
+use crate::{BlockArchive, ChainIndex, Result};
+
+/// The result of a garbage collection, see [collect_garbage].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GcSummary {
+    /// The blocks removed or quarantined, or that would be in a dry run, from the tip of each
+    /// fork back to its branch point.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub collected: Vec<BlockHash>,
+    /// The number of bytes of block data in the collected blocks.
+    pub bytes: u64,
+    /// The number of blocks of stale forks that were kept because they are not deep enough.
+    pub kept: usize,
+}
+
+impl GcSummary {
+    /// Get the summary as a JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "collected": self.collected.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+            "bytes": self.bytes,
+            "kept": self.kept,
+        })
+    }
+}
+
+/// Remove the blocks of the forks from the best chain whose tip is at least depth blocks below
+/// the best tip, or move them to the quarantine archive if one is given.
+///
+/// A block that is shared by several forks is only collected if all of them are deep enough.
+/// Blocks that are not linked to the best chain are never collected, as they may be the start of
+/// a partial archive. The chain index must be built from the archive. If dry_run is set nothing
+/// is changed.
+pub async fn collect_garbage<A>(archive: &mut A, chain: &ChainIndex, depth: u64, quarantine: Option<&dyn BlockArchive>,
+                                dry_run: bool) -> Result<GcSummary>
+    where A: BlockArchive + ?Sized
+{
+    let mut summary = GcSummary::default();
+    let best_height = match chain.tip() {
+        Some((_, height)) => height,
+        None => return Ok(summary),
+    };
+    // the blocks of forks that are too recent are kept, even if a deep fork shares them
+    let mut stale = Vec::new();
+    let mut recent = BTreeSet::new();
+    for fork in chain.forks().into_iter().filter(|f| f.branch_point.is_some()) {
+        let tip_height = chain.height_of(&fork.tip()).unwrap_or(best_height);
+        if tip_height.saturating_add(depth) <= best_height {
+            stale.push(fork);
+        } else {
+            recent.extend(fork.blocks);
+        }
+    }
+    let mut seen = BTreeSet::new();
+    for fork in stale.iter() {
+        // from the tip, so that an interrupted collection leaves the rest of the fork linked
+        for block_hash in fork.blocks.iter().rev() {
+            if !seen.insert(*block_hash) {
+                continue;
+            }
+            if recent.contains(block_hash) {
+                summary.kept += 1;
+                continue;
+            }
+            let size = archive.block_size(block_hash).await? as u64;
+            if !dry_run {
+                match quarantine {
+                    Some(q) => move_block(&*archive, q, block_hash).await?,
+                    None => archive.remove_block(block_hash).await?,
+                }
+                #[cfg(feature = "metrics")]
+                crate::metrics::record_gc(size);
+            }
+            summary.collected.push(*block_hash);
+            summary.bytes += size;
+        }
+    }
+    Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hex::FromHex;
+    use crate::MemoryBlockArchive;
+    use super::*;
+
+    // the easiest target, as used on regtest
+    const EASY_BITS: u32 = 0x207fffff;
+
+    // Store a block that is only a header with the given parent, returning its hash.
+    async fn store_header(archive: &MemoryBlockArchive, prev_hash: &BlockHash, nonce: u32) -> BlockHash {
+        let mut b = Vec::new();
+        b.extend_from_slice(&1u32.to_le_bytes());
+        b.extend_from_slice(&prev_hash.hash);
+        b.extend_from_slice(&[0u8; 32]);
+        b.extend_from_slice(&0u32.to_le_bytes());
+        b.extend_from_slice(&EASY_BITS.to_le_bytes());
+        b.extend_from_slice(&nonce.to_le_bytes());
+        let block_hash = BlockHash::sha256d(&b);
+        archive.store_block(&block_hash, &mut &b[..]).await.unwrap();
+        block_hash
+    }
+
+    // A deep fork is collected, a recent fork and an orphan are kept, and nothing is changed in
+    // a dry run.
+    #[tokio::test]
+    async fn test_collect_garbage() {
+        let mut archive = MemoryBlockArchive::new();
+        let zero = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+        let mut best = vec![store_header(&archive, &zero, 0).await];
+        for i in 1..=5 {
+            let prev_hash = best[i - 1];
+            best.push(store_header(&archive, &prev_hash, i as u32).await);
+        }
+        let b2 = store_header(&archive, &best[1], 10).await;
+        let b3 = store_header(&archive, &b2, 11).await;
+        let c4 = store_header(&archive, &best[3], 12).await;
+        let orphan = store_header(&archive, &BlockHash::from_hex(format!("{:064x}", 1)).unwrap(), 13).await;
+        let chain = ChainIndex::build(&mut archive).await.unwrap();
+
+        let summary = collect_garbage(&mut archive, &chain, 3, None, false).await.unwrap();
+        assert!(summary.collected.is_empty());
+        let summary = collect_garbage(&mut archive, &chain, 2, None, true).await.unwrap();
+        assert_eq!(summary.collected, vec![b3, b2]);
+        assert_eq!(summary.bytes, 160);
+        assert!(archive.block_exists(&b3).await.unwrap());
+
+        let quarantine = MemoryBlockArchive::new();
+        let summary = collect_garbage(&mut archive, &chain, 2, Some(&quarantine as &dyn BlockArchive), false).await.unwrap();
+        assert_eq!(summary.collected, vec![b3, b2]);
+        assert!(!archive.block_exists(&b2).await.unwrap());
+        assert!(quarantine.block_exists(&b2).await.unwrap());
+        assert!(archive.block_exists(&c4).await.unwrap());
+        assert!(archive.block_exists(&orphan).await.unwrap());
+        assert_eq!(summary.to_json()["bytes"], 160);
+    }
+}
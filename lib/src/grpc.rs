@@ -0,0 +1,430 @@
+//! Remote access to an archive over gRPC.
+//!
+//! [serve] makes an archive available over gRPC, using the service defined in
+//! `proto/blockarchive.proto`. [RemoteBlockArchive] is a client for the service which implements
+//! the [BlockArchive] trait, so a remote archive can be used in the same way as a local one.
+//!
+//! Blocks are sent in chunks in both directions, so a block is never held in memory in full.
+//!
+//! Example code:
+//!     let listener = TcpListener::bind("127.0.0.1:50051").await?;
+//!     grpc::serve(archive, listener).await?;
+//!
+//!     let remote = RemoteBlockArchive::connect("http://127.0.0.1:50051").await?;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use hex::FromHex;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, RwLock};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::{Channel, Server};
+use tonic::{Code, Request, Response, Status, Streaming};
+use crate::block_archive::{BlockHashListStream, BlockHashListStreamFromChannel};
+use crate::headers::HEADER_SIZE;
+use crate::{BlockArchive, BlockAttrs, Error, Result};
+
+/// The code generated from `proto/blockarchive.proto`.
+pub mod proto {
+    tonic::include_proto!("blockarchive");
+}
+
+use proto::block_archive_service_client::BlockArchiveServiceClient;
+use proto::block_archive_service_server::{BlockArchiveService, BlockArchiveServiceServer};
+use proto::{BlockChunk, BlockRequest, Empty, ExistsResponse, HeaderResponse, RemoveBlockAttrRequest,
+            SetBlockAttrRequest, SizeResponse, StoreBlockChunk, VerifyResponse};
+
+// the size of the chunks that blocks are sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// the number of chunks or hashes that are buffered between a task and a stream
+const CHANNEL_SIZE: usize = 16;
+
+type RpcResult<T> = std::result::Result<T, Status>;
+
+/// Serve the archive over gRPC, until an error occurs.
+pub async fn serve<A: BlockArchive + 'static>(archive: A, listener: TcpListener) -> Result<()> {
+    Server::builder()
+        .add_service(BlockArchiveServiceServer::new(GrpcBlockArchiveService::new(archive)))
+        .serve_with_incoming(TcpListenerStream::new(listener)).await
+        .map_err(|e| Error::RemoteError(e.to_string()))
+}
+
+/// The gRPC service for an archive, for use with a tonic server that provides other services
+/// as well. [serve] is simpler if the archive is the only service.
+pub struct GrpcBlockArchiveService<A: BlockArchive> {
+    // block_list() needs exclusive access, everything else can share the archive
+    archive: Arc<RwLock<A>>,
+}
+
+impl<A: BlockArchive> GrpcBlockArchiveService<A> {
+    /// Serve an archive.
+    pub fn new(archive: A) -> GrpcBlockArchiveService<A> {
+        GrpcBlockArchiveService { archive: Arc::new(RwLock::new(archive)) }
+    }
+}
+
+#[tonic::async_trait]
+impl<A: BlockArchive + 'static> BlockArchiveService for GrpcBlockArchiveService<A> {
+    type GetBlockStream = ReceiverStream<RpcResult<BlockChunk>>;
+
+    async fn get_block(&self, request: Request<BlockRequest>) -> RpcResult<Response<Self::GetBlockStream>> {
+        let block_hash = parse_hash(&request.get_ref().block_hash)?;
+        let mut reader = self.archive.read().await.get_block(&block_hash).await.map_err(to_status)?;
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            loop {
+                let chunk = match reader.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => Ok(BlockChunk { data: buf[..n].to_vec() }),
+                    Err(e) => Err(to_status(e.into())),
+                };
+                let failed = chunk.is_err();
+                // stop if the client has gone away
+                if tx.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_header(&self, request: Request<BlockRequest>) -> RpcResult<Response<HeaderResponse>> {
+        let block_hash = parse_hash(&request.get_ref().block_hash)?;
+        let mut header = Vec::new();
+        self.archive.read().await.get_block_range(&block_hash, 0, HEADER_SIZE as u64).await.map_err(to_status)?
+            .read_to_end(&mut header).await.map_err(|e| to_status(e.into()))?;
+        Ok(Response::new(HeaderResponse { header }))
+    }
+
+    async fn block_exists(&self, request: Request<BlockRequest>) -> RpcResult<Response<ExistsResponse>> {
+        let block_hash = parse_hash(&request.get_ref().block_hash)?;
+        let exists = self.archive.read().await.block_exists(&block_hash).await.map_err(to_status)?;
+        Ok(Response::new(ExistsResponse { exists }))
+    }
+
+    async fn block_size(&self, request: Request<BlockRequest>) -> RpcResult<Response<SizeResponse>> {
+        let block_hash = parse_hash(&request.get_ref().block_hash)?;
+        let size = self.archive.read().await.block_size(&block_hash).await.map_err(to_status)?;
+        Ok(Response::new(SizeResponse { size: size as u64 }))
+    }
+
+    async fn store_block(&self, request: Request<Streaming<StoreBlockChunk>>) -> RpcResult<Response<Empty>> {
+        let mut stream = request.into_inner();
+        let first = stream.message().await?.ok_or_else(|| Status::invalid_argument("no block was sent"))?;
+        let block_hash = parse_hash(&first.block_hash)?;
+        let mut reader = ChunkReader::new(stream.map(|c| c.map(|c| c.data)), first.data);
+        self.archive.read().await.store_block(&block_hash, &mut reader).await.map_err(to_status)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn remove_block(&self, request: Request<BlockRequest>) -> RpcResult<Response<Empty>> {
+        let block_hash = parse_hash(&request.get_ref().block_hash)?;
+        self.archive.read().await.remove_block(&block_hash).await.map_err(to_status)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    type ListBlocksStream = ReceiverStream<RpcResult<BlockRequest>>;
+
+    async fn list_blocks(&self, _request: Request<Empty>) -> RpcResult<Response<Self::ListBlocksStream>> {
+        let mut results = self.archive.write().await.block_list().await.map_err(to_status)?;
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        tokio::spawn(async move {
+            while let Some(block_hash) = results.next().await {
+                if tx.send(Ok(BlockRequest { block_hash: block_hash.to_string() })).await.is_err() {
+                    return;
+                }
+            }
+            if let Some(e) = results.as_mut().take_error() {
+                let _ = tx.send(Err(to_status(e))).await;
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn get_block_attrs(&self, request: Request<BlockRequest>) -> RpcResult<Response<proto::BlockAttrs>> {
+        let block_hash = parse_hash(&request.get_ref().block_hash)?;
+        let attrs = self.archive.read().await.get_block_attrs(&block_hash).await.map_err(to_status)?;
+        Ok(Response::new(proto::BlockAttrs { attrs: attrs.into_iter().collect() }))
+    }
+
+    async fn set_block_attr(&self, request: Request<SetBlockAttrRequest>) -> RpcResult<Response<Empty>> {
+        let request = request.get_ref();
+        let block_hash = parse_hash(&request.block_hash)?;
+        self.archive.read().await.set_block_attr(&block_hash, &request.key, &request.value).await.map_err(to_status)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn remove_block_attr(&self, request: Request<RemoveBlockAttrRequest>) -> RpcResult<Response<Empty>> {
+        let request = request.get_ref();
+        let block_hash = parse_hash(&request.block_hash)?;
+        self.archive.read().await.remove_block_attr(&block_hash, &request.key).await.map_err(to_status)?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn verify_checksum(&self, request: Request<BlockRequest>) -> RpcResult<Response<VerifyResponse>> {
+        let block_hash = parse_hash(&request.get_ref().block_hash)?;
+        let valid = self.archive.read().await.verify_checksum(&block_hash).await.map_err(to_status)?;
+        Ok(Response::new(VerifyResponse { valid }))
+    }
+}
+
+// Parse a block hash from a request.
+fn parse_hash(s: &str) -> RpcResult<BlockHash> {
+    BlockHash::from_hex(s).map_err(|_| to_status(Error::InvalidHash(s.to_string())))
+}
+
+// Get the status that is sent to the client for an error.
+fn to_status(e: Error) -> Status {
+    let code = match e {
+        Error::BlockNotFound => Code::NotFound,
+        Error::BlockExists => Code::AlreadyExists,
+        Error::InvalidHash(_) | Error::InvalidAttribute(_) | Error::InvalidBlock(_) => Code::InvalidArgument,
+        Error::CorruptBlock(_) | Error::ChecksumMismatch(_) => Code::DataLoss,
+        Error::StorageUnavailable(_) => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    Status::new(code, e.to_string())
+}
+
+// Get the error for a status received from the server, for a request about a block.
+fn from_status(status: Status, block_hash: &BlockHash) -> Error {
+    match status.code() {
+        Code::NotFound => Error::BlockNotFound,
+        Code::AlreadyExists => Error::BlockExists,
+        Code::InvalidArgument => Error::InvalidBlock(status.message().to_string()),
+        Code::DataLoss => Error::CorruptBlock(*block_hash),
+        Code::Unavailable => Error::StorageUnavailable(status.message().to_string()),
+        _ => Error::RemoteError(status.message().to_string()),
+    }
+}
+
+/// A block archive on a server that is reached over gRPC, see [serve].
+///
+/// Cloning the archive is cheap, the clones share the connection.
+#[derive(Clone)]
+pub struct RemoteBlockArchive {
+    client: BlockArchiveServiceClient<Channel>,
+}
+
+impl RemoteBlockArchive {
+    /// Connect to a server, for example "http://127.0.0.1:50051".
+    pub async fn connect(url: &str) -> Result<RemoteBlockArchive> {
+        let client = BlockArchiveServiceClient::connect(url.to_string()).await
+            .map_err(|e| Error::StorageUnavailable(format!("{}: {}", url, e)))?;
+        Ok(RemoteBlockArchive { client })
+    }
+
+    // Make a request about a block.
+    fn block_request(block_hash: &BlockHash) -> BlockRequest {
+        BlockRequest { block_hash: block_hash.to_string() }
+    }
+}
+
+#[async_trait]
+impl BlockArchive for RemoteBlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let stream = self.client.clone().get_block(Self::block_request(block_hash)).await
+            .map_err(|s| from_status(s, block_hash))?.into_inner();
+        Ok(Box::new(ChunkReader::new(stream.map(|c| c.map(|c| c.data)), Vec::new())))
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        let response = self.client.clone().block_exists(Self::block_request(block_hash)).await
+            .map_err(|s| from_status(s, block_hash))?;
+        Ok(response.get_ref().exists)
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        let mut client = self.client.clone();
+        let request = client.store_block(ReceiverStream::new(rx));
+        let send = async move {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            // the hash is sent with the first chunk, which is sent even if the block is empty
+            let mut hash = block_hash.to_string();
+            loop {
+                let n = block.read(&mut buf).await?;
+                if n == 0 && hash.is_empty() {
+                    break;
+                }
+                let chunk = StoreBlockChunk { block_hash: std::mem::take(&mut hash), data: buf[..n].to_vec() };
+                // the server has stopped reading, its response gives the reason
+                if tx.send(chunk).await.is_err() || n == 0 {
+                    break;
+                }
+            }
+            Ok::<(), Error>(())
+        };
+        tokio::pin!(request, send);
+        // if the block can not be read then the request is dropped, which cancels it so that the
+        // server does not store a partial block
+        let response = tokio::select! {
+            r = &mut request => r,
+            r = &mut send => {
+                r?;
+                request.await
+            }
+        };
+        response.map_err(|s| from_status(s, block_hash))?;
+        Ok(())
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.client.clone().remove_block(Self::block_request(block_hash)).await
+            .map_err(|s| from_status(s, block_hash))?;
+        Ok(())
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        let response = self.client.clone().block_size(Self::block_request(block_hash)).await
+            .map_err(|s| from_status(s, block_hash))?;
+        Ok(response.get_ref().size as usize)
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        let response = self.client.clone().get_header(Self::block_request(block_hash)).await
+            .map_err(|s| from_status(s, block_hash))?;
+        Ok(BlockHeader::from_binary(&mut &response.get_ref().header[..]).await?)
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let mut stream = self.client.clone().list_blocks(Empty {}).await
+            .map_err(|s| Error::RemoteError(s.message().to_string()))?.into_inner();
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        let handle = tokio::spawn(async move {
+            while let Some(m) = stream.message().await.map_err(|s| Error::RemoteError(s.message().to_string()))? {
+                let block_hash = BlockHash::from_hex(&m.block_hash).map_err(|_| Error::InvalidHash(m.block_hash.clone()))?;
+                if tx.send(block_hash).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        let request = SetBlockAttrRequest { block_hash: block_hash.to_string(), key: key.to_string(), value: value.to_string() };
+        self.client.clone().set_block_attr(request).await.map_err(|s| match s.code() {
+            Code::InvalidArgument => Error::InvalidAttribute(s.message().to_string()),
+            _ => from_status(s, block_hash),
+        })?;
+        Ok(())
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        let request = RemoveBlockAttrRequest { block_hash: block_hash.to_string(), key: key.to_string() };
+        self.client.clone().remove_block_attr(request).await.map_err(|s| match s.code() {
+            Code::InvalidArgument => Error::InvalidAttribute(s.message().to_string()),
+            _ => from_status(s, block_hash),
+        })?;
+        Ok(())
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        let response = self.client.clone().get_block_attrs(Self::block_request(block_hash)).await
+            .map_err(|s| from_status(s, block_hash))?;
+        Ok(response.into_inner().attrs.into_iter().collect())
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        let response = self.client.clone().verify_checksum(Self::block_request(block_hash)).await
+            .map_err(|s| match s.code() {
+                Code::DataLoss => Error::ChecksumMismatch(*block_hash),
+                _ => from_status(s, block_hash),
+            })?;
+        Ok(response.get_ref().valid)
+    }
+}
+
+// Reads the data from a stream of chunks.
+struct ChunkReader<S> {
+    stream: S,
+    // the chunk being read, and the position in it
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> ChunkReader<S> {
+    // Read the first chunk, which has already been received, and then the rest of the stream.
+    fn new(stream: S, first: Vec<u8>) -> ChunkReader<S> {
+        ChunkReader { stream, chunk: first, pos: 0 }
+    }
+}
+
+impl<S: Stream<Item=RpcResult<Vec<u8>>> + Unpin> AsyncRead for ChunkReader<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            if self.pos < self.chunk.len() {
+                let n = buf.remaining().min(self.chunk.len() - self.pos);
+                buf.put_slice(&self.chunk[self.pos..self.pos + n]);
+                self.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Poll::Ready(Some(Err(status))) => return Poll::Ready(Err(std::io::Error::other(status.message().to_string()))),
+                // the end of the block
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // A block stored through the client can be read back, listed, and removed, and errors from
+    // the server are mapped back to the errors of the archive.
+    #[tokio::test]
+    async fn test_remote_archive() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(MemoryBlockArchive::new(), listener));
+        let mut remote = RemoteBlockArchive::connect(&format!("http://{}", addr)).await.unwrap();
+
+        assert!(!remote.block_exists(&h).await.unwrap());
+        assert!(matches!(remote.get_block(&h).await, Err(Error::BlockNotFound)));
+        remote.store_block(&h, &mut &block[..]).await.unwrap();
+        assert!(matches!(remote.store_block(&h, &mut &block[..]).await, Err(Error::BlockExists)));
+        assert!(remote.block_exists(&h).await.unwrap());
+        assert_eq!(remote.block_size(&h).await.unwrap(), block.len());
+        assert_eq!(remote.block_header(&h).await.unwrap().hash(), h);
+        let mut read = Vec::new();
+        remote.get_block(&h).await.unwrap().read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, block);
+        assert!(remote.verify_checksum(&h).await.unwrap());
+
+        remote.set_block_attr(&h, "source", "test").await.unwrap();
+        assert_eq!(remote.get_block_attrs(&h).await.unwrap().get("source").map(String::as_str), Some("test"));
+        remote.remove_block_attr(&h, "source").await.unwrap();
+        assert!(remote.get_block_attrs(&h).await.unwrap().is_empty());
+
+        let mut results = remote.block_list().await.unwrap();
+        assert_eq!(results.next().await, Some(h));
+        assert_eq!(results.next().await, None);
+        assert!(results.as_mut().take_error().is_none());
+        drop(results);
+
+        remote.remove_block(&h).await.unwrap();
+        assert!(matches!(remote.remove_block(&h).await, Err(Error::BlockNotFound)));
+    }
+}
@@ -0,0 +1,283 @@
+//! Archives that hold only the headers of blocks.
+use std::path::Path;
+use std::pin::Pin;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use hex::{FromHex, ToHex};
+use crate::block_archive::{BlockHashListStream, BlockHashListStreamFromChannel};
+use crate::headers::HEADER_SIZE;
+use crate::{Error, Result};
+
+// the name of the tree which holds the tip of a chain-linked archive
+const META_TREE: &str = "meta";
+const TIP_KEY: &str = "tip";
+
+// the size of the channel used to send block hashes
+const LIST_BUFFER: usize = 1000;
+
+/// An archive of block headers, without the rest of the blocks.
+///
+/// A header archive is for deployments that need the chain of headers, for example to prove that
+/// a block is in the chain, but not the transactions. It can be filled from a full archive with
+/// [crate::sync::sync_headers] or from a node with [crate::fetch::fetch_headers].
+#[async_trait]
+pub trait HeaderArchive: Send + Sync {
+    /// Store the encoded header of a block, returning the block hash.
+    ///
+    /// Fails with [Error::BlockExists] if the header is already in the archive.
+    async fn store_header(&self, header: &[u8; 80]) -> Result<BlockHash>;
+
+    /// Get the encoded header of a block.
+    ///
+    /// Fails with [Error::BlockNotFound] if the header is not in the archive.
+    async fn get_header(&self, block_hash: &BlockHash) -> Result<[u8; 80]>;
+
+    /// Get the decoded header of a block.
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        let header = self.get_header(block_hash).await?;
+        Ok(BlockHeader::from_binary(&mut &header[..]).await?)
+    }
+
+    /// Check if the header of a block is in the archive.
+    async fn header_exists(&self, block_hash: &BlockHash) -> Result<bool>;
+
+    /// Remove the header of a block from the archive.
+    ///
+    /// Fails with [Error::BlockNotFound] if the header is not in the archive.
+    async fn remove_header(&self, block_hash: &BlockHash) -> Result<()>;
+
+    /// Get a list of the hashes of the blocks whose headers are in the archive.
+    async fn header_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>>;
+
+    /// Get the height of a block, or None if the archive does not link the headers into a chain.
+    async fn header_height(&self, _block_hash: &BlockHash) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Get the hash and height of the highest header, or None if the archive is empty or does
+    /// not link the headers into a chain.
+    async fn tip(&self) -> Result<Option<(BlockHash, u64)>> {
+        Ok(None)
+    }
+}
+
+/// A header archive in a sled database in a local directory.
+///
+/// With chain linkage, a header is only stored once the header of its parent is in the archive,
+/// unless it is a genesis block, and the height of each header and the highest header are
+/// recorded. Use the same setting every time the archive is opened, headers stored without chain
+/// linkage do not have a height and so cannot be the parent of a header stored with it.
+///
+/// Example code:
+///     let archive = SledHeaderArchive::open(Path::new("/mnt/headers"))?.with_chain_linkage(true);
+///     archive.store_header(&header).await?;
+pub struct SledHeaderArchive {
+    // maps the hex block hash to the header, followed by the height with chain linkage
+    db: sled::Db,
+    // holds the hex hash and height of the tip with chain linkage
+    meta: sled::Tree,
+    linked: bool,
+}
+
+impl SledHeaderArchive {
+    /// Open the header archive in the given directory, creating it if necessary.
+    pub fn open(path: &Path) -> Result<SledHeaderArchive> {
+        let db = sled::open(path).map_err(header_error)?;
+        let meta = db.open_tree(META_TREE).map_err(header_error)?;
+        Ok(SledHeaderArchive { db, meta, linked: false })
+    }
+
+    /// Set whether headers are linked into a chain, the default is not to link them.
+    ///
+    /// A header whose parent is not in the archive is then rejected with [Error::MissingParent].
+    pub fn with_chain_linkage(mut self, linked: bool) -> SledHeaderArchive {
+        self.linked = linked;
+        self
+    }
+
+    /// Flush the database to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush().map_err(header_error)?;
+        Ok(())
+    }
+
+    fn get_value(&self, block_hash: &BlockHash) -> Result<Option<sled::IVec>> {
+        let key: String = block_hash.encode_hex();
+        self.db.get(key).map_err(header_error)
+    }
+
+    fn height_of(&self, block_hash: &BlockHash) -> Result<Option<u64>> {
+        Ok(self.get_value(block_hash)?.and_then(|v| decode_height(&v)))
+    }
+
+    // Record the header as the tip if it is higher than the current tip.
+    fn update_tip(&self, block_hash: &BlockHash, height: u64) -> Result<()> {
+        let tip = encode_tip(block_hash, height);
+        self.meta.fetch_and_update(TIP_KEY, |current| {
+            match current.and_then(decode_tip) {
+                Some((_, h)) if h >= height => current.map(|c| c.to_vec()),
+                _ => Some(tip.clone()),
+            }
+        }).map_err(header_error)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HeaderArchive for SledHeaderArchive {
+    /// With chain linkage, fails with [Error::MissingParent] if the parent of the header is not in
+    /// the archive.
+    async fn store_header(&self, header: &[u8; 80]) -> Result<BlockHash> {
+        let block_hash = BlockHash::sha256d(header);
+        if self.header_exists(&block_hash).await? {
+            return Err(Error::BlockExists);
+        }
+        let mut value = header.to_vec();
+        let mut height = None;
+        if self.linked {
+            let prev_hash = BlockHeader::from_binary(&mut &header[..]).await?.prev_hash;
+            let h = if prev_hash.hash == [0u8; 32] {
+                0
+            } else {
+                self.height_of(&prev_hash)?.ok_or(Error::MissingParent(prev_hash))? + 1
+            };
+            value.extend_from_slice(&h.to_le_bytes());
+            height = Some(h);
+        }
+        let key: String = block_hash.encode_hex();
+        if self.db.compare_and_swap(key, None::<&[u8]>, Some(value)).map_err(header_error)?.is_err() {
+            // another writer got there first
+            return Err(Error::BlockExists);
+        }
+        if let Some(h) = height {
+            self.update_tip(&block_hash, h)?;
+        }
+        Ok(block_hash)
+    }
+
+    async fn get_header(&self, block_hash: &BlockHash) -> Result<[u8; 80]> {
+        let value = self.get_value(block_hash)?.ok_or(Error::BlockNotFound)?;
+        value.get(..HEADER_SIZE).and_then(|h| h.try_into().ok())
+            .ok_or_else(|| Error::InvalidHeaderArchive(format!("short header for block {}", block_hash)))
+    }
+
+    async fn header_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        let key: String = block_hash.encode_hex();
+        self.db.contains_key(key).map_err(header_error)
+    }
+
+    /// Removing a header does not change the heights of the headers after it. If the header is
+    /// the tip, its parent becomes the tip.
+    async fn remove_header(&self, block_hash: &BlockHash) -> Result<()> {
+        let key: String = block_hash.encode_hex();
+        let value = self.db.remove(key).map_err(header_error)?.ok_or(Error::BlockNotFound)?;
+        if self.tip().await?.map(|(h, _)| h) == Some(*block_hash) {
+            let prev_hash = BlockHeader::from_binary(&mut &value[..HEADER_SIZE]).await?.prev_hash;
+            let updated = match self.height_of(&prev_hash)? {
+                Some(h) => self.meta.insert(TIP_KEY, encode_tip(&prev_hash, h)),
+                None => self.meta.remove(TIP_KEY),
+            };
+            updated.map_err(header_error)?;
+        }
+        Ok(())
+    }
+
+    /// The blocks are listed in order of their hex encoded hash.
+    async fn header_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER);
+        let db = self.db.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            for key in db.iter().keys() {
+                let key = key.map_err(header_error)?;
+                let hash = BlockHash::from_hex(&key[..]).map_err(|_| Error::InvalidHeaderArchive("invalid block hash".to_string()))?;
+                if tx.blocking_send(hash).is_err() {
+                    return Ok(());      // this is not an error, the receiver has merely dropped
+                }
+            }
+            Ok(())
+        });
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn header_height(&self, block_hash: &BlockHash) -> Result<Option<u64>> {
+        self.height_of(block_hash)
+    }
+
+    async fn tip(&self) -> Result<Option<(BlockHash, u64)>> {
+        Ok(self.meta.get(TIP_KEY).map_err(header_error)?.and_then(|v| decode_tip(&v)))
+    }
+}
+
+fn decode_height(value: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(value.get(HEADER_SIZE..HEADER_SIZE + 8)?.try_into().ok()?))
+}
+
+// The tip is the hex hash followed by the height.
+fn encode_tip(block_hash: &BlockHash, height: u64) -> Vec<u8> {
+    let mut tip = block_hash.encode_hex::<String>().into_bytes();
+    tip.extend_from_slice(&height.to_le_bytes());
+    tip
+}
+
+fn decode_tip(value: &[u8]) -> Option<(BlockHash, u64)> {
+    let block_hash = BlockHash::from_hex(value.get(..64)?).ok()?;
+    let height = u64::from_le_bytes(value.get(64..72)?.try_into().ok()?);
+    Some((block_hash, height))
+}
+
+fn header_error(e: sled::Error) -> Error {
+    Error::InvalidHeaderArchive(e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use tokio_stream::StreamExt;
+    use crate::{BlockArchive, Network, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    async fn read_header(archive: &SimpleFileBasedBlockArchive, block_hash: &BlockHash) -> [u8; 80] {
+        let mut header = [0u8; 80];
+        archive.get_block(block_hash).await.unwrap().read_exact(&mut header).await.unwrap();
+        header
+    }
+
+    // Store headers with chain linkage, a header without its parent is rejected.
+    #[tokio::test]
+    async fn test_sled_header_archive() {
+        let blocks = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = Network::Mainnet.genesis_hash();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let mut archive = SledHeaderArchive::open(&dir.to_path_buf()).unwrap().with_chain_linkage(true);
+        assert!(archive.tip().await.unwrap().is_none());
+
+        let header = read_header(&blocks, &h).await;
+        assert!(matches!(archive.store_header(&header).await, Err(Error::MissingParent(_))));
+        let genesis_header = read_header(&blocks, &genesis).await;
+        assert_eq!(archive.store_header(&genesis_header).await.unwrap(), genesis);
+        assert!(matches!(archive.store_header(&genesis_header).await, Err(Error::BlockExists)));
+        assert_eq!(archive.get_header(&genesis).await.unwrap(), genesis_header);
+        assert_eq!(archive.block_header(&genesis).await.unwrap().hash(), genesis);
+        assert_eq!(archive.header_height(&genesis).await.unwrap(), Some(0));
+        assert_eq!(archive.tip().await.unwrap(), Some((genesis, 0)));
+
+        let mut results = archive.header_list().await.unwrap();
+        assert_eq!(results.next().await, Some(genesis));
+        assert_eq!(results.next().await, None);
+        drop(results);
+
+        archive.remove_header(&genesis).await.unwrap();
+        assert!(!archive.header_exists(&genesis).await.unwrap());
+        assert!(archive.tip().await.unwrap().is_none());
+        assert!(matches!(archive.get_header(&genesis).await, Err(Error::BlockNotFound)));
+
+        // without chain linkage any header can be stored
+        let archive = archive.with_chain_linkage(false);
+        assert_eq!(archive.store_header(&header).await.unwrap(), h);
+        assert_eq!(archive.header_height(&h).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use crate::Result;
+
+// the size of an encoded block header
+pub(crate) const HEADER_SIZE: usize = 80;
+
+// the size of a record in the headers file, a tag followed by a header or a block hash
+const RECORD_LEN: usize = 1 + HEADER_SIZE;
+
+// the tags of the records
+const TAG_HEADER: u8 = 0;
+const TAG_REMOVED: u8 = 1;
+
+// Encode a block header.
+pub(crate) fn encode_header(header: &BlockHeader) -> [u8; HEADER_SIZE] {
+    let mut encoded = [0u8; HEADER_SIZE];
+    encoded[..4].copy_from_slice(&header.version.to_le_bytes());
+    encoded[4..36].copy_from_slice(&header.prev_hash.hash);
+    encoded[36..68].copy_from_slice(&header.merkle_root.hash);
+    encoded[68..72].copy_from_slice(&header.timestamp.to_le_bytes());
+    encoded[72..76].copy_from_slice(&header.bits.to_le_bytes());
+    encoded[76..].copy_from_slice(&header.nonce.to_le_bytes());
+    encoded
+}
+
+/// A file holding the headers of all the blocks in an archive, so that headers can be found
+/// without opening each block.
+///
+/// The file is append-only, a record is added for each block that is stored and for each block
+/// that is removed. The file is only read when a header is first needed, and then kept in memory.
+/// A partial record at the end of the file, from an interrupted append, is ignored and
+/// overwritten by the next append.
+#[derive(Debug)]
+pub(crate) struct HeadersFile {
+    path: PathBuf,
+    // the headers keyed by block hash, once the file has been read
+    headers: Option<BTreeMap<[u8; 32], BlockHeader>>,
+}
+
+impl HeadersFile {
+    pub(crate) fn new(path: PathBuf) -> HeadersFile {
+        HeadersFile { path, headers: None }
+    }
+
+    // Check whether the file exists. Nothing is recorded if it does not.
+    pub(crate) async fn exists(&self) -> Result<bool> {
+        Ok(tokio::fs::try_exists(&self.path).await?)
+    }
+
+    // Get the header of a block, or None if the file does not exist or does not have the block.
+    pub(crate) async fn get(&mut self, block_hash: &BlockHash) -> Result<Option<BlockHeader>> {
+        if self.headers.is_none() {
+            self.headers = self.read().await?;
+        }
+        Ok(self.headers.as_ref().and_then(|h| h.get(&block_hash.hash).cloned()))
+    }
+
+    // Record the header of a stored block, if the file exists.
+    pub(crate) async fn add(&mut self, header: &[u8; HEADER_SIZE]) -> Result<()> {
+        if !self.exists().await? {
+            return Ok(());
+        }
+        let mut record = vec![TAG_HEADER];
+        record.extend_from_slice(header);
+        self.append(&record).await?;
+        if let Some(headers) = self.headers.as_mut() {
+            let header = BlockHeader::from_binary(&mut &header[..]).await?;
+            headers.insert(header.hash().hash, header);
+        }
+        Ok(())
+    }
+
+    // Record that a block has been removed, if the file exists.
+    pub(crate) async fn remove(&mut self, block_hash: &BlockHash) -> Result<()> {
+        if !self.exists().await? {
+            return Ok(());
+        }
+        let mut record = vec![0u8; RECORD_LEN];
+        record[0] = TAG_REMOVED;
+        record[1..33].copy_from_slice(&block_hash.hash);
+        self.append(&record).await?;
+        if let Some(headers) = self.headers.as_mut() {
+            headers.remove(&block_hash.hash);
+        }
+        Ok(())
+    }
+
+    // Replace the file with one holding the given headers, creating it if it does not exist.
+    pub(crate) async fn write(&mut self, headers: &[[u8; HEADER_SIZE]]) -> Result<()> {
+        let mut buf = Vec::with_capacity(headers.len() * RECORD_LEN);
+        for header in headers {
+            buf.push(TAG_HEADER);
+            buf.extend_from_slice(header);
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(&buf).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        // read the new file when it is next needed
+        self.headers = None;
+        Ok(())
+    }
+
+    // Read the file, returning None if it does not exist.
+    async fn read(&self) -> Result<Option<BTreeMap<[u8; 32], BlockHeader>>> {
+        let data = match tokio::fs::read(&self.path).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut headers = BTreeMap::new();
+        for record in data.chunks_exact(RECORD_LEN) {
+            if record[0] == TAG_REMOVED {
+                headers.remove(&record[1..33]);
+            } else {
+                let header = BlockHeader::from_binary(&mut &record[1..]).await?;
+                headers.insert(header.hash().hash, header);
+            }
+        }
+        Ok(Some(headers))
+    }
+
+    // Append a record, first cutting off any partial record left by an interrupted append.
+    async fn append(&self, record: &[u8]) -> Result<()> {
+        let mut file = OpenOptions::new().write(true).open(&self.path).await?;
+        let len = file.metadata().await?.len();
+        let whole = len - len % RECORD_LEN as u64;
+        if whole != len {
+            file.set_len(whole).await?;
+        }
+        file.seek(std::io::SeekFrom::End(0)).await?;
+        file.write_all(record).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use crate::{BlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // Nothing is recorded until the file is written, and removals and partial records are
+    // handled when the file is read back.
+    #[tokio::test]
+    async fn test_headers_file() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut header = [0u8; HEADER_SIZE];
+        archive.get_block(&h).await.unwrap().read_exact(&mut header).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let path = dir.to_path_buf().join("headers.dat");
+        let mut file = HeadersFile::new(path.clone());
+        file.add(&header).await.unwrap();
+        assert!(!file.exists().await.unwrap());
+        assert!(file.get(&h).await.unwrap().is_none());
+
+        file.write(&[]).await.unwrap();
+        file.add(&header).await.unwrap();
+        assert_eq!(file.get(&h).await.unwrap().unwrap().hash(), h);
+        file.remove(&h).await.unwrap();
+        assert!(file.get(&h).await.unwrap().is_none());
+        // a partial record is overwritten by the next append
+        let mut f = OpenOptions::new().append(true).open(&path).await.unwrap();
+        f.write_all(&[TAG_HEADER, 1, 2, 3]).await.unwrap();
+        drop(f);
+        file.add(&header).await.unwrap();
+        assert_eq!(tokio::fs::metadata(&path).await.unwrap().len(), 3 * RECORD_LEN as u64);
+        let mut reopened = HeadersFile::new(path);
+        assert_eq!(reopened.get(&h).await.unwrap().unwrap().hash(), h);
+    }
+}
@@ -0,0 +1,788 @@
+//! A minimal HTTP server that serves the blocks in an archive.
+//!
+//! The server understands the following requests:
+//!
+//! ```text
+//! GET /block/{hash}               the encoded block, supports a single byte range
+//! GET /block/{hash}?format=hex    the encoded block, hex encoded
+//! HEAD /block/{hash}              whether the block exists, with its size as the content length
+//! GET /block/{hash}/size          the size of the block in bytes
+//! GET /block/{hash}/attrs         the attributes of the block, as key=value lines
+//! GET /block/{hash}/verify        true if the block matches its checksum, otherwise false
+//! GET /header/{hash}              the encoded block header, add ?format=hex for hex
+//! GET /block/{hash}/proof/{txid}  a merkle proof that the transaction is in the block, as the
+//!                                 JSON of the TSC merkle proof standard
+//! GET /blocks                     the hashes of all blocks, one per line
+//! ```
+//!
+//! The responses to GET and HEAD requests allow any origin, so that a web page or worker can read
+//! the archive directly.
+//!
+//! A server started with [serve_writable] also accepts changes to the archive:
+//!
+//! ```text
+//! PUT /block/{hash}               store the block in the body, which may be chunked
+//! DELETE /block/{hash}            remove the block
+//! PUT /block/{hash}/attrs/{key}   set an attribute to the body, the key is percent encoded
+//! DELETE /block/{hash}/attrs/{key}    remove an attribute
+//! ```
+//!
+//! A server started with [serve_with_events] also streams the events of the archive:
+//!
+//! ```text
+//! GET /events                     server-sent events, one for each [BlockEvent] as it happens
+//! ```
+//!
+//! Each event has the name of the event as its type and [BlockEvent::to_json] as its data. A
+//! comment is sent when there have been no events for a while, so that proxies keep the stream
+//! open. A client that falls too far behind misses events.
+//!
+//! Each connection handles a single request, unless the request has a `Connection: keep-alive`
+//! header, in which case the connection is kept open for further requests.
+//! [crate::HttpBlockArchive] is a client for the server, and the bsv-blockarchive-wasm crate has a
+//! read-only client for web pages and workers.
+//!
+//! Example code:
+//!     let listener = TcpListener::bind("127.0.0.1:8080").await?;
+//!     http::serve(archive, listener).await?;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use bitcoinsv::bitcoin::BlockHash;
+use hex::FromHex;
+use tracing::debug;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, RwLock};
+use tokio::time::{timeout, Duration};
+use tokio_stream::StreamExt;
+use crate::block_archive::encode_block_attrs;
+use crate::events::BlockEvent;
+use crate::merkle::merkle_proof;
+use crate::{BlockArchive, Error, Result};
+
+// the longest request line or header line that will be accepted
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+// the most header lines that will be accepted
+const MAX_HEADERS: usize = 100;
+
+// the largest request body that will be accepted
+const MAX_BODY_LEN: usize = 1024 * 1024;
+
+// the size of an encoded block header
+const HEADER_SIZE: u64 = 80;
+
+// the size of the chunks used when hex encoding a block
+const HEX_CHUNK_SIZE: usize = 64 * 1024;
+
+// how long an event stream can be quiet before a comment is sent to keep it open
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// An HTTP request.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    // header names are lower case
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    // Get the value of a header.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    // Get the value of a query parameter.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.split('&').filter_map(|p| p.split_once('=')).find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+}
+
+/// Serve the blocks in the archive over HTTP, until an error occurs accepting a connection.
+pub async fn serve<A: BlockArchive + 'static>(archive: A, listener: TcpListener) -> Result<()> {
+    serve_archive(archive, listener, ServerOptions { writable: false, events: None }).await
+}
+
+/// Serve the blocks in the archive over HTTP, and accept requests that store and remove blocks
+/// and change their attributes, until an error occurs accepting a connection.
+///
+/// Anyone who can connect to the server can change the archive.
+pub async fn serve_writable<A: BlockArchive + 'static>(archive: A, listener: TcpListener) -> Result<()> {
+    serve_archive(archive, listener, ServerOptions { writable: true, events: None }).await
+}
+
+/// Serve the blocks in the archive over HTTP, and stream the events sent to `events` to the
+/// clients of `GET /events`, until an error occurs accepting a connection. The server also accepts
+/// changes to the archive if `writable` is true, see [serve_writable].
+///
+/// The events usually come from a [crate::events::NotifyingBlockArchive] that wraps the archive,
+/// with a listener that sends them to the channel.
+pub async fn serve_with_events<A: BlockArchive + 'static>(archive: A, listener: TcpListener, writable: bool, events: broadcast::Sender<BlockEvent>) -> Result<()> {
+    serve_archive(archive, listener, ServerOptions { writable, events: Some(events) }).await
+}
+
+// What a server allows.
+struct ServerOptions {
+    // true if the archive can be changed
+    writable: bool,
+    // the events streamed from /events, if it is served
+    events: Option<broadcast::Sender<BlockEvent>>,
+}
+
+async fn serve_archive<A: BlockArchive + 'static>(archive: A, listener: TcpListener, options: ServerOptions) -> Result<()> {
+    // block_list() needs exclusive access, everything else can share the archive
+    let archive = Arc::new(RwLock::new(archive));
+    let options = Arc::new(options);
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let archive = archive.clone();
+        let options = options.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&archive, &options, socket).await {
+                debug!(%addr, error = %e, "error handling request");
+            }
+        });
+    }
+}
+
+// The response to a request, which keeps track of whether the connection can be used for
+// another request.
+struct Response<'a, W> {
+    writer: &'a mut W,
+    // true if the connection is kept open after the response
+    keep_alive: bool,
+    // true if the response is to a HEAD request, which does not have a body
+    head: bool,
+    // true if the response can be read by a page from any origin
+    cors: bool,
+    // true once the status line has been written
+    started: bool,
+}
+
+impl<W: AsyncWrite + Unpin + Send> Response<'_, W> {
+    // Write the status line and headers.
+    async fn status(&mut self, status: u16, content_type: &str, content_length: Option<u64>, headers: &[(&str, &str)]) -> Result<()> {
+        self.started = true;
+        let connection = if self.keep_alive { "keep-alive" } else { "close" };
+        if self.cors {
+            let headers = [headers, &[("Access-Control-Allow-Origin", "*")]].concat();
+            return write_status(self.writer, status, content_type, content_length, connection, &headers).await;
+        }
+        write_status(self.writer, status, content_type, content_length, connection, headers).await
+    }
+
+    // Write a complete response.
+    async fn send(&mut self, status: u16, content_type: &str, body: &[u8]) -> Result<()> {
+        self.status(status, content_type, Some(body.len() as u64), &[]).await?;
+        if !self.head {
+            self.writer.write_all(body).await?;
+        }
+        Ok(())
+    }
+}
+
+// Read requests and write the responses, until the client closes the connection or does not ask
+// for it to be kept open.
+async fn handle_connection<A: BlockArchive>(archive: &RwLock<A>, options: &ServerOptions, socket: TcpStream) -> Result<()> {
+    let (reader, writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    loop {
+        let request = match read_request_head(&mut reader).await? {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let keep_alive = request.header("connection").is_some_and(|v| v.eq_ignore_ascii_case("keep-alive"));
+        let cors = request.method == "GET" || request.method == "HEAD";
+        let mut response = Response { writer: &mut writer, keep_alive, head: request.method == "HEAD", cors, started: false };
+        if let Err(e) = handle_request(archive, options, request, &mut reader, &mut response).await {
+            // the status line may already have been sent, but try anyway
+            if response.started {
+                response.keep_alive = false;
+            }
+            let (status, msg) = match e {
+                Error::BlockNotFound => (404, "block not found".to_string()),
+                Error::TxNotFound => (404, "transaction not found".to_string()),
+                Error::BlockExists => (409, e.to_string()),
+                Error::InvalidHash(_) | Error::InvalidAttribute(_) | Error::InvalidBlock(_) => (400, e.to_string()),
+                Error::StorageUnavailable(_) => (503, e.to_string()),
+                _ => (500, e.to_string()),
+            };
+            response.send(status, "text/plain", msg.as_bytes()).await?;
+        }
+        let keep_alive = response.keep_alive;
+        writer.flush().await?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// Read the body of a request and dispatch it. The connection is not kept open if the body can
+// not be read in full.
+async fn handle_request<A, R, W>(archive: &RwLock<A>, options: &ServerOptions, mut request: HttpRequest, reader: &mut R, response: &mut Response<'_, W>) -> Result<()>
+    where A: BlockArchive, R: AsyncBufRead + Unpin + Send, W: AsyncWrite + Unpin + Send
+{
+    let mut body = BodyReader::for_message(reader, request.header("transfer-encoding"), request.header("content-length"))?;
+    let path = request.path.clone();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    // a block is read from the body as it is stored, the other bodies are small
+    if options.writable && request.method == "PUT" && segments.len() > 2 {
+        (&mut body).take(MAX_BODY_LEN as u64 + 1).read_to_end(&mut request.body).await?;
+        if request.body.len() > MAX_BODY_LEN {
+            return Err(bad_request("request body too large"));
+        }
+    }
+    let r = dispatch(archive, options, &request, &segments, &mut body, response).await;
+    // read the rest of a body that was not used, such as a block that already exists, so that
+    // the client receives the response rather than a reset connection
+    if tokio::io::copy(&mut body, &mut tokio::io::sink()).await.is_err() {
+        response.keep_alive = false;
+    }
+    r
+}
+
+// Handle a request.
+async fn dispatch<A, R, W>(archive: &RwLock<A>, options: &ServerOptions, request: &HttpRequest, segments: &[&str], body: &mut R, response: &mut Response<'_, W>) -> Result<()>
+    where A: BlockArchive, R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send
+{
+    let hex = request.query_param("format") == Some("hex");
+    match (request.method.as_str(), segments) {
+        ("GET", ["blocks"]) => {
+            let mut results = archive.write().await.block_list().await?;
+            response.status(200, "text/plain", None, &[("Transfer-Encoding", "chunked")]).await?;
+            while let Some(block_hash) = results.next().await {
+                let line = format!("{}\n", block_hash);
+                response.writer.write_all(format!("{:x}\r\n{}\r\n", line.len(), line).as_bytes()).await?;
+            }
+            // without the last chunk the client can tell that the list is incomplete
+            if let Some(e) = results.as_mut().take_error() {
+                return Err(e);
+            }
+            response.writer.write_all(b"0\r\n\r\n").await?;
+            Ok(())
+        }
+        ("GET", ["block", hash]) => {
+            let block_hash = parse_hash(hash)?;
+            let archive = archive.read().await;
+            let size = archive.block_size(&block_hash).await? as u64;
+            if hex {
+                let mut reader = archive.get_block(&block_hash).await?;
+                response.status(200, "text/plain", Some(size * 2), &[]).await?;
+                let mut buf = vec![0u8; HEX_CHUNK_SIZE];
+                loop {
+                    let n = reader.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    response.writer.write_all(hex::encode(&buf[..n]).as_bytes()).await?;
+                }
+                return Ok(());
+            }
+            let (start, end) = match request.header("range") {
+                Some(range) => match parse_range(range, size) {
+                    Some(r) => r,
+                    None => {
+                        let content_range = format!("bytes */{}", size);
+                        response.status(416, "text/plain", Some(0), &[("Content-Range", &content_range)]).await?;
+                        return Ok(());
+                    }
+                },
+                None => (0, size),
+            };
+            if end - start == size {
+                response.status(200, "application/octet-stream", Some(size), &[("Accept-Ranges", "bytes")]).await?;
+            } else {
+                let content_range = format!("bytes {}-{}/{}", start, end - 1, size);
+                response.status(206, "application/octet-stream", Some(end - start), &[("Content-Range", &content_range)]).await?;
+            }
+            let mut reader = archive.get_block_range(&block_hash, start, end - start).await?;
+            tokio::io::copy(&mut reader, response.writer).await?;
+            Ok(())
+        }
+        ("HEAD", ["block", hash]) => {
+            let block_hash = parse_hash(hash)?;
+            let size = archive.read().await.block_size(&block_hash).await? as u64;
+            response.status(200, "application/octet-stream", Some(size), &[]).await
+        }
+        ("GET", ["block", hash, "size"]) => {
+            let block_hash = parse_hash(hash)?;
+            let size = archive.read().await.block_size(&block_hash).await?;
+            response.send(200, "text/plain", size.to_string().as_bytes()).await
+        }
+        ("GET", ["block", hash, "attrs"]) => {
+            let block_hash = parse_hash(hash)?;
+            let attrs = archive.read().await.get_block_attrs(&block_hash).await?;
+            response.send(200, "text/plain", encode_block_attrs(&attrs).as_bytes()).await
+        }
+        ("GET", ["block", hash, "verify"]) => {
+            let block_hash = parse_hash(hash)?;
+            let valid = archive.read().await.verify_checksum(&block_hash).await?;
+            response.send(200, "text/plain", valid.to_string().as_bytes()).await
+        }
+        ("GET", ["header", hash]) => {
+            let block_hash = parse_hash(hash)?;
+            let mut header = Vec::new();
+            archive.read().await.get_block_range(&block_hash, 0, HEADER_SIZE).await?.read_to_end(&mut header).await?;
+            if hex {
+                response.send(200, "text/plain", hex::encode(&header).as_bytes()).await
+            } else {
+                response.send(200, "application/octet-stream", &header).await
+            }
+        }
+        ("GET", ["block", hash, "proof", txid]) => {
+            let block_hash = parse_hash(hash)?;
+            let proof = merkle_proof(&*archive.read().await, &block_hash, &parse_hash(txid)?).await?;
+            response.send(200, "application/json", proof.to_json().to_string().as_bytes()).await
+        }
+        ("PUT", ["block", hash]) if options.writable => {
+            let block_hash = parse_hash(hash)?;
+            archive.read().await.store_block(&block_hash, body).await?;
+            response.send(201, "text/plain", block_hash.to_string().as_bytes()).await
+        }
+        ("DELETE", ["block", hash]) if options.writable => {
+            let block_hash = parse_hash(hash)?;
+            archive.read().await.remove_block(&block_hash).await?;
+            response.send(200, "text/plain", b"").await
+        }
+        ("PUT", ["block", hash, "attrs", key]) if options.writable => {
+            let block_hash = parse_hash(hash)?;
+            let key = percent_decode(key)?;
+            let value = std::str::from_utf8(&request.body).map_err(|_| Error::InvalidAttribute(key.clone()))?;
+            archive.read().await.set_block_attr(&block_hash, &key, value).await?;
+            response.send(200, "text/plain", b"").await
+        }
+        ("DELETE", ["block", hash, "attrs", key]) if options.writable => {
+            let block_hash = parse_hash(hash)?;
+            let key = percent_decode(key)?;
+            archive.read().await.remove_block_attr(&block_hash, &key).await?;
+            response.send(200, "text/plain", b"").await
+        }
+        ("GET", ["events"]) => match &options.events {
+            Some(events) => stream_events(events.subscribe(), response).await,
+            None => response.send(404, "text/plain", b"not found").await,
+        },
+        ("GET" | "HEAD", _) => response.send(404, "text/plain", b"not found").await,
+        _ => response.send(405, "text/plain", b"method not allowed").await,
+    }
+}
+
+// Parse a block hash from a path.
+// Write the events to the client as server-sent events, until the client goes away or the events
+// stop.
+async fn stream_events<W: AsyncWrite + Unpin + Send>(mut receiver: broadcast::Receiver<BlockEvent>, response: &mut Response<'_, W>) -> Result<()> {
+    // the stream only ends when the connection is closed
+    response.keep_alive = false;
+    response.status(200, "text/event-stream", None, &[("Cache-Control", "no-cache")]).await?;
+    response.writer.flush().await?;
+    loop {
+        let message = match timeout(EVENT_KEEPALIVE, receiver.recv()).await {
+            Ok(Ok(event)) => format!("event: {}\ndata: {}\n\n", event.name(), event.to_json()),
+            Ok(Err(RecvError::Lagged(missed))) => format!(": missed {} events\n\n", missed),
+            Ok(Err(RecvError::Closed)) => return Ok(()),
+            Err(_) => ": keep-alive\n\n".to_string(),
+        };
+        response.writer.write_all(message.as_bytes()).await?;
+        response.writer.flush().await?;
+    }
+}
+
+fn parse_hash(s: &str) -> Result<BlockHash> {
+    BlockHash::from_hex(s).map_err(|_| Error::InvalidHash(s.to_string()))
+}
+
+// Decode a percent encoded path segment.
+fn percent_decode(s: &str) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let b = s.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok())
+                .ok_or_else(|| Error::InvalidAttribute(s.to_string()))?;
+            decoded.push(b);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| Error::InvalidAttribute(s.to_string()))
+}
+
+// Parse a single byte range, returning the start and the end (exclusive), or None if the range
+// is not valid for the size.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.trim().split_once('-')?;
+    let (start, end) = match (start, end) {
+        // the last n bytes
+        ("", n) => (size.saturating_sub(n.parse().ok()?), size),
+        (s, "") => (s.parse().ok()?, size),
+        (s, e) => (s.parse().ok()?, e.parse::<u64>().ok()?.saturating_add(1).min(size)),
+    };
+    if start >= end {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Read an HTTP request. Returns None if the connection was closed before a request was sent.
+pub(crate) async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<HttpRequest>> {
+    let mut request = match read_request_head(reader).await? {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    if let Some(len) = request.header("content-length") {
+        let len: usize = len.parse().map_err(|_| bad_request("invalid content length"))?;
+        if len > MAX_BODY_LEN {
+            return Err(bad_request("request body too large"));
+        }
+        request.body = vec![0u8; len];
+        reader.read_exact(&mut request.body).await?;
+    }
+    Ok(Some(request))
+}
+
+// Read the request line and the headers of a request, but not the body.
+async fn read_request_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<HttpRequest>> {
+    let line = match read_line(reader).await? {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers = read_headers(reader).await?;
+    Ok(Some(HttpRequest { method, path: path.to_string(), query: query.to_string(), headers, body: Vec::new() }))
+}
+
+/// Read the header lines that follow a request line or a status line, up to the empty line.
+/// Header names are converted to lower case.
+pub(crate) async fn read_headers<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader).await?.unwrap_or_default();
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADERS {
+            return Err(bad_request("too many headers"));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    Ok(headers)
+}
+
+/// Read a line, without the line ending. Returns None at the end of the stream.
+pub(crate) async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if (&mut *reader).take(MAX_LINE_LEN).read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(bad_request("line too long"));
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn bad_request(msg: &str) -> Error {
+    Error::IoError(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+/// A reader for the body of a request or a response, which stops at the end of the body. The
+/// body either has a known length or uses the chunked transfer encoding.
+pub(crate) struct BodyReader<R> {
+    inner: R,
+    state: BodyState,
+}
+
+enum BodyState {
+    // the number of bytes left in a body with a known length
+    Length(u64),
+    // reading the line with the size of the next chunk
+    ChunkSize(Vec<u8>),
+    // the number of bytes left in the current chunk
+    Chunk(u64),
+    // reading the line ending after a chunk
+    ChunkEnd(Vec<u8>),
+    // reading the trailer lines after the last chunk
+    Trailer(Vec<u8>),
+    // the whole body has been read
+    Done,
+}
+
+impl<R: AsyncBufRead + Unpin> BodyReader<R> {
+    /// Read a body with a known length.
+    pub(crate) fn with_length(inner: R, length: u64) -> BodyReader<R> {
+        BodyReader { inner, state: BodyState::Length(length) }
+    }
+
+    /// Read the body of a message with the given transfer encoding and content length headers.
+    /// A message that has neither has an empty body.
+    pub(crate) fn for_message(inner: R, transfer_encoding: Option<&str>, content_length: Option<&str>) -> Result<BodyReader<R>> {
+        if transfer_encoding.is_some_and(|e| e.eq_ignore_ascii_case("chunked")) {
+            return Ok(BodyReader { inner, state: BodyState::ChunkSize(Vec::new()) });
+        }
+        let length = match content_length {
+            Some(len) => len.parse().map_err(|_| bad_request("invalid content length"))?,
+            None => 0,
+        };
+        Ok(BodyReader::with_length(inner, length))
+    }
+
+    /// Check whether the whole body has been read.
+    pub(crate) fn is_done(&self) -> bool {
+        matches!(self.state, BodyState::Done | BodyState::Length(0))
+    }
+
+    /// Get the reader of the message.
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for BodyReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            match &mut this.state {
+                BodyState::Done | BodyState::Length(0) => return Poll::Ready(Ok(())),
+                BodyState::Length(remaining) | BodyState::Chunk(remaining) => {
+                    let available = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+                    if available.is_empty() {
+                        return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    let n = available.len().min(buf.remaining()).min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                    buf.put_slice(&available[..n]);
+                    Pin::new(&mut this.inner).consume(n);
+                    *remaining -= n as u64;
+                    if *remaining == 0 && matches!(this.state, BodyState::Chunk(_)) {
+                        this.state = BodyState::ChunkEnd(Vec::new());
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                BodyState::ChunkSize(line) | BodyState::ChunkEnd(line) | BodyState::Trailer(line) => {
+                    ready!(poll_line(&mut this.inner, cx, line))?;
+                    let line = std::mem::take(line);
+                    this.state = match this.state {
+                        BodyState::ChunkSize(_) => {
+                            let size = std::str::from_utf8(&line).ok()
+                                .and_then(|l| u64::from_str_radix(l.split(';').next().unwrap_or_default().trim(), 16).ok())
+                                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid chunk size"))?;
+                            if size == 0 { BodyState::Trailer(Vec::new()) } else { BodyState::Chunk(size) }
+                        }
+                        BodyState::ChunkEnd(_) if line.is_empty() => BodyState::ChunkSize(Vec::new()),
+                        BodyState::ChunkEnd(_) => {
+                            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid chunk ending")));
+                        }
+                        _ if line.is_empty() => BodyState::Done,
+                        _ => BodyState::Trailer(Vec::new()),
+                    };
+                }
+            }
+        }
+    }
+}
+
+// Read a line into the buffer, without the line ending.
+fn poll_line<R: AsyncBufRead + Unpin>(inner: &mut R, cx: &mut Context<'_>, line: &mut Vec<u8>) -> Poll<std::io::Result<()>> {
+    loop {
+        let available = ready!(Pin::new(&mut *inner).poll_fill_buf(cx))?;
+        if available.is_empty() {
+            return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let (used, complete) = match available.iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (available.len(), false),
+        };
+        line.extend_from_slice(&available[..used]);
+        Pin::new(&mut *inner).consume(used);
+        if complete {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if line.len() as u64 > MAX_LINE_LEN {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "line too long")));
+        }
+    }
+}
+
+// Write the status line and headers of a response.
+async fn write_status<W>(writer: &mut W, status: u16, content_type: &str, content_length: Option<u64>, connection: &str, headers: &[(&str, &str)]) -> Result<()>
+    where W: AsyncWrite + Unpin + ?Sized
+{
+    let mut s = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: {}\r\n", status, reason(status), content_type, connection);
+    if let Some(len) = content_length {
+        s.push_str(&format!("Content-Length: {}\r\n", len));
+    }
+    for (name, value) in headers {
+        s.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    s.push_str("\r\n");
+    writer.write_all(s.as_bytes()).await?;
+    Ok(())
+}
+
+/// Write a complete response, after which the connection is closed.
+pub(crate) async fn write_response<W>(writer: &mut W, status: u16, content_type: &str, body: &[u8]) -> Result<()>
+    where W: AsyncWrite + Unpin + ?Sized
+{
+    write_status(writer, status, content_type, Some(body.len() as u64), "close", &[]).await?;
+    writer.write_all(body).await?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        416 => "Range Not Satisfiable",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    const HASH: &str = "00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f";
+
+    // Start a server for the test archive.
+    async fn start_server() -> SocketAddr {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(archive, listener));
+        addr
+    }
+
+    // Send a request and return the response.
+    async fn get(addr: SocketAddr, request: &str) -> Vec<u8> {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-79", 1000), Some((0, 80)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("lines=0-1", 1000), None);
+    }
+
+    // A chunked body with a chunk extension and a trailer is read in full, and nothing after it.
+    #[tokio::test]
+    async fn test_chunked_body() {
+        let message = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\nnext";
+        let mut reader = &message[..];
+        let mut body = BodyReader::for_message(&mut reader, Some("chunked"), None).unwrap();
+        let mut data = Vec::new();
+        body.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello world");
+        assert!(body.is_done());
+        assert_eq!(reader, b"next");
+
+        let mut body = BodyReader::for_message(&b"5\r\nhel"[..], Some("chunked"), None).unwrap();
+        assert!(body.read_to_end(&mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let addr = start_server().await;
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex(HASH).unwrap();
+        let size = archive.block_size(&h).await.unwrap();
+        let mut block = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+
+        let response = get(addr, &format!("GET /block/{}/size HTTP/1.1\r\n\r\n", HASH)).await;
+        assert!(response.ends_with(format!("\r\n\r\n{}", size).as_bytes()));
+
+        let response = get(addr, &format!("GET /block/{} HTTP/1.1\r\n\r\n", HASH)).await;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&block));
+
+        let response = get(addr, &format!("GET /header/{}?format=hex HTTP/1.1\r\n\r\n", HASH)).await;
+        assert!(response.ends_with(hex::encode(&block[..80]).as_bytes()));
+
+        // the block has a single transaction, so its txid is the merkle root and the proof is empty
+        let txid = BlockHash::sha256d(&block[81..]);
+        let response = String::from_utf8(get(addr, &format!("GET /block/{}/proof/{} HTTP/1.1\r\n\r\n", HASH, txid)).await).unwrap();
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: *\r\n"));
+        let proof: serde_json::Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(proof["txOrId"], serde_json::json!(txid.to_string()));
+        assert_eq!(proof["nodes"], serde_json::json!([]));
+        let response = get(addr, &format!("GET /block/{}/proof/{} HTTP/1.1\r\n\r\n", HASH, HASH)).await;
+        assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+
+        let response = get(addr, &format!("GET /block/{} HTTP/1.1\r\nRange: bytes=10-19\r\n\r\n", HASH)).await;
+        assert!(response.starts_with(b"HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.ends_with(&[b"\r\n\r\n", &block[10..20]].concat()));
+
+        let response = get(addr, "GET /block/00000000000000000000000000000000000000000000000000000000000000ff HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+
+        let response = get(addr, "GET /block/nothex HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+
+        let response = String::from_utf8(get(addr, "GET /blocks HTTP/1.1\r\n\r\n").await).unwrap();
+        assert!(response.contains(HASH));
+    }
+
+    // The events sent to the server are streamed to a client of /events, once it has connected.
+    // A server started without events does not serve /events.
+    #[tokio::test]
+    async fn test_events() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (events, _) = broadcast::channel(10);
+        tokio::spawn(serve_with_events(archive, listener, false, events.clone()));
+
+        let mut reader = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        reader.get_mut().write_all(b"GET /events HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "HTTP/1.1 200 OK\r\n");
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+        }
+        let h = BlockHash::from_hex(HASH).unwrap();
+        events.send(BlockEvent::BlockDeleted(h)).unwrap();
+        let mut message = String::new();
+        while !message.ends_with("\n\n") {
+            reader.read_line(&mut message).await.unwrap();
+        }
+        assert_eq!(message, format!("event: block_deleted\ndata: {}\n\n", BlockEvent::BlockDeleted(h).to_json()));
+
+        let response = get(start_server().await, "GET /events HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,488 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use hex::FromHex;
+use tracing::debug;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use crate::block_archive::{decode_block_attrs, BlockHashListStream, BlockHashListStreamFromChannel};
+use crate::http::{read_headers, read_line, BodyReader};
+use crate::{BlockArchive, BlockAttrs, Error, Result};
+
+// the number of idle connections that are kept by default
+const DEFAULT_MAX_IDLE: usize = 8;
+
+// the default number of retries of a failed request, and the delay before the first retry
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(200);
+
+// the size of the chunks that a block is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// the number of hashes that are buffered between the listing task and the stream
+const CHANNEL_SIZE: usize = 1000;
+
+// a connection to the server, writes go straight to the socket
+type Connection = BufReader<TcpStream>;
+
+/// A block archive on a server that is reached over HTTP, see [crate::http].
+///
+/// Idle connections are kept and used again for later requests. Requests that can safely be
+/// repeated are tried again if they fail to reach the server, or if the server is unavailable.
+/// Blocks are streamed in both directions.
+///
+/// The server must be started with [crate::http::serve_writable] to store or remove blocks, or to
+/// change their attributes.
+///
+/// Cloning the archive is cheap, the clones share the idle connections.
+///
+/// Example code:
+///     let archive = HttpBlockArchive::new("http://127.0.0.1:8080")?.with_retries(5, Duration::from_secs(1));
+#[derive(Clone)]
+pub struct HttpBlockArchive {
+    // the host and port of the server
+    host: String,
+    pool: Arc<Pool>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl HttpBlockArchive {
+    /// Create a client for a server, for example "http://127.0.0.1:8080". No connection is made
+    /// until the first request.
+    pub fn new(url: &str) -> Result<HttpBlockArchive> {
+        let host = url.strip_prefix("http://").map(|h| h.trim_end_matches('/'))
+            .filter(|h| !h.is_empty() && !h.contains('/'))
+            .ok_or_else(|| Error::StorageUnavailable(format!("not an http URL of a server: {}", url)))?;
+        let host = if host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(HttpBlockArchive {
+            host,
+            pool: Arc::new(Pool { connections: Mutex::new(Vec::new()), max_idle: DEFAULT_MAX_IDLE }),
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+        })
+    }
+
+    /// Set the number of idle connections that are kept. The default is 8.
+    pub fn with_max_idle_connections(mut self, max_idle: usize) -> HttpBlockArchive {
+        self.pool = Arc::new(Pool { connections: Mutex::new(Vec::new()), max_idle });
+        self
+    }
+
+    /// Set the number of times a failed request is tried again, and the delay before the first
+    /// retry. The delay doubles for each retry. The default is 3 retries starting after 200ms.
+    ///
+    /// Storing a block is not tried again, because the block can only be read once.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> HttpBlockArchive {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    // Send a request and read the head of the response, trying again after a failure. Returns
+    // the response and a reader for its body.
+    async fn send(&self, method: &str, path: &str, headers: &[(&str, &str)], body: Option<&[u8]>) -> Result<(HttpResponse, PooledBody)> {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let e = match self.try_send(method, path, headers, body).await {
+                Ok((response, _)) if response.status == 503 && attempt < self.retries => Error::StorageUnavailable(self.host.clone()),
+                Ok(r) => return Ok(r),
+                Err(e) if attempt >= self.retries => return Err(e),
+                Err(e) => e,
+            };
+            debug!(%method, %path, ?delay, error = %e, "request failed, trying again");
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    // Send a request on an idle connection if there is one, or on a new connection if there is
+    // not or the idle connection has been closed by the server.
+    async fn try_send(&self, method: &str, path: &str, headers: &[(&str, &str)], body: Option<&[u8]>) -> Result<(HttpResponse, PooledBody)> {
+        let mut request = self.request_head(method, path, headers, body.map(|b| b.len()));
+        request.extend_from_slice(body.unwrap_or_default());
+        if let Some(conn) = self.pool.take() {
+            if let Ok(r) = self.exchange(conn, method, &request).await {
+                return Ok(r);
+            }
+        }
+        let conn = self.connect().await?;
+        self.exchange(conn, method, &request).await
+    }
+
+    // Write a request and read the head of the response.
+    async fn exchange(&self, mut conn: Connection, method: &str, request: &[u8]) -> Result<(HttpResponse, PooledBody)> {
+        conn.write_all(request).await?;
+        let response = read_response_head(&mut conn).await?;
+        let body = self.body(method, &response, conn)?;
+        Ok((response, body))
+    }
+
+    // Get an idle connection that the server has not closed, or a new connection, for a request
+    // that can not be sent again.
+    async fn open_connection(&self) -> Result<Connection> {
+        while let Some(conn) = self.pool.take() {
+            // an idle connection has nothing to read, unless the server has closed it
+            let mut b = [0u8; 1];
+            if conn.buffer().is_empty() && matches!(conn.get_ref().try_read(&mut b), Err(e) if e.kind() == std::io::ErrorKind::WouldBlock) {
+                return Ok(conn);
+            }
+        }
+        self.connect().await
+    }
+
+    async fn connect(&self) -> Result<Connection> {
+        let socket = TcpStream::connect(&self.host).await
+            .map_err(|e| Error::StorageUnavailable(format!("{}: {}", self.host, e)))?;
+        socket.set_nodelay(true)?;
+        Ok(BufReader::new(socket))
+    }
+
+    // Format the request line and headers of a request.
+    fn request_head(&self, method: &str, path: &str, headers: &[(&str, &str)], content_length: Option<usize>) -> Vec<u8> {
+        let mut s = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n", method, path, self.host);
+        if let Some(len) = content_length {
+            s.push_str(&format!("Content-Length: {}\r\n", len));
+        }
+        for (name, value) in headers {
+            s.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        s.push_str("\r\n");
+        s.into_bytes()
+    }
+
+    // Get a reader for the body of a response, which gives the connection back to the pool once
+    // the body has been read.
+    fn body(&self, method: &str, response: &HttpResponse, conn: Connection) -> Result<PooledBody> {
+        let keep_alive = response.header("connection").is_some_and(|v| v.eq_ignore_ascii_case("keep-alive"));
+        // the response to a HEAD request has a content length but no body
+        let content_length = if method == "HEAD" { None } else { response.header("content-length") };
+        let body = BodyReader::for_message(conn, response.header("transfer-encoding"), content_length)?;
+        let mut body = PooledBody { body: Some(body), pool: self.pool.clone(), keep_alive };
+        body.release();
+        Ok(body)
+    }
+
+    // Send a request and read the whole body of the response, which must be successful.
+    async fn fetch(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<Vec<u8>> {
+        let (response, mut reader) = self.send(method, path, &[], body).await?;
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await?;
+        response.check(&body)?;
+        Ok(body)
+    }
+
+    // Send a request and read the response as text.
+    async fn fetch_text(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<String> {
+        let body = self.fetch(method, path, body).await?;
+        String::from_utf8(body).map_err(|_| Error::RemoteError(format!("{} {} returned invalid text", method, path)))
+    }
+}
+
+#[async_trait]
+impl BlockArchive for HttpBlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let (response, mut body) = self.send("GET", &format!("/block/{}", block_hash), &[], None).await?;
+        if response.status != 200 {
+            let mut message = Vec::new();
+            body.read_to_end(&mut message).await?;
+            response.check(&message)?;
+        }
+        Ok(Box::new(body))
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        if length == 0 {
+            // the reader is empty, but the block must exist
+            self.block_size(block_hash).await?;
+            return Ok(Box::new(tokio::io::empty()));
+        }
+        let range = format!("bytes={}-{}", offset, offset.saturating_add(length - 1));
+        let (response, mut body) = self.send("GET", &format!("/block/{}", block_hash), &[("Range", &range)], None).await?;
+        match response.status {
+            200 | 206 => Ok(Box::new(body)),
+            // the range starts after the end of the block
+            416 => Ok(Box::new(tokio::io::empty())),
+            _ => {
+                let mut message = Vec::new();
+                body.read_to_end(&mut message).await?;
+                response.check(&message)?;
+                Ok(Box::new(tokio::io::empty()))
+            }
+        }
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        let (response, _) = self.send("HEAD", &format!("/block/{}", block_hash), &[], None).await?;
+        match response.status {
+            404 => Ok(false),
+            _ => response.check(&[]).map(|_| true),
+        }
+    }
+
+    /// The block is sent with the chunked transfer encoding, on an idle connection that the
+    /// server has not closed or on a new connection. If the block can not be read the connection
+    /// is closed before the end of the block, so the server does not store it.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let mut conn = self.open_connection().await?;
+        let head = self.request_head("PUT", &format!("/block/{}", block_hash), &[("Transfer-Encoding", "chunked")], None);
+        let mut sent = conn.write_all(&head).await;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        while sent.is_ok() {
+            let n = block.read(&mut buf).await?;
+            let mut chunk = format!("{:x}\r\n", n).into_bytes();
+            chunk.extend_from_slice(&buf[..n]);
+            chunk.extend_from_slice(b"\r\n");
+            sent = conn.write_all(&chunk).await;
+            // an empty chunk is the end of the block
+            if n == 0 {
+                break;
+            }
+        }
+        // the server may have rejected the block before reading all of it, in which case its
+        // response explains why sending failed
+        let response = match read_response_head(&mut conn).await {
+            Ok(r) => r,
+            Err(e) => return Err(sent.err().map(Error::from).unwrap_or(e)),
+        };
+        let mut message = Vec::new();
+        self.body("PUT", &response, conn)?.read_to_end(&mut message).await?;
+        response.check(&message)
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.fetch("DELETE", &format!("/block/{}", block_hash), None).await?;
+        Ok(())
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        let size = self.fetch_text("GET", &format!("/block/{}/size", block_hash), None).await?;
+        size.trim().parse().map_err(|_| Error::RemoteError(format!("invalid block size: {}", size)))
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        let header = self.fetch("GET", &format!("/header/{}", block_hash), None).await?;
+        Ok(BlockHeader::from_binary(&mut &header[..]).await?)
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let (response, body) = self.send("GET", "/blocks", &[], None).await?;
+        response.check(&[])?;
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+        let handle = tokio::spawn(async move {
+            // the list ends early if the body is incomplete, which is an error
+            let mut lines = BufReader::new(body).lines();
+            while let Some(line) = lines.next_line().await? {
+                let block_hash = BlockHash::from_hex(&line).map_err(|_| Error::InvalidHash(line.clone()))?;
+                if tx.send(block_hash).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        let path = format!("/block/{}/attrs/{}", block_hash, percent_encode(key));
+        self.fetch("PUT", &path, Some(value.as_bytes())).await.map_err(attr_error)?;
+        Ok(())
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        let path = format!("/block/{}/attrs/{}", block_hash, percent_encode(key));
+        self.fetch("DELETE", &path, None).await.map_err(attr_error)?;
+        Ok(())
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        decode_block_attrs(&self.fetch_text("GET", &format!("/block/{}/attrs", block_hash), None).await?)
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        let valid = self.fetch_text("GET", &format!("/block/{}/verify", block_hash), None).await?;
+        Ok(valid.trim() == "true")
+    }
+}
+
+// The status line and headers of a response.
+struct HttpResponse {
+    status: u16,
+    // header names are lower case
+    headers: Vec<(String, String)>,
+}
+
+impl HttpResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    // Get the error for a response that was not successful, the body has the message.
+    fn check(&self, body: &[u8]) -> Result<()> {
+        let message = String::from_utf8_lossy(body).trim().to_string();
+        match self.status {
+            200..=299 => Ok(()),
+            400 => Err(Error::InvalidBlock(message)),
+            404 => Err(Error::BlockNotFound),
+            409 => Err(Error::BlockExists),
+            503 => Err(Error::StorageUnavailable(message)),
+            status => Err(Error::RemoteError(format!("HTTP status {}: {}", status, message))),
+        }
+    }
+}
+
+// Read the status line and headers of a response.
+async fn read_response_head(conn: &mut Connection) -> Result<HttpResponse> {
+    let line = read_line(conn).await?.ok_or_else(|| Error::IoError(std::io::ErrorKind::UnexpectedEof.into()))?;
+    let status = line.split_whitespace().nth(1).and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::RemoteError(format!("invalid status line: {}", line)))?;
+    Ok(HttpResponse { status, headers: read_headers(conn).await? })
+}
+
+// The server reports an invalid attribute as a bad request.
+fn attr_error(e: Error) -> Error {
+    match e {
+        Error::InvalidBlock(msg) => Error::InvalidAttribute(msg),
+        e => e,
+    }
+}
+
+// Percent encode a path segment.
+fn percent_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// Idle connections to the server.
+struct Pool {
+    connections: Mutex<Vec<Connection>>,
+    max_idle: usize,
+}
+
+impl Pool {
+    fn take(&self) -> Option<Connection> {
+        self.connections.lock().unwrap().pop()
+    }
+
+    fn put(&self, conn: Connection) {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.len() < self.max_idle {
+            connections.push(conn);
+        }
+    }
+}
+
+// The body of a response, which gives the connection back to the pool once it has been read.
+// The connection is closed if the body is dropped before then.
+struct PooledBody {
+    body: Option<BodyReader<Connection>>,
+    pool: Arc<Pool>,
+    // true if the server will keep the connection open
+    keep_alive: bool,
+}
+
+impl PooledBody {
+    // Give the connection back to the pool if the body has been read.
+    fn release(&mut self) {
+        if self.body.as_ref().is_some_and(|b| b.is_done()) {
+            let conn = self.body.take().unwrap().into_inner();
+            if self.keep_alive {
+                self.pool.put(conn);
+            }
+        }
+    }
+}
+
+impl AsyncRead for PooledBody {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let body = match self.body.as_mut() {
+            Some(b) => b,
+            None => return Poll::Ready(Ok(())),
+        };
+        ready!(Pin::new(body).poll_read(cx, buf))?;
+        self.release();
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use crate::http::{serve, serve_writable};
+    use super::*;
+
+    // A block stored through the client can be read back, listed, and removed, on a handful of
+    // pooled connections.
+    #[tokio::test]
+    async fn test_http_archive() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_writable(MemoryBlockArchive::new(), listener));
+        let mut archive = HttpBlockArchive::new(&format!("http://{}/", addr)).unwrap();
+
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert!(matches!(archive.get_block(&h).await, Err(Error::BlockNotFound)));
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        assert!(matches!(archive.store_block(&h, &mut &block[..]).await, Err(Error::BlockExists)));
+        assert!(archive.block_exists(&h).await.unwrap());
+        assert_eq!(archive.block_size(&h).await.unwrap(), block.len());
+        assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+        let mut read = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, block);
+        let mut read = Vec::new();
+        archive.get_block_range(&h, 10, 20).await.unwrap().read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, &block[10..30]);
+        assert!(archive.verify_checksum(&h).await.unwrap());
+        // the connections were used again
+        assert!((1..=2).contains(&archive.pool.connections.lock().unwrap().len()));
+
+        archive.set_block_attr(&h, "source/a b", "test").await.unwrap();
+        assert_eq!(archive.get_block_attrs(&h).await.unwrap().get("source/a b").map(String::as_str), Some("test"));
+        assert!(matches!(archive.set_block_attr(&h, "a=b", "test").await, Err(Error::InvalidAttribute(_))));
+        archive.remove_block_attr(&h, "source/a b").await.unwrap();
+        assert!(archive.get_block_attrs(&h).await.unwrap().is_empty());
+
+        let mut results = archive.block_list().await.unwrap();
+        assert_eq!(results.next().await, Some(h));
+        assert_eq!(results.next().await, None);
+        assert!(results.as_mut().take_error().is_none());
+        drop(results);
+
+        archive.remove_block(&h).await.unwrap();
+        assert!(matches!(archive.remove_block(&h).await, Err(Error::BlockNotFound)));
+    }
+
+    // A read only server does not accept changes.
+    #[tokio::test]
+    async fn test_read_only() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(MemoryBlockArchive::new(), listener));
+        let archive = HttpBlockArchive::new(&format!("http://{}", addr)).unwrap().with_retries(0, DEFAULT_BACKOFF);
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(archive.store_block(&h, &mut &[0u8; 100][..]).await.is_err());
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert!(HttpBlockArchive::new("https://example.com").is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Batches of writes to an archive, with a journal so that an interrupted batch can be recovered.
+//!
+//! A [Batch] wraps an archive, usually from [BlockArchive::begin_batch], and is itself an archive.
+//! The blocks stored through it can be committed or aborted as a whole. Aborting removes them.
+//!
+//! With a journal, the batch writes its intent to store a block to a file before it stores the
+//! block. Once the block is stored, it records that too. Committing or aborting the batch removes
+//! the file. If the process stops first, the file is left in the journal directory.
+//! [recover_batches] then removes the blocks that were being stored, which may be incomplete.
+//! It also removes the rest of the blocks of the batch if they are to be rolled back. The
+//! journal file starts with the name of the archive, so several archives can share a directory.
+//!
+//! Recovery must not run while a batch is being written to the archive, which the write lock of
+//! the archive ensures, see [crate::lock].
+//!
+//! Example code:
+//!     recover_batches(&archive, &journal_dir, "main", Recovery::Resume).await?;
+//!     let batch = archive.begin_batch().with_journal(&journal_dir, "main");
+//!     sync_archives(&mut src, &batch, |_, _| {}).await?;
+//!     batch.commit().await?;
+//!     let archive = batch.into_inner();
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use hex::FromHex;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tracing::info;
+use crate::block_archive::BlockHashListStream;
+use crate::{BlockArchive, BlockAttrs, Error, ListOptions, Result};
+
+/// The extension of journal files.
+pub const JOURNAL_EXT: &str = "journal";
+
+// makes the names of the journal files of a process unique
+static JOURNAL_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// What [recover_batches] does with the blocks of a batch that was not committed or aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Remove every block stored by the batch.
+    RollBack,
+    /// Keep the blocks that were stored in full, so that running the operation again carries
+    /// on where it stopped. Only the blocks that were being stored are removed.
+    Resume,
+}
+
+/// The result of [recover_batches].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecoveryReport {
+    /// The number of batches that were recovered.
+    pub batches: usize,
+    /// The blocks that were removed.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub removed: Vec<BlockHash>,
+    /// The number of blocks that were kept.
+    pub kept: usize,
+}
+
+/// A batch of writes to an archive, see the [module documentation](self).
+///
+/// Blocks that are already in the archive can not be stored through a batch, so that aborting or
+/// rolling back never removes a block that was there before. Attributes set and blocks removed
+/// through the batch are passed straight to the archive, they are not undone.
+pub struct Batch<A: BlockArchive> {
+    archive: A,
+    // the directory of the journal and the name of the archive
+    journal: Option<(PathBuf, String)>,
+    state: Mutex<BatchState>,
+}
+
+// The blocks stored by a batch and its journal file, which is created when the first block is
+// stored.
+#[derive(Default)]
+struct BatchState {
+    stored: Vec<BlockHash>,
+    file: Option<(PathBuf, tokio::fs::File)>,
+}
+
+impl<A: BlockArchive> Batch<A> {
+    /// Start a batch of writes to the archive, without a journal.
+    pub fn new(archive: A) -> Batch<A> {
+        Batch { archive, journal: None, state: Mutex::new(BatchState::default()) }
+    }
+
+    /// Keep a journal in the directory, which is created if necessary. The name identifies the
+    /// archive to [recover_batches].
+    pub fn with_journal(mut self, dir: &Path, name: &str) -> Batch<A> {
+        self.journal = Some((dir.to_path_buf(), name.to_string()));
+        self
+    }
+
+    /// Get the archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+
+    /// Get the archive back. A batch that has not been committed or aborted is left to
+    /// [recover_batches].
+    pub fn into_inner(self) -> A {
+        self.archive
+    }
+
+    /// Get the blocks stored since the batch started or was last committed.
+    pub async fn stored(&self) -> Vec<BlockHash> {
+        self.state.lock().await.stored.clone()
+    }
+
+    /// Keep the blocks stored by the batch and remove the journal. Returns the number of blocks.
+    ///
+    /// The batch can be used again, the blocks stored after this are a new batch.
+    pub async fn commit(&self) -> Result<usize> {
+        let mut state = self.state.lock().await;
+        finish_journal(&mut state).await?;
+        let n = state.stored.len();
+        state.stored.clear();
+        Ok(n)
+    }
+
+    /// Remove the blocks stored by the batch, then the journal. Returns the number of blocks
+    /// removed.
+    ///
+    /// The batch can be used again, the blocks stored after this are a new batch.
+    pub async fn abort(&self) -> Result<usize> {
+        let mut state = self.state.lock().await;
+        let mut removed = 0;
+        for block_hash in state.stored.iter().rev() {
+            match self.archive.remove_block(block_hash).await {
+                Ok(()) => removed += 1,
+                Err(Error::BlockNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        state.stored.clear();
+        finish_journal(&mut state).await?;
+        Ok(removed)
+    }
+
+    // Write an entry to the journal, creating the file if this is the first entry.
+    async fn record(&self, state: &mut BatchState, action: &str, block_hash: &BlockHash) -> Result<()> {
+        let (dir, name) = match &self.journal {
+            Some(j) => j,
+            None => return Ok(()),
+        };
+        if state.file.is_none() {
+            tokio::fs::create_dir_all(dir).await?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+            let seq = JOURNAL_SEQ.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("{}-{}-{}.{}", now, std::process::id(), seq, JOURNAL_EXT));
+            let mut file = tokio::fs::File::create(&path).await?;
+            file.write_all(format!("archive {}\n", name).as_bytes()).await?;
+            state.file = Some((path, file));
+        }
+        let (_, file) = state.file.as_mut().unwrap();
+        file.write_all(format!("{} {}\n", action, block_hash).as_bytes()).await?;
+        // the entry must be on disk before the block is written
+        file.sync_data().await?;
+        Ok(())
+    }
+}
+
+// Remove the journal file of a batch, if it has one.
+async fn finish_journal(state: &mut BatchState) -> Result<()> {
+    if let Some((path, file)) = state.file.take() {
+        drop(file);
+        tokio::fs::remove_file(&path).await?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for Batch<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.archive.get_block(block_hash).await
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.archive.get_block_range(block_hash, offset, length).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.block_exists(block_hash).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        self.archive.blocks_exist(block_hashes).await
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        if self.archive.block_exists(block_hash).await? {
+            return Err(Error::BlockExists);
+        }
+        // the lock is not held while the block is stored, so that blocks can be stored at the
+        // same time
+        self.record(&mut *self.state.lock().await, "intent", block_hash).await?;
+        self.archive.store_block(block_hash, block).await?;
+        let mut state = self.state.lock().await;
+        self.record(&mut state, "stored", block_hash).await?;
+        state.stored.push(*block_hash);
+        Ok(())
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.archive.remove_block(block_hash).await?;
+        self.state.lock().await.stored.retain(|h| h != block_hash);
+        Ok(())
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.archive.block_size(block_hash).await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.archive.block_header(block_hash).await
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        self.archive.block_headers(block_hashes).await
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.archive.block_list().await
+    }
+
+    async fn block_list_opts(&mut self, options: ListOptions) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.archive.block_list_opts(options).await
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.archive.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.archive.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.archive.get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.verify_checksum(block_hash).await
+    }
+}
+
+/// Recover the batches of the named archive that were left in the journal directory by a
+/// process that stopped before committing or aborting them, then remove their journals.
+///
+/// The blocks that were being stored are always removed, the rest depend on the mode. A missing
+/// directory has nothing to recover.
+pub async fn recover_batches<A>(archive: &A, dir: &Path, name: &str, mode: Recovery) -> Result<RecoveryReport>
+    where A: BlockArchive + ?Sized
+{
+    let mut report = RecoveryReport::default();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(report),
+        Err(e) => return Err(e.into()),
+    };
+    let mut paths = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) == Some(JOURNAL_EXT) {
+            paths.push(path);
+        }
+    }
+    // recover the oldest batch first, the names start with the time
+    paths.sort();
+    let header = format!("archive {}", name);
+    for path in paths {
+        let text = tokio::fs::read_to_string(&path).await?;
+        let mut lines = text.lines();
+        if lines.next() != Some(header.as_str()) {
+            continue;
+        }
+        let mut intents = Vec::new();
+        let mut stored = BTreeSet::new();
+        // the last line may be cut short, which leaves a hash that can not be parsed
+        for line in lines {
+            match line.split_once(' ').and_then(|(a, h)| Some((a, BlockHash::from_hex(h).ok()?))) {
+                Some(("intent", h)) => intents.push(h),
+                Some(("stored", h)) => {
+                    stored.insert(h);
+                }
+                _ => {}
+            }
+        }
+        for block_hash in intents.iter().rev() {
+            if mode == Recovery::Resume && stored.contains(block_hash) {
+                report.kept += 1;
+                continue;
+            }
+            if archive.block_exists(block_hash).await? {
+                archive.remove_block(block_hash).await?;
+                report.removed.push(*block_hash);
+            }
+        }
+        tokio::fs::remove_file(&path).await?;
+        info!(journal = %path.display(), ?mode, "recovered batch");
+        report.batches += 1;
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hex::FromHex;
+    use mktemp::Temp;
+    use crate::MemoryBlockArchive;
+    use super::*;
+
+    // The hash of a test block, the archive does not check that it matches the block.
+    fn block_hash(n: u64) -> BlockHash {
+        BlockHash::from_hex(format!("{:064x}", n)).unwrap()
+    }
+
+    // The journal of a batch is removed when it is committed or aborted, and aborting removes the
+    // blocks of the batch but not the blocks that were already in the archive.
+    #[tokio::test]
+    async fn test_commit_and_abort() {
+        let dir = Temp::new_dir().unwrap();
+        let archive = MemoryBlockArchive::new();
+        archive.store_block(&block_hash(1), &mut &[0u8; 81][..]).await.unwrap();
+        let batch = archive.begin_batch().with_journal(dir.as_path(), "test");
+        assert!(matches!(batch.store_block(&block_hash(1), &mut &[0u8; 81][..]).await, Err(Error::BlockExists)));
+        batch.store_block(&block_hash(2), &mut &[0u8; 81][..]).await.unwrap();
+        assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 1);
+        assert_eq!(batch.commit().await.unwrap(), 1);
+        assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 0);
+
+        batch.store_block(&block_hash(3), &mut &[0u8; 81][..]).await.unwrap();
+        assert_eq!(batch.stored().await, vec![block_hash(3)]);
+        assert_eq!(batch.abort().await.unwrap(), 1);
+        assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 0);
+        let archive = batch.into_inner();
+        assert!(archive.block_exists(&block_hash(1)).await.unwrap());
+        assert!(archive.block_exists(&block_hash(2)).await.unwrap());
+        assert!(!archive.block_exists(&block_hash(3)).await.unwrap());
+    }
+
+    // A batch that stopped while a block was being stored is resumed by keeping the blocks that
+    // were stored, or rolled back by removing them too. Journals of other archives are ignored.
+    #[tokio::test]
+    async fn test_recover_batches() {
+        for mode in [Recovery::Resume, Recovery::RollBack] {
+            let dir = Temp::new_dir().unwrap();
+            let batch = MemoryBlockArchive::new().begin_batch().with_journal(dir.as_path(), "test");
+            batch.store_block(&block_hash(1), &mut &[0u8; 81][..]).await.unwrap();
+            // the process stops after storing the second block but before recording it
+            let archive = batch.into_inner();
+            archive.store_block(&block_hash(2), &mut &[0u8; 81][..]).await.unwrap();
+            let path = std::fs::read_dir(dir.as_path()).unwrap().next().unwrap().unwrap().path();
+            let mut journal = std::fs::read_to_string(&path).unwrap();
+            journal.push_str(&format!("intent {}\n", block_hash(2)));
+            std::fs::write(&path, journal).unwrap();
+
+            let other = recover_batches(&archive, dir.as_path(), "other", mode).await.unwrap();
+            assert_eq!(other, RecoveryReport::default());
+            let report = recover_batches(&archive, dir.as_path(), "test", mode).await.unwrap();
+            assert_eq!(report.batches, 1);
+            assert!(!archive.block_exists(&block_hash(2)).await.unwrap());
+            match mode {
+                Recovery::Resume => {
+                    assert_eq!(report.removed, vec![block_hash(2)]);
+                    assert_eq!(report.kept, 1);
+                    assert!(archive.block_exists(&block_hash(1)).await.unwrap());
+                }
+                Recovery::RollBack => {
+                    assert_eq!(report.removed, vec![block_hash(2), block_hash(1)]);
+                    assert!(!archive.block_exists(&block_hash(1)).await.unwrap());
+                }
+            }
+            assert_eq!(std::fs::read_dir(dir.as_path()).unwrap().count(), 0);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+use crate::block_archive::{BlockHashListStream, BlockHashListStreamFromChannel};
+use crate::{BlockArchive, BlockAttrs, Error, Result};
+
+// The size of the channel used to list blocks.
+const LIST_BUFFER: usize = 1000;
+
+/// A block archive made of layers of other archives, for example a fast local archive over a
+/// slow S3 archive.
+///
+/// Reads check the layers in order and use the first layer that has the block. New blocks are
+/// stored in the top layer, which is the first layer. Attributes are set on the layer that holds
+/// the block. Removing a block removes it from every layer.
+///
+/// Example code:
+///     let archive = LayeredBlockArchive::new(vec![Box::new(local), Box::new(s3)]);
+pub struct LayeredBlockArchive {
+    layers: Vec<Box<dyn BlockArchive>>,
+}
+
+impl LayeredBlockArchive {
+    /// Create an archive from layers, the first layer is the top layer.
+    ///
+    /// Panics if there are no layers.
+    pub fn new(layers: Vec<Box<dyn BlockArchive>>) -> LayeredBlockArchive {
+        assert!(!layers.is_empty(), "a layered archive needs at least one layer");
+        LayeredBlockArchive { layers }
+    }
+
+    /// Get the layers, the first layer is the top layer.
+    pub fn layers(&self) -> &[Box<dyn BlockArchive>] {
+        &self.layers
+    }
+
+    /// Get mutable access to the layers, for example to list the blocks in one layer.
+    pub fn layers_mut(&mut self) -> &mut [Box<dyn BlockArchive>] {
+        &mut self.layers
+    }
+
+    /// Get the layer that holds a block.
+    pub async fn find_layer(&self, block_hash: &BlockHash) -> Result<Option<usize>> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            if layer.block_exists(block_hash).await? {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    // Get the layer that holds a block, failing if no layer holds it.
+    async fn layer_for(&self, block_hash: &BlockHash) -> Result<&dyn BlockArchive> {
+        match self.find_layer(block_hash).await? {
+            Some(i) => Ok(self.layers[i].as_ref()),
+            None => Err(Error::BlockNotFound),
+        }
+    }
+
+    // Send the hashes from each stream in turn, skipping hashes that have already been sent.
+    async fn block_list_bgrnd(streams: Vec<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>>, transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<()> {
+        let mut seen = BTreeSet::new();
+        for mut stream in streams {
+            while let Some(hash) = stream.next().await {
+                if !seen.insert(hash) {
+                    continue;
+                }
+                if transmit.send(hash).await.is_err() {
+                    return Ok(());      // this is not an error, the receiver has merely dropped
+                }
+            }
+            if let Some(e) = stream.as_mut().take_error() {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockArchive for LayeredBlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.layer_for(block_hash).await?.get_block(block_hash).await
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.layer_for(block_hash).await?.get_block_range(block_hash, offset, length).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        Ok(self.find_layer(block_hash).await?.is_some())
+    }
+
+    /// The block is stored in the top layer. Fails with [Error::BlockExists] if any layer already
+    /// holds the block.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        if self.block_exists(block_hash).await? {
+            return Err(Error::BlockExists);
+        }
+        self.layers[0].store_block(block_hash, block).await
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        let mut found = false;
+        for layer in self.layers.iter() {
+            if layer.block_exists(block_hash).await? {
+                layer.remove_block(block_hash).await?;
+                found = true;
+            }
+        }
+        if !found {
+            return Err(Error::BlockNotFound);
+        }
+        Ok(())
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.layer_for(block_hash).await?.block_size(block_hash).await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.layer_for(block_hash).await?.block_header(block_hash).await
+    }
+
+    /// Lists the blocks of each layer in turn, a block that is in more than one layer is only
+    /// listed once.
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let mut streams = Vec::new();
+        for layer in self.layers.iter_mut() {
+            streams.push(layer.block_list().await?);
+        }
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER);
+        let handle = tokio::spawn(Self::block_list_bgrnd(streams, tx));
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.layer_for(block_hash).await?.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.layer_for(block_hash).await?.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.layer_for(block_hash).await?.get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.layer_for(block_hash).await?.verify_checksum(block_hash).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tokio::io::AsyncReadExt;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // Reads fall through to the lower layer, writes go to the top layer, and the list has no
+    // duplicates.
+    #[tokio::test]
+    async fn test_layered_archive() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let hashes: Vec<BlockHash> = src.block_list().await.unwrap().collect().await;
+        assert!(hashes.len() >= 3);
+        let mut blocks = Vec::new();
+        for h in hashes.iter() {
+            let mut block = Vec::new();
+            src.get_block(h).await.unwrap().read_to_end(&mut block).await.unwrap();
+            blocks.push(block);
+        }
+        let top = MemoryBlockArchive::new();
+        let bottom = MemoryBlockArchive::new();
+        // the first block is in both layers, the rest are only in the bottom layer
+        top.store_block(&hashes[0], &mut &blocks[0][..]).await.unwrap();
+        for (h, block) in hashes.iter().zip(blocks.iter()).skip(1) {
+            bottom.store_block(h, &mut &block[..]).await.unwrap();
+        }
+        bottom.store_block(&hashes[0], &mut &blocks[0][..]).await.unwrap();
+        let last = hashes.len() - 1;
+        bottom.remove_block(&hashes[last]).await.unwrap();
+        let layers: Vec<Box<dyn BlockArchive>> = vec![Box::new(top), Box::new(bottom)];
+        let mut archive = LayeredBlockArchive::new(layers);
+
+        assert_eq!(archive.find_layer(&hashes[0]).await.unwrap(), Some(0));
+        assert_eq!(archive.find_layer(&hashes[1]).await.unwrap(), Some(1));
+        assert_eq!(archive.block_size(&hashes[1]).await.unwrap(), blocks[1].len());
+        assert_eq!(archive.block_header(&hashes[1]).await.unwrap().hash(), hashes[1]);
+        archive.set_block_attr(&hashes[1], "tier", "cold").await.unwrap();
+        assert_eq!(archive.layers()[1].get_block_attrs(&hashes[1]).await.unwrap().get("tier").unwrap(), "cold");
+
+        // new blocks go to the top layer
+        assert!(!archive.block_exists(&hashes[last]).await.unwrap());
+        archive.store_block(&hashes[last], &mut &blocks[last][..]).await.unwrap();
+        assert_eq!(archive.find_layer(&hashes[last]).await.unwrap(), Some(0));
+        assert!(matches!(archive.store_block(&hashes[1], &mut &blocks[1][..]).await, Err(Error::BlockExists)));
+
+        let mut listed: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        listed.sort();
+        let mut expected = hashes.clone();
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        // removing a block removes it from every layer
+        archive.remove_block(&hashes[0]).await.unwrap();
+        assert!(!archive.block_exists(&hashes[0]).await.unwrap());
+        assert!(matches!(archive.remove_block(&hashes[0]).await, Err(Error::BlockNotFound)));
+    }
+}
@@ -1,8 +1,105 @@
+pub mod backup;
+pub mod bench;
+pub mod blkdat;
 mod block_archive;
+pub mod blocking;
+mod cache;
+mod candidates;
+mod chain_index;
+pub mod checksums;
+pub mod chunks;
+pub mod coinbase;
+pub mod compact;
+mod dedup_archive;
+pub mod diff;
+pub mod encryption;
+pub mod events;
+pub mod fetch;
+pub mod filters;
+pub mod gaps;
+pub mod gc;
+mod header_archive;
+mod headers;
+pub mod http;
+mod http_archive;
+pub mod journal;
+mod layered_archive;
+pub mod lock;
+mod manifest;
+#[cfg(any(test, feature = "test-util"))]
+mod memory_archive;
+pub mod merkle;
+pub mod meta;
+mod network;
+pub mod p2p_server;
+mod packed_archive;
+pub mod pow;
+pub mod prefetch;
+pub mod publish;
+pub mod quarantine;
+pub mod replicate;
+pub mod report;
+mod resilient;
+pub mod rpc;
+pub mod runtime;
 mod sfb_archive;
+mod sharded_archive;
+mod sizes;
+pub mod stats;
+pub mod sync;
+pub mod thin;
+pub mod throttle;
+pub mod tier;
+pub mod trace;
+mod txindex;
+pub mod verify;
 
-pub use block_archive::BlockArchive;
-pub use sfb_archive::SimpleFileBasedBlockArchive;
+pub use block_archive::{BlockArchive, BlockAttrs, BlockHashListStream, BlockSizeStream, BlockStream, BlockSummary, ListOptions, ListOrder, WritePolicy};
+pub use cache::CachedBlockArchive;
+pub use candidates::{CandidateInfo, CandidateStore};
+pub use chain_index::{ChainEntry, ChainIndex, CheckpointResult, Fork};
+pub use dedup_archive::{DedupBlockArchive, DedupStats};
+pub use header_archive::{HeaderArchive, SledHeaderArchive};
+pub use http_archive::HttpBlockArchive;
+pub use layered_archive::LayeredBlockArchive;
+pub use manifest::{Manifest, ManifestEntry, ManifestReport};
+#[cfg(any(test, feature = "test-util"))]
+pub use memory_archive::MemoryBlockArchive;
+pub use network::Network;
+pub use packed_archive::PackedBlockArchive;
+pub use resilient::{CircuitState, ResilientBlockArchive};
+pub use sfb_archive::{ListErrorPolicy, SimpleFileBasedBlockArchive};
+pub use sharded_archive::{RebalanceSummary, ShardMap, ShardedFileBlockArchive};
+pub use txindex::{IndexedBlockArchive, Transaction, TxIndex, TxInput, TxLocation, TxOutput};
 
 mod result;
-pub use result::{Error, Result};
+pub use result::{Error, ErrorRecord, Result};
+
+#[cfg(feature = "cabi")]
+pub mod cabi;
+#[cfg(feature = "catalog")]
+pub mod catalog;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(any(feature = "azure", feature = "gcs"))]
+mod object_archive;
+#[cfg(any(feature = "azure", feature = "gcs"))]
+pub use object_archive::ObjectStoreBlockArchive;
+#[cfg(feature = "azure")]
+pub use object_archive::AzureBlockArchive;
+#[cfg(feature = "gcs")]
+pub use object_archive::GcsBlockArchive;
+#[cfg(feature = "parquet")]
+pub mod parquet_export;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "s3")]
+mod s3_archive;
+#[cfg(feature = "s3")]
+pub use s3_archive::{S3BlockArchive, S3ListSource};
+#[cfg(feature = "serde")]
+mod serde_support;
@@ -0,0 +1,177 @@
+//! Advisory locking of an archive in a local directory, so that processes sharing an archive do
+//! not step on each other.
+//!
+//! There are three kinds of access:
+//!
+//! * A reader holds a shared lock on the ARCHIVE_LOCK file in the root directory. Any number of
+//!   readers can use the archive at once.
+//! * A writer holds a [WriteGuard], which is a shared lock on the ARCHIVE_LOCK file and an
+//!   exclusive lock on the ARCHIVE_WRITE_LOCK file. Only one writer can use the archive at a
+//!   time, alongside any number of readers, as each block is written atomically.
+//! * Maintenance that rewrites the archive, such as a migration, holds an exclusive lock on the
+//!   ARCHIVE_LOCK file, which shuts out readers and writers.
+//!
+//! A process that cannot take a lock retries until the wait has passed and then fails with
+//! [Error::ArchiveLocked]. The locks are advisory, they only affect processes that take them, and
+//! they are released when dropped or when the process exits.
+//!
+//! Example code:
+//!     let guard = WriteGuard::acquire(&root_path, Duration::from_secs(10)).await?;
+//!     archive.store_block(&block_hash, &mut block).await?;
+//!     drop(guard);
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use fs2::FileExt;
+use crate::{Error, Result};
+
+/// The name of the file that readers and maintenance lock.
+pub const LOCK_FILE: &str = "ARCHIVE_LOCK";
+/// The name of the file that writers lock.
+pub const WRITE_LOCK_FILE: &str = "ARCHIVE_WRITE_LOCK";
+
+// how often a lock that is held by another process is tried again
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The kind of lock held on the ARCHIVE_LOCK file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Held by readers and writers, any number of processes can hold it.
+    Shared,
+    /// Held for maintenance, no other process can hold a lock.
+    Exclusive,
+}
+
+/// A lock on the ARCHIVE_LOCK file of an archive, released when dropped.
+#[derive(Debug)]
+pub struct ArchiveLock {
+    _file: File,
+    mode: LockMode,
+}
+
+impl ArchiveLock {
+    /// Lock the archive in the root directory, waiting up to the given time for other processes
+    /// to release a conflicting lock.
+    ///
+    /// Fails with [Error::ArchiveLocked] if the lock could not be taken in time.
+    pub async fn acquire(root_path: &Path, mode: LockMode, wait: Duration) -> Result<ArchiveLock> {
+        let file = lock_file(&root_path.join(LOCK_FILE), mode, wait).await?;
+        Ok(ArchiveLock { _file: file, mode })
+    }
+
+    /// Take a shared lock on the archive in the root directory without creating the ARCHIVE_LOCK
+    /// file, for an archive opened read-only, such as one on a read-only mount. Returns None if
+    /// the archive has no ARCHIVE_LOCK file, which means that no process that locks the archive
+    /// has written to it.
+    ///
+    /// Fails with [Error::ArchiveLocked] if the lock could not be taken in time.
+    pub async fn acquire_existing(root_path: &Path, wait: Duration) -> Result<Option<ArchiveLock>> {
+        let path = root_path.join(LOCK_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let file = lock(file, &path, LockMode::Shared, wait).await?;
+        Ok(Some(ArchiveLock { _file: file, mode: LockMode::Shared }))
+    }
+
+    /// Get the kind of lock held.
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+/// The locks held by the one process that is writing to an archive, released when dropped.
+#[derive(Debug)]
+pub struct WriteGuard {
+    _lock: ArchiveLock,
+    _write: File,
+}
+
+impl WriteGuard {
+    /// Take the write locks of the archive in the root directory, waiting up to the given time
+    /// for another writer, or maintenance, to finish.
+    ///
+    /// Fails with [Error::ArchiveLocked] if the locks could not be taken in time.
+    pub async fn acquire(root_path: &Path, wait: Duration) -> Result<WriteGuard> {
+        let deadline = Instant::now() + wait;
+        let lock = ArchiveLock::acquire(root_path, LockMode::Shared, wait).await?;
+        let write = lock_file(&root_path.join(WRITE_LOCK_FILE), LockMode::Exclusive,
+                              deadline.saturating_duration_since(Instant::now())).await?;
+        Ok(WriteGuard { _lock: lock, _write: write })
+    }
+}
+
+// Open and lock a file, creating it if necessary.
+async fn lock_file(path: &Path, mode: LockMode, wait: Duration) -> Result<File> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    lock(file, path, mode, wait).await
+}
+
+// Lock an open file, the path is for the error.
+async fn lock(file: File, path: &Path, mode: LockMode, wait: Duration) -> Result<File> {
+    let deadline = Instant::now() + wait;
+    loop {
+        // called through the trait, as newer versions of std have methods of the same name
+        let locked = match mode {
+            LockMode::Shared => FileExt::try_lock_shared(&file),
+            LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+        };
+        match locked {
+            Ok(()) => return Ok(file),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {}
+            Err(e) => return Err(e.into()),
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::ArchiveLocked(format!("{} is locked by another process", path.display())));
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use mktemp::Temp;
+    use super::*;
+
+    // Readers share the archive with one writer, maintenance excludes everyone, and the locks are
+    // released when dropped.
+    #[tokio::test]
+    async fn test_locks() {
+        let dir = Temp::new_dir().unwrap();
+        let root = dir.to_path_buf();
+        let none = Duration::ZERO;
+        let reader = ArchiveLock::acquire(&root, LockMode::Shared, none).await.unwrap();
+        assert_eq!(reader.mode(), LockMode::Shared);
+        let writer = WriteGuard::acquire(&root, none).await.unwrap();
+        assert!(matches!(WriteGuard::acquire(&root, none).await, Err(Error::ArchiveLocked(_))));
+        assert!(matches!(ArchiveLock::acquire(&root, LockMode::Exclusive, none).await, Err(Error::ArchiveLocked(_))));
+        let _other_reader = ArchiveLock::acquire(&root, LockMode::Shared, none).await.unwrap();
+        drop(writer);
+        let _writer = WriteGuard::acquire(&root, Duration::from_millis(200)).await.unwrap();
+
+        let dir2 = Temp::new_dir().unwrap();
+        let root2 = dir2.to_path_buf();
+        let maintenance = ArchiveLock::acquire(&root2, LockMode::Exclusive, none).await.unwrap();
+        assert!(matches!(ArchiveLock::acquire(&root2, LockMode::Shared, none).await, Err(Error::ArchiveLocked(_))));
+        drop(maintenance);
+        ArchiveLock::acquire(&root2, LockMode::Shared, none).await.unwrap();
+    }
+
+    // A read-only reader does not create the lock file, but takes the lock if the file exists.
+    #[tokio::test]
+    async fn test_acquire_existing() {
+        let dir = Temp::new_dir().unwrap();
+        let root = dir.to_path_buf();
+        let none = Duration::ZERO;
+        assert!(ArchiveLock::acquire_existing(&root, none).await.unwrap().is_none());
+        assert!(!root.join(LOCK_FILE).exists());
+        let maintenance = ArchiveLock::acquire(&root, LockMode::Exclusive, none).await.unwrap();
+        assert!(matches!(ArchiveLock::acquire_existing(&root, none).await, Err(Error::ArchiveLocked(_))));
+        drop(maintenance);
+        let reader = ArchiveLock::acquire_existing(&root, none).await.unwrap().unwrap();
+        assert_eq!(reader.mode(), LockMode::Shared);
+    }
+}
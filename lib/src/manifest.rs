@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bitcoinsv::bitcoin::BlockHash;
+use hex::{FromHex, ToHex};
+use ring::digest::{Context, SHA256};
+use ring::signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_stream::StreamExt;
+use crate::{BlockArchive, Error, Result, SimpleFileBasedBlockArchive};
+
+// the first line of every manifest, includes the format version
+const MANIFEST_HEADER: &str = "bsv-blockarchive-manifest 1";
+
+// the size of the buffer used when hashing blocks
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Calculate the SHA-256 checksum of everything that can be read from the reader.
+///
+/// Returns the checksum and the number of bytes read.
+pub(crate) async fn sha256_reader<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<([u8; 32], u64)> {
+    let mut context = Context::new(&SHA256);
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    let mut size = 0u64;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buf[..n]);
+        size += n as u64;
+    }
+    let mut checksum = [0u8; 32];
+    checksum.copy_from_slice(context.finish().as_ref());
+    Ok((checksum, size))
+}
+
+/// The size and checksum of a block recorded in a [Manifest].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The size of the encoded block.
+    pub size: u64,
+    /// The SHA-256 checksum of the encoded block.
+    pub checksum: [u8; 32],
+}
+
+/// A manifest lists every block in an archive at a point in time, with its size and checksum.
+///
+/// A manifest can be shipped with a snapshot of an archive so that the receiver can check that
+/// the snapshot is complete and undamaged. It can optionally be signed with an Ed25519 key, the
+/// signature is detached and covers the text form of the manifest.
+///
+/// The text form is line based:
+///
+/// ```text
+/// bsv-blockarchive-manifest 1
+/// created 1715000000
+/// <block hash> <size> <sha256 checksum>
+/// ...
+/// ```
+///
+/// Entries are sorted by block hash so the text form of a manifest is always the same.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// When the manifest was created, in seconds since the unix epoch.
+    pub created: u64,
+    /// The blocks in the manifest.
+    pub entries: BTreeMap<BlockHash, ManifestEntry>,
+}
+
+/// The result of checking an archive against a [Manifest].
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ManifestReport {
+    /// The number of blocks that matched the manifest.
+    pub ok: usize,
+    /// Blocks in the manifest that are not in the archive.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub missing: Vec<BlockHash>,
+    /// Blocks whose size or checksum differs from the manifest.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub mismatched: Vec<BlockHash>,
+}
+
+impl ManifestReport {
+    /// Returns true if every block in the manifest was found and matched.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl Manifest {
+    /// Create an empty manifest, timestamped now.
+    pub fn new() -> Manifest {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Manifest { created, entries: BTreeMap::new() }
+    }
+
+    /// Generate a manifest of all the blocks in an archive.
+    ///
+    /// Every block is read in full, this may take a long time.
+    pub async fn generate<A: BlockArchive>(archive: &mut A) -> Result<Manifest> {
+        let mut manifest = Manifest::new();
+        let mut blocks = archive.block_stream().await?;
+        while let Some(r) = blocks.next().await {
+            let (block_hash, mut reader) = r?;
+            let (checksum, size) = sha256_reader(&mut reader).await?;
+            manifest.entries.insert(block_hash, ManifestEntry { size, checksum });
+        }
+        Ok(manifest)
+    }
+
+    /// Generate a manifest of all the blocks in a simple archive, reading the given number of
+    /// shard directories at the same time, see [SimpleFileBasedBlockArchive::shard_dirs].
+    ///
+    /// The manifest is the same as the one [Manifest::generate] makes, but on storage that
+    /// serves many reads at once it is made much faster.
+    pub async fn build(archive: &SimpleFileBasedBlockArchive, jobs: usize) -> Result<Manifest> {
+        let mut manifest = Manifest::new();
+        let shards = archive.shard_dirs().await?;
+        let builders = shards.iter().map(|shard| Self::build_shard(archive, shard));
+        let mut results = futures::StreamExt::buffer_unordered(futures::stream::iter(builders), jobs.max(1));
+        while let Some(r) = results.next().await {
+            manifest.entries.extend(r?);
+        }
+        Ok(manifest)
+    }
+
+    // Read the blocks in one shard directory of a simple archive.
+    async fn build_shard(archive: &SimpleFileBasedBlockArchive, shard: &Path) -> Result<Vec<(BlockHash, ManifestEntry)>> {
+        let mut entries = Vec::new();
+        for block_hash in archive.shard_blocks(shard).await? {
+            let mut reader = match archive.get_block(&block_hash).await {
+                Ok(r) => r,
+                // removed since the shard was listed
+                Err(Error::BlockNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            let (checksum, size) = sha256_reader(&mut reader).await?;
+            entries.push((block_hash, ManifestEntry { size, checksum }));
+        }
+        Ok(entries)
+    }
+
+    /// Check that every block in the manifest is in the archive with the correct size and
+    /// checksum.
+    ///
+    /// Blocks in the archive that are not in the manifest are ignored.
+    pub async fn verify<A: BlockArchive>(&self, archive: &A) -> Result<ManifestReport> {
+        self.verify_parallel(archive, 1).await
+    }
+
+    /// Check the archive against the manifest like [Manifest::verify], reading the given number
+    /// of blocks at the same time.
+    pub async fn verify_parallel<A: BlockArchive + ?Sized>(&self, archive: &A, jobs: usize) -> Result<ManifestReport> {
+        // each check gives whether the block matched, None if it is missing
+        let checks = self.entries.iter().map(|(block_hash, entry)| async move {
+            let mut reader = match archive.get_block(block_hash).await {
+                Ok(r) => r,
+                Err(Error::BlockNotFound) => return Ok((*block_hash, None)),
+                Err(e) => return Err(e),
+            };
+            let (checksum, size) = sha256_reader(&mut reader).await?;
+            Ok((*block_hash, Some(checksum == entry.checksum && size == entry.size)))
+        });
+        let mut results = futures::StreamExt::buffer_unordered(futures::stream::iter(checks), jobs.max(1));
+        let mut report = ManifestReport::default();
+        while let Some(r) = results.next().await {
+            match r? {
+                (_, Some(true)) => report.ok += 1,
+                (block_hash, Some(false)) => report.mismatched.push(block_hash),
+                (block_hash, None) => report.missing.push(block_hash),
+            }
+        }
+        report.missing.sort();
+        report.mismatched.sort();
+        Ok(report)
+    }
+
+    /// Sign the text form of the manifest with an Ed25519 key in PKCS#8 format.
+    ///
+    /// Returns the detached signature.
+    pub fn sign(&self, pkcs8_key: &[u8]) -> Result<Vec<u8>> {
+        // keys made by openssl are PKCS#8 version 1, without the public key
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8_key)
+            .map_err(|e| Error::InvalidManifest(format!("invalid signing key: {}", e)))?;
+        Ok(key_pair.sign(self.to_string().as_bytes()).as_ref().to_vec())
+    }
+
+    /// Verify a detached signature created by [Manifest::sign] against an Ed25519 public key.
+    pub fn verify_signature(&self, public_key: &[u8], signature: &[u8]) -> Result<()> {
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(self.to_string().as_bytes(), signature)
+            .map_err(|_| Error::InvalidSignature)
+    }
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest::new()
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", MANIFEST_HEADER)?;
+        writeln!(f, "created {}", self.created)?;
+        for (block_hash, entry) in self.entries.iter() {
+            let checksum: String = entry.checksum.encode_hex();
+            writeln!(f, "{} {} {}", block_hash, entry.size, checksum)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut lines = s.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(Error::InvalidManifest("unknown manifest format".to_string()));
+        }
+        let created = lines.next()
+            .and_then(|l| l.strip_prefix("created "))
+            .and_then(|t| t.parse::<u64>().ok())
+            .ok_or_else(|| Error::InvalidManifest("missing creation time".to_string()))?;
+        let mut entries = BTreeMap::new();
+        for line in lines {
+            if line.is_empty() {
+                continue;
+            }
+            let bad_line = || Error::InvalidManifest(format!("invalid entry: {}", line));
+            let mut fields = line.split(' ');
+            let block_hash = fields.next().and_then(|f| BlockHash::from_hex(f).ok()).ok_or_else(bad_line)?;
+            let size = fields.next().and_then(|f| f.parse::<u64>().ok()).ok_or_else(bad_line)?;
+            let checksum = fields.next().and_then(|f| <[u8; 32]>::from_hex(f).ok()).ok_or_else(bad_line)?;
+            if fields.next().is_some() {
+                return Err(bad_line());
+            }
+            entries.insert(block_hash, ManifestEntry { size, checksum });
+        }
+        Ok(Manifest { created, entries })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use ring::rand::SystemRandom;
+    use ring::signature::KeyPair;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Generate a manifest for the test archive and check it survives a round trip to text.
+    #[tokio::test]
+    async fn test_generate_manifest() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let manifest = Manifest::generate(&mut archive).await.unwrap();
+        assert_eq!(manifest.entries.len(), 3);
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert_eq!(manifest.entries[&h].size, 227);
+        let parsed = Manifest::from_str(&manifest.to_string()).unwrap();
+        assert_eq!(parsed, manifest);
+        assert!(manifest.verify(&archive).await.unwrap().is_ok());
+    }
+
+    // A listing that fails part way fails the manifest, rather than giving a partial one.
+    #[tokio::test]
+    async fn test_generate_manifest_list_error() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        tokio::fs::remove_dir_all(root.as_path()).await.unwrap();
+        assert!(matches!(Manifest::generate(&mut archive).await, Err(Error::IoError(_))));
+    }
+
+    // Building a manifest a shard at a time gives the same entries as generating it.
+    #[tokio::test]
+    async fn test_build_manifest() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let generated = Manifest::generate(&mut archive).await.unwrap();
+        let built = Manifest::build(&archive, 4).await.unwrap();
+        assert_eq!(built.entries, generated.entries);
+        assert!(archive.shard_dirs().await.unwrap().len() <= built.entries.len());
+        let report = built.verify_parallel(&archive, 4).await.unwrap();
+        assert_eq!(report.ok, built.entries.len());
+        assert!(report.is_ok());
+    }
+
+    // A changed block and a missing block should both be reported.
+    #[tokio::test]
+    async fn test_verify_manifest() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let h1 = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let h2 = BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1").unwrap();
+        let mut block: Box<dyn AsyncRead + Unpin + Send> = Box::new(Cursor::new(b"block one".to_vec()));
+        archive.store_block(&h1, &mut block).await.unwrap();
+        let mut manifest = Manifest::generate(&mut archive).await.unwrap();
+        manifest.entries.insert(h2, ManifestEntry { size: 1, checksum: [0; 32] });
+        manifest.entries.get_mut(&h1).unwrap().size = 8;
+        let report = manifest.verify(&archive).await.unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.missing, vec![h2]);
+        assert_eq!(report.mismatched, vec![h1]);
+    }
+
+    // A signature should verify, and fail once the manifest is changed.
+    #[test]
+    fn test_sign_manifest() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let mut manifest = Manifest::new();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        manifest.entries.insert(h, ManifestEntry { size: 227, checksum: [1; 32] });
+        let signature = manifest.sign(pkcs8.as_ref()).unwrap();
+        manifest.verify_signature(key_pair.public_key().as_ref(), &signature).unwrap();
+        manifest.created += 1;
+        assert!(manifest.verify_signature(key_pair.public_key().as_ref(), &signature).is_err());
+    }
+
+    // Garbage should not parse.
+    #[test]
+    fn test_parse_invalid_manifest() {
+        assert!(Manifest::from_str("not a manifest").is_err());
+        assert!(Manifest::from_str("bsv-blockarchive-manifest 1\ncreated 1\nabc 1 2\n").is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::RwLock;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::block_archive::{checked_block, validate_block_attr, BlockHashListStream, BlockHashListStreamFromChannel, ChecksumReader};
+use crate::{BlockArchive, BlockAttrs, Error, Result, WritePolicy};
+
+/// A block archive that keeps all blocks in memory.
+///
+/// This is intended for tests of code that uses a block archive, it does not touch the
+/// filesystem. It is only available with the "test-util" feature.
+///
+/// Example code:
+///     let archive = MemoryBlockArchive::new();
+///     archive.store_block(&hash, &mut &block[..]).await?;
+#[derive(Default)]
+pub struct MemoryBlockArchive {
+    blocks: RwLock<BTreeMap<BlockHash, MemoryBlock>>,
+    // the checks made on blocks before they are stored
+    write_policy: WritePolicy,
+    // whether store_block() replaces an existing block
+    overwrite: bool,
+}
+
+// A block and the data stored with it.
+struct MemoryBlock {
+    data: Vec<u8>,
+    attrs: BlockAttrs,
+    checksum: String,
+}
+
+impl MemoryBlockArchive {
+    /// Create an empty archive.
+    pub fn new() -> MemoryBlockArchive {
+        MemoryBlockArchive::default()
+    }
+
+    /// Set the checks that are made on blocks before they are stored, the default is to make no
+    /// checks.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> MemoryBlockArchive {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Set whether storing a block that is already in the archive replaces it, instead of
+    /// failing with [Error::BlockExists]. The attributes of the block are kept.
+    pub fn with_overwrite(mut self, overwrite: bool) -> MemoryBlockArchive {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Get the number of blocks in the archive.
+    pub fn len(&self) -> usize {
+        self.blocks.read().unwrap().len()
+    }
+
+    /// Check whether the archive is empty.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.read().unwrap().is_empty()
+    }
+
+    // Get a copy of the block data.
+    fn data(&self, block_hash: &BlockHash) -> Result<Vec<u8>> {
+        match self.blocks.read().unwrap().get(block_hash) {
+            Some(b) => Ok(b.data.clone()),
+            None => Err(Error::BlockNotFound),
+        }
+    }
+}
+
+#[async_trait]
+impl BlockArchive for MemoryBlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        Ok(Box::new(std::io::Cursor::new(self.data(block_hash)?)))
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let data = self.data(block_hash)?;
+        let start = (offset.min(data.len() as u64)) as usize;
+        let end = (offset.saturating_add(length).min(data.len() as u64)) as usize;
+        Ok(Box::new(std::io::Cursor::new(data[start..end].to_vec())))
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        Ok(self.blocks.read().unwrap().contains_key(block_hash))
+    }
+
+    /// Fails with [Error::BlockExists] if the block is already in the archive.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        if !self.overwrite && self.block_exists(block_hash).await? {
+            return Err(Error::BlockExists);
+        }
+        let block = checked_block(self.write_policy, block_hash, block).await?;
+        let mut reader = ChecksumReader::new(block);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        let checksum = reader.checksum();
+        let mut blocks = self.blocks.write().unwrap();
+        if !self.overwrite && blocks.contains_key(block_hash) {
+            return Err(Error::BlockExists);
+        }
+        let attrs = blocks.remove(block_hash).map(|b| b.attrs).unwrap_or_default();
+        blocks.insert(*block_hash, MemoryBlock { data, attrs, checksum });
+        Ok(())
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        match self.blocks.write().unwrap().remove(block_hash) {
+            Some(_) => Ok(()),
+            None => Err(Error::BlockNotFound),
+        }
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        match self.blocks.read().unwrap().get(block_hash) {
+            Some(b) => Ok(b.data.len()),
+            None => Err(Error::BlockNotFound),
+        }
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        let data = self.data(block_hash)?;
+        Ok(BlockHeader::from_binary(&mut &data[..]).await?)
+    }
+
+    /// The blocks are listed in order of hash.
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let hashes: Vec<BlockHash> = self.blocks.read().unwrap().keys().cloned().collect();
+        let (tx, rx) = tokio::sync::mpsc::channel(hashes.len().max(1));
+        let handle = tokio::spawn(async move {
+            for hash in hashes {
+                if tx.send(hash).await.is_err() {
+                    break;      // the receiver has dropped
+                }
+            }
+            Ok(())
+        });
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        validate_block_attr(key, value)?;
+        match self.blocks.write().unwrap().get_mut(block_hash) {
+            Some(b) => {
+                b.attrs.insert(key.to_string(), value.to_string());
+                Ok(())
+            }
+            None => Err(Error::BlockNotFound),
+        }
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        match self.blocks.write().unwrap().get_mut(block_hash) {
+            Some(b) => {
+                b.attrs.remove(key);
+                Ok(())
+            }
+            None => Err(Error::BlockNotFound),
+        }
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        match self.blocks.read().unwrap().get(block_hash) {
+            Some(b) => Ok(b.attrs.clone()),
+            None => Err(Error::BlockNotFound),
+        }
+    }
+
+    /// The checksum is recorded when the block is stored, so this is always true for blocks in
+    /// the archive.
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        let checksum = match self.blocks.read().unwrap().get(block_hash) {
+            Some(b) => b.checksum.clone(),
+            None => return Err(Error::BlockNotFound),
+        };
+        crate::block_archive::check_block_checksum(self, block_hash, &checksum).await?;
+        Ok(true)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use tokio_stream::StreamExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_archive() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let mut archive = MemoryBlockArchive::new();
+        assert!(archive.is_empty());
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        assert!(matches!(archive.store_block(&h, &mut &block[..]).await, Err(Error::BlockExists)));
+        assert!(archive.block_exists(&h).await.unwrap());
+        assert_eq!(archive.block_size(&h).await.unwrap(), block.len());
+        assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+        let mut buf = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, block);
+        assert!(archive.verify_checksum(&h).await.unwrap());
+        archive.set_block_attr(&h, "source", "test").await.unwrap();
+        assert_eq!(archive.get_block_attrs(&h).await.unwrap().get("source").unwrap(), "test");
+        archive.remove_block_attr(&h, "source").await.unwrap();
+        assert!(archive.get_block_attrs(&h).await.unwrap().is_empty());
+        let hashes: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        assert_eq!(hashes, vec![h]);
+        let mut blocks = archive.block_stream().await.unwrap();
+        let (hash, mut reader) = blocks.next().await.unwrap().unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!((hash, buf), (h, block.clone()));
+        assert!(blocks.next().await.is_none());
+        drop(blocks);
+        archive.remove_block(&h).await.unwrap();
+        assert!(archive.is_empty());
+        assert!(matches!(archive.remove_block(&h).await, Err(Error::BlockNotFound)));
+        assert!(matches!(archive.get_block(&h).await, Err(Error::BlockNotFound)));
+    }
+
+    // A block is rejected if it is stored under another hash, or if its transactions have been
+    // changed and the policy checks the merkle root.
+    #[tokio::test]
+    async fn test_write_policy() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let mut corrupt = block.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+
+        let archive = MemoryBlockArchive::new().with_write_policy(WritePolicy::HeaderOnly);
+        assert!(matches!(archive.store_block(&genesis, &mut &block[..]).await, Err(Error::InvalidBlock(_))));
+        assert!(matches!(archive.store_block(&h, &mut &block[..40]).await, Err(Error::InvalidBlock(_))));
+        assert!(archive.is_empty());
+        // only the header is checked
+        archive.store_block(&h, &mut &corrupt[..]).await.unwrap();
+        assert_eq!(archive.data(&h).unwrap(), corrupt);
+
+        let archive = MemoryBlockArchive::new().with_write_policy(WritePolicy::MerkleRoot);
+        assert!(matches!(archive.store_block(&genesis, &mut &block[..]).await, Err(Error::InvalidBlock(_))));
+        assert!(matches!(archive.store_block(&h, &mut &corrupt[..]).await, Err(Error::InvalidBlock(_))));
+        assert!(archive.is_empty());
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        assert_eq!(archive.data(&h).unwrap(), block);
+        assert!(archive.verify_checksum(&h).await.unwrap());
+
+        let archive = MemoryBlockArchive::new();
+        archive.store_block(&genesis, &mut &block[..]).await.unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+//! Merkle roots and merkle proofs of the transactions in a block.
+//!
+//! A merkle proof shows that a transaction is in a block using only the block header, which is
+//! what SPV clients need.
+//!
+//! At each level of the tree the hashes are hashed in pairs, and the last hash of a level with an
+//! odd number of hashes is paired with itself. This means that a list of txids that ends with a
+//! repeated pair has the same merkle root as the list without the repeat (CVE-2012-2459), so a
+//! block should also be checked with [validate_no_duplicate_vulnerability].
+use bitcoinsv::bitcoin::BlockHash;
+use serde_json::{json, Value};
+use crate::txindex::scan_transactions;
+use crate::{BlockArchive, Error, Result};
+
+/// The id of a transaction, which uses the same hash type as a block hash.
+pub type TxHash = BlockHash;
+
+/// A proof that a transaction is in a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// The block that contains the transaction.
+    pub block_hash: BlockHash,
+    /// The transaction.
+    pub txid: BlockHash,
+    /// The position of the transaction in the block.
+    pub index: u64,
+    /// The hashes needed to calculate the merkle root from the txid, from the bottom of the tree
+    /// up. None means that the hash is combined with itself, which happens at the end of a level
+    /// with an odd number of hashes.
+    pub branch: Vec<Option<BlockHash>>,
+}
+
+impl MerkleProof {
+    /// Calculate the merkle root from the txid and the branch.
+    pub fn merkle_root(&self) -> BlockHash {
+        let mut hash = self.txid;
+        for (i, node) in self.branch.iter().enumerate() {
+            let sibling = node.unwrap_or(hash);
+            hash = if (self.index >> i) & 1 == 1 { hash_pair(&sibling, &hash) } else { hash_pair(&hash, &sibling) };
+        }
+        hash
+    }
+
+    /// Check the proof against the merkle root from the block header.
+    pub fn verify(&self, merkle_root: &BlockHash) -> bool {
+        self.merkle_root() == *merkle_root
+    }
+
+    /// Encode the proof in the JSON format of the TSC merkle proof standard, with the block hash
+    /// as the target. Hashes are hex encoded in the usual display order.
+    pub fn to_json(&self) -> Value {
+        let nodes: Vec<String> = self.branch.iter()
+            .map(|n| n.map_or("*".to_string(), |h| h.to_string()))
+            .collect();
+        json!({
+            "index": self.index,
+            "txOrId": self.txid.to_string(),
+            "target": self.block_hash.to_string(),
+            "nodes": nodes,
+        })
+    }
+
+    /// Encode the proof in the binary format of the TSC merkle proof standard, with the block hash
+    /// as the target. Hashes are in internal byte order.
+    pub fn to_binary(&self) -> Vec<u8> {
+        // the flags: a txid, a block hash target, and a branch
+        let mut v = vec![0u8];
+        write_varint(&mut v, self.index);
+        v.extend_from_slice(&self.txid.hash);
+        v.extend_from_slice(&self.block_hash.hash);
+        write_varint(&mut v, self.branch.len() as u64);
+        for node in self.branch.iter() {
+            match node {
+                Some(h) => {
+                    v.push(0);
+                    v.extend_from_slice(&h.hash);
+                }
+                None => v.push(1),
+            }
+        }
+        v
+    }
+}
+
+/// Calculate the merkle root of the txids of a block, in block order, or None if there are no
+/// txids.
+///
+/// The root of a single transaction is its txid.
+pub fn compute_merkle_root(txids: impl Iterator<Item=TxHash>) -> Option<TxHash> {
+    let mut level: Vec<TxHash> = txids.collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.first().copied()
+}
+
+/// Check that the txids of a block do not have the same merkle root as a different list of txids,
+/// which happens if a level of the tree pairs two identical hashes (CVE-2012-2459).
+///
+/// Returns false if the list is vulnerable. A block with such a list is invalid, even if its
+/// merkle root matches the header.
+pub fn validate_no_duplicate_vulnerability(txids: impl Iterator<Item=TxHash>) -> bool {
+    let mut level: Vec<TxHash> = txids.collect();
+    while level.len() > 1 {
+        if level.chunks_exact(2).any(|pair| pair[0] == pair[1]) {
+            return false;
+        }
+        level = next_level(&level);
+    }
+    true
+}
+
+/// Make a proof that a transaction is in a block in the archive.
+///
+/// The whole block is read. Fails with [Error::TxNotFound] if the transaction is not in the block.
+pub async fn merkle_proof<A>(archive: &A, block_hash: &BlockHash, txid: &BlockHash) -> Result<MerkleProof>
+    where A: BlockArchive + ?Sized
+{
+    let mut reader = archive.get_block(block_hash).await?;
+    let txids: Vec<BlockHash> = scan_transactions(&mut reader).await?.into_iter().map(|(h, _, _)| h).collect();
+    merkle_proof_from_txids(block_hash, &txids, txid)
+}
+
+/// Make a proof that a transaction is in a block from the txids of the block, in block order.
+///
+/// Fails with [Error::TxNotFound] if the transaction is not in the list.
+pub fn merkle_proof_from_txids(block_hash: &BlockHash, txids: &[TxHash], txid: &TxHash) -> Result<MerkleProof> {
+    let position = txids.iter().position(|h| h == txid).ok_or(Error::TxNotFound)?;
+    let branch = merkle_branch(txids, position);
+    Ok(MerkleProof { block_hash: *block_hash, txid: *txid, index: position as u64, branch })
+}
+
+// Get the branch for the txid at a position in the list.
+fn merkle_branch(txids: &[BlockHash], position: usize) -> Vec<Option<BlockHash>> {
+    let mut branch = Vec::new();
+    let mut level = txids.to_vec();
+    let mut i = position;
+    while level.len() > 1 {
+        branch.push(level.get(i ^ 1).copied());
+        level = next_level(&level);
+        i /= 2;
+    }
+    branch
+}
+
+// Hash each pair of hashes in a level of the tree, the last hash is paired with itself if the
+// level has an odd number of hashes.
+fn next_level(level: &[BlockHash]) -> Vec<BlockHash> {
+    level.chunks(2).map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0]))).collect()
+}
+
+fn hash_pair(left: &BlockHash, right: &BlockHash) -> BlockHash {
+    let mut buf = [0u8; 64];
+    buf[..32].copy_from_slice(&left.hash);
+    buf[32..].copy_from_slice(&right.hash);
+    BlockHash::sha256d(&buf)
+}
+
+pub(crate) fn write_varint(v: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => v.push(n as u8),
+        0xfd..=0xffff => {
+            v.push(0xfd);
+            v.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            v.push(0xfe);
+            v.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            v.push(0xff);
+            v.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    fn hash(n: u8) -> BlockHash {
+        BlockHash::from_hex(format!("{:064x}", n)).unwrap()
+    }
+
+    // The last hash of an odd level is paired with itself, at every level.
+    #[test]
+    fn test_compute_merkle_root() {
+        let txids: Vec<TxHash> = (1..=5).map(hash).collect();
+        assert_eq!(compute_merkle_root(std::iter::empty()), None);
+        assert_eq!(compute_merkle_root(txids[..1].iter().copied()), Some(txids[0]));
+        let ab = hash_pair(&txids[0], &txids[1]);
+        assert_eq!(compute_merkle_root(txids[..2].iter().copied()), Some(ab));
+        let cc = hash_pair(&txids[2], &txids[2]);
+        assert_eq!(compute_merkle_root(txids[..3].iter().copied()), Some(hash_pair(&ab, &cc)));
+        let cd = hash_pair(&txids[2], &txids[3]);
+        let ee = hash_pair(&txids[4], &txids[4]);
+        let abcd = hash_pair(&ab, &cd);
+        let eeee = hash_pair(&ee, &ee);
+        assert_eq!(compute_merkle_root(txids.iter().copied()), Some(hash_pair(&abcd, &eeee)));
+    }
+
+    // Repeating the last txid gives the same root, which the validation catches.
+    #[test]
+    fn test_duplicate_vulnerability() {
+        let txids: Vec<TxHash> = (1..=3).map(hash).collect();
+        let mut mutated = txids.clone();
+        mutated.push(txids[2]);
+        assert_eq!(compute_merkle_root(txids.iter().copied()), compute_merkle_root(mutated.iter().copied()));
+        assert!(validate_no_duplicate_vulnerability(txids.iter().copied()));
+        assert!(!validate_no_duplicate_vulnerability(mutated.iter().copied()));
+        // a repeat at a higher level of the tree
+        let mut mutated: Vec<TxHash> = (1..=6).map(hash).collect();
+        mutated.extend_from_slice(&[hash(5), hash(6)]);
+        assert!(!validate_no_duplicate_vulnerability(mutated.into_iter()));
+        assert!(validate_no_duplicate_vulnerability(txids[..1].iter().copied()));
+        assert!(validate_no_duplicate_vulnerability(std::iter::empty()));
+    }
+
+    // Every transaction in a tree with an odd number of leaves has a valid proof.
+    #[test]
+    fn test_branch() {
+        let txids: Vec<BlockHash> = (1..=5).map(hash).collect();
+        let root = compute_merkle_root(txids.iter().copied()).unwrap();
+        for (i, txid) in txids.iter().enumerate() {
+            let branch = merkle_branch(&txids, i);
+            assert_eq!(branch.len(), 3);
+            let proof = MerkleProof { block_hash: hash(0), txid: *txid, index: i as u64, branch };
+            assert!(proof.verify(&root));
+        }
+    }
+
+    // Proofs for the first and last transactions of a block in the test archive match the header.
+    #[tokio::test]
+    async fn test_merkle_proof() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let header = archive.block_header(&h).await.unwrap();
+        let mut reader = archive.get_block(&h).await.unwrap();
+        let txids: Vec<BlockHash> = scan_transactions(&mut reader).await.unwrap().into_iter().map(|(t, _, _)| t).collect();
+        assert_eq!(compute_merkle_root(txids.iter().copied()), Some(header.merkle_root));
+        assert!(validate_no_duplicate_vulnerability(txids.iter().copied()));
+        for txid in [txids[0], txids[txids.len() - 1]] {
+            let proof = merkle_proof(&archive, &h, &txid).await.unwrap();
+            assert!(proof.verify(&header.merkle_root));
+            assert_eq!(proof.to_json()["txOrId"], json!(txid.to_string()));
+            assert_eq!(proof.to_binary()[0], 0);
+        }
+        assert!(matches!(merkle_proof(&archive, &h, &hash(1)).await, Err(Error::TxNotFound)));
+    }
+}
@@ -0,0 +1,236 @@
+use std::path::Path;
+use async_trait::async_trait;
+use crate::block_archive::{decode_block_attrs, encode_block_attrs};
+use crate::{BlockAttrs, Error, Network, Result, SimpleFileBasedBlockArchive};
+
+/// The name of the file at the root of the archive which records the format of the archive.
+pub const META_FILE: &str = "ARCHIVE_META";
+
+/// The on-disk format version written by this version of the library.
+///
+/// Version 0 is an archive created before the format was versioned, it has no [META_FILE]. A new
+/// archive is given a meta file with this version when it is first opened.
+pub const CURRENT_FORMAT_VERSION: u32 = 1;
+
+/// The contents of the [META_FILE] at the root of an archive.
+///
+/// The file is stored as "key=value" lines.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ArchiveMeta {
+    /// The on-disk format version of the archive.
+    pub format_version: u32,
+    /// Set while a migration is in progress, to the version being migrated to.
+    pub migrating_to: Option<u32>,
+    /// The network that the blocks in the archive belong to, if it has been recorded.
+    pub network: Option<Network>,
+    /// Whether new blocks are stored compressed, if it has been recorded.
+    pub compress: Option<bool>,
+    /// When the archive was created, in seconds since the epoch. Unknown for archives created
+    /// before the meta file was written on creation.
+    pub created: Option<u64>,
+    /// Whether the blocks in the archive can never be replaced or removed, see
+    /// [SimpleFileBasedBlockArchive::set_immutable].
+    pub immutable: bool,
+}
+
+impl ArchiveMeta {
+    /// Read the meta file from the root of an archive, returns None if there is no meta file.
+    pub async fn read(root_path: &Path) -> Result<Option<ArchiveMeta>> {
+        let s = match tokio::fs::read_to_string(root_path.join(META_FILE)).await {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let values = decode_block_attrs(&s).map_err(|_| Error::InvalidArchiveMeta("unreadable meta file".to_string()))?;
+        let get_u32 = |key: &str| -> Result<Option<u32>> {
+            match values.get(key) {
+                Some(v) => v.parse::<u32>().map(Some).map_err(|_| Error::InvalidArchiveMeta(format!("invalid {}", key))),
+                None => Ok(None),
+            }
+        };
+        let format_version = get_u32("format_version")?
+            .ok_or_else(|| Error::InvalidArchiveMeta("missing format_version".to_string()))?;
+        let network = match values.get("network") {
+            Some(n) => Some(n.parse::<Network>().map_err(|_| Error::InvalidArchiveMeta(format!("invalid network {}", n)))?),
+            None => None,
+        };
+        let compress = match values.get("compress").map(|v| v.as_str()) {
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            Some(_) => return Err(Error::InvalidArchiveMeta("invalid compress".to_string())),
+            None => None,
+        };
+        let created = match values.get("created") {
+            Some(v) => Some(v.parse::<u64>().map_err(|_| Error::InvalidArchiveMeta("invalid created".to_string()))?),
+            None => None,
+        };
+        let immutable = match values.get("immutable").map(|v| v.as_str()) {
+            Some("true") => true,
+            Some("false") | None => false,
+            Some(_) => return Err(Error::InvalidArchiveMeta("invalid immutable".to_string())),
+        };
+        Ok(Some(ArchiveMeta { format_version, migrating_to: get_u32("migrating_to")?, network, compress, created, immutable }))
+    }
+
+    /// Write the meta file to the root of an archive.
+    ///
+    /// The file is written to a temporary file first and then renamed, so that it is never
+    /// partially written.
+    pub async fn write(&self, root_path: &Path) -> Result<()> {
+        let mut values = BlockAttrs::new();
+        values.insert("format_version".to_string(), self.format_version.to_string());
+        if let Some(v) = self.migrating_to {
+            values.insert("migrating_to".to_string(), v.to_string());
+        }
+        if let Some(n) = self.network {
+            values.insert("network".to_string(), n.to_string());
+        }
+        if let Some(c) = self.compress {
+            values.insert("compress".to_string(), c.to_string());
+        }
+        if let Some(t) = self.created {
+            values.insert("created".to_string(), t.to_string());
+        }
+        if self.immutable {
+            values.insert("immutable".to_string(), "true".to_string());
+        }
+        let tmp_path = root_path.join(format!("{}.tmp", META_FILE));
+        tokio::fs::write(&tmp_path, encode_block_attrs(&values)).await?;
+        tokio::fs::rename(tmp_path, root_path.join(META_FILE)).await?;
+        Ok(())
+    }
+}
+
+/// A step that upgrades the on-disk format of an archive by one version.
+///
+/// Migrations may be interrupted, so [Migration::run] must be safe to run again on an archive
+/// that has been partially migrated.
+#[async_trait]
+pub trait Migration: Send + Sync {
+    /// The version that this migration upgrades from, it upgrades to the next version.
+    fn source_version(&self) -> u32;
+
+    /// A short description of the migration.
+    fn description(&self) -> &'static str;
+
+    /// Perform the migration.
+    async fn run(&self, archive: &SimpleFileBasedBlockArchive) -> Result<()>;
+}
+
+// Version 0 to 1: the layout is unchanged, version 1 only adds the meta file, which is written
+// by the framework once the step completes.
+struct AddMetaFile;
+
+#[async_trait]
+impl Migration for AddMetaFile {
+    fn source_version(&self) -> u32 { 0 }
+
+    fn description(&self) -> &'static str { "record the format version in the ARCHIVE_META file" }
+
+    async fn run(&self, _archive: &SimpleFileBasedBlockArchive) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// All known migrations, in order.
+pub fn migrations() -> Vec<Box<dyn Migration>> {
+    vec![Box::new(AddMetaFile)]
+}
+
+/// Upgrade an archive to [CURRENT_FORMAT_VERSION], running each migration in turn.
+///
+/// Progress is recorded in the meta file before and after each step, so an interrupted migration
+/// resumes with the step that was interrupted. Returns the descriptions of the steps that were run.
+pub async fn migrate_to_latest(archive: &SimpleFileBasedBlockArchive) -> Result<Vec<&'static str>> {
+    let root_path = archive.root_path.as_path();
+    let mut meta = ArchiveMeta::read(root_path).await?
+        .unwrap_or_default();
+    if meta.format_version > CURRENT_FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion(meta.format_version));
+    }
+    let mut done = Vec::new();
+    for migration in migrations() {
+        if migration.source_version() != meta.format_version {
+            continue;
+        }
+        let to_version = meta.format_version + 1;
+        // the meta file is only written once there is one, a version 0 archive has none
+        if meta.format_version > 0 {
+            meta.migrating_to = Some(to_version);
+            meta.write(root_path).await?;
+        }
+        migration.run(archive).await?;
+        meta.format_version = to_version;
+        meta.migrating_to = None;
+        meta.write(root_path).await?;
+        done.push(migration.description());
+    }
+    Ok(done)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use super::*;
+
+    // A legacy archive without a meta file is migrated to the current version.
+    #[tokio::test]
+    async fn test_migrate_legacy_archive() {
+        let root = Temp::new_dir().unwrap();
+        // a directory that is not empty is an existing archive
+        tokio::fs::write(root.to_path_buf().join("README"), b"blocks").await.unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert_eq!(archive.format_version().await.unwrap(), 0);
+        assert_eq!(migrate_to_latest(&archive).await.unwrap().len(), 1);
+        assert_eq!(archive.format_version().await.unwrap(), CURRENT_FORMAT_VERSION);
+        // nothing left to do
+        assert!(migrate_to_latest(&archive).await.unwrap().is_empty());
+    }
+
+    // An interrupted migration is run again and completed.
+    #[tokio::test]
+    async fn test_resume_migration() {
+        let root = Temp::new_dir().unwrap();
+        let meta = ArchiveMeta { format_version: 0, migrating_to: Some(1), network: Some(Network::Testnet), ..Default::default() };
+        meta.write(&root.to_path_buf()).await.unwrap();
+        assert_eq!(ArchiveMeta::read(&root.to_path_buf()).await.unwrap().unwrap(), meta);
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert_eq!(migrate_to_latest(&archive).await.unwrap().len(), 1);
+        let meta = ArchiveMeta::read(&root.to_path_buf()).await.unwrap().unwrap();
+        assert_eq!(meta.format_version, CURRENT_FORMAT_VERSION);
+        assert_eq!(meta.migrating_to, None);
+        assert_eq!(meta.network, Some(Network::Testnet));
+    }
+
+    // An archive written by a newer version of the library cannot be opened.
+    #[tokio::test]
+    async fn test_newer_format_version() {
+        let root = Temp::new_dir().unwrap();
+        let meta = ArchiveMeta { format_version: CURRENT_FORMAT_VERSION + 1, ..Default::default() };
+        meta.write(&root.to_path_buf()).await.unwrap();
+        match SimpleFileBasedBlockArchive::new(root.to_path_buf()).await {
+            Err(Error::UnsupportedFormatVersion(v)) => assert_eq!(v, CURRENT_FORMAT_VERSION + 1),
+            _ => panic!("expected an unsupported format version"),
+        }
+        assert!(SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.is_ok());
+    }
+
+    // A new archive gets a meta file with the current version, and the recorded settings are
+    // used when it is opened.
+    #[tokio::test]
+    async fn test_new_archive_meta() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let meta = ArchiveMeta::read(&root.to_path_buf()).await.unwrap().unwrap();
+        assert_eq!(meta.format_version, CURRENT_FORMAT_VERSION);
+        assert!(meta.created.is_some());
+        assert!(migrate_to_latest(&archive).await.unwrap().is_empty());
+        archive.set_compression(true).await.unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert!(archive.compress);
+        assert_eq!(ArchiveMeta::read(&root.to_path_buf()).await.unwrap().unwrap().created, meta.created);
+    }
+}
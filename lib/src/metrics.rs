@@ -0,0 +1,326 @@
+//! Prometheus metrics for archive operations.
+//!
+//! Wrap an archive in a [MeteredBlockArchive] to record the operations on it, and expose the
+//! metrics with [serve_metrics]. The metrics are global, all metered archives in a process add to
+//! the same counters.
+//!
+//! Example code:
+//!     let archive = MeteredBlockArchive::new(archive);
+//!     let listener = TcpListener::bind("127.0.0.1:9100").await?;
+//!     tokio::spawn(metrics::serve_metrics(listener));
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use tracing::debug;
+use tokio::io::{AsyncRead, BufReader, ReadBuf};
+use tokio::net::TcpListener;
+use crate::block_archive::{BlockHashListStream, BlockSizeStream};
+use crate::http::{read_request, write_response};
+use crate::{BlockArchive, BlockAttrs, Result};
+
+// the upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 10] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+// the latencies of an operation
+#[derive(Default)]
+struct Histogram {
+    // the number of observations in each bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+struct Metrics {
+    blocks_read: AtomicU64,
+    blocks_written: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    gc_blocks: AtomicU64,
+    gc_bytes: AtomicU64,
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+static METRICS: Metrics = Metrics {
+    blocks_read: AtomicU64::new(0),
+    blocks_written: AtomicU64::new(0),
+    bytes_read: AtomicU64::new(0),
+    bytes_written: AtomicU64::new(0),
+    gc_blocks: AtomicU64::new(0),
+    gc_bytes: AtomicU64::new(0),
+    latency: Mutex::new(BTreeMap::new()),
+    errors: Mutex::new(BTreeMap::new()),
+};
+
+/// A block archive that records metrics for every operation.
+///
+/// The latency of [BlockArchive::get_block] is the time taken to open the block, the bytes are
+/// counted as they are read.
+pub struct MeteredBlockArchive<A: BlockArchive> {
+    archive: A,
+}
+
+impl<A: BlockArchive> MeteredBlockArchive<A> {
+    /// Wrap an archive.
+    pub fn new(archive: A) -> MeteredBlockArchive<A> {
+        MeteredBlockArchive { archive }
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for MeteredBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let start = Instant::now();
+        let r = self.archive.get_block(block_hash).await;
+        record("get_block", start, &r);
+        let reader = r?;
+        METRICS.blocks_read.fetch_add(1, Ordering::Relaxed);
+        Ok(Box::new(CountingReader { inner: reader, counter: &METRICS.bytes_read }))
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let start = Instant::now();
+        let r = self.archive.get_block_range(block_hash, offset, length).await;
+        record("get_block_range", start, &r);
+        Ok(Box::new(CountingReader { inner: r?, counter: &METRICS.bytes_read }))
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        let start = Instant::now();
+        let r = self.archive.block_exists(block_hash).await;
+        record("block_exists", start, &r);
+        r
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        let start = Instant::now();
+        let r = self.archive.blocks_exist(block_hashes).await;
+        record("blocks_exist", start, &r);
+        r
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let start = Instant::now();
+        let mut reader = CountingReader { inner: block, counter: &METRICS.bytes_written };
+        let r = self.archive.store_block(block_hash, &mut reader).await;
+        record("store_block", start, &r);
+        if r.is_ok() {
+            METRICS.blocks_written.fetch_add(1, Ordering::Relaxed);
+        }
+        r
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        let start = Instant::now();
+        let r = self.archive.remove_block(block_hash).await;
+        record("remove_block", start, &r);
+        r
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        let start = Instant::now();
+        let r = self.archive.block_size(block_hash).await;
+        record("block_size", start, &r);
+        r
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        let start = Instant::now();
+        let r = self.archive.block_header(block_hash).await;
+        record("block_header", start, &r);
+        r
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        let start = Instant::now();
+        let r = self.archive.block_headers(block_hashes).await;
+        record("block_headers", start, &r);
+        r
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let start = Instant::now();
+        let r = self.archive.block_list().await;
+        record("block_list", start, &r);
+        r
+    }
+
+    async fn block_sizes<'a>(&'a mut self) -> Result<BlockSizeStream<'a>> {
+        let start = Instant::now();
+        let r = self.archive.block_sizes().await;
+        record("block_sizes", start, &r);
+        r
+    }
+
+    async fn total_size(&mut self) -> Result<u64> {
+        let start = Instant::now();
+        let r = self.archive.total_size().await;
+        record("total_size", start, &r);
+        r
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        let start = Instant::now();
+        let r = self.archive.set_block_attr(block_hash, key, value).await;
+        record("set_block_attr", start, &r);
+        r
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        let start = Instant::now();
+        let r = self.archive.remove_block_attr(block_hash, key).await;
+        record("remove_block_attr", start, &r);
+        r
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        let start = Instant::now();
+        let r = self.archive.get_block_attrs(block_hash).await;
+        record("get_block_attrs", start, &r);
+        r
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        let start = Instant::now();
+        let r = self.archive.verify_checksum(block_hash).await;
+        record("verify_checksum", start, &r);
+        r
+    }
+}
+
+// A reader that counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    counter: &'static AtomicU64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.counter.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        r
+    }
+}
+
+// Record the latency and outcome of an operation.
+fn record<T>(operation: &'static str, start: Instant, result: &Result<T>) {
+    let secs = start.elapsed().as_secs_f64();
+    {
+        let mut latency = METRICS.latency.lock().unwrap();
+        let histogram = latency.entry(operation).or_default();
+        histogram.count += 1;
+        histogram.sum += secs;
+        if let Some(i) = BUCKETS.iter().position(|b| secs <= *b) {
+            histogram.buckets[i] += 1;
+        }
+    }
+    if let Err(e) = result {
+        *METRICS.errors.lock().unwrap().entry(e.kind()).or_default() += 1;
+    }
+}
+
+// Record a block collected by crate::gc::collect_garbage.
+pub(crate) fn record_gc(bytes: u64) {
+    METRICS.gc_blocks.fetch_add(1, Ordering::Relaxed);
+    METRICS.gc_bytes.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Render the metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut s = String::new();
+    let counters = [
+        ("blockarchive_blocks_read_total", "Blocks opened for reading.", &METRICS.blocks_read),
+        ("blockarchive_blocks_written_total", "Blocks stored.", &METRICS.blocks_written),
+        ("blockarchive_bytes_read_total", "Bytes of block data read.", &METRICS.bytes_read),
+        ("blockarchive_bytes_written_total", "Bytes of block data stored.", &METRICS.bytes_written),
+        ("blockarchive_gc_blocks_total", "Blocks of stale forks removed or quarantined.", &METRICS.gc_blocks),
+        ("blockarchive_gc_bytes_total", "Bytes of block data reclaimed from stale forks.", &METRICS.gc_bytes),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(s, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value.load(Ordering::Relaxed));
+    }
+    let name = "blockarchive_operation_duration_seconds";
+    let _ = writeln!(s, "# HELP {} The latency of archive operations.\n# TYPE {} histogram", name, name);
+    for (operation, histogram) in METRICS.latency.lock().unwrap().iter() {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(s, "{}_bucket{{operation=\"{}\",le=\"{}\"}} {}", name, operation, bound, cumulative);
+        }
+        let _ = writeln!(s, "{}_bucket{{operation=\"{}\",le=\"+Inf\"}} {}", name, operation, histogram.count);
+        let _ = writeln!(s, "{}_sum{{operation=\"{}\"}} {}", name, operation, histogram.sum);
+        let _ = writeln!(s, "{}_count{{operation=\"{}\"}} {}", name, operation, histogram.count);
+    }
+    let name = "blockarchive_errors_total";
+    let _ = writeln!(s, "# HELP {} Errors returned by archive operations.\n# TYPE {} counter", name, name);
+    for (kind, count) in METRICS.errors.lock().unwrap().iter() {
+        let _ = writeln!(s, "{}{{kind=\"{}\"}} {}", name, kind, count);
+    }
+    s
+}
+
+/// Serve the metrics on GET /metrics, until an error occurs accepting a connection.
+pub async fn serve_metrics(listener: TcpListener) -> Result<()> {
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(socket);
+            let r = match read_request(&mut reader).await {
+                Ok(Some(request)) if request.method == "GET" && request.path == "/metrics" => {
+                    write_response(reader.get_mut(), 200, "text/plain; version=0.0.4", render().as_bytes()).await
+                }
+                Ok(Some(_)) => write_response(reader.get_mut(), 404, "text/plain", b"not found").await,
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = r {
+                debug!(%addr, error = %e, "error handling metrics request");
+            }
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use tokio::io::AsyncReadExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Get the value of a metric from the rendered metrics.
+    fn value(metrics: &str, name: &str) -> u64 {
+        metrics.lines().find_map(|l| l.strip_prefix(name)?.strip_prefix(' ')?.parse().ok()).unwrap()
+    }
+
+    // The metrics are global and the tests run in parallel, so only check for increases.
+    #[tokio::test]
+    async fn test_metered_archive() {
+        let inner = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let archive = MeteredBlockArchive::new(inner);
+        let before = render();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let missing = BlockHash::from_hex("00000000000000000000000000000000000000000000000000000000000000ff").unwrap();
+        assert!(archive.get_block(&missing).await.is_err());
+        let after = render();
+        assert!(value(&after, "blockarchive_blocks_read_total") > value(&before, "blockarchive_blocks_read_total"));
+        assert!(value(&after, "blockarchive_bytes_read_total") >= value(&before, "blockarchive_bytes_read_total") + block.len() as u64);
+        assert!(value(&after, "blockarchive_errors_total{kind=\"block_not_found\"}") >= 1);
+        assert!(value(&after, "blockarchive_operation_duration_seconds_count{operation=\"get_block\"}") >= 2);
+    }
+}
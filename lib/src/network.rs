@@ -0,0 +1,176 @@
+use std::fmt;
+use std::str::FromStr;
+use bitcoinsv::bitcoin::BlockHash;
+use hex::FromHex;
+use crate::Error;
+
+/// The Bitcoin SV network that the blocks in an archive belong to.
+///
+/// The archive itself does not care about the network, but anything that interprets the chain
+/// (linking blocks back to genesis, checking proof of work, reading node data files) needs the
+/// parameters of the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    /// The Scaling Test Network.
+    Stn,
+    Regtest,
+}
+
+impl Network {
+    /// All known networks.
+    pub const ALL: [Network; 4] = [Network::Mainnet, Network::Testnet, Network::Stn, Network::Regtest];
+
+    /// The hash of the genesis block of the network.
+    pub fn genesis_hash(&self) -> BlockHash {
+        let s = match self {
+            Network::Mainnet => "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            // the STN re-uses the testnet genesis block
+            Network::Testnet | Network::Stn => "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            Network::Regtest => "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+        };
+        BlockHash::from_hex(s).unwrap()
+    }
+
+    /// The magic bytes that start every P2P message on the network.
+    pub fn net_magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xe3, 0xe1, 0xf3, 0xe8],
+            Network::Testnet => [0xf4, 0xe5, 0xf3, 0xf4],
+            Network::Stn => [0xfb, 0xce, 0xc4, 0xf9],
+            Network::Regtest => [0xda, 0xb5, 0xbf, 0xfa],
+        }
+    }
+
+    /// The magic bytes used by the node to frame blocks in its blk*.dat files.
+    ///
+    /// These are the original Bitcoin network magic bytes, which differ from [Network::net_magic].
+    pub fn disk_magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+            Network::Stn => [0xfb, 0xce, 0xc4, 0xf9],
+            Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+
+    /// The proof of work limit in compact ("bits") format, the easiest target allowed.
+    pub fn pow_limit_bits(&self) -> u32 {
+        match self {
+            Network::Regtest => 0x207fffff,
+            _ => 0x1d00ffff,
+        }
+    }
+
+    /// The number of blocks between halvings of the block subsidy.
+    pub fn halving_interval(&self) -> u64 {
+        match self {
+            Network::Regtest => 150,
+            _ => 210_000,
+        }
+    }
+
+    /// The new coins that the coinbase of the block at the height may claim, in satoshis.
+    pub fn block_subsidy(&self, height: u64) -> u64 {
+        match height / self.halving_interval() {
+            halvings if halvings < 64 => (50 * 100_000_000) >> halvings,
+            _ => 0,
+        }
+    }
+
+    /// The heights and hashes of well known blocks of the network, which the best chain of an
+    /// archive of the network must pass through.
+    ///
+    /// These are blocks from before the forks of BCH and BSV, so they are shared by all chains
+    /// that descend from Bitcoin. Regtest and the STN have none.
+    pub fn checkpoints(&self) -> Vec<(u64, BlockHash)> {
+        let checkpoints: &[(u64, &str)] = match self {
+            Network::Mainnet => &[
+                (11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+                (33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+                (74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+                (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+                (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+                (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+                (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+                (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+                (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+                (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+                (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+                (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+                (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+            ],
+            Network::Testnet => &[
+                (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+            ],
+            Network::Stn | Network::Regtest => &[],
+        };
+        checkpoints.iter().map(|(height, h)| (*height, BlockHash::from_hex(h).unwrap())).collect()
+    }
+
+    /// The short name of the network, as accepted by [Network::from_str].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Stn => "stn",
+            Network::Regtest => "regtest",
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mainnet" | "main" => Ok(Network::Mainnet),
+            "testnet" | "test" => Ok(Network::Testnet),
+            "stn" => Ok(Network::Stn),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(Error::UnknownNetwork(s.to_string())),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The names of the networks should round trip.
+    #[test]
+    fn network_names() {
+        for n in Network::ALL {
+            assert_eq!(Network::from_str(n.name()).unwrap(), n);
+        }
+        assert_eq!(Network::from_str("Main").unwrap(), Network::Mainnet);
+        assert!(Network::from_str("litecoin").is_err());
+    }
+
+    // The genesis hashes should parse, the STN shares the testnet genesis block.
+    #[test]
+    fn genesis_hashes() {
+        assert_eq!(Network::Mainnet.genesis_hash().to_string(), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+        assert_eq!(Network::Stn.genesis_hash(), Network::Testnet.genesis_hash());
+        assert_ne!(Network::Regtest.genesis_hash(), Network::Mainnet.genesis_hash());
+    }
+
+    // The subsidy halves every 210000 blocks, or every 150 blocks on regtest.
+    #[test]
+    fn block_subsidy() {
+        assert_eq!(Network::Mainnet.block_subsidy(0), 5_000_000_000);
+        assert_eq!(Network::Mainnet.block_subsidy(227_495), 2_500_000_000);
+        assert_eq!(Network::Mainnet.block_subsidy(840_000), 312_500_000);
+        assert_eq!(Network::Regtest.block_subsidy(150), 2_500_000_000);
+        assert_eq!(Network::Mainnet.block_subsidy(64 * 210_000), 0);
+    }
+}
@@ -0,0 +1,456 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use futures::{StreamExt as _, TryStreamExt};
+use hex::{FromHex, ToHex};
+use object_store::path::Path;
+use object_store::{GetOptions, GetRange, ObjectStore, PutPayload, WriteMultipart};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+use crate::{BlockArchive, BlockAttrs, Error, Result, WritePolicy};
+use crate::block_archive::{check_block_checksum, check_blocks_exist, checked_block, decode_block_attrs, encode_block_attrs, validate_block_attr, BlockHashListStream, BlockHashListStreamFromChannel, ChecksumReader};
+
+// the size of the parts of a multipart upload, blocks smaller than this are uploaded in one request
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+// the number of parts of a multipart upload that are uploaded at the same time
+const PART_CONCURRENCY: usize = 4;
+
+// the size of an encoded block header
+const HEADER_SIZE: usize = 80;
+
+// the size of the channel used to send block hashes, the listing waits for the channel to be read
+// when it is full
+const LIST_BUFFER: usize = 1000;
+
+// the number of headers that are fetched at the same time by block_headers()
+const HEADER_CONCURRENCY: usize = 32;
+
+// the number of blocks that blocks_exist() checks at the same time
+const EXISTS_CONCURRENCY: usize = 32;
+
+/// A block archive stored in an object store, see [AzureBlockArchive] and [GcsBlockArchive].
+///
+/// Blocks are stored as objects under a prefix, using the same layout as the
+/// [crate::SimpleFileBasedBlockArchive] and the S3 archive, so an archive can be copied between
+/// stores with standard tools.
+///
+/// Example: prefix/31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin
+///
+/// Any store supported by the object_store crate can be used with [ObjectStoreBlockArchive::with_store].
+#[derive(Debug)]
+pub struct ObjectStoreBlockArchive<S: ObjectStore> {
+    /// The store in which the blocks are stored.
+    store: Arc<S>,
+    /// The prefix of the block objects, may be empty.
+    pub prefix: String,
+    // the checks made on blocks before they are stored
+    write_policy: WritePolicy,
+    // whether store_block() replaces an existing block
+    overwrite: bool,
+}
+
+/// A block archive stored in an Azure Blob Storage container.
+///
+/// The account and credentials are taken from the usual AZURE_STORAGE_* environment variables.
+///
+/// Example code:
+///     let archive = AzureBlockArchive::new("my-container", "mainnet").await?;
+#[cfg(feature = "azure")]
+pub type AzureBlockArchive = ObjectStoreBlockArchive<object_store::azure::MicrosoftAzure>;
+
+/// A block archive stored in a Google Cloud Storage bucket.
+///
+/// The credentials are taken from the usual GOOGLE_* environment variables, such as
+/// GOOGLE_APPLICATION_CREDENTIALS.
+///
+/// Example code:
+///     let archive = GcsBlockArchive::new("my-bucket", "mainnet").await?;
+#[cfg(feature = "gcs")]
+pub type GcsBlockArchive = ObjectStoreBlockArchive<object_store::gcp::GoogleCloudStorage>;
+
+#[cfg(feature = "azure")]
+impl ObjectStoreBlockArchive<object_store::azure::MicrosoftAzure> {
+    /// Connect to a block archive in a container.
+    ///
+    /// Fails with [Error::StorageUnavailable] if the container can not be reached.
+    pub async fn new(container: &str, prefix: &str) -> Result<AzureBlockArchive> {
+        let store = object_store::azure::MicrosoftAzureBuilder::from_env()
+            .with_container_name(container)
+            .build()
+            .map_err(|e| Error::StorageUnavailable(format!("container {}: {}", container, e)))?;
+        Self::with_store(Arc::new(store), prefix).await
+    }
+}
+
+#[cfg(feature = "gcs")]
+impl ObjectStoreBlockArchive<object_store::gcp::GoogleCloudStorage> {
+    /// Connect to a block archive in a bucket.
+    ///
+    /// Fails with [Error::StorageUnavailable] if the bucket can not be reached.
+    pub async fn new(bucket: &str, prefix: &str) -> Result<GcsBlockArchive> {
+        let store = object_store::gcp::GoogleCloudStorageBuilder::from_env()
+            .with_bucket_name(bucket)
+            .build()
+            .map_err(|e| Error::StorageUnavailable(format!("bucket {}: {}", bucket, e)))?;
+        Self::with_store(Arc::new(store), prefix).await
+    }
+}
+
+impl<S: ObjectStore> ObjectStoreBlockArchive<S> {
+    /// Create a block archive using an already configured store.
+    ///
+    /// Fails with [Error::StorageUnavailable] if the store can not be reached.
+    pub async fn with_store(store: Arc<S>, prefix: &str) -> Result<ObjectStoreBlockArchive<S>> {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        // a listing of a single level is the cheapest request that checks the credentials
+        store.list_with_delimiter(Some(&Path::from(prefix.as_str()))).await
+            .map_err(|e| Error::StorageUnavailable(e.to_string()))?;
+        Ok(ObjectStoreBlockArchive {
+            store,
+            prefix,
+            write_policy: WritePolicy::default(),
+            overwrite: false,
+        })
+    }
+
+    /// Set the checks that are made on blocks before they are stored, the default is to make no
+    /// checks.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> ObjectStoreBlockArchive<S> {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Set whether storing a block that is already in the archive replaces it, instead of
+    /// failing with [Error::BlockExists].
+    pub fn with_overwrite(mut self, overwrite: bool) -> ObjectStoreBlockArchive<S> {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Get the store.
+    pub fn store(&self) -> &Arc<S> {
+        &self.store
+    }
+
+    // Get a reader for part of an object, the range is None for the whole object.
+    async fn get_reader(&self, path: &Path, range: Option<GetRange>) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let options = GetOptions { range, ..GetOptions::default() };
+        let r = match self.store.get_opts(path, options).await {
+            Ok(r) => r,
+            Err(object_store::Error::NotFound { .. }) => return Err(Error::BlockNotFound),
+            Err(e) => return Err(store_error(e)),
+        };
+        let stream = r.into_stream().map_err(std::io::Error::other);
+        Ok(Box::new(StreamReader::new(stream)))
+    }
+
+    // Get a small object in full, None if it does not exist.
+    async fn get_small(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let r = match self.store.get(path).await {
+            Ok(r) => r,
+            Err(object_store::Error::NotFound { .. }) => return Ok(None),
+            Err(e) => return Err(store_error(e)),
+        };
+        Ok(Some(r.bytes().await.map_err(store_error)?.to_vec()))
+    }
+
+    // Upload a block in parts, the first part has already been read into buf.
+    async fn multipart_upload(&self, path: &Path, block: &mut (dyn AsyncRead + Unpin + Send), mut buf: Vec<u8>) -> Result<()> {
+        let upload = self.store.put_multipart(path).await.map_err(store_error)?;
+        let mut writer = WriteMultipart::new_with_chunk_size(upload, PART_SIZE);
+        let r = loop {
+            if buf.is_empty() {
+                break Ok(());
+            }
+            // bounds the memory used to the parts in flight
+            if let Err(e) = writer.wait_for_capacity(PART_CONCURRENCY).await {
+                break Err(store_error(e));
+            }
+            writer.write(&buf);
+            buf = match read_part(block).await {
+                Ok(b) => b,
+                Err(e) => break Err(e),
+            };
+        };
+        match r {
+            Ok(_) => writer.finish().await.map(|_| ()).map_err(store_error),
+            Err(e) => {
+                // dont leave the parts lying around, they are charged for
+                let _ = writer.abort().await;
+                Err(e)
+            }
+        }
+    }
+
+    // Delete an object, it is not an error if it does not exist.
+    async fn delete(&self, path: &Path) -> Result<()> {
+        match self.store.delete(path).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    // Write the attributes of a block, removing the attributes object if there are none.
+    async fn write_block_attrs(&self, block_hash: &BlockHash, attrs: &BlockAttrs) -> Result<()> {
+        let path = attrs_path(&self.prefix, block_hash);
+        if attrs.is_empty() {
+            self.delete(&path).await
+        } else {
+            self.store.put(&path, PutPayload::from(encode_block_attrs(attrs).into_bytes())).await.map_err(store_error)?;
+            Ok(())
+        }
+    }
+
+    // Get a list of all blocks in the background, sending results to the channel.
+    async fn block_list_bgrnd(store: Arc<S>, prefix: String, transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<()> {
+        let list_prefix = Path::from(prefix.as_str());
+        // the store fetches the pages of the listing as the stream is read
+        let mut objects = store.list(Some(&list_prefix));
+        while let Some(object) = objects.next().await {
+            let object = object.map_err(store_error)?;
+            let location = object.location.as_ref();
+            // ignore objects that are not blocks, or are not in the correct location
+            let hash = match location.rsplit('/').next().and_then(|n| n.strip_suffix(".bin")).map(BlockHash::from_hex) {
+                Some(Ok(h)) => h,
+                _ => continue,
+            };
+            if location != block_path(&prefix, &hash).as_ref() {
+                continue;
+            }
+            if transmit.send(hash).await.is_err() {
+                return Ok(());      // this is not an error, the receiver has merely dropped
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: ObjectStore> BlockArchive for ObjectStoreBlockArchive<S> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.get_reader(&block_path(&self.prefix, block_hash), None).await
+    }
+
+    /// Only the range is fetched, using a range request.
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        // the stores differ in how they treat a range past the end of the object, so clip it first
+        let size = self.block_size(block_hash).await? as u64;
+        let end = offset.saturating_add(length).min(size);
+        if offset >= end {
+            return Ok(Box::new(tokio::io::empty()));
+        }
+        let range = GetRange::Bounded(offset as usize..end as usize);
+        self.get_reader(&block_path(&self.prefix, block_hash), Some(range)).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        match self.store.head(&block_path(&self.prefix, block_hash)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    /// The blocks are checked with several requests at a time.
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        check_blocks_exist(self, block_hashes, EXISTS_CONCURRENCY).await
+    }
+
+    /// Blocks larger than 8MiB are uploaded with a multipart upload, so the block is never held
+    /// in memory in full.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        if !self.overwrite && self.block_exists(block_hash).await? {
+            return Err(Error::BlockExists);
+        }
+        let path = block_path(&self.prefix, block_hash);
+        let block = checked_block(self.write_policy, block_hash, block).await?;
+        let mut reader = ChecksumReader::new(block);
+        let buf = read_part(&mut reader).await?;
+        if buf.len() < PART_SIZE {
+            self.store.put(&path, PutPayload::from(buf)).await.map_err(store_error)?;
+        } else {
+            self.multipart_upload(&path, &mut reader, buf).await?;
+        }
+        self.store.put(&checksum_path(&self.prefix, block_hash), PutPayload::from(reader.checksum().into_bytes())).await
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    /// The attributes and checksum objects are removed before the block.
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        if !self.block_exists(block_hash).await? {
+            return Err(Error::BlockNotFound);
+        }
+        for path in [attrs_path(&self.prefix, block_hash), checksum_path(&self.prefix, block_hash), block_path(&self.prefix, block_hash)] {
+            self.delete(&path).await?;
+        }
+        Ok(())
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        match self.store.head(&block_path(&self.prefix, block_hash)).await {
+            Ok(meta) => Ok(meta.size),
+            Err(object_store::Error::NotFound { .. }) => Err(Error::BlockNotFound),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        // only fetch the header, not the whole block
+        let mut reader = self.get_reader(&block_path(&self.prefix, block_hash), Some(GetRange::Bounded(0..HEADER_SIZE))).await?;
+        Ok(BlockHeader::from_binary(&mut reader).await?)
+    }
+
+    /// The headers are fetched with several requests at a time.
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        futures::stream::iter(block_hashes.to_vec())
+            .map(move |h| async move { self.block_header(&h).await.map(|header| (h, header)) })
+            .buffered(HEADER_CONCURRENCY)
+            .try_collect()
+            .await
+    }
+
+    /// Get a list of all the blocks in the archive, using paginated listing of the objects under
+    /// the prefix.
+    ///
+    /// Objects that are not in the correct location for their hash are not returned.
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER);
+        let handle = tokio::spawn(Self::block_list_bgrnd(self.store.clone(), self.prefix.clone(), tx));
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        validate_block_attr(key, value)?;
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        attrs.insert(key.to_string(), value.to_string());
+        self.write_block_attrs(block_hash, &attrs).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        if attrs.remove(key).is_some() {
+            self.write_block_attrs(block_hash, &attrs).await?;
+        }
+        Ok(())
+    }
+
+    /// Attributes are stored in an object next to the block, with an "attrs" extension.
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        if !self.block_exists(block_hash).await? {
+            return Err(Error::BlockNotFound);
+        }
+        let path = attrs_path(&self.prefix, block_hash);
+        match self.get_small(&path).await? {
+            Some(bytes) => {
+                let s = String::from_utf8(bytes).map_err(|_| Error::InvalidAttribute(path.to_string()))?;
+                decode_block_attrs(&s)
+            }
+            // no attributes have been set
+            None => Ok(BlockAttrs::new()),
+        }
+    }
+
+    /// The checksum is stored in an object next to the block, with a "sha256" extension.
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        let checksum = match self.get_small(&checksum_path(&self.prefix, block_hash)).await? {
+            Some(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+            None => {
+                return if self.block_exists(block_hash).await? { Ok(false) } else { Err(Error::BlockNotFound) };
+            }
+        };
+        check_block_checksum(self, block_hash, &checksum).await?;
+        Ok(true)
+    }
+}
+
+// Get the path of the object for a block.
+fn block_path(prefix: &str, hash: &BlockHash) -> Path {
+    object_path(prefix, hash, "bin")
+}
+
+// Get the path of the object holding the attributes of a block.
+fn attrs_path(prefix: &str, hash: &BlockHash) -> Path {
+    object_path(prefix, hash, "attrs")
+}
+
+// Get the path of the object holding the checksum of a block.
+fn checksum_path(prefix: &str, hash: &BlockHash) -> Path {
+    object_path(prefix, hash, "sha256")
+}
+
+// Get the path of an object next to a block, with the given extension.
+fn object_path(prefix: &str, hash: &BlockHash, extension: &str) -> Path {
+    let s: String = hash.encode_hex();
+    let path = format!("{}/{}/{}.{}", &s[62..], &s[60..62], s, extension);
+    if prefix.is_empty() {
+        Path::from(path)
+    } else {
+        Path::from(format!("{}/{}", prefix, path))
+    }
+}
+
+// Read up to PART_SIZE bytes, less only if the end of the block is reached.
+async fn read_part(block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(PART_SIZE);
+    block.take(PART_SIZE as u64).read_to_end(&mut buf).await?;
+    Ok(buf)
+}
+
+// Convert an error from the store.
+fn store_error(e: object_store::Error) -> Error {
+    Error::StorageUnavailable(e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+    use tokio::io::AsyncReadExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Test the path generation, which must match the layout of the SimpleFileBasedBlockArchive.
+    #[test]
+    fn check_path_from_hash() {
+        let h = BlockHash::from_hex("00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531").unwrap();
+        assert_eq!(block_path("", &h).as_ref(), "31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin");
+        assert_eq!(block_path("mainnet", &h).as_ref(), "mainnet/31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin");
+        assert_eq!(attrs_path("mainnet", &h).as_ref(), "mainnet/31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.attrs");
+    }
+
+    // Store, read, list and remove a block in an in-memory store.
+    #[tokio::test]
+    async fn test_object_store_archive() {
+        let source = SimpleFileBasedBlockArchive::new(std::path::PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        source.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+
+        let mut archive = ObjectStoreBlockArchive::with_store(Arc::new(InMemory::new()), "mainnet/").await.unwrap();
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        assert!(matches!(archive.store_block(&h, &mut &block[..]).await, Err(Error::BlockExists)));
+        let mut read = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, block);
+        assert_eq!(archive.block_size(&h).await.unwrap(), block.len());
+        assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+        let mut range = Vec::new();
+        archive.get_block_range(&h, 10, 20).await.unwrap().read_to_end(&mut range).await.unwrap();
+        assert_eq!(range, block[10..30]);
+        range.clear();
+        archive.get_block_range(&h, block.len() as u64, 20).await.unwrap().read_to_end(&mut range).await.unwrap();
+        assert!(range.is_empty());
+        assert!(archive.verify_checksum(&h).await.unwrap());
+
+        archive.set_block_attr(&h, "source", "test").await.unwrap();
+        assert_eq!(archive.get_block_attrs(&h).await.unwrap().get("source").unwrap(), "test");
+        let hashes: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        assert_eq!(hashes, vec![h]);
+        archive.remove_block(&h).await.unwrap();
+        assert!(matches!(archive.get_block(&h).await, Err(Error::BlockNotFound)));
+        assert!(archive.block_list().await.unwrap().next().await.is_none());
+    }
+}
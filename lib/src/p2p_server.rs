@@ -0,0 +1,296 @@
+//! A server that answers requests for blocks and headers over the peer-to-peer protocol.
+//!
+//! Nodes that are syncing can connect to the server and download the blocks of the archive,
+//! instead of downloading them from other nodes. The server answers getdata messages for blocks,
+//! with a notfound message for the blocks that it does not have, and getheaders messages, with
+//! the headers of the best chain. It does not relay transactions or announce new blocks, and
+//! ignores every other message apart from ping.
+//!
+//! The headers come from a [ChainIndex] that is built when the server starts, which reads the
+//! headers file of the archive if it has one. Blocks added to the archive after that are served
+//! but their headers are not.
+//!
+//! Example code:
+//!     let listener = TcpListener::bind("127.0.0.1:8333").await?;
+//!     p2p_server::serve(archive, listener, Network::Mainnet).await?;
+use std::sync::Arc;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use ring::digest::{digest, Context, SHA256};
+use tracing::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use crate::fetch::{checksum, decode_header, encode_message, message_header, version_payload, MAX_HEADERS,
+                   MAX_MESSAGE_SIZE, MESSAGE_HEADER_SIZE, MSG_BLOCK};
+use crate::headers::{encode_header, HEADER_SIZE};
+use crate::merkle::write_varint;
+use crate::txindex::{take, take_varint};
+use crate::{BlockArchive, ChainIndex, Error, Network, Result};
+
+// the services that we provide, a full copy of the blocks
+const NODE_NETWORK: u64 = 1;
+
+// the most entries in an inventory message
+const MAX_INV_SIZE: u64 = 50_000;
+
+// the size of an inventory entry, the type and the hash
+const INV_ENTRY_SIZE: usize = 36;
+
+// the size of the buffer used to hash a block
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+// The state shared by all connections.
+struct ServerState<A> {
+    archive: A,
+    chain: ChainIndex,
+    network: Network,
+}
+
+/// Serve the archive to nodes over the peer-to-peer protocol, until an error occurs accepting a
+/// connection.
+///
+/// The chain index is built before the server starts accepting connections, which reads the
+/// header of every block in the archive.
+pub async fn serve<A: BlockArchive + 'static>(mut archive: A, listener: TcpListener, network: Network) -> Result<()> {
+    let chain = ChainIndex::build(&mut archive).await?;
+    let state = Arc::new(ServerState { archive, chain, network });
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            debug!(%addr, "peer connected");
+            if let Err(e) = handle_connection(&state, socket).await {
+                debug!(%addr, error = %e, "error handling peer");
+            }
+        });
+    }
+}
+
+// Answer the messages from a peer until it disconnects.
+async fn handle_connection<A: BlockArchive>(state: &ServerState<A>, socket: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(socket);
+    while let Some((command, payload)) = read_message(&mut reader, state.network).await? {
+        let socket = reader.get_mut();
+        match command.as_str() {
+            "version" => {
+                let height = state.chain.tip().map_or(0, |(_, h)| h as i32);
+                socket.write_all(&encode_message(state.network, "version", &version_payload(NODE_NETWORK, height))).await?;
+                socket.write_all(&encode_message(state.network, "verack", &[])).await?;
+            }
+            "ping" => socket.write_all(&encode_message(state.network, "pong", &payload)).await?,
+            "getdata" => get_data(state, socket, &payload).await?,
+            "getheaders" => get_headers(state, socket, &payload).await?,
+            _ => debug!(%command, "ignoring message"),
+        }
+    }
+    Ok(())
+}
+
+// Send the requested blocks, followed by a notfound message for the blocks that we do not have
+// and for anything else that was requested.
+async fn get_data<A: BlockArchive>(state: &ServerState<A>, socket: &mut TcpStream, payload: &[u8]) -> Result<()> {
+    let entries = decode_inv(payload).ok_or_else(|| Error::PeerError("invalid getdata message".to_string()))?;
+    let mut not_found = Vec::new();
+    for entry in entries {
+        let inv_type = u32::from_le_bytes(entry[..4].try_into().unwrap());
+        let block_hash = BlockHash { hash: entry[4..].try_into().unwrap() };
+        if inv_type != MSG_BLOCK || !send_block(state, socket, &block_hash).await? {
+            not_found.push(entry);
+        }
+    }
+    if !not_found.is_empty() {
+        let mut notfound = Vec::new();
+        write_varint(&mut notfound, not_found.len() as u64);
+        for entry in not_found {
+            notfound.extend_from_slice(entry);
+        }
+        socket.write_all(&encode_message(state.network, "notfound", &notfound)).await?;
+    }
+    Ok(())
+}
+
+// Send a block message, returning false if we do not have the block.
+//
+// The checksum in the message header covers the whole block, so the block is read twice, once
+// to find the checksum and then to send it, rather than being held in memory.
+async fn send_block<A: BlockArchive>(state: &ServerState<A>, socket: &mut TcpStream, block_hash: &BlockHash) -> Result<bool> {
+    let size = match state.archive.block_size(block_hash).await {
+        Ok(size) => size as u64,
+        Err(Error::BlockNotFound) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let length = match u32::try_from(size) {
+        Ok(length) => length,
+        Err(_) => {
+            warn!(%block_hash, size, "block is too large for a block message");
+            return Ok(false);
+        }
+    };
+    let mut context = Context::new(&SHA256);
+    let mut reader = state.archive.get_block(block_hash).await?;
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buf[..n]);
+    }
+    let hash = digest(&SHA256, context.finish().as_ref());
+    let checksum = hash.as_ref()[..4].try_into().unwrap();
+    socket.write_all(&message_header(state.network, "block", length, checksum)).await?;
+    let reader = state.archive.get_block(block_hash).await?;
+    let sent = tokio::io::copy(&mut reader.take(size), socket).await?;
+    if sent != size {
+        // the peer can not make sense of the connection after a short block
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    debug!(%block_hash, size, "sent block");
+    Ok(true)
+}
+
+// Send the headers of the best chain that follow the locator.
+async fn get_headers<A>(state: &ServerState<A>, socket: &mut TcpStream, payload: &[u8]) -> Result<()> {
+    let (locator, stop) = decode_getheaders(payload).ok_or_else(|| Error::PeerError("invalid getheaders message".to_string()))?;
+    let headers = find_headers(&state.chain, &locator, &stop);
+    let mut message = Vec::with_capacity(3 + headers.len() * (HEADER_SIZE + 1));
+    write_varint(&mut message, headers.len() as u64);
+    for header in headers.iter() {
+        message.extend_from_slice(&encode_header(header));
+        // the transaction count, which is always zero
+        message.push(0);
+    }
+    socket.write_all(&encode_message(state.network, "headers", &message)).await?;
+    Ok(())
+}
+
+// Find the headers of the best chain after the first block in the locator that is in the best
+// chain, or after the genesis block if none of them are, up to and including the stop block.
+//
+// With an empty locator only the header of the stop block is returned, as a node does.
+fn find_headers<'a>(chain: &'a ChainIndex, locator: &[BlockHash], stop: &BlockHash) -> Vec<&'a BlockHeader> {
+    if locator.is_empty() {
+        return chain.get(stop).map(|e| &e.header).into_iter().collect();
+    }
+    let start = locator.iter()
+        .find(|h| chain.is_in_best_chain(h))
+        .and_then(|h| chain.height_of(h))
+        .map_or(1, |h| h + 1);
+    let mut headers = Vec::new();
+    let mut height = start;
+    while headers.len() < MAX_HEADERS {
+        let block_hash = match chain.block_by_height(height) {
+            Some(h) => h,
+            None => break,
+        };
+        headers.push(&chain.get(&block_hash).unwrap().header);
+        if block_hash == *stop {
+            break;
+        }
+        height += 1;
+    }
+    headers
+}
+
+// Read a message, returning the command and the payload, or None if the peer has disconnected.
+async fn read_message(reader: &mut BufReader<TcpStream>, network: Network) -> Result<Option<(String, Vec<u8>)>> {
+    let mut header = [0u8; MESSAGE_HEADER_SIZE];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let (command, length) = decode_header(network, &header)?;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(Error::PeerError(format!("message too large: {} bytes", length)));
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload).await?;
+    if checksum(&payload) != header[20..] {
+        return Err(Error::PeerError(format!("bad checksum on {} message", command)));
+    }
+    Ok(Some((command, payload)))
+}
+
+// Decode the entries of an inventory message, each is the type followed by the hash.
+fn decode_inv(payload: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut cursor = payload;
+    let count = take_varint(&mut cursor)?;
+    if count > MAX_INV_SIZE {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        entries.push(take(&mut cursor, INV_ENTRY_SIZE as u64)?);
+    }
+    Some(entries)
+}
+
+// Decode a getheaders message, returning the locator and the stop hash.
+fn decode_getheaders(payload: &[u8]) -> Option<(Vec<BlockHash>, BlockHash)> {
+    let mut cursor = payload;
+    // the protocol version of the peer
+    take(&mut cursor, 4)?;
+    let count = take_varint(&mut cursor)?;
+    if count > MAX_HEADERS as u64 {
+        return None;
+    }
+    let mut locator = Vec::new();
+    for _ in 0..count {
+        locator.push(BlockHash { hash: take(&mut cursor, 32)?.try_into().ok()? });
+    }
+    let stop = BlockHash { hash: take(&mut cursor, 32)?.try_into().ok()? };
+    Some((locator, stop))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use mktemp::Temp;
+    use crate::fetch::P2PFetcher;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Fetch blocks and headers from the test archive with the fetcher.
+    #[tokio::test]
+    async fn test_serve() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(archive, listener, Network::Mainnet));
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = Network::Mainnet.genesis_hash();
+        let block1 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        let root = Temp::new_dir().unwrap();
+        let dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let mut fetcher = P2PFetcher::connect(addr, Network::Mainnet).await.unwrap();
+        assert!(fetcher.fetch_block(&block1, &dst).await.unwrap());
+        let mut block = Vec::new();
+        src.get_block(&block1).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let mut stored = Vec::new();
+        dst.get_block(&block1).await.unwrap().read_to_end(&mut stored).await.unwrap();
+        assert_eq!(stored, block);
+        let unknown = BlockHash::from_hex("0000000000000000000000000000000000000000000000000000000000000001").unwrap();
+        assert!(!fetcher.fetch_block(&unknown, &dst).await.unwrap());
+        // block 1 follows the genesis block, and is also sent when the locator is not known
+        assert_eq!(fetcher.get_headers(&[genesis]).await.unwrap(), vec![<[u8; HEADER_SIZE]>::try_from(&block[..HEADER_SIZE]).unwrap()]);
+        assert_eq!(fetcher.get_headers(&[unknown]).await.unwrap().len(), 1);
+        assert!(fetcher.get_headers(&[block1]).await.unwrap().is_empty());
+        server.abort();
+    }
+
+    // A getheaders message is decoded, and the payload of a short one is rejected.
+    #[test]
+    fn test_decode_getheaders() {
+        let mut payload = 70015i32.to_le_bytes().to_vec();
+        payload.push(1);
+        payload.extend_from_slice(&[1u8; 32]);
+        payload.extend_from_slice(&[0u8; 32]);
+        let (locator, stop) = decode_getheaders(&payload).unwrap();
+        assert_eq!(locator, vec![BlockHash { hash: [1u8; 32] }]);
+        assert_eq!(stop, BlockHash { hash: [0u8; 32] });
+        assert!(decode_getheaders(&payload[..60]).is_none());
+        assert!(decode_inv(&[2u8, 0, 0]).is_none());
+    }
+}
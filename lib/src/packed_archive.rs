@@ -0,0 +1,364 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use hex::{FromHex, ToHex};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::sync::Mutex;
+use crate::block_archive::{check_block_checksum, checked_block, decode_block_attrs, encode_block_attrs, validate_block_attr, BlockHashListStream, BlockHashListStreamFromChannel, ChecksumReader};
+use crate::{BlockArchive, BlockAttrs, Error, Result, WritePolicy};
+
+// the directory, relative to the root, of the index database
+const INDEX_DIR: &str = "index";
+
+// the name of the tree which holds the attributes of the blocks
+const ATTRS_TREE: &str = "attrs";
+
+// the prefix and extension of pack file names
+const PACK_PREFIX: &str = "pack-";
+const PACK_EXTENSION: &str = "dat";
+
+// the default size at which a new pack file is started
+const DEFAULT_MAX_PACK_SIZE: u64 = 1 << 30;
+
+// the length of an encoded index entry: pack number, offset, length, and SHA-256 checksum
+const ENTRY_LEN: usize = 4 + 8 + 8 + 32;
+
+// the size of the channel used to send block hashes
+const LIST_BUFFER: usize = 1000;
+
+/// A block archive that appends blocks to large pack files, for archives with many small blocks
+/// such as the early chain or testnet.
+///
+/// The blocks are stored one after another in files named "pack-00000000.dat" in the root
+/// directory. A new pack file is started once the current one reaches the maximum pack size. An
+/// index in a sled database in the "index" directory maps each block hash to its pack, offset,
+/// length, and checksum, and also holds the block attributes.
+///
+/// Removing a block only removes it from the index, the space it used in the pack file is not
+/// reclaimed. Copy the archive into a new packed archive to reclaim the space.
+///
+/// Example code:
+///     let archive = PackedBlockArchive::new(PathBuf::from("/mnt/blockstore/testnet")).await?;
+pub struct PackedBlockArchive {
+    root_path: PathBuf,
+    // maps the hex block hash to the encoded PackEntry
+    db: sled::Db,
+    // maps the hex block hash to the encoded attributes
+    attrs: sled::Tree,
+    max_pack_size: u64,
+    // the pack file that blocks are appended to, and its size
+    writer: Mutex<(u32, u64)>,
+    // the checks made on blocks before they are stored
+    write_policy: WritePolicy,
+    // whether store_block() replaces an existing block
+    overwrite: bool,
+}
+
+// The location of a block in the pack files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PackEntry {
+    pack: u32,
+    offset: u64,
+    length: u64,
+    checksum: [u8; 32],
+}
+
+impl PackedBlockArchive {
+    /// Open the packed archive in the given directory, creating it if necessary.
+    pub async fn new(root_path: PathBuf) -> Result<PackedBlockArchive> {
+        tokio::fs::create_dir_all(&root_path).await?;
+        let db = sled::open(root_path.join(INDEX_DIR)).map_err(pack_error)?;
+        let attrs = db.open_tree(ATTRS_TREE).map_err(pack_error)?;
+        // continue appending to the last pack file
+        let mut pack = 0;
+        let mut entries = tokio::fs::read_dir(&root_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(n) = entry.file_name().to_str().and_then(pack_number) {
+                pack = pack.max(n);
+            }
+        }
+        let size = match tokio::fs::metadata(pack_path(&root_path, pack)).await {
+            Ok(m) => m.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(PackedBlockArchive {
+            root_path,
+            db,
+            attrs,
+            max_pack_size: DEFAULT_MAX_PACK_SIZE,
+            writer: Mutex::new((pack, size)),
+            write_policy: WritePolicy::default(),
+            overwrite: false,
+        })
+    }
+
+    /// Set the size at which a new pack file is started, the default is 1 GiB.
+    ///
+    /// A block is never split between pack files, so a pack file may be larger than this.
+    pub fn with_max_pack_size(mut self, max_pack_size: u64) -> PackedBlockArchive {
+        self.max_pack_size = max_pack_size;
+        self
+    }
+
+    /// Set the checks that are made on blocks before they are stored, the default is to make no
+    /// checks. A block that fails is rejected before anything is written to a pack file.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> PackedBlockArchive {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Set whether storing a block that is already in the archive replaces it, instead of
+    /// failing with [Error::BlockExists]. The new block is appended and the space used by the old
+    /// block is not reclaimed, as with [BlockArchive::remove_block].
+    pub fn with_overwrite(mut self, overwrite: bool) -> PackedBlockArchive {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Get the root directory of the archive.
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Get the number of blocks in the archive.
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    /// Check whether the archive is empty.
+    pub fn is_empty(&self) -> bool {
+        self.db.is_empty()
+    }
+
+    // Get the location of a block.
+    fn entry(&self, block_hash: &BlockHash) -> Result<PackEntry> {
+        let key: String = block_hash.encode_hex();
+        match self.db.get(key).map_err(pack_error)? {
+            Some(v) => decode_entry(&v),
+            None => Err(Error::BlockNotFound),
+        }
+    }
+}
+
+#[async_trait]
+impl BlockArchive for PackedBlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let entry = self.entry(block_hash)?;
+        let mut file = File::open(pack_path(&self.root_path, entry.pack)).await?;
+        file.seek(SeekFrom::Start(entry.offset)).await?;
+        Ok(Box::new(BufReader::new(file).take(entry.length)))
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let entry = self.entry(block_hash)?;
+        let offset = offset.min(entry.length);
+        let mut file = File::open(pack_path(&self.root_path, entry.pack)).await?;
+        file.seek(SeekFrom::Start(entry.offset + offset)).await?;
+        Ok(Box::new(BufReader::new(file).take(length.min(entry.length - offset))))
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        let key: String = block_hash.encode_hex();
+        self.db.contains_key(key).map_err(pack_error)
+    }
+
+    /// The block is appended to the current pack file and synced to disk before it is added to
+    /// the index. If the write fails the pack file is truncated back to its previous size.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        // only one block is appended at a time
+        let mut writer = self.writer.lock().await;
+        if !self.overwrite && self.block_exists(block_hash).await? {
+            return Err(Error::BlockExists);
+        }
+        let block = checked_block(self.write_policy, block_hash, block).await?;
+        let (mut pack, mut offset) = *writer;
+        if offset > 0 && offset >= self.max_pack_size {
+            pack += 1;
+            offset = 0;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(pack_path(&self.root_path, pack)).await?;
+        let mut reader = ChecksumReader::new(block);
+        let written = match tokio::io::copy(&mut reader, &mut file).await {
+            Ok(n) => n,
+            Err(e) => {
+                file.set_len(offset).await?;
+                return Err(e.into());
+            }
+        };
+        file.sync_data().await?;
+        let checksum = <[u8; 32]>::from_hex(reader.checksum()).unwrap();
+        let entry = PackEntry { pack, offset, length: written, checksum };
+        let key: String = block_hash.encode_hex();
+        self.db.insert(key, encode_entry(&entry)).map_err(pack_error)?;
+        self.db.flush_async().await.map_err(pack_error)?;
+        *writer = (pack, offset + written);
+        Ok(())
+    }
+
+    /// The block is removed from the index, the pack file is not changed.
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        let key: String = block_hash.encode_hex();
+        self.attrs.remove(&key).map_err(pack_error)?;
+        if self.db.remove(&key).map_err(pack_error)?.is_none() {
+            return Err(Error::BlockNotFound);
+        }
+        self.db.flush_async().await.map_err(pack_error)?;
+        Ok(())
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        Ok(self.entry(block_hash)?.length as usize)
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        let mut reader = self.get_block(block_hash).await?;
+        Ok(BlockHeader::from_binary(&mut reader).await?)
+    }
+
+    /// The blocks are listed in order of their hex encoded hash.
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER);
+        let db = self.db.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            for key in db.iter().keys() {
+                let key = key.map_err(pack_error)?;
+                let hash = BlockHash::from_hex(&key[..]).map_err(|_| Error::InvalidPack("invalid block hash in index".to_string()))?;
+                if tx.blocking_send(hash).is_err() {
+                    return Ok(());      // this is not an error, the receiver has merely dropped
+                }
+            }
+            Ok(())
+        });
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        validate_block_attr(key, value)?;
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        attrs.insert(key.to_string(), value.to_string());
+        let hash: String = block_hash.encode_hex();
+        self.attrs.insert(hash, encode_block_attrs(&attrs).as_bytes()).map_err(pack_error)?;
+        Ok(())
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        if attrs.remove(key).is_some() {
+            let hash: String = block_hash.encode_hex();
+            self.attrs.insert(hash, encode_block_attrs(&attrs).as_bytes()).map_err(pack_error)?;
+        }
+        Ok(())
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        if !self.block_exists(block_hash).await? {
+            return Err(Error::BlockNotFound);
+        }
+        let hash: String = block_hash.encode_hex();
+        match self.attrs.get(hash).map_err(pack_error)? {
+            Some(v) => decode_block_attrs(&String::from_utf8_lossy(&v)),
+            None => Ok(BlockAttrs::new()),
+        }
+    }
+
+    /// The checksum of every block is recorded in the index, so this is never false.
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        let entry = self.entry(block_hash)?;
+        check_block_checksum(self, block_hash, &hex::encode(entry.checksum)).await?;
+        Ok(true)
+    }
+}
+
+// Get the path of a pack file.
+fn pack_path(root_path: &Path, pack: u32) -> PathBuf {
+    root_path.join(format!("{}{:08}.{}", PACK_PREFIX, pack, PACK_EXTENSION))
+}
+
+// Get the number of a pack file from its name, or None if it is not a pack file.
+fn pack_number(name: &str) -> Option<u32> {
+    name.strip_prefix(PACK_PREFIX)?.strip_suffix(PACK_EXTENSION)?.strip_suffix('.')?.parse().ok()
+}
+
+fn encode_entry(entry: &PackEntry) -> Vec<u8> {
+    let mut v = Vec::with_capacity(ENTRY_LEN);
+    v.extend_from_slice(&entry.pack.to_le_bytes());
+    v.extend_from_slice(&entry.offset.to_le_bytes());
+    v.extend_from_slice(&entry.length.to_le_bytes());
+    v.extend_from_slice(&entry.checksum);
+    v
+}
+
+fn decode_entry(v: &[u8]) -> Result<PackEntry> {
+    if v.len() != ENTRY_LEN {
+        return Err(Error::InvalidPack("invalid index entry".to_string()));
+    }
+    Ok(PackEntry {
+        pack: u32::from_le_bytes(v[0..4].try_into().unwrap()),
+        offset: u64::from_le_bytes(v[4..12].try_into().unwrap()),
+        length: u64::from_le_bytes(v[12..20].try_into().unwrap()),
+        checksum: v[20..52].try_into().unwrap(),
+    })
+}
+
+fn pack_error(e: sled::Error) -> Error {
+    Error::InvalidPack(e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use mktemp::Temp;
+    use tokio_stream::StreamExt;
+    use crate::sync::sync_archives;
+    use crate::{Manifest, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    #[test]
+    fn test_encode_entry() {
+        let entry = PackEntry { pack: 3, offset: 1234, length: 285, checksum: [7u8; 32] };
+        assert_eq!(decode_entry(&encode_entry(&entry)).unwrap(), entry);
+        assert!(decode_entry(&[0u8; 10]).is_err());
+        assert_eq!(pack_number("pack-00000012.dat"), Some(12));
+        assert_eq!(pack_number("pack-00000012.tmp"), None);
+        assert_eq!(pack_number("index"), None);
+    }
+
+    // Repack the test archive with a tiny pack size, so each block is in its own pack, and then
+    // reopen it.
+    #[tokio::test]
+    async fn test_packed_archive() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let mut archive = PackedBlockArchive::new(root.to_path_buf()).await.unwrap().with_max_pack_size(1);
+        assert!(archive.is_empty());
+        let summary = sync_archives(&mut src, &archive, |_, _| {}).await.unwrap();
+        assert_eq!(archive.len(), summary.copied);
+        let manifest = Manifest::generate(&mut src).await.unwrap();
+        assert!(manifest.verify(&archive).await.unwrap().is_ok());
+        let hashes: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        assert_eq!(hashes.len(), summary.copied);
+        assert!(pack_path(&root.to_path_buf(), summary.copied as u32 - 1).exists());
+        let h = hashes[0];
+        assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+        assert!(archive.verify_checksum(&h).await.unwrap());
+        assert!(matches!(archive.store_block(&h, &mut &[0u8; 80][..]).await, Err(Error::BlockExists)));
+        archive.set_block_attr(&h, "source", "test").await.unwrap();
+        archive.remove_block(&h).await.unwrap();
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert!(matches!(archive.get_block_attrs(&h).await, Err(Error::BlockNotFound)));
+        drop(archive);
+
+        // new blocks are appended to the last pack
+        let archive = PackedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert_eq!(archive.len(), summary.copied - 1);
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        assert_eq!(archive.entry(&h).unwrap().pack, summary.copied as u32 - 1);
+        assert!(manifest.verify(&archive).await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,345 @@
+//! Export block and transaction data to Parquet files, for analytics tools such as Spark and
+//! DuckDB.
+//!
+//! Two files are written to the output directory. "blocks.parquet" has a row for each block with
+//! the columns hash, height, time, size, tx_count, and fees. "transactions.parquet" has a row for
+//! each transaction with the columns txid, block_hash, size, input_count, and output_count, and is
+//! only written if [ParquetOptions::transactions] is set. Hashes are hex strings in the usual byte
+//! order, times are seconds since the epoch, and the height is null for a block that is not linked
+//! to the genesis block.
+//!
+//! The fees of a block are the reward claimed by its coinbase transaction less the subsidy at its
+//! height, see [Coinbase::fees](crate::coinbase::Coinbase::fees). They are null when the height is
+//! not known.
+//!
+//! Each block is read once, as a stream, and the rows are written in row groups so that the
+//! memory used does not depend on the number of blocks exported.
+//!
+//! Example code:
+//!     let blocks = vec![(block_hash, Some(height))];
+//!     let summary = export_parquet(&archive, blocks, &out_dir, &ParquetOptions::default()).await?;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use tokio::io::AsyncRead;
+use crate::coinbase::parse_coinbase;
+use crate::headers::HEADER_SIZE;
+use crate::txindex::{read_bytes, read_tx, read_varint, take, take_varint};
+use crate::{BlockArchive, Error, Network, Result};
+
+/// The name of the file of blocks.
+pub const BLOCKS_FILE: &str = "blocks.parquet";
+/// The name of the file of transactions.
+pub const TRANSACTIONS_FILE: &str = "transactions.parquet";
+
+// the number of rows buffered before a row group is written
+const BLOCK_ROW_GROUP_SIZE: usize = 10_000;
+const TX_ROW_GROUP_SIZE: usize = 1_000_000;
+
+const BLOCK_SCHEMA: &str = "
+    message block {
+        REQUIRED BYTE_ARRAY hash (UTF8);
+        OPTIONAL INT64 height;
+        REQUIRED INT64 time;
+        REQUIRED INT64 size;
+        REQUIRED INT64 tx_count;
+        OPTIONAL INT64 fees;
+    }";
+
+const TX_SCHEMA: &str = "
+    message transaction {
+        REQUIRED BYTE_ARRAY txid (UTF8);
+        REQUIRED BYTE_ARRAY block_hash (UTF8);
+        REQUIRED INT64 size;
+        REQUIRED INT64 input_count;
+        REQUIRED INT64 output_count;
+    }";
+
+/// The options of [export_parquet].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParquetOptions {
+    /// The network of the blocks, which gives the subsidy used to work out the fees.
+    pub network: Network,
+    /// Also write the transactions file. This is the default.
+    pub transactions: bool,
+}
+
+impl Default for ParquetOptions {
+    fn default() -> ParquetOptions {
+        ParquetOptions { network: Network::Mainnet, transactions: true }
+    }
+}
+
+/// The number of rows written by [export_parquet].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParquetSummary {
+    /// The number of blocks exported.
+    pub blocks: u64,
+    /// The number of transactions in the blocks exported, whether or not the transactions file
+    /// was written.
+    pub transactions: u64,
+}
+
+/// Export the blocks, given with their heights, to Parquet files in a directory, which is
+/// created if necessary.
+///
+/// Fails with [Error::CorruptBlock] if a block cannot be parsed. The files are only complete once
+/// the function returns successfully.
+pub async fn export_parquet<A, I>(archive: &A, blocks: I, dir: &Path, options: &ParquetOptions) -> Result<ParquetSummary>
+    where A: BlockArchive + ?Sized, I: IntoIterator<Item=(BlockHash, Option<u64>)>
+{
+    tokio::fs::create_dir_all(dir).await?;
+    let mut blocks_file = create_writer(&dir.join(BLOCKS_FILE), BLOCK_SCHEMA)?;
+    let mut txs_file = if options.transactions {
+        Some(create_writer(&dir.join(TRANSACTIONS_FILE), TX_SCHEMA)?)
+    } else {
+        None
+    };
+    let mut block_rows = BlockRows::default();
+    let mut tx_rows = TxRows::default();
+    let mut summary = ParquetSummary::default();
+    for (block_hash, height) in blocks {
+        let mut reader = archive.get_block(&block_hash).await?;
+        let subsidy = height.map(|h| options.network.block_subsidy(h));
+        let block_tx_rows = txs_file.as_ref().map(|_| &mut tx_rows);
+        match read_block(&mut reader, &block_hash, height, subsidy, &mut block_rows, block_tx_rows).await {
+            Ok(num_tx) => summary.transactions += num_tx,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(Error::CorruptBlock(block_hash)),
+            Err(e) => return Err(e),
+        }
+        summary.blocks += 1;
+        if block_rows.hash.len() >= BLOCK_ROW_GROUP_SIZE {
+            block_rows.write(&mut blocks_file)?;
+        }
+        if let Some(txs_file) = txs_file.as_mut() {
+            if tx_rows.txid.len() >= TX_ROW_GROUP_SIZE {
+                tx_rows.write(txs_file)?;
+            }
+        }
+    }
+    block_rows.write(&mut blocks_file)?;
+    blocks_file.close().map_err(parquet_error)?;
+    if let Some(mut txs_file) = txs_file {
+        tx_rows.write(&mut txs_file)?;
+        txs_file.close().map_err(parquet_error)?;
+    }
+    Ok(summary)
+}
+
+// Read a block, adding a row for it and, if there are transaction rows, a row for each of its
+// transactions. The fees are only worked out if the subsidy is known. Returns the number of
+// transactions.
+async fn read_block<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, block_hash: &BlockHash, height: Option<u64>, subsidy: Option<u64>,
+                                                  block_rows: &mut BlockRows, mut tx_rows: Option<&mut TxRows>) -> Result<u64> {
+    let mut buf = Vec::new();
+    read_bytes(reader, &mut buf, HEADER_SIZE as u64).await?;
+    let header = BlockHeader::from_binary(&mut &buf[..]).await?;
+    let num_tx = read_varint(reader, &mut buf).await?;
+    let mut size = buf.len() as u64;
+    let block_hash_value = ByteArray::from(block_hash.to_string().into_bytes());
+    let mut fees = None;
+    for i in 0..num_tx {
+        buf.clear();
+        read_tx(reader, &mut buf).await?;
+        size += buf.len() as u64;
+        if let Some(subsidy) = subsidy.filter(|_| i == 0) {
+            let (_, reward) = parse_coinbase(&buf).ok_or(Error::CorruptBlock(*block_hash))?;
+            fees = Some(reward.saturating_sub(subsidy));
+        }
+        if let Some(tx_rows) = tx_rows.as_deref_mut() {
+            let (inputs, outputs) = tx_counts(&buf).ok_or(Error::CorruptBlock(*block_hash))?;
+            tx_rows.txid.push(ByteArray::from(BlockHash::sha256d(&buf).to_string().into_bytes()));
+            tx_rows.block_hash.push(block_hash_value.clone());
+            tx_rows.size.push(buf.len() as i64);
+            tx_rows.input_count.push(inputs as i64);
+            tx_rows.output_count.push(outputs as i64);
+        }
+    }
+    block_rows.hash.push(block_hash_value);
+    match height {
+        Some(h) => {
+            block_rows.height.push(h as i64);
+            block_rows.height_levels.push(1);
+        }
+        None => block_rows.height_levels.push(0),
+    }
+    block_rows.time.push(header.timestamp as i64);
+    block_rows.size.push(size as i64);
+    block_rows.tx_count.push(num_tx as i64);
+    match fees {
+        Some(fees) => {
+            block_rows.fees.push(fees as i64);
+            block_rows.fees_levels.push(1);
+        }
+        None => block_rows.fees_levels.push(0),
+    }
+    Ok(num_tx)
+}
+
+// Get the number of inputs and outputs of an encoded transaction.
+fn tx_counts(tx: &[u8]) -> Option<(u64, u64)> {
+    let mut cursor = tx.get(4..)?;
+    let inputs = take_varint(&mut cursor)?;
+    for _ in 0..inputs {
+        take(&mut cursor, 36)?;
+        let script_len = take_varint(&mut cursor)?;
+        take(&mut cursor, script_len.checked_add(4)?)?;
+    }
+    Some((inputs, take_varint(&mut cursor)?))
+}
+
+// The buffered rows of the blocks file.
+#[derive(Default)]
+struct BlockRows {
+    hash: Vec<ByteArray>,
+    // the heights that are known, with a definition level of 1 for each row that has a height
+    height: Vec<i64>,
+    height_levels: Vec<i16>,
+    time: Vec<i64>,
+    size: Vec<i64>,
+    tx_count: Vec<i64>,
+    // the fees that are known, with their definition levels as for the heights
+    fees: Vec<i64>,
+    fees_levels: Vec<i16>,
+}
+
+impl BlockRows {
+    fn write(&mut self, writer: &mut SerializedFileWriter<File>) -> Result<()> {
+        let columns = [
+            Column::Bytes(&self.hash),
+            Column::Int(&self.height, Some(&self.height_levels[..])),
+            Column::Int(&self.time, None),
+            Column::Int(&self.size, None),
+            Column::Int(&self.tx_count, None),
+            Column::Int(&self.fees, Some(&self.fees_levels[..])),
+        ];
+        if !self.hash.is_empty() {
+            write_row_group(writer, &columns)?;
+        }
+        *self = BlockRows::default();
+        Ok(())
+    }
+}
+
+// The buffered rows of the transactions file.
+#[derive(Default)]
+struct TxRows {
+    txid: Vec<ByteArray>,
+    block_hash: Vec<ByteArray>,
+    size: Vec<i64>,
+    input_count: Vec<i64>,
+    output_count: Vec<i64>,
+}
+
+impl TxRows {
+    fn write(&mut self, writer: &mut SerializedFileWriter<File>) -> Result<()> {
+        let columns = [
+            Column::Bytes(&self.txid),
+            Column::Bytes(&self.block_hash),
+            Column::Int(&self.size, None),
+            Column::Int(&self.input_count, None),
+            Column::Int(&self.output_count, None),
+        ];
+        if !self.txid.is_empty() {
+            write_row_group(writer, &columns)?;
+        }
+        *self = TxRows::default();
+        Ok(())
+    }
+}
+
+// The values of a column in a row group, with the definition levels of an optional column.
+enum Column<'a> {
+    Bytes(&'a [ByteArray]),
+    Int(&'a [i64], Option<&'a [i16]>),
+}
+
+fn create_writer(path: &Path, schema: &str) -> Result<SerializedFileWriter<File>> {
+    let schema = Arc::new(parse_message_type(schema).map_err(parquet_error)?);
+    let properties = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+    SerializedFileWriter::new(File::create(path)?, schema, properties).map_err(parquet_error)
+}
+
+// Write the columns as a row group, in the order of the schema.
+fn write_row_group(writer: &mut SerializedFileWriter<File>, columns: &[Column]) -> Result<()> {
+    let mut row_group = writer.next_row_group().map_err(parquet_error)?;
+    for column in columns {
+        let mut column_writer = row_group.next_column().map_err(parquet_error)?
+            .ok_or_else(|| Error::ExportError("more columns than in the schema".to_string()))?;
+        let written = match column {
+            Column::Bytes(values) => column_writer.typed::<ByteArrayType>().write_batch(values, None, None),
+            Column::Int(values, levels) => column_writer.typed::<Int64Type>().write_batch(values, *levels, None),
+        };
+        written.map_err(parquet_error)?;
+        column_writer.close().map_err(parquet_error)?;
+    }
+    row_group.close().map_err(parquet_error)?;
+    Ok(())
+}
+
+fn parquet_error(e: parquet::errors::ParquetError) -> Error {
+    Error::ExportError(e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use mktemp::Temp;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Export two blocks, one without a height, and read the files back.
+    #[tokio::test]
+    async fn test_export_parquet() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h1 = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let h2 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let summary = export_parquet(&archive, vec![(h1, Some(227495)), (h2, None)], &dir.to_path_buf(), &ParquetOptions::default()).await.unwrap();
+        assert_eq!(summary.blocks, 2);
+        assert_eq!(summary.transactions, 2);
+
+        let reader = SerializedFileReader::new(File::open(dir.to_path_buf().join(BLOCKS_FILE)).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(|r| r.unwrap().to_string()).collect();
+        assert!(rows[0].contains(&h1.to_string()));
+        assert!(rows[0].contains("height: 227495"));
+        // the coinbase of the block only claims the subsidy
+        assert!(rows[0].contains("fees: 0"));
+        assert!(rows[1].contains("height: null"));
+        assert!(rows[1].contains("fees: null"));
+        let size = archive.block_size(&h1).await.unwrap();
+        assert!(rows[0].contains(&format!("size: {}", size)));
+
+        let reader = SerializedFileReader::new(File::open(dir.to_path_buf().join(TRANSACTIONS_FILE)).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows() as u64, summary.transactions);
+    }
+
+    // The fees use the subsidy of the network, and the transactions file is optional.
+    #[tokio::test]
+    async fn test_export_fees_without_transactions() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let dir = Temp::new_dir().unwrap();
+        // on regtest the subsidy has gone by this height, so the whole reward is fees
+        let options = ParquetOptions { network: Network::Regtest, transactions: false };
+        let summary = export_parquet(&archive, vec![(h, Some(227495))], &dir.to_path_buf(), &options).await.unwrap();
+        assert_eq!(summary, ParquetSummary { blocks: 1, transactions: 1 });
+        assert!(!dir.to_path_buf().join(TRANSACTIONS_FILE).exists());
+
+        let reader = SerializedFileReader::new(File::open(dir.to_path_buf().join(BLOCKS_FILE)).unwrap()).unwrap();
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(|r| r.unwrap().to_string()).collect();
+        assert!(rows[0].contains("fees: 2500000000"));
+    }
+}
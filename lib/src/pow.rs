@@ -0,0 +1,124 @@
+//! Proof-of-work checks of the blocks in an archive.
+//!
+//! These checks only need the block header, so they are much faster than the merkle root check.
+use bitcoinsv::bitcoin::BlockHash;
+use crate::{BlockArchive, Result};
+
+/// The result of checking the header of a stored block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderCheck {
+    /// The header matches the hash and meets its target.
+    Ok,
+    /// The stored header has a different hash, the block is misfiled. Contains the hash of the
+    /// stored header.
+    HashMismatch(BlockHash),
+    /// The hash of the header does not meet the target encoded in bits, or bits is not valid.
+    InsufficientWork,
+}
+
+/// Get the target encoded in the compact bits field of a header, as a big-endian 256 bit number.
+///
+/// Returns None if the encoding is negative or overflows.
+pub fn target_from_bits(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+    // the sign bit
+    if bits & 0x0080_0000 != 0 && mantissa != 0 {
+        return None;
+    }
+    let mut target = [0u8; 32];
+    if exponent <= 3 {
+        let value = mantissa >> (8 * (3 - exponent));
+        target[28..].copy_from_slice(&value.to_be_bytes());
+        return Some(target);
+    }
+    let m = mantissa.to_be_bytes();
+    // the three mantissa bytes start at byte 32 - exponent
+    for (i, b) in m[1..].iter().enumerate() {
+        match (32 + i).checked_sub(exponent) {
+            Some(pos) if pos < 32 => target[pos] = *b,
+            _ if *b == 0 => {}
+            _ => return None,
+        }
+    }
+    Some(target)
+}
+
+/// Check whether a block hash meets the target encoded in bits.
+pub fn check_pow(block_hash: &BlockHash, bits: u32) -> bool {
+    let target = match target_from_bits(bits) {
+        Some(t) => t,
+        None => return false,
+    };
+    // the hash is stored little-endian
+    let mut hash = block_hash.hash;
+    hash.reverse();
+    hash <= target
+}
+
+/// Check that the header stored for a block has the hash of the block and meets its target.
+pub async fn check_header<A: BlockArchive + ?Sized>(archive: &A, block_hash: &BlockHash) -> Result<HeaderCheck> {
+    let header = archive.block_header(block_hash).await?;
+    let hash = header.hash();
+    if hash != *block_hash {
+        return Ok(HeaderCheck::HashMismatch(hash));
+    }
+    if !check_pow(&hash, header.bits) {
+        return Ok(HeaderCheck::InsufficientWork);
+    }
+    Ok(HeaderCheck::Ok)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    #[test]
+    fn test_target_from_bits() {
+        let mut expected = [0u8; 32];
+        expected[4] = 0xff;
+        expected[5] = 0xff;
+        assert_eq!(target_from_bits(0x1d00ffff), Some(expected));
+        let mut expected = [0u8; 32];
+        expected[0] = 0x7f;
+        expected[1] = 0xff;
+        expected[2] = 0xff;
+        assert_eq!(target_from_bits(0x207fffff), Some(expected));
+        let mut expected = [0u8; 32];
+        expected[31] = 0x12;
+        assert_eq!(target_from_bits(0x01123456), Some(expected));
+        // negative
+        assert_eq!(target_from_bits(0x04923456), None);
+        // overflow
+        assert_eq!(target_from_bits(0x23123456), None);
+    }
+
+    #[test]
+    fn test_check_pow() {
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(check_pow(&h, 0x1d00ffff));
+        let h = BlockHash::from_hex("00000001000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(!check_pow(&h, 0x1d00ffff));
+    }
+
+    // The blocks in the test archive pass, a block stored under the wrong hash does not.
+    #[tokio::test]
+    async fn test_check_header() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert_eq!(check_header(&archive, &h).await.unwrap(), HeaderCheck::Ok);
+        let mut block = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let misfiled = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let wrong = BlockHash::from_hex("00000000000000000000000000000000000000000000000000000000000000ff").unwrap();
+        misfiled.store_block(&wrong, &mut &block[..]).await.unwrap();
+        assert_eq!(check_header(&misfiled, &wrong).await.unwrap(), HeaderCheck::HashMismatch(h));
+    }
+}
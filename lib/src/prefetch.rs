@@ -0,0 +1,383 @@
+//! Reading blocks ahead of a consumer that reads them in order.
+//!
+//! Commands that read every block in turn, such as checking every block or exporting them, spend
+//! most of their time waiting for each block on storage with a high latency, such as an object
+//! store. A [PrefetchingBlockArchive] reads the next few blocks in the background while the
+//! current one is processed. The blocks are read ahead in the order they are listed, or in the
+//! order given to [PrefetchingBlockArchive::prefetch].
+//!
+//! Example code:
+//!     let mut archive = PrefetchingBlockArchive::new(archive, 8);
+//!     let mut blocks = archive.block_list().await?;
+//!     while let Some(block_hash) = blocks.next().await {
+//!       let reader = archive.get_block(&block_hash).await?;
+//!     }
+use std::collections::{HashSet, VecDeque};
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+use crate::block_archive::{BlockHashListStream, BlockHashListStreamFromVec, BlockSizeStream};
+use crate::{BlockArchive, BlockAttrs, Error, ListOptions, Result};
+
+/// The most of a block that is read ahead into memory by default, 64 MiB. The rest of a larger
+/// block is read when the block is.
+pub const DEFAULT_MAX_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+type BlockReader = Box<dyn AsyncRead + Unpin + Send>;
+
+// A block that is being read ahead, or has been.
+enum Fetch {
+    Running(JoinHandle<Result<BlockReader>>),
+    Done(Result<BlockReader>),
+}
+
+// The blocks to read ahead and the blocks that are being read ahead.
+#[derive(Default)]
+struct Window {
+    // the blocks that are still to be read ahead, in order, and the set of them
+    upcoming: VecDeque<BlockHash>,
+    queued: HashSet<BlockHash>,
+    // the blocks that are being read ahead or are waiting to be read, in the order they were started
+    fetches: VecDeque<(BlockHash, Fetch)>,
+    // the tasks that have been cancelled, which hold the archive until they have stopped
+    cancelled: Vec<JoinHandle<Result<BlockReader>>>,
+}
+
+impl Window {
+    // Get the next block to read ahead.
+    fn next_to_fetch(&mut self) -> Option<BlockHash> {
+        while let Some(block_hash) = self.upcoming.pop_front() {
+            // a block is only read ahead once
+            if self.queued.remove(&block_hash) {
+                return Some(block_hash);
+            }
+        }
+        None
+    }
+
+    // Skip the blocks before a block that has not been read ahead yet, returning false if the
+    // block is not one to be read ahead.
+    fn skip_to(&mut self, block_hash: &BlockHash) -> bool {
+        if !self.queued.remove(block_hash) {
+            return false;
+        }
+        while let Some(h) = self.upcoming.pop_front() {
+            if h == *block_hash {
+                break;
+            }
+            self.queued.remove(&h);
+        }
+        true
+    }
+
+    // Drop the blocks that were started more than lag blocks before the given position in the
+    // window, the consumer has passed them by.
+    fn evict(&mut self, position: usize, lag: usize) {
+        for _ in 0..position.saturating_sub(lag) {
+            if let Some((_, fetch)) = self.fetches.pop_front() {
+                self.cancel_fetch(fetch);
+            }
+        }
+    }
+
+    // Drop a block from the window.
+    fn forget(&mut self, block_hash: &BlockHash) {
+        if let Some(i) = self.fetches.iter().position(|(h, _)| h == block_hash) {
+            let (_, fetch) = self.fetches.remove(i).unwrap();
+            self.cancel_fetch(fetch);
+        }
+    }
+
+    // Stop reading ahead.
+    fn cancel(&mut self) {
+        while let Some((_, fetch)) = self.fetches.pop_front() {
+            self.cancel_fetch(fetch);
+        }
+        self.upcoming.clear();
+        self.queued.clear();
+    }
+
+    fn cancel_fetch(&mut self, fetch: Fetch) {
+        self.cancelled.retain(|handle| !handle.is_finished());
+        if let Fetch::Running(handle) = fetch {
+            handle.abort();
+            self.cancelled.push(handle);
+        }
+    }
+}
+
+/// A wrapper that reads blocks ahead of the consumer, see [crate::prefetch].
+///
+/// Up to depth blocks are read ahead into memory, in background tasks, and [BlockArchive::get_block]
+/// returns them from memory. Reading a block that is not in the window reads it from the archive
+/// as usual, and a block that is further on moves the window to it. A block that is passed over
+/// by the consumer, such as one that a resumed check skips, is dropped from the window.
+///
+/// Listing the blocks with [BlockArchive::block_list] or [BlockArchive::block_list_opts] lists
+/// them all before the first is returned, so that they can be read ahead in that order.
+pub struct PrefetchingBlockArchive<A> {
+    // shared with the tasks that read ahead
+    archive: Arc<A>,
+    depth: usize,
+    max_block_size: u64,
+    window: Mutex<Window>,
+}
+
+impl<A: BlockArchive + 'static> PrefetchingBlockArchive<A> {
+    /// Wrap an archive, reading up to depth blocks ahead.
+    pub fn new(archive: A, depth: usize) -> PrefetchingBlockArchive<A> {
+        PrefetchingBlockArchive {
+            archive: Arc::new(archive),
+            depth,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            window: Mutex::new(Window::default()),
+        }
+    }
+
+    /// Set the most of each block that is read ahead into memory, the default is
+    /// [DEFAULT_MAX_BLOCK_SIZE].
+    pub fn with_max_block_size(mut self, max_block_size: u64) -> PrefetchingBlockArchive<A> {
+        self.max_block_size = max_block_size;
+        self
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+
+    /// Read ahead the given blocks in order, instead of the blocks that were to be read ahead.
+    pub fn prefetch(&self, block_hashes: Vec<BlockHash>) {
+        let mut window = self.window.lock().unwrap();
+        window.cancel();
+        window.queued = block_hashes.iter().copied().collect();
+        window.upcoming = block_hashes.into();
+        self.fill(&mut window);
+    }
+
+    // Start reading ahead until the window is full.
+    fn fill(&self, window: &mut Window) {
+        while window.fetches.len() < self.depth {
+            let block_hash = match window.next_to_fetch() {
+                Some(h) => h,
+                None => break,
+            };
+            let archive = self.archive.clone();
+            let max_block_size = self.max_block_size;
+            let handle = tokio::spawn(async move { read_ahead(&*archive, &block_hash, max_block_size).await });
+            window.fetches.push_back((block_hash, Fetch::Running(handle)));
+        }
+    }
+
+    // Take a block from the window if it is being read ahead, and move the window along.
+    fn take(&self, block_hash: &BlockHash) -> Option<Fetch> {
+        let mut window = self.window.lock().unwrap();
+        // blocks are read by several consumers at once a little out of order
+        let lag = self.depth / 2;
+        let fetch = match window.fetches.iter().position(|(h, _)| h == block_hash) {
+            Some(i) => {
+                let (_, fetch) = window.fetches.remove(i).unwrap();
+                window.evict(i, lag);
+                Some(fetch)
+            }
+            None => {
+                if window.skip_to(block_hash) {
+                    let end = window.fetches.len();
+                    window.evict(end, lag);
+                }
+                None
+            }
+        };
+        self.fill(&mut window);
+        fetch
+    }
+
+    // Get the wrapped archive for a method that needs it to itself, once the tasks reading
+    // ahead have finished. The blocks that they read are kept. Fails if a task still holds the
+    // archive.
+    async fn archive_mut(&mut self) -> Result<&mut A> {
+        let window = self.window.get_mut().unwrap();
+        for (_, fetch) in window.fetches.iter_mut() {
+            if let Fetch::Running(handle) = fetch {
+                let r = handle.await.unwrap_or_else(|e| Err(Error::TaskFailed(e)));
+                *fetch = Fetch::Done(r);
+            }
+        }
+        for handle in window.cancelled.drain(..) {
+            let _ = handle.await;
+        }
+        Arc::get_mut(&mut self.archive)
+            .ok_or_else(|| Error::StorageUnavailable("the archive is still being read ahead".to_string()))
+    }
+
+    // List the blocks in full and read them ahead in that order.
+    async fn list_and_prefetch(&mut self, options: Option<ListOptions>) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let archive = self.archive_mut().await?;
+        let mut results = match options {
+            Some(options) => archive.block_list_opts(options).await?,
+            None => archive.block_list().await?,
+        };
+        let mut block_hashes = Vec::new();
+        while let Some(block_hash) = results.next().await {
+            block_hashes.push(block_hash);
+        }
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        self.prefetch(block_hashes.clone());
+        Ok(Box::pin(BlockHashListStreamFromVec { hashes: block_hashes.into_iter() }))
+    }
+}
+
+impl<A> Drop for PrefetchingBlockArchive<A> {
+    fn drop(&mut self) {
+        if let Ok(window) = self.window.get_mut() {
+            window.cancel();
+        }
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchive + 'static> BlockArchive for PrefetchingBlockArchive<A> {
+    /// A block that has been read ahead is returned from memory.
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        match self.take(block_hash) {
+            Some(Fetch::Running(handle)) => handle.await.map_err(Error::TaskFailed)?,
+            Some(Fetch::Done(r)) => r,
+            None => self.archive.get_block(block_hash).await,
+        }
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.archive.get_block_range(block_hash, offset, length).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.block_exists(block_hash).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        self.archive.blocks_exist(block_hashes).await
+    }
+
+    /// A block that is stored again is dropped from the window, so that the old block is not
+    /// returned.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        self.window.lock().unwrap().forget(block_hash);
+        self.archive.store_block(block_hash, block).await
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.window.lock().unwrap().forget(block_hash);
+        self.archive.remove_block(block_hash).await
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.archive.block_size(block_hash).await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.archive.block_header(block_hash).await
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        self.archive.block_headers(block_hashes).await
+    }
+
+    /// The blocks are read ahead in the order they are listed.
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.list_and_prefetch(None).await
+    }
+
+    /// The blocks are read ahead in the order they are listed.
+    async fn block_list_opts(&mut self, options: ListOptions) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.list_and_prefetch(Some(options)).await
+    }
+
+    async fn block_sizes<'a>(&'a mut self) -> Result<BlockSizeStream<'a>> {
+        self.archive_mut().await?.block_sizes().await
+    }
+
+    async fn total_size(&mut self) -> Result<u64> {
+        self.archive_mut().await?.total_size().await
+    }
+
+    async fn blocks_in_time_range(&mut self, start: u64, end: u64, median_time_past: bool) -> Result<Vec<BlockHash>> {
+        self.archive_mut().await?.blocks_in_time_range(start, end, median_time_past).await
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.archive.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.archive.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.archive.get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.verify_checksum(block_hash).await
+    }
+}
+
+// Read a block into memory, up to the maximum size. The rest of a larger block is read from the
+// archive when the block is read.
+async fn read_ahead<A: BlockArchive + ?Sized>(archive: &A, block_hash: &BlockHash, max_block_size: u64) -> Result<BlockReader> {
+    let mut reader = archive.get_block(block_hash).await?;
+    let mut buf = Vec::new();
+    (&mut reader).take(max_block_size).read_to_end(&mut buf).await?;
+    Ok(Box::new(Cursor::new(buf).chain(reader)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Read every block of the test archive through the wrapper, in the listed order and in
+    // another order.
+    #[tokio::test]
+    async fn test_prefetch() {
+        let root = PathBuf::from("../testdata/blockarchive");
+        let mut direct = SimpleFileBasedBlockArchive::new(root.clone()).await.unwrap();
+        let mut archive = PrefetchingBlockArchive::new(SimpleFileBasedBlockArchive::new(root).await.unwrap(), 2)
+            .with_max_block_size(100);
+        let mut results = archive.block_list().await.unwrap();
+        let mut block_hashes = Vec::new();
+        while let Some(block_hash) = results.next().await {
+            let mut block = Vec::new();
+            archive.get_block(&block_hash).await.unwrap().read_to_end(&mut block).await.unwrap();
+            let mut expected = Vec::new();
+            direct.get_block(&block_hash).await.unwrap().read_to_end(&mut expected).await.unwrap();
+            assert_eq!(block, expected);
+            block_hashes.push(block_hash);
+        }
+        let listed: Vec<BlockHash> = direct.block_list().await.unwrap().collect().await;
+        assert_eq!(block_hashes.len(), listed.len());
+        // a block that is skipped can still be read after the blocks that follow it
+        block_hashes.reverse();
+        archive.prefetch(block_hashes.clone());
+        block_hashes.rotate_left(1);
+        for block_hash in block_hashes.iter() {
+            assert!(archive.block_exists(block_hash).await.unwrap());
+            let mut block = Vec::new();
+            archive.get_block(block_hash).await.unwrap().read_to_end(&mut block).await.unwrap();
+            assert_eq!(block.len(), direct.block_size(block_hash).await.unwrap());
+        }
+        assert!(archive.window.lock().unwrap().upcoming.is_empty());
+        assert_eq!(archive.total_size().await.unwrap(), direct.total_size().await.unwrap());
+        let h = BlockHash::from_hex("00000000000000000000000000000000000000000000000000000000000000ff").unwrap();
+        assert!(matches!(archive.get_block(&h).await, Err(Error::BlockNotFound)));
+    }
+}
@@ -0,0 +1,253 @@
+//! Publishing the blocks stored in an archive to a message broker, such as Kafka or NATS.
+//!
+//! A [BlockPublisher] is a listener for a [crate::events::NotifyingBlockArchive] that publishes a
+//! message for each block stored, for streaming pipelines that process new blocks as they arrive.
+//! The messages are published to a topic, in the order the blocks were stored, with the block
+//! hash as the key. The content of the message is given by the [PayloadSchema].
+//!
+//! The brokers are [Publisher]s. [KafkaPublisher] needs the `kafka` feature and [NatsPublisher]
+//! needs the `nats` feature, other brokers can be added by implementing [Publisher].
+//!
+//! Example code:
+//!     let publisher = BlockPublisher::connect("nats://localhost:4222", "blocks", PayloadSchema::Json).await?;
+//!     let archive = NotifyingBlockArchive::new(archive).with_details().with_listener(publisher.listener());
+use std::sync::Arc;
+use async_trait::async_trait;
+use tracing::warn;
+use crate::events::BlockEvent;
+use crate::{Error, Result};
+
+/// The content of the message published for a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadSchema {
+    /// The event as JSON, see [BlockEvent::to_json], which has the height, size and number of
+    /// transactions of the block if the archive reads them.
+    #[default]
+    Json,
+    /// The hex encoded block hash, for consumers that fetch the block themselves.
+    Hash,
+}
+
+impl PayloadSchema {
+    /// Encode the message for an event.
+    pub fn encode(&self, event: &BlockEvent) -> Vec<u8> {
+        match self {
+            PayloadSchema::Json => event.to_json().to_string().into_bytes(),
+            PayloadSchema::Hash => event.block_hash().to_string().into_bytes(),
+        }
+    }
+}
+
+/// A message broker that messages can be published to.
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    /// Publish a message to a topic, with a key that identifies it.
+    async fn publish(&self, topic: &str, key: &str, payload: Vec<u8>) -> Result<()>;
+}
+
+/// Publishes the blocks stored in an archive to a topic, see [crate::publish].
+#[derive(Clone)]
+pub struct BlockPublisher {
+    publisher: Arc<dyn Publisher>,
+    topic: String,
+    schema: PayloadSchema,
+}
+
+impl BlockPublisher {
+    /// Publish to a topic of a broker.
+    pub fn new(publisher: Arc<dyn Publisher>, topic: &str, schema: PayloadSchema) -> BlockPublisher {
+        BlockPublisher { publisher, topic: topic.to_string(), schema }
+    }
+
+    /// Connect to the broker at a URL and publish to a topic. The URL is
+    /// kafka://host:port,host:port with the bootstrap brokers of a Kafka cluster, or
+    /// nats://host:port for a NATS server.
+    ///
+    /// Fails if the broker is not supported by the features of the crate.
+    pub async fn connect(url: &str, topic: &str, schema: PayloadSchema) -> Result<BlockPublisher> {
+        let publisher = connect_publisher(url).await?;
+        Ok(BlockPublisher::new(publisher, topic, schema))
+    }
+
+    /// Get the topic that the blocks are published to.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// A listener that publishes the [BlockEvent::BlockStored] events, the other events are not
+    /// published.
+    ///
+    /// The messages are published in order by a separate task, so that storing a block does not
+    /// wait for the broker. A message that can not be published is logged and dropped. Must be
+    /// called within a tokio runtime.
+    pub fn listener(&self) -> impl Fn(&BlockEvent) + Send + Sync + 'static {
+        let publisher = self.clone();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<BlockEvent>();
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                let key = event.block_hash().to_string();
+                if let Err(e) = publisher.publisher.publish(&publisher.topic, &key, publisher.schema.encode(&event)).await {
+                    warn!(topic = %publisher.topic, block_hash = %key, error = %e, "could not publish block");
+                }
+            }
+        });
+        move |event: &BlockEvent| {
+            if let BlockEvent::BlockStored(..) = event {
+                let _ = tx.send(event.clone());
+            }
+        }
+    }
+}
+
+// Connect to the broker at a URL, see [BlockPublisher::connect]. Without the kafka and nats
+// features every URL fails.
+async fn connect_publisher(url: &str) -> Result<Arc<dyn Publisher>> {
+    match url.split_once("://") {
+        #[cfg(feature = "kafka")]
+        Some(("kafka", brokers)) => Ok(Arc::new(KafkaPublisher::connect(brokers.split(',').map(String::from).collect()).await?)),
+        #[cfg(feature = "nats")]
+        Some(("nats", _)) => Ok(Arc::new(NatsPublisher::connect(url).await?)),
+        // the brokers of the features that are not enabled
+        #[allow(unreachable_patterns)]
+        Some((scheme @ ("kafka" | "nats"), _)) =>
+            Err(Error::PublishError(format!("publishing to {} needs the {} feature", scheme, scheme))),
+        _ => Err(Error::PublishError(format!("unknown broker {}, expected kafka:// or nats://", url))),
+    }
+}
+
+/// Publishes to a Kafka cluster.
+///
+/// The messages are produced to one partition of each topic, partition 0 unless another is
+/// given, which keeps the blocks in the order they were stored.
+#[cfg(feature = "kafka")]
+pub struct KafkaPublisher {
+    client: rskafka::client::Client,
+    partition: i32,
+    // the client of the partition of each topic, made when the topic is first published to
+    partitions: tokio::sync::Mutex<std::collections::HashMap<String, Arc<rskafka::client::partition::PartitionClient>>>,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaPublisher {
+    /// Connect to a Kafka cluster through its bootstrap brokers, given as host:port.
+    pub async fn connect(brokers: Vec<String>) -> Result<KafkaPublisher> {
+        let client = rskafka::client::ClientBuilder::new(brokers).build().await.map_err(kafka_error)?;
+        Ok(KafkaPublisher { client, partition: 0, partitions: tokio::sync::Mutex::new(std::collections::HashMap::new()) })
+    }
+
+    /// Produce the messages to this partition.
+    pub fn with_partition(mut self, partition: i32) -> KafkaPublisher {
+        self.partition = partition;
+        self
+    }
+
+    async fn partition_client(&self, topic: &str) -> Result<Arc<rskafka::client::partition::PartitionClient>> {
+        let mut partitions = self.partitions.lock().await;
+        if let Some(client) = partitions.get(topic) {
+            return Ok(client.clone());
+        }
+        let client = self.client.partition_client(topic, self.partition, rskafka::client::partition::UnknownTopicHandling::Error)
+            .await.map_err(kafka_error)?;
+        let client = Arc::new(client);
+        partitions.insert(topic.to_string(), client.clone());
+        Ok(client)
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl Publisher for KafkaPublisher {
+    async fn publish(&self, topic: &str, key: &str, payload: Vec<u8>) -> Result<()> {
+        let record = rskafka::record::Record {
+            key: Some(key.as_bytes().to_vec()),
+            value: Some(payload),
+            headers: std::collections::BTreeMap::new(),
+            timestamp: chrono::Utc::now(),
+        };
+        self.partition_client(topic).await?
+            .produce(vec![record], rskafka::client::partition::Compression::NoCompression)
+            .await.map_err(kafka_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kafka")]
+fn kafka_error(e: rskafka::client::error::Error) -> Error {
+    Error::PublishError(format!("kafka: {}", e))
+}
+
+/// Publishes to a NATS server, with the topic as the subject.
+///
+/// The key of each message is sent as its Nats-Msg-Id header, so that a JetStream stream drops
+/// the messages of a block that is published again.
+#[cfg(feature = "nats")]
+pub struct NatsPublisher {
+    client: async_nats::Client,
+}
+
+#[cfg(feature = "nats")]
+impl NatsPublisher {
+    /// Connect to a NATS server, given as nats://host:port.
+    pub async fn connect(url: &str) -> Result<NatsPublisher> {
+        let client = async_nats::connect(url).await.map_err(|e| Error::PublishError(format!("nats: {}", e)))?;
+        Ok(NatsPublisher { client })
+    }
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl Publisher for NatsPublisher {
+    async fn publish(&self, topic: &str, key: &str, payload: Vec<u8>) -> Result<()> {
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert("Nats-Msg-Id", key);
+        self.client.publish_with_headers(topic.to_string(), headers, payload.into()).await
+            .map_err(|e| Error::PublishError(format!("nats: {}", e)))?;
+        // the message is buffered until it is flushed, which reports whether it was sent
+        self.client.flush().await.map_err(|e| Error::PublishError(format!("nats: {}", e)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bitcoinsv::bitcoin::BlockHash;
+    use hex::FromHex;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+    use crate::events::BlockDetails;
+    use super::*;
+
+    // A publisher that sends the messages to a channel.
+    struct ChannelPublisher(UnboundedSender<(String, String, Vec<u8>)>);
+
+    #[async_trait]
+    impl Publisher for ChannelPublisher {
+        async fn publish(&self, topic: &str, key: &str, payload: Vec<u8>) -> Result<()> {
+            let _ = self.0.send((topic.to_string(), key.to_string(), payload));
+            Ok(())
+        }
+    }
+
+    // Only the stored blocks are published, keyed by their hash, with the payload of the schema.
+    #[tokio::test]
+    async fn test_publish() {
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let details = BlockDetails { height: Some(300_000), size: 1000, tx_count: 3 };
+        let (tx, mut rx) = unbounded_channel();
+        let publisher = BlockPublisher::new(Arc::new(ChannelPublisher(tx.clone())), "blocks", PayloadSchema::Json);
+        let listener = publisher.listener();
+        listener(&BlockEvent::BlockDeleted(h));
+        listener(&BlockEvent::BlockStored(h, Some(details)));
+        let (topic, key, payload) = rx.recv().await.unwrap();
+        assert_eq!(topic, "blocks");
+        assert_eq!(key, h.to_string());
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json, BlockEvent::BlockStored(h, Some(details)).to_json());
+        assert_eq!(json["height"], 300_000);
+
+        let listener = BlockPublisher::new(Arc::new(ChannelPublisher(tx)), "hashes", PayloadSchema::Hash).listener();
+        listener(&BlockEvent::BlockStored(h, None));
+        assert_eq!(rx.recv().await.unwrap().2, h.to_string().into_bytes());
+
+        assert!(matches!(BlockPublisher::connect("amqp://localhost", "blocks", PayloadSchema::Json).await, Err(Error::PublishError(_))));
+    }
+}
@@ -0,0 +1,208 @@
+//! Python bindings for the read API, built with PyO3.
+//!
+//! This module is only compiled with the `python` feature. The Python package is built with
+//! maturin from the `lib` directory:
+//!
+//! ```text
+//! maturin develop --release
+//! ```
+//!
+//! maturin builds with the `python-extension` feature, which leaves libpython to be linked by the
+//! interpreter that loads the module. The `python` feature alone links libpython, so that the
+//! bindings can be tested with cargo.
+//!
+//! Example Python code:
+//!
+//! ```text
+//! import bsv_blockarchive
+//! archive = bsv_blockarchive.BlockArchive("/mnt/blockstore/mainnet")
+//! header = archive.block_header("00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531")
+//! failures = archive.check_blocks(pow=True, jobs=4)
+//! ```
+//!
+//! The methods are blocking, each archive object owns its own tokio runtime.
+
+// the macros of pyo3 0.20 expand to impls inside functions, which newer compilers lint against
+#![allow(non_local_definitions)]
+use std::path::PathBuf;
+use bitcoinsv::bitcoin::BlockHash;
+use hex::FromHex;
+use pyo3::exceptions::{PyKeyError, PyOSError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+use crate::headers::encode_header;
+use crate::verify::{check_stored_block, CheckReport, Verifier};
+use crate::{BlockArchive, Error, SimpleFileBasedBlockArchive};
+
+impl From<Error> for PyErr {
+    fn from(err: Error) -> PyErr {
+        match err {
+            Error::BlockNotFound => PyKeyError::new_err(err.to_string()),
+            Error::IoError(_) => PyOSError::new_err(err.to_string()),
+            _ => PyRuntimeError::new_err(err.to_string()),
+        }
+    }
+}
+
+// parse a hex encoded block hash
+fn parse_hash(block_hash: &str) -> PyResult<BlockHash> {
+    BlockHash::from_hex(block_hash).map_err(|_| PyValueError::new_err("invalid block hash"))
+}
+
+// The failures of a check as tuples of the block hash, the kind of failure, and a message.
+fn failures(report: CheckReport) -> Vec<(String, String, String)> {
+    report.failures.into_iter().map(|f| (f.block_hash.to_string(), f.kind.name().to_string(), f.message)).collect()
+}
+
+/// A block archive opened from Python.
+#[pyclass(name = "BlockArchive")]
+struct PyBlockArchive {
+    runtime: Runtime,
+    archive: SimpleFileBasedBlockArchive,
+    // the checks of many blocks are made with their own read-only archive opened from here
+    root_path: PathBuf,
+}
+
+impl PyBlockArchive {
+    // Get a verifier of the archive.
+    fn verifier(&self) -> PyResult<Verifier<SimpleFileBasedBlockArchive>> {
+        let archive = self.runtime.block_on(SimpleFileBasedBlockArchive::open_read_only(self.root_path.clone()))?;
+        Ok(Verifier::new(archive))
+    }
+}
+
+#[pymethods]
+impl PyBlockArchive {
+    /// Open the archive at root_path.
+    #[new]
+    fn new(root_path: &str) -> PyResult<Self> {
+        let runtime = Runtime::new()?;
+        let root_path = PathBuf::from(root_path);
+        let archive = runtime.block_on(SimpleFileBasedBlockArchive::new(root_path.clone()))?;
+        Ok(PyBlockArchive { runtime, archive, root_path })
+    }
+
+    /// Check whether a block exists in the archive.
+    fn block_exists(&self, block_hash: &str) -> PyResult<bool> {
+        let hash = parse_hash(block_hash)?;
+        Ok(self.runtime.block_on(self.archive.block_exists(&hash))?)
+    }
+
+    /// Get the size of a block in bytes.
+    fn block_size(&self, block_hash: &str) -> PyResult<usize> {
+        let hash = parse_hash(block_hash)?;
+        Ok(self.runtime.block_on(self.archive.block_size(&hash))?)
+    }
+
+    /// Get the 80 byte encoded header of a block.
+    fn block_header(&self, py: Python, block_hash: &str) -> PyResult<PyObject> {
+        let hash = parse_hash(block_hash)?;
+        let header = self.runtime.block_on(self.archive.block_header(&hash))?;
+        Ok(PyBytes::new(py, &encode_header(&header)).into())
+    }
+
+    /// Get the encoded block.
+    ///
+    /// The whole block is read into memory, this is not suitable for very large blocks.
+    fn get_block(&self, py: Python, block_hash: &str) -> PyResult<PyObject> {
+        let hash = parse_hash(block_hash)?;
+        let buf = self.runtime.block_on(async {
+            let mut reader = self.archive.get_block(&hash).await?;
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await?;
+            Ok::<Vec<u8>, Error>(buf)
+        })?;
+        Ok(PyBytes::new(py, &buf).into())
+    }
+
+    /// Get a list of the hashes of all blocks in the archive.
+    fn block_list(&mut self) -> PyResult<Vec<String>> {
+        let archive = &mut self.archive;
+        let hashes = self.runtime.block_on(async {
+            let mut results = archive.block_list().await?;
+            let mut hashes = Vec::new();
+            while let Some(block_hash) = results.next().await {
+                hashes.push(block_hash.to_string());
+            }
+            if let Some(e) = results.as_mut().take_error() {
+                return Err(e);
+            }
+            Ok::<Vec<String>, Error>(hashes)
+        })?;
+        Ok(hashes)
+    }
+
+    /// Check a block, returning None if it passes, or the kind of failure and a message if it
+    /// fails. If pow is true the proof-of-work of the header is also checked.
+    #[pyo3(signature = (block_hash, pow = false))]
+    fn check_block(&self, block_hash: &str, pow: bool) -> PyResult<Option<(String, String)>> {
+        let hash = parse_hash(block_hash)?;
+        let failure = self.runtime.block_on(check_stored_block(&self.archive, &hash, pow))?;
+        Ok(failure.map(|f| (f.kind.name().to_string(), f.message)))
+    }
+
+    /// Check that the parent of every block is in the archive, returning the failures as tuples
+    /// of the block hash, the kind of failure, and a message.
+    fn check_links(&self) -> PyResult<Vec<(String, String, String)>> {
+        let verifier = self.verifier()?;
+        Ok(failures(self.runtime.block_on(verifier.check_links(|_| {}))?))
+    }
+
+    /// Check every block in the archive, see check_block, with up to jobs blocks checked at the
+    /// same time. If quick is true only the header and transaction count of each block are
+    /// checked. The failures are returned as for check_links.
+    #[pyo3(signature = (pow = false, quick = false, jobs = 1))]
+    fn check_blocks(&self, pow: bool, quick: bool, jobs: usize) -> PyResult<Vec<(String, String, String)>> {
+        let verifier = self.verifier()?.with_pow(pow).with_quick(quick).with_jobs(jobs);
+        Ok(failures(self.runtime.block_on(verifier.check_blocks(|_| {}))?))
+    }
+}
+
+/// The bsv_blockarchive Python module.
+#[pymodule]
+fn bsv_blockarchive(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyBlockArchive>()?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::verify::FailureKind;
+    use super::*;
+
+    const BLOCK: &str = "00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f";
+
+    // Read a block and its header from the test archive, and refuse a hash that is not hex.
+    #[test]
+    fn test_read_block() {
+        pyo3::prepare_freethreaded_python();
+        let mut archive = PyBlockArchive::new("../testdata/blockarchive").unwrap();
+        assert!(archive.block_exists(BLOCK).unwrap());
+        assert_eq!(archive.block_size(BLOCK).unwrap(), 227);
+        assert!(archive.block_list().unwrap().contains(&BLOCK.to_string()));
+        assert!(archive.block_size("not a hash").is_err());
+        Python::with_gil(|py| {
+            let block = archive.get_block(py, BLOCK).unwrap();
+            let block = block.as_ref(py).downcast::<PyBytes>().unwrap().as_bytes();
+            let header = archive.block_header(py, BLOCK).unwrap();
+            assert_eq!(header.as_ref(py).downcast::<PyBytes>().unwrap().as_bytes(), &block[..80]);
+            assert!(archive.get_block(py, &format!("{:064x}", 1)).unwrap_err().is_instance_of::<PyKeyError>(py));
+        });
+    }
+
+    // The blocks of the test archive pass their checks, and the block without its parent is
+    // reported by the check of the links.
+    #[test]
+    fn test_check_blocks() {
+        let archive = PyBlockArchive::new("../testdata/blockarchive").unwrap();
+        assert_eq!(archive.check_block(BLOCK, true).unwrap(), None);
+        assert!(archive.check_blocks(true, false, 2).unwrap().is_empty());
+        assert!(archive.check_blocks(false, true, 1).unwrap().is_empty());
+        let failures = archive.check_links().unwrap();
+        assert!(failures.iter().any(|(h, kind, _)| h == BLOCK && kind == FailureKind::MissingParent.name()));
+    }
+}
@@ -0,0 +1,357 @@
+//! Quarantine of corrupt blocks.
+//!
+//! A block that fails a consistency or checksum check is moved to a quarantine archive, with the
+//! reason and the time recorded in its attributes. The block is no longer in the archive, so it
+//! is not listed or served, but it is kept for inspection. [repair_block] replaces a quarantined
+//! block with a copy from another archive, and [repair_block_from_peer] with a copy from a peer,
+//! checking the copy before it is restored. [repair_archive] checks every block in an archive and
+//! swaps good copies in for the blocks that fail.
+//!
+//! Example code:
+//!     quarantine_block(&archive, &quarantine, &block_hash, "merkle root mismatch").await?;
+//!     for q in quarantined_blocks(&mut quarantine).await? {
+//!         repair_block(&archive, &quarantine, &source, &q.block_hash).await?;
+//!     }
+use std::time::{SystemTime, UNIX_EPOCH};
+use bitcoinsv::bitcoin::BlockHash;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_stream::StreamExt;
+use tracing::warn;
+use crate::block_archive::checked_block;
+use crate::fetch::P2PFetcher;
+use crate::tier::move_block;
+use crate::verify::{check_stored_block, CheckFailure, FailureKind};
+use crate::{BlockArchive, Error, Result, WritePolicy};
+
+/// The attribute of a quarantined block that holds the reason it was quarantined.
+pub const REASON_ATTR: &str = "quarantine.reason";
+
+/// The attribute of a quarantined block that holds the time it was quarantined, in seconds since
+/// the epoch.
+pub const TIME_ATTR: &str = "quarantine.time";
+
+/// A block in a quarantine archive, see [quarantined_blocks].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QuarantinedBlock {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))]
+    pub block_hash: BlockHash,
+    /// The reason the block was quarantined, if it was recorded.
+    pub reason: Option<String>,
+    /// The time the block was quarantined, if it was recorded.
+    pub time: Option<u64>,
+}
+
+/// Where [repair_archive] gets good copies of blocks from.
+pub enum RepairSource<'a> {
+    /// Another archive, for example a replica or a remote archive.
+    Archive(&'a dyn BlockArchive),
+    /// A node.
+    Peer(&'a mut P2PFetcher),
+}
+
+/// The result of [repair_archive].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RepairReport {
+    /// The number of blocks checked.
+    pub checked: u64,
+    /// The blocks that failed the checks, in the order of the block list.
+    pub failures: Vec<CheckFailure>,
+    /// The blocks that were replaced with good copies.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub repaired: Vec<BlockHash>,
+    /// The blocks that the source does not have, which are left as they are.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub unavailable: Vec<BlockHash>,
+    /// The blocks whose copy from the source failed the checks, with the reason. These are also
+    /// left as they are.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash_pairs"))]
+    pub bad_copies: Vec<(BlockHash, String)>,
+}
+
+impl RepairReport {
+    /// Get the report as a JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "checked": self.checked,
+            "errors": self.failures.len(),
+            "repaired": self.repaired.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+            "unavailable": self.unavailable.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+            "bad_copies": self.bad_copies.iter().map(|(h, _)| h.to_string()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Move a block from the archive to the quarantine archive, recording the reason.
+///
+/// The block is copied as it is, the copy is checked against the original before the original
+/// is removed. A block that can not be read can not be quarantined.
+pub async fn quarantine_block<A, Q>(archive: &A, quarantine: &Q, block_hash: &BlockHash, reason: &str) -> Result<()>
+    where A: BlockArchive + ?Sized, Q: BlockArchive + ?Sized
+{
+    move_block(archive, quarantine, block_hash).await?;
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    quarantine.set_block_attr(block_hash, REASON_ATTR, reason).await?;
+    quarantine.set_block_attr(block_hash, TIME_ATTR, &time.to_string()).await
+}
+
+/// Get the blocks in a quarantine archive, with the reason each one was quarantined.
+pub async fn quarantined_blocks<Q>(quarantine: &mut Q) -> Result<Vec<QuarantinedBlock>>
+    where Q: BlockArchive + ?Sized
+{
+    let mut hashes = Vec::new();
+    let mut results = quarantine.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        hashes.push(block_hash);
+    }
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    let mut blocks = Vec::with_capacity(hashes.len());
+    for block_hash in hashes {
+        let attrs = quarantine.get_block_attrs(&block_hash).await?;
+        blocks.push(QuarantinedBlock {
+            block_hash,
+            reason: attrs.get(REASON_ATTR).cloned(),
+            time: attrs.get(TIME_ATTR).and_then(|t| t.parse().ok()),
+        });
+    }
+    Ok(blocks)
+}
+
+/// Restore a quarantined block to the archive with the copy in the source archive, removing it
+/// from the quarantine archive.
+///
+/// The header hash and merkle root of the copy are checked before it is stored, a copy that fails
+/// fails with [Error::InvalidBlock] and the block stays in quarantine. If the archive already has
+/// the block, for example because it was fetched again, it is kept. Returns false if the source
+/// does not have the block.
+pub async fn repair_block<A, Q, S>(archive: &A, quarantine: &Q, source: &S, block_hash: &BlockHash) -> Result<bool>
+    where A: BlockArchive + ?Sized, Q: BlockArchive + ?Sized, S: BlockArchive + ?Sized
+{
+    if !source.block_exists(block_hash).await? {
+        return Ok(false);
+    }
+    let mut reader = source.get_block(block_hash).await?;
+    restore_block(archive, quarantine, block_hash, &mut reader).await?;
+    Ok(true)
+}
+
+/// Restore a quarantined block to the archive with a copy fetched from a peer, see
+/// [repair_block]. Returns false if the peer does not have the block.
+pub async fn repair_block_from_peer<A, Q>(archive: &A, quarantine: &Q, fetcher: &mut P2PFetcher, block_hash: &BlockHash) -> Result<bool>
+    where A: BlockArchive + ?Sized, Q: BlockArchive + ?Sized
+{
+    // the block is held until it has been checked
+    let data = match fetcher.fetch_block_data(block_hash).await? {
+        Some(d) => d,
+        None => return Ok(false),
+    };
+    restore_block(archive, quarantine, block_hash, &mut &data[..]).await?;
+    Ok(true)
+}
+
+// Check a copy of a quarantined block, store it in the archive and remove the block from the
+// quarantine archive.
+async fn restore_block<A, Q>(archive: &A, quarantine: &Q, block_hash: &BlockHash, copy: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()>
+    where A: BlockArchive + ?Sized, Q: BlockArchive + ?Sized
+{
+    let mut block = checked_block(WritePolicy::MerkleRoot, block_hash, copy).await?;
+    match archive.store_block(block_hash, &mut block).await {
+        Ok(_) | Err(Error::BlockExists) => {}
+        Err(e) => return Err(e),
+    }
+    quarantine.remove_block(block_hash).await
+}
+
+/// Check every block in the archive against its checksum and its merkle root, and replace the
+/// blocks that fail, or can not be read in full, with good copies from the source.
+///
+/// Each copy is checked before it is used. A copy of the damaged block is kept in the quarantine
+/// archive, with the failure as the reason, if it can be read. If the archive has been set to
+/// overwrite blocks the good copy is swapped in with a single store, which for a simple archive
+/// replaces the file atomically. Otherwise the damaged block is removed before the good copy is
+/// stored. The progress function is called for each block when it has been checked.
+pub async fn repair_archive<A, Q, F>(archive: &mut A, quarantine: &Q, source: &mut RepairSource<'_>, mut progress: F) -> Result<RepairReport>
+    where A: BlockArchive + ?Sized, Q: BlockArchive + ?Sized, F: FnMut(&BlockHash, Option<&CheckFailure>)
+{
+    let mut hashes = Vec::new();
+    let mut results = archive.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        hashes.push(block_hash);
+    }
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    let mut report = RepairReport::default();
+    for block_hash in hashes {
+        report.checked += 1;
+        let failure = find_damage(&*archive, &block_hash).await?;
+        progress(&block_hash, failure.as_ref());
+        let failure = match failure {
+            Some(f) => f,
+            None => continue,
+        };
+        match fetch_copy(source, &block_hash).await {
+            Ok(Some(copy)) => {
+                keep_damaged_copy(&*archive, quarantine, &block_hash, &failure.message).await;
+                swap_block(&*archive, &copy, &block_hash).await?;
+                report.repaired.push(block_hash);
+            }
+            Ok(None) => report.unavailable.push(block_hash),
+            Err(Error::InvalidBlock(msg)) => report.bad_copies.push((block_hash, msg)),
+            Err(e) => return Err(e),
+        }
+        report.failures.push(failure);
+    }
+    Ok(report)
+}
+
+// Check a block against its checksum and its merkle root.
+async fn find_damage<A: BlockArchive + ?Sized>(archive: &A, block_hash: &BlockHash) -> Result<Option<CheckFailure>> {
+    match archive.verify_checksum(block_hash).await {
+        Ok(_) => {}
+        Err(Error::ChecksumMismatch(_)) => return Ok(Some(CheckFailure::new(block_hash, FailureKind::ChecksumMismatch,
+            format!("checksum mismatch for block {}", block_hash)))),
+        Err(e) => return Ok(Some(CheckFailure::new(block_hash, FailureKind::ReadError,
+            format!("error reading block {}: {}", block_hash, e)))),
+    }
+    match check_stored_block(archive, block_hash, false).await {
+        Ok(f) => Ok(f),
+        // a truncated block can not be read to the end
+        Err(e) => Ok(Some(CheckFailure::new(block_hash, FailureKind::ReadError,
+            format!("error reading block {}: {}", block_hash, e)))),
+    }
+}
+
+// Read a copy of a block from the source into memory and check it. Returns None if the source
+// does not have the block.
+async fn fetch_copy(source: &mut RepairSource<'_>, block_hash: &BlockHash) -> Result<Option<Vec<u8>>> {
+    let data = match source {
+        RepairSource::Archive(a) => {
+            if !a.block_exists(block_hash).await? {
+                return Ok(None);
+            }
+            let mut data = Vec::new();
+            a.get_block(block_hash).await?.read_to_end(&mut data).await?;
+            data
+        }
+        RepairSource::Peer(fetcher) => match fetcher.fetch_block_data(block_hash).await? {
+            Some(d) => d,
+            None => return Ok(None),
+        },
+    };
+    checked_block(WritePolicy::MerkleRoot, block_hash, &mut &data[..]).await?;
+    Ok(Some(data))
+}
+
+// Keep a copy of a damaged block in the quarantine archive, if it can be read.
+async fn keep_damaged_copy<A, Q>(archive: &A, quarantine: &Q, block_hash: &BlockHash, reason: &str)
+    where A: BlockArchive + ?Sized, Q: BlockArchive + ?Sized
+{
+    let r = async {
+        let mut reader = archive.get_block(block_hash).await?;
+        match quarantine.store_block(block_hash, &mut reader).await {
+            Ok(_) | Err(Error::BlockExists) => {}
+            Err(e) => return Err(e),
+        }
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        quarantine.set_block_attr(block_hash, REASON_ATTR, reason).await?;
+        quarantine.set_block_attr(block_hash, TIME_ATTR, &time.to_string()).await
+    }.await;
+    if let Err(e) = r {
+        warn!(block_hash = %block_hash, error = %e, "could not keep a copy of a damaged block");
+    }
+}
+
+// Replace a block in the archive with a checked copy.
+async fn swap_block<A: BlockArchive + ?Sized>(archive: &A, copy: &[u8], block_hash: &BlockHash) -> Result<()> {
+    match archive.store_block(block_hash, &mut &copy[..]).await {
+        Ok(_) => Ok(()),
+        // the archive does not overwrite blocks
+        Err(Error::BlockExists) => {
+            archive.remove_block(block_hash).await?;
+            archive.store_block(block_hash, &mut &copy[..]).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // A corrupt block is quarantined, and only restored from a good copy.
+    #[tokio::test]
+    async fn test_quarantine_and_repair() {
+        let source = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let mut block = Vec::new();
+        source.get_block(&genesis).await.unwrap().read_to_end(&mut block).await.unwrap();
+        // changing the lock time changes the txid, so the merkle root does not match
+        let last = block.len() - 1;
+        block[last] ^= 1;
+        let archive = MemoryBlockArchive::new();
+        archive.store_block(&genesis, &mut &block[..]).await.unwrap();
+        let mut quarantine = MemoryBlockArchive::new();
+
+        quarantine_block(&archive, &quarantine, &genesis, "merkle root mismatch").await.unwrap();
+        assert!(!archive.block_exists(&genesis).await.unwrap());
+        let blocks = quarantined_blocks(&mut quarantine).await.unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].block_hash, genesis);
+        assert_eq!(blocks[0].reason.as_deref(), Some("merkle root mismatch"));
+        assert!(blocks[0].time.is_some());
+
+        // a source without the block, and a source with another bad copy
+        assert!(!repair_block(&archive, &quarantine, &MemoryBlockArchive::new(), &genesis).await.unwrap());
+        let bad = MemoryBlockArchive::new();
+        bad.store_block(&genesis, &mut &block[..]).await.unwrap();
+        assert!(matches!(repair_block(&archive, &quarantine, &bad, &genesis).await, Err(Error::InvalidBlock(_))));
+        assert!(quarantine.block_exists(&genesis).await.unwrap());
+        assert!(!archive.block_exists(&genesis).await.unwrap());
+
+        assert!(repair_block(&archive, &quarantine, &source, &genesis).await.unwrap());
+        assert!(archive.block_exists(&genesis).await.unwrap());
+        assert!(quarantined_blocks(&mut quarantine).await.unwrap().is_empty());
+    }
+
+    // Only the damaged block is replaced, and a copy of it is kept in quarantine.
+    #[tokio::test]
+    async fn test_repair_archive() {
+        let source = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let block1 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        let mut archive = MemoryBlockArchive::new();
+        archive.store_block(&block1, &mut source.get_block(&block1).await.unwrap()).await.unwrap();
+        let mut block = Vec::new();
+        source.get_block(&genesis).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let last = block.len() - 1;
+        block[last] ^= 1;
+        archive.store_block(&genesis, &mut &block[..]).await.unwrap();
+        let mut quarantine = MemoryBlockArchive::new();
+
+        let mut checked = Vec::new();
+        let report = repair_archive(&mut archive, &quarantine, &mut RepairSource::Archive(&source), |h, _| checked.push(*h)).await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(checked.len(), 2);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].kind, FailureKind::MerkleRootMismatch);
+        assert_eq!(report.repaired, vec![genesis]);
+        assert!(find_damage(&archive, &genesis).await.unwrap().is_none());
+        let kept = quarantined_blocks(&mut quarantine).await.unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].reason.as_deref(), Some(report.failures[0].message.as_str()));
+
+        // nothing is left to repair
+        let report = repair_archive(&mut archive, &quarantine, &mut RepairSource::Archive(&source), |_, _| {}).await.unwrap();
+        assert!(report.failures.is_empty());
+        assert_eq!(report.to_json()["checked"], 2);
+    }
+}
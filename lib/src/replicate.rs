@@ -0,0 +1,361 @@
+//! Keeping replica archives up to date with a primary archive.
+//!
+//! A [Replicator] lists the primary archive periodically and copies the blocks that are new since
+//! the last listing to each replica. Each copy is checked against the SHA-256 checksum of the data
+//! that was read from the primary, a copy that does not match is removed. A copy that fails is
+//! tried again after a delay that doubles with each attempt, and a block that still could not be
+//! copied is tried again in the next pass.
+//!
+//! The replicas can be of any type, for example a local archive and an S3 archive.
+//!
+//! With a journal directory, the blocks copied to a replica in a pass are a [Batch] with a
+//! journal, so that a copy that was interrupted by the process stopping is removed by the first
+//! pass after a restart, see [crate::journal].
+//!
+//! Example code:
+//!     let mut replicator = Replicator::new(primary).with_replica("backup", replica);
+//!     tokio::spawn(replicate::serve_status(replicator.status(), listener));
+//!     replicator.run(Duration::from_secs(60)).await;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bitcoinsv::bitcoin::BlockHash;
+use tracing::{debug, info, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpListener;
+use tokio_stream::StreamExt;
+use crate::block_archive::{check_block_checksum, ChecksumReader};
+use crate::http::{read_request, write_response};
+use crate::journal::{recover_batches, Batch, Recovery};
+use crate::{BlockArchive, Error, Result};
+
+// the number of times a failed copy is tried again before the pass moves on
+const DEFAULT_RETRIES: u32 = 5;
+
+// the delay before the first retry of a failed copy
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The state of the replication to one replica.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicaStatus {
+    /// The name of the replica.
+    pub name: String,
+    /// The number of blocks copied to the replica.
+    pub copied: u64,
+    /// The number of bytes of block data copied to the replica.
+    pub bytes: u64,
+    /// The number of copies that failed after all retries.
+    pub failed: u64,
+    /// The number of blocks of the primary that the replica did not have at the end of the last
+    /// pass.
+    pub pending: u64,
+    /// The last error copying a block to the replica.
+    pub last_error: Option<String>,
+}
+
+/// The state of a [Replicator], which is shared with the status endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicationStatus {
+    /// The number of completed passes.
+    pub passes: u64,
+    /// The time at which the last pass completed, in seconds since the Unix epoch.
+    pub last_pass: Option<u64>,
+    /// The number of blocks in the primary archive at the last pass.
+    pub primary_blocks: u64,
+    /// The last error listing the primary archive.
+    pub last_error: Option<String>,
+    /// The state of each replica, in the order they were added.
+    pub replicas: Vec<ReplicaStatus>,
+}
+
+impl ReplicationStatus {
+    /// Encode the status as JSON, as served by [serve_status].
+    pub fn to_json(&self) -> Value {
+        let replicas: Vec<Value> = self.replicas.iter().map(|r| json!({
+            "name": r.name,
+            "copied": r.copied,
+            "bytes": r.bytes,
+            "failed": r.failed,
+            "pending": r.pending,
+            "last_error": r.last_error,
+        })).collect();
+        json!({
+            "passes": self.passes,
+            "last_pass": self.last_pass,
+            "primary_blocks": self.primary_blocks,
+            "last_error": self.last_error,
+            "replicas": replicas,
+        })
+    }
+}
+
+/// Copies the new blocks of a primary archive to one or more replica archives.
+pub struct Replicator<P> {
+    primary: P,
+    replicas: Vec<Box<dyn BlockArchive>>,
+    retries: u32,
+    backoff: Duration,
+    // the blocks of the primary that every replica had at the end of the last pass
+    replicated: BTreeSet<BlockHash>,
+    status: Arc<Mutex<ReplicationStatus>>,
+    journal_dir: Option<PathBuf>,
+    // whether the batches left in the journal directory have been recovered
+    recovered: bool,
+}
+
+impl<P: BlockArchive> Replicator<P> {
+    /// Create a replicator for the primary archive, without any replicas.
+    pub fn new(primary: P) -> Replicator<P> {
+        Replicator {
+            primary,
+            replicas: Vec::new(),
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            replicated: BTreeSet::new(),
+            status: Arc::new(Mutex::new(ReplicationStatus::default())),
+            journal_dir: None,
+            recovered: false,
+        }
+    }
+
+    /// Add a replica, the name is used in the status and in log messages.
+    pub fn with_replica<R: BlockArchive + 'static>(mut self, name: &str, replica: R) -> Replicator<P> {
+        self.replicas.push(Box::new(replica));
+        self.status.lock().unwrap().replicas.push(ReplicaStatus { name: name.to_string(), ..Default::default() });
+        self
+    }
+
+    /// Set the number of times a failed copy is tried again, and the delay before the first
+    /// retry. The default is 5 retries starting after one second.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Replicator<P> {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Keep journals of the blocks copied to the replicas in the directory, the journals of a
+    /// replica are named by the name of the replica.
+    pub fn with_journal_dir(mut self, dir: &Path) -> Replicator<P> {
+        self.journal_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Get the status of the replication, which is updated as blocks are copied.
+    pub fn status(&self) -> Arc<Mutex<ReplicationStatus>> {
+        self.status.clone()
+    }
+
+    /// Run passes until the task is dropped, waiting for the interval after each pass. An error
+    /// listing the primary archive is logged and recorded in the status, and the next pass is run
+    /// as usual.
+    pub async fn run(&mut self, interval: Duration) {
+        loop {
+            if let Err(e) = self.run_pass().await {
+                warn!(error = %e, "replication pass failed");
+                self.status.lock().unwrap().last_error = Some(e.to_string());
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// List the primary archive and copy the blocks that are new since the last pass to the
+    /// replicas that do not have them. The first pass checks every block of the primary.
+    ///
+    /// Fails only if the primary archive can not be listed, failed copies are recorded in the
+    /// status.
+    pub async fn run_pass(&mut self) -> Result<()> {
+        let mut results = self.primary.block_list().await?;
+        let mut blocks = BTreeSet::new();
+        while let Some(block_hash) = results.next().await {
+            blocks.insert(block_hash);
+        }
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        drop(results);
+        // forget blocks that have been removed from the primary
+        self.replicated.retain(|h| blocks.contains(h));
+        let new_blocks: Vec<BlockHash> = blocks.iter().filter(|h| !self.replicated.contains(h)).copied().collect();
+        let names: Vec<String> = self.status.lock().unwrap().replicas.iter().map(|r| r.name.clone()).collect();
+        if let Some(dir) = self.journal_dir.as_ref().filter(|_| !self.recovered) {
+            for (replica, name) in self.replicas.iter().zip(names.iter()) {
+                let report = recover_batches(replica.as_ref(), dir, name, Recovery::Resume).await?;
+                if report.batches > 0 {
+                    info!(replica = %name, removed = report.removed.len(), "recovered interrupted copies");
+                }
+            }
+            self.recovered = true;
+        }
+        // the replicas are put back at the end of the pass
+        let batches: Vec<Batch<Box<dyn BlockArchive>>> = std::mem::take(&mut self.replicas).into_iter().zip(names.iter())
+            .map(|(replica, name)| match &self.journal_dir {
+                Some(dir) => replica.begin_batch().with_journal(dir, name),
+                None => replica.begin_batch(),
+            })
+            .collect();
+        let mut pending = vec![0u64; batches.len()];
+        for block_hash in new_blocks.iter() {
+            let mut complete = true;
+            for (i, replica) in batches.iter().enumerate() {
+                match self.copy_block(replica, block_hash).await {
+                    Ok(Some(bytes)) => {
+                        let mut status = self.status.lock().unwrap();
+                        status.replicas[i].copied += 1;
+                        status.replicas[i].bytes += bytes;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        let mut status = self.status.lock().unwrap();
+                        warn!(block_hash = %block_hash, replica = %status.replicas[i].name, error = %e, "could not copy block");
+                        status.replicas[i].failed += 1;
+                        status.replicas[i].last_error = Some(e.to_string());
+                        pending[i] += 1;
+                        complete = false;
+                    }
+                }
+            }
+            if complete {
+                self.replicated.insert(*block_hash);
+            }
+        }
+        for (batch, name) in batches.into_iter().zip(names.iter()) {
+            if let Err(e) = batch.commit().await {
+                warn!(replica = %name, error = %e, "could not remove the journal of the pass");
+            }
+            self.replicas.push(batch.into_inner());
+        }
+        let mut status = self.status.lock().unwrap();
+        status.passes += 1;
+        status.last_pass = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+        status.primary_blocks = blocks.len() as u64;
+        status.last_error = None;
+        for (replica, n) in status.replicas.iter_mut().zip(pending) {
+            replica.pending = n;
+        }
+        info!(pass = status.passes, blocks = blocks.len(), "replication pass complete");
+        Ok(())
+    }
+
+    // Copy a block to a replica, trying again after a failure. Returns the size of the block, or
+    // None if the replica already had it.
+    async fn copy_block(&self, replica: &dyn BlockArchive, block_hash: &BlockHash) -> Result<Option<u64>> {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.try_copy_block(replica, block_hash).await {
+                Ok(r) => return Ok(r),
+                Err(e) if attempt >= self.retries => return Err(e),
+                Err(e) => {
+                    debug!(block_hash = %block_hash, ?delay, error = %e, "copying block failed, trying again");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    // Copy a block to a replica and check the copy.
+    async fn try_copy_block(&self, replica: &dyn BlockArchive, block_hash: &BlockHash) -> Result<Option<u64>> {
+        if replica.block_exists(block_hash).await? {
+            return Ok(None);
+        }
+        let mut reader = ChecksumReader::new(self.primary.get_block(block_hash).await?);
+        match replica.store_block(block_hash, &mut reader).await {
+            Ok(()) => {}
+            // another writer got there first
+            Err(Error::BlockExists) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        if let Err(e) = check_block_checksum(replica, block_hash, &reader.checksum()).await {
+            // never leave a bad copy in the replica
+            let _ = replica.remove_block(block_hash).await;
+            return Err(e);
+        }
+        for (key, value) in self.primary.get_block_attrs(block_hash).await?.iter() {
+            replica.set_block_attr(block_hash, key, value).await?;
+        }
+        Ok(Some(replica.block_size(block_hash).await? as u64))
+    }
+}
+
+/// Serve the status of a replicator as JSON over HTTP, for any GET request, until an error
+/// occurs accepting a connection.
+pub async fn serve_status(status: Arc<Mutex<ReplicationStatus>>, listener: TcpListener) -> Result<()> {
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let status = status.clone();
+        tokio::spawn(async move {
+            let r: Result<()> = async {
+                let mut reader = BufReader::new(socket);
+                if read_request(&mut reader).await?.is_none() {
+                    return Ok(());
+                }
+                let body = status.lock().unwrap().to_json().to_string();
+                let mut writer = BufWriter::new(reader.into_inner());
+                write_response(&mut writer, 200, "application/json", body.as_bytes()).await?;
+                writer.flush().await?;
+                Ok(())
+            }.await;
+            if let Err(e) = r {
+                debug!(%addr, error = %e, "error handling status request");
+            }
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tokio::io::AsyncReadExt;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // Blocks are copied to every replica that does not have them, and only new blocks are copied
+    // in later passes.
+    #[tokio::test]
+    async fn test_run_pass() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let hashes: Vec<BlockHash> = src.block_list().await.unwrap().collect().await;
+        let mut blocks = Vec::new();
+        for h in hashes.iter() {
+            let mut block = Vec::new();
+            src.get_block(h).await.unwrap().read_to_end(&mut block).await.unwrap();
+            blocks.push(block);
+        }
+        let primary = MemoryBlockArchive::new();
+        primary.store_block(&hashes[0], &mut &blocks[0][..]).await.unwrap();
+        primary.store_block(&hashes[1], &mut &blocks[1][..]).await.unwrap();
+        primary.set_block_attr(&hashes[1], "source", "test").await.unwrap();
+        let replica = MemoryBlockArchive::new();
+        replica.store_block(&hashes[0], &mut &blocks[0][..]).await.unwrap();
+        let mut replicator = Replicator::new(primary)
+            .with_replica("a", replica)
+            .with_replica("b", MemoryBlockArchive::new());
+        let status = replicator.status();
+
+        replicator.run_pass().await.unwrap();
+        {
+            let status = status.lock().unwrap();
+            assert_eq!((status.passes, status.primary_blocks), (1, 2));
+            assert_eq!((status.replicas[0].copied, status.replicas[1].copied), (1, 2));
+            assert_eq!(status.replicas[1].bytes, (blocks[0].len() + blocks[1].len()) as u64);
+            assert_eq!(status.replicas[0].pending, 0);
+        }
+        for replica in replicator.replicas.iter() {
+            assert!(replica.verify_checksum(&hashes[1]).await.unwrap());
+            assert_eq!(replica.get_block_attrs(&hashes[1]).await.unwrap().get("source").unwrap(), "test");
+        }
+
+        replicator.primary.store_block(&hashes[2], &mut &blocks[2][..]).await.unwrap();
+        replicator.run_pass().await.unwrap();
+        let json = status.lock().unwrap().to_json();
+        assert_eq!(json["passes"], 2);
+        assert_eq!(json["replicas"][0]["copied"], 2);
+        assert_eq!(json["replicas"][1]["copied"], 3);
+        assert_eq!(replicator.replicated.len(), 3);
+    }
+}
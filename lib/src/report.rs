@@ -0,0 +1,252 @@
+//! Verification reports, records of the checks of the blocks in an archive that can be signed.
+//!
+//! A custodian of an archive keeps the report of each check as evidence of when the archive was
+//! checked and what was found. The report is JSON with the result and timing of each block, the
+//! tool that made the check, and information about the archive. It can be signed with an Ed25519
+//! key, so that a report that has been changed fails the check of its signature.
+//!
+//! A report document is a JSON object with the report under "report" and, if it is signed, the
+//! signature under "signature". The signature is made over the report as serde_json writes it
+//! without whitespace, so the document can be written with any layout.
+//!
+//! Example code:
+//!     let mut report = VerificationReport::new("blocks", "blockarchive", "0.1.0");
+//!     report.record(BlockResult { block_hash, status: BlockStatus::Ok, ... });
+//!     report.finish();
+//!     let document = report.to_document(Some(&pkcs8_key))?;
+//!     let checked = verify_document(&document, Some(&public_key))?;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bitcoinsv::bitcoin::BlockHash;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde_json::{json, Value};
+use crate::verify::CheckFailure;
+use crate::{Error, Result};
+
+/// The format of a report, recorded in every report.
+pub const REPORT_FORMAT: &str = "bsv-blockarchive-verification-report 1";
+
+// the only signature algorithm
+const SIGNATURE_ALGORITHM: &str = "ed25519";
+
+/// The result of the check of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum BlockStatus {
+    /// The block passed the check.
+    Ok,
+    /// The block could not be checked, such as a block that has no recorded checksum.
+    Unchecked,
+    /// The block failed the check.
+    Failed(CheckFailure),
+}
+
+/// The check of one block in a [VerificationReport].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockResult {
+    /// The block.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))]
+    pub block_hash: BlockHash,
+    /// The result of the check.
+    pub status: BlockStatus,
+    /// The size of the block, zero if it is not known.
+    pub size: u64,
+    /// How long the check of the block took.
+    pub elapsed: Duration,
+    /// Whether the result was taken from an earlier check that was resumed.
+    pub resumed: bool,
+}
+
+/// A record of a check of the blocks in an archive, see [crate::report].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VerificationReport {
+    /// The check that was made, such as "blocks" or "checksums".
+    pub check: String,
+    /// The name of the tool that made the check.
+    pub tool: String,
+    /// The version of the tool that made the check.
+    pub tool_version: String,
+    /// Information about the archive, such as its root and network.
+    pub archive: BTreeMap<String, String>,
+    /// When the check started, in seconds since the Unix epoch.
+    pub started: u64,
+    /// When the check finished, in seconds since the Unix epoch, zero until it has.
+    pub finished: u64,
+    /// The result of each block, in the order they were recorded.
+    pub blocks: Vec<BlockResult>,
+}
+
+impl VerificationReport {
+    /// Start the report of a check, timestamped now.
+    pub fn new(check: &str, tool: &str, tool_version: &str) -> VerificationReport {
+        VerificationReport {
+            check: check.to_string(),
+            tool: tool.to_string(),
+            tool_version: tool_version.to_string(),
+            archive: BTreeMap::new(),
+            started: now(),
+            finished: 0,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Add an item of information about the archive.
+    pub fn with_archive_info(mut self, key: &str, value: &str) -> VerificationReport {
+        self.archive.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Record the result of a block.
+    pub fn record(&mut self, result: BlockResult) {
+        self.blocks.push(result);
+    }
+
+    /// Record that the check has finished, now.
+    pub fn finish(&mut self) {
+        self.finished = now();
+    }
+
+    /// Whether every block passed or could not be checked.
+    pub fn is_ok(&self) -> bool {
+        !self.blocks.iter().any(|b| matches!(b.status, BlockStatus::Failed(_)))
+    }
+
+    /// The report as JSON.
+    pub fn to_json(&self) -> Value {
+        let count = |f: fn(&BlockResult) -> bool| self.blocks.iter().filter(|b| f(b)).count();
+        let blocks: Vec<Value> = self.blocks.iter().map(|b| {
+            let mut v = json!({
+                "block_hash": b.block_hash.to_string(),
+                "size": b.size,
+                "millis": b.elapsed.as_millis() as u64,
+                "resumed": b.resumed,
+            });
+            match &b.status {
+                BlockStatus::Ok => v["status"] = json!("ok"),
+                BlockStatus::Unchecked => v["status"] = json!("unchecked"),
+                BlockStatus::Failed(failure) => {
+                    v["status"] = json!("error");
+                    v["error"] = json!(failure.kind.name());
+                    v["message"] = json!(failure.message);
+                }
+            }
+            v
+        }).collect();
+        json!({
+            "format": REPORT_FORMAT,
+            "check": self.check,
+            "tool": {"name": self.tool, "version": self.tool_version},
+            "archive": self.archive,
+            "started": self.started,
+            "finished": self.finished,
+            "summary": {
+                "checked": self.blocks.len(),
+                "ok": count(|b| b.status == BlockStatus::Ok),
+                "unchecked": count(|b| b.status == BlockStatus::Unchecked),
+                "errors": count(|b| matches!(b.status, BlockStatus::Failed(_))),
+                "resumed": count(|b| b.resumed),
+            },
+            "blocks": blocks,
+        })
+    }
+
+    /// The report document, signed with an Ed25519 key in PKCS#8 format if one is given.
+    pub fn to_document(&self, pkcs8_key: Option<&[u8]>) -> Result<Value> {
+        let report = self.to_json();
+        let key = match pkcs8_key {
+            Some(k) => k,
+            None => return Ok(json!({"report": report})),
+        };
+        // keys made by openssl are PKCS#8 version 1, without the public key
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(key)
+            .map_err(|e| Error::InvalidReport(format!("invalid signing key: {}", e)))?;
+        let signature = key_pair.sign(report.to_string().as_bytes());
+        Ok(json!({
+            "report": report,
+            "signature": {
+                "algorithm": SIGNATURE_ALGORITHM,
+                "public_key": hex::encode(key_pair.public_key().as_ref()),
+                "signature": hex::encode(signature.as_ref()),
+            },
+        }))
+    }
+}
+
+/// Check the signature of a report document made by [VerificationReport::to_document],
+/// returning the report.
+///
+/// If a public key is given the report must have been signed with its key. Otherwise any valid
+/// signature is accepted, which only shows that the report has not been changed since it was
+/// signed with the key in the document.
+pub fn verify_document(document: &Value, public_key: Option<&[u8]>) -> Result<Value> {
+    let report = document.get("report").filter(|r| r.is_object())
+        .ok_or_else(|| Error::InvalidReport("there is no report".to_string()))?;
+    if report.get("format").and_then(Value::as_str) != Some(REPORT_FORMAT) {
+        return Err(Error::InvalidReport("unknown format".to_string()));
+    }
+    let signature = document.get("signature")
+        .ok_or_else(|| Error::InvalidReport("the report is not signed".to_string()))?;
+    if signature.get("algorithm").and_then(Value::as_str) != Some(SIGNATURE_ALGORITHM) {
+        return Err(Error::InvalidReport("unknown signature algorithm".to_string()));
+    }
+    let field = |name: &str| signature.get(name).and_then(Value::as_str).and_then(|s| hex::decode(s).ok())
+        .ok_or_else(|| Error::InvalidReport(format!("invalid {}", name)));
+    let signed_by = field("public_key")?;
+    if public_key.is_some_and(|k| k != signed_by.as_slice()) {
+        return Err(Error::InvalidSignature);
+    }
+    UnparsedPublicKey::new(&ED25519, &signed_by)
+        .verify(report.to_string().as_bytes(), &field("signature")?)
+        .map_err(|_| Error::InvalidSignature)?;
+    Ok(report.clone())
+}
+
+// the time now, in seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hex::FromHex;
+    use ring::rand::SystemRandom;
+    use crate::verify::FailureKind;
+    use super::*;
+
+    // A signed report can be checked after it has been written and read back, and fails if it
+    // is changed or was signed with another key.
+    #[test]
+    fn test_signed_report() {
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut report = VerificationReport::new("blocks", "test", "1").with_archive_info("root", "/data");
+        report.record(BlockResult { block_hash: h, status: BlockStatus::Ok, size: 100, elapsed: Duration::from_millis(5), resumed: false });
+        let failure = CheckFailure::new(&h, FailureKind::MerkleRootMismatch, "damaged".to_string());
+        report.record(BlockResult { block_hash: h, status: BlockStatus::Failed(failure), size: 0, elapsed: Duration::ZERO, resumed: true });
+        report.finish();
+        assert!(!report.is_ok());
+        let json = report.to_json();
+        assert_eq!(json["summary"]["errors"], 1);
+        assert_eq!(json["blocks"][1]["error"], FailureKind::MerkleRootMismatch.name());
+
+        let rng = SystemRandom::new();
+        let key = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let public_key = Ed25519KeyPair::from_pkcs8(key.as_ref()).unwrap().public_key().as_ref().to_vec();
+        let document = report.to_document(Some(key.as_ref())).unwrap();
+        let read: Value = serde_json::from_str(&document.to_string()).unwrap();
+        assert_eq!(verify_document(&read, Some(&public_key)).unwrap(), json);
+        assert!(verify_document(&read, None).is_ok());
+
+        let mut changed = read.clone();
+        changed["report"]["summary"]["errors"] = json!(0);
+        assert!(matches!(verify_document(&changed, None), Err(Error::InvalidSignature)));
+        let other = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let other_key = Ed25519KeyPair::from_pkcs8(other.as_ref()).unwrap().public_key().as_ref().to_vec();
+        assert!(matches!(verify_document(&read, Some(&other_key)), Err(Error::InvalidSignature)));
+        let unsigned = report.to_document(None).unwrap();
+        assert!(matches!(verify_document(&unsigned, None), Err(Error::InvalidReport(_))));
+    }
+}
@@ -0,0 +1,409 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use tracing::{debug, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::AsyncRead;
+use crate::block_archive::BlockHashListStream;
+use crate::{BlockArchive, BlockAttrs, Error, ListOptions, Result};
+
+// the default number of retries of a failed operation, and the delay before the first retry
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(200);
+
+/// The state of the circuit breaker of a [ResilientBlockArchive].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Operations are passed to the wrapped archive.
+    Closed,
+    /// The wrapped archive has failed too often, operations fail without being tried.
+    Open,
+    /// The circuit has been open for long enough, the next operation is tried and closes the
+    /// circuit if it succeeds or opens it again if it fails.
+    HalfOpen,
+}
+
+/// A block archive that protects its callers from the transient failures of the wrapped archive,
+/// such as an S3 or HTTP archive on an unreliable network.
+///
+/// Each operation can be given a time limit. Operations that fail with a transient error, which
+/// is a timeout, an IO error, or an unavailable or remote archive, are tried again after a delay
+/// that doubles each time, with some randomness so that many clients do not retry together.
+/// Storing a block and listing blocks are not tried again, because the block can only be read
+/// once and the listing may have been partly consumed.
+///
+/// An optional circuit breaker stops sending operations to an archive that keeps failing. After a
+/// number of consecutive transient failures the circuit opens and operations fail at once with
+/// [Error::StorageUnavailable]. Once the reset time has passed one operation is let through, and
+/// the circuit closes again if it succeeds.
+///
+/// The time limit and retries apply to opening a block, not to reading it.
+///
+/// Example code:
+///     let archive = ResilientBlockArchive::new(archive)
+///         .with_timeout(Duration::from_secs(10))
+///         .with_circuit_breaker(5, Duration::from_secs(30));
+pub struct ResilientBlockArchive<A: BlockArchive> {
+    archive: A,
+    policy: Policy,
+}
+
+impl<A: BlockArchive> ResilientBlockArchive<A> {
+    /// Wrap an archive, with 3 retries starting after 200ms, and no time limit or circuit
+    /// breaker.
+    pub fn new(archive: A) -> ResilientBlockArchive<A> {
+        ResilientBlockArchive {
+            archive,
+            policy: Policy {
+                timeout: None,
+                retries: DEFAULT_RETRIES,
+                backoff: DEFAULT_BACKOFF,
+                breaker: None,
+                state: Mutex::new(BreakerState::default()),
+                rng: SystemRandom::new(),
+            },
+        }
+    }
+
+    /// Limit the time of each attempt of an operation. The limit must allow for the largest block
+    /// to be stored.
+    pub fn with_timeout(mut self, timeout: Duration) -> ResilientBlockArchive<A> {
+        self.policy.timeout = Some(timeout);
+        self
+    }
+
+    /// Set the number of times an operation that fails with a transient error is tried again,
+    /// and the delay before the first retry.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> ResilientBlockArchive<A> {
+        self.policy.retries = retries;
+        self.policy.backoff = backoff;
+        self
+    }
+
+    /// Open the circuit after the number of consecutive transient failures, and try the archive
+    /// again after the reset time.
+    pub fn with_circuit_breaker(mut self, failures: u32, reset_after: Duration) -> ResilientBlockArchive<A> {
+        self.policy.breaker = Some((failures.max(1), reset_after));
+        self
+    }
+
+    /// Get the state of the circuit breaker, which is always closed if there is no breaker.
+    pub fn circuit_state(&self) -> CircuitState {
+        let state = self.policy.state.lock().unwrap();
+        match (state.opened_at, self.policy.breaker) {
+            (Some(opened_at), Some((_, reset_after))) if opened_at.elapsed() < reset_after => CircuitState::Open,
+            (Some(_), _) => CircuitState::HalfOpen,
+            (None, _) => CircuitState::Closed,
+        }
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+}
+
+// The time limit, retries, and circuit breaker, kept apart from the archive so that an operation
+// can use the policy and borrow the archive mutably.
+struct Policy {
+    timeout: Option<Duration>,
+    retries: u32,
+    backoff: Duration,
+    // the number of failures that opens the circuit, and the time before it is tried again
+    breaker: Option<(u32, Duration)>,
+    state: Mutex<BreakerState>,
+    rng: SystemRandom,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    // when the circuit was opened, None if it is closed
+    opened_at: Option<Instant>,
+    // true while the operation that was let through a half open circuit is running
+    trial: bool,
+}
+
+impl Policy {
+    // Run an operation, trying it again after a transient failure if retry is true.
+    async fn call<T, F, Fut>(&self, name: &str, retry: bool, f: F) -> Result<T>
+        where F: Fn() -> Fut, Fut: Future<Output=Result<T>>
+    {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let result = self.once(f()).await;
+            match result {
+                Err(e) if retry && attempt < self.retries && is_transient(&e) => {
+                    let wait = self.jitter(delay);
+                    debug!(operation = name, ?wait, error = %e, "operation failed, trying again");
+                    tokio::time::sleep(wait).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    // Run a single attempt of an operation, if the circuit breaker allows it.
+    async fn once<T, Fut: Future<Output=Result<T>>>(&self, f: Fut) -> Result<T> {
+        self.admit()?;
+        let result = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, f).await
+                .unwrap_or_else(|_| Err(Error::IoError(std::io::ErrorKind::TimedOut.into()))),
+            None => f.await,
+        };
+        self.record(&result);
+        result
+    }
+
+    // Check that the circuit breaker allows an operation.
+    fn admit(&self) -> Result<()> {
+        let reset_after = match self.breaker {
+            Some((_, reset_after)) => reset_after,
+            None => return Ok(()),
+        };
+        let mut state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => Ok(()),
+            Some(opened_at) if opened_at.elapsed() >= reset_after && !state.trial => {
+                state.trial = true;
+                Ok(())
+            }
+            Some(_) => Err(Error::StorageUnavailable(format!("circuit breaker is open after {} failures", state.consecutive_failures))),
+        }
+    }
+
+    // Update the circuit breaker with the result of an operation.
+    fn record<T>(&self, result: &Result<T>) {
+        let failures = match self.breaker {
+            Some((failures, _)) => failures,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Err(e) if is_transient(e) => {
+                state.consecutive_failures += 1;
+                if state.trial || (state.opened_at.is_none() && state.consecutive_failures >= failures) {
+                    warn!(failures = state.consecutive_failures, error = %e, "opening the circuit breaker");
+                    state.opened_at = Some(Instant::now());
+                    state.trial = false;
+                }
+            }
+            _ => {
+                if state.opened_at.is_some() {
+                    debug!("closing the circuit breaker");
+                }
+                *state = BreakerState::default();
+            }
+        }
+    }
+
+    // Get a random delay between half and all of the delay.
+    fn jitter(&self, delay: Duration) -> Duration {
+        let mut bytes = [0u8; 4];
+        let r = match self.rng.fill(&mut bytes) {
+            Ok(()) => u32::from_le_bytes(bytes) as f64 / u32::MAX as f64,
+            Err(_) => 1.0,
+        };
+        delay.mul_f64(0.5 + r / 2.0)
+    }
+}
+
+// Check whether an error may go away if the operation is tried again.
+fn is_transient(e: &Error) -> bool {
+    matches!(e, Error::StorageUnavailable(_) | Error::IoError(_) | Error::RemoteError(_) | Error::PeerError(_))
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for ResilientBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.policy.call("get_block", true, || self.archive.get_block(block_hash)).await
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.policy.call("get_block_range", true, || self.archive.get_block_range(block_hash, offset, length)).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.policy.call("block_exists", true, || self.archive.block_exists(block_hash)).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        self.policy.call("blocks_exist", true, || self.archive.blocks_exist(block_hashes)).await
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        self.policy.once(self.archive.store_block(block_hash, block)).await
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.policy.call("remove_block", true, || self.archive.remove_block(block_hash)).await
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.policy.call("block_size", true, || self.archive.block_size(block_hash)).await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.policy.call("block_header", true, || self.archive.block_header(block_hash)).await
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        self.policy.call("block_headers", true, || self.archive.block_headers(block_hashes)).await
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.policy.once(self.archive.block_list()).await
+    }
+
+    async fn block_list_opts(&mut self, options: ListOptions) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.policy.once(self.archive.block_list_opts(options)).await
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.policy.call("set_block_attr", true, || self.archive.set_block_attr(block_hash, key, value)).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.policy.call("remove_block_attr", true, || self.archive.remove_block_attr(block_hash, key)).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.policy.call("get_block_attrs", true, || self.archive.get_block_attrs(block_hash)).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.policy.call("verify_checksum", true, || self.archive.verify_checksum(block_hash)).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use hex::FromHex;
+    use crate::MemoryBlockArchive;
+    use super::*;
+
+    // An archive whose block_exists fails a number of times before it works, and may be slow.
+    struct FlakyArchive {
+        archive: MemoryBlockArchive,
+        failures: AtomicU32,
+        calls: AtomicU32,
+        delay: Duration,
+    }
+
+    impl FlakyArchive {
+        fn new(failures: u32, delay: Duration) -> FlakyArchive {
+            FlakyArchive { archive: MemoryBlockArchive::new(), failures: AtomicU32::new(failures), calls: AtomicU32::new(0), delay }
+        }
+    }
+
+    #[async_trait]
+    impl BlockArchive for FlakyArchive {
+        async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+            self.archive.get_block(block_hash).await
+        }
+
+        async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(Error::StorageUnavailable("flaky".to_string()));
+            }
+            self.archive.block_exists(block_hash).await
+        }
+
+        async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+            self.archive.store_block(block_hash, block).await
+        }
+
+        async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+            self.archive.remove_block(block_hash).await
+        }
+
+        async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.archive.block_size(block_hash).await
+        }
+
+        async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+            self.archive.block_header(block_hash).await
+        }
+
+        async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+            self.archive.block_list().await
+        }
+
+        async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+            self.archive.set_block_attr(block_hash, key, value).await
+        }
+
+        async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+            self.archive.remove_block_attr(block_hash, key).await
+        }
+
+        async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+            self.archive.get_block_attrs(block_hash).await
+        }
+
+        async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+            self.archive.verify_checksum(block_hash).await
+        }
+    }
+
+    fn hash() -> BlockHash {
+        BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap()
+    }
+
+    // Transient failures are tried again, other errors are not, and slow operations time out.
+    #[tokio::test]
+    async fn test_retries() {
+        let archive = ResilientBlockArchive::new(FlakyArchive::new(2, Duration::ZERO))
+            .with_retries(3, Duration::from_millis(1));
+        assert!(!archive.block_exists(&hash()).await.unwrap());
+        assert_eq!(archive.archive().calls.load(Ordering::SeqCst), 3);
+
+        assert!(matches!(archive.block_size(&hash()).await, Err(Error::BlockNotFound)));
+        assert_eq!(archive.archive().calls.load(Ordering::SeqCst), 4);
+
+        let archive = ResilientBlockArchive::new(FlakyArchive::new(5, Duration::ZERO))
+            .with_retries(1, Duration::from_millis(1));
+        assert!(matches!(archive.block_exists(&hash()).await, Err(Error::StorageUnavailable(_))));
+        assert_eq!(archive.archive().calls.load(Ordering::SeqCst), 2);
+
+        let archive = ResilientBlockArchive::new(FlakyArchive::new(0, Duration::from_secs(10)))
+            .with_timeout(Duration::from_millis(10))
+            .with_retries(0, Duration::ZERO);
+        match archive.block_exists(&hash()).await {
+            Err(Error::IoError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+            r => panic!("expected a timeout, got {:?}", r.map_err(|e| e.to_string())),
+        }
+    }
+
+    // The circuit opens after consecutive failures, and closes when a trial succeeds.
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let archive = ResilientBlockArchive::new(FlakyArchive::new(2, Duration::ZERO))
+            .with_retries(0, Duration::ZERO)
+            .with_circuit_breaker(2, Duration::from_millis(50));
+        assert!(archive.block_exists(&hash()).await.is_err());
+        assert_eq!(archive.circuit_state(), CircuitState::Closed);
+        assert!(archive.block_exists(&hash()).await.is_err());
+        assert_eq!(archive.circuit_state(), CircuitState::Open);
+        // the archive is not called while the circuit is open
+        assert!(matches!(archive.block_exists(&hash()).await, Err(Error::StorageUnavailable(_))));
+        assert_eq!(archive.archive().calls.load(Ordering::SeqCst), 2);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(archive.circuit_state(), CircuitState::HalfOpen);
+        assert!(!archive.block_exists(&hash()).await.unwrap());
+        assert_eq!(archive.circuit_state(), CircuitState::Closed);
+        assert_eq!(archive.archive().calls.load(Ordering::SeqCst), 3);
+    }
+}
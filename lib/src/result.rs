@@ -1,29 +1,246 @@
 
+use bitcoinsv::bitcoin::BlockHash;
+
 /// Standard Result used in the library
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Standard error type used in the library
+///
+/// With the `serde` feature an error is serialized as an [ErrorRecord], its [Error::kind] and its
+/// message. An error that is deserialized keeps its kind if it carries no data, any other error is
+/// read back as a [Error::RemoteError] with the message, as it may have held an IO error. Read an
+/// [ErrorRecord] to keep the kind of every error.
 #[derive(Debug)]
 pub enum Error {
     /// The block was not found in the archive.
     BlockNotFound,
-    /// The block already exists in the archive. This error may be returned by [BlockArchive::store_block].
+    /// The block already exists in the archive. This error is returned by [BlockArchive::store_block]
+    /// unless the archive has been set to overwrite blocks.
     BlockExists,
+    /// A block hash could not be parsed.
+    InvalidHash(String),
+    /// The storage behind the archive cannot be reached, for example the root directory is missing.
+    StorageUnavailable(String),
+    /// The stored block could not be read in full or is damaged.
+    CorruptBlock(BlockHash),
+    /// The checksum of a block did not match the expected checksum.
+    ChecksumMismatch(BlockHash),
+    /// A block attribute key or value cannot be stored.
+    InvalidAttribute(String),
+    /// The name of a network was not recognised.
+    UnknownNetwork(String),
+    /// A manifest could not be parsed or created.
+    InvalidManifest(String),
+    /// A signature did not verify.
+    InvalidSignature,
+    /// The ARCHIVE_META file could not be read.
+    InvalidArchiveMeta(String),
+    /// The archive was written in a format version that this version of the library does not support.
+    UnsupportedFormatVersion(u32),
+    /// A backup set could not be read.
+    InvalidBackup(String),
+    /// A blk*.dat block file could not be read.
+    InvalidBlockFile(String),
+    /// A block given to [crate::BlockArchive::store_block] failed the checks of the
+    /// [crate::WritePolicy] of the archive.
+    InvalidBlock(String),
+    /// An error communicating with a peer.
+    PeerError(String),
+    /// The transaction was not found in the transaction index.
+    TxNotFound,
+    /// An error reading or writing the transaction index.
+    IndexError(String),
+    /// An error reading or writing the block filters, or a damaged filter.
+    FilterError(String),
+    /// A tiering policy could not be parsed.
+    InvalidPolicy(String),
+    /// A pack file or the index of a packed archive could not be read or written.
+    InvalidPack(String),
+    /// The database of a header archive could not be read or written.
+    InvalidHeaderArchive(String),
+    /// The database of a deduplicating archive could not be read or written, or is damaged, see
+    /// [crate::DedupBlockArchive].
+    InvalidDedupStore(String),
+    /// The manifest of a block that is stored in chunks could not be read, see [crate::chunks].
+    InvalidChunkManifest(String),
+    /// The parent of a header is not in a chain-linked header archive, see
+    /// [crate::SledHeaderArchive::with_chain_linkage].
+    MissingParent(BlockHash),
+    /// A thin block, or the store of thin blocks, could not be read or written.
+    InvalidThinBlock(String),
+    /// The archive is locked by another process, see [crate::lock].
+    ArchiveLocked(String),
+    /// The archive was opened read-only and cannot be changed.
+    ReadOnly,
+    /// The archive is immutable, stored blocks cannot be replaced or removed, see
+    /// [crate::SimpleFileBasedBlockArchive::set_immutable].
+    ArchiveImmutable,
+    /// The shard map of a sharded archive could not be read or does not cover every block, see
+    /// [crate::ShardMap].
+    InvalidShardMap(String),
+    /// The archive holds blocks of a different network.
+    WrongNetwork(String),
+    /// An encryption key could not be read or is not valid.
+    InvalidKey(String),
+    /// An error reported by a remote archive, or an error communicating with it.
+    RemoteError(String),
+    /// Data could not be exported, for example a Parquet file could not be written.
+    ExportError(String),
+    /// The catalog of block metadata could not be read or written, see [crate::catalog].
+    CatalogError(String),
+    /// A verification report could not be read, or could not be signed, see [crate::report].
+    InvalidReport(String),
+    /// A block could not be published to a message broker, see [crate::publish].
+    PublishError(String),
+    /// A background task panicked or was cancelled.
+    TaskFailed(tokio::task::JoinError),
+    /// A task spawned on a runtime other than tokio panicked, with the message of the panic, see
+    /// [crate::runtime].
+    TaskPanicked(String),
+    /// An IO error from the underlying storage.
     IoError(std::io::Error),
+    /// An error decoding block data.
     BitcoinSVError(bitcoinsv::Error),
 }
 
+impl Error {
+    /// Get a short name of the error, for metrics and scripts.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::BlockNotFound => "block_not_found",
+            Error::BlockExists => "block_exists",
+            Error::InvalidHash(_) => "invalid_hash",
+            Error::StorageUnavailable(_) => "storage_unavailable",
+            Error::CorruptBlock(_) => "corrupt_block",
+            Error::ChecksumMismatch(_) => "checksum_mismatch",
+            Error::InvalidAttribute(_) => "invalid_attribute",
+            Error::UnknownNetwork(_) => "unknown_network",
+            Error::InvalidManifest(_) => "invalid_manifest",
+            Error::InvalidSignature => "invalid_signature",
+            Error::InvalidArchiveMeta(_) => "invalid_archive_meta",
+            Error::UnsupportedFormatVersion(_) => "unsupported_format_version",
+            Error::InvalidBackup(_) => "invalid_backup",
+            Error::InvalidBlockFile(_) => "invalid_block_file",
+            Error::InvalidBlock(_) => "invalid_block",
+            Error::PeerError(_) => "peer_error",
+            Error::TxNotFound => "tx_not_found",
+            Error::IndexError(_) => "index_error",
+            Error::FilterError(_) => "filter_error",
+            Error::InvalidPolicy(_) => "invalid_policy",
+            Error::InvalidPack(_) => "invalid_pack",
+            Error::InvalidHeaderArchive(_) => "invalid_header_archive",
+            Error::InvalidDedupStore(_) => "invalid_dedup_store",
+            Error::InvalidChunkManifest(_) => "invalid_chunk_manifest",
+            Error::MissingParent(_) => "missing_parent",
+            Error::InvalidThinBlock(_) => "invalid_thin_block",
+            Error::ArchiveLocked(_) => "archive_locked",
+            Error::ReadOnly => "read_only",
+            Error::ArchiveImmutable => "archive_immutable",
+            Error::InvalidShardMap(_) => "invalid_shard_map",
+            Error::WrongNetwork(_) => "wrong_network",
+            Error::InvalidKey(_) => "invalid_key",
+            Error::RemoteError(_) => "remote_error",
+            Error::ExportError(_) => "export_error",
+            Error::CatalogError(_) => "catalog_error",
+            Error::InvalidReport(_) => "invalid_report",
+            Error::PublishError(_) => "publish_error",
+            Error::TaskFailed(_) => "task_failed",
+            Error::TaskPanicked(_) => "task_panicked",
+            Error::IoError(_) => "io_error",
+            Error::BitcoinSVError(_) => "bitcoinsv_error",
+        }
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Error::BlockNotFound => write!(f, "Block not found"),
             Error::BlockExists => write!(f, "Block exists"),
+            Error::InvalidHash(s) => write!(f, "Invalid block hash: {}", s),
+            Error::StorageUnavailable(msg) => write!(f, "Storage unavailable: {}", msg),
+            Error::CorruptBlock(hash) => write!(f, "Corrupt block: {}", hash),
+            Error::ChecksumMismatch(hash) => write!(f, "Checksum mismatch for block {}", hash),
+            Error::InvalidAttribute(key) => write!(f, "Invalid block attribute: {}", key),
+            Error::UnknownNetwork(name) => write!(f, "Unknown network: {}", name),
+            Error::InvalidManifest(msg) => write!(f, "Invalid manifest: {}", msg),
+            Error::InvalidSignature => write!(f, "Invalid signature"),
+            Error::InvalidArchiveMeta(msg) => write!(f, "Invalid archive meta file: {}", msg),
+            Error::UnsupportedFormatVersion(v) => write!(f, "Unsupported archive format version: {}", v),
+            Error::InvalidBackup(msg) => write!(f, "Invalid backup: {}", msg),
+            Error::InvalidBlockFile(msg) => write!(f, "Invalid block file: {}", msg),
+            Error::InvalidBlock(msg) => write!(f, "Invalid block: {}", msg),
+            Error::PeerError(msg) => write!(f, "Peer error: {}", msg),
+            Error::TxNotFound => write!(f, "Transaction not found"),
+            Error::IndexError(msg) => write!(f, "Transaction index error: {}", msg),
+            Error::FilterError(msg) => write!(f, "Block filter error: {}", msg),
+            Error::InvalidPolicy(s) => write!(f, "Invalid tiering policy: {}", s),
+            Error::InvalidPack(msg) => write!(f, "Invalid pack: {}", msg),
+            Error::InvalidHeaderArchive(msg) => write!(f, "Invalid header archive: {}", msg),
+            Error::InvalidDedupStore(msg) => write!(f, "Invalid deduplicating archive: {}", msg),
+            Error::InvalidChunkManifest(msg) => write!(f, "Invalid chunk manifest: {}", msg),
+            Error::MissingParent(hash) => write!(f, "Missing parent: {}", hash),
+            Error::InvalidThinBlock(msg) => write!(f, "Invalid thin block: {}", msg),
+            Error::ArchiveLocked(msg) => write!(f, "Archive locked: {}", msg),
+            Error::ReadOnly => write!(f, "Archive is read-only"),
+            Error::ArchiveImmutable => write!(f, "Archive is immutable"),
+            Error::InvalidShardMap(msg) => write!(f, "Invalid shard map: {}", msg),
+            Error::WrongNetwork(msg) => write!(f, "Wrong network: {}", msg),
+            Error::InvalidKey(msg) => write!(f, "Invalid encryption key: {}", msg),
+            Error::RemoteError(msg) => write!(f, "Remote archive error: {}", msg),
+            Error::ExportError(msg) => write!(f, "Export error: {}", msg),
+            Error::CatalogError(msg) => write!(f, "Catalog error: {}", msg),
+            Error::InvalidReport(msg) => write!(f, "Invalid verification report: {}", msg),
+            Error::PublishError(msg) => write!(f, "Publish error: {}", msg),
+            Error::TaskFailed(err) => write!(f, "Background task failed: {}", err),
+            Error::TaskPanicked(msg) => write!(f, "Background task panicked: {}", msg),
             Error::IoError(err) => write!(f, "IO error: {}", err),
             Error::BitcoinSVError(err) => write!(f, "Bitcoin SV error: {}", err),
         }
     }
 }
 
+/// The kind and message of an [Error], which can be serialized and deserialized in full.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorRecord {
+    /// The short name of the error, see [Error::kind].
+    pub kind: String,
+    /// The message of the error.
+    pub message: String,
+}
+
+impl From<&Error> for ErrorRecord {
+    fn from(err: &Error) -> ErrorRecord {
+        ErrorRecord { kind: err.kind().to_string(), message: err.to_string() }
+    }
+}
+
+impl From<ErrorRecord> for Error {
+    fn from(record: ErrorRecord) -> Error {
+        match record.kind.as_str() {
+            "block_not_found" => Error::BlockNotFound,
+            "block_exists" => Error::BlockExists,
+            "invalid_signature" => Error::InvalidSignature,
+            "tx_not_found" => Error::TxNotFound,
+            "read_only" => Error::ReadOnly,
+            "archive_immutable" => Error::ArchiveImmutable,
+            _ => Error::RemoteError(record.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::TaskFailed(err) => Some(err),
+            Error::IoError(err) => Some(err),
+            Error::BitcoinSVError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
         Error::IoError(err)
@@ -0,0 +1,333 @@
+//! A JSON-RPC server that answers a subset of the bitcoind RPC calls from an archive.
+//!
+//! The supported methods are getblock, getblockheader, getblockhash, and getblockcount, with the
+//! same parameters and results as bitcoind, so that tools written against a node can read blocks
+//! straight from the archive. Verbosity 2 of getblock, which decodes every transaction, is not
+//! supported.
+//!
+//! Heights come from a [ChainIndex] that is built when the server starts. Blocks added to the
+//! archive after that are served but do not have a height. The server is read-only and ignores
+//! any credentials sent by the client.
+//!
+//! Example code:
+//!     let listener = TcpListener::bind("127.0.0.1:8332").await?;
+//!     rpc::serve(archive, listener).await?;
+use std::sync::Arc;
+use bitcoinsv::bitcoin::BlockHash;
+use hex::FromHex;
+use tracing::debug;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use crate::http::{read_request, write_response};
+use crate::txindex::scan_transactions;
+use crate::{BlockArchive, ChainIndex, Error, Result};
+
+// the size of an encoded block header
+const HEADER_SIZE: u64 = 80;
+
+// error codes, as used by bitcoind
+const RPC_INVALID_REQUEST: i64 = -32600;
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+const RPC_PARSE_ERROR: i64 = -32700;
+const RPC_MISC_ERROR: i64 = -1;
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+const RPC_INVALID_PARAMETER: i64 = -8;
+
+// An error returned to the client.
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError {
+        RpcError { code, message: message.to_string() }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::BlockNotFound => RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "Block not found"),
+            e => RpcError::new(RPC_MISC_ERROR, &e.to_string()),
+        }
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+// The state shared by all connections.
+struct RpcState<A> {
+    archive: A,
+    chain: ChainIndex,
+}
+
+/// Serve the archive over JSON-RPC, until an error occurs accepting a connection.
+///
+/// The chain index is built before the server starts accepting connections, which reads the
+/// header of every block in the archive.
+pub async fn serve<A: BlockArchive + 'static>(mut archive: A, listener: TcpListener) -> Result<()> {
+    let chain = ChainIndex::build(&mut archive).await?;
+    let state = Arc::new(RpcState { archive, chain });
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&state, socket).await {
+                debug!(%addr, error = %e, "error handling rpc request");
+            }
+        });
+    }
+}
+
+// Read a request and write the response.
+async fn handle_connection<A: BlockArchive>(state: &RpcState<A>, socket: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(socket);
+    let request = match read_request(&mut reader).await? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let mut socket = reader.into_inner();
+    if request.method != "POST" {
+        return write_response(&mut socket, 405, "text/plain", b"JSONRPC server handles only POST requests").await;
+    }
+    // the http status codes follow bitcoind
+    let (status, response) = match serde_json::from_slice::<Value>(&request.body) {
+        Ok(Value::Array(calls)) => {
+            let mut responses = Vec::new();
+            for call in calls {
+                responses.push(handle_call(state, &call).await.1);
+            }
+            (200, Value::Array(responses))
+        }
+        Ok(call) => handle_call(state, &call).await,
+        Err(_) => (500, make_response(Value::Null, Err(RpcError::new(RPC_PARSE_ERROR, "Parse error")))),
+    };
+    write_response(&mut socket, status, "application/json", response.to_string().as_bytes()).await
+}
+
+// Handle a single call, returning the http status and the response.
+async fn handle_call<A: BlockArchive>(state: &RpcState<A>, call: &Value) -> (u16, Value) {
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+    let method = match call.get("method").and_then(Value::as_str) {
+        Some(m) => m,
+        None => return (400, make_response(id, Err(RpcError::new(RPC_INVALID_REQUEST, "Method must be a string")))),
+    };
+    let params = match call.get("params") {
+        None | Some(Value::Null) => &[][..],
+        Some(Value::Array(p)) => &p[..],
+        Some(_) => return (400, make_response(id, Err(RpcError::new(RPC_INVALID_REQUEST, "Params must be an array")))),
+    };
+    let result = match method {
+        "getblock" => get_block(state, params).await,
+        "getblockcount" => get_block_count(state),
+        "getblockhash" => get_block_hash(state, params),
+        "getblockheader" => get_block_header(state, params).await,
+        _ => Err(RpcError::new(RPC_METHOD_NOT_FOUND, "Method not found")),
+    };
+    let status = match &result {
+        Ok(_) => 200,
+        Err(e) if e.code == RPC_METHOD_NOT_FOUND => 404,
+        Err(_) => 500,
+    };
+    (status, make_response(id, result))
+}
+
+fn make_response(id: Value, result: RpcResult) -> Value {
+    match result {
+        Ok(v) => json!({"result": v, "error": null, "id": id}),
+        Err(e) => json!({"result": null, "error": {"code": e.code, "message": e.message}, "id": id}),
+    }
+}
+
+// getblock "blockhash" ( verbosity )
+async fn get_block<A: BlockArchive>(state: &RpcState<A>, params: &[Value]) -> RpcResult {
+    let block_hash = hash_param(params, 0)?;
+    let verbosity = match params.get(1) {
+        None | Some(Value::Null) => 1,
+        Some(Value::Bool(b)) => *b as u64,
+        Some(v) => v.as_u64().ok_or_else(|| RpcError::new(RPC_INVALID_PARAMETER, "verbosity must be a number"))?,
+    };
+    if verbosity > 1 {
+        return Err(RpcError::new(RPC_INVALID_PARAMETER, "Verbosity greater than 1 is not supported"));
+    }
+    let mut block = Vec::new();
+    state.archive.get_block(&block_hash).await?.read_to_end(&mut block).await.map_err(Error::from)?;
+    if verbosity == 0 {
+        return Ok(json!(hex::encode(&block)));
+    }
+    if block.len() < HEADER_SIZE as usize {
+        return Err(Error::CorruptBlock(block_hash).into());
+    }
+    let txids: Vec<String> = scan_transactions(&mut &block[..]).await?.iter().map(|(txid, _, _)| txid.to_string()).collect();
+    let mut result = header_json(state, &block_hash, &block[..HEADER_SIZE as usize]);
+    result["size"] = json!(block.len());
+    result["nTx"] = json!(txids.len());
+    result["tx"] = json!(txids);
+    Ok(result)
+}
+
+// getblockcount
+fn get_block_count<A>(state: &RpcState<A>) -> RpcResult {
+    match state.chain.tip() {
+        Some((_, height)) => Ok(json!(height)),
+        None => Err(RpcError::new(RPC_MISC_ERROR, "The archive does not contain a chain")),
+    }
+}
+
+// getblockhash height
+fn get_block_hash<A>(state: &RpcState<A>, params: &[Value]) -> RpcResult {
+    let height = params.first().and_then(Value::as_u64)
+        .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMETER, "height must be a number"))?;
+    match state.chain.block_by_height(height) {
+        Some(block_hash) => Ok(json!(block_hash.to_string())),
+        None => Err(RpcError::new(RPC_INVALID_PARAMETER, "Block height out of range")),
+    }
+}
+
+// getblockheader "blockhash" ( verbose )
+async fn get_block_header<A: BlockArchive>(state: &RpcState<A>, params: &[Value]) -> RpcResult {
+    let block_hash = hash_param(params, 0)?;
+    let verbose = match params.get(1) {
+        None | Some(Value::Null) => true,
+        Some(v) => v.as_bool().ok_or_else(|| RpcError::new(RPC_INVALID_PARAMETER, "verbose must be a boolean"))?,
+    };
+    let mut header = Vec::new();
+    state.archive.get_block(&block_hash).await?.take(HEADER_SIZE).read_to_end(&mut header).await.map_err(Error::from)?;
+    if header.len() < HEADER_SIZE as usize {
+        return Err(Error::CorruptBlock(block_hash).into());
+    }
+    if verbose {
+        Ok(header_json(state, &block_hash, &header))
+    } else {
+        Ok(json!(hex::encode(&header)))
+    }
+}
+
+// Get a block hash parameter.
+fn hash_param(params: &[Value], i: usize) -> std::result::Result<BlockHash, RpcError> {
+    let s = params.get(i).and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMETER, "blockhash must be a string"))?;
+    BlockHash::from_hex(s).map_err(|_| RpcError::new(RPC_INVALID_PARAMETER, "blockhash must be a hex string of length 64"))
+}
+
+// Describe a block header in the same way as bitcoind.
+fn header_json<A>(state: &RpcState<A>, block_hash: &BlockHash, header: &[u8]) -> Value {
+    let field = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    let mut result = json!({
+        "hash": block_hash.to_string(),
+        "version": field(0),
+        "versionHex": format!("{:08x}", field(0)),
+        "merkleroot": display_hash(&header[36..68]),
+        "time": field(68),
+        "nonce": field(76),
+        "bits": format!("{:08x}", field(72)),
+        "confirmations": -1,
+    });
+    if header[4..36] != [0u8; 32] {
+        result["previousblockhash"] = json!(display_hash(&header[4..36]));
+    }
+    if let Some(height) = state.chain.height_of(block_hash) {
+        result["height"] = json!(height);
+        if state.chain.is_in_best_chain(block_hash) {
+            if let Some((_, tip_height)) = state.chain.tip() {
+                result["confirmations"] = json!(tip_height - height + 1);
+            }
+            if let Some(next) = state.chain.block_by_height(height + 1) {
+                result["nextblockhash"] = json!(next.to_string());
+            }
+        }
+    }
+    result
+}
+
+// Hashes are displayed in the reverse of their encoded order.
+fn display_hash(encoded: &[u8]) -> String {
+    let mut b = encoded.to_vec();
+    b.reverse();
+    hex::encode(b)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use tokio::io::AsyncWriteExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    const HASH: &str = "00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f";
+    const GENESIS: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+    const BLOCK1: &str = "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048";
+
+    // Send a request body and return the http status and the response.
+    async fn post(addr: SocketAddr, body: &str) -> (String, Value) {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let request = format!("POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().to_string();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_rpc() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(archive, listener));
+
+        let (status, r) = post(addr, &format!(r#"{{"method": "getblockheader", "params": ["{}"], "id": 1}}"#, HASH)).await;
+        assert_eq!(status, "200");
+        assert_eq!(r["id"], json!(1));
+        assert_eq!(r["result"]["hash"], json!(HASH));
+        assert_eq!(r["result"]["version"], json!(2));
+        assert_eq!(r["result"]["previousblockhash"], json!("0000000000000135aeabf9666fc9f1d5b8573685db070a5f1dfdd78f728a167a"));
+        assert_eq!(r["result"]["merkleroot"], json!("949904a56c861ecde4b43c9fc4ad612b82d10e38bdd164ea820b8cd0e6a39178"));
+        // the block does not link to the genesis block of the test archive
+        assert_eq!(r["result"]["confirmations"], json!(-1));
+
+        let (_, r) = post(addr, &format!(r#"{{"method": "getblock", "params": ["{}", 1], "id": 2}}"#, HASH)).await;
+        assert_eq!(r["result"]["nTx"].as_u64().unwrap() as usize, r["result"]["tx"].as_array().unwrap().len());
+        assert!(r["result"]["size"].as_u64().unwrap() > HEADER_SIZE);
+
+        let (_, header) = post(addr, &format!(r#"{{"method": "getblockheader", "params": ["{}", false], "id": 3}}"#, HASH)).await;
+        let (_, block) = post(addr, &format!(r#"{{"method": "getblock", "params": ["{}", 0], "id": 4}}"#, HASH)).await;
+        assert!(block["result"].as_str().unwrap().starts_with(header["result"].as_str().unwrap()));
+
+        // the best chain of the test archive is the genesis block and block 1
+        let (status, r) = post(addr, r#"{"method": "getblockhash", "params": [0], "id": 5}"#).await;
+        assert_eq!(status, "200");
+        assert_eq!(r["result"], json!(GENESIS));
+        let (_, r) = post(addr, &format!(r#"{{"method": "getblockheader", "params": ["{}"], "id": 5}}"#, GENESIS)).await;
+        assert_eq!(r["result"]["height"], json!(0));
+        assert_eq!(r["result"]["confirmations"], json!(2));
+        assert_eq!(r["result"]["nextblockhash"], json!(BLOCK1));
+        let (status, r) = post(addr, r#"{"method": "getblockhash", "params": [2], "id": 5}"#).await;
+        assert_eq!(status, "500");
+        assert_eq!(r["error"]["code"], json!(RPC_INVALID_PARAMETER));
+
+        let (status, r) = post(addr, r#"{"method": "getblockheader", "params": ["00000000000000000000000000000000000000000000000000000000000000ff"]}"#).await;
+        assert_eq!(status, "500");
+        assert_eq!(r["error"]["code"], json!(RPC_INVALID_ADDRESS_OR_KEY));
+
+        let (status, r) = post(addr, r#"{"method": "sendrawtransaction", "params": [], "id": 6}"#).await;
+        assert_eq!(status, "404");
+        assert_eq!(r["error"]["code"], json!(RPC_METHOD_NOT_FOUND));
+
+        let (status, r) = post(addr, r#"[{"method": "getblockcount", "id": 7}, {"method": "getblockhash", "params": [0], "id": 8}]"#).await;
+        assert_eq!(status, "200");
+        assert_eq!(r.as_array().unwrap().len(), 2);
+        assert_eq!(r[0]["result"], json!(1));
+        assert_eq!(r[1]["id"], json!(8));
+
+        let (status, r) = post(addr, "not json").await;
+        assert_eq!(status, "500");
+        assert_eq!(r["error"]["code"], json!(RPC_PARSE_ERROR));
+    }
+}
@@ -0,0 +1,386 @@
+//! The async runtime used to spawn tasks, access files and pass messages.
+//!
+//! Code that is generic over a [Runtime] does not depend on the tokio executor, so a library
+//! that is already committed to another executor can embed it without running a second one.
+//! [TokioRuntime] is the default. With the `async-std` feature [AsyncStdRuntime] runs on the
+//! async-std executor and with the `smol` feature [SmolRuntime] runs on the smol executor.
+//!
+//! The readers, writers and channels are the same for every runtime: files are wrapped in the
+//! tokio IO traits, which do not need the tokio executor, and channels are the futures channels.
+//! [crate::CandidateStore] is generic over the runtime, the archives themselves still use tokio
+//! for their files.
+//!
+//! Example code:
+//!     let candidates = CandidateStore::<SmolRuntime>::with_runtime(path).await?;
+//!     let handle = SmolRuntime::spawn(async move { candidates.list().await });
+//!     let list = handle.await??;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::{Error, Result};
+
+pub use futures::channel::mpsc::{Receiver, Sender};
+
+/// The handle of a task spawned with [Runtime::spawn] or [Runtime::spawn_blocking].
+///
+/// The handle resolves to the output of the task, or an error if the task panicked. Dropping the
+/// handle does not stop the task.
+pub struct JoinHandle<T> {
+    future: BoxFuture<'static, Result<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    fn new(future: impl Future<Output = Result<T>> + Send + 'static) -> JoinHandle<T> {
+        JoinHandle { future: Box::pin(future) }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// The operations of an async runtime that the library needs.
+#[async_trait]
+pub trait Runtime: Send + Sync + 'static {
+    /// Spawn a future onto the executor of the runtime.
+    fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static;
+
+    /// Run a blocking function on a thread where blocking is allowed.
+    fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+
+    /// Create a bounded channel.
+    fn channel<T: Send>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+        futures::channel::mpsc::channel(capacity)
+    }
+
+    /// Open a file for reading.
+    async fn open(path: &Path) -> std::io::Result<Box<dyn AsyncRead + Unpin + Send>>;
+
+    /// Create a file for writing, truncating it if it exists.
+    async fn create(path: &Path) -> std::io::Result<Box<dyn AsyncWrite + Unpin + Send>>;
+
+    /// Create a directory and any missing parents.
+    async fn create_dir_all(path: &Path) -> std::io::Result<()>;
+
+    /// Remove a file.
+    async fn remove_file(path: &Path) -> std::io::Result<()>;
+
+    /// Rename a file, replacing the destination if it exists.
+    async fn rename(from: &Path, to: &Path) -> std::io::Result<()>;
+
+    /// Get the metadata of a file or directory.
+    async fn metadata(path: &Path) -> std::io::Result<std::fs::Metadata>;
+
+    /// List the paths of the entries in a directory.
+    async fn read_dir(path: &Path) -> std::io::Result<Vec<PathBuf>>;
+
+    /// Check if a file or directory exists.
+    async fn try_exists(path: &Path) -> std::io::Result<bool> {
+        match Self::metadata(path).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The tokio runtime, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+#[async_trait]
+impl Runtime for TokioRuntime {
+    fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let handle = tokio::spawn(future);
+        JoinHandle::new(async move { handle.await.map_err(Error::TaskFailed) })
+    }
+
+    fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let handle = tokio::task::spawn_blocking(f);
+        JoinHandle::new(async move { handle.await.map_err(Error::TaskFailed) })
+    }
+
+    async fn open(path: &Path) -> std::io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        Ok(Box::new(tokio::fs::File::open(path).await?))
+    }
+
+    async fn create(path: &Path) -> std::io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        Ok(Box::new(tokio::fs::File::create(path).await?))
+    }
+
+    async fn create_dir_all(path: &Path) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(path).await
+    }
+
+    async fn remove_file(path: &Path) -> std::io::Result<()> {
+        tokio::fs::remove_file(path).await
+    }
+
+    async fn rename(from: &Path, to: &Path) -> std::io::Result<()> {
+        tokio::fs::rename(from, to).await
+    }
+
+    async fn metadata(path: &Path) -> std::io::Result<std::fs::Metadata> {
+        tokio::fs::metadata(path).await
+    }
+
+    async fn read_dir(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let mut dir = tokio::fs::read_dir(path).await?;
+        let mut paths = Vec::new();
+        while let Some(entry) = dir.next_entry().await? {
+            paths.push(entry.path());
+        }
+        Ok(paths)
+    }
+}
+
+// Wrap a future so that its output, or the message of its panic, is sent to a handle. The
+// executors of async-std and smol do not report a panic to the handle of the task.
+#[cfg(any(feature = "async-std", feature = "smol"))]
+fn watch<F>(future: F) -> (impl Future<Output = ()> + Send + 'static, JoinHandle<F::Output>)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    use futures::FutureExt;
+    let (tx, rx) = futures::channel::oneshot::channel();
+    let task = async move {
+        let _ = tx.send(std::panic::AssertUnwindSafe(future).catch_unwind().await);
+    };
+    let handle = JoinHandle::new(async move {
+        match rx.await {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(panic)) => {
+                let msg = panic.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                Err(Error::TaskPanicked(msg))
+            }
+            Err(_) => Err(Error::TaskPanicked("the task was dropped".to_string())),
+        }
+    });
+    (task, handle)
+}
+
+/// The async-std runtime.
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdRuntime;
+
+#[cfg(feature = "async-std")]
+#[async_trait]
+impl Runtime for AsyncStdRuntime {
+    fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = watch(future);
+        async_std::task::spawn(task);
+        handle
+    }
+
+    fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::spawn(async_std::task::spawn_blocking(f))
+    }
+
+    async fn open(path: &Path) -> std::io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        use tokio_util::compat::FuturesAsyncReadCompatExt;
+        Ok(Box::new(async_std::fs::File::open(path).await?.compat()))
+    }
+
+    async fn create(path: &Path) -> std::io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        use tokio_util::compat::FuturesAsyncWriteCompatExt;
+        Ok(Box::new(async_std::fs::File::create(path).await?.compat_write()))
+    }
+
+    async fn create_dir_all(path: &Path) -> std::io::Result<()> {
+        async_std::fs::create_dir_all(path).await
+    }
+
+    async fn remove_file(path: &Path) -> std::io::Result<()> {
+        async_std::fs::remove_file(path).await
+    }
+
+    async fn rename(from: &Path, to: &Path) -> std::io::Result<()> {
+        async_std::fs::rename(from, to).await
+    }
+
+    async fn metadata(path: &Path) -> std::io::Result<std::fs::Metadata> {
+        async_std::fs::metadata(path).await
+    }
+
+    async fn read_dir(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        use futures::TryStreamExt;
+        let mut dir = async_std::fs::read_dir(path).await?;
+        let mut paths = Vec::new();
+        while let Some(entry) = dir.try_next().await? {
+            paths.push(entry.path().into());
+        }
+        Ok(paths)
+    }
+}
+
+/// The smol runtime.
+#[cfg(feature = "smol")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmolRuntime;
+
+#[cfg(feature = "smol")]
+#[async_trait]
+impl Runtime for SmolRuntime {
+    fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = watch(future);
+        // a smol task is cancelled when it is dropped
+        smol::spawn(task).detach();
+        handle
+    }
+
+    fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::spawn(smol::unblock(f))
+    }
+
+    async fn open(path: &Path) -> std::io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        use tokio_util::compat::FuturesAsyncReadCompatExt;
+        Ok(Box::new(smol::fs::File::open(path).await?.compat()))
+    }
+
+    async fn create(path: &Path) -> std::io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        use tokio_util::compat::FuturesAsyncWriteCompatExt;
+        Ok(Box::new(smol::fs::File::create(path).await?.compat_write()))
+    }
+
+    async fn create_dir_all(path: &Path) -> std::io::Result<()> {
+        smol::fs::create_dir_all(path).await
+    }
+
+    async fn remove_file(path: &Path) -> std::io::Result<()> {
+        smol::fs::remove_file(path).await
+    }
+
+    async fn rename(from: &Path, to: &Path) -> std::io::Result<()> {
+        smol::fs::rename(from, to).await
+    }
+
+    async fn metadata(path: &Path) -> std::io::Result<std::fs::Metadata> {
+        smol::fs::metadata(path).await
+    }
+
+    async fn read_dir(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        use futures::TryStreamExt;
+        let mut dir = smol::fs::read_dir(path).await?;
+        let mut paths = Vec::new();
+        while let Some(entry) = dir.try_next().await? {
+            paths.push(entry.path());
+        }
+        Ok(paths)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use mktemp::Temp;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use super::*;
+
+    // Spawn tasks, write, list, rename and remove a file, and pass messages, with a runtime.
+    async fn exercise<R: Runtime>() {
+        assert_eq!(R::spawn(async { 1 + 1 }).await.unwrap(), 2);
+        assert_eq!(R::spawn_blocking(|| 3).await.unwrap(), 3);
+
+        let root = Temp::new_dir().unwrap();
+        let dir = root.to_path_buf().join("a").join("b");
+        R::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("file.bin");
+        let mut file = R::create(&path).await.unwrap();
+        file.write_all(b"block").await.unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+        assert!(R::try_exists(&path).await.unwrap());
+        assert_eq!(R::metadata(&path).await.unwrap().len(), 5);
+        let renamed = dir.join("renamed.bin");
+        R::rename(&path, &renamed).await.unwrap();
+        assert_eq!(R::read_dir(&dir).await.unwrap(), vec![renamed.clone()]);
+        let mut buf = Vec::new();
+        R::open(&renamed).await.unwrap().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"block");
+        R::remove_file(&renamed).await.unwrap();
+        assert!(!R::try_exists(&renamed).await.unwrap());
+        assert_eq!(R::open(&renamed).await.err().unwrap().kind(), std::io::ErrorKind::NotFound);
+
+        let (mut tx, mut rx) = R::channel(1);
+        let sender = R::spawn(async move {
+            for i in 0..3 {
+                tx.send(i).await.unwrap();
+            }
+        });
+        assert_eq!(rx.by_ref().collect::<Vec<_>>().await, vec![0, 1, 2]);
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tokio() {
+        exercise::<TokioRuntime>().await;
+        assert!(matches!(TokioRuntime::spawn(async { panic!("failed") }).await, Err(Error::TaskFailed(_))));
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_async_std() {
+        async_std::task::block_on(async {
+            exercise::<AsyncStdRuntime>().await;
+            match AsyncStdRuntime::spawn(async { panic!("failed") }).await {
+                Err(Error::TaskPanicked(msg)) => assert_eq!(msg, "failed"),
+                _ => panic!("expected a panicked task"),
+            }
+        });
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn test_smol() {
+        smol::block_on(async {
+            exercise::<SmolRuntime>().await;
+            match SmolRuntime::spawn(async { panic!("failed") }).await {
+                Err(Error::TaskPanicked(msg)) => assert_eq!(msg, "failed"),
+                _ => panic!("expected a panicked task"),
+            }
+        });
+    }
+}
@@ -0,0 +1,1036 @@
+use std::collections::{BTreeSet, HashMap};
+use std::pin::Pin;
+use async_compression::tokio::bufread::GzipDecoder;
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::Client;
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use futures::{StreamExt as _, TryStreamExt};
+use hex::{FromHex, ToHex};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use crate::{BlockArchive, BlockAttrs, Error, Result, WritePolicy};
+use crate::chunks::{check_chunk_size, may_be_first_chunk, part_suffix, ChainedReader, ChunkManifest, OpenChunk, MANIFEST_EXTENSION};
+use crate::block_archive::{check_block_checksum, check_blocks_exist, checked_block, decode_block_attrs, encode_block_attrs, validate_block_attr, BlockHashListStream, BlockHashListStreamFromChannel, ChecksumReader};
+
+// the size of the parts of a multipart upload, blocks smaller than this are uploaded in one request
+// S3 requires every part except the last to be at least 5MiB
+// the chunk size is a multiple of this, so the first part of a block always fits in the first chunk
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+// the size of an encoded block header
+const HEADER_SIZE: usize = 80;
+
+// the size of the channel used to send block hashes, the listing waits for the channel to be read
+// when it is full
+const LIST_BUFFER: usize = 1000;
+
+// the number of headers that are fetched at the same time by block_headers()
+const HEADER_CONCURRENCY: usize = 32;
+
+// the number of blocks that blocks_exist() checks at the same time
+const EXISTS_CONCURRENCY: usize = 32;
+
+// the number of blocks from which blocks_exist() looks the blocks up in the listing of a list
+// source, rather than checking each block with a request
+const LISTING_EXISTS_MIN: usize = 100_000;
+
+/// Where the list of the blocks in an [S3BlockArchive] is read from.
+///
+/// Listing the objects in a bucket takes a request for every thousand objects, which is slow and
+/// costly for a large archive. A listing made in advance can be read instead, at the cost of
+/// missing the blocks stored since it was made and including the blocks removed since.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum S3ListSource {
+    /// List the objects under the prefix of the archive.
+    #[default]
+    Objects,
+    /// Read the most recent report of an S3 Inventory configuration of the bucket. The prefix is
+    /// where the reports are delivered, the destination prefix followed by the source bucket and
+    /// the configuration id, or the key of the manifest.json of a particular report. Only reports
+    /// in CSV format can be read.
+    Inventory { bucket: String, prefix: String },
+    /// Read a listing file, an object holding the hashes of the blocks one per line, such as the
+    /// output of "blockarchive list". A key ending in ".gz" is read as gzip compressed.
+    ListingFile { bucket: String, key: String },
+}
+
+/// A block archive stored in an S3-compatible object store, such as AWS S3, MinIO, or Wasabi.
+///
+/// Blocks are stored as objects under a prefix in a bucket, using the same layout as the
+/// [crate::SimpleFileBasedBlockArchive], so an archive can be copied to or from a bucket with
+/// standard tools.
+///
+/// Example: prefix/31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin
+///
+/// Blocks that are larger than the store accepts in one object can be stored in chunks, see
+/// [S3BlockArchive::with_chunk_size], and a failed upload of a large block can be continued
+/// rather than started again, see [S3BlockArchive::with_resumable_uploads].
+///
+/// Credentials and region are taken from the usual AWS environment variables and configuration
+/// files.
+///
+/// Example code:
+///     let archive = S3BlockArchive::new("my-bucket", "mainnet", None).await?;
+#[derive(Debug)]
+pub struct S3BlockArchive {
+    /// The client used to access the store.
+    client: Client,
+    /// The bucket in which the blocks are stored.
+    pub bucket: String,
+    /// The prefix of the block objects, may be empty.
+    pub prefix: String,
+    // the checks made on blocks before they are stored
+    write_policy: WritePolicy,
+    // whether store_block() replaces an existing block
+    overwrite: bool,
+    // blocks larger than this are stored in chunks
+    chunk_size: Option<u64>,
+    // whether failed multipart uploads are kept to be continued
+    resumable: bool,
+    // where block_list() reads the blocks from
+    list_source: S3ListSource,
+}
+
+impl S3BlockArchive {
+    /// Connect to a block archive in a bucket.
+    ///
+    /// The endpoint is needed for stores other than AWS, for example "http://localhost:9000" for
+    /// a local MinIO server. Fails with [Error::StorageUnavailable] if the bucket can not be reached.
+    pub async fn new(bucket: &str, prefix: &str, endpoint: Option<&str>) -> Result<S3BlockArchive> {
+        let sdk_config = aws_config::defaults(BehaviorVersion::latest()).load().await;
+        let mut config = aws_sdk_s3::config::Builder::from(&sdk_config);
+        if let Some(endpoint) = endpoint {
+            // most S3-compatible stores do not support virtual-hosted style bucket names
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+        Self::with_client(Client::from_conf(config.build()), bucket, prefix).await
+    }
+
+    /// Create a block archive using an already configured client.
+    pub async fn with_client(client: Client, bucket: &str, prefix: &str) -> Result<S3BlockArchive> {
+        client.head_bucket().bucket(bucket).send().await
+            .map_err(|e| Error::StorageUnavailable(format!("bucket {}: {}", bucket, DisplayErrorContext(&e))))?;
+        Ok(S3BlockArchive {
+            client,
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+            write_policy: WritePolicy::default(),
+            overwrite: false,
+            chunk_size: None,
+            resumable: false,
+            list_source: S3ListSource::Objects,
+        })
+    }
+
+    /// Set the checks that are made on blocks before they are stored, the default is to make no
+    /// checks.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> S3BlockArchive {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Set whether storing a block that is already in the archive replaces it, instead of
+    /// failing with [Error::BlockExists].
+    pub fn with_overwrite(mut self, overwrite: bool) -> S3BlockArchive {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Store blocks that are larger than the chunk size in chunks, see [crate::chunks], for stores
+    /// that limit the size of an object. By default blocks are never split.
+    ///
+    /// Fails with [Error::InvalidChunkManifest] if the chunk size is not a multiple of
+    /// [crate::chunks::CHUNK_ALIGN], see [check_chunk_size].
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> Result<S3BlockArchive> {
+        check_chunk_size(chunk_size)?;
+        self.chunk_size = Some(chunk_size);
+        Ok(self)
+    }
+
+    /// Set whether a multipart upload that fails is kept, so that storing the block again
+    /// continues it without uploading the parts it already has, instead of aborting it. The
+    /// default is false. The parts of a kept upload are charged for until it is continued or
+    /// aborted, see [S3BlockArchive::abort_incomplete_uploads].
+    pub fn with_resumable_uploads(mut self, resumable: bool) -> S3BlockArchive {
+        self.resumable = resumable;
+        self
+    }
+
+    /// Read the list of blocks from a listing made in advance, such as an S3 Inventory report,
+    /// rather than listing the objects in the bucket, see [S3ListSource]. The listing is also
+    /// used by [BlockArchive::blocks_exist] for large numbers of blocks.
+    pub fn with_list_source(mut self, source: S3ListSource) -> S3BlockArchive {
+        self.list_source = source;
+        self
+    }
+
+    /// Abort the unfinished multipart uploads under the prefix of the archive, such as those
+    /// kept by failed uploads when uploads are resumable. Returns the number of uploads aborted.
+    ///
+    /// This must not be run while another process is storing blocks in the archive, because it
+    /// would abort the uploads in progress.
+    pub async fn abort_incomplete_uploads(&self) -> Result<usize> {
+        let list_prefix = if self.prefix.is_empty() { self.prefix.clone() } else { format!("{}/", self.prefix) };
+        let mut count = 0;
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+        loop {
+            let page = self.client.list_multipart_uploads().bucket(&self.bucket).prefix(&list_prefix)
+                .set_key_marker(key_marker).set_upload_id_marker(upload_id_marker)
+                .send().await.map_err(s3_error)?;
+            for upload in page.uploads() {
+                if let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) {
+                    self.client.abort_multipart_upload().bucket(&self.bucket).key(key)
+                        .upload_id(upload_id).send().await.map_err(s3_error)?;
+                    count += 1;
+                }
+            }
+            if !page.is_truncated().unwrap_or(false) {
+                break;
+            }
+            key_marker = page.next_key_marker().map(String::from);
+            upload_id_marker = page.next_upload_id_marker().map(String::from);
+        }
+        Ok(count)
+    }
+
+    // Upload an object in parts, the first part has already been read into buf. Returns the size
+    // of the object.
+    async fn multipart_upload(&self, key: &str, block: &mut (dyn AsyncRead + Unpin + Send), buf: Vec<u8>) -> Result<u64> {
+        let (upload_id, parts, size) = self.upload_parts(key, block, buf).await?;
+        self.complete_upload(key, &upload_id, parts).await?;
+        Ok(size)
+    }
+
+    // Upload the parts of an object, the first part has already been read into buf. Returns the
+    // id of the upload, which is left for the caller to complete, the parts and the size of the
+    // object. With resumable uploads an unfinished upload of the object is continued, and the
+    // parts it already has are not uploaded again. A part with the same number and size as one
+    // already uploaded has the same contents, because the object is named after the block hash.
+    async fn upload_parts(&self, key: &str, block: &mut (dyn AsyncRead + Unpin + Send), mut buf: Vec<u8>)
+                          -> Result<(String, Vec<CompletedPart>, u64)> {
+        let (upload_id, uploaded) = match self.find_upload(key).await? {
+            Some(upload) => upload,
+            None => {
+                let upload = self.client.create_multipart_upload().bucket(&self.bucket).key(key).send().await
+                    .map_err(s3_error)?;
+                (upload.upload_id().unwrap_or_default().to_string(), HashMap::new())
+            }
+        };
+        let mut parts = Vec::new();
+        let mut part_number = 1;
+        let mut size = 0;
+        let r = loop {
+            if buf.is_empty() {
+                break Ok(());
+            }
+            size += buf.len() as u64;
+            match uploaded.get(&part_number) {
+                Some((part_size, e_tag)) if *part_size == buf.len() as i64 => {
+                    parts.push(CompletedPart::builder().e_tag(e_tag).part_number(part_number).build());
+                }
+                _ => {
+                    let part = self.client.upload_part().bucket(&self.bucket).key(key)
+                        .upload_id(&upload_id).part_number(part_number)
+                        .body(ByteStream::from(buf)).send().await;
+                    match part {
+                        Ok(p) => parts.push(CompletedPart::builder()
+                            .set_e_tag(p.e_tag().map(String::from)).part_number(part_number).build()),
+                        Err(e) => break Err(s3_error(e)),
+                    }
+                }
+            }
+            part_number += 1;
+            buf = match read_part(block).await {
+                Ok(b) => b,
+                Err(e) => break Err(e),
+            };
+        };
+        match r {
+            Ok(_) => Ok((upload_id, parts, size)),
+            Err(e) => {
+                self.abandon_upload(key, &upload_id).await;
+                Err(e)
+            }
+        }
+    }
+
+    // Complete a multipart upload.
+    async fn complete_upload(&self, key: &str, upload_id: &str, parts: Vec<CompletedPart>) -> Result<()> {
+        let r = self.client.complete_multipart_upload().bucket(&self.bucket).key(key)
+            .upload_id(upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send().await.map(|_| ()).map_err(s3_error);
+        if r.is_err() {
+            self.abandon_upload(key, upload_id).await;
+        }
+        r
+    }
+
+    // Give up on a multipart upload that failed. It is aborted unless uploads are resumable, so
+    // that the parts are not left lying around, they are charged for.
+    async fn abandon_upload(&self, key: &str, upload_id: &str) {
+        if !self.resumable {
+            let _ = self.client.abort_multipart_upload().bucket(&self.bucket).key(key)
+                .upload_id(upload_id).send().await;
+        }
+    }
+
+    // Find an unfinished upload of an object to continue if uploads are resumable, returning its
+    // id and the size and entity tag of each part that it has.
+    async fn find_upload(&self, key: &str) -> Result<Option<(String, HashMap<i32, (i64, String)>)>> {
+        if !self.resumable {
+            return Ok(None);
+        }
+        let uploads = self.client.list_multipart_uploads().bucket(&self.bucket).prefix(key).send().await
+            .map_err(s3_error)?;
+        // continue the most recent upload of the object
+        let upload_id = uploads.uploads().iter()
+            .filter(|u| u.key() == Some(key))
+            .max_by_key(|u| u.initiated().map(|t| (t.secs(), t.subsec_nanos())))
+            .and_then(|u| u.upload_id());
+        let upload_id = match upload_id {
+            Some(id) => id.to_string(),
+            None => return Ok(None),
+        };
+        let mut parts = HashMap::new();
+        let mut pages = self.client.list_parts().bucket(&self.bucket).key(key).upload_id(&upload_id).into_paginator().send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(s3_error)?;
+            for part in page.parts() {
+                if let (Some(number), Some(size), Some(e_tag)) = (part.part_number(), part.size(), part.e_tag()) {
+                    parts.insert(number, (size, e_tag.to_string()));
+                }
+            }
+        }
+        Ok(Some((upload_id, parts)))
+    }
+
+    // Upload a block that may be larger than the chunk size, the first part has already been read
+    // into buf. The first chunk is uploaded to the block object, which is only completed once the
+    // other chunks and the manifest are stored, so an interrupted upload never leaves part of a
+    // block. Returns the manifest if the block was stored in chunks.
+    async fn chunked_upload(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send), buf: Vec<u8>,
+                            chunk_size: u64) -> Result<Option<ChunkManifest>> {
+        let key = block_key(&self.prefix, block_hash);
+        let first_size = chunk_size - buf.len() as u64;
+        let (upload_id, parts, _) = self.upload_parts(&key, &mut (&mut *block).take(first_size), buf).await?;
+        let mut probe = [0u8; 1];
+        let more = match block.read(&mut probe).await {
+            Ok(n) => n,
+            Err(e) => {
+                self.abandon_upload(&key, &upload_id).await;
+                return Err(e.into());
+            }
+        };
+        if more == 0 {
+            self.complete_upload(&key, &upload_id, parts).await?;
+            return Ok(None);
+        }
+        let mut rest = (&probe[..]).chain(block);
+        match self.upload_chunks(block_hash, &mut rest, chunk_size).await {
+            Ok(manifest) => {
+                self.complete_upload(&key, &upload_id, parts).await?;
+                Ok(Some(manifest))
+            }
+            Err(e) => {
+                self.abandon_upload(&key, &upload_id).await;
+                Err(e)
+            }
+        }
+    }
+
+    // Upload the chunks of a block after the first, which is a full chunk, followed by the
+    // manifest. With resumable uploads the full chunks stored by an earlier attempt are not
+    // uploaded again.
+    async fn upload_chunks(&self, block_hash: &BlockHash, rest: &mut (dyn AsyncRead + Unpin + Send),
+                           chunk_size: u64) -> Result<ChunkManifest> {
+        let mut manifest = ChunkManifest { sizes: vec![chunk_size] };
+        loop {
+            let key = part_key(&self.prefix, block_hash, manifest.sizes.len());
+            let mut chunk = (&mut *rest).take(chunk_size);
+            let buf = read_part(&mut chunk).await?;
+            if buf.is_empty() {
+                // the block was a multiple of the chunk size
+                break;
+            }
+            let size = if buf.len() < PART_SIZE {
+                let size = buf.len() as u64;
+                self.client.put_object().bucket(&self.bucket).key(&key)
+                    .body(ByteStream::from(buf)).send().await.map_err(s3_error)?;
+                size
+            } else if self.resumable && self.object_size(&key).await? == Some(chunk_size) {
+                let size = buf.len() as u64 + tokio::io::copy(&mut chunk, &mut tokio::io::sink()).await?;
+                if size != chunk_size {
+                    return Err(Error::InvalidChunkManifest(format!("{} does not match the block", key)));
+                }
+                size
+            } else {
+                self.multipart_upload(&key, &mut chunk, buf).await?
+            };
+            manifest.sizes.push(size);
+            if size < chunk_size {
+                break;
+            }
+        }
+        self.client.put_object().bucket(&self.bucket).key(manifest_key(&self.prefix, block_hash))
+            .body(ByteStream::from(manifest.encode().into_bytes())).send().await.map_err(s3_error)?;
+        Ok(manifest)
+    }
+
+    // Get the size of an object, or None if it does not exist.
+    async fn object_size(&self, key: &str) -> Result<Option<u64>> {
+        match self.client.head_object().bucket(&self.bucket).key(key).send().await {
+            Ok(o) => Ok(Some(o.content_length().unwrap_or_default() as u64)),
+            Err(e) => match e.into_service_error() {
+                e if e.is_not_found() => Ok(None),
+                e => Err(s3_error(e)),
+            }
+        }
+    }
+
+    // Read the chunk manifest of a block whose block object has the given size, or None if the
+    // block is not stored in chunks.
+    async fn chunk_manifest(&self, block_hash: &BlockHash, object_size: u64) -> Result<Option<ChunkManifest>> {
+        if !may_be_first_chunk(object_size) {
+            return Ok(None);
+        }
+        match self.client.get_object().bucket(&self.bucket).key(manifest_key(&self.prefix, block_hash)).send().await {
+            Ok(o) => {
+                let bytes = o.body.collect().await.map_err(s3_error)?.into_bytes();
+                let manifest = ChunkManifest::decode(&String::from_utf8_lossy(&bytes))?;
+                // a manifest left behind by a block that was replaced does not match the block object
+                Ok(Some(manifest).filter(|m| m.sizes[0] == object_size))
+            }
+            Err(e) => match e.into_service_error() {
+                e if e.is_no_such_key() => Ok(None),
+                e => Err(s3_error(e)),
+            }
+        }
+    }
+
+    // Open a chunk of a block other than the first, from the given offset in the chunk.
+    fn open_chunk(&self, block_hash: &BlockHash, index: usize, offset: u64) -> OpenChunk {
+        let mut request = self.client.get_object().bucket(&self.bucket).key(part_key(&self.prefix, block_hash, index));
+        if offset > 0 {
+            request = request.range(format!("bytes={}-", offset));
+        }
+        Box::pin(async move {
+            let o = request.send().await.map_err(s3_error)?;
+            Ok::<_, Error>(Box::new(Box::pin(o.body.into_async_read())) as Box<dyn AsyncRead + Unpin + Send>)
+        })
+    }
+
+    // Read a range of a block that is stored in chunks. The reader of the range of the block
+    // object is given if the range starts in the first chunk.
+    async fn chunked_range(&self, block_hash: &BlockHash, manifest: &ChunkManifest, offset: u64, length: u64,
+                           first: Option<Box<dyn AsyncRead + Unpin + Send>>) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let (first, index) = match first {
+            Some(first) => (first, 0),
+            None => match manifest.locate(offset) {
+                Some((index, chunk_offset)) => (self.open_chunk(block_hash, index, chunk_offset).await?, index),
+                None => return Ok(Box::new(tokio::io::empty())),
+            }
+        };
+        let rest = (index + 1..manifest.sizes.len()).map(|i| self.open_chunk(block_hash, i, 0)).collect();
+        Ok(Box::new(ChainedReader::new(first, rest).take(length)))
+    }
+
+    // Write the attributes of a block, removing the attributes object if there are none.
+    async fn write_block_attrs(&self, block_hash: &BlockHash, attrs: &BlockAttrs) -> Result<()> {
+        let key = attrs_key(&self.prefix, block_hash);
+        if attrs.is_empty() {
+            self.client.delete_object().bucket(&self.bucket).key(key).send().await.map_err(s3_error)?;
+        } else {
+            self.client.put_object().bucket(&self.bucket).key(key)
+                .body(ByteStream::from(encode_block_attrs(attrs).into_bytes()))
+                .send().await.map_err(s3_error)?;
+        }
+        Ok(())
+    }
+
+    // List the blocks from the list source in the background.
+    fn listing(&self) -> BlockHashListStreamFromChannel {
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER);
+        let handle = tokio::spawn(Self::block_list_bgrnd(self.client.clone(), self.bucket.clone(), self.prefix.clone(),
+                                                         self.list_source.clone(), tx));
+        BlockHashListStreamFromChannel::new(rx, handle)
+    }
+
+    // Get a list of all blocks in the background, sending results to the channel.
+    async fn block_list_bgrnd(client: Client, bucket: String, prefix: String, source: S3ListSource,
+                              transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<()> {
+        match source {
+            S3ListSource::Objects => Self::list_objects(client, bucket, prefix, transmit).await,
+            S3ListSource::Inventory { bucket: report_bucket, prefix: report_prefix } =>
+                Self::list_inventory(client, report_bucket, report_prefix, prefix, transmit).await,
+            S3ListSource::ListingFile { bucket: file_bucket, key } => Self::list_listing_file(client, file_bucket, key, transmit).await,
+        }
+    }
+
+    // List the objects under the prefix, sending the blocks to the channel.
+    async fn list_objects(client: Client, bucket: String, prefix: String, transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<()> {
+        let list_prefix = if prefix.is_empty() { prefix.clone() } else { format!("{}/", prefix) };
+        let mut pages = client.list_objects_v2().bucket(&bucket).prefix(list_prefix).into_paginator().send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(s3_error)?;
+            for object in page.contents() {
+                // ignore objects that are not blocks, or are not in the correct location
+                let hash = match object.key().and_then(|k| key_hash(&prefix, k)) {
+                    Some(h) => h,
+                    None => continue,
+                };
+                if transmit.send(hash).await.is_err() {
+                    return Ok(());      // this is not an error, the receiver has merely dropped
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Read the most recent report of an inventory configuration, sending the blocks under the
+    // prefix of the archive to the channel.
+    async fn list_inventory(client: Client, bucket: String, inventory: String, prefix: String,
+                            transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<()> {
+        let manifest_key = match inventory.ends_with("manifest.json") {
+            true => inventory,
+            false => latest_inventory(&client, &bucket, &inventory).await?,
+        };
+        let o = client.get_object().bucket(&bucket).key(&manifest_key).send().await.map_err(s3_error)?;
+        let bytes = o.body.collect().await.map_err(s3_error)?.into_bytes();
+        let manifest = InventoryManifest::parse(&String::from_utf8_lossy(&bytes))?;
+        for file in manifest.files.iter() {
+            let o = client.get_object().bucket(&manifest.bucket).key(file).send().await.map_err(s3_error)?;
+            let mut decoder = GzipDecoder::new(BufReader::new(Box::pin(o.body.into_async_read())));
+            decoder.multiple_members(true);
+            let mut rows = BufReader::new(decoder).lines();
+            while let Some(row) = rows.next_line().await? {
+                let hash = match manifest.block_hash(&prefix, &row) {
+                    Some(h) => h,
+                    None => continue,
+                };
+                if transmit.send(hash).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Read a listing file, sending the blocks to the channel.
+    async fn list_listing_file(client: Client, bucket: String, key: String, transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<()> {
+        let o = client.get_object().bucket(&bucket).key(&key).send().await.map_err(s3_error)?;
+        let body = BufReader::new(Box::pin(o.body.into_async_read()));
+        let reader: Box<dyn AsyncBufRead + Unpin + Send> = match key.ends_with(".gz") {
+            true => Box::new(BufReader::new(GzipDecoder::new(body))),
+            false => Box::new(body),
+        };
+        let mut lines = reader.lines();
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let hash = BlockHash::from_hex(line).map_err(|_| Error::InvalidHash(line.to_string()))?;
+            if transmit.send(hash).await.is_err() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+// The parts of the manifest.json of an S3 Inventory report that are needed to read the report.
+#[derive(Debug, PartialEq, Eq)]
+struct InventoryManifest {
+    // the bucket holding the files of the report
+    bucket: String,
+    // the keys of the files of the report, each a gzip compressed CSV file
+    files: Vec<String>,
+    // the column of the object key
+    key_column: usize,
+    // the columns that mark old versions and delete markers, if versions are listed
+    latest_column: Option<usize>,
+    delete_marker_column: Option<usize>,
+}
+
+impl InventoryManifest {
+    // Parse a manifest.json.
+    fn parse(s: &str) -> Result<InventoryManifest> {
+        let v: serde_json::Value = serde_json::from_str(s)
+            .map_err(|e| Error::InvalidManifest(format!("inventory manifest: {}", e)))?;
+        let format = v["fileFormat"].as_str().unwrap_or_default();
+        if format != "CSV" {
+            return Err(Error::InvalidManifest(format!("inventory reports in {} format can not be read, only CSV", format)));
+        }
+        // the destination is given as the ARN of the bucket
+        let bucket = v["destinationBucket"].as_str()
+            .map(|b| b.trim_start_matches("arn:aws:s3:::").to_string())
+            .ok_or_else(|| Error::InvalidManifest("inventory manifest has no destinationBucket".to_string()))?;
+        let schema: Vec<&str> = v["fileSchema"].as_str().unwrap_or_default().split(',').map(str::trim).collect();
+        let column = |name: &str| schema.iter().position(|c| *c == name);
+        let key_column = column("Key")
+            .ok_or_else(|| Error::InvalidManifest("inventory reports without the Key field can not be read".to_string()))?;
+        let files = v["files"].as_array()
+            .ok_or_else(|| Error::InvalidManifest("inventory manifest has no files".to_string()))?
+            .iter().filter_map(|f| f["key"].as_str().map(String::from)).collect();
+        Ok(InventoryManifest { bucket, files, key_column, latest_column: column("IsLatest"), delete_marker_column: column("IsDeleteMarker") })
+    }
+
+    // Get the hash of the block in a row of a report, or None if the row is not the current
+    // version of a block of the archive under the prefix.
+    fn block_hash(&self, prefix: &str, row: &str) -> Option<BlockHash> {
+        let fields = csv_fields(row);
+        let field = |c: Option<usize>| c.and_then(|c| fields.get(c)).map(String::as_str);
+        if field(self.latest_column) == Some("false") || field(self.delete_marker_column) == Some("true") {
+            return None;
+        }
+        // the keys in a report are URL encoded
+        key_hash(prefix, &percent_decode(field(Some(self.key_column))?))
+    }
+}
+
+// Find the manifest of the most recent report of an inventory configuration. The reports are in
+// directories named after the time they were made, such as "2024-01-31T01-00Z", which sort in the
+// order they were made, and the manifest is written once the report is complete.
+async fn latest_inventory(client: &Client, bucket: &str, prefix: &str) -> Result<String> {
+    let list_prefix = format!("{}/", prefix.trim_end_matches('/'));
+    let mut dirs = Vec::new();
+    let mut pages = client.list_objects_v2().bucket(bucket).prefix(&list_prefix).delimiter("/").into_paginator().send();
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(s3_error)?;
+        // the hive directory is not a report
+        dirs.extend(page.common_prefixes().iter().filter_map(|p| p.prefix())
+            .filter(|p| p[list_prefix.len()..].starts_with(|c: char| c.is_ascii_digit()))
+            .map(String::from));
+    }
+    dirs.sort();
+    for dir in dirs.iter().rev() {
+        let key = format!("{}manifest.json", dir);
+        match client.head_object().bucket(bucket).key(&key).send().await {
+            Ok(_) => return Ok(key),
+            Err(e) => match e.into_service_error() {
+                // the report is still being written
+                e if e.is_not_found() => continue,
+                e => return Err(s3_error(e)),
+            }
+        }
+    }
+    Err(Error::InvalidManifest(format!("no inventory reports under {}/{}", bucket, list_prefix)))
+}
+
+#[async_trait]
+impl BlockArchive for S3BlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        match self.client.get_object().bucket(&self.bucket).key(block_key(&self.prefix, block_hash)).send().await {
+            Ok(o) => {
+                let size = o.content_length().unwrap_or_default() as u64;
+                let body: Box<dyn AsyncRead + Unpin + Send> = Box::new(Box::pin(o.body.into_async_read()));
+                match self.chunk_manifest(block_hash, size).await? {
+                    Some(manifest) => {
+                        let rest = (1..manifest.sizes.len()).map(|i| self.open_chunk(block_hash, i, 0)).collect();
+                        Ok(Box::new(ChainedReader::new(body, rest)))
+                    }
+                    None => Ok(body),
+                }
+            }
+            Err(e) => match e.into_service_error() {
+                e if e.is_no_such_key() => Err(Error::BlockNotFound),
+                e => Err(s3_error(e)),
+            }
+        }
+    }
+
+    /// Only the range is fetched, using a range request for each chunk that it covers if the block
+    /// is stored in chunks.
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        if length == 0 {
+            if !self.block_exists(block_hash).await? {
+                return Err(Error::BlockNotFound);
+            }
+            return Ok(Box::new(tokio::io::empty()));
+        }
+        let range = format!("bytes={}-{}", offset, offset.saturating_add(length - 1));
+        match self.client.get_object().bucket(&self.bucket).key(block_key(&self.prefix, block_hash)).range(range).send().await {
+            Ok(o) => {
+                // the size of the block object, from "bytes first-last/size"
+                let size = o.content_range().and_then(|r| r.rsplit('/').next()).and_then(|s| s.parse::<u64>().ok());
+                let body: Box<dyn AsyncRead + Unpin + Send> = Box::new(Box::pin(o.body.into_async_read()));
+                if let Some(size) = size.filter(|s| offset.saturating_add(length) > *s) {
+                    if let Some(manifest) = self.chunk_manifest(block_hash, size).await? {
+                        return self.chunked_range(block_hash, &manifest, offset, length, Some(body)).await;
+                    }
+                }
+                Ok(body)
+            }
+            // the range starts after the end of the block object, which may be the first chunk
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 416) => {
+                let size = self.object_size(&block_key(&self.prefix, block_hash)).await?.ok_or(Error::BlockNotFound)?;
+                match self.chunk_manifest(block_hash, size).await? {
+                    Some(manifest) => self.chunked_range(block_hash, &manifest, offset, length, None).await,
+                    None => Ok(Box::new(tokio::io::empty())),
+                }
+            }
+            Err(e) => match e.into_service_error() {
+                e if e.is_no_such_key() => Err(Error::BlockNotFound),
+                e => Err(s3_error(e)),
+            }
+        }
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        match self.client.head_object().bucket(&self.bucket).key(block_key(&self.prefix, block_hash)).send().await {
+            Ok(_) => Ok(true),
+            Err(e) => match e.into_service_error() {
+                e if e.is_not_found() => Ok(false),
+                e => Err(s3_error(e)),
+            }
+        }
+    }
+
+    /// The blocks are checked with several requests at a time. With a list source, the blocks of
+    /// a large batch are first looked up in its listing and only those that are not in it are
+    /// checked with requests, so a block that was removed since the listing was made is reported
+    /// to exist.
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        if self.list_source == S3ListSource::Objects || block_hashes.len() < LISTING_EXISTS_MIN {
+            return check_blocks_exist(self, block_hashes, EXISTS_CONCURRENCY).await;
+        }
+        let wanted: BTreeSet<BlockHash> = block_hashes.iter().copied().collect();
+        let mut listed = BTreeSet::new();
+        let mut listing = self.listing();
+        while let Some(block_hash) = listing.next().await {
+            if wanted.contains(&block_hash) {
+                listed.insert(block_hash);
+            }
+        }
+        if let Some(e) = Pin::new(&mut listing).take_error() {
+            return Err(e);
+        }
+        let unlisted: Vec<BlockHash> = wanted.difference(&listed).copied().collect();
+        let found: BTreeSet<BlockHash> = check_blocks_exist(self, &unlisted, EXISTS_CONCURRENCY).await?
+            .into_iter().filter(|(_, exists)| *exists).map(|(h, _)| h).collect();
+        Ok(block_hashes.iter().map(|h| (*h, listed.contains(h) || found.contains(h))).collect())
+    }
+
+    /// Blocks larger than 8MiB are uploaded with a multipart upload, so the block is never held
+    /// in memory in full. Blocks larger than the chunk size, if there is one, are stored in chunks.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let key = block_key(&self.prefix, block_hash);
+        let existing = self.object_size(&key).await?;
+        if !self.overwrite && existing.is_some() {
+            return Err(Error::BlockExists);
+        }
+        // a replaced block may have been stored in chunks
+        let replaced = match existing {
+            Some(size) => self.chunk_manifest(block_hash, size).await?,
+            None => None,
+        };
+        let block = checked_block(self.write_policy, block_hash, block).await?;
+        let mut reader = ChecksumReader::new(block);
+        let buf = read_part(&mut reader).await?;
+        let manifest = if buf.len() < PART_SIZE {
+            self.client.put_object().bucket(&self.bucket).key(key)
+                .body(ByteStream::from(buf)).send().await.map_err(s3_error)?;
+            None
+        } else {
+            match self.chunk_size {
+                Some(chunk_size) => self.chunked_upload(block_hash, &mut reader, buf, chunk_size).await?,
+                None => {
+                    self.multipart_upload(&key, &mut reader, buf).await?;
+                    None
+                }
+            }
+        };
+        if let Some(replaced) = replaced {
+            let chunks = manifest.as_ref().map_or(1, |m| m.sizes.len());
+            let mut keys: Vec<String> = (chunks..replaced.sizes.len()).map(|i| part_key(&self.prefix, block_hash, i)).collect();
+            if manifest.is_none() {
+                keys.push(manifest_key(&self.prefix, block_hash));
+            }
+            for key in keys {
+                self.client.delete_object().bucket(&self.bucket).key(key).send().await.map_err(s3_error)?;
+            }
+        }
+        self.client.put_object().bucket(&self.bucket).key(checksum_key(&self.prefix, block_hash))
+            .body(ByteStream::from(reader.checksum().into_bytes())).send().await.map_err(s3_error)?;
+        Ok(())
+    }
+
+    /// The attributes and checksum objects are removed before the block, and the other chunks of
+    /// a block stored in chunks after it.
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        let size = self.object_size(&block_key(&self.prefix, block_hash)).await?.ok_or(Error::BlockNotFound)?;
+        let manifest = self.chunk_manifest(block_hash, size).await?;
+        let mut keys = vec![attrs_key(&self.prefix, block_hash), checksum_key(&self.prefix, block_hash), block_key(&self.prefix, block_hash)];
+        if let Some(manifest) = manifest {
+            keys.extend((1..manifest.sizes.len()).map(|i| part_key(&self.prefix, block_hash, i)));
+            keys.push(manifest_key(&self.prefix, block_hash));
+        }
+        // deleting an object that does not exist is not an error
+        for key in keys {
+            self.client.delete_object().bucket(&self.bucket).key(key).send().await.map_err(s3_error)?;
+        }
+        Ok(())
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        let size = self.object_size(&block_key(&self.prefix, block_hash)).await?.ok_or(Error::BlockNotFound)?;
+        match self.chunk_manifest(block_hash, size).await? {
+            Some(manifest) => Ok(manifest.block_size() as usize),
+            None => Ok(size as usize),
+        }
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        // only fetch the header, not the whole block
+        let range = format!("bytes=0-{}", HEADER_SIZE - 1);
+        match self.client.get_object().bucket(&self.bucket).key(block_key(&self.prefix, block_hash)).range(range).send().await {
+            Ok(o) => {
+                let mut reader = Box::pin(o.body.into_async_read());
+                Ok(BlockHeader::from_binary(&mut reader).await?)
+            }
+            Err(e) => match e.into_service_error() {
+                e if e.is_no_such_key() => Err(Error::BlockNotFound),
+                e => Err(s3_error(e)),
+            }
+        }
+    }
+
+    /// The headers are fetched with several requests at a time.
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        futures::stream::iter(block_hashes.to_vec())
+            .map(move |h| async move { self.block_header(&h).await.map(|header| (h, header)) })
+            .buffered(HEADER_CONCURRENCY)
+            .try_collect()
+            .await
+    }
+
+    /// Get a list of all the blocks in the archive, using paginated listing of the objects under
+    /// the prefix, or reading the listing of the list source if there is one, see
+    /// [S3BlockArchive::with_list_source].
+    ///
+    /// Objects that are not in the correct location for their hash are not returned.
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        Ok(Box::pin(self.listing()))
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        validate_block_attr(key, value)?;
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        attrs.insert(key.to_string(), value.to_string());
+        self.write_block_attrs(block_hash, &attrs).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        if attrs.remove(key).is_some() {
+            self.write_block_attrs(block_hash, &attrs).await?;
+        }
+        Ok(())
+    }
+
+    /// Attributes are stored in an object next to the block, with an "attrs" extension.
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        if !self.block_exists(block_hash).await? {
+            return Err(Error::BlockNotFound);
+        }
+        match self.client.get_object().bucket(&self.bucket).key(attrs_key(&self.prefix, block_hash)).send().await {
+            Ok(o) => {
+                let bytes = o.body.collect().await.map_err(s3_error)?.into_bytes();
+                let s = String::from_utf8(bytes.to_vec())
+                    .map_err(|_| Error::InvalidAttribute(attrs_key(&self.prefix, block_hash)))?;
+                decode_block_attrs(&s)
+            }
+            Err(e) => match e.into_service_error() {
+                // no attributes have been set
+                e if e.is_no_such_key() => Ok(BlockAttrs::new()),
+                e => Err(s3_error(e)),
+            }
+        }
+    }
+
+    /// The checksum is stored in an object next to the block, with a "sha256" extension.
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        let checksum = match self.client.get_object().bucket(&self.bucket).key(checksum_key(&self.prefix, block_hash)).send().await {
+            Ok(o) => {
+                let bytes = o.body.collect().await.map_err(s3_error)?.into_bytes();
+                String::from_utf8_lossy(&bytes).to_string()
+            }
+            Err(e) => match e.into_service_error() {
+                e if e.is_no_such_key() => {
+                    return if self.block_exists(block_hash).await? { Ok(false) } else { Err(Error::BlockNotFound) };
+                }
+                e => return Err(s3_error(e)),
+            }
+        };
+        check_block_checksum(self, block_hash, &checksum).await?;
+        Ok(true)
+    }
+}
+
+// Get the key of the object for a block.
+fn block_key(prefix: &str, hash: &BlockHash) -> String {
+    let s: String = hash.encode_hex();
+    let path = format!("{}/{}/{}.bin", &s[62..], &s[60..62], s);
+    if prefix.is_empty() {
+        path
+    } else {
+        format!("{}/{}", prefix, path)
+    }
+}
+
+// Get the hash of the block stored in an object, or None if the object is not a block or is not
+// in the correct location for its hash.
+fn key_hash(prefix: &str, key: &str) -> Option<BlockHash> {
+    let hash = BlockHash::from_hex(key.rsplit('/').next()?.strip_suffix(".bin")?).ok()?;
+    Some(hash).filter(|h| key == block_key(prefix, h))
+}
+
+// Get the key of the object holding the attributes of a block.
+fn attrs_key(prefix: &str, hash: &BlockHash) -> String {
+    let mut key = block_key(prefix, hash);
+    key.truncate(key.len() - "bin".len());
+    key.push_str("attrs");
+    key
+}
+
+// Get the key of the object holding the checksum of a block.
+fn checksum_key(prefix: &str, hash: &BlockHash) -> String {
+    let mut key = block_key(prefix, hash);
+    key.truncate(key.len() - "bin".len());
+    key.push_str("sha256");
+    key
+}
+
+// Get the key of the object holding a chunk of a block, other than the first.
+fn part_key(prefix: &str, hash: &BlockHash, index: usize) -> String {
+    let mut key = block_key(prefix, hash);
+    key.truncate(key.len() - "bin".len());
+    key.push_str(&part_suffix(index));
+    key
+}
+
+// Get the key of the object holding the chunk manifest of a block.
+fn manifest_key(prefix: &str, hash: &BlockHash) -> String {
+    let mut key = block_key(prefix, hash);
+    key.truncate(key.len() - "bin".len());
+    key.push_str(MANIFEST_EXTENSION);
+    key
+}
+
+// Read up to PART_SIZE bytes, less only if the end of the block is reached.
+async fn read_part(block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(PART_SIZE);
+    block.take(PART_SIZE as u64).read_to_end(&mut buf).await?;
+    Ok(buf)
+}
+
+// Split a row of a CSV file into its fields, which may be quoted.
+fn csv_fields(row: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = row.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+// Decode the %XX escapes of a URL encoded string.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => s.get(i + 1..i + 3).filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// Convert an error from the store.
+fn s3_error<E: std::error::Error>(e: E) -> Error {
+    Error::StorageUnavailable(DisplayErrorContext(&e).to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test the key generation, which must match the layout of the SimpleFileBasedBlockArchive.
+    #[test]
+    fn check_key_from_hash() {
+        let h = BlockHash::from_hex("00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531").unwrap();
+        assert_eq!(block_key("", &h), "31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin");
+        assert_eq!(block_key("mainnet", &h), "mainnet/31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.bin");
+        assert_eq!(attrs_key("mainnet", &h), "mainnet/31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.attrs");
+        assert_eq!(part_key("", &h, 1), "31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.00001.part");
+        assert_eq!(manifest_key("", &h), "31/c5/00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531.chunks");
+        assert_eq!(key_hash("mainnet", &block_key("mainnet", &h)), Some(h));
+        assert_eq!(key_hash("mainnet", &block_key("", &h)), None);
+        assert_eq!(key_hash("", &attrs_key("", &h)), None);
+    }
+
+    // The blocks of an inventory report are the current versions of the objects in the correct
+    // location under the prefix, and reports in other formats are refused.
+    #[test]
+    fn test_inventory_manifest() {
+        let manifest = InventoryManifest::parse(r#"{
+            "sourceBucket": "blocks",
+            "destinationBucket": "arn:aws:s3:::inventory",
+            "version": "2016-11-30",
+            "fileFormat": "CSV",
+            "fileSchema": "Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size",
+            "files": [{"key": "blocks/daily/data/1.csv.gz", "size": 100, "MD5checksum": "0"}]
+        }"#).unwrap();
+        assert_eq!(manifest.bucket, "inventory");
+        assert_eq!(manifest.files, vec!["blocks/daily/data/1.csv.gz".to_string()]);
+        let h = BlockHash::from_hex("00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531").unwrap();
+        let key = block_key("mainnet", &h).replace('/', "%2F");
+        let row = |version: &str, latest: &str, deleted: &str| format!("\"blocks\",\"{}\",\"{}\",\"{}\",\"{}\",\"227\"", key, version, latest, deleted);
+        assert_eq!(manifest.block_hash("mainnet", &row("v1", "true", "false")), Some(h));
+        assert_eq!(manifest.block_hash("mainnet", &row("v0", "false", "false")), None);
+        assert_eq!(manifest.block_hash("mainnet", &row("v2", "true", "true")), None);
+        assert_eq!(manifest.block_hash("testnet", &row("v1", "true", "false")), None);
+        assert_eq!(manifest.block_hash("mainnet", "\"blocks\""), None);
+
+        let parquet = r#"{"destinationBucket": "inventory", "fileFormat": "Parquet", "fileSchema": "", "files": []}"#;
+        assert!(matches!(InventoryManifest::parse(parquet), Err(Error::InvalidManifest(_))));
+        assert!(matches!(InventoryManifest::parse("{}"), Err(Error::InvalidManifest(_))));
+    }
+
+    // Quoted fields may hold commas and quotes, and escapes in keys are decoded.
+    #[test]
+    fn test_csv_fields() {
+        assert_eq!(csv_fields("\"a,b\",\"say \"\"hi\"\"\",,c"), vec!["a,b", "say \"hi\"", "", "c"]);
+        assert_eq!(percent_decode("mainnet%2F31%2fc5/x%zz%4"), "mainnet/31/c5/x%zz%4");
+    }
+}
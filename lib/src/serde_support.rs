@@ -0,0 +1,223 @@
+//! Serialize and Deserialize for the public types, with the `serde` feature.
+//!
+//! The stats, report, summary, event and configuration types derive them. Block hashes are written
+//! as hex strings in the usual display order, headers as the hex of their 80 byte encoding, and
+//! networks, failure kinds and tier policies by the names they are given on the command line.
+//! Errors are written as an [ErrorRecord], their kind and message, because they may hold IO errors.
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use futures::FutureExt;
+use hex::FromHex;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::headers::{encode_header, HEADER_SIZE};
+use crate::tier::TierPolicy;
+use crate::verify::FailureKind;
+use crate::{Error, ErrorRecord, Network};
+
+// Implement the traits with the Display and FromStr of each type.
+macro_rules! serde_with_str {
+    ($($t:ty),*) => {$(
+        impl Serialize for $t {
+            fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $t {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(|_| D::Error::custom(format!("invalid {}: {}", stringify!($t), s)))
+            }
+        }
+    )*};
+}
+
+serde_with_str!(FailureKind, Network, TierPolicy);
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        ErrorRecord::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Error {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(ErrorRecord::deserialize(deserializer)?.into())
+    }
+}
+
+// Parse a hex encoded block hash.
+fn parse_hash<E: serde::de::Error>(s: &str) -> std::result::Result<BlockHash, E> {
+    BlockHash::from_hex(s).map_err(|_| E::custom(format!("invalid block hash: {}", s)))
+}
+
+// A block hash as a hex string, for fields with serde(with).
+pub(crate) mod hash {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(hash: &BlockHash, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(hash)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<BlockHash, D::Error> {
+        parse_hash(&String::deserialize(deserializer)?)
+    }
+}
+
+// An optional block hash as a hex string or null, for fields with serde(with).
+pub(crate) mod optional_hash {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(hash: &Option<BlockHash>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        hash.map(|h| h.to_string()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<BlockHash>, D::Error> {
+        Option::<String>::deserialize(deserializer)?.map(|h| parse_hash(&h)).transpose()
+    }
+}
+
+// A list of block hashes as hex strings, for fields with serde(with).
+pub(crate) mod hashes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(list: &[BlockHash], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(list.iter().map(|h| h.to_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<BlockHash>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?.iter().map(|h| parse_hash(h)).collect()
+    }
+}
+
+// A list of block hashes, each with a value such as a size or a message, for fields with
+// serde(with).
+pub(crate) mod hash_pairs {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: Serialize>(list: &[(BlockHash, T)], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(list.iter().map(|(h, value)| (h.to_string(), value)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> std::result::Result<Vec<(BlockHash, T)>, D::Error> {
+        Vec::<(String, T)>::deserialize(deserializer)?.into_iter()
+            .map(|(h, value)| parse_hash(&h).map(|h| (h, value)))
+            .collect()
+    }
+}
+
+// A block header as the hex of its encoding, for fields with serde(with).
+pub(crate) mod header {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(header: &BlockHeader, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(encode_header(header)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<BlockHeader, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let bytes = Vec::<u8>::from_hex(&s).ok().filter(|b| b.len() == HEADER_SIZE)
+            .ok_or_else(|| D::Error::custom(format!("invalid block header: {}", s)))?;
+        // reading from a slice never waits
+        BlockHeader::from_binary(&mut &bytes[..]).now_or_never().and_then(|r| r.ok())
+            .ok_or_else(|| D::Error::custom(format!("invalid block header: {}", s)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use serde_json::json;
+    use crate::diff::ArchiveDiff;
+    use crate::gaps::Gap;
+    use crate::quarantine::RepairReport;
+    use crate::stats::{ArchiveStats, CsvOptions, StatsColumn};
+    use crate::verify::{CheckFailure, CheckReport};
+    use crate::{BlockArchive, BlockSummary, CheckpointResult, Fork, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // The stats and report types survive a round trip through JSON, with their hashes as hex.
+    #[test]
+    fn test_round_trip() {
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let stats = ArchiveStats { blocks: 1, total_bytes: 227, largest: vec![(h, 227)], ..Default::default() };
+        let encoded = serde_json::to_value(&stats).unwrap();
+        assert_eq!(encoded["largest"], json!([[h.to_string(), 227]]));
+        assert_eq!(serde_json::from_value::<ArchiveStats>(encoded).unwrap(), stats);
+
+        let failure = CheckFailure::new(&h, FailureKind::MissingParent, "no parent".to_string());
+        let report = CheckReport { checked: 1, failures: vec![failure], ..Default::default() };
+        let encoded = serde_json::to_value(&report).unwrap();
+        assert_eq!(encoded["failures"][0]["kind"], json!("missing_parent"));
+        assert_eq!(serde_json::from_value::<CheckReport>(encoded).unwrap(), report);
+
+        assert_eq!(serde_json::to_value(Network::Stn).unwrap(), json!("stn"));
+        assert_eq!(serde_json::from_value::<TierPolicy>(json!("age:30")).unwrap(), TierPolicy::OlderThanDays(30));
+        assert!(serde_json::from_value::<Network>(json!("nonet")).is_err());
+        assert_eq!(serde_json::to_value(Error::BlockNotFound).unwrap(), json!({"kind": "block_not_found", "message": "Block not found"}));
+        let e = Error::IoError(std::io::Error::new(std::io::ErrorKind::NotFound, "no such file"));
+        let encoded = serde_json::to_value(&e).unwrap();
+        assert_eq!(encoded["kind"], json!("io_error"));
+        assert_eq!(encoded["message"], json!(e.to_string()));
+    }
+
+    // An error keeps its kind through an ErrorRecord, and through an Error if it carries no data.
+    #[test]
+    fn test_error() {
+        let e = Error::IoError(std::io::Error::new(std::io::ErrorKind::NotFound, "no such file"));
+        let record = serde_json::from_value::<ErrorRecord>(serde_json::to_value(&e).unwrap()).unwrap();
+        assert_eq!(record, ErrorRecord::from(&e));
+        assert_eq!(record.kind, "io_error");
+        match serde_json::from_value::<Error>(serde_json::to_value(&e).unwrap()).unwrap() {
+            Error::RemoteError(msg) => assert_eq!(msg, e.to_string()),
+            other => panic!("expected a remote error, got {}", other),
+        }
+        let decoded = serde_json::from_value::<Error>(serde_json::to_value(Error::BlockNotFound).unwrap()).unwrap();
+        assert!(matches!(decoded, Error::BlockNotFound));
+        assert!(serde_json::from_value::<Error>(json!("block_not_found")).is_err());
+    }
+
+    // The chain, gap, diff and export types write their hashes as hex, and None as null.
+    #[test]
+    fn test_chain_types() {
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let fork = Fork { branch_point: None, blocks: vec![h] };
+        let encoded = serde_json::to_value(&fork).unwrap();
+        assert_eq!(encoded, json!({"branch_point": null, "blocks": [h.to_string()]}));
+        assert_eq!(serde_json::from_value::<Fork>(encoded).unwrap(), fork);
+
+        let result = CheckpointResult { height: 1, expected: h, found: Some(h) };
+        let encoded = serde_json::to_value(&result).unwrap();
+        assert_eq!(encoded["found"], json!(h.to_string()));
+        assert_eq!(serde_json::from_value::<CheckpointResult>(encoded).unwrap(), result);
+
+        let gap = Gap { start_height: 5, blocks: vec![h] };
+        assert_eq!(serde_json::from_value::<Gap>(serde_json::to_value(&gap).unwrap()).unwrap(), gap);
+        let diff = ArchiveDiff { same: 2, only_in_a: vec![h], ..Default::default() };
+        assert_eq!(serde_json::from_value::<ArchiveDiff>(serde_json::to_value(&diff).unwrap()).unwrap(), diff);
+        assert!(serde_json::from_value::<Gap>(json!({"start_height": 5, "blocks": ["00"]})).is_err());
+
+        let report = RepairReport { bad_copies: vec![(h, "bad merkle root".to_string())], ..Default::default() };
+        let encoded = serde_json::to_value(&report).unwrap();
+        assert_eq!(encoded["bad_copies"], json!([[h.to_string(), "bad merkle root"]]));
+        assert_eq!(serde_json::from_value::<RepairReport>(encoded).unwrap(), report);
+
+        let options = CsvOptions { columns: vec![StatsColumn::Hash, StatsColumn::TxCount], ..Default::default() };
+        let encoded = serde_json::to_value(&options).unwrap();
+        assert_eq!(encoded["columns"], json!(["hash", "tx_count"]));
+        assert_eq!(serde_json::from_value::<CsvOptions>(encoded).unwrap(), options);
+    }
+
+    // A header is written as the hex of its encoding and read back.
+    #[tokio::test]
+    async fn test_header() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let summary = archive.block_summary(&h).await.unwrap();
+        let encoded = serde_json::to_value(&summary).unwrap();
+        assert_eq!(encoded["header"].as_str().unwrap().len(), 2 * HEADER_SIZE);
+        assert_eq!(serde_json::from_value::<BlockSummary>(encoded).unwrap(), summary);
+        assert!(serde_json::from_value::<BlockSummary>(json!({"header": "00", "tx_count": 1, "size": 81})).is_err());
+    }
+}
@@ -1,20 +1,87 @@
-use std::path::PathBuf;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
 use async_trait::async_trait;
 use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
-use tokio::io::AsyncRead;
-use crate::{BlockArchive, Error, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use crate::{BlockArchive, BlockAttrs, CandidateStore, Error, Network, Result, WritePolicy};
 use hex::{FromHex, ToHex};
 use tokio::fs::File;
+use tokio::sync::Mutex;
 use tokio_stream::StreamExt;
 use tokio_stream::wrappers::ReadDirStream;
-use crate::block_archive::{BlockHashListStream, BlockHashListStreamFromChannel};
+use tracing::warn;
+use crate::headers::{HeadersFile, HEADER_SIZE};
+use crate::sizes::SizesFile;
+use crate::lock::{LOCK_FILE, WRITE_LOCK_FILE};
+use crate::meta::{ArchiveMeta, CURRENT_FORMAT_VERSION};
+use crate::chunks::{check_chunk_size, may_be_first_chunk, part_suffix, ChainedReader, ChunkManifest, OpenChunk, MANIFEST_EXTENSION, PART_EXTENSION};
+use crate::block_archive::{check_block_checksum, check_blocks_exist, check_hash_prefix, checked_block, decode_block_attrs,encode_block_attrs, full_hash, list_block_sizes, list_prefix, validate_block_attr, BlockHashListStream, BlockHashListStreamFromChannel, BlockSizeStream, ChecksumReader};
 
-// the absolute maximum number of blocks that will be stored
-// this is used to limit the size of the channel used to send block hashes
-// at the time of writing, testnet had about 1.2 million blocks
-// if this is too small, the background process will wait for the channel to be read
-const MAX_BLOCKS: usize = 2_000_000;
+// the directory, relative to the root, in which candidate blocks are stored
+const CANDIDATES_DIR: &str = "candidates";
+
+// the file, relative to the root, in which the headers of all blocks are kept
+const HEADERS_FILE: &str = "headers.dat";
+
+// the file, relative to the root, in which the sizes of all blocks are kept
+const SIZES_FILE: &str = "sizes.dat";
+
+// the extension of the files in which block attributes are stored
+const ATTRS_EXTENSION: &str = "attrs";
+
+// the extension of the files in which block checksums are stored
+const CHECKSUM_EXTENSION: &str = "sha256";
+
+// the extension added to files while they are being written
+const TMP_EXTENSION: &str = "tmp";
+
+// the extension of compressed block files
+const COMPRESSED_EXTENSION: &str = "bin.zst";
+
+// the number of blocks that blocks_exist() checks at the same time
+const EXISTS_CONCURRENCY: usize = 16;
+
+// the magic number and length of the zstd skippable frame appended to a compressed block, which
+// holds the size of the uncompressed block so that block_size() does not have to decompress it
+const SIZE_FRAME_MAGIC: u32 = 0x184D2A50;
+const SIZE_FRAME_LEN: usize = 16;
+
+// the default size of the channel used to send block hashes when listing blocks
+// the background process waits for the channel to be read when it is full
+const LIST_BUFFER: usize = 1000;
+
+// the default number of directories read at the same time when listing blocks
+const LIST_CONCURRENCY: usize = 32;
+
+/// What to do when a directory or a directory entry can not be read while listing the blocks of a
+/// [SimpleFileBasedBlockArchive].
+///
+/// Files that are not blocks are always skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListErrorPolicy {
+    /// End the listing, the error is returned by [BlockHashListStream::take_error].
+    #[default]
+    Fail,
+    /// Log a warning and carry on without the directory or entry.
+    Skip,
+}
+
+impl ListErrorPolicy {
+    // Handle an error reading a path, returning the error if the listing should end.
+    fn check(&self, path: &Path, e: std::io::Error) -> Result<()> {
+        match self {
+            ListErrorPolicy::Fail => Err(e.into()),
+            ListErrorPolicy::Skip => {
+                warn!(path = %path.display(), error = %e, "skipping directory while listing blocks");
+                Ok(())
+            }
+        }
+    }
+}
 
 /// A simple file-based block archive.
 ///
@@ -34,32 +101,455 @@ const MAX_BLOCKS: usize = 2_000_000;
 ///
 /// Note that if block files are stored in the wrong location then they are not recognised by the
 /// archive.
+///
+/// Blocks may also be stored zstd compressed, in a file with a "bin.zst" extension. Compressed
+/// and uncompressed blocks can be mixed in an archive and are read transparently. New blocks are
+/// only compressed if compression is turned on with [SimpleFileBasedBlockArchive::with_compression].
+///
+/// Blocks that are larger than a filesystem allows can be stored in several files, see
+/// [SimpleFileBasedBlockArchive::with_chunk_size] and [crate::chunks]. These are also read
+/// transparently.
+///
+/// The archive assumes mainnet unless told otherwise with [SimpleFileBasedBlockArchive::with_network],
+/// or the network is recorded in the ARCHIVE_META file with [SimpleFileBasedBlockArchive::set_network].
+///
+/// If the archive has a "headers.dat" file, created with [SimpleFileBasedBlockArchive::rebuild_headers],
+/// block headers are read from that file rather than from each block, which is much faster for
+/// chain operations. The file is kept up to date as blocks are stored and removed. In the same
+/// way a "sizes.dat" file, created with [SimpleFileBasedBlockArchive::rebuild_sizes], holds the
+/// sizes of the blocks for [BlockArchive::block_sizes] and [BlockArchive::total_size].
+///
+/// Listing the blocks reads many directories at the same time, which matters on network
+/// filesystems, see [SimpleFileBasedBlockArchive::with_list_concurrency]. A directory that can not
+/// be read ends the listing unless the archive is told to skip it with
+/// [SimpleFileBasedBlockArchive::with_list_errors].
 #[derive(Debug)]
 pub struct SimpleFileBasedBlockArchive {
     /// The root of the file store
     pub root_path: PathBuf,
+    /// The network that the blocks belong to
+    pub network: Network,
+    /// Whether new blocks are stored compressed
+    pub compress: bool,
+    // the headers file, if the archive has one
+    headers: Mutex<HeadersFile>,
+    // the sizes file, if the archive has one
+    sizes: Mutex<SizesFile>,
+    // the number of directories read at the same time when listing blocks
+    list_concurrency: usize,
+    // the number of block hashes buffered when listing blocks
+    list_buffer: usize,
+    // what to do with errors when listing blocks
+    list_errors: ListErrorPolicy,
+    // the checks made on blocks before they are stored
+    write_policy: WritePolicy,
+    // whether store_block() replaces an existing block
+    overwrite: bool,
+    // whether changes to the archive are refused
+    read_only: bool,
+    // whether stored blocks can never be replaced or removed
+    immutable: bool,
+    // blocks larger than this are stored in chunks
+    chunk_size: Option<u64>,
 }
 
 impl SimpleFileBasedBlockArchive
 {
     /// Create a new block archive with the given root path.
+    ///
+    /// If the root directory is empty then this is a new archive and an ARCHIVE_META file is
+    /// written, see [crate::meta]. The network and compression recorded in the meta file are
+    /// used as the settings of the archive.
+    ///
+    /// Fails with [Error::UnsupportedFormatVersion] if the archive was written by a newer version
+    /// of the library.
     pub async fn new(root_path: PathBuf) -> Result<SimpleFileBasedBlockArchive> {
+        SimpleFileBasedBlockArchive::open(root_path, true).await
+    }
+
+    /// Open an archive read-only, see [SimpleFileBasedBlockArchive::with_read_only].
+    ///
+    /// Nothing is written to the root directory, not even the ARCHIVE_META file of an empty
+    /// root, so an archive on a snapshot, a read-only mount, or a copy can be read without
+    /// changing it.
+    pub async fn open_read_only(root_path: PathBuf) -> Result<SimpleFileBasedBlockArchive> {
+        Ok(SimpleFileBasedBlockArchive::open(root_path, false).await?.with_read_only(true))
+    }
+
+    // Open the archive, writing the ARCHIVE_META file of a new archive if create is true.
+    async fn open(root_path: PathBuf, create: bool) -> Result<SimpleFileBasedBlockArchive> {
         // Check if the root_path is accessible
         match tokio::fs::metadata(&root_path).await {
             Ok(_) => {
+                let meta = match ArchiveMeta::read(&root_path).await? {
+                    Some(meta) => meta,
+                    None if create && is_empty_root(&root_path).await? => {
+                        let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok();
+                        let meta = ArchiveMeta { format_version: CURRENT_FORMAT_VERSION, created, ..Default::default() };
+                        meta.write(&root_path).await?;
+                        meta
+                    }
+                    // an archive created before the format was versioned
+                    None => ArchiveMeta::default(),
+                };
+                if meta.format_version > CURRENT_FORMAT_VERSION {
+                    return Err(Error::UnsupportedFormatVersion(meta.format_version));
+                }
+                let headers = Mutex::new(HeadersFile::new(root_path.join(HEADERS_FILE)));
+                let sizes = Mutex::new(SizesFile::new(root_path.join(SIZES_FILE)));
                 Ok(SimpleFileBasedBlockArchive {
                     root_path,
+                    network: meta.network.unwrap_or_default(),
+                    compress: meta.compress.unwrap_or(false),
+                    headers,
+                    sizes,
+                    list_concurrency: LIST_CONCURRENCY,
+                    list_buffer: LIST_BUFFER,
+                    list_errors: ListErrorPolicy::default(),
+                    write_policy: WritePolicy::default(),
+                    overwrite: false,
+                    read_only: false,
+                    immutable: meta.immutable,
+                    chunk_size: None,
                 })
             },
-            Err(e) => {
-                Err(e.into()) // Convert the error into your custom error type
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => Err(Error::StorageUnavailable(format!("{} does not exist", root_path.display()))),
+                _ => Err(e.into())
+            }
+        }
+    }
+
+    /// Get the on-disk format version of the archive, see [crate::meta].
+    pub async fn format_version(&self) -> Result<u32> {
+        Ok(ArchiveMeta::read(&self.root_path).await?.map_or(0, |m| m.format_version))
+    }
+
+    /// Set the network that the blocks in the archive belong to.
+    pub fn with_network(mut self, network: Network) -> SimpleFileBasedBlockArchive {
+        self.network = network;
+        self
+    }
+
+    /// Get the network recorded in the ARCHIVE_META file, or None if no network has been recorded.
+    pub async fn recorded_network(&self) -> Result<Option<Network>> {
+        Ok(ArchiveMeta::read(&self.root_path).await?.and_then(|m| m.network))
+    }
+
+    /// Record the network of the archive in the ARCHIVE_META file, so that it is used whenever
+    /// the archive is opened.
+    ///
+    /// Fails with [Error::WrongNetwork] if a different network has already been recorded.
+    pub async fn set_network(&mut self, network: Network) -> Result<()> {
+        self.check_writable()?;
+        let mut meta = ArchiveMeta::read(&self.root_path).await?.unwrap_or_default();
+        match meta.network {
+            Some(n) if n == network => {}
+            Some(n) => return Err(Error::WrongNetwork(format!("the archive holds {} blocks, not {}", n, network))),
+            None => {
+                meta.network = Some(network);
+                meta.write(&self.root_path).await?;
+            }
+        }
+        self.network = network;
+        Ok(())
+    }
+
+    /// Record in the ARCHIVE_META file whether new blocks are stored zstd compressed, so that the
+    /// setting is used whenever the archive is opened. Existing blocks are not changed.
+    pub async fn set_compression(&mut self, compress: bool) -> Result<()> {
+        self.check_writable()?;
+        let mut meta = ArchiveMeta::read(&self.root_path).await?.unwrap_or_default();
+        meta.compress = Some(compress);
+        meta.write(&self.root_path).await?;
+        self.compress = compress;
+        Ok(())
+    }
+
+    /// Make the archive immutable, recording it in the ARCHIVE_META file so that it applies
+    /// whenever the archive is opened. There is no way to turn it off again.
+    ///
+    /// In an immutable archive a stored block is never changed: storing a block that is already
+    /// in the archive, even with [SimpleFileBasedBlockArchive::with_overwrite], removing a block,
+    /// and compressing or decompressing a block fail with [Error::ArchiveImmutable]. The files of
+    /// new blocks are made read-only once they are written. Block attributes can still be
+    /// changed, as they are not part of the block.
+    pub async fn set_immutable(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let mut meta = ArchiveMeta::read(&self.root_path).await?.unwrap_or_default();
+        if !meta.immutable {
+            meta.immutable = true;
+            meta.write(&self.root_path).await?;
+        }
+        self.immutable = true;
+        Ok(())
+    }
+
+    /// Check whether the archive is immutable, see [SimpleFileBasedBlockArchive::set_immutable].
+    pub fn is_immutable(&self) -> bool {
+        self.immutable
+    }
+
+    /// Set whether new blocks are stored zstd compressed, overriding the setting recorded in the
+    /// ARCHIVE_META file. Existing blocks are not changed, see
+    /// [SimpleFileBasedBlockArchive::compress_block].
+    pub fn with_compression(mut self, compress: bool) -> SimpleFileBasedBlockArchive {
+        self.compress = compress;
+        self
+    }
+
+    /// Set the number of directories that are read at the same time when listing the blocks, the
+    /// default is 32.
+    pub fn with_list_concurrency(mut self, concurrency: usize) -> SimpleFileBasedBlockArchive {
+        self.list_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Set the number of block hashes that are buffered when listing the blocks, the default is
+    /// 1000. The listing waits for the consumer when the buffer is full, so a larger buffer uses
+    /// more memory in exchange for reading the directories while the consumer is busy.
+    pub fn with_list_buffer(mut self, size: usize) -> SimpleFileBasedBlockArchive {
+        self.list_buffer = size.max(1);
+        self
+    }
+
+    /// Set what to do when a directory can not be read while listing the blocks, the default is to
+    /// end the listing.
+    pub fn with_list_errors(mut self, policy: ListErrorPolicy) -> SimpleFileBasedBlockArchive {
+        self.list_errors = policy;
+        self
+    }
+
+    /// Set the checks that are made on blocks before they are stored, the default is to make no
+    /// checks. A block that fails is rejected before its file is created.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> SimpleFileBasedBlockArchive {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Set whether storing a block that is already in the archive replaces it, instead of
+    /// failing with [Error::BlockExists]. The new block is written in full before it replaces
+    /// the old one, and the attributes of the block are kept.
+    pub fn with_overwrite(mut self, overwrite: bool) -> SimpleFileBasedBlockArchive {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Set whether the archive is read-only, the default is false. Every method that would change
+    /// the archive then fails with [Error::ReadOnly]. Use this with a shared lock, see
+    /// [crate::lock], to read an archive that another process is writing to.
+    pub fn with_read_only(mut self, read_only: bool) -> SimpleFileBasedBlockArchive {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Store blocks that are larger than the chunk size in chunks, see [crate::chunks]. Blocks
+    /// stored in chunks are not compressed. By default blocks are never split.
+    ///
+    /// Fails with [Error::InvalidChunkManifest] if the chunk size is not a multiple of
+    /// [crate::chunks::CHUNK_ALIGN], see [check_chunk_size].
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> Result<SimpleFileBasedBlockArchive> {
+        check_chunk_size(chunk_size)?;
+        self.chunk_size = Some(chunk_size);
+        Ok(self)
+    }
+
+    /// Get the size of the files in which a block is stored, which is smaller than the size
+    /// returned by [BlockArchive::block_size] if the block is compressed.
+    pub async fn block_disk_size(&self, block_hash: &BlockHash) -> Result<u64> {
+        let (path, compressed) = self.block_file(block_hash).await?;
+        let size = tokio::fs::metadata(path).await?.len();
+        if !compressed {
+            if let Some(manifest) = self.chunk_manifest(block_hash, size).await? {
+                return Ok(manifest.block_size());
+            }
+        }
+        Ok(size)
+    }
+
+    /// Compress a stored block.
+    ///
+    /// Returns false if the block was already compressed, or is stored in chunks.
+    pub async fn compress_block(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.check_writable()?;
+        self.check_mutable()?;
+        let (path, compressed) = self.block_file(block_hash).await?;
+        if compressed || self.stored_manifest(block_hash).await?.is_some() {
+            return Ok(false);
+        }
+        let new_path = self.get_compressed_path_from_hash(block_hash);
+        let tmp_path = tmp_path(&new_path);
+        let mut file = File::open(&path).await?;
+        write_compressed(&mut file, &tmp_path).await?;
+        tokio::fs::rename(tmp_path, new_path).await?;
+        tokio::fs::remove_file(&path).await?;
+        sync_dir(path.parent().unwrap()).await?;
+        Ok(true)
+    }
+
+    /// Decompress a stored block.
+    ///
+    /// Returns false if the block was not compressed.
+    pub async fn decompress_block(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.check_writable()?;
+        self.check_mutable()?;
+        let (path, compressed) = self.block_file(block_hash).await?;
+        if !compressed {
+            return Ok(false);
+        }
+        let new_path = self.get_path_from_hash(block_hash);
+        let tmp_path = tmp_path(&new_path);
+        let mut reader = self.get_block(block_hash).await?;
+        let mut file = File::create(&tmp_path).await?;
+        tokio::io::copy(&mut reader, &mut file).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(tmp_path, &new_path).await?;
+        tokio::fs::remove_file(path).await?;
+        sync_dir(new_path.parent().unwrap()).await?;
+        Ok(true)
+    }
+
+    /// Find the temporary files left behind by writes that were interrupted, for example by a
+    /// crash, and the chunks of blocks whose write or removal was interrupted. These files are
+    /// never read as blocks.
+    pub async fn partial_files(&self) -> Result<Vec<PathBuf>> {
+        let mut partials = Vec::new();
+        let mut stack = vec![self.root_path.clone()];
+        while let Some(path) = stack.pop() {
+            let mut entries = tokio::fs::read_dir(path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    stack.push(path);
+                } else if path.extension().is_some_and(|e| e == TMP_EXTENSION) {
+                    partials.push(path);
+                } else if path.extension().is_some_and(|e| e == PART_EXTENSION || e == MANIFEST_EXTENSION) {
+                    // the chunks of a block outlive its first chunk if a write or removal was
+                    // interrupted
+                    let block_path = path.with_extension("").with_extension("bin");
+                    if !tokio::fs::try_exists(block_path).await? {
+                        partials.push(path);
+                    }
+                }
+            }
+        }
+        partials.sort();
+        Ok(partials)
+    }
+
+    /// Remove the temporary files left behind by interrupted writes, returning the files removed.
+    ///
+    /// This must not be run while another process is writing to the archive, because it would
+    /// remove the files being written.
+    pub async fn remove_partial_files(&self) -> Result<Vec<PathBuf>> {
+        self.check_writable()?;
+        let partials = self.partial_files().await?;
+        for path in partials.iter() {
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(partials)
+    }
+
+    /// Write the headers file from the headers of all the blocks in the archive, creating it if
+    /// it does not exist. Returns the number of headers written.
+    ///
+    /// Once the file exists it is kept up to date by [BlockArchive::store_block] and
+    /// [BlockArchive::remove_block]. This must not be run while another process is writing to
+    /// the archive, because the blocks it stores may be missed.
+    pub async fn rebuild_headers(&mut self) -> Result<usize> {
+        self.check_writable()?;
+        let mut headers = Vec::new();
+        let mut results = self.block_list().await?;
+        while let Some(block_hash) = results.next().await {
+            if let Some(header) = self.read_raw_header(&block_hash).await? {
+                headers.push(header);
             }
         }
+        // a headers file missing some blocks would be trusted from then on
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        self.headers.lock().await.write(&headers).await?;
+        Ok(headers.len())
+    }
+
+    /// Check whether the archive has a headers file, see [SimpleFileBasedBlockArchive::rebuild_headers].
+    pub async fn has_headers_file(&self) -> Result<bool> {
+        self.headers.lock().await.exists().await
+    }
+
+    /// Write the sizes file from the sizes of all the blocks in the archive, creating it if it
+    /// does not exist. Returns the number of sizes written.
+    ///
+    /// Once the file exists it is kept up to date by [BlockArchive::store_block] and
+    /// [BlockArchive::remove_block], and the sizes are read from it rather than from each block.
+    /// This must not be run while another process is writing to the archive, because the blocks
+    /// it stores may be missed.
+    pub async fn rebuild_sizes(&mut self) -> Result<usize> {
+        self.check_writable()?;
+        let mut sizes = Vec::new();
+        let mut results = list_block_sizes(self).await?;
+        while let Some(r) = results.next().await {
+            sizes.push(r?);
+        }
+        drop(results);
+        self.sizes.lock().await.write(&sizes).await?;
+        Ok(sizes.len())
+    }
+
+    /// Check whether the archive has a sizes file, see [SimpleFileBasedBlockArchive::rebuild_sizes].
+    pub async fn has_sizes_file(&self) -> Result<bool> {
+        self.sizes.lock().await.exists().await
+    }
+
+    /// Get the storage area for candidate blocks, which is kept in the "candidates" directory
+    /// under the root.
+    pub async fn candidates(&self) -> Result<CandidateStore> {
+        CandidateStore::new(self.root_path.join(CANDIDATES_DIR)).await
+    }
+
+    /// Get the shard directories of the archive. These are the directories directly below the
+    /// root, each holding the blocks whose hashes end with the same two hex digits, which can be
+    /// read independently, see [SimpleFileBasedBlockArchive::shard_blocks].
+    pub async fn shard_dirs(&self) -> Result<Vec<PathBuf>> {
+        let mut dirs = Self::sub_dirs(self.root_path.clone(), self.list_errors).await?;
+        // other directories under the root, such as the candidates, are not shards
+        dirs.retain(|d| d.file_name().and_then(|n| n.to_str())
+            .is_some_and(|n| n.len() == 2 && n.chars().all(|c| c.is_ascii_hexdigit())));
+        dirs.sort();
+        Ok(dirs)
+    }
+
+    /// List the blocks in one shard directory, see [SimpleFileBasedBlockArchive::shard_dirs].
+    pub async fn shard_blocks(&self, shard: &Path) -> Result<Vec<BlockHash>> {
+        let dirs = Self::sub_dirs(shard.to_path_buf(), self.list_errors).await?;
+        let (tx, mut rx) = tokio::sync::mpsc::channel(self.list_buffer);
+        let send = async move {
+            for dir in dirs {
+                Self::send_blocks(self.root_path.clone(), dir, self.list_errors, tx.clone()).await?;
+            }
+            Ok::<(), Error>(())
+        };
+        let receive = async {
+            let mut blocks = Vec::new();
+            while let Some(h) = rx.recv().await {
+                blocks.push(h);
+            }
+            blocks
+        };
+        let (sent, blocks) = tokio::join!(send, receive);
+        sent?;
+        Ok(blocks)
     }
 
     // Get the path for a block.
-    fn get_path_from_hash(&self, hash: &BlockHash) -> PathBuf {
-        let mut path = self.root_path.clone();
+    pub(crate) fn get_path_from_hash(&self, hash: &BlockHash) -> PathBuf {
+        self.root_path.join(Self::relative_block_path(hash))
+    }
+
+    /// Get the path of the uncompressed file of a block, relative to the root of an archive.
+    pub fn relative_block_path(hash: &BlockHash) -> PathBuf {
+        let mut path = PathBuf::new();
         let s: String = hash.encode_hex();
         path.push(&s[62..]);
         path.push(&s[60..62]);
@@ -68,46 +558,321 @@ impl SimpleFileBasedBlockArchive
         return path
     }
 
+    // Get the path of a chunk of a block, other than the first.
+    fn get_part_path_from_hash(&self, hash: &BlockHash, index: usize) -> PathBuf {
+        self.get_path_from_hash(hash).with_extension(part_suffix(index))
+    }
+
+    // Get the path for a compressed block.
+    fn get_compressed_path_from_hash(&self, hash: &BlockHash) -> PathBuf {
+        self.get_path_from_hash(hash).with_extension(COMPRESSED_EXTENSION)
+    }
+
+    // Read the encoded header from a stored block, or None if the block is too short to have one.
+    async fn read_raw_header(&self, block_hash: &BlockHash) -> Result<Option<[u8; HEADER_SIZE]>> {
+        let mut header = [0u8; HEADER_SIZE];
+        match self.get_block(block_hash).await?.read_exact(&mut header).await {
+            Ok(_) => Ok(Some(header)),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Find the file in which a block is stored, and whether it is compressed.
+    pub(crate) async fn block_file(&self, hash: &BlockHash) -> Result<(PathBuf, bool)> {
+        let path = self.get_path_from_hash(hash);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok((path, false));
+        }
+        let path = self.get_compressed_path_from_hash(hash);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok((path, true));
+        }
+        Err(Error::BlockNotFound)
+    }
+
+    // Find the files in which a block is stored, the block file followed by its other chunks if
+    // it is stored in chunks.
+    pub(crate) async fn block_files(&self, hash: &BlockHash) -> Result<Vec<PathBuf>> {
+        let (path, _) = self.block_file(hash).await?;
+        let mut paths = vec![path];
+        if let Some(manifest) = self.stored_manifest(hash).await? {
+            paths.extend((1..manifest.sizes.len()).map(|i| self.get_part_path_from_hash(hash, i)));
+        }
+        Ok(paths)
+    }
+
+    // Read the chunk manifest of a block whose uncompressed block file has the given size, or
+    // None if the block is not stored in chunks.
+    async fn chunk_manifest(&self, hash: &BlockHash, file_size: u64) -> Result<Option<ChunkManifest>> {
+        if !may_be_first_chunk(file_size) {
+            return Ok(None);
+        }
+        let path = self.get_path_from_hash(hash).with_extension(MANIFEST_EXTENSION);
+        match tokio::fs::read_to_string(path).await {
+            // a manifest left behind by a block that was replaced does not match the block file
+            Ok(s) => Ok(Some(ChunkManifest::decode(&s)?).filter(|m| m.sizes[0] == file_size)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Read the chunk manifest of a stored block, or None if the block is not in the archive or is
+    // not stored in chunks.
+    async fn stored_manifest(&self, hash: &BlockHash) -> Result<Option<ChunkManifest>> {
+        match self.block_file(hash).await {
+            Ok((path, false)) => self.chunk_manifest(hash, tokio::fs::metadata(path).await?.len()).await,
+            Ok((_, true)) | Err(Error::BlockNotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Open the chunks of a block from the given chunk, which is not the first, to the last.
+    fn open_chunks(&self, hash: &BlockHash, manifest: &ChunkManifest, from: usize) -> Vec<OpenChunk> {
+        (from..manifest.sizes.len()).map(|i| {
+            let path = self.get_part_path_from_hash(hash, i);
+            Box::pin(async move {
+                Ok::<_, Error>(Box::new(File::open(path).await?) as Box<dyn AsyncRead + Unpin + Send>)
+            }) as OpenChunk
+        }).collect()
+    }
+
+    // Write a block to a temporary file, returning the temporary file and the file that it
+    // replaces once complete, and the manifest if the block is stored in chunks. The temporary
+    // file is removed if the write fails.
+    async fn write_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send))
+                         -> Result<(PathBuf, PathBuf, Option<ChunkManifest>)> {
+        let path = self.get_path_from_hash(block_hash);
+        let compressed_path = self.get_compressed_path_from_hash(block_hash);
+        let chunk_size = match self.chunk_size {
+            Some(chunk_size) => chunk_size,
+            None => {
+                let block_path = if self.compress { compressed_path } else { path };
+                let tmp_path = tmp_path(&block_path);
+                let r = if self.compress {
+                    write_compressed(block, &tmp_path).await
+                } else {
+                    write_file(block, &tmp_path).await.map(|_| ())
+                };
+                if let Err(e) = r {
+                    let _ = tokio::fs::remove_file(&tmp_path).await;
+                    return Err(e);
+                }
+                return Ok((tmp_path, block_path, None));
+            }
+        };
+        // write the first chunk, then look for more of the block
+        let first_tmp_path = tmp_path(&path);
+        let mut probe = [0u8; 1];
+        let r = async {
+            let size = write_file(&mut (&mut *block).take(chunk_size), &first_tmp_path).await?;
+            let more = if size == chunk_size { block.read(&mut probe).await? } else { 0 };
+            Ok::<_, Error>((size, more))
+        }.await;
+        let (size, more) = match r {
+            Ok(r) => r,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&first_tmp_path).await;
+                return Err(e);
+            }
+        };
+        if more == 0 {
+            if !self.compress {
+                return Ok((first_tmp_path, path, None));
+            }
+            // the block fits in one chunk, compress it as usual
+            let compressed_tmp_path = tmp_path(&compressed_path);
+            let r = async { write_compressed(&mut File::open(&first_tmp_path).await?, &compressed_tmp_path).await }.await;
+            let _ = tokio::fs::remove_file(&first_tmp_path).await;
+            if let Err(e) = r {
+                let _ = tokio::fs::remove_file(&compressed_tmp_path).await;
+                return Err(e);
+            }
+            return Ok((compressed_tmp_path, compressed_path, None));
+        }
+        let mut rest = (&probe[..]).chain(block);
+        match self.write_chunks(block_hash, &mut rest, size, chunk_size).await {
+            Ok(manifest) => Ok((first_tmp_path, path, Some(manifest))),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&first_tmp_path).await;
+                Err(e)
+            }
+        }
+    }
+
+    // Write the chunks of a block after the first, followed by the manifest. Chunks that are
+    // written before a failure are left for remove_partial_files().
+    async fn write_chunks(&self, block_hash: &BlockHash, rest: &mut (dyn AsyncRead + Unpin + Send),
+                          first_size: u64, chunk_size: u64) -> Result<ChunkManifest> {
+        let mut manifest = ChunkManifest { sizes: vec![first_size] };
+        loop {
+            let part_path = self.get_part_path_from_hash(block_hash, manifest.sizes.len());
+            let tmp_path = tmp_path(&part_path);
+            let size = match write_file(&mut (&mut *rest).take(chunk_size), &tmp_path).await {
+                Ok(size) => size,
+                Err(e) => {
+                    let _ = tokio::fs::remove_file(&tmp_path).await;
+                    return Err(e);
+                }
+            };
+            if size == 0 {
+                // the block was a multiple of the chunk size
+                tokio::fs::remove_file(&tmp_path).await?;
+                break;
+            }
+            if self.immutable {
+                set_read_only(&tmp_path).await?;
+            }
+            tokio::fs::rename(&tmp_path, &part_path).await?;
+            manifest.sizes.push(size);
+            if size < chunk_size {
+                break;
+            }
+        }
+        let manifest_path = self.get_path_from_hash(block_hash).with_extension(MANIFEST_EXTENSION);
+        write_atomic(&manifest_path, manifest.encode().as_bytes()).await?;
+        if self.immutable {
+            set_read_only(&manifest_path).await?;
+        }
+        Ok(manifest)
+    }
+
+    // Fail with Error::ReadOnly if the archive is read-only.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    // Fail with Error::ArchiveImmutable if the archive is immutable.
+    fn check_mutable(&self) -> Result<()> {
+        if self.immutable {
+            return Err(Error::ArchiveImmutable);
+        }
+        Ok(())
+    }
+
+    // Write the attributes of a block, removing the attributes file if there are none.
+    // The file is written to a temporary file first so that readers never see a partial file.
+    async fn write_block_attrs(&self, block_hash: &BlockHash, attrs: &BlockAttrs) -> Result<()> {
+        let path = self.get_path_from_hash(block_hash).with_extension(ATTRS_EXTENSION);
+        if attrs.is_empty() {
+            return match tokio::fs::remove_file(path).await {
+                Ok(_) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+        }
+        write_atomic(&path, encode_block_attrs(attrs).as_bytes()).await
+    }
+
     // Get a list of all blocks in the background, sending results to the channel.
+    // Blocks are two directories below the root, the directories are read concurrently.
     // Do not return blocks that are stored in the wrong location because these
     // won't be retrievable by get_block().
-    async fn block_list_bgrnd(root_path: PathBuf, transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<()> {
-        let mut stack = Vec::new();
-        stack.push(root_path.clone());
-        while let Some(path) = stack.pop() {
-            let dir = tokio::fs::read_dir(path).await?;
-            let mut stream = ReadDirStream::new(dir);
-            // it would be fun to spawn a new task for each directory, but that would be a bit daft
-            while let Some(entry) = stream.next().await {
-                let entry = entry?;
-                let path = entry.path();
-                if path.is_dir() {
-                    stack.push(path);
-                } else {
-                    // ignore files which are not .bin files
-                    if path.extension().is_none() || path.extension().unwrap() != "bin" {
+    async fn block_list_bgrnd(root_path: PathBuf, concurrency: usize, policy: ListErrorPolicy,
+                              transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<()> {
+        let top = Self::sub_dirs(root_path.clone(), policy).await?;
+        let listers = top.into_iter().map(|dir| Self::sub_dirs(dir, policy));
+        let mut results = futures::StreamExt::buffer_unordered(futures::stream::iter(listers), concurrency);
+        let mut dirs = Vec::new();
+        while let Some(r) = results.next().await {
+            dirs.extend(r?);
+        }
+        let listers = dirs.into_iter().map(|dir| Self::send_blocks(root_path.clone(), dir, policy, transmit.clone()));
+        let mut results = futures::StreamExt::buffer_unordered(futures::stream::iter(listers), concurrency);
+        while let Some(r) = results.next().await {
+            if !r? {
+                return Ok(());      // this is not an error, the receiver has merely dropped
+            }
+        }
+        Ok(())
+    }
+
+    // Get the sub-directories of a directory.
+    async fn sub_dirs(path: PathBuf, policy: ListErrorPolicy) -> Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        let dir = match tokio::fs::read_dir(&path).await {
+            Ok(d) => d,
+            Err(e) => {
+                policy.check(&path, e)?;
+                return Ok(dirs);
+            }
+        };
+        let mut stream = ReadDirStream::new(dir);
+        while let Some(entry) = stream.next().await {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    policy.check(&path, e)?;
+                    continue;
+                }
+            };
+            match entry.file_type().await {
+                Ok(t) if t.is_dir() => dirs.push(entry.path()),
+                Ok(_) => {}
+                Err(e) => policy.check(&entry.path(), e)?,
+            }
+        }
+        Ok(dirs)
+    }
+
+    // Send the hashes of the blocks in a directory, returning false if the receiver has dropped.
+    async fn send_blocks(root_path: PathBuf, dir: PathBuf, policy: ListErrorPolicy,
+                         transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<bool> {
+        let stream = match tokio::fs::read_dir(&dir).await {
+            Ok(d) => d,
+            Err(e) => {
+                policy.check(&dir, e)?;
+                return Ok(true);
+            }
+        };
+        let mut stream = ReadDirStream::new(stream);
+        while let Some(entry) = stream.next().await {
+            let path = match entry {
+                Ok(e) => e.path(),
+                Err(e) => {
+                    policy.check(&dir, e)?;
+                    continue;
+                }
+            };
+            // ignore files which are not .bin or .bin.zst files
+            let file_name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(n) => n,
+                None => continue,
+            };
+            let f_name = match file_name.strip_suffix(".bin").or_else(|| file_name.strip_suffix(".bin.zst")) {
+                Some(n) => n,
+                None => continue,
+            };
+            // ignore files which are not valid block hashes
+            let h = match BlockHash::from_hex(f_name) {
+                Ok(h) => h,
+                Err(_) => continue,
+            };
+            // ignore files that are not in the correct location
+            let correct_path = root_path.join(&f_name[62..]).join(&f_name[60..62]).join(file_name);
+            if path != correct_path {
+                continue;
+            }
+            // a block that is being compressed or decompressed briefly has both
+            // files, only return it once
+            if file_name.ends_with(".zst") {
+                match tokio::fs::try_exists(path.with_extension("")).await {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => {
+                        policy.check(&path, e)?;
                         continue;
                     }
-                    let f_name = path.file_stem().unwrap().to_str().unwrap();
-                    match BlockHash::from_hex(f_name) {
-                        Ok(h) => {
-                            // ignore files that are not in the correct location
-                            let correct_path = root_path.join(&f_name[62..]).join(&f_name[60..62]).join(f_name).with_extension("bin");
-                            if path != correct_path {
-                                continue;
-                            }
-                            match transmit.send(h).await {
-                                Ok(_) => {}
-                                Err(_) => return Ok(())     // this is not an error, the receiver has merely dropped
-                            }
-                        }
-                        // ignore files which are not valid block hashes
-                        Err(_) => continue
-                    };
                 }
             }
+            if transmit.send(h).await.is_err() {
+                return Ok(false);
+            }
         }
-        Ok(())
+        Ok(true)
     }
 }
 
@@ -115,65 +880,178 @@ impl SimpleFileBasedBlockArchive
 impl BlockArchive for SimpleFileBasedBlockArchive
 {
     async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
-        let path = self.get_path_from_hash(block_hash);
-        match File::open(path).await {
-            Ok(f) => Ok(Box::new(f)),
-            Err(e) => match e.kind() {
-                // if the file does not exist, return a BlockNotFound error
-                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
-                _ => Err(e.into())
+        let (path, compressed) = self.block_file(block_hash).await?;
+        let file = File::open(path).await?;
+        if compressed {
+            return Ok(Box::new(ZstdDecoder::new(BufReader::new(file))));
+        }
+        match self.chunk_manifest(block_hash, file.metadata().await?.len()).await? {
+            Some(manifest) => Ok(Box::new(ChainedReader::new(Box::new(file), self.open_chunks(block_hash, &manifest, 1)))),
+            None => Ok(Box::new(file)),
+        }
+    }
+
+    /// Uncompressed blocks are read from the offset, starting with the chunk that holds it if the
+    /// block is stored in chunks, compressed blocks have to be decompressed from the start.
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let (path, compressed) = self.block_file(block_hash).await?;
+        if compressed {
+            let mut reader = self.get_block(block_hash).await?;
+            tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink()).await?;
+            return Ok(Box::new(reader.take(length)));
+        }
+        let mut file = File::open(path).await?;
+        if let Some(manifest) = self.chunk_manifest(block_hash, file.metadata().await?.len()).await? {
+            let (index, chunk_offset) = match manifest.locate(offset) {
+                Some(l) => l,
+                None => return Ok(Box::new(tokio::io::empty())),
+            };
+            if index > 0 {
+                file = File::open(self.get_part_path_from_hash(block_hash, index)).await?;
             }
+            file.seek(SeekFrom::Start(chunk_offset)).await?;
+            let reader = ChainedReader::new(Box::new(file), self.open_chunks(block_hash, &manifest, index + 1));
+            return Ok(Box::new(reader.take(length)));
         }
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file.take(length)))
     }
 
     /// Check if a block exists in the archive.
     async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
-        let path = self.get_path_from_hash(block_hash);
-        match tokio::fs::metadata(path).await {
+        match self.block_file(block_hash).await {
             Ok(_) => Ok(true),
-            Err(e) => match e.kind() {
-                // if the file does not exist, return false
-                std::io::ErrorKind::NotFound => Ok(false),
-                _ => Err(e.into())
-            }
+            // if the file does not exist, return false
+            Err(Error::BlockNotFound) => Ok(false),
+            Err(e) => Err(e),
         }
     }
 
-    async fn store_block(&self, block_hash: &BlockHash, block: &mut Box<dyn AsyncRead + Unpin + Send>) -> Result<()> {
-        if self.block_exists(block_hash).await? {
-            return Err(Error::BlockExists);
+    /// The files are checked several at a time.
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        check_blocks_exist(self, block_hashes, EXISTS_CONCURRENCY).await
+    }
+
+    /// The block is compressed if compression is turned on, unless it is stored in chunks.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        self.check_writable()?;
+        if (self.immutable || !self.overwrite) && self.block_exists(block_hash).await? {
+            return Err(if self.immutable { Error::ArchiveImmutable } else { Error::BlockExists });
         }
+        let block = checked_block(self.write_policy, block_hash, block).await?;
         let path = self.get_path_from_hash(block_hash);
         // create the directory structure if it does not exist
         tokio::fs::create_dir_all(path.parent().unwrap()).await?;
-        // store the block in a file
-        let mut file = File::create(path).await?;
-        tokio::io::copy(block, &mut file).await?;
+        // a replaced block may have been stored in chunks
+        let replaced = if self.overwrite && !self.immutable { self.stored_manifest(block_hash).await? } else { None };
+        // store the block in a file, calculating the checksum of the uncompressed block
+        let mut reader = ChecksumReader::new(block);
+        // write to a temporary file which is renamed once complete, so that an interrupted write
+        // never leaves a partial block
+        let (tmp_path, block_path, manifest) = self.write_block(block_hash, &mut reader).await?;
+        if self.immutable {
+            set_read_only(&tmp_path).await?;
+        }
+        tokio::fs::rename(&tmp_path, &block_path).await?;
+        if self.overwrite && !self.immutable {
+            // a replaced block may have been stored with the other compression setting
+            let other_path = if block_path == path { self.get_compressed_path_from_hash(block_hash) } else { path.clone() };
+            remove_if_exists(&other_path).await?;
+            if let Some(replaced) = replaced {
+                let chunks = manifest.as_ref().map_or(1, |m| m.sizes.len());
+                for i in chunks..replaced.sizes.len() {
+                    remove_if_exists(&self.get_part_path_from_hash(block_hash, i)).await?;
+                }
+                if manifest.is_none() {
+                    remove_if_exists(&path.with_extension(MANIFEST_EXTENSION)).await?;
+                }
+            }
+        }
+        let checksum_path = path.with_extension(CHECKSUM_EXTENSION);
+        write_atomic(&checksum_path, reader.checksum().as_bytes()).await?;
+        if self.immutable {
+            set_read_only(&checksum_path).await?;
+        }
+        sync_dir(path.parent().unwrap()).await?;
+        let mut headers = self.headers.lock().await;
+        if headers.exists().await? {
+            if let Some(header) = self.read_raw_header(block_hash).await? {
+                headers.add(&header).await?;
+            }
+        }
+        let mut sizes = self.sizes.lock().await;
+        if sizes.exists().await? {
+            let size = self.block_size(block_hash).await? as u64;
+            sizes.add(block_hash, size).await?;
+        }
         Ok(())
     }
 
-    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
-        let path = self.get_path_from_hash(block_hash);
-        match tokio::fs::metadata(path).await {
-            Ok(m) => Ok(m.len() as usize),
-            Err(e) => match e.kind() {
-                // if the file does not exist, return a BlockNotFound error
-                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
-                _ => Err(e.into())
+    /// The shard directories of the block are removed if they are left empty.
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.check_writable()?;
+        self.check_mutable()?;
+        let (path, _) = self.block_file(block_hash).await?;
+        let manifest = self.stored_manifest(block_hash).await?;
+        // remove the sidecar files first, so that they never outlive the block
+        let plain_path = self.get_path_from_hash(block_hash);
+        for extension in [ATTRS_EXTENSION, CHECKSUM_EXTENSION] {
+            match tokio::fs::remove_file(plain_path.with_extension(extension)).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
             }
         }
+        tokio::fs::remove_file(&path).await?;
+        // the other chunks are removed after the block file, so that the block is never read
+        // without them
+        if let Some(manifest) = manifest {
+            for i in 1..manifest.sizes.len() {
+                remove_if_exists(&self.get_part_path_from_hash(block_hash, i)).await?;
+            }
+            remove_if_exists(&plain_path.with_extension(MANIFEST_EXTENSION)).await?;
+        }
+        self.headers.lock().await.remove(block_hash).await?;
+        self.sizes.lock().await.remove(block_hash).await?;
+        // remove_dir fails if the directory is not empty
+        let dir = path.parent().unwrap();
+        if tokio::fs::remove_dir(dir).await.is_ok() {
+            let _ = tokio::fs::remove_dir(dir.parent().unwrap()).await;
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the uncompressed block, even if the block is compressed.
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        let (path, compressed) = self.block_file(block_hash).await?;
+        if !compressed {
+            let size = tokio::fs::metadata(path).await?.len();
+            return match self.chunk_manifest(block_hash, size).await? {
+                Some(manifest) => Ok(manifest.block_size() as usize),
+                None => Ok(size as usize),
+            };
+        }
+        // read the size from the frame at the end of the file
+        let mut file = File::open(path).await?;
+        let mut frame = [0u8; SIZE_FRAME_LEN];
+        if file.metadata().await?.len() < SIZE_FRAME_LEN as u64 {
+            return Err(Error::CorruptBlock(*block_hash));
+        }
+        file.seek(SeekFrom::End(-(SIZE_FRAME_LEN as i64))).await?;
+        file.read_exact(&mut frame).await?;
+        if frame[..4] != SIZE_FRAME_MAGIC.to_le_bytes() || frame[4..8] != 8u32.to_le_bytes() {
+            return Err(Error::CorruptBlock(*block_hash));
+        }
+        Ok(u64::from_le_bytes(frame[8..].try_into().unwrap()) as usize)
     }
 
+    /// The header is read from the headers file if the archive has one and it has the block.
     async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
-        let path = self.get_path_from_hash(block_hash);
-        match File::open(path).await {
-            Ok(mut file) => Ok(BlockHeader::from_binary(&mut file).await?),
-            Err(e) => match e.kind() {
-                // if the file does not exist, return a BlockNotFound error
-                std::io::ErrorKind::NotFound => Err(Error::BlockNotFound),
-                _ => Err(e.into())
-            }
+        if let Some(header) = self.headers.lock().await.get(block_hash).await? {
+            return Ok(header);
         }
+        let mut reader = self.get_block(block_hash).await?;
+        Ok(BlockHeader::from_binary(&mut reader).await?)
     }
 
     /// Get a list of all the blocks in the archive.
@@ -187,16 +1065,175 @@ impl BlockArchive for SimpleFileBasedBlockArchive
     ///     }
     ///
     /// This function does not return blocks that are stored in the wrong location because these
-    /// won't be retrievable by get_block().
+    /// won't be retrievable by get_block(). Files that are not blocks are skipped, see
+    /// [ListErrorPolicy] for directories that can not be read.
     async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
-        // make the channel large enough to buffer all hashes, including testnet
-        // so that the background task can collect all buffer hashes despite how slow the consumer is
-        let (tx, rx) = tokio::sync::mpsc::channel(MAX_BLOCKS);
-        let handle = tokio::spawn(Self::block_list_bgrnd(self.root_path.clone(), tx));
+        // the channel is bounded, when it is full the background task waits for the consumer to
+        // read from it, so a slow consumer slows the listing rather than using more memory
+        let (tx, rx) = tokio::sync::mpsc::channel(self.list_buffer);
+        let handle = tokio::spawn(Self::block_list_bgrnd(self.root_path.clone(), self.list_concurrency, self.list_errors, tx));
         Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
     }
+
+    /// The blocks are found in the sizes file if the archive has one, which is read once and
+    /// kept in memory. Otherwise every shard is listed, as the shards are named after the end of
+    /// the hash rather than its start.
+    async fn resolve_prefix(&mut self, prefix: &str) -> Result<Vec<BlockHash>> {
+        let prefix = check_hash_prefix(prefix)?;
+        if let Some(block_hash) = full_hash(&prefix) {
+            return Ok(if self.block_exists(&block_hash).await? { vec![block_hash] } else { Vec::new() });
+        }
+        let found = self.sizes.lock().await.all().await?.map(|sizes| {
+            let mut hashes: Vec<BlockHash> = sizes.keys().map(|h| BlockHash { hash: *h })
+                .filter(|h| h.to_string().starts_with(&prefix)).collect();
+            hashes.sort_by_cached_key(|h| h.to_string());
+            hashes
+        });
+        match found {
+            Some(hashes) => Ok(hashes),
+            None => list_prefix(self, prefix).await,
+        }
+    }
+
+    /// The sizes are read from the sizes file if the archive has one.
+    async fn block_sizes<'a>(&'a mut self) -> Result<BlockSizeStream<'a>> {
+        let sizes = self.sizes.lock().await.all().await?
+            .map(|sizes| sizes.iter().map(|(h, size)| Ok((BlockHash { hash: *h }, *size))).collect::<Vec<Result<_>>>());
+        match sizes {
+            Some(sizes) => Ok(Box::pin(futures::stream::iter(sizes))),
+            None => list_block_sizes(self).await,
+        }
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.check_writable()?;
+        validate_block_attr(key, value)?;
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        attrs.insert(key.to_string(), value.to_string());
+        self.write_block_attrs(block_hash, &attrs).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.check_writable()?;
+        let mut attrs = self.get_block_attrs(block_hash).await?;
+        if attrs.remove(key).is_some() {
+            self.write_block_attrs(block_hash, &attrs).await?;
+        }
+        Ok(())
+    }
+
+    /// Attributes are stored in a file next to the block, with an "attrs" extension.
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        if !self.block_exists(block_hash).await? {
+            return Err(Error::BlockNotFound);
+        }
+        let path = self.get_path_from_hash(block_hash).with_extension(ATTRS_EXTENSION);
+        match tokio::fs::read_to_string(path).await {
+            Ok(s) => decode_block_attrs(&s),
+            Err(e) => match e.kind() {
+                // no attributes have been set
+                std::io::ErrorKind::NotFound => Ok(BlockAttrs::new()),
+                _ => Err(e.into())
+            }
+        }
+    }
+
+    /// The checksum is stored in a file next to the block, with a "sha256" extension. It is the
+    /// checksum of the uncompressed block, so it is not changed by compressing the block.
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        let path = self.get_path_from_hash(block_hash).with_extension(CHECKSUM_EXTENSION);
+        let checksum = match tokio::fs::read_to_string(path).await {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return if self.block_exists(block_hash).await? { Ok(false) } else { Err(Error::BlockNotFound) };
+            }
+            Err(e) => return Err(e.into()),
+        };
+        check_block_checksum(self, block_hash, &checksum).await?;
+        Ok(true)
+    }
+}
+
+
+// Check whether the root directory of an archive is empty, ignoring the lock files, which may be
+// created before the archive is opened.
+async fn is_empty_root(root_path: &Path) -> Result<bool> {
+    let mut entries = tokio::fs::read_dir(root_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        if name != LOCK_FILE && name != WRITE_LOCK_FILE {
+            return Ok(false);
+        }
+    }
+    Ok(true)
 }
 
+// Remove the write permission of a file.
+async fn set_read_only(path: &Path) -> Result<()> {
+    let mut permissions = tokio::fs::metadata(path).await?.permissions();
+    permissions.set_readonly(true);
+    tokio::fs::set_permissions(path, permissions).await?;
+    Ok(())
+}
+
+// Write a block to a zstd compressed file, followed by a skippable frame holding the size of the
+// uncompressed block. The file is still a valid zstd file that the zstd tool can decompress.
+async fn write_compressed(block: &mut (dyn AsyncRead + Unpin + Send), path: &Path) -> Result<()> {
+    let mut encoder = ZstdEncoder::new(File::create(path).await?);
+    let size = tokio::io::copy(block, &mut encoder).await?;
+    encoder.shutdown().await?;
+    let mut file = encoder.into_inner();
+    file.write_all(&SIZE_FRAME_MAGIC.to_le_bytes()).await?;
+    file.write_all(&8u32.to_le_bytes()).await?;
+    file.write_all(&size.to_le_bytes()).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+// Write a block to a file, making sure it has reached the disk, returning the size of the file.
+async fn write_file(block: &mut (dyn AsyncRead + Unpin + Send), path: &Path) -> Result<u64> {
+    let mut file = File::create(path).await?;
+    let size = tokio::io::copy(block, &mut file).await?;
+    file.sync_all().await?;
+    Ok(size)
+}
+
+// Remove a file, if it exists.
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Write a small file through a temporary file, so that readers never see a partial file.
+async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = tmp_path(path);
+    let mut file = File::create(&tmp_path).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(tmp_path, path).await?;
+    Ok(())
+}
+
+// Get the path of the temporary file used while writing a file.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".");
+    s.push(TMP_EXTENSION);
+    PathBuf::from(s)
+}
+
+// Make sure that renames in a directory have reached the disk. Directories can only be synced on
+// unix.
+async fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir).await?.sync_all().await?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
 
 #[cfg(test)]
 mod tests {
@@ -204,6 +1241,8 @@ mod tests {
     use hex::FromHex;
     use mktemp::Temp;
     use tokio::io::AsyncReadExt;
+    use crate::chunks::CHUNK_ALIGN;
+    use crate::{ListOptions, ListOrder};
     use super::*;
 
     // Test the path generation from a block hash.
@@ -229,6 +1268,78 @@ mod tests {
         assert_eq!(count, 3);
     }
 
+    // The listing is the same however many directories are read at once, and with a small buffer.
+    #[tokio::test]
+    async fn test_block_list_settings() {
+        let root = PathBuf::from("../testdata/blockarchive");
+        let mut archive = SimpleFileBasedBlockArchive::new(root.clone()).await.unwrap();
+        let mut expected: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        expected.sort();
+        let mut archive = SimpleFileBasedBlockArchive::new(root).await.unwrap().with_list_concurrency(1).with_list_buffer(1);
+        let mut listed: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        listed.sort();
+        assert_eq!(listed, expected);
+        assert_eq!(listed.len(), 3);
+    }
+
+    // The list can be sorted, filtered by prefix, and paged.
+    #[tokio::test]
+    async fn test_block_list_opts() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let options = ListOptions { order: ListOrder::Hash, ..Default::default() };
+        let sorted: Vec<BlockHash> = archive.block_list_opts(options).await.unwrap().collect().await;
+        assert_eq!(sorted.len(), 3);
+        assert!(sorted.windows(2).all(|w| w[0].to_string() < w[1].to_string()));
+        let options = ListOptions { order: ListOrder::Hash, offset: 1, limit: Some(1), ..Default::default() };
+        let page: Vec<BlockHash> = archive.block_list_opts(options).await.unwrap().collect().await;
+        assert_eq!(page, vec![sorted[1]]);
+        let options = ListOptions { prefix: Some("00000000839A".to_string()), ..Default::default() };
+        let found: Vec<BlockHash> = archive.block_list_opts(options).await.unwrap().collect().await;
+        assert_eq!(found, vec![BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap()]);
+        // genesis then block 1, then the block that is not linked
+        let options = ListOptions { order: ListOrder::Height, ..Default::default() };
+        let by_height: Vec<BlockHash> = archive.block_list_opts(options).await.unwrap().collect().await;
+        assert_eq!(by_height[0], BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap());
+        assert_eq!(by_height[1], found[0]);
+        assert_eq!(by_height.len(), 3);
+    }
+
+    // Blocks can be found by header timestamp or by median time past, in order of time.
+    #[tokio::test]
+    async fn test_blocks_in_time_range() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let block1 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        // genesis is at 1231006505 and block 1 at 1231469665, the end is excluded
+        let found = archive.blocks_in_time_range(0, 1231469665, false).await.unwrap();
+        assert_eq!(found, vec![genesis]);
+        let found = archive.blocks_in_time_range(1231006505, 1262304000, false).await.unwrap();
+        assert_eq!(found, vec![genesis, block1]);
+        let found = archive.blocks_in_time_range(0, 1262304000, true).await.unwrap();
+        assert_eq!(found, vec![genesis, block1]);
+        assert!(archive.blocks_in_time_range(0, 1231006505, true).await.unwrap().is_empty());
+    }
+
+    // A missing root ends the listing with an error, unless errors are skipped.
+    #[tokio::test]
+    async fn test_block_list_errors() {
+        let root_dir = Temp::new_dir().unwrap();
+        let root = root_dir.to_path_buf();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.clone()).await.unwrap();
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+        let mut results = archive.block_list().await.unwrap();
+        assert_eq!(results.next().await, None);
+        assert!(matches!(results.as_mut().take_error(), Some(Error::IoError(_))));
+        // a failed listing is not taken for a complete one
+        assert!(matches!(archive.rebuild_headers().await, Err(Error::IoError(_))));
+        assert!(matches!(archive.total_size().await, Err(Error::IoError(_))));
+        assert!(matches!(archive.rebuild_sizes().await, Err(Error::IoError(_))));
+        let mut archive = archive.with_list_errors(ListErrorPolicy::Skip);
+        let mut results = archive.block_list().await.unwrap();
+        assert_eq!(results.next().await, None);
+        assert!(results.as_mut().take_error().is_none());
+    }
+
     // Test the block list function with no blocks.
     #[tokio::test]
     async fn test_empty_block_list() {
@@ -250,6 +1361,10 @@ mod tests {
         let root = PathBuf::from("../testdata/nonexistent");
         let archive = SimpleFileBasedBlockArchive::new(root).await;
         assert!(archive.is_err());
+        match archive {
+            Err(Error::StorageUnavailable(_)) => assert!(true),
+            _ => assert!(false)
+        }
     }
 
     // Test getting a block
@@ -302,6 +1417,18 @@ mod tests {
         assert!(!exists);
     }
 
+    // Several blocks are checked at once, in the order they are given.
+    #[tokio::test]
+    async fn test_blocks_exist() {
+        let root = PathBuf::from("../testdata/blockarchive");
+        let archive = SimpleFileBasedBlockArchive::new(root).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let unknown = BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1").unwrap();
+        let result = archive.blocks_exist(&[unknown, h, unknown]).await.unwrap();
+        assert_eq!(result, vec![(unknown, false), (h, true), (unknown, false)]);
+        assert!(archive.blocks_exist(&[]).await.unwrap().is_empty());
+    }
+
     // A block that is stored in the wrong location wont exist
     #[tokio::test]
     async fn test_wrong_location_block_exists() {
@@ -356,6 +1483,32 @@ mod tests {
         }
     }
 
+    // A block is replaced when overwriting is turned on, even if it was stored compressed, and
+    // store_block_if_absent() never replaces it.
+    #[tokio::test]
+    async fn test_overwrite_block() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap().with_compression(true);
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(archive.store_block_if_absent(&h, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap());
+        archive.set_block_attr(&h, "source", "test").await.unwrap();
+        assert!(!archive.store_block_if_absent(&h, &mut Cursor::new(b"This is a new block".to_vec())).await.unwrap());
+
+        let archive = archive.with_compression(false).with_overwrite(true);
+        assert!(!archive.store_block_if_absent(&h, &mut Cursor::new(b"This is a new block".to_vec())).await.unwrap());
+        let mut buf = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"This is a block");
+        archive.store_block(&h, &mut Cursor::new(b"This is a new block".to_vec())).await.unwrap();
+        let mut buf = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"This is a new block");
+        assert!(!archive.block_file(&h).await.unwrap().1);
+        assert!(!tokio::fs::try_exists(archive.get_compressed_path_from_hash(&h)).await.unwrap());
+        assert!(archive.verify_checksum(&h).await.unwrap());
+        assert_eq!(archive.get_block_attrs(&h).await.unwrap().get("source").unwrap(), "test");
+    }
+
     // Test getting the size of a block
     #[tokio::test]
     async fn test_block_size() {
@@ -413,4 +1566,362 @@ mod tests {
             }
         }
     }
+
+    // Test setting, getting, and removing block attributes
+    #[tokio::test]
+    async fn test_block_attrs() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let block_cursor = Box::new(Cursor::new("This is a block".as_bytes().to_vec()));
+        archive.store_block(&h, &mut (block_cursor as Box<dyn AsyncRead + Unpin + Send>)).await.unwrap();
+        assert!(archive.get_block_attrs(&h).await.unwrap().is_empty());
+        archive.set_block_attr(&h, "source", "peer-x").await.unwrap();
+        archive.set_block_attr(&h, "legal-hold", "true").await.unwrap();
+        archive.set_block_attr(&h, "source", "peer-y").await.unwrap();
+        let attrs = archive.get_block_attrs(&h).await.unwrap();
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs["source"], "peer-y");
+        archive.remove_block_attr(&h, "source").await.unwrap();
+        archive.remove_block_attr(&h, "legal-hold").await.unwrap();
+        assert!(archive.get_block_attrs(&h).await.unwrap().is_empty());
+        assert!(archive.set_block_attr(&h, "a=b", "c").await.is_err());
+        // the attributes file should not be listed as a block
+        let mut archive = archive;
+        archive.set_block_attr(&h, "source", "peer-x").await.unwrap();
+        let mut results = archive.block_list().await.unwrap();
+        let mut count = 0;
+        while let Some(_) = results.next().await {
+            count += 1;
+        }
+        assert_eq!(count, 1);
+    }
+
+    // Test attributes of an unknown block
+    #[tokio::test]
+    async fn test_unknown_block_attrs() {
+        let root = PathBuf::from("../testdata/blockarchive");
+        let archive = SimpleFileBasedBlockArchive::new(root).await.unwrap();
+        let h = BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1").unwrap();
+        match archive.set_block_attr(&h, "source", "peer-x").await {
+            Err(Error::BlockNotFound) => {}
+            _ => assert!(false)
+        }
+    }
+
+    // Test using the archive as a boxed trait object
+    #[tokio::test]
+    async fn test_boxed_archive() {
+        let root = PathBuf::from("../testdata/blockarchive");
+        let mut archive: Box<dyn BlockArchive> = Box::new(SimpleFileBasedBlockArchive::new(root).await.unwrap());
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(archive.block_exists(&h).await.unwrap());
+        let mut buf = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), archive.block_size(&h).await.unwrap());
+        // a boxed archive can be used wherever a BlockArchive is expected
+        let manifest = crate::Manifest::generate(&mut archive).await.unwrap();
+        assert_eq!(manifest.entries.len(), 3);
+    }
+
+    // Test storing, reading, and migrating a compressed block
+    #[tokio::test]
+    async fn test_compressed_block() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap().with_compression(true);
+        archive.store_block(&h, &mut Cursor::new(block.clone())).await.unwrap();
+        assert!(archive.block_file(&h).await.unwrap().1);
+        assert_eq!(archive.block_size(&h).await.unwrap(), block.len());
+        assert!(archive.block_disk_size(&h).await.unwrap() < block.len() as u64);
+        assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+        let mut buf = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, block);
+        let mut results = archive.block_list().await.unwrap();
+        assert_eq!(results.next().await, Some(h));
+        assert_eq!(results.next().await, None);
+        drop(results);
+        // decompress and compress again
+        assert!(archive.decompress_block(&h).await.unwrap());
+        assert!(!archive.decompress_block(&h).await.unwrap());
+        assert_eq!(archive.block_disk_size(&h).await.unwrap(), block.len() as u64);
+        assert!(archive.compress_block(&h).await.unwrap());
+        assert!(archive.block_file(&h).await.unwrap().1);
+        assert_eq!(archive.block_size(&h).await.unwrap(), block.len());
+        // the checksum is of the uncompressed block
+        assert!(archive.verify_checksum(&h).await.unwrap());
+    }
+
+    // Ranges of compressed and uncompressed blocks, including ranges past the end of the block.
+    #[tokio::test]
+    async fn test_get_block_range() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let h1 = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let h2 = BlockHash::from_hex("00000000000000b86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        archive.store_block(&h1, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        let archive = archive.with_compression(true);
+        archive.store_block(&h2, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        for h in [h1, h2] {
+            for (offset, length, expected) in [(5, 2, &b"is"[..]), (10, 100, &b"block"[..]), (100, 10, &b""[..])] {
+                let mut buf = Vec::new();
+                archive.get_block_range(&h, offset, length).await.unwrap().read_to_end(&mut buf).await.unwrap();
+                assert_eq!(buf, expected);
+            }
+        }
+    }
+
+    // Headers come from the headers file once it exists, and it follows stores and removals.
+    #[tokio::test]
+    async fn test_rebuild_headers() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h1 = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let h2 = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h1).await.unwrap().read_to_end(&mut block).await.unwrap();
+        archive.store_block(&h1, &mut Cursor::new(block)).await.unwrap();
+        assert!(!archive.has_headers_file().await.unwrap());
+        assert_eq!(archive.rebuild_headers().await.unwrap(), 1);
+        assert!(archive.has_headers_file().await.unwrap());
+        // replace the block with one that has no header, the header is still found
+        let path = archive.get_path_from_hash(&h1);
+        tokio::fs::write(&path, b"short").await.unwrap();
+        assert_eq!(archive.block_header(&h1).await.unwrap().hash(), h1);
+        let mut block = Vec::new();
+        src.get_block(&h2).await.unwrap().read_to_end(&mut block).await.unwrap();
+        archive.store_block(&h2, &mut Cursor::new(block)).await.unwrap();
+        assert_eq!(archive.headers.lock().await.get(&h2).await.unwrap().unwrap().hash(), h2);
+        archive.remove_block(&h1).await.unwrap();
+        assert!(archive.block_header(&h1).await.is_err());
+        // a rebuild skips blocks that are too short
+        archive.store_block(&h1, &mut Cursor::new(b"short".to_vec())).await.unwrap();
+        assert_eq!(archive.rebuild_headers().await.unwrap(), 1);
+    }
+
+    // Sizes come from the sizes file once it exists, and it follows stores and removals.
+    #[tokio::test]
+    async fn test_rebuild_sizes() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let h1 = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let h2 = BlockHash::from_hex("00000000000000b86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        archive.store_block(&h1, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        assert_eq!(archive.total_size().await.unwrap(), 15);
+        assert!(!archive.has_sizes_file().await.unwrap());
+        assert_eq!(archive.rebuild_sizes().await.unwrap(), 1);
+        assert!(archive.has_sizes_file().await.unwrap());
+        // change the block behind the archive's back, the recorded size is still used
+        tokio::fs::write(archive.get_path_from_hash(&h1), b"short").await.unwrap();
+        assert_eq!(archive.total_size().await.unwrap(), 15);
+        archive.store_block(&h2, &mut Cursor::new(b"This is another block".to_vec())).await.unwrap();
+        assert_eq!(archive.total_size().await.unwrap(), 36);
+        archive.remove_block(&h1).await.unwrap();
+        let sizes: Vec<(BlockHash, u64)> = archive.block_sizes().await.unwrap().map(|r| r.unwrap()).collect().await;
+        assert_eq!(sizes, vec![(h2, 21)]);
+    }
+
+    // Short hashes are resolved by listing the archive, and from the sizes file once it exists.
+    #[tokio::test]
+    async fn test_resolve_prefix() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let h1 = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let h2 = BlockHash::from_hex("00000000000000b86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        archive.store_block(&h1, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        archive.store_block(&h2, &mut Cursor::new(b"This is another block".to_vec())).await.unwrap();
+        assert_eq!(archive.resolve_prefix("00000000000000a").await.unwrap(), vec![h1]);
+        assert_eq!(archive.resolve_prefix("0000").await.unwrap(), vec![h1, h2]);
+        archive.rebuild_sizes().await.unwrap();
+        assert_eq!(archive.resolve_prefix("00000000000000B").await.unwrap(), vec![h2]);
+        assert_eq!(archive.resolve_prefix("0000").await.unwrap(), vec![h1, h2]);
+        assert!(archive.resolve_prefix("1").await.unwrap().is_empty());
+    }
+
+    // Test removing a block and its files
+    #[tokio::test]
+    async fn test_remove_block() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let h1 = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let h2 = BlockHash::from_hex("00000000000000b86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        archive.store_block(&h1, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        archive.store_block(&h2, &mut Cursor::new(b"This is another block".to_vec())).await.unwrap();
+        archive.set_block_attr(&h1, "source", "peer-x").await.unwrap();
+        let dir = archive.get_path_from_hash(&h1).parent().unwrap().to_path_buf();
+        archive.remove_block(&h1).await.unwrap();
+        assert!(!archive.block_exists(&h1).await.unwrap());
+        assert!(archive.block_exists(&h2).await.unwrap());
+        // the directory still holds the other block
+        assert!(tokio::fs::try_exists(&dir).await.unwrap());
+        assert!(matches!(archive.remove_block(&h1).await, Err(Error::BlockNotFound)));
+        archive.remove_block(&h2).await.unwrap();
+        assert!(!tokio::fs::try_exists(&dir).await.unwrap());
+        assert!(!tokio::fs::try_exists(dir.parent().unwrap()).await.unwrap());
+        // the block can be stored again, without the old attributes
+        archive.store_block(&h1, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        assert!(archive.get_block_attrs(&h1).await.unwrap().is_empty());
+    }
+
+    // Test finding and removing the files left by interrupted writes
+    #[tokio::test]
+    async fn test_partial_files() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        archive.store_block(&h, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        assert!(archive.partial_files().await.unwrap().is_empty());
+        // an interrupted write of another block in the same directory
+        let partial = tmp_path(&archive.get_path_from_hash(&h).with_file_name("00000000000000b86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f.bin"));
+        tokio::fs::write(&partial, b"This is a").await.unwrap();
+        assert_eq!(archive.partial_files().await.unwrap(), vec![partial.clone()]);
+        assert_eq!(archive.remove_partial_files().await.unwrap(), vec![partial.clone()]);
+        assert!(!tokio::fs::try_exists(&partial).await.unwrap());
+        assert!(archive.block_exists(&h).await.unwrap());
+    }
+
+    // Test storing, reading and removing a block that is larger than the chunk size
+    #[tokio::test]
+    async fn test_chunked_block() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap()
+            .with_chunk_size(CHUNK_ALIGN).unwrap().with_compression(true);
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let block: Vec<u8> = (0..2 * CHUNK_ALIGN + 100).map(|i| (i % 251) as u8).collect();
+        archive.store_block(&h, &mut Cursor::new(block.clone())).await.unwrap();
+        let path = archive.get_path_from_hash(&h);
+        assert_eq!(tokio::fs::metadata(&path).await.unwrap().len(), CHUNK_ALIGN);
+        assert_eq!(archive.block_files(&h).await.unwrap().len(), 3);
+        assert_eq!(archive.block_size(&h).await.unwrap(), block.len());
+        assert_eq!(archive.block_disk_size(&h).await.unwrap(), block.len() as u64);
+        let mut data = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut data).await.unwrap();
+        assert!(data == block);
+        // a range across the end of the first chunk
+        let mut data = Vec::new();
+        archive.get_block_range(&h, CHUNK_ALIGN - 10, 20).await.unwrap().read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, block[CHUNK_ALIGN as usize - 10..CHUNK_ALIGN as usize + 10]);
+        assert!(archive.verify_checksum(&h).await.unwrap());
+        assert!(!archive.compress_block(&h).await.unwrap());
+        assert!(archive.partial_files().await.unwrap().is_empty());
+        archive.remove_block(&h).await.unwrap();
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert!(!tokio::fs::try_exists(path.with_extension(MANIFEST_EXTENSION)).await.unwrap());
+        // a small block is compressed as usual
+        archive.store_block(&h, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        assert!(archive.block_file(&h).await.unwrap().1);
+    }
+
+    // Test that a chunk size that is not a multiple of the alignment is refused
+    #[tokio::test]
+    async fn test_invalid_chunk_size() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert!(matches!(archive.with_chunk_size(CHUNK_ALIGN + 1), Err(Error::InvalidChunkManifest(_))));
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert!(matches!(archive.with_chunk_size(0), Err(Error::InvalidChunkManifest(_))));
+    }
+
+    // Test verifying the checksum recorded when a block is stored
+    #[tokio::test]
+    async fn test_verify_checksum() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        archive.store_block(&h, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        assert!(archive.verify_checksum(&h).await.unwrap());
+        // damage the block
+        tokio::fs::write(archive.block_file(&h).await.unwrap().0, b"This is a blocc").await.unwrap();
+        match archive.verify_checksum(&h).await {
+            Err(Error::ChecksumMismatch(b)) => assert_eq!(b, h),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // the test archive has no checksums
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        assert!(!archive.verify_checksum(&h).await.unwrap());
+        let unknown = BlockHash::from_hex("0000000000000000094cc2ba6cc08514bcf9cbae26719d0a654a7754f3c75ef1").unwrap();
+        assert!(matches!(archive.verify_checksum(&unknown).await, Err(Error::BlockNotFound)));
+    }
+
+    // The network is recorded in the meta file and can not be changed.
+    #[tokio::test]
+    async fn test_set_network() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert_eq!(archive.recorded_network().await.unwrap(), None);
+        archive.set_network(Network::Testnet).await.unwrap();
+        assert_eq!(archive.network, Network::Testnet);
+        archive.set_network(Network::Testnet).await.unwrap();
+        assert!(matches!(archive.set_network(Network::Mainnet).await, Err(Error::WrongNetwork(_))));
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert_eq!(archive.network, Network::Testnet);
+        assert_eq!(archive.recorded_network().await.unwrap(), Some(Network::Testnet));
+        assert_eq!(archive.format_version().await.unwrap(), CURRENT_FORMAT_VERSION);
+    }
+
+    // A read-only archive refuses changes, and a new root that only holds the lock files is
+    // still treated as empty.
+    #[tokio::test]
+    async fn test_read_only() {
+        let root = Temp::new_dir().unwrap();
+        let _lock = crate::lock::WriteGuard::acquire(&root.to_path_buf(), std::time::Duration::ZERO).await.unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert_eq!(archive.format_version().await.unwrap(), CURRENT_FORMAT_VERSION);
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        archive.store_block(&h, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+
+        let archive = archive.with_read_only(true);
+        let h2 = BlockHash::from_hex("00000000000000b86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(matches!(archive.store_block(&h2, &mut Cursor::new(b"This is a block".to_vec())).await, Err(Error::ReadOnly)));
+        assert!(matches!(archive.remove_block(&h).await, Err(Error::ReadOnly)));
+        assert!(matches!(archive.set_block_attr(&h, "key", "value").await, Err(Error::ReadOnly)));
+        assert!(matches!(archive.compress_block(&h).await, Err(Error::ReadOnly)));
+        assert!(archive.block_exists(&h).await.unwrap());
+        assert!(!archive.block_exists(&h2).await.unwrap());
+    }
+
+    // An archive opened read-only refuses changes without writing anything to its root, even when
+    // the root is empty.
+    #[tokio::test]
+    async fn test_open_read_only() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::open_read_only(root.to_path_buf()).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(matches!(archive.store_block(&h, &mut Cursor::new(b"This is a block".to_vec())).await, Err(Error::ReadOnly)));
+        assert!(matches!(archive.remove_block(&h).await, Err(Error::ReadOnly)));
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert!(std::fs::read_dir(root.to_path_buf()).unwrap().next().is_none());
+
+        let archive = SimpleFileBasedBlockArchive::open_read_only(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        assert!(archive.block_exists(&h).await.unwrap());
+    }
+
+    // An immutable archive keeps its blocks, also when opened again, and makes their files
+    // read-only.
+    #[tokio::test]
+    async fn test_immutable() {
+        let root = Temp::new_dir().unwrap();
+        let mut archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap().with_overwrite(true);
+        assert!(!archive.is_immutable());
+        archive.set_immutable().await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        archive.store_block(&h, &mut Cursor::new(b"This is a block".to_vec())).await.unwrap();
+        let path = archive.get_path_from_hash(&h);
+        assert!(tokio::fs::metadata(&path).await.unwrap().permissions().readonly());
+        assert!(matches!(archive.store_block(&h, &mut Cursor::new(b"Another block".to_vec())).await, Err(Error::ArchiveImmutable)));
+        assert!(matches!(archive.compress_block(&h).await, Err(Error::ArchiveImmutable)));
+        archive.set_block_attr(&h, "key", "value").await.unwrap();
+
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        assert!(archive.is_immutable());
+        assert!(matches!(archive.remove_block(&h).await, Err(Error::ArchiveImmutable)));
+        let mut block = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        assert_eq!(block, b"This is a block");
+    }
 }
@@ -0,0 +1,424 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use hex::ToHex;
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+use crate::block_archive::{check_blocks_exist, BlockHashListStream, BlockHashListStreamFromChannel};
+use crate::tier::move_block;
+use crate::{BlockArchive, BlockAttrs, Error, Result, SimpleFileBasedBlockArchive, WritePolicy};
+
+// The size of the channel used to list blocks.
+const LIST_BUFFER: usize = 1000;
+
+// the number of blocks that blocks_exist() checks at the same time
+const EXISTS_CONCURRENCY: usize = 16;
+
+// The prefix that matches every block, in the shard map file.
+const DEFAULT_PREFIX: &str = "*";
+
+/// The map from hash prefixes to the directories of a [ShardedFileBlockArchive].
+///
+/// A prefix is matched against the name of the first level directory of a block in a
+/// [SimpleFileBasedBlockArchive], which is the last two characters of the hex encoded hash. So
+/// the prefix "3" matches the directories "30" to "3f", and the prefix "31" only matches "31".
+/// The longest matching prefix is used, and the empty prefix matches every block.
+///
+/// The map is written as lines of a prefix and a path, with "*" for the empty prefix. Blank
+/// lines and lines starting with "#" are ignored.
+///
+/// Example:
+///     0 /mnt/disk1/mainnet
+///     1 /mnt/disk1/mainnet
+///     * /mnt/disk2/mainnet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShardMap {
+    entries: BTreeMap<String, PathBuf>,
+}
+
+impl ShardMap {
+    /// Create an empty map.
+    pub fn new() -> ShardMap {
+        ShardMap::default()
+    }
+
+    /// Add a prefix to the map, replacing the path of the prefix if it is already in the map.
+    ///
+    /// Fails with [Error::InvalidShardMap] if the prefix is not up to two lower case hex
+    /// characters.
+    pub fn insert(&mut self, prefix: &str, path: PathBuf) -> Result<()> {
+        if prefix.len() > 2 || !prefix.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)) {
+            return Err(Error::InvalidShardMap(format!("invalid prefix {}", prefix)));
+        }
+        self.entries.insert(prefix.to_string(), path);
+        Ok(())
+    }
+
+    /// Parse a map from the lines of a shard map file.
+    pub fn parse(s: &str) -> Result<ShardMap> {
+        let mut map = ShardMap::new();
+        for line in s.lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (prefix, path) = line.split_once(char::is_whitespace)
+                .ok_or_else(|| Error::InvalidShardMap(format!("invalid line {}", line)))?;
+            let prefix = if prefix == DEFAULT_PREFIX { "" } else { prefix };
+            map.insert(prefix, PathBuf::from(path.trim()))?;
+        }
+        Ok(map)
+    }
+
+    /// Read a map from a shard map file.
+    pub async fn read(path: &Path) -> Result<ShardMap> {
+        ShardMap::parse(&tokio::fs::read_to_string(path).await?)
+    }
+
+    /// Get the prefixes and their paths.
+    pub fn entries(&self) -> &BTreeMap<String, PathBuf> {
+        &self.entries
+    }
+
+    /// Get the path of the directory that a block belongs in.
+    pub fn path_for(&self, block_hash: &BlockHash) -> Option<&Path> {
+        self.prefix_for(&dir_name(block_hash)).map(|p| self.entries[p].as_path())
+    }
+
+    // Get the longest prefix that matches a first level directory.
+    fn prefix_for(&self, dir: &str) -> Option<&str> {
+        (0..=dir.len()).rev().find_map(|n| self.entries.get_key_value(&dir[..n]).map(|(p, _)| p.as_str()))
+    }
+
+    // Check that every first level directory has a prefix.
+    fn check(&self) -> Result<()> {
+        for i in 0..=255u8 {
+            let dir = format!("{:02x}", i);
+            if self.prefix_for(&dir).is_none() {
+                return Err(Error::InvalidShardMap(format!("no shard for directory {}", dir)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ShardMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (prefix, path) in self.entries.iter() {
+            let prefix = if prefix.is_empty() { DEFAULT_PREFIX } else { prefix };
+            writeln!(f, "{} {}", prefix, path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// A summary of a rebalance, see [ShardedFileBlockArchive::rebalance].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RebalanceSummary {
+    /// The number of blocks that were checked.
+    pub checked: usize,
+    /// The number of blocks moved to the shard they belong in, or that would be in a dry run.
+    pub moved: usize,
+    /// The number of bytes of block data moved.
+    pub bytes: u64,
+}
+
+/// A block archive spread over several directories, usually on different volumes, so that an
+/// archive can be larger than a single disk.
+///
+/// Each directory is a [SimpleFileBasedBlockArchive], called a shard, and the [ShardMap] chooses
+/// the shard of each block from its hash. Several prefixes can share a shard.
+///
+/// When the map is changed, for example when a volume is added, blocks are left in their old
+/// shard until [ShardedFileBlockArchive::rebalance] moves them. Until then they can still be read,
+/// removed and have their attributes changed, as every shard is searched for a block that is not
+/// in the shard it belongs in.
+///
+/// Example code:
+///     let map = ShardMap::read(Path::new("/etc/blockarchive/shards")).await?;
+///     let archive = ShardedFileBlockArchive::new(map).await?;
+#[derive(Debug)]
+pub struct ShardedFileBlockArchive {
+    map: ShardMap,
+    shards: Vec<SimpleFileBasedBlockArchive>,
+    // the index in shards of each prefix in the map
+    routes: BTreeMap<String, usize>,
+}
+
+impl ShardedFileBlockArchive {
+    /// Open the shards of a map, prefixes with the same path share a shard.
+    ///
+    /// Fails with [Error::InvalidShardMap] if a block would not have a shard, and with
+    /// [Error::StorageUnavailable] if the directory of a shard does not exist.
+    pub async fn new(map: ShardMap) -> Result<ShardedFileBlockArchive> {
+        ShardedFileBlockArchive::open(map, false).await
+    }
+
+    /// Open the shards of a map read-only, see [SimpleFileBasedBlockArchive::open_read_only].
+    /// Nothing is written to the directories of the shards.
+    pub async fn open_read_only(map: ShardMap) -> Result<ShardedFileBlockArchive> {
+        ShardedFileBlockArchive::open(map, true).await
+    }
+
+    async fn open(map: ShardMap, read_only: bool) -> Result<ShardedFileBlockArchive> {
+        map.check()?;
+        let mut shards: Vec<SimpleFileBasedBlockArchive> = Vec::new();
+        let mut routes = BTreeMap::new();
+        for (prefix, path) in map.entries.iter() {
+            let i = match shards.iter().position(|s| &s.root_path == path) {
+                Some(i) => i,
+                None => {
+                    let shard = if read_only {
+                        SimpleFileBasedBlockArchive::open_read_only(path.clone()).await?
+                    } else {
+                        SimpleFileBasedBlockArchive::new(path.clone()).await?
+                    };
+                    shards.push(shard);
+                    shards.len() - 1
+                }
+            };
+            routes.insert(prefix.clone(), i);
+        }
+        Ok(ShardedFileBlockArchive { map, shards, routes })
+    }
+
+    /// Set the checks that are made on blocks before they are stored, the default is to make no
+    /// checks.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> ShardedFileBlockArchive {
+        self.shards = self.shards.into_iter().map(|s| s.with_write_policy(policy)).collect();
+        self
+    }
+
+    /// Set whether new blocks are stored compressed in every shard.
+    pub fn with_compression(mut self, compress: bool) -> ShardedFileBlockArchive {
+        self.shards = self.shards.into_iter().map(|s| s.with_compression(compress)).collect();
+        self
+    }
+
+    /// Set whether changes to every shard are refused with [Error::ReadOnly].
+    pub fn with_read_only(mut self, read_only: bool) -> ShardedFileBlockArchive {
+        self.shards = self.shards.into_iter().map(|s| s.with_read_only(read_only)).collect();
+        self
+    }
+
+    /// Get the map of the archive.
+    pub fn map(&self) -> &ShardMap {
+        &self.map
+    }
+
+    /// Get the shards, in the order of the first prefix of each in the map.
+    pub fn shards(&self) -> &[SimpleFileBasedBlockArchive] {
+        &self.shards
+    }
+
+    /// Move the blocks that are not in the shard they belong in, after the map has been changed.
+    ///
+    /// Each block is copied, checked against the original, and then the original is removed, so
+    /// an interrupted rebalance can be run again. If dry_run is set nothing is changed. The
+    /// progress function is called after each block is moved.
+    pub async fn rebalance<F>(&mut self, dry_run: bool, mut progress: F) -> Result<RebalanceSummary>
+        where F: FnMut(&BlockHash, &RebalanceSummary)
+    {
+        let mut summary = RebalanceSummary::default();
+        for i in 0..self.shards.len() {
+            let mut results = self.shards[i].block_list().await?;
+            while let Some(block_hash) = results.next().await {
+                summary.checked += 1;
+                let j = self.shard_index(&block_hash);
+                if j == i {
+                    continue;
+                }
+                let size = self.shards[i].block_size(&block_hash).await? as u64;
+                if !dry_run {
+                    move_block(&self.shards[i], &self.shards[j], &block_hash).await?;
+                }
+                summary.moved += 1;
+                summary.bytes += size;
+                progress(&block_hash, &summary);
+            }
+            if let Some(e) = results.as_mut().take_error() {
+                return Err(e);
+            }
+        }
+        Ok(summary)
+    }
+
+    // Get the index of the shard that a block belongs in.
+    fn shard_index(&self, block_hash: &BlockHash) -> usize {
+        // the map has been checked, every directory has a prefix
+        let prefix = self.map.prefix_for(&dir_name(block_hash)).unwrap_or_default();
+        self.routes[prefix]
+    }
+
+    // Get the shard that holds a block, or the shard it belongs in if no shard holds it.
+    async fn holder(&self, block_hash: &BlockHash) -> Result<&SimpleFileBasedBlockArchive> {
+        let home = self.shard_index(block_hash);
+        if self.shards[home].block_exists(block_hash).await? {
+            return Ok(&self.shards[home]);
+        }
+        for (i, shard) in self.shards.iter().enumerate() {
+            if i != home && shard.block_exists(block_hash).await? {
+                return Ok(shard);
+            }
+        }
+        Ok(&self.shards[home])
+    }
+
+    // Send the hashes from each stream in turn.
+    async fn block_list_bgrnd(streams: Vec<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>>, transmit: tokio::sync::mpsc::Sender<BlockHash>) -> Result<()> {
+        for mut stream in streams {
+            while let Some(hash) = stream.next().await {
+                if transmit.send(hash).await.is_err() {
+                    return Ok(());      // this is not an error, the receiver has merely dropped
+                }
+            }
+            if let Some(e) = stream.as_mut().take_error() {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockArchive for ShardedFileBlockArchive {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.holder(block_hash).await?.get_block(block_hash).await
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.holder(block_hash).await?.get_block_range(block_hash, offset, length).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.holder(block_hash).await?.block_exists(block_hash).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        check_blocks_exist(self, block_hashes, EXISTS_CONCURRENCY).await
+    }
+
+    /// A block that is in another shard, waiting to be rebalanced, is treated as already stored.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let home = &self.shards[self.shard_index(block_hash)];
+        if !std::ptr::eq(self.holder(block_hash).await?, home) {
+            return Err(Error::BlockExists);
+        }
+        home.store_block(block_hash, block).await
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.holder(block_hash).await?.remove_block(block_hash).await
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.holder(block_hash).await?.block_size(block_hash).await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.holder(block_hash).await?.block_header(block_hash).await
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        let mut headers = Vec::with_capacity(block_hashes.len());
+        for block_hash in block_hashes {
+            headers.push((*block_hash, self.block_header(block_hash).await?));
+        }
+        Ok(headers)
+    }
+
+    /// The shards are listed one after another. A block that is being moved by a rebalance may
+    /// be listed twice.
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let mut streams = Vec::new();
+        for shard in self.shards.iter_mut() {
+            streams.push(shard.block_list().await?);
+        }
+        let (tx, rx) = tokio::sync::mpsc::channel(LIST_BUFFER);
+        let handle = tokio::spawn(Self::block_list_bgrnd(streams, tx));
+        Ok(Box::pin(BlockHashListStreamFromChannel::new(rx, handle)))
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.holder(block_hash).await?.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.holder(block_hash).await?.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.holder(block_hash).await?.get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.holder(block_hash).await?.verify_checksum(block_hash).await
+    }
+}
+
+// Get the name of the first level directory of a block, the last two characters of its hash.
+fn dir_name(block_hash: &BlockHash) -> String {
+    let s: String = block_hash.encode_hex();
+    s[62..].to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hex::FromHex;
+    use mktemp::Temp;
+    use crate::sync::sync_archives;
+    use super::*;
+
+    #[test]
+    fn test_parse_shard_map() {
+        let map = ShardMap::parse("# shards\n0 /a\n\n31 /b\n* /c\n").unwrap();
+        let h = BlockHash::from_hex("00000000000000000124a294b9e1e65224f0636ffd4dadac777bed5e709dc531").unwrap();
+        assert_eq!(map.path_for(&h), Some(Path::new("/b")));
+        let h = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        assert_eq!(map.path_for(&h), Some(Path::new("/c")));
+        assert_eq!(ShardMap::parse(&map.to_string()).unwrap(), map);
+        assert!(ShardMap::parse("0 /a\n1 /b\n").unwrap().check().is_err());
+        assert!(ShardMap::parse("g /a\n").is_err());
+        assert!(ShardMap::parse("123 /a\n").is_err());
+        assert!(ShardMap::parse("/a\n").is_err());
+    }
+
+    // Blocks left in their old shard after a volume is added can be read, and a rebalance moves
+    // them to the new shard.
+    #[tokio::test]
+    async fn test_sharded_archive() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let (dir1, dir2) = (Temp::new_dir().unwrap(), Temp::new_dir().unwrap());
+        let mut map = ShardMap::new();
+        map.insert("", dir1.to_path_buf()).unwrap();
+        let archive = ShardedFileBlockArchive::new(map.clone()).await.unwrap();
+        let summary = sync_archives(&mut src, &archive, |_, _| {}).await.unwrap();
+        assert_eq!(summary.copied, 3);
+
+        // the block in directory "48" belongs in the new volume
+        map.insert("4", dir2.to_path_buf()).unwrap();
+        let mut archive = ShardedFileBlockArchive::new(map).await.unwrap();
+        assert_eq!(archive.shards().len(), 2);
+        let h = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        assert!(archive.block_exists(&h).await.unwrap());
+        assert!(matches!(archive.store_block(&h, &mut &[0u8; 80][..]).await, Err(Error::BlockExists)));
+        assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+        assert!(!archive.shards()[1].block_exists(&h).await.unwrap());
+
+        let summary = archive.rebalance(true, |_, _| {}).await.unwrap();
+        assert_eq!((summary.checked, summary.moved), (3, 1));
+        assert!(!archive.shards()[1].block_exists(&h).await.unwrap());
+        let summary = archive.rebalance(false, |_, _| {}).await.unwrap();
+        assert_eq!(summary.moved, 1);
+        assert!(archive.shards()[1].block_exists(&h).await.unwrap());
+        assert!(!archive.shards()[0].block_exists(&h).await.unwrap());
+        assert!(archive.verify_checksum(&h).await.unwrap());
+        let hashes: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(archive.rebalance(false, |_, _| {}).await.unwrap().moved, 0);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use bitcoinsv::bitcoin::BlockHash;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use crate::Result;
+
+// the size of a record in the sizes file, a tag, a block hash and a size
+const RECORD_LEN: usize = 1 + 32 + 8;
+
+// the tags of the records
+const TAG_SIZE: u8 = 0;
+const TAG_REMOVED: u8 = 1;
+
+/// A file holding the sizes of all the blocks in an archive, so that the size of the archive can
+/// be found without reading the size of each block.
+///
+/// The file is append-only like the headers file, a record is added for each block that is
+/// stored and for each block that is removed. The file is only read when a size is first needed,
+/// and then kept in memory. A partial record at the end of the file, from an interrupted append,
+/// is ignored and overwritten by the next append.
+#[derive(Debug)]
+pub(crate) struct SizesFile {
+    path: PathBuf,
+    // the sizes keyed by block hash, once the file has been read
+    sizes: Option<BTreeMap<[u8; 32], u64>>,
+}
+
+impl SizesFile {
+    pub(crate) fn new(path: PathBuf) -> SizesFile {
+        SizesFile { path, sizes: None }
+    }
+
+    // Check whether the file exists. Nothing is recorded if it does not.
+    pub(crate) async fn exists(&self) -> Result<bool> {
+        Ok(tokio::fs::try_exists(&self.path).await?)
+    }
+
+    // Get the sizes of all the blocks, or None if the file does not exist.
+    pub(crate) async fn all(&mut self) -> Result<Option<&BTreeMap<[u8; 32], u64>>> {
+        if self.sizes.is_none() {
+            self.sizes = self.read().await?;
+        }
+        Ok(self.sizes.as_ref())
+    }
+
+    // Record the size of a stored block, if the file exists.
+    pub(crate) async fn add(&mut self, block_hash: &BlockHash, size: u64) -> Result<()> {
+        if !self.exists().await? {
+            return Ok(());
+        }
+        self.append(&record(TAG_SIZE, block_hash, size)).await?;
+        if let Some(sizes) = self.sizes.as_mut() {
+            sizes.insert(block_hash.hash, size);
+        }
+        Ok(())
+    }
+
+    // Record that a block has been removed, if the file exists.
+    pub(crate) async fn remove(&mut self, block_hash: &BlockHash) -> Result<()> {
+        if !self.exists().await? {
+            return Ok(());
+        }
+        self.append(&record(TAG_REMOVED, block_hash, 0)).await?;
+        if let Some(sizes) = self.sizes.as_mut() {
+            sizes.remove(&block_hash.hash);
+        }
+        Ok(())
+    }
+
+    // Replace the file with one holding the given sizes, creating it if it does not exist.
+    pub(crate) async fn write(&mut self, sizes: &[(BlockHash, u64)]) -> Result<()> {
+        let mut buf = Vec::with_capacity(sizes.len() * RECORD_LEN);
+        for (block_hash, size) in sizes {
+            buf.extend_from_slice(&record(TAG_SIZE, block_hash, *size));
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(&buf).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        // read the new file when it is next needed
+        self.sizes = None;
+        Ok(())
+    }
+
+    // Read the file, returning None if it does not exist.
+    async fn read(&self) -> Result<Option<BTreeMap<[u8; 32], u64>>> {
+        let data = match tokio::fs::read(&self.path).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut sizes = BTreeMap::new();
+        for record in data.chunks_exact(RECORD_LEN) {
+            let hash: [u8; 32] = record[1..33].try_into().unwrap();
+            if record[0] == TAG_REMOVED {
+                sizes.remove(&hash);
+            } else {
+                sizes.insert(hash, u64::from_le_bytes(record[33..].try_into().unwrap()));
+            }
+        }
+        Ok(Some(sizes))
+    }
+
+    // Append a record, first cutting off any partial record left by an interrupted append.
+    async fn append(&self, record: &[u8]) -> Result<()> {
+        let mut file = OpenOptions::new().write(true).open(&self.path).await?;
+        let len = file.metadata().await?.len();
+        let whole = len - len % RECORD_LEN as u64;
+        if whole != len {
+            file.set_len(whole).await?;
+        }
+        file.seek(std::io::SeekFrom::End(0)).await?;
+        file.write_all(record).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+}
+
+// Encode a record.
+fn record(tag: u8, block_hash: &BlockHash, size: u64) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0] = tag;
+    record[1..33].copy_from_slice(&block_hash.hash);
+    record[33..].copy_from_slice(&size.to_le_bytes());
+    record
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hex::FromHex;
+    use mktemp::Temp;
+    use super::*;
+
+    // Nothing is recorded until the file is written, and removals and partial records are
+    // handled when the file is read back.
+    #[tokio::test]
+    async fn test_sizes_file() {
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let h2 = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let path = dir.to_path_buf().join("sizes.dat");
+        let mut file = SizesFile::new(path.clone());
+        file.add(&h, 100).await.unwrap();
+        assert!(!file.exists().await.unwrap());
+        assert!(file.all().await.unwrap().is_none());
+
+        file.write(&[(h, 100)]).await.unwrap();
+        file.add(&h2, 285).await.unwrap();
+        file.add(&h, 200).await.unwrap();
+        assert_eq!(file.all().await.unwrap().unwrap().values().sum::<u64>(), 485);
+        file.remove(&h2).await.unwrap();
+        // a partial record is overwritten by the next append
+        let mut f = OpenOptions::new().append(true).open(&path).await.unwrap();
+        f.write_all(&[TAG_SIZE, 1, 2, 3]).await.unwrap();
+        drop(f);
+        file.add(&h2, 300).await.unwrap();
+        assert_eq!(tokio::fs::metadata(&path).await.unwrap().len(), 5 * RECORD_LEN as u64);
+        let mut reopened = SizesFile::new(path);
+        let sizes = reopened.all().await.unwrap().unwrap();
+        assert_eq!(sizes.get(&h.hash), Some(&200));
+        assert_eq!(sizes.get(&h2.hash), Some(&300));
+    }
+}
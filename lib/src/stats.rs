@@ -0,0 +1,362 @@
+//! Statistics about the blocks in an archive.
+//!
+//! [collect_stats] gives the statistics of the whole archive, and [export_csv] writes the
+//! statistics of each block as CSV or TSV, for a spreadsheet or a simple script.
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use bitcoinsv::bitcoin::BlockHash;
+use serde_json::{json, Value};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use crate::{BlockArchive, Result};
+
+// The number of block summaries read at the same time.
+const SUMMARY_CONCURRENCY: usize = 16;
+
+// The number of seconds in a day.
+const DAY: u64 = 24 * 60 * 60;
+
+/// Statistics about the blocks in an archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ArchiveStats {
+    /// The number of blocks.
+    pub blocks: u64,
+    /// The total size of the blocks in bytes.
+    pub total_bytes: u64,
+    /// The size of the smallest block, zero if there are no blocks.
+    pub min_size: u64,
+    /// The size of the largest block, zero if there are no blocks.
+    pub max_size: u64,
+    /// The number of blocks by size, keyed by the power of ten at the bottom of the range. A block
+    /// of 2500 bytes is counted under 1000.
+    pub size_histogram: BTreeMap<u64, u64>,
+    /// The number of blocks by the year of their header timestamp.
+    pub by_year: BTreeMap<i32, u64>,
+    /// The largest blocks with their sizes, largest first.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash_pairs"))]
+    pub largest: Vec<(BlockHash, u64)>,
+    /// The total number of transactions, None if they were not counted, such as for the
+    /// statistics kept by the catalog.
+    pub transactions: Option<u64>,
+}
+
+impl ArchiveStats {
+    /// The average size of a block, zero if there are no blocks.
+    pub fn average_size(&self) -> u64 {
+        self.total_bytes.checked_div(self.blocks).unwrap_or(0)
+    }
+
+    /// Get the statistics as a JSON object.
+    pub fn to_json(&self) -> Value {
+        let histogram: BTreeMap<String, u64> = self.size_histogram.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+        let by_year: BTreeMap<String, u64> = self.by_year.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+        let largest: Vec<Value> = self.largest.iter()
+            .map(|(h, size)| json!({"block_hash": h.to_string(), "size": size}))
+            .collect();
+        json!({
+            "blocks": self.blocks,
+            "total_bytes": self.total_bytes,
+            "min_size": self.min_size,
+            "max_size": self.max_size,
+            "average_size": self.average_size(),
+            "size_histogram": histogram,
+            "by_year": by_year,
+            "largest": largest,
+            "transactions": self.transactions,
+        })
+    }
+}
+
+/// Walk the archive and collect statistics, keeping the given number of largest blocks.
+///
+/// The summary of every block is read with [BlockArchive::block_summary], which gives the size,
+/// header and number of transactions without reading the transactions.
+pub async fn collect_stats<A>(archive: &mut A, largest: usize) -> Result<ArchiveStats>
+    where A: BlockArchive + ?Sized
+{
+    let hashes = read_hashes(archive).await?;
+    let archive = &*archive;
+    let summaries = hashes.iter().map(|h| async move { (*h, archive.block_summary(h).await) });
+    let mut results = futures::StreamExt::buffer_unordered(tokio_stream::iter(summaries), SUMMARY_CONCURRENCY);
+    let mut collector = StatsCollector::new(largest);
+    while let Some((block_hash, r)) = results.next().await {
+        let summary = r?;
+        collector.add(block_hash, summary.size, summary.header.timestamp as u64);
+        collector.add_transactions(summary.tx_count);
+    }
+    Ok(collector.finish())
+}
+
+/// A column of the statistics of a block written by [export_csv].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum StatsColumn {
+    /// The block hash.
+    Hash,
+    /// The height of the block, empty if it is not linked to the genesis block.
+    Height,
+    /// The timestamp of the header, in seconds since the epoch.
+    Time,
+    /// The size of the block in bytes.
+    Size,
+    /// The number of transactions in the block.
+    TxCount,
+}
+
+impl StatsColumn {
+    /// All columns, in the order they are written by default.
+    pub const ALL: [StatsColumn; 5] = [StatsColumn::Hash, StatsColumn::Height, StatsColumn::Time, StatsColumn::Size, StatsColumn::TxCount];
+
+    /// The name of the column in the header row.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StatsColumn::Hash => "hash",
+            StatsColumn::Height => "height",
+            StatsColumn::Time => "time",
+            StatsColumn::Size => "size",
+            StatsColumn::TxCount => "tx_count",
+        }
+    }
+}
+
+/// The options of [export_csv].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CsvOptions {
+    /// The columns to write, in order.
+    pub columns: Vec<StatsColumn>,
+    /// The character between the values of a row, a comma for CSV or a tab for TSV.
+    pub delimiter: char,
+    /// Only write blocks at or above this height. Blocks without a height are left out if either
+    /// height is given.
+    pub from_height: Option<u64>,
+    /// Only write blocks at or below this height.
+    pub to_height: Option<u64>,
+    /// Only write blocks with a header timestamp at or after this time, in seconds since the epoch.
+    pub from_time: Option<u64>,
+    /// Only write blocks with a header timestamp at or before this time.
+    pub to_time: Option<u64>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions { columns: StatsColumn::ALL.to_vec(), delimiter: ',', from_height: None, to_height: None, from_time: None, to_time: None }
+    }
+}
+
+impl CsvOptions {
+    // Check whether a block is in the heights and times that are written.
+    fn selects(&self, height: Option<u64>, time: u64) -> bool {
+        let heights = match height {
+            Some(h) => in_range(h, self.from_height, self.to_height),
+            None => self.from_height.is_none() && self.to_height.is_none(),
+        };
+        heights && in_range(time, self.from_time, self.to_time)
+    }
+}
+
+// Check whether a value is between the bounds that are given, inclusive.
+fn in_range(value: u64, from: Option<u64>, to: Option<u64>) -> bool {
+    from.unwrap_or(0) <= value && value <= to.unwrap_or(u64::MAX)
+}
+
+/// Write the statistics of the blocks, given with their heights, as a header row and a row for
+/// each block, in the order the blocks are given. Returns the number of blocks written.
+///
+/// The statistics come from [BlockArchive::block_summary], so the transactions are not read.
+pub async fn export_csv<A, I, W>(archive: &A, blocks: I, writer: &mut W, options: &CsvOptions) -> Result<u64>
+    where A: BlockArchive + ?Sized, I: IntoIterator<Item=(BlockHash, Option<u64>)>, W: AsyncWrite + Unpin + ?Sized
+{
+    let delimiter = options.delimiter.to_string();
+    let header: Vec<&str> = options.columns.iter().map(|c| c.name()).collect();
+    writer.write_all(format!("{}\n", header.join(&delimiter)).as_bytes()).await?;
+    let summaries = blocks.into_iter().map(|(h, height)| async move { (h, height, archive.block_summary(&h).await) });
+    let mut results = futures::StreamExt::buffered(tokio_stream::iter(summaries), SUMMARY_CONCURRENCY);
+    let mut written = 0;
+    while let Some((block_hash, height, r)) = results.next().await {
+        let summary = r?;
+        let time = summary.header.timestamp as u64;
+        if !options.selects(height, time) {
+            continue;
+        }
+        let row: Vec<String> = options.columns.iter().map(|c| match c {
+            StatsColumn::Hash => block_hash.to_string(),
+            StatsColumn::Height => height.map_or(String::new(), |h| h.to_string()),
+            StatsColumn::Time => time.to_string(),
+            StatsColumn::Size => summary.size.to_string(),
+            StatsColumn::TxCount => summary.tx_count.to_string(),
+        }).collect();
+        writer.write_all(format!("{}\n", row.join(&delimiter)).as_bytes()).await?;
+        written += 1;
+    }
+    writer.flush().await?;
+    Ok(written)
+}
+
+// Collects the statistics one block at a time.
+pub(crate) struct StatsCollector {
+    stats: ArchiveStats,
+    // the number of largest blocks to keep
+    largest: usize,
+    // the largest blocks seen so far, smallest at the top
+    heap: BinaryHeap<Reverse<(u64, BlockHash)>>,
+}
+
+impl StatsCollector {
+    pub(crate) fn new(largest: usize) -> StatsCollector {
+        StatsCollector { stats: ArchiveStats::default(), largest, heap: BinaryHeap::new() }
+    }
+
+    // Add a block with its size and header timestamp.
+    pub(crate) fn add(&mut self, block_hash: BlockHash, size: u64, timestamp: u64) {
+        let stats = &mut self.stats;
+        if stats.blocks == 0 || size < stats.min_size {
+            stats.min_size = size;
+        }
+        stats.max_size = stats.max_size.max(size);
+        stats.blocks += 1;
+        stats.total_bytes += size;
+        *stats.size_histogram.entry(size_bucket(size)).or_default() += 1;
+        *stats.by_year.entry(year_of(timestamp)).or_default() += 1;
+        push_largest(&mut self.heap, self.largest, block_hash, size);
+    }
+
+    // Add the number of transactions of a block.
+    pub(crate) fn add_transactions(&mut self, tx_count: u64) {
+        *self.stats.transactions.get_or_insert(0) += tx_count;
+    }
+
+    pub(crate) fn finish(mut self) -> ArchiveStats {
+        self.stats.largest = self.heap.into_sorted_vec().into_iter().map(|Reverse((size, h))| (h, size)).collect();
+        self.stats
+    }
+}
+
+/// Get the given number of largest blocks with their sizes, largest first.
+///
+/// Only the sizes are read, so this is fast for an archive that keeps an index of the sizes, see
+/// [BlockArchive::block_sizes].
+pub async fn largest_blocks<A>(archive: &mut A, count: usize) -> Result<Vec<(BlockHash, u64)>>
+    where A: BlockArchive + ?Sized
+{
+    let mut heap = BinaryHeap::new();
+    let mut sizes = archive.block_sizes().await?;
+    while let Some(r) = sizes.next().await {
+        let (block_hash, size) = r?;
+        push_largest(&mut heap, count, block_hash, size);
+    }
+    Ok(heap.into_sorted_vec().into_iter().map(|Reverse((size, h))| (h, size)).collect())
+}
+
+// List all the blocks.
+async fn read_hashes<A: BlockArchive + ?Sized>(archive: &mut A) -> Result<Vec<BlockHash>> {
+    let mut hashes = Vec::new();
+    let mut results = archive.block_list().await?;
+    while let Some(h) = results.next().await {
+        hashes.push(h);
+    }
+    match results.as_mut().take_error() {
+        Some(e) => Err(e),
+        None => Ok(hashes),
+    }
+}
+
+// Add a block to a heap of the largest blocks, smallest at the top, keeping at most count blocks.
+fn push_largest(heap: &mut BinaryHeap<Reverse<(u64, BlockHash)>>, count: usize, block_hash: BlockHash, size: u64) {
+    heap.push(Reverse((size, block_hash)));
+    if heap.len() > count {
+        heap.pop();
+    }
+}
+
+// Get the power of ten at the bottom of the histogram range for a size.
+fn size_bucket(size: u64) -> u64 {
+    if size == 0 {
+        return 0;
+    }
+    10u64.pow(size.ilog10())
+}
+
+// Get the year of a timestamp in seconds since the epoch.
+fn year_of(timestamp: u64) -> i32 {
+    // the civil from days algorithm, with years starting on the 1st of March
+    let z = (timestamp / DAY) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let year = yoe + era * 400;
+    // January and February belong to the next year
+    if mp >= 10 { year as i32 + 1 } else { year as i32 }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        assert_eq!(size_bucket(0), 0);
+        assert_eq!(size_bucket(285), 100);
+        assert_eq!(size_bucket(1000), 1000);
+        assert_eq!(size_bucket(2500), 1000);
+        assert_eq!(year_of(0), 1970);
+        assert_eq!(year_of(946684799), 1999);
+        assert_eq!(year_of(951782400), 2000);
+        assert_eq!(year_of(1231006505), 2009);
+    }
+
+    #[tokio::test]
+    async fn test_collect_stats() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let hashes: Vec<BlockHash> = archive.block_list().await.unwrap().collect().await;
+        let stats = collect_stats(&mut archive, 2).await.unwrap();
+        assert_eq!(stats.blocks, hashes.len() as u64);
+        assert_eq!(stats.size_histogram.values().sum::<u64>(), stats.blocks);
+        assert_eq!(stats.by_year.values().sum::<u64>(), stats.blocks);
+        assert_eq!(stats.by_year.get(&2009), Some(&2));
+        assert!(stats.transactions.unwrap() >= stats.blocks);
+        assert_eq!(stats.largest.len(), 2);
+        assert_eq!(stats.largest[0].1, stats.max_size);
+        assert!(stats.largest[0].1 >= stats.largest[1].1);
+        assert!(stats.min_size <= stats.average_size() && stats.average_size() <= stats.max_size);
+        assert_eq!(stats.to_json()["blocks"], json!(stats.blocks));
+        assert_eq!(collect_stats(&mut archive, 0).await.unwrap().largest.len(), 0);
+        assert_eq!(largest_blocks(&mut archive, 2).await.unwrap(), stats.largest);
+    }
+
+    // Write the rows of two blocks, then select the columns and filter by height and time.
+    #[tokio::test]
+    async fn test_export_csv() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let blocks = vec![(genesis, Some(0)), (h, None)];
+        let size = archive.block_size(&genesis).await.unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(export_csv(&archive, blocks.clone(), &mut out, &CsvOptions::default()).await.unwrap(), 2);
+        let lines: Vec<String> = String::from_utf8(out).unwrap().lines().map(|l| l.to_string()).collect();
+        assert_eq!(lines[0], "hash,height,time,size,tx_count");
+        assert_eq!(lines[1], format!("{},0,1231006505,{},1", genesis, size));
+        assert!(lines[2].starts_with(&format!("{},,", h)));
+        assert_eq!(lines.len(), 3);
+
+        let options = CsvOptions { columns: vec![StatsColumn::Size, StatsColumn::Hash], delimiter: '\t', from_height: Some(0), ..Default::default() };
+        let mut out = Vec::new();
+        assert_eq!(export_csv(&archive, blocks.clone(), &mut out, &options).await.unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap(), format!("size\thash\n{}\t{}\n", size, genesis));
+
+        let options = CsvOptions { columns: vec![StatsColumn::Hash], from_time: Some(1231006506), ..Default::default() };
+        let mut out = Vec::new();
+        assert_eq!(export_csv(&archive, blocks, &mut out, &options).await.unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap(), format!("hash\n{}\n", h));
+    }
+}
@@ -0,0 +1,169 @@
+//! Copying blocks between archives.
+//!
+//! The archives can be of different types, for example to move blocks from a
+//! [crate::SimpleFileBasedBlockArchive] into object storage. The headers of the blocks can also
+//! be copied to a [HeaderArchive].
+use std::collections::BTreeMap;
+use bitcoinsv::bitcoin::BlockHash;
+use tokio::io::AsyncReadExt;
+use tokio_stream::StreamExt;
+use crate::{BlockArchive, Error, HeaderArchive, Result};
+
+/// A summary of a sync.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SyncSummary {
+    /// The number of blocks copied to the destination.
+    pub copied: usize,
+    /// The number of bytes of block data copied.
+    pub bytes: u64,
+    /// The number of blocks that were already in the destination.
+    pub skipped: usize,
+}
+
+/// Copy every block in the source archive that is missing from the destination archive.
+///
+/// Block data is streamed from one archive to the other, blocks are never held in memory in full.
+/// The attributes of each copied block are copied too. Blocks that are already in the destination
+/// are not compared or changed. The progress function is called after each block is copied or
+/// skipped.
+pub async fn sync_archives<S, D, F>(src: &mut S, dst: &D, mut progress: F) -> Result<SyncSummary>
+    where S: BlockArchive + ?Sized, D: BlockArchive + ?Sized, F: FnMut(&BlockHash, &SyncSummary)
+{
+    let mut summary = SyncSummary::default();
+    let mut results = src.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        if dst.block_exists(&block_hash).await? {
+            summary.skipped += 1;
+            progress(&block_hash, &summary);
+            continue;
+        }
+        let mut reader = src.get_block(&block_hash).await?;
+        match dst.store_block(&block_hash, &mut reader).await {
+            Ok(_) => {}
+            // another writer got there first
+            Err(Error::BlockExists) => {
+                summary.skipped += 1;
+                progress(&block_hash, &summary);
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        for (key, value) in src.get_block_attrs(&block_hash).await?.iter() {
+            dst.set_block_attr(&block_hash, key, value).await?;
+        }
+        summary.copied += 1;
+        summary.bytes += dst.block_size(&block_hash).await? as u64;
+        progress(&block_hash, &summary);
+    }
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    Ok(summary)
+}
+
+/// A summary of [sync_headers].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeaderSyncSummary {
+    /// The number of headers copied to the header archive.
+    pub copied: usize,
+    /// The number of headers that were already in the header archive.
+    pub skipped: usize,
+    /// Blocks whose headers were not copied because their parent is not in either archive, in
+    /// hash order. Only a chain-linked header archive rejects these.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hashes"))]
+    pub unlinked: Vec<BlockHash>,
+}
+
+/// Copy the header of every block in the source archive that is missing from the header archive.
+///
+/// Only the first 80 bytes of each block are read. A chain-linked header archive only accepts a
+/// header once it has the parent, so a header whose parent has not been copied yet is held back
+/// until it has. The progress function is called after each header is copied or skipped.
+pub async fn sync_headers<S, H, F>(src: &mut S, dst: &H, mut progress: F) -> Result<HeaderSyncSummary>
+    where S: BlockArchive + ?Sized, H: HeaderArchive + ?Sized, F: FnMut(&BlockHash, &HeaderSyncSummary)
+{
+    let mut summary = HeaderSyncSummary::default();
+    // the headers that are waiting for their parent, keyed by the hash of the parent
+    let mut waiting: BTreeMap<BlockHash, Vec<(BlockHash, [u8; 80])>> = BTreeMap::new();
+    let mut results = src.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        if dst.header_exists(&block_hash).await? {
+            summary.skipped += 1;
+            progress(&block_hash, &summary);
+            continue;
+        }
+        let mut header = [0u8; 80];
+        src.get_block_range(&block_hash, 0, 80).await?.read_exact(&mut header).await?;
+        if BlockHash::sha256d(&header) != block_hash {
+            return Err(Error::CorruptBlock(block_hash));
+        }
+        // storing a header can release the headers waiting for it, and they can release others
+        let mut queue = vec![(block_hash, header)];
+        while let Some((block_hash, header)) = queue.pop() {
+            match dst.store_header(&header).await {
+                Ok(_) => summary.copied += 1,
+                // another writer got there first
+                Err(Error::BlockExists) => summary.skipped += 1,
+                Err(Error::MissingParent(parent)) => {
+                    waiting.entry(parent).or_default().push((block_hash, header));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            progress(&block_hash, &summary);
+            queue.extend(waiting.remove(&block_hash).unwrap_or_default());
+        }
+    }
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    summary.unlinked = waiting.into_values().flatten().map(|(h, _)| h).collect();
+    summary.unlinked.sort();
+    Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use mktemp::Temp;
+    use crate::{Manifest, SimpleFileBasedBlockArchive, SledHeaderArchive};
+    use super::*;
+
+    // Sync the test archive into an empty, compressed, archive and then sync again.
+    #[tokio::test]
+    async fn test_sync_archives() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let root = Temp::new_dir().unwrap();
+        let dst = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap().with_compression(true);
+        let mut calls = 0;
+        let summary = sync_archives(&mut src, &dst, |_, _| calls += 1).await.unwrap();
+        assert_eq!(calls, 3);
+        assert_eq!(summary.copied, 3);
+        assert_eq!(summary.skipped, 0);
+        let manifest = Manifest::generate(&mut src).await.unwrap();
+        assert!(manifest.verify(&dst).await.unwrap().is_ok());
+        let summary = sync_archives(&mut src, &dst, |_, _| {}).await.unwrap();
+        assert_eq!(summary, SyncSummary { copied: 0, bytes: 0, skipped: 3 });
+    }
+
+    // Sync the headers of the test archive into a chain-linked header archive, only the genesis
+    // block and block 1 are linked.
+    #[tokio::test]
+    async fn test_sync_headers() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let dst = SledHeaderArchive::open(&dir.to_path_buf()).unwrap().with_chain_linkage(true);
+        let summary = sync_headers(&mut src, &dst, |_, _| {}).await.unwrap();
+        let block1 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        assert_eq!(summary.copied, 2);
+        assert_eq!(summary.unlinked.len(), 1);
+        assert_eq!(dst.tip().await.unwrap(), Some((block1, 1)));
+        let summary = sync_headers(&mut src, &dst, |_, _| {}).await.unwrap();
+        assert_eq!(summary.copied, 0);
+        assert_eq!(summary.skipped, 2);
+    }
+}
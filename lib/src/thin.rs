@@ -0,0 +1,263 @@
+//! Thin blocks, which keep only the header and the txids of a block.
+//!
+//! A thin block is enough to know which transactions are in a block and to make merkle proofs for
+//! them, at a small fraction of the size of the block. Thinning a block with [thin_block] stores
+//! the thin block in a [ThinBlockStore] and removes the full block from the archive. The full
+//! block can be fetched again from a peer with [rehydrate_block].
+//!
+//! Example code:
+//!     let store = ThinBlockStore::open(Path::new("/mnt/blockstore/thin"))?;
+//!     thin_block(&archive, &store, &block_hash).await?;
+//!     let proof = store.get(&block_hash)?.unwrap().merkle_proof(&txid)?;
+use std::path::Path;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader, Encodable};
+use hex::{FromHex, ToHex};
+use tokio::io::AsyncRead;
+use crate::fetch::P2PFetcher;
+use crate::headers::HEADER_SIZE;
+use crate::merkle::{compute_merkle_root, merkle_proof_from_txids, validate_no_duplicate_vulnerability, write_varint, MerkleProof, TxHash};
+use crate::txindex::{read_bytes, read_tx, read_varint, take, take_varint};
+use crate::{BlockArchive, Error, Result};
+
+/// The header and the txids of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThinBlock {
+    /// The encoded header of the block.
+    pub header: [u8; 80],
+    /// The txids of the transactions in the block, in block order.
+    pub txids: Vec<TxHash>,
+}
+
+impl ThinBlock {
+    /// Read an encoded block, keeping the header and computing the txids.
+    pub async fn read<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<ThinBlock> {
+        let mut buf = Vec::new();
+        read_bytes(reader, &mut buf, HEADER_SIZE as u64).await?;
+        let header = buf[..].try_into().unwrap();
+        let num_tx = read_varint(reader, &mut buf).await?;
+        let mut txids = Vec::new();
+        for _ in 0..num_tx {
+            buf.clear();
+            read_tx(reader, &mut buf).await?;
+            txids.push(BlockHash::sha256d(&buf));
+        }
+        Ok(ThinBlock { header, txids })
+    }
+
+    /// Decode a thin block from the format written by [ThinBlock::to_bytes].
+    pub fn from_bytes(bytes: &[u8]) -> Option<ThinBlock> {
+        let mut cursor = bytes;
+        let header = take(&mut cursor, HEADER_SIZE as u64)?.try_into().ok()?;
+        let num_tx = take_varint(&mut cursor)?;
+        let txids = take(&mut cursor, num_tx.checked_mul(32)?)?.chunks_exact(32)
+            .map(|h| BlockHash { hash: h.try_into().unwrap() })
+            .collect();
+        if !cursor.is_empty() {
+            return None;
+        }
+        Some(ThinBlock { header, txids })
+    }
+
+    /// Encode the thin block as the header, the number of txids as a varint, and the txids.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header.to_vec();
+        write_varint(&mut bytes, self.txids.len() as u64);
+        for txid in self.txids.iter() {
+            bytes.extend_from_slice(&txid.hash);
+        }
+        bytes
+    }
+
+    /// Get the hash of the block.
+    pub fn block_hash(&self) -> BlockHash {
+        BlockHash::sha256d(&self.header)
+    }
+
+    /// Get the decoded header of the block.
+    pub async fn block_header(&self) -> Result<BlockHeader> {
+        Ok(BlockHeader::from_binary(&mut &self.header[..]).await?)
+    }
+
+    /// Check that the txids have the merkle root in the header, and are not a list that only
+    /// matches it because of a repeated pair (CVE-2012-2459).
+    pub async fn verify(&self) -> Result<bool> {
+        let merkle_root = self.block_header().await?.merkle_root;
+        Ok(compute_merkle_root(self.txids.iter().copied()) == Some(merkle_root)
+            && validate_no_duplicate_vulnerability(self.txids.iter().copied()))
+    }
+
+    /// Make a proof that a transaction is in the block.
+    ///
+    /// Fails with [Error::TxNotFound] if the transaction is not in the block.
+    pub fn merkle_proof(&self, txid: &TxHash) -> Result<MerkleProof> {
+        merkle_proof_from_txids(&self.block_hash(), &self.txids, txid)
+    }
+}
+
+/// The thin blocks of an archive, kept in a sled database.
+pub struct ThinBlockStore {
+    // maps the hex block hash to the encoded thin block
+    db: sled::Db,
+}
+
+impl ThinBlockStore {
+    /// Open the store in the given directory, creating it if necessary.
+    pub fn open(path: &Path) -> Result<ThinBlockStore> {
+        Ok(ThinBlockStore { db: sled::open(path).map_err(thin_error)? })
+    }
+
+    /// Get the thin block of a block, or None if it is not in the store.
+    pub fn get(&self, block_hash: &BlockHash) -> Result<Option<ThinBlock>> {
+        let key: String = block_hash.encode_hex();
+        match self.db.get(key).map_err(thin_error)? {
+            Some(v) => ThinBlock::from_bytes(&v).map(Some)
+                .ok_or_else(|| Error::InvalidThinBlock(format!("damaged thin block {}", block_hash))),
+            None => Ok(None),
+        }
+    }
+
+    /// Check if the thin block of a block is in the store.
+    pub fn contains(&self, block_hash: &BlockHash) -> Result<bool> {
+        let key: String = block_hash.encode_hex();
+        self.db.contains_key(key).map_err(thin_error)
+    }
+
+    /// Add a thin block to the store, replacing any thin block of the same block.
+    pub fn insert(&self, thin: &ThinBlock) -> Result<()> {
+        let key: String = thin.block_hash().encode_hex();
+        self.db.insert(key, thin.to_bytes()).map_err(thin_error)?;
+        Ok(())
+    }
+
+    /// Remove the thin block of a block, returning false if it was not in the store.
+    pub fn remove(&self, block_hash: &BlockHash) -> Result<bool> {
+        let key: String = block_hash.encode_hex();
+        Ok(self.db.remove(key).map_err(thin_error)?.is_some())
+    }
+
+    /// Get the hashes of the blocks in the store, in order of their hex encoded hash.
+    pub fn block_hashes(&self) -> Result<Vec<BlockHash>> {
+        let mut hashes = Vec::new();
+        for key in self.db.iter().keys() {
+            let key = key.map_err(thin_error)?;
+            hashes.push(BlockHash::from_hex(&key[..]).map_err(|_| Error::InvalidThinBlock("invalid block hash".to_string()))?);
+        }
+        Ok(hashes)
+    }
+
+    /// Flush the store to disk.
+    pub async fn flush(&self) -> Result<()> {
+        self.db.flush_async().await.map_err(thin_error)?;
+        Ok(())
+    }
+}
+
+/// Replace a block in the archive with its thin block.
+///
+/// The thin block is written to the store before the block is removed from the archive. Fails
+/// with [Error::CorruptBlock] if the block does not have the given hash or its txids do not match
+/// the merkle root.
+pub async fn thin_block<A: BlockArchive + ?Sized>(archive: &A, store: &ThinBlockStore, block_hash: &BlockHash) -> Result<ThinBlock> {
+    let thin = match ThinBlock::read(&mut archive.get_block(block_hash).await?).await {
+        Ok(t) => t,
+        Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(Error::CorruptBlock(*block_hash)),
+        Err(e) => return Err(e),
+    };
+    if thin.block_hash() != *block_hash || !thin.verify().await? {
+        return Err(Error::CorruptBlock(*block_hash));
+    }
+    store.insert(&thin)?;
+    store.flush().await?;
+    archive.remove_block(block_hash).await?;
+    Ok(thin)
+}
+
+/// Fetch a thinned block from a peer and store it in the archive, removing its thin block.
+///
+/// The fetched block must have the txids of the thin block, otherwise it is removed from the
+/// archive again and this fails with [Error::CorruptBlock]. Returns false if the peer does not
+/// have the block. Fails with [Error::BlockNotFound] if the block is not in the store.
+pub async fn rehydrate_block<A>(archive: &A, store: &ThinBlockStore, fetcher: &mut P2PFetcher, block_hash: &BlockHash) -> Result<bool>
+    where A: BlockArchive + ?Sized
+{
+    let thin = store.get(block_hash)?.ok_or(Error::BlockNotFound)?;
+    if !archive.block_exists(block_hash).await? && !fetcher.fetch_block(block_hash, archive).await? {
+        return Ok(false);
+    }
+    let fetched = match ThinBlock::read(&mut archive.get_block(block_hash).await?).await {
+        Ok(t) => Some(t),
+        Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+        Err(e) => return Err(e),
+    };
+    if fetched.as_ref() != Some(&thin) {
+        archive.remove_block(block_hash).await?;
+        return Err(Error::CorruptBlock(*block_hash));
+    }
+    store.remove(block_hash)?;
+    Ok(true)
+}
+
+fn thin_error(e: sled::Error) -> Error {
+    Error::InvalidThinBlock(e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use tokio_stream::StreamExt;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // Make a block whose transactions are the coinbase transactions of the test archive, as each
+    // block in the test archive has only one transaction.
+    async fn make_block() -> (BlockHash, Vec<u8>) {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let hashes: Vec<BlockHash> = src.block_list().await.unwrap().collect().await;
+        let mut header = Vec::new();
+        let mut txids = Vec::new();
+        let mut txs = Vec::new();
+        for h in hashes.iter() {
+            let mut block = Vec::new();
+            src.get_block(h).await.unwrap().read_to_end(&mut block).await.unwrap();
+            header = block[..HEADER_SIZE].to_vec();
+            // skip the transaction count, which is one byte
+            txids.push(BlockHash::sha256d(&block[HEADER_SIZE + 1..]));
+            txs.extend_from_slice(&block[HEADER_SIZE + 1..]);
+        }
+        header[36..68].copy_from_slice(&compute_merkle_root(txids.into_iter()).unwrap().hash);
+        let mut block = header;
+        write_varint(&mut block, hashes.len() as u64);
+        block.extend_from_slice(&txs);
+        (BlockHash::sha256d(&block[..HEADER_SIZE]), block)
+    }
+
+    // Thin a block with several transactions, make a proof from the thin block, and check that
+    // the thin block is encoded and decoded unchanged.
+    #[tokio::test]
+    async fn test_thin_block() {
+        let (h, block) = make_block().await;
+        let src = MemoryBlockArchive::new();
+        src.store_block(&h, &mut &block[..]).await.unwrap();
+        let archive = MemoryBlockArchive::new();
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let store = ThinBlockStore::open(&dir.to_path_buf()).unwrap();
+
+        let thin = thin_block(&archive, &store, &h).await.unwrap();
+        assert!(thin.txids.len() > 1);
+        assert!(!archive.block_exists(&h).await.unwrap());
+        assert_eq!(store.get(&h).unwrap(), Some(thin.clone()));
+        assert_eq!(store.block_hashes().unwrap(), vec![h]);
+        assert_eq!(ThinBlock::from_bytes(&thin.to_bytes()), Some(thin.clone()));
+        assert_eq!(ThinBlock::from_bytes(&thin.to_bytes()[..100]), None);
+
+        let txid = thin.txids[1];
+        let proof = thin.merkle_proof(&txid).unwrap();
+        assert_eq!(proof, crate::merkle::merkle_proof(&src, &h, &txid).await.unwrap());
+        assert!(proof.verify(&thin.block_header().await.unwrap().merkle_root));
+        assert!(matches!(thin_block(&archive, &store, &h).await, Err(Error::BlockNotFound)));
+    }
+}
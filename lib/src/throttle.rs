@@ -0,0 +1,275 @@
+//! Limiting the bandwidth and the operations per second used on an archive.
+//!
+//! Long running jobs, such as checking every block or copying an archive, can use all the
+//! bandwidth of the storage or all of the request budget of an object store. Wrap the archive in
+//! a [ThrottledBlockArchive] to limit them. A [RateLimiter] can be shared by several archives, so
+//! that the limits apply to all of them together.
+//!
+//! Example code:
+//!     let limiter = RateLimiter::new(Some(50 * 1024 * 1024), Some(100));
+//!     let archive = ThrottledBlockArchive::new(archive, limiter.clone());
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::Sleep;
+use crate::block_archive::{BlockHashListStream, BlockSizeStream};
+use crate::{BlockArchive, BlockAttrs, Result};
+
+/// Limits on the bytes per second and the operations per second, shared by its clones.
+///
+/// Each limit is a token bucket that holds up to one second of its rate, so short bursts are
+/// allowed. A request larger than the bucket is allowed, and the requests after it wait until it
+/// has been paid for.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    bytes: Option<Arc<Mutex<Bucket>>>,
+    ops: Option<Arc<Mutex<Bucket>>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter, None or zero for no limit.
+    pub fn new(bytes_per_sec: Option<u64>, ops_per_sec: Option<u64>) -> RateLimiter {
+        let bucket = |rate: Option<u64>| rate.filter(|r| *r > 0).map(|r| Arc::new(Mutex::new(Bucket::new(r as f64))));
+        RateLimiter { bytes: bucket(bytes_per_sec), ops: bucket(ops_per_sec) }
+    }
+
+    /// Check whether the limiter has any limit.
+    pub fn is_limited(&self) -> bool {
+        self.bytes.is_some() || self.ops.is_some()
+    }
+
+    /// Wait until an operation is allowed.
+    pub async fn acquire_op(&self) {
+        if let Some(ops) = &self.ops {
+            let wait = ops.lock().unwrap().reserve(1.0);
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+
+    // Take bytes from the bucket, returning how long to wait before the next bytes.
+    fn reserve_bytes(&self, bytes: u64) -> Duration {
+        match &self.bytes {
+            Some(b) => b.lock().unwrap().reserve(bytes as f64),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+// A token bucket, the tokens go negative when a request is larger than the tokens available.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Bucket {
+        Bucket { rate, tokens: rate, last: Instant::now() }
+    }
+
+    // Take tokens, returning how long to wait until the bucket is no longer in debt.
+    fn reserve(&mut self, amount: f64) -> Duration {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.last = now;
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// A block archive whose operations and block data are limited by a [RateLimiter].
+///
+/// Every operation waits for the operations limit, and the bytes of blocks are limited as they
+/// are read from or written to the archive.
+pub struct ThrottledBlockArchive<A: BlockArchive> {
+    archive: A,
+    limiter: RateLimiter,
+}
+
+impl<A: BlockArchive> ThrottledBlockArchive<A> {
+    /// Wrap an archive.
+    pub fn new(archive: A, limiter: RateLimiter) -> ThrottledBlockArchive<A> {
+        ThrottledBlockArchive { archive, limiter }
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for ThrottledBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.limiter.acquire_op().await;
+        let reader = self.archive.get_block(block_hash).await?;
+        Ok(Box::new(ThrottledReader::new(reader, self.limiter.clone())))
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.limiter.acquire_op().await;
+        let reader = self.archive.get_block_range(block_hash, offset, length).await?;
+        Ok(Box::new(ThrottledReader::new(reader, self.limiter.clone())))
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.limiter.acquire_op().await;
+        self.archive.block_exists(block_hash).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        self.limiter.acquire_op().await;
+        self.archive.blocks_exist(block_hashes).await
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        self.limiter.acquire_op().await;
+        let mut reader = ThrottledReader::new(block, self.limiter.clone());
+        self.archive.store_block(block_hash, &mut reader).await
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.limiter.acquire_op().await;
+        self.archive.remove_block(block_hash).await
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.limiter.acquire_op().await;
+        self.archive.block_size(block_hash).await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.limiter.acquire_op().await;
+        self.archive.block_header(block_hash).await
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        self.limiter.acquire_op().await;
+        self.archive.block_headers(block_hashes).await
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.limiter.acquire_op().await;
+        self.archive.block_list().await
+    }
+
+    async fn block_sizes<'a>(&'a mut self) -> Result<BlockSizeStream<'a>> {
+        self.limiter.acquire_op().await;
+        self.archive.block_sizes().await
+    }
+
+    async fn total_size(&mut self) -> Result<u64> {
+        self.limiter.acquire_op().await;
+        self.archive.total_size().await
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.limiter.acquire_op().await;
+        self.archive.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.limiter.acquire_op().await;
+        self.archive.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.limiter.acquire_op().await;
+        self.archive.get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.limiter.acquire_op().await;
+        self.archive.verify_checksum(block_hash).await
+    }
+}
+
+// A reader that waits after each read until the bytes read are within the bandwidth limit.
+struct ThrottledReader<R> {
+    inner: R,
+    limiter: RateLimiter,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> ThrottledReader<R> {
+    fn new(inner: R, limiter: RateLimiter) -> ThrottledReader<R> {
+        ThrottledReader { inner, limiter, sleep: None }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if let Some(sleep) = self.sleep.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.sleep = None;
+        }
+        let before = buf.filled().len();
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        if read > 0 {
+            let wait = self.limiter.reserve_bytes(read);
+            if !wait.is_zero() {
+                self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        r
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use tokio::io::AsyncReadExt;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // A burst of up to one second of operations is allowed at once, after that they are paced.
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let limiter = RateLimiter::new(None, Some(20));
+        assert!(limiter.is_limited());
+        let start = Instant::now();
+        for _ in 0..20 {
+            limiter.acquire_op().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(200));
+        for _ in 0..5 {
+            limiter.acquire_op().await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(!RateLimiter::new(Some(0), None).is_limited());
+    }
+
+    // Reading a block that is larger than one second of bandwidth waits for the rest of it.
+    #[tokio::test]
+    async fn test_throttled_archive() {
+        let inner = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let size = inner.block_size(&h).await.unwrap() as u64;
+        // the block is a quarter larger than one second of bandwidth, so its end waits for a quarter
+        // of a second
+        let archive = ThrottledBlockArchive::new(inner, RateLimiter::new(Some(size * 4 / 5), None));
+        let start = Instant::now();
+        let mut block = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        assert_eq!(block.len() as u64, size);
+        archive.get_block(&h).await.unwrap().read_u8().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
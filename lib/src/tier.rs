@@ -0,0 +1,190 @@
+//! Moving old blocks from a hot archive to a cold archive.
+//!
+//! This is used with a [crate::LayeredBlockArchive] where the top layer is fast storage and a
+//! lower layer is cheaper, slower, storage. Blocks selected by a [TierPolicy] are copied to the
+//! cold archive, the copy is checked, and only then is the block removed from the hot archive.
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bitcoinsv::bitcoin::BlockHash;
+use tokio_stream::StreamExt;
+use crate::manifest::sha256_reader;
+use crate::{BlockArchive, ChainIndex, Error, Result};
+
+// The number of seconds in a day.
+const DAY: u64 = 24 * 60 * 60;
+
+/// Which blocks to move to the cold archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TierPolicy {
+    /// Blocks in the best chain with a height below this height. Written as "height:N".
+    BelowHeight(u64),
+    /// Blocks with a header timestamp older than this many days. Written as "age:N".
+    OlderThanDays(u64),
+}
+
+impl TierPolicy {
+    /// Check whether a block is selected by the policy, at the time now in seconds since the epoch.
+    pub async fn selects<A>(&self, archive: &A, chain: &ChainIndex, block_hash: &BlockHash, now: u64) -> Result<bool>
+        where A: BlockArchive + ?Sized
+    {
+        match self {
+            TierPolicy::BelowHeight(height) => {
+                Ok(chain.is_in_best_chain(block_hash) && chain.height_of(block_hash).is_some_and(|h| h < *height))
+            }
+            TierPolicy::OlderThanDays(days) => {
+                let timestamp = archive.block_header(block_hash).await?.timestamp as u64;
+                Ok(timestamp + days * DAY < now)
+            }
+        }
+    }
+}
+
+impl FromStr for TierPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TierPolicy> {
+        let (kind, value) = s.split_once(':').ok_or_else(|| Error::InvalidPolicy(s.to_string()))?;
+        let value = value.parse::<u64>().map_err(|_| Error::InvalidPolicy(s.to_string()))?;
+        match kind {
+            "height" => Ok(TierPolicy::BelowHeight(value)),
+            "age" => Ok(TierPolicy::OlderThanDays(value)),
+            _ => Err(Error::InvalidPolicy(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for TierPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TierPolicy::BelowHeight(h) => write!(f, "height:{}", h),
+            TierPolicy::OlderThanDays(d) => write!(f, "age:{}", d),
+        }
+    }
+}
+
+/// A summary of a tiering run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TierSummary {
+    /// The number of blocks in the hot archive that were checked against the policy.
+    pub checked: usize,
+    /// The number of blocks moved to the cold archive, or that would be moved in a dry run.
+    pub moved: usize,
+    /// The number of bytes of block data moved.
+    pub bytes: u64,
+}
+
+/// Move the blocks in the hot archive that are selected by the policy to the cold archive.
+///
+/// Each block is copied with its attributes, the copy is checked against the original, and then
+/// the original is removed. A block that is already in the cold archive is checked in the same
+/// way before the original is removed. If a copy does not match, the copy is removed and the
+/// function fails with [Error::ChecksumMismatch], leaving the original in place.
+///
+/// The chain index is used by height policies and should cover both archives. If dry_run is set
+/// nothing is changed. The progress function is called after each block is moved.
+pub async fn tier_blocks<H, C, F>(hot: &mut H, cold: &C, chain: &ChainIndex, policy: &TierPolicy, dry_run: bool, mut progress: F) -> Result<TierSummary>
+    where H: BlockArchive + ?Sized, C: BlockArchive + ?Sized, F: FnMut(&BlockHash, &TierSummary)
+{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut summary = TierSummary::default();
+    let mut results = hot.block_list().await?;
+    while let Some(block_hash) = results.next().await {
+        summary.checked += 1;
+        if !policy.selects(&*hot, chain, &block_hash, now).await? {
+            continue;
+        }
+        let size = hot.block_size(&block_hash).await? as u64;
+        if !dry_run {
+            move_block(&*hot, cold, &block_hash).await?;
+        }
+        summary.moved += 1;
+        summary.bytes += size;
+        progress(&block_hash, &summary);
+    }
+    if let Some(e) = results.as_mut().take_error() {
+        return Err(e);
+    }
+    Ok(summary)
+}
+
+// Move one block, checking the copy before removing the original.
+pub(crate) async fn move_block<H, C>(hot: &H, cold: &C, block_hash: &BlockHash) -> Result<()>
+    where H: BlockArchive + ?Sized, C: BlockArchive + ?Sized
+{
+    let mut copied = false;
+    if !cold.block_exists(block_hash).await? {
+        let mut reader = hot.get_block(block_hash).await?;
+        match cold.store_block(block_hash, &mut reader).await {
+            Ok(_) => copied = true,
+            // another writer got there first
+            Err(Error::BlockExists) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let (expected, _) = sha256_reader(&mut hot.get_block(block_hash).await?).await?;
+    let (actual, _) = sha256_reader(&mut cold.get_block(block_hash).await?).await?;
+    if actual != expected {
+        if copied {
+            cold.remove_block(block_hash).await?;
+        }
+        return Err(Error::ChecksumMismatch(*block_hash));
+    }
+    for (key, value) in hot.get_block_attrs(block_hash).await?.iter() {
+        cold.set_block_attr(block_hash, key, value).await?;
+    }
+    hot.remove_block(block_hash).await
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use tokio::io::AsyncReadExt;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("height:800000".parse::<TierPolicy>().unwrap(), TierPolicy::BelowHeight(800000));
+        assert_eq!("age:365".parse::<TierPolicy>().unwrap(), TierPolicy::OlderThanDays(365));
+        assert_eq!(TierPolicy::OlderThanDays(365).to_string(), "age:365");
+        assert!("height".parse::<TierPolicy>().is_err());
+        assert!("size:10".parse::<TierPolicy>().is_err());
+        assert!("age:x".parse::<TierPolicy>().is_err());
+    }
+
+    // The test archive has the genesis block and block 1, and all the test blocks are old.
+    #[tokio::test]
+    async fn test_tier_blocks() {
+        let mut src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let hashes: Vec<BlockHash> = src.block_list().await.unwrap().collect().await;
+        let mut hot = MemoryBlockArchive::new();
+        for h in hashes.iter() {
+            let mut block = Vec::new();
+            src.get_block(h).await.unwrap().read_to_end(&mut block).await.unwrap();
+            hot.store_block(h, &mut &block[..]).await.unwrap();
+        }
+        hot.set_block_attr(&hashes[0], "source", "test").await.unwrap();
+        let cold = MemoryBlockArchive::new();
+        let chain = ChainIndex::build(&mut hot).await.unwrap();
+
+        let summary = tier_blocks(&mut hot, &cold, &chain, &TierPolicy::BelowHeight(2), true, |_, _| {}).await.unwrap();
+        assert_eq!(summary.checked, hashes.len());
+        assert_eq!(summary.moved, 2);
+        let policy = TierPolicy::OlderThanDays(30);
+        let summary = tier_blocks(&mut hot, &cold, &chain, &policy, true, |_, _| {}).await.unwrap();
+        assert_eq!(summary.moved, hashes.len());
+        assert_eq!(hot.len(), hashes.len());
+        assert!(cold.is_empty());
+
+        let mut calls = 0;
+        let summary = tier_blocks(&mut hot, &cold, &chain, &policy, false, |_, _| calls += 1).await.unwrap();
+        assert_eq!(summary.moved, hashes.len());
+        assert_eq!(calls, hashes.len());
+        assert!(hot.is_empty());
+        assert_eq!(cold.len(), hashes.len());
+        assert_eq!(cold.get_block_attrs(&hashes[0]).await.unwrap().get("source").unwrap(), "test");
+    }
+}
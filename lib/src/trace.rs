@@ -0,0 +1,203 @@
+//! Tracing of archive operations.
+//!
+//! Wrap an archive in a [TracedBlockArchive] to run every operation on it in a tracing span, with
+//! the operation, the backend, the block hash, the number of bytes and the duration as fields.
+//! Errors are reported as events in the span. The spans are at the debug level, so they cost
+//! little unless a subscriber is listening at that level.
+//!
+//! Example code:
+//!     let archive = TracedBlockArchive::new(archive, "simple");
+//!     tracing_subscriber::fmt().with_span_events(FmtSpan::CLOSE).init();
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::field::{display, Empty};
+use tracing::{debug, debug_span, warn, Instrument, Span};
+use crate::block_archive::{BlockHashListStream, BlockSizeStream};
+use crate::{BlockArchive, BlockAttrs, Error, Result};
+
+/// A block archive that runs every operation in a tracing span.
+///
+/// The span of [BlockArchive::get_block] ends when the reader is dropped, the bytes are counted
+/// as they are read.
+pub struct TracedBlockArchive<A: BlockArchive> {
+    archive: A,
+    backend: &'static str,
+}
+
+impl<A: BlockArchive> TracedBlockArchive<A> {
+    /// Wrap an archive, the backend names the kind of archive in the spans, such as "simple".
+    pub fn new(archive: A, backend: &'static str) -> TracedBlockArchive<A> {
+        TracedBlockArchive { archive, backend }
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+
+    // Create the span of an operation.
+    fn span(&self, operation: &'static str, block_hash: Option<&BlockHash>) -> Span {
+        let span = debug_span!("archive", operation, backend = self.backend, block_hash = Empty, bytes = Empty, duration_ms = Empty);
+        if let Some(h) = block_hash {
+            span.record("block_hash", display(h));
+        }
+        span
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for TracedBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let span = self.span("get_block", Some(block_hash));
+        let reader = traced(span.clone(), self.archive.get_block(block_hash)).await?;
+        Ok(Box::new(TracedReader { inner: reader, bytes: 0, span: Some(span) }))
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let span = self.span("get_block_range", Some(block_hash));
+        let reader = traced(span.clone(), self.archive.get_block_range(block_hash, offset, length)).await?;
+        Ok(Box::new(TracedReader { inner: reader, bytes: 0, span: Some(span) }))
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        traced(self.span("block_exists", Some(block_hash)), self.archive.block_exists(block_hash)).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        traced(self.span("blocks_exist", None), self.archive.blocks_exist(block_hashes)).await
+    }
+
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        let span = self.span("store_block", Some(block_hash));
+        let mut reader = TracedReader { inner: block, bytes: 0, span: None };
+        let r = traced(span.clone(), self.archive.store_block(block_hash, &mut reader)).await;
+        span.record("bytes", reader.bytes);
+        r
+    }
+
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        traced(self.span("remove_block", Some(block_hash)), self.archive.remove_block(block_hash)).await
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        let span = self.span("block_size", Some(block_hash));
+        let size = traced(span.clone(), self.archive.block_size(block_hash)).await?;
+        span.record("bytes", size);
+        Ok(size)
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        traced(self.span("block_header", Some(block_hash)), self.archive.block_header(block_hash)).await
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        traced(self.span("block_headers", None), self.archive.block_headers(block_hashes)).await
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        let span = self.span("block_list", None);
+        traced(span, self.archive.block_list()).await
+    }
+
+    async fn block_sizes<'a>(&'a mut self) -> Result<BlockSizeStream<'a>> {
+        let span = self.span("block_sizes", None);
+        traced(span, self.archive.block_sizes()).await
+    }
+
+    async fn total_size(&mut self) -> Result<u64> {
+        let span = self.span("total_size", None);
+        let total = traced(span.clone(), self.archive.total_size()).await?;
+        span.record("bytes", total);
+        Ok(total)
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        traced(self.span("set_block_attr", Some(block_hash)), self.archive.set_block_attr(block_hash, key, value)).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        traced(self.span("remove_block_attr", Some(block_hash)), self.archive.remove_block_attr(block_hash, key)).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        traced(self.span("get_block_attrs", Some(block_hash)), self.archive.get_block_attrs(block_hash)).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        traced(self.span("verify_checksum", Some(block_hash)), self.archive.verify_checksum(block_hash)).await
+    }
+}
+
+// Run an operation in its span, recording the duration and reporting an error.
+async fn traced<T, F: Future<Output=Result<T>>>(span: Span, operation: F) -> Result<T> {
+    let start = Instant::now();
+    let r = operation.instrument(span.clone()).await;
+    span.record("duration_ms", start.elapsed().as_secs_f64() * 1000.0);
+    if let Err(e) = &r {
+        let _entered = span.enter();
+        match e {
+            // these are part of normal use
+            Error::BlockNotFound | Error::BlockExists => debug!(error = %e, "archive operation failed"),
+            _ => warn!(error = %e, "archive operation failed"),
+        }
+    }
+    r
+}
+
+// A reader that counts the bytes read through it, and records them in the span when dropped.
+struct TracedReader<R> {
+    inner: R,
+    bytes: u64,
+    span: Option<Span>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TracedReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.bytes += (buf.filled().len() - before) as u64;
+        r
+    }
+}
+
+impl<R> Drop for TracedReader<R> {
+    fn drop(&mut self) {
+        if let Some(span) = &self.span {
+            span.record("bytes", self.bytes);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use tokio::io::AsyncReadExt;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // The traced archive passes every operation through to the wrapped archive.
+    #[tokio::test]
+    async fn test_traced_archive() {
+        let inner = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let archive = TracedBlockArchive::new(inner, "simple");
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        assert_eq!(block.len(), archive.block_size(&h).await.unwrap());
+        assert_eq!(archive.block_header(&h).await.unwrap().hash(), h);
+
+        let archive = TracedBlockArchive::new(MemoryBlockArchive::new(), "memory");
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        assert!(matches!(archive.store_block(&h, &mut &block[..]).await, Err(Error::BlockExists)));
+        assert!(archive.block_exists(&h).await.unwrap());
+        archive.remove_block(&h).await.unwrap();
+        assert!(matches!(archive.get_block(&h).await, Err(Error::BlockNotFound)));
+    }
+}
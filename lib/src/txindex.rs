@@ -0,0 +1,533 @@
+use std::path::Path;
+use std::pin::Pin;
+use async_trait::async_trait;
+use bitcoinsv::bitcoin::{BlockHash, BlockHeader};
+use hex::{FromHex, ToHex};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_stream::StreamExt;
+use crate::block_archive::BlockHashListStream;
+use crate::{BlockArchive, BlockAttrs, Error, Result};
+
+// the name of the tree which records the blocks that have been indexed
+const BLOCKS_TREE: &str = "blocks";
+
+// the size of an encoded block header
+const HEADER_SIZE: u64 = 80;
+
+// the length of an encoded index entry, the hex block hash followed by the offset and length
+const ENTRY_LEN: usize = 64 + 8 + 8;
+
+/// The location of a transaction in the archive, see [TxIndex].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TxLocation {
+    /// The hash of the block that contains the transaction.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))]
+    pub block_hash: BlockHash,
+    /// The offset of the encoded transaction from the start of the encoded block.
+    pub offset: u64,
+    /// The length of the encoded transaction.
+    pub length: u64,
+}
+
+/// A decoded transaction, see [Transaction::from_bytes].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    /// The hash of the encoded transaction.
+    pub txid: BlockHash,
+    pub version: u32,
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
+    pub lock_time: u32,
+}
+
+/// An input of a [Transaction].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxInput {
+    /// The id of the transaction with the output that is spent.
+    pub prev_txid: BlockHash,
+    /// The index of the output that is spent.
+    pub prev_index: u32,
+    pub script: Vec<u8>,
+    pub sequence: u32,
+}
+
+/// An output of a [Transaction].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxOutput {
+    /// The value in satoshis.
+    pub value: u64,
+    pub script: Vec<u8>,
+}
+
+impl Transaction {
+    /// Decode a transaction, returning None if it is not a whole transaction.
+    pub fn from_bytes(tx: &[u8]) -> Option<Transaction> {
+        let mut cursor = tx;
+        let version = u32::from_le_bytes(take(&mut cursor, 4)?.try_into().unwrap());
+        let num_inputs = take_varint(&mut cursor)?;
+        let mut inputs = Vec::new();
+        for _ in 0..num_inputs {
+            let prev_txid = BlockHash { hash: take(&mut cursor, 32)?.try_into().unwrap() };
+            let prev_index = u32::from_le_bytes(take(&mut cursor, 4)?.try_into().unwrap());
+            let script_len = take_varint(&mut cursor)?;
+            let script = take(&mut cursor, script_len)?.to_vec();
+            let sequence = u32::from_le_bytes(take(&mut cursor, 4)?.try_into().unwrap());
+            inputs.push(TxInput { prev_txid, prev_index, script, sequence });
+        }
+        let num_outputs = take_varint(&mut cursor)?;
+        let mut outputs = Vec::new();
+        for _ in 0..num_outputs {
+            let value = u64::from_le_bytes(take(&mut cursor, 8)?.try_into().unwrap());
+            let script_len = take_varint(&mut cursor)?;
+            outputs.push(TxOutput { value, script: take(&mut cursor, script_len)?.to_vec() });
+        }
+        let lock_time = u32::from_le_bytes(take(&mut cursor, 4)?.try_into().unwrap());
+        if !cursor.is_empty() {
+            return None;
+        }
+        Some(Transaction { txid: BlockHash::sha256d(tx), version, inputs, outputs, lock_time })
+    }
+}
+
+/// An index of the transactions in an archive, mapping each txid to the block that contains it
+/// and the position of the transaction in the block.
+///
+/// The index is kept in a sled database, normally in a "txindex" directory next to the archive.
+/// It records which blocks have been indexed, so [TxIndex::build] only has to read new blocks.
+///
+/// Example code:
+///     let index = TxIndex::open(&root_dir.join("txindex"))?;
+///     index.build(&mut archive).await?;
+///     let location = index.get(&txid)?;
+pub struct TxIndex {
+    // maps txid to the encoded TxLocation
+    db: sled::Db,
+    // the blocks that have been indexed
+    blocks: sled::Tree,
+}
+
+impl TxIndex {
+    /// Open the index in the given directory, creating it if necessary.
+    pub fn open(path: &Path) -> Result<TxIndex> {
+        let db = sled::open(path).map_err(index_error)?;
+        let blocks = db.open_tree(BLOCKS_TREE).map_err(index_error)?;
+        Ok(TxIndex { db, blocks })
+    }
+
+    /// Get the location of a transaction, or None if it is not in the index.
+    pub fn get(&self, txid: &BlockHash) -> Result<Option<TxLocation>> {
+        match self.db.get(txid.hash).map_err(index_error)? {
+            Some(v) => Ok(Some(decode_location(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check whether a transaction is in the index, without decoding its location.
+    pub fn contains(&self, txid: &BlockHash) -> Result<bool> {
+        self.db.contains_key(txid.hash).map_err(index_error)
+    }
+
+    /// Check whether a block has been indexed.
+    pub fn is_indexed(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.blocks.contains_key(block_hash.hash).map_err(index_error)
+    }
+
+    /// Get the number of blocks that have been indexed.
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Add the transactions in a block to the index.
+    ///
+    /// Returns the number of transactions indexed, which is zero if the block had already been
+    /// indexed.
+    pub async fn index_block<A: BlockArchive + ?Sized>(&self, archive: &A, block_hash: &BlockHash) -> Result<usize> {
+        if self.is_indexed(block_hash)? {
+            return Ok(0);
+        }
+        let mut reader = archive.get_block(block_hash).await?;
+        let txs = match scan_transactions(&mut reader).await {
+            Ok(txs) => txs,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(Error::CorruptBlock(*block_hash)),
+            Err(e) => return Err(e),
+        };
+        let mut batch = sled::Batch::default();
+        for (txid, offset, length) in txs.iter() {
+            let location = TxLocation { block_hash: *block_hash, offset: *offset, length: *length };
+            batch.insert(&txid.hash[..], encode_location(&location));
+        }
+        // the block is only marked as indexed once all of its transactions have been stored
+        self.db.apply_batch(batch).map_err(index_error)?;
+        self.blocks.insert(block_hash.hash, &[] as &[u8]).map_err(index_error)?;
+        Ok(txs.len())
+    }
+
+    /// Remove the transactions in a block from the index.
+    ///
+    /// The block must still be in the archive, so that its transactions can be found. Returns the
+    /// number of transactions removed, which is zero if the block had not been indexed.
+    pub async fn remove_block<A: BlockArchive + ?Sized>(&self, archive: &A, block_hash: &BlockHash) -> Result<usize> {
+        if !self.is_indexed(block_hash)? {
+            return Ok(0);
+        }
+        let mut reader = archive.get_block(block_hash).await?;
+        let txs = scan_transactions(&mut reader).await?;
+        // the block is unmarked first, so that an interrupted removal can be repeated
+        self.blocks.remove(block_hash.hash).map_err(index_error)?;
+        let mut batch = sled::Batch::default();
+        for (txid, _, _) in txs.iter() {
+            // a transaction can appear in more than one block, e.g. in a fork
+            if let Some(location) = self.get(txid)? {
+                if location.block_hash == *block_hash {
+                    batch.remove(&txid.hash[..]);
+                }
+            }
+        }
+        self.db.apply_batch(batch).map_err(index_error)?;
+        Ok(txs.len())
+    }
+
+    /// Index every block in the archive that has not already been indexed.
+    ///
+    /// Returns the number of blocks that were indexed.
+    pub async fn build<A: BlockArchive + ?Sized>(&self, archive: &mut A) -> Result<usize> {
+        let mut count = 0;
+        let mut results = archive.block_list().await?;
+        while let Some(block_hash) = results.next().await {
+            if self.index_block(archive, &block_hash).await? > 0 {
+                count += 1;
+            }
+        }
+        self.flush().await?;
+        if let Some(e) = results.as_mut().take_error() {
+            return Err(e);
+        }
+        Ok(count)
+    }
+
+    /// Write any buffered changes to disk.
+    pub async fn flush(&self) -> Result<()> {
+        // the trees share the database, so this flushes the blocks tree too
+        self.db.flush_async().await.map_err(index_error)?;
+        Ok(())
+    }
+}
+
+/// A block archive with a transaction index, so that individual transactions can be fetched
+/// without reading whole blocks.
+///
+/// Blocks stored through the wrapper are indexed as they are stored.
+pub struct IndexedBlockArchive<A: BlockArchive> {
+    archive: A,
+    index: TxIndex,
+}
+
+impl<A: BlockArchive> IndexedBlockArchive<A> {
+    /// Wrap an archive with an index.
+    pub fn new(archive: A, index: TxIndex) -> IndexedBlockArchive<A> {
+        IndexedBlockArchive { archive, index }
+    }
+
+    /// Get the wrapped archive.
+    pub fn archive(&self) -> &A {
+        &self.archive
+    }
+
+    /// Get the index.
+    pub fn index(&self) -> &TxIndex {
+        &self.index
+    }
+
+    /// Get an encoded transaction.
+    ///
+    /// Fails with [Error::TxNotFound] if the transaction is not in the index.
+    pub async fn get_transaction(&self, txid: &BlockHash) -> Result<Vec<u8>> {
+        let location = self.index.get(txid)?.ok_or(Error::TxNotFound)?;
+        let mut reader = self.archive.get_block_range(&location.block_hash, location.offset, location.length).await?;
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await?;
+        if buf.len() as u64 != location.length {
+            return Err(Error::CorruptBlock(location.block_hash));
+        }
+        Ok(buf)
+    }
+
+    /// Get a decoded transaction.
+    ///
+    /// Fails with [Error::TxNotFound] if the transaction is not in the index, or with
+    /// [Error::CorruptBlock] if the indexed bytes are not a transaction.
+    pub async fn get_decoded_transaction(&self, txid: &BlockHash) -> Result<Transaction> {
+        let tx = self.get_transaction(txid).await?;
+        match Transaction::from_bytes(&tx) {
+            Some(t) => Ok(t),
+            None => Err(Error::CorruptBlock(self.index.get(txid)?.ok_or(Error::TxNotFound)?.block_hash)),
+        }
+    }
+}
+
+#[async_trait]
+impl<A: BlockArchive> BlockArchive for IndexedBlockArchive<A> {
+    async fn get_block(&self, block_hash: &BlockHash) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.archive.get_block(block_hash).await
+    }
+
+    async fn get_block_range(&self, block_hash: &BlockHash, offset: u64, length: u64) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        self.archive.get_block_range(block_hash, offset, length).await
+    }
+
+    async fn block_exists(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.block_exists(block_hash).await
+    }
+
+    async fn blocks_exist(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, bool)>> {
+        self.archive.blocks_exist(block_hashes).await
+    }
+
+    /// The block is added to the index once it has been stored.
+    async fn store_block(&self, block_hash: &BlockHash, block: &mut (dyn AsyncRead + Unpin + Send)) -> Result<()> {
+        self.archive.store_block(block_hash, block).await?;
+        self.index.index_block(&self.archive, block_hash).await?;
+        Ok(())
+    }
+
+    /// The transactions in the block are removed from the index before the block is removed.
+    async fn remove_block(&self, block_hash: &BlockHash) -> Result<()> {
+        self.index.remove_block(&self.archive, block_hash).await?;
+        self.archive.remove_block(block_hash).await
+    }
+
+    async fn block_size(&self, block_hash: &BlockHash) -> Result<usize> {
+        self.archive.block_size(block_hash).await
+    }
+
+    async fn block_header(&self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        self.archive.block_header(block_hash).await
+    }
+
+    async fn block_headers(&self, block_hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockHeader)>> {
+        self.archive.block_headers(block_hashes).await
+    }
+
+    async fn block_list(&mut self) -> Result<Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>> {
+        self.archive.block_list().await
+    }
+
+    async fn set_block_attr(&self, block_hash: &BlockHash, key: &str, value: &str) -> Result<()> {
+        self.archive.set_block_attr(block_hash, key, value).await
+    }
+
+    async fn remove_block_attr(&self, block_hash: &BlockHash, key: &str) -> Result<()> {
+        self.archive.remove_block_attr(block_hash, key).await
+    }
+
+    async fn get_block_attrs(&self, block_hash: &BlockHash) -> Result<BlockAttrs> {
+        self.archive.get_block_attrs(block_hash).await
+    }
+
+    async fn verify_checksum(&self, block_hash: &BlockHash) -> Result<bool> {
+        self.archive.verify_checksum(block_hash).await
+    }
+}
+
+// Read the transactions in an encoded block, returning the txid, offset, and length of each.
+pub(crate) async fn scan_transactions<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Vec<(BlockHash, u64, u64)>> {
+    let mut buf = Vec::new();
+    read_bytes(reader, &mut buf, HEADER_SIZE).await?;
+    let num_tx = read_varint(reader, &mut buf).await?;
+    let mut offset = buf.len() as u64;
+    let mut result = Vec::new();
+    for _ in 0..num_tx {
+        buf.clear();
+        read_tx(reader, &mut buf).await?;
+        result.push((BlockHash::sha256d(&buf), offset, buf.len() as u64));
+        offset += buf.len() as u64;
+    }
+    Ok(result)
+}
+
+// Read an encoded transaction, appending it to buf.
+pub(crate) async fn read_tx<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, buf: &mut Vec<u8>) -> Result<()> {
+    // version
+    read_bytes(reader, buf, 4).await?;
+    let num_inputs = read_varint(reader, buf).await?;
+    for _ in 0..num_inputs {
+        // outpoint, script, sequence
+        read_bytes(reader, buf, 36).await?;
+        let script_len = read_varint(reader, buf).await?;
+        read_bytes(reader, buf, script_len).await?;
+        read_bytes(reader, buf, 4).await?;
+    }
+    let num_outputs = read_varint(reader, buf).await?;
+    for _ in 0..num_outputs {
+        // value, script
+        read_bytes(reader, buf, 8).await?;
+        let script_len = read_varint(reader, buf).await?;
+        read_bytes(reader, buf, script_len).await?;
+    }
+    // lock time
+    read_bytes(reader, buf, 4).await
+}
+
+// Read a variable length integer, appending the encoded bytes to buf.
+pub(crate) async fn read_varint<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, buf: &mut Vec<u8>) -> Result<u64> {
+    let prefix = reader.read_u8().await?;
+    buf.push(prefix);
+    let n = match prefix {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        v => return Ok(v as u64),
+    };
+    let start = buf.len();
+    read_bytes(reader, buf, n as u64).await?;
+    let mut value = [0u8; 8];
+    value[..n].copy_from_slice(&buf[start..]);
+    Ok(u64::from_le_bytes(value))
+}
+
+// Read exactly n bytes, appending them to buf.
+pub(crate) async fn read_bytes<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, buf: &mut Vec<u8>, n: u64) -> Result<()> {
+    // read through take() so that a corrupt length does not cause a huge allocation
+    let read = reader.take(n).read_to_end(buf).await?;
+    if read as u64 != n {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+// Take n bytes from the front of the cursor.
+pub(crate) fn take<'a>(cursor: &mut &'a [u8], n: u64) -> Option<&'a [u8]> {
+    let n = usize::try_from(n).ok()?;
+    if n > cursor.len() {
+        return None;
+    }
+    let (head, tail) = cursor.split_at(n);
+    *cursor = tail;
+    Some(head)
+}
+
+// Take a variable length integer from the front of the cursor.
+pub(crate) fn take_varint(cursor: &mut &[u8]) -> Option<u64> {
+    let n = match take(cursor, 1)?[0] {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        v => return Some(v as u64),
+    };
+    let mut value = [0u8; 8];
+    value[..n].copy_from_slice(take(cursor, n as u64)?);
+    Some(u64::from_le_bytes(value))
+}
+
+fn encode_location(location: &TxLocation) -> Vec<u8> {
+    let mut v = Vec::with_capacity(ENTRY_LEN);
+    let hash: String = location.block_hash.encode_hex();
+    v.extend_from_slice(hash.as_bytes());
+    v.extend_from_slice(&location.offset.to_le_bytes());
+    v.extend_from_slice(&location.length.to_le_bytes());
+    v
+}
+
+fn decode_location(v: &[u8]) -> Result<TxLocation> {
+    if v.len() != ENTRY_LEN {
+        return Err(Error::IndexError("invalid index entry".to_string()));
+    }
+    let block_hash = BlockHash::from_hex(&v[..64])
+        .map_err(|_| Error::IndexError("invalid block hash in index entry".to_string()))?;
+    Ok(TxLocation {
+        block_hash,
+        offset: u64::from_le_bytes(v[64..72].try_into().unwrap()),
+        length: u64::from_le_bytes(v[72..80].try_into().unwrap()),
+    })
+}
+
+fn index_error(e: sled::Error) -> Error {
+    Error::IndexError(e.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use mktemp::Temp;
+    use crate::SimpleFileBasedBlockArchive;
+    use super::*;
+
+    // Index the test archive and fetch every transaction in a block.
+    #[tokio::test]
+    async fn test_index_and_get_transaction() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let index = TxIndex::open(&dir.to_path_buf()).unwrap();
+        assert_eq!(index.build(&mut archive).await.unwrap(), 3);
+        assert_eq!(index.build(&mut archive).await.unwrap(), 0);
+        assert_eq!(index.num_blocks(), 3);
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        let mut block = Vec::new();
+        archive.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let txs = scan_transactions(&mut &block[..]).await.unwrap();
+        // the transactions follow each other to the end of the block
+        let (_, offset, length) = txs.last().unwrap();
+        assert_eq!(offset + length, block.len() as u64);
+        let indexed = IndexedBlockArchive::new(archive, index);
+        for (txid, offset, length) in txs.iter() {
+            let location = indexed.index().get(txid).unwrap().unwrap();
+            assert_eq!(location, TxLocation { block_hash: h, offset: *offset, length: *length });
+            let tx = indexed.get_transaction(txid).await.unwrap();
+            assert_eq!(BlockHash::sha256d(&tx), *txid);
+        }
+        match indexed.get_transaction(&h).await {
+            Err(Error::TxNotFound) => {}
+            _ => assert!(false)
+        }
+        assert_eq!(indexed.index().remove_block(indexed.archive(), &h).await.unwrap(), txs.len());
+        assert!(!indexed.index().is_indexed(&h).unwrap());
+        assert_eq!(indexed.index().get(&txs[0].0).unwrap(), None);
+        assert_eq!(indexed.index().remove_block(indexed.archive(), &h).await.unwrap(), 0);
+    }
+
+    // Decode the coinbase transaction of the genesis block.
+    #[tokio::test]
+    async fn test_decode_transaction() {
+        let mut archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let index = TxIndex::open(&dir.to_path_buf()).unwrap();
+        index.build(&mut archive).await.unwrap();
+        let indexed = IndexedBlockArchive::new(archive, index);
+        let txid = BlockHash::from_hex("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b").unwrap();
+        assert!(indexed.index().contains(&txid).unwrap());
+        let tx = indexed.get_decoded_transaction(&txid).await.unwrap();
+        assert_eq!(tx.txid, txid);
+        assert_eq!(tx.version, 1);
+        assert_eq!(tx.inputs.len(), 1);
+        assert_eq!(tx.inputs[0].prev_index, 0xffffffff);
+        assert_eq!(tx.outputs.len(), 1);
+        assert_eq!(tx.outputs[0].value, 5_000_000_000);
+        assert_eq!(tx.lock_time, 0);
+        // trailing bytes are not part of the transaction
+        let mut encoded = indexed.get_transaction(&txid).await.unwrap();
+        encoded.push(0);
+        assert_eq!(Transaction::from_bytes(&encoded), None);
+        assert!(!indexed.index().contains(&tx.inputs[0].prev_txid).unwrap());
+    }
+
+    // A truncated block is reported as corrupt.
+    #[tokio::test]
+    async fn test_truncated_block() {
+        let root = Temp::new_dir().unwrap();
+        let archive = SimpleFileBasedBlockArchive::new(root.to_path_buf()).await.unwrap();
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        // a header and a transaction count of one, but no transaction
+        let mut block = [0u8; 81];
+        block[80] = 1;
+        archive.store_block(&h, &mut &block[..]).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let index = TxIndex::open(&dir.to_path_buf()).unwrap();
+        match index.index_block(&archive, &h).await {
+            Err(Error::CorruptBlock(b)) => assert_eq!(b, h),
+            _ => assert!(false)
+        }
+        assert!(!index.is_indexed(&h).unwrap());
+    }
+}
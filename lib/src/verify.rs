@@ -0,0 +1,755 @@
+//! Integrity checks of the blocks in an archive.
+//!
+//! These are the checks behind the "check" commands of the blockarchive command, so that
+//! applications that embed an archive can run them too. A check reports each failed block with a
+//! [FailureKind] rather than stopping at the first failure.
+//!
+//! The long running checks of a [Verifier] can save their progress to a state file and resume
+//! from it. The file has a line for each block, the block hash followed by the result for the
+//! block. Each type of check should use a different state file.
+//!
+//! Example code:
+//!     let verifier = Verifier::new(archive).with_jobs(4);
+//!     let report = verifier.check_blocks(|_| {}).await?;
+//!     for failure in report.failures.iter() { ... }
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bitcoinsv::bitcoin::{BlockHash, FullBlockStream};
+use hex::FromHex;
+use tracing::info;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, RwLock, RwLockReadGuard};
+use tokio_stream::StreamExt;
+use crate::merkle::{compute_merkle_root, validate_no_duplicate_vulnerability};
+use crate::pow::{check_header as check_pow_header, check_pow, HeaderCheck};
+use crate::block_archive::{BlockHashListStream, BlockHashListStreamFromVec};
+use crate::{BlockArchive, ChainIndex, Error, Network, Result};
+
+// The number of headers read from the archive at a time.
+const HEADER_BATCH_SIZE: usize = 500;
+
+/// The kind of a check failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FailureKind {
+    /// The best chain does not have the known block at the height of a checkpoint.
+    CheckpointMismatch,
+    /// The block does not match its recorded checksum.
+    ChecksumMismatch,
+    /// The transactions of the block can be changed without changing the merkle root.
+    DuplicateTxids,
+    /// The stored header has a different hash, the block is misfiled.
+    HashMismatch,
+    /// The hash of the header does not meet its target.
+    InsufficientWork,
+    /// The merkle root of the transactions does not match the header.
+    MerkleRootMismatch,
+    /// The parent of the block is not in the archive.
+    MissingParent,
+    /// The block could not be read.
+    ReadError,
+    /// The block is the genesis block of another network.
+    WrongNetwork,
+}
+
+impl FailureKind {
+    /// Get the short name of the kind, for example "merkle_root_mismatch".
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailureKind::CheckpointMismatch => "checkpoint_mismatch",
+            FailureKind::ChecksumMismatch => "checksum_mismatch",
+            FailureKind::DuplicateTxids => "duplicate_txids",
+            FailureKind::HashMismatch => "hash_mismatch",
+            FailureKind::InsufficientWork => "insufficient_work",
+            FailureKind::MerkleRootMismatch => "merkle_root_mismatch",
+            FailureKind::MissingParent => "missing_parent",
+            FailureKind::ReadError => "read_error",
+            FailureKind::WrongNetwork => "wrong_network",
+        }
+    }
+}
+
+impl FromStr for FailureKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<FailureKind, ()> {
+        match s {
+            "checkpoint_mismatch" => Ok(FailureKind::CheckpointMismatch),
+            "checksum_mismatch" => Ok(FailureKind::ChecksumMismatch),
+            "duplicate_txids" => Ok(FailureKind::DuplicateTxids),
+            "hash_mismatch" => Ok(FailureKind::HashMismatch),
+            "insufficient_work" => Ok(FailureKind::InsufficientWork),
+            "merkle_root_mismatch" => Ok(FailureKind::MerkleRootMismatch),
+            "missing_parent" => Ok(FailureKind::MissingParent),
+            "read_error" => Ok(FailureKind::ReadError),
+            "wrong_network" => Ok(FailureKind::WrongNetwork),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A check that failed for a block.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckFailure {
+    /// The block that failed.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))]
+    pub block_hash: BlockHash,
+    /// The kind of failure.
+    pub kind: FailureKind,
+    /// A description of the failure for people.
+    pub message: String,
+}
+
+impl CheckFailure {
+    /// Create a failure of a block.
+    pub fn new(block_hash: &BlockHash, kind: FailureKind, message: String) -> CheckFailure {
+        CheckFailure { block_hash: *block_hash, kind, message }
+    }
+
+    // Read a failure saved in a state file as the kind followed by the message.
+    fn from_state(block_hash: &BlockHash, result: &str) -> Option<CheckFailure> {
+        let (kind, message) = result.split_once(' ')?;
+        Some(CheckFailure::new(block_hash, kind.parse().ok()?, message.to_string()))
+    }
+
+    // The form saved in a state file.
+    fn to_state(&self) -> String {
+        format!("{} {}", self.kind, self.message)
+    }
+}
+
+/// The result of a check of many blocks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckReport {
+    /// The number of blocks checked, including those whose results were read from the state file.
+    pub checked: u64,
+    /// The number of blocks whose results were read from the state file.
+    pub resumed: u64,
+    /// The failures, in the order of the block list.
+    pub failures: Vec<CheckFailure>,
+    /// The groups of blocks that are linked to each other, only found by [Verifier::check_links].
+    pub components: Vec<LinkedComponent>,
+}
+
+/// A group of blocks that are linked to each other, a tree that grows from a single root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LinkedComponent {
+    /// The first block, whose parent is not in the archive or which is a genesis block.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))]
+    pub root: BlockHash,
+    /// The parent of the root, all zeros if the root is a genesis block.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_support::hash"))]
+    pub root_parent: BlockHash,
+    /// The number of blocks, including the root.
+    pub blocks: u64,
+    /// Whether the root is the genesis block of the network, so that every block in the group is
+    /// linked to genesis.
+    pub anchored: bool,
+}
+
+impl CheckReport {
+    /// Check whether every block passed.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A block that has been checked by [Verifier::check_blocks].
+#[derive(Debug, Clone, Copy)]
+pub struct CheckedBlock<'a> {
+    /// The block.
+    pub block_hash: &'a BlockHash,
+    /// The size of the block, zero if the result was read from the state file.
+    pub size: u64,
+    /// The failure, if the block failed.
+    pub failure: Option<&'a CheckFailure>,
+    /// Whether the result was read from the state file.
+    pub resumed: bool,
+    /// How long the check of the block took, zero if the result was read from the state file.
+    pub elapsed: Duration,
+}
+
+/// Check that the header stored for a block has the hash of the block and meets its target.
+pub async fn check_header<A>(archive: &A, block_hash: &BlockHash) -> Result<Option<CheckFailure>>
+    where A: BlockArchive + ?Sized
+{
+    match check_pow_header(archive, block_hash).await? {
+        HeaderCheck::Ok => Ok(None),
+        HeaderCheck::HashMismatch(h) => Ok(Some(CheckFailure::new(block_hash, FailureKind::HashMismatch,
+            format!("block {} contains the header of block {}", block_hash, h)))),
+        HeaderCheck::InsufficientWork => Ok(Some(CheckFailure::new(block_hash, FailureKind::InsufficientWork,
+            format!("block {} does not meet its target", block_hash)))),
+    }
+}
+
+/// Check that the merkle root of the transactions of a block matches its header, and that the
+/// transactions can not be changed without changing the merkle root.
+///
+/// This is not block validation, but it does read and hash every transaction.
+pub async fn check_transactions(block_hash: &BlockHash, mut block: FullBlockStream) -> Result<Option<CheckFailure>> {
+    let mut txids = Vec::new();
+    while let Some(tx) = block.next().await {
+        txids.push(tx.map_err(Error::from)?.hash());
+    }
+    if compute_merkle_root(txids.iter().copied()) != Some(block.block_header.merkle_root) {
+        return Ok(Some(CheckFailure::new(block_hash, FailureKind::MerkleRootMismatch,
+            format!("merkle root mismatch for block {}", block_hash))));
+    }
+    if !validate_no_duplicate_vulnerability(txids.into_iter()) {
+        return Ok(Some(CheckFailure::new(block_hash, FailureKind::DuplicateTxids,
+            format!("block {} has duplicate txids in its merkle tree", block_hash))));
+    }
+    Ok(None)
+}
+
+/// Runs the integrity checks of the blocks in an archive.
+pub struct Verifier<A> {
+    // block_list() needs exclusive access, everything else can share the archive
+    archive: Arc<RwLock<A>>,
+    pow: bool,
+    // only the summary of each block is checked by the checks of many blocks
+    quick: bool,
+    jobs: usize,
+    state_file: Option<PathBuf>,
+    network: Option<Network>,
+}
+
+impl<A: BlockArchive + 'static> Verifier<A> {
+    /// Create a verifier that checks one block at a time, without the proof-of-work check.
+    pub fn new(archive: A) -> Verifier<A> {
+        Verifier { archive: Arc::new(RwLock::new(archive)), pow: false, quick: false, jobs: 1, state_file: None, network: None }
+    }
+
+    /// Also check the proof-of-work of the header in [Verifier::check_block] and
+    /// [Verifier::check_blocks].
+    pub fn with_pow(mut self, pow: bool) -> Verifier<A> {
+        self.pow = pow;
+        self
+    }
+
+    /// Only check the header and transaction count of each block in [Verifier::check_blocks] and
+    /// [Verifier::check_range], see [BlockArchive::block_summary], rather than reading and
+    /// hashing every transaction. This finds misfiled blocks and blocks whose start is damaged
+    /// much faster, but not damaged transactions.
+    ///
+    /// The state file does not record which check was made, so a quick check should not resume
+    /// from the state file of a full check, or the other way round.
+    pub fn with_quick(mut self, quick: bool) -> Verifier<A> {
+        self.quick = quick;
+        self
+    }
+
+    /// Set the number of blocks checked at the same time by [Verifier::check_blocks].
+    pub fn with_jobs(mut self, jobs: usize) -> Verifier<A> {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Save the progress of the long running checks to this file, and resume from it if it exists.
+    pub fn with_state_file(mut self, path: PathBuf) -> Verifier<A> {
+        self.state_file = Some(path);
+        self
+    }
+
+    /// Check that the blocks are linked to the genesis block of the network in
+    /// [Verifier::check_links].
+    pub fn with_network(mut self, network: Network) -> Verifier<A> {
+        self.network = Some(network);
+        self
+    }
+
+    /// Borrow the archive, for example to quarantine the blocks that failed a check. Waits for
+    /// a check that needs exclusive access to the archive to finish.
+    pub async fn archive(&self) -> RwLockReadGuard<'_, A> {
+        self.archive.read().await
+    }
+
+    /// Get the archive back, once the checks have finished. Returns the verifier if a check
+    /// still holds the archive.
+    pub fn into_archive(self) -> std::result::Result<A, Verifier<A>> {
+        match Arc::try_unwrap(self.archive) {
+            Ok(archive) => Ok(archive.into_inner()),
+            Err(archive) => Err(Verifier { archive, ..self }),
+        }
+    }
+
+    /// Check a block, returning the failure if it fails.
+    pub async fn check_block(&self, block_hash: &BlockHash) -> Result<Option<CheckFailure>> {
+        check_stored_block(&*self.archive.read().await, block_hash, self.pow).await
+    }
+
+    /// Check that the parent of every block is in the archive. The progress function is called
+    /// for each block as it is listed.
+    ///
+    /// The report also has the groups of linked blocks, the blocks are all linked to genesis if
+    /// there is a single group that is anchored. Without a network the genesis block has no
+    /// parent so it is always reported. With a network the genesis block of the network passes,
+    /// and the genesis block of another network is reported as [FailureKind::WrongNetwork].
+    pub async fn check_links<F>(&self, mut progress: F) -> Result<CheckReport>
+        where F: FnMut(&BlockHash)
+    {
+        let mut state = CheckState::open(self.state_file.clone()).await?;
+        let mut report = CheckReport::default();
+        let mut block_it = self.archive.write().await.block_list().await?;
+        let archive = self.archive.read().await;
+        // collect all hashes for checking parents
+        let mut block_hashes = BTreeSet::new();
+        // every block with its parent
+        let mut parents = Vec::new();
+        // blocks whose headers have not been read yet
+        let mut pending = Vec::new();
+        while let Some(block_hash) = block_it.next().await {
+            progress(&block_hash);
+            block_hashes.insert(block_hash);
+            // the state records the parent of each block whose header has been read
+            match state.get(&block_hash).and_then(|v| BlockHash::from_hex(v).ok()) {
+                Some(prev_hash) => {
+                    parents.push((block_hash, prev_hash));
+                    report.resumed += 1;
+                }
+                None => pending.push(block_hash),
+            }
+            if pending.len() >= HEADER_BATCH_SIZE {
+                read_parents(&*archive, &mut pending, &mut state, &mut parents).await?;
+            }
+        }
+        read_parents(&*archive, &mut pending, &mut state, &mut parents).await?;
+        state.flush().await?;
+        if let Some(e) = block_it.as_mut().take_error() {
+            return Err(e);
+        }
+        report.checked = parents.len() as u64;
+        let genesis = self.network.map(|n| n.genesis_hash());
+        let zero = BlockHash { hash: [0u8; 32] };
+        for (block_hash, prev_hash) in parents.iter() {
+            if block_hashes.contains(prev_hash) {
+                continue;
+            }
+            match self.network {
+                Some(_) if Some(*block_hash) == genesis => {}
+                Some(network) if *prev_hash == zero => {
+                    report.failures.push(CheckFailure::new(block_hash, FailureKind::WrongNetwork,
+                        format!("block {} is the genesis block of a network other than {}", block_hash, network)));
+                }
+                _ => report.failures.push(CheckFailure::new(block_hash, FailureKind::MissingParent,
+                    format!("dont have parent {} of block {}", prev_hash, block_hash))),
+            }
+        }
+        report.components = linked_components(&parents, genesis);
+        Ok(report)
+    }
+
+    /// Check every block in the archive, see [Verifier::check_block]. The progress function is
+    /// called for each block when it has been checked.
+    ///
+    /// A block that can not be read is reported as a [FailureKind::ReadError] failure.
+    pub async fn check_blocks<F>(&self, progress: F) -> Result<CheckReport>
+        where F: Fn(&CheckedBlock) + Send + Sync + 'static
+    {
+        let block_it = self.archive.write().await.block_list().await?;
+        self.check_listed_blocks(block_it, progress).await
+    }
+
+    /// Check the blocks of the best chain from one height to another, see [Verifier::check_blocks],
+    /// so that the most recently stored blocks can be checked without checking every block.
+    ///
+    /// The range ends at the best tip if no end is given or the end is above the tip. The blocks
+    /// are found with a [ChainIndex], which reads the header of every block.
+    pub async fn check_range<F>(&self, from: u64, to: Option<u64>, progress: F) -> Result<CheckReport>
+        where F: Fn(&CheckedBlock) + Send + Sync + 'static
+    {
+        let chain = ChainIndex::build(&mut *self.archive.write().await).await?;
+        let hashes = match chain.tip() {
+            Some((_, tip)) => (from..=to.map_or(tip, |to| to.min(tip))).filter_map(|height| chain.block_by_height(height)).collect(),
+            None => Vec::new(),
+        };
+        let block_it = Box::pin(BlockHashListStreamFromVec { hashes: hashes.into_iter() });
+        self.check_listed_blocks(block_it, progress).await
+    }
+
+    // Check the blocks in a list, using the workers.
+    async fn check_listed_blocks<F>(&self, block_it: Pin<Box<dyn BlockHashListStream<Item=BlockHash>>>, progress: F) -> Result<CheckReport>
+        where F: Fn(&CheckedBlock) + Send + Sync + 'static
+    {
+        // the state records "OK" or the failure for each block that has been checked
+        let state = Arc::new(Mutex::new(CheckState::open(self.state_file.clone()).await?));
+        let progress = Arc::new(progress);
+        // the workers share the block list, numbering the blocks so that failures can be
+        // reported in order
+        let queue = Arc::new(Mutex::new((0usize, block_it)));
+        let mut workers = Vec::new();
+        for _ in 0..self.jobs {
+            let archive = self.archive.clone();
+            let queue = queue.clone();
+            let state = state.clone();
+            let progress = progress.clone();
+            let pow = self.pow;
+            let quick = self.quick;
+            workers.push(tokio::spawn(async move {
+                let mut report = CheckReport::default();
+                let mut failures = Vec::new();
+                loop {
+                    let (seq, block_hash) = {
+                        let mut queue = queue.lock().await;
+                        match queue.1.next().await {
+                            Some(h) => {
+                                queue.0 += 1;
+                                (queue.0, h)
+                            }
+                            None => match queue.1.as_mut().take_error() {
+                                Some(e) => return Err(e),
+                                None => break,
+                            },
+                        }
+                    };
+                    report.checked += 1;
+                    // a result that can not be read is checked again
+                    let saved = state.lock().await.get(&block_hash)
+                        .map(|r| if r == "OK" { Some(None) } else { CheckFailure::from_state(&block_hash, r).map(Some) })
+                        .unwrap_or(None);
+                    if let Some(failure) = saved {
+                        report.resumed += 1;
+                        progress(&CheckedBlock { block_hash: &block_hash, size: 0, failure: failure.as_ref(), resumed: true, elapsed: Duration::ZERO });
+                        if let Some(failure) = failure {
+                            failures.push((seq, failure));
+                        }
+                        continue;
+                    }
+                    let started = Instant::now();
+                    let guard = archive.read().await;
+                    let checked = if quick {
+                        check_block_summary(&*guard, &block_hash, pow).await
+                    } else {
+                        let size = guard.block_size(&block_hash).await.unwrap_or(0) as u64;
+                        check_stored_block(&*guard, &block_hash, pow).await.map(|f| (size, f))
+                    };
+                    let (size, failure) = match checked {
+                        Ok(r) => r,
+                        Err(e) => (0, Some(CheckFailure::new(&block_hash, FailureKind::ReadError,
+                            format!("error reading block {}: {}", block_hash, e)))),
+                    };
+                    drop(guard);
+                    progress(&CheckedBlock { block_hash: &block_hash, size, failure: failure.as_ref(), resumed: false, elapsed: started.elapsed() });
+                    let result = failure.as_ref().map_or("OK".to_string(), |f| f.to_state());
+                    if let Some(failure) = failure {
+                        failures.push((seq, failure));
+                    }
+                    state.lock().await.record(&block_hash, result).await?;
+                }
+                Ok::<_, Error>((report, failures))
+            }));
+        }
+        let mut report = CheckReport::default();
+        let mut failures = Vec::new();
+        for worker in workers {
+            let (r, f) = worker.await.map_err(std::io::Error::from)??;
+            report.checked += r.checked;
+            report.resumed += r.resumed;
+            failures.extend(f);
+        }
+        state.lock().await.flush().await?;
+        failures.sort();
+        report.failures = failures.into_iter().map(|(_, f)| f).collect();
+        Ok(report)
+    }
+}
+
+// Check a block in the archive, returning the failure if it fails.
+pub(crate) async fn check_stored_block<A>(archive: &A, block_hash: &BlockHash, pow: bool) -> Result<Option<CheckFailure>>
+    where A: BlockArchive + ?Sized
+{
+    if pow {
+        if let Some(failure) = check_header(archive, block_hash).await? {
+            return Ok(Some(failure));
+        }
+    }
+    let reader = archive.get_block(block_hash).await?;
+    let block = FullBlockStream::new(reader).await?;
+    check_transactions(block_hash, block).await
+}
+
+// Check the summary of a block in the archive, returning the size of the block and the failure if
+// it fails.
+async fn check_block_summary<A>(archive: &A, block_hash: &BlockHash, pow: bool) -> Result<(u64, Option<CheckFailure>)>
+    where A: BlockArchive + ?Sized
+{
+    let summary = archive.block_summary(block_hash).await?;
+    let failure = if summary.header.hash() != *block_hash {
+        Some(CheckFailure::new(block_hash, FailureKind::HashMismatch,
+            format!("block {} contains the header of block {}", block_hash, summary.header.hash())))
+    } else if pow && !check_pow(block_hash, summary.header.bits) {
+        Some(CheckFailure::new(block_hash, FailureKind::InsufficientWork,
+            format!("block {} does not meet its target", block_hash)))
+    } else if summary.tx_count == 0 {
+        Some(CheckFailure::new(block_hash, FailureKind::ReadError, format!("block {} has no transactions", block_hash)))
+    } else {
+        None
+    };
+    Ok((summary.size, failure))
+}
+
+// Read the headers of the pending blocks in one batch, recording the parent of each.
+async fn read_parents<A>(archive: &A, pending: &mut Vec<BlockHash>, state: &mut CheckState,
+                         parents: &mut Vec<(BlockHash, BlockHash)>) -> Result<()>
+    where A: BlockArchive + ?Sized
+{
+    for (block_hash, header) in archive.block_headers(pending).await? {
+        state.record(&block_hash, header.prev_hash.to_string()).await?;
+        parents.push((block_hash, header.prev_hash));
+    }
+    pending.clear();
+    Ok(())
+}
+
+// Group the blocks by the root of the tree they are in. A block is anchored if its root is the
+// genesis block, or any genesis block if there is no network. Anchored groups come first.
+fn linked_components(parents: &[(BlockHash, BlockHash)], genesis: Option<BlockHash>) -> Vec<LinkedComponent> {
+    let parent_of: BTreeMap<BlockHash, BlockHash> = parents.iter().copied().collect();
+    let mut root_of: BTreeMap<BlockHash, BlockHash> = BTreeMap::new();
+    let mut components: BTreeMap<BlockHash, LinkedComponent> = BTreeMap::new();
+    for (block_hash, _) in parents.iter() {
+        // walk back to a block whose root is known, or to the root
+        let mut path = Vec::new();
+        let mut next = *block_hash;
+        let root = loop {
+            if let Some(root) = root_of.get(&next) {
+                break *root;
+            }
+            path.push(next);
+            match parent_of.get(&next) {
+                Some(prev) if parent_of.contains_key(prev) => next = *prev,
+                _ => break next,
+            }
+        };
+        for h in path {
+            root_of.insert(h, root);
+        }
+        let root_parent = parent_of[&root];
+        let anchored = match genesis {
+            Some(g) => root == g,
+            None => root_parent == BlockHash { hash: [0u8; 32] },
+        };
+        components.entry(root).or_insert(LinkedComponent { root, root_parent, blocks: 0, anchored }).blocks += 1;
+    }
+    let mut components: Vec<LinkedComponent> = components.into_values().collect();
+    components.sort_by_key(|c| (!c.anchored, c.root));
+    components
+}
+
+// The results of a long running check, which are saved to the state file if there is one.
+struct CheckState {
+    results: BTreeMap<BlockHash, String>,
+    file: Option<tokio::io::BufWriter<tokio::fs::File>>,
+    // the number of results written since the last flush
+    unsaved: usize,
+}
+
+impl CheckState {
+    // the number of results between checkpoints
+    const CHECKPOINT_INTERVAL: usize = 1000;
+
+    // Read the results in the state file, if there is one, and open it for appending.
+    async fn open(path: Option<PathBuf>) -> Result<CheckState> {
+        let mut results = BTreeMap::new();
+        let path = match path {
+            Some(p) => p,
+            None => return Ok(CheckState { results, file: None, unsaved: 0 }),
+        };
+        if tokio::fs::try_exists(&path).await? {
+            for line in tokio::fs::read_to_string(&path).await?.lines() {
+                // the last line may be incomplete if the check was interrupted
+                if let Some((h, result)) = line.split_once(' ') {
+                    if let Ok(h) = BlockHash::from_hex(h) {
+                        results.insert(h, result.to_string());
+                    }
+                }
+            }
+            info!(path = %path.display(), checked = results.len(), "resuming check");
+        }
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+        Ok(CheckState { results, file: Some(tokio::io::BufWriter::new(file)), unsaved: 0 })
+    }
+
+    // Get the saved result for a block.
+    fn get(&self, block_hash: &BlockHash) -> Option<&String> {
+        self.results.get(block_hash)
+    }
+
+    // Save the result for a block.
+    async fn record(&mut self, block_hash: &BlockHash, result: String) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(format!("{} {}\n", block_hash, result).as_bytes()).await?;
+            self.unsaved += 1;
+            if self.unsaved >= Self::CHECKPOINT_INTERVAL {
+                self.flush().await?;
+            }
+        }
+        self.results.insert(*block_hash, result);
+        Ok(())
+    }
+
+    // Write any unsaved results to the state file.
+    async fn flush(&mut self) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush().await?;
+            file.get_ref().sync_data().await?;
+        }
+        self.unsaved = 0;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use hex::FromHex;
+    use mktemp::Temp;
+    use tokio::io::AsyncReadExt;
+    use crate::{MemoryBlockArchive, SimpleFileBasedBlockArchive};
+    use super::*;
+
+    // The blocks in the test archive pass, and a second run is taken from the state file.
+    #[tokio::test]
+    async fn test_check_blocks() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let dir = Temp::new_dir().unwrap();
+        let state_file = dir.as_path().join("state");
+        let verifier = Verifier::new(archive).with_pow(true).with_jobs(2).with_state_file(state_file);
+        let report = verifier.check_blocks(|b| assert!(b.failure.is_none())).await.unwrap();
+        assert!(report.is_ok());
+        assert!(report.checked >= 3);
+        assert_eq!(report.resumed, 0);
+        let again = verifier.check_blocks(|b| assert!(b.resumed)).await.unwrap();
+        assert_eq!(again.checked, report.checked);
+        assert_eq!(again.resumed, report.checked);
+    }
+
+    // A quick check reads only the summaries, and finds a block stored under another hash.
+    #[tokio::test]
+    async fn test_quick_check() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let verifier = Verifier::new(archive).with_pow(true).with_quick(true).with_jobs(2);
+        let report = verifier.check_blocks(|b| assert!(b.failure.is_none() && b.size > 0)).await.unwrap();
+        assert!(report.is_ok());
+        assert!(report.checked >= 3);
+
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(verifier.archive().await.block_exists(&h).await.unwrap());
+        let src = verifier.into_archive().ok().unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let other = BlockHash::from_hex(format!("{:064x}", 1)).unwrap();
+        let misfiled = MemoryBlockArchive::new();
+        misfiled.store_block(&other, &mut &block[..]).await.unwrap();
+        let report = Verifier::new(misfiled).with_quick(true).check_blocks(|_| {}).await.unwrap();
+        assert_eq!(report.failures[0].kind, FailureKind::HashMismatch);
+    }
+
+    // The test archive has the genesis block and the block at height 1, the range ends at the tip.
+    #[tokio::test]
+    async fn test_check_range() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let verifier = Verifier::new(archive).with_jobs(2);
+        let report = verifier.check_range(0, None, |b| assert!(b.failure.is_none())).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.checked, 2);
+        assert_eq!(verifier.check_range(1, Some(5), |_| {}).await.unwrap().checked, 1);
+        assert_eq!(verifier.check_range(2, None, |_| {}).await.unwrap().checked, 0);
+    }
+
+    // The genesis block and a block whose parent is not in the test archive have missing parents.
+    #[tokio::test]
+    async fn test_check_links() {
+        let archive = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let verifier = Verifier::new(archive);
+        let mut listed = 0;
+        let report = verifier.check_links(|_| listed += 1).await.unwrap();
+        assert_eq!(report.checked, listed);
+        let h = BlockHash::from_hex("00000000000000a86c0a6d7b3445ff9e64908d6417cd6b256dbc23efd01de26f").unwrap();
+        assert!(report.failures.iter().any(|f| f.block_hash == h && f.kind == FailureKind::MissingParent));
+        let h1 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        assert!(!report.failures.iter().any(|f| f.block_hash == h1));
+    }
+
+    // With a network, the blocks linked to its genesis block form the anchored group, and the
+    // root of each other group is reported.
+    #[tokio::test]
+    async fn test_check_links_to_genesis() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let genesis = Network::Mainnet.genesis_hash();
+        let mut block = Vec::new();
+        src.get_block(&genesis).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let archive = MemoryBlockArchive::new();
+        archive.store_block(&genesis, &mut &block[..]).await.unwrap();
+        // blocks that are only a header with no transactions
+        let header = |prev_hash: BlockHash, nonce: u32| {
+            let mut b = block[..80].to_vec();
+            b[4..36].copy_from_slice(&prev_hash.hash);
+            b[76..80].copy_from_slice(&nonce.to_le_bytes());
+            b.push(0);
+            let h = BlockHash::sha256d(&b[..80]);
+            (h, b)
+        };
+        let zero = BlockHash { hash: [0u8; 32] };
+        let (a1, b) = header(genesis, 1);
+        archive.store_block(&a1, &mut &b[..]).await.unwrap();
+        let (a2, b) = header(a1, 2);
+        archive.store_block(&a2, &mut &b[..]).await.unwrap();
+        let (missing, _) = header(a2, 3);
+        let (orphan, b) = header(missing, 4);
+        archive.store_block(&orphan, &mut &b[..]).await.unwrap();
+        let (foreign, b) = header(zero, 5);
+        archive.store_block(&foreign, &mut &b[..]).await.unwrap();
+
+        let report = Verifier::new(archive).with_network(Network::Mainnet).check_links(|_| {}).await.unwrap();
+        assert_eq!(report.checked, 5);
+        let mut failures: Vec<(BlockHash, FailureKind)> = report.failures.iter().map(|f| (f.block_hash, f.kind)).collect();
+        failures.sort();
+        let mut expected = vec![(orphan, FailureKind::MissingParent), (foreign, FailureKind::WrongNetwork)];
+        expected.sort();
+        assert_eq!(failures, expected);
+        assert_eq!(report.components.len(), 3);
+        assert_eq!(report.components[0], LinkedComponent { root: genesis, root_parent: zero, blocks: 3, anchored: true });
+        assert!(report.components[1..].iter().all(|c| !c.anchored && c.blocks == 1));
+        assert!(report.components.iter().any(|c| c.root == orphan && c.root_parent == missing));
+    }
+
+    // A misfiled block fails the proof-of-work check, and a changed transaction fails the merkle
+    // root check.
+    #[tokio::test]
+    async fn test_failures() {
+        let src = SimpleFileBasedBlockArchive::new(PathBuf::from("../testdata/blockarchive")).await.unwrap();
+        let h1 = BlockHash::from_hex("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048").unwrap();
+        let mut block = Vec::new();
+        src.get_block(&h1).await.unwrap().read_to_end(&mut block).await.unwrap();
+        let archive = MemoryBlockArchive::new();
+        let misfiled = BlockHash::from_hex(format!("{:064x}", 1)).unwrap();
+        archive.store_block(&misfiled, &mut &block[..]).await.unwrap();
+        // the last byte is in the lock time of the coinbase transaction
+        let last = block.len() - 1;
+        block[last] ^= 1;
+        archive.store_block(&h1, &mut &block[..]).await.unwrap();
+        let verifier = Verifier::new(archive).with_pow(true);
+        assert_eq!(verifier.check_block(&misfiled).await.unwrap().unwrap().kind, FailureKind::HashMismatch);
+        assert_eq!(verifier.check_block(&h1).await.unwrap().unwrap().kind, FailureKind::MerkleRootMismatch);
+        let report = verifier.check_blocks(|_| {}).await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.failures.len(), 2);
+        let failure = &report.failures[0];
+        assert_eq!(CheckFailure::from_state(&failure.block_hash, &failure.to_state()).as_ref(), Some(failure));
+    }
+}
@@ -0,0 +1,315 @@
+//! A read-only client of the HTTP server of a block archive, see `bsv_blockarchive::http`, that
+//! compiles to wasm32 for web pages and for workers such as Cloudflare Workers.
+//!
+//! The library crate can not be built for wasm32, because it uses the file system and sockets of
+//! tokio, so this crate does not depend on it. Requests are made with the global `fetch` function
+//! of the JavaScript host, which manages the connections.
+//!
+//! Every response is checked before it is returned: a header must hash to the block hash, a block
+//! must start with that header, and a merkle proof must lead to the merkle root of the header.
+//! Blocks are read into memory, so blocks larger than a limit are not fetched.
+//!
+//! Example code, in JavaScript:
+//! ```text
+//! const client = new ArchiveClient("https://archive.example.com");
+//! const header = await client.header(blockHash);
+//! const proof = await client.proof(blockHash, txid);
+//! ```
+use std::fmt;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Request, RequestInit, Response};
+
+// the size of an encoded block header
+const HEADER_SIZE: usize = 80;
+
+// the largest block that is fetched by default
+const DEFAULT_MAX_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+#[wasm_bindgen]
+extern "C" {
+    // the fetch function of the global scope, which web pages and workers both have
+    #[wasm_bindgen(js_name = fetch)]
+    fn fetch_with_request(request: &Request) -> js_sys::Promise;
+}
+
+/// The errors of the client, which are passed to JavaScript as Error objects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// The URL is not the http or https URL of a server.
+    InvalidUrl(String),
+    /// A hash is not 64 hex digits.
+    InvalidHash(String),
+    /// The server does not have the block or transaction.
+    NotFound,
+    /// The block is larger than the largest block that is fetched.
+    BlockTooLarge(u64),
+    /// The server answered with an unexpected status.
+    HttpStatus(u16),
+    /// The request did not reach the server, or the response could not be read.
+    FetchFailed(String),
+    /// The response does not match the block hash, or can not be parsed.
+    InvalidResponse(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(url) => write!(f, "not an http URL of a server: {}", url),
+            ClientError::InvalidHash(s) => write!(f, "invalid hash: {}", s),
+            ClientError::NotFound => write!(f, "not found"),
+            ClientError::BlockTooLarge(size) => write!(f, "the block is too large to fetch: {} bytes", size),
+            ClientError::HttpStatus(status) => write!(f, "unexpected HTTP status {}", status),
+            ClientError::FetchFailed(msg) => write!(f, "fetch failed: {}", msg),
+            ClientError::InvalidResponse(msg) => write!(f, "invalid response: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+type Result<T> = std::result::Result<T, ClientError>;
+
+/// A client of an archive server.
+#[wasm_bindgen]
+pub struct ArchiveClient {
+    // the URL of the server, without a trailing slash
+    url: String,
+    max_block_size: u64,
+}
+
+#[wasm_bindgen]
+impl ArchiveClient {
+    /// Create a client for a server, for example "https://archive.example.com". No request is
+    /// made until the first call.
+    #[wasm_bindgen(constructor)]
+    pub fn new(url: &str) -> std::result::Result<ArchiveClient, JsError> {
+        Ok(ArchiveClient { url: server_url(url)?, max_block_size: DEFAULT_MAX_BLOCK_SIZE })
+    }
+
+    /// Set the size of the largest block that is fetched by [ArchiveClient::block]. The default
+    /// is 4MB.
+    #[wasm_bindgen(js_name = setMaxBlockSize)]
+    pub fn set_max_block_size(&mut self, size: u64) {
+        self.max_block_size = size;
+    }
+
+    /// Get the 80 byte encoded header of a block.
+    pub async fn header(&self, block_hash: &str) -> std::result::Result<Vec<u8>, JsError> {
+        Ok(self.checked_header(block_hash).await?)
+    }
+
+    /// Check whether the server has a block.
+    #[wasm_bindgen(js_name = blockExists)]
+    pub async fn block_exists(&self, block_hash: &str) -> std::result::Result<bool, JsError> {
+        parse_hash(block_hash)?;
+        match self.fetch("HEAD", &format!("/block/{}", block_hash)).await {
+            Ok(_) => Ok(true),
+            Err(ClientError::NotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Get the size of a block in bytes.
+    #[wasm_bindgen(js_name = blockSize)]
+    pub async fn block_size(&self, block_hash: &str) -> std::result::Result<u64, JsError> {
+        Ok(self.size(block_hash).await?)
+    }
+
+    /// Get an encoded block. Fails if the block is larger than the largest block that is fetched.
+    pub async fn block(&self, block_hash: &str) -> std::result::Result<Vec<u8>, JsError> {
+        let hash = parse_hash(block_hash)?;
+        let size = self.size(block_hash).await?;
+        if size > self.max_block_size {
+            return Err(ClientError::BlockTooLarge(size).into());
+        }
+        let block = bytes(self.fetch("GET", &format!("/block/{}", block_hash)).await?).await?;
+        if block.len() as u64 != size {
+            return Err(ClientError::InvalidResponse(format!("expected {} bytes, received {}", size, block.len())).into());
+        }
+        check_header(block.get(..HEADER_SIZE).unwrap_or(&block), &hash)?;
+        Ok(block)
+    }
+
+    /// Get the hashes of all the blocks on the server.
+    #[wasm_bindgen(js_name = blockHashes)]
+    pub async fn block_hashes(&self) -> std::result::Result<Vec<String>, JsError> {
+        let list = text(self.fetch("GET", "/blocks").await?).await?;
+        Ok(list.lines().map(|l| l.to_string()).collect())
+    }
+
+    /// Get a proof that a transaction is in a block, as an object in the JSON format of the TSC
+    /// merkle proof standard. The proof is checked against the header of the block.
+    pub async fn proof(&self, block_hash: &str, txid: &str) -> std::result::Result<JsValue, JsError> {
+        let txid_bytes = parse_hash(txid)?;
+        let header = self.checked_header(block_hash).await?;
+        let json = text(self.fetch("GET", &format!("/block/{}/proof/{}", block_hash, txid)).await?).await?;
+        let proof: Value = serde_json::from_str(&json).map_err(|e| ClientError::InvalidResponse(e.to_string()))?;
+        check_proof(&proof, &txid_bytes, &header[36..68])?;
+        js_sys::JSON::parse(&json).map_err(|e| ClientError::InvalidResponse(format!("{:?}", e)).into())
+    }
+}
+
+impl ArchiveClient {
+    // Get the header of a block, checking that it has the hash.
+    async fn checked_header(&self, block_hash: &str) -> Result<Vec<u8>> {
+        let hash = parse_hash(block_hash)?;
+        let header = bytes(self.fetch("GET", &format!("/header/{}", block_hash)).await?).await?;
+        check_header(&header, &hash)?;
+        Ok(header)
+    }
+
+    // Get the size of a block.
+    async fn size(&self, block_hash: &str) -> Result<u64> {
+        parse_hash(block_hash)?;
+        let size = text(self.fetch("GET", &format!("/block/{}/size", block_hash)).await?).await?;
+        size.trim().parse().map_err(|_| ClientError::InvalidResponse(format!("invalid size: {}", size)))
+    }
+
+    // Send a request, failing unless the server answers with success.
+    async fn fetch(&self, method: &str, path: &str) -> Result<Response> {
+        let init = RequestInit::new();
+        init.set_method(method);
+        let request = Request::new_with_str_and_init(&format!("{}{}", self.url, path), &init).map_err(fetch_error)?;
+        let response: Response = JsFuture::from(fetch_with_request(&request)).await.map_err(fetch_error)?
+            .dyn_into().map_err(fetch_error)?;
+        match response.status() {
+            200 | 206 => Ok(response),
+            404 => Err(ClientError::NotFound),
+            status => Err(ClientError::HttpStatus(status)),
+        }
+    }
+}
+
+// Read the body of a response.
+async fn bytes(response: Response) -> Result<Vec<u8>> {
+    let buf = JsFuture::from(response.array_buffer().map_err(fetch_error)?).await.map_err(fetch_error)?;
+    Ok(js_sys::Uint8Array::new(&buf).to_vec())
+}
+
+// Read the body of a response as text.
+async fn text(response: Response) -> Result<String> {
+    let s = JsFuture::from(response.text().map_err(fetch_error)?).await.map_err(fetch_error)?;
+    s.as_string().ok_or_else(|| ClientError::InvalidResponse("the body is not text".to_string()))
+}
+
+fn fetch_error(e: JsValue) -> ClientError {
+    ClientError::FetchFailed(e.as_string().unwrap_or_else(|| format!("{:?}", e)))
+}
+
+// Check the URL of a server and remove any trailing slash.
+fn server_url(url: &str) -> Result<String> {
+    let host = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")).map(|h| h.trim_end_matches('/'));
+    match host {
+        Some(h) if !h.is_empty() && !h.contains('/') => Ok(url.trim_end_matches('/').to_string()),
+        _ => Err(ClientError::InvalidUrl(url.to_string())),
+    }
+}
+
+// Parse a hex encoded hash in the usual display order, giving the bytes in the order they are
+// hashed.
+fn parse_hash(s: &str) -> Result<[u8; 32]> {
+    let mut hash = <[u8; 32]>::try_from(hex::decode(s).map_err(|_| ClientError::InvalidHash(s.to_string()))?)
+        .map_err(|_| ClientError::InvalidHash(s.to_string()))?;
+    hash.reverse();
+    Ok(hash)
+}
+
+fn sha256d(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+// Check that an encoded header has the block hash.
+fn check_header(header: &[u8], block_hash: &[u8; 32]) -> Result<()> {
+    if header.len() != HEADER_SIZE || sha256d(header) != *block_hash {
+        return Err(ClientError::InvalidResponse("the header does not match the block hash".to_string()));
+    }
+    Ok(())
+}
+
+// Check that a proof in the TSC JSON format is for the transaction, and leads to the merkle root.
+// A node of "*" is the hash combined with itself, at the end of a level with an odd number of
+// hashes.
+fn check_proof(proof: &Value, txid: &[u8; 32], merkle_root: &[u8]) -> Result<()> {
+    let invalid = |msg: &str| ClientError::InvalidResponse(msg.to_string());
+    let index = proof["index"].as_u64().ok_or_else(|| invalid("the proof has no index"))?;
+    if parse_hash(proof["txOrId"].as_str().unwrap_or_default())? != *txid {
+        return Err(invalid("the proof is for another transaction"));
+    }
+    let nodes = proof["nodes"].as_array().ok_or_else(|| invalid("the proof has no nodes"))?;
+    let mut hash = *txid;
+    for (i, node) in nodes.iter().enumerate() {
+        let sibling = match node.as_str() {
+            Some("*") => hash,
+            Some(s) => parse_hash(s)?,
+            None => return Err(invalid("a node of the proof is not a string")),
+        };
+        let pair = if (index >> i) & 1 == 1 { [sibling, hash] } else { [hash, sibling] };
+        hash = sha256d(&pair.concat());
+    }
+    if hash != merkle_root {
+        return Err(invalid("the proof does not match the merkle root of the block"));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    const GENESIS: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+    fn genesis_block() -> Vec<u8> {
+        std::fs::read(format!("../testdata/blockarchive/6f/e2/{}.bin", GENESIS)).unwrap()
+    }
+
+    #[test]
+    fn test_server_url() {
+        assert_eq!(server_url("https://archive.example.com/").unwrap(), "https://archive.example.com");
+        assert_eq!(server_url("http://127.0.0.1:8080").unwrap(), "http://127.0.0.1:8080");
+        assert!(server_url("ftp://archive.example.com").is_err());
+        assert!(server_url("https://archive.example.com/blocks").is_err());
+        assert!(server_url("https://").is_err());
+    }
+
+    // A header is only accepted with its own hash.
+    #[test]
+    fn test_check_header() {
+        let block = genesis_block();
+        let hash = parse_hash(GENESIS).unwrap();
+        assert!(check_header(&block[..HEADER_SIZE], &hash).is_ok());
+        assert!(check_header(&block[..HEADER_SIZE - 1], &hash).is_err());
+        let mut other = hash;
+        other[0] ^= 1;
+        assert!(check_header(&block[..HEADER_SIZE], &other).is_err());
+        assert!(parse_hash("6f").is_err());
+        assert!(parse_hash(&"zz".repeat(32)).is_err());
+    }
+
+    // The genesis block has a single transaction, so its txid is the merkle root. A proof for the
+    // second of two transactions combines it with the first.
+    #[test]
+    fn test_check_proof() {
+        let block = genesis_block();
+        let txid = sha256d(&block[HEADER_SIZE + 1..]);
+        let txid_hex = hex::encode(txid.iter().rev().copied().collect::<Vec<u8>>());
+        let proof = json!({"index": 0, "txOrId": txid_hex, "target": GENESIS, "nodes": []});
+        assert!(check_proof(&proof, &txid, &block[36..68]).is_ok());
+        assert!(check_proof(&proof, &[0; 32], &block[36..68]).is_err());
+        assert!(check_proof(&proof, &txid, &[0; 32]).is_err());
+
+        let first = [1u8; 32];
+        let root = sha256d(&[first, txid].concat());
+        let proof = json!({"index": 1, "txOrId": txid_hex, "nodes": [hex::encode(first)]});
+        assert!(check_proof(&proof, &txid, &root).is_ok());
+        let proof = json!({"index": 0, "txOrId": txid_hex, "nodes": [hex::encode(first)]});
+        assert!(check_proof(&proof, &txid, &root).is_err());
+        let proof = json!({"index": 0, "txOrId": txid_hex, "nodes": ["*"]});
+        assert!(check_proof(&proof, &txid, &sha256d(&[txid, txid].concat())).is_ok());
+    }
+}